@@ -1,3 +1,51 @@
 pub mod bank {
     tonic::include_proto!("bank.v1");
-}
\ No newline at end of file
+}
+
+/// Metadata a follower attaches when rejecting a write, naming the leader to retry against.
+pub const LEADER_ID_HEADER: &str = "x-leader-id";
+pub const LEADER_ADDR_HEADER: &str = "x-leader-addr";
+
+/// Metadata a node attaches when rejecting a request for accounts owned by another shard.
+pub const SHARD_GROUP_HEADER: &str = "x-shard-group";
+pub const SHARD_ADDR_HEADER: &str = "x-shard-addr";
+
+/// Metadata a client may send naming itself, which a node queuing writes fairly takes
+/// turns between.
+pub const CLIENT_ID_HEADER: &str = "x-client-id";
+
+/// Metadata a node attaches when the changes asked for were compacted into a snapshot,
+/// giving the index the snapshot covers.
+pub const SNAPSHOT_INDEX_HEADER: &str = "x-snapshot-index";
+
+/// Metadata naming why a request failed, one of the `reason` constants, so a client can
+/// branch on it rather than on the message.
+pub const ERROR_REASON_HEADER: &str = "x-error-reason";
+
+/// The values `ERROR_REASON_HEADER` takes.
+pub mod reason {
+    /// The request, or the command it makes, is malformed.
+    pub const INVALID_ARGUMENT: &str = "INVALID_ARGUMENT";
+    /// This node does not lead; the leader headers say which one does.
+    pub const NOT_LEADER: &str = "NOT_LEADER";
+    /// No leader is known to this node, as during an election.
+    pub const NO_LEADER: &str = "NO_LEADER";
+    /// The accounts belong to another shard; the shard headers say which.
+    pub const WRONG_SHARD: &str = "WRONG_SHARD";
+    /// The accounts belong to several shards.
+    pub const CROSS_SHARD: &str = "CROSS_SHARD";
+    /// What was asked for was compacted into the snapshot `SNAPSHOT_INDEX_HEADER` gives.
+    pub const COMPACTED: &str = "COMPACTED";
+    /// This node has yet to apply the index the read requires.
+    pub const NOT_APPLIED: &str = "NOT_APPLIED";
+    pub const ACCOUNT_NOT_FOUND: &str = "ACCOUNT_NOT_FOUND";
+    /// Too many writes are waiting already.
+    pub const OVERLOADED: &str = "OVERLOADED";
+    pub const DEADLINE_EXCEEDED: &str = "DEADLINE_EXCEEDED";
+    pub const SHUTTING_DOWN: &str = "SHUTTING_DOWN";
+    /// The leader could not confirm it still leads.
+    pub const LEADERSHIP_UNCONFIRMED: &str = "LEADERSHIP_UNCONFIRMED";
+    pub const UNSUPPORTED: &str = "UNSUPPORTED";
+    /// The node failed to read or persist its state.
+    pub const INTERNAL: &str = "INTERNAL";
+}
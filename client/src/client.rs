@@ -0,0 +1,634 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint};
+use tonic::{Code, Response, Status};
+use bank_api::bank::bank_service_client::BankServiceClient;
+use bank_api::bank::{
+    BatchTransferRequest, CreateAccountRequest, CreateAccountResponse, DepositRequest,
+    DepositResponse, GetBalanceRequest, GetBalanceResponse, GetHistoryRequest,
+    GetHistoryResponse, GetTransferStatusRequest, GetTransferStatusResponse, MultiGetRequest,
+    MultiGetResponse, SetOverdraftLimitRequest, SetOverdraftLimitResponse, TransferRequest,
+    TransferResponse, WithdrawRequest,
+};
+use bank_api::{LEADER_ADDR_HEADER, LEADER_ID_HEADER};
+use crate::{PeerDiscovery, RetryPolicy};
+
+/// A bank client for a whole cluster rather than a single node.
+///
+/// Writes go to the cached leader. A follower's redirect moves the cache to the
+/// leader it names; an unreachable node or one that knows no leader is skipped
+/// in favour of the next known node after a backoff. Reads are served by the
+/// same node. Each request fails only once the retry policy is spent and every
+/// known node has been tried.
+pub struct BankClient {
+    retry: RetryPolicy,
+    tls: Option<ClientTlsConfig>,
+    discovery: Option<Arc<dyn PeerDiscovery>>,
+    state: Mutex<State>,
+}
+
+struct State {
+    nodes: Vec<String>,
+    leader: Option<String>,
+    /// Node to try next while no leader is cached.
+    next: usize,
+    clients: HashMap<String, BankServiceClient<Channel>>,
+}
+
+/// What a failed attempt tells the client to do next.
+enum Retry {
+    Redirect(String),
+    NextNode,
+}
+
+impl BankClient {
+    /// Creates a client for the nodes at `nodes` (`host:port` bank addresses).
+    pub fn new(nodes: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        let state = State {
+            nodes: nodes.into_iter().map(Into::into).collect(),
+            leader: None,
+            next: 0,
+            clients: HashMap::new(),
+        };
+
+        Self {
+            retry: RetryPolicy::default(),
+            tls: None,
+            discovery: None,
+            state: Mutex::new(state),
+        }
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Dials every node over TLS with `tls`.
+    pub fn with_tls(mut self, tls: ClientTlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Refreshes the known nodes from `discovery` whenever a full pass over them fails.
+    pub fn with_discovery(mut self, discovery: Arc<dyn PeerDiscovery>) -> Self {
+        self.discovery = Some(discovery);
+        self
+    }
+
+    /// Address of the node the client currently believes is the leader.
+    pub fn leader(&self) -> Option<String> {
+        self.lock().leader.clone()
+    }
+
+    pub fn nodes(&self) -> Vec<String> {
+        self.lock().nodes.clone()
+    }
+
+    /// Replaces the known nodes with those reported by the discovery source, if any.
+    pub async fn refresh_peers(&self) -> Result<(), Status> {
+        let Some(discovery) = &self.discovery else {
+            return Ok(());
+        };
+        let peers = discovery.peers().await?;
+        if peers.is_empty() {
+            return Ok(());
+        }
+
+        let mut state = self.lock();
+        if state.leader.as_ref().is_some_and(|leader| !peers.contains(leader)) {
+            state.leader = None;
+        }
+        state.clients.retain(|addr, _| peers.contains(addr));
+        state.nodes = peers;
+        state.next = 0;
+        Ok(())
+    }
+
+    pub async fn create_account(
+        &self,
+        request: CreateAccountRequest,
+    ) -> Result<CreateAccountResponse, Status> {
+        self.call(true, |mut client| {
+            let request = request.clone();
+            async move { client.create_account(request).await }
+        })
+        .await
+    }
+
+    pub async fn transfer(&self, request: TransferRequest) -> Result<TransferResponse, Status> {
+        self.call(true, |mut client| {
+            let request = request.clone();
+            async move { client.transfer(request).await }
+        })
+        .await
+    }
+
+    pub async fn batch_transfer(
+        &self,
+        request: BatchTransferRequest,
+    ) -> Result<TransferResponse, Status> {
+        self.call(true, |mut client| {
+            let request = request.clone();
+            async move { client.batch_transfer(request).await }
+        })
+        .await
+    }
+
+    pub async fn withdraw(&self, request: WithdrawRequest) -> Result<TransferResponse, Status> {
+        self.call(true, |mut client| {
+            let request = request.clone();
+            async move { client.withdraw(request).await }
+        })
+        .await
+    }
+
+    pub async fn deposit(&self, request: DepositRequest) -> Result<DepositResponse, Status> {
+        self.call(true, |mut client| {
+            let request = request.clone();
+            async move { client.deposit(request).await }
+        })
+        .await
+    }
+
+    pub async fn set_overdraft_limit(
+        &self,
+        request: SetOverdraftLimitRequest,
+    ) -> Result<SetOverdraftLimitResponse, Status> {
+        self.call(true, |mut client| {
+            let request = request.clone();
+            async move { client.set_overdraft_limit(request).await }
+        })
+        .await
+    }
+
+    pub async fn get_balance(
+        &self,
+        request: GetBalanceRequest,
+    ) -> Result<GetBalanceResponse, Status> {
+        self.call(false, |mut client| {
+            let request = request.clone();
+            async move { client.get_balance(request).await }
+        })
+        .await
+    }
+
+    pub async fn multi_get(&self, request: MultiGetRequest) -> Result<MultiGetResponse, Status> {
+        self.call(false, |mut client| {
+            let request = request.clone();
+            async move { client.multi_get(request).await }
+        })
+        .await
+    }
+
+    pub async fn get_history(
+        &self,
+        request: GetHistoryRequest,
+    ) -> Result<GetHistoryResponse, Status> {
+        self.call(false, |mut client| {
+            let request = request.clone();
+            async move { client.get_history(request).await }
+        })
+        .await
+    }
+
+    pub async fn get_transfer_status(
+        &self,
+        request: GetTransferStatusRequest,
+    ) -> Result<GetTransferStatusResponse, Status> {
+        self.call(false, |mut client| {
+            let request = request.clone();
+            async move { client.get_transfer_status(request).await }
+        })
+        .await
+    }
+
+    /// Runs `rpc` against the leader (or the next candidate node) until it succeeds,
+    /// fails with a non-retryable status, or the attempts run out. A successful
+    /// write confirms the node as leader.
+    async fn call<T, F, Fut>(&self, write: bool, mut rpc: F) -> Result<T, Status>
+    where
+        F: FnMut(BankServiceClient<Channel>) -> Fut,
+        Fut: Future<Output = Result<Response<T>, Status>>,
+    {
+        let attempts = self.retry.max_attempts.max(self.lock().nodes.len());
+        let mut failures = 0;
+        let mut last_error = Status::unavailable("no bank nodes are known");
+
+        for _ in 0..attempts {
+            let Some((addr, client)) = self.target()? else {
+                break;
+            };
+
+            let status = match rpc(client).await {
+                Ok(response) => {
+                    if write {
+                        self.lock().leader = Some(addr);
+                    }
+                    return Ok(response.into_inner());
+                }
+                Err(status) => status,
+            };
+
+            match retry(&status) {
+                Some(Retry::Redirect(leader)) if leader != addr => self.follow(leader),
+                Some(_) => {
+                    let wrapped = self.skip(&addr);
+                    tokio::time::sleep(self.retry.backoff(failures)).await;
+                    failures += 1;
+                    if wrapped {
+                        // Discovery is best effort; the current nodes remain usable.
+                        let _ = self.refresh_peers().await;
+                    }
+                }
+                None => return Err(status),
+            }
+            last_error = status;
+        }
+
+        Err(last_error)
+    }
+
+    /// The node to send the next attempt to, with a client for it.
+    fn target(&self) -> Result<Option<(String, BankServiceClient<Channel>)>, Status> {
+        let mut state = self.lock();
+        let addr = match &state.leader {
+            Some(leader) => leader.clone(),
+            None if state.nodes.is_empty() => return Ok(None),
+            None => state.nodes[state.next % state.nodes.len()].clone(),
+        };
+
+        if let Some(client) = state.clients.get(&addr) {
+            return Ok(Some((addr, client.clone())));
+        }
+        let client = BankServiceClient::new(self.channel(&addr)?);
+        state.clients.insert(addr.clone(), client.clone());
+        Ok(Some((addr, client)))
+    }
+
+    fn channel(&self, addr: &str) -> Result<Channel, Status> {
+        let scheme = if self.tls.is_some() { "https" } else { "http" };
+        let invalid = |e| Status::invalid_argument(format!("bad node address {}: {}", addr, e));
+
+        let mut endpoint =
+            Endpoint::from_shared(format!("{}://{}", scheme, addr)).map_err(invalid)?;
+        if let Some(tls) = &self.tls {
+            endpoint = endpoint.tls_config(tls.clone()).map_err(invalid)?;
+        }
+        Ok(endpoint.connect_lazy())
+    }
+
+    fn follow(&self, leader: String) {
+        let mut state = self.lock();
+        if !state.nodes.contains(&leader) {
+            state.nodes.push(leader.clone());
+        }
+        state.leader = Some(leader);
+    }
+
+    /// Moves on from `addr` to the node after it, returning whether that completes
+    /// a pass over every known node.
+    fn skip(&self, addr: &str) -> bool {
+        let mut state = self.lock();
+        state.leader = None;
+        if state.nodes.is_empty() {
+            return true;
+        }
+
+        let current = state.nodes.iter().position(|node| node == addr).unwrap_or(state.next);
+        state.next = (current + 1) % state.nodes.len();
+        state.next == 0
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+/// Whether (and where) a failed attempt is worth retrying. Followers answer writes
+/// with `FAILED_PRECONDITION` and a leader hint; unreachable nodes and nodes without
+/// a leader answer `UNAVAILABLE`. Anything else is the request's own fault.
+fn retry(status: &Status) -> Option<Retry> {
+    let metadata = status.metadata();
+    match status.code() {
+        Code::Unavailable => Some(Retry::NextNode),
+        Code::FailedPrecondition if metadata.contains_key(LEADER_ID_HEADER) => {
+            let addr = metadata.get(LEADER_ADDR_HEADER).and_then(|addr| addr.to_str().ok());
+            Some(addr.map_or(Retry::NextNode, |addr| Retry::Redirect(addr.to_string())))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tonic::metadata::MetadataValue;
+    use tonic::Request;
+    use bank_api::bank::bank_service_server::{BankService, BankServiceServer};
+    use bank_api::bank::{
+        AccountId, ChangeEvent, ClientTxId, SubscribeChangesRequest, TransferStatus,
+    };
+
+    /// Bank nodes that only know who leads: followers redirect, and with no leader
+    /// every node is unavailable.
+    #[derive(Default)]
+    struct FakeCluster {
+        addrs: Mutex<Vec<String>>,
+        leader: Mutex<Option<usize>>,
+        /// Index of the node that accepted each write.
+        accepted: Mutex<Vec<usize>>,
+    }
+
+    impl FakeCluster {
+        async fn start(size: usize) -> Arc<Self> {
+            let cluster = Arc::new(Self::default());
+            for index in 0..size {
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                cluster.addrs.lock().unwrap().push(listener.local_addr().unwrap().to_string());
+
+                let node = FakeNode {
+                    index,
+                    cluster: cluster.clone(),
+                };
+                tokio::spawn(
+                    tonic::transport::Server::builder()
+                        .add_service(BankServiceServer::new(node))
+                        .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(
+                            listener,
+                        )),
+                );
+            }
+            cluster
+        }
+
+        fn addr(&self, index: usize) -> String {
+            self.addrs.lock().unwrap()[index].clone()
+        }
+
+        fn addrs(&self) -> Vec<String> {
+            self.addrs.lock().unwrap().clone()
+        }
+
+        fn elect(&self, leader: Option<usize>) {
+            *self.leader.lock().unwrap() = leader;
+        }
+
+        fn accepted(&self) -> Vec<usize> {
+            self.accepted.lock().unwrap().clone()
+        }
+    }
+
+    struct FakeNode {
+        index: usize,
+        cluster: Arc<FakeCluster>,
+    }
+
+    impl FakeNode {
+        fn accept_write(&self) -> Result<(), Status> {
+            let leader = *self.cluster.leader.lock().unwrap();
+            match leader {
+                Some(leader) if leader == self.index => {
+                    self.cluster.accepted.lock().unwrap().push(self.index);
+                    Ok(())
+                }
+                Some(leader) => {
+                    let mut status = Status::failed_precondition("not the leader");
+                    let metadata = status.metadata_mut();
+                    metadata.insert(LEADER_ID_HEADER, MetadataValue::from(leader));
+                    let addr = self.cluster.addr(leader);
+                    metadata.insert(LEADER_ADDR_HEADER, addr.parse().unwrap());
+                    Err(status)
+                }
+                None => Err(Status::unavailable("no leader is currently known")),
+            }
+        }
+    }
+
+    #[tonic::async_trait]
+    impl BankService for FakeNode {
+        async fn create_account(
+            &self,
+            _request: Request<CreateAccountRequest>,
+        ) -> Result<Response<CreateAccountResponse>, Status> {
+            Err(Status::unimplemented("create_account"))
+        }
+
+        async fn get_balance(
+            &self,
+            _request: Request<GetBalanceRequest>,
+        ) -> Result<Response<GetBalanceResponse>, Status> {
+            Ok(Response::new(GetBalanceResponse {
+                balance: self.index as i64,
+            }))
+        }
+
+        async fn multi_get(
+            &self,
+            _request: Request<MultiGetRequest>,
+        ) -> Result<Response<MultiGetResponse>, Status> {
+            Err(Status::unimplemented("multi_get"))
+        }
+
+        async fn transfer(
+            &self,
+            request: Request<TransferRequest>,
+        ) -> Result<Response<TransferResponse>, Status> {
+            if request.get_ref().amount <= 0 {
+                return Err(Status::invalid_argument("amount must be positive"));
+            }
+            self.accept_write()?;
+
+            Ok(Response::new(TransferResponse {
+                status: TransferStatus::CommittedOk as i32,
+                message: String::new(),
+                applied_index: 1,
+            }))
+        }
+
+        async fn batch_transfer(
+            &self,
+            _request: Request<BatchTransferRequest>,
+        ) -> Result<Response<TransferResponse>, Status> {
+            Err(Status::unimplemented("batch_transfer"))
+        }
+
+        async fn withdraw(
+            &self,
+            _request: Request<WithdrawRequest>,
+        ) -> Result<Response<TransferResponse>, Status> {
+            Err(Status::unimplemented("withdraw"))
+        }
+
+        async fn deposit(
+            &self,
+            _request: Request<DepositRequest>,
+        ) -> Result<Response<DepositResponse>, Status> {
+            Err(Status::unimplemented("deposit"))
+        }
+
+        async fn set_overdraft_limit(
+            &self,
+            _request: Request<SetOverdraftLimitRequest>,
+        ) -> Result<Response<SetOverdraftLimitResponse>, Status> {
+            Err(Status::unimplemented("set_overdraft_limit"))
+        }
+
+        async fn get_history(
+            &self,
+            _request: Request<GetHistoryRequest>,
+        ) -> Result<Response<GetHistoryResponse>, Status> {
+            Err(Status::unimplemented("get_history"))
+        }
+
+        async fn get_transfer_status(
+            &self,
+            _request: Request<GetTransferStatusRequest>,
+        ) -> Result<Response<GetTransferStatusResponse>, Status> {
+            Err(Status::unimplemented("get_transfer_status"))
+        }
+
+        type SubscribeChangesStream = tokio_stream::Empty<Result<ChangeEvent, Status>>;
+
+        async fn subscribe_changes(
+            &self,
+            _request: Request<SubscribeChangesRequest>,
+        ) -> Result<Response<Self::SubscribeChangesStream>, Status> {
+            Err(Status::unimplemented("subscribe_changes"))
+        }
+    }
+
+    struct StaticDiscovery(Vec<String>);
+
+    #[tonic::async_trait]
+    impl PeerDiscovery for StaticDiscovery {
+        async fn peers(&self) -> Result<Vec<String>, Status> {
+            Ok(self.0.clone())
+        }
+    }
+
+    fn fast_retries(max_attempts: usize) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(50),
+        }
+    }
+
+    fn transfer_request(amount: i64) -> TransferRequest {
+        TransferRequest {
+            from: Some(AccountId { id: "alice".to_string() }),
+            to: Some(AccountId { id: "bob".to_string() }),
+            amount,
+            client_tx_id: Some(ClientTxId { id: "tx-1".to_string() }),
+        }
+    }
+
+    /// An address nothing listens on.
+    async fn dead_addr() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_client_follows_leader_redirect() {
+        let cluster = FakeCluster::start(3).await;
+        cluster.elect(Some(1));
+        let client = BankClient::new(cluster.addrs()).with_retry_policy(fast_retries(5));
+
+        client.transfer(transfer_request(10)).await.unwrap();
+        assert_eq!(client.leader(), Some(cluster.addr(1)));
+        assert_eq!(cluster.accepted(), vec![1]);
+
+        // The cached leader takes later writes directly.
+        client.transfer(transfer_request(10)).await.unwrap();
+        assert_eq!(cluster.accepted(), vec![1, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_client_retries_through_leader_change() {
+        let cluster = FakeCluster::start(3).await;
+        cluster.elect(Some(0));
+        let client = BankClient::new(cluster.addrs()).with_retry_policy(fast_retries(20));
+        client.transfer(transfer_request(10)).await.unwrap();
+        assert_eq!(client.leader(), Some(cluster.addr(0)));
+
+        // The leader steps down and a new one is only elected a while later.
+        cluster.elect(None);
+        let election = tokio::spawn({
+            let cluster = cluster.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(150)).await;
+                cluster.elect(Some(2));
+            }
+        });
+
+        client.transfer(transfer_request(10)).await.unwrap();
+        election.await.unwrap();
+        assert_eq!(client.leader(), Some(cluster.addr(2)));
+        assert_eq!(cluster.accepted(), vec![0, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_client_skips_unreachable_node() {
+        let cluster = FakeCluster::start(2).await;
+        cluster.elect(Some(1));
+        let mut nodes = vec![dead_addr().await];
+        nodes.extend(cluster.addrs());
+        let client = BankClient::new(nodes).with_retry_policy(fast_retries(5));
+
+        client.transfer(transfer_request(10)).await.unwrap();
+        assert_eq!(client.leader(), Some(cluster.addr(1)));
+    }
+
+    #[tokio::test]
+    async fn test_client_gives_up_after_trying_every_node() {
+        let cluster = FakeCluster::start(3).await;
+        let client = BankClient::new(cluster.addrs()).with_retry_policy(fast_retries(1));
+
+        let status = client.transfer(transfer_request(10)).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unavailable);
+        assert!(cluster.accepted().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_client_does_not_retry_rejected_request() {
+        let cluster = FakeCluster::start(3).await;
+        cluster.elect(Some(0));
+        let client = BankClient::new(cluster.addrs()).with_retry_policy(fast_retries(5));
+
+        let status = client.transfer(transfer_request(0)).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(client.leader(), None);
+    }
+
+    #[tokio::test]
+    async fn test_client_refreshes_nodes_from_discovery() {
+        let cluster = FakeCluster::start(2).await;
+        cluster.elect(Some(1));
+        let client = BankClient::new([dead_addr().await])
+            .with_retry_policy(fast_retries(5))
+            .with_discovery(Arc::new(StaticDiscovery(cluster.addrs())));
+
+        client.transfer(transfer_request(10)).await.unwrap();
+        assert_eq!(client.nodes(), cluster.addrs());
+        assert_eq!(client.leader(), Some(cluster.addr(1)));
+    }
+
+    #[tokio::test]
+    async fn test_client_reads_do_not_change_leader() {
+        let cluster = FakeCluster::start(2).await;
+        let client = BankClient::new(cluster.addrs()).with_retry_policy(fast_retries(5));
+
+        let response = client
+            .get_balance(GetBalanceRequest {
+                account: Some(AccountId { id: "alice".to_string() }),
+                min_index: 0,
+            })
+            .await
+            .unwrap();
+        assert_eq!(response.balance, 0);
+        assert_eq!(client.leader(), None);
+    }
+}
@@ -0,0 +1,8 @@
+use tonic::Status;
+
+/// A source of cluster membership the client consults when its known nodes stop answering.
+#[tonic::async_trait]
+pub trait PeerDiscovery: Send + Sync + 'static {
+    /// Bank service addresses (`host:port`) of the nodes currently in the cluster.
+    async fn peers(&self) -> Result<Vec<String>, Status>;
+}
@@ -0,0 +1,7 @@
+mod client;
+mod discovery;
+mod retry;
+
+pub use client::BankClient;
+pub use discovery::PeerDiscovery;
+pub use retry::RetryPolicy;
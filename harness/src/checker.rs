@@ -0,0 +1,206 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use crate::history::{Op, OpResult, Operation};
+
+/// The operations on an account that no sequential order explains.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    pub account: String,
+    pub operations: Vec<Operation>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the {} operations on account {} are not linearizable",
+            self.operations.len(),
+            self.account
+        )
+    }
+}
+
+impl std::error::Error for Violation {}
+
+/// Checks that `operations` are linearizable against a bank account: deposits always
+/// succeed, withdrawals fail rather than overdraw, and reads return the balance left
+/// by everything before them. `initial` gives each account's opening balance.
+///
+/// Every operation touches one account, so each account is checked on its own.
+/// Operations without a result may be placed anywhere after their invocation, or left
+/// out as if they never took effect.
+pub fn check(operations: &[Operation], initial: &BTreeMap<String, i64>) -> Result<(), Violation> {
+    let mut accounts: BTreeMap<&str, Vec<&Operation>> = BTreeMap::new();
+    for operation in operations {
+        // A read whose result was lost tells us nothing.
+        if operation.op == Op::Read && operation.result.is_none() {
+            continue;
+        }
+        accounts.entry(&operation.account).or_default().push(operation);
+    }
+
+    for (account, operations) in accounts {
+        let balance = initial.get(account).copied().unwrap_or_default();
+        let mut search = Search {
+            operations: &operations,
+            placed: vec![false; operations.len()],
+            seen: HashSet::new(),
+        };
+        if !search.run(balance) {
+            return Err(Violation {
+                account: account.to_string(),
+                operations: operations.into_iter().cloned().collect(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Depth-first search for a sequential order of one account's operations that agrees
+/// with their results and with real time, in the style of Wing and Gong.
+struct Search<'a> {
+    operations: &'a [&'a Operation],
+    placed: Vec<bool>,
+    /// (placed operations, balance) states already known to lead nowhere.
+    seen: HashSet<(Vec<bool>, i64)>,
+}
+
+impl Search<'_> {
+    fn run(&mut self, balance: i64) -> bool {
+        let unplaced = || self.operations.iter().zip(&self.placed).filter(|(_, placed)| !**placed);
+        // Nothing can go next that was invoked after an unplaced operation completed.
+        let Some(horizon) = unplaced().filter_map(|(operation, _)| operation.completed).min()
+        else {
+            // Every completed operation is placed; the pending ones may never have happened.
+            return true;
+        };
+        if !self.seen.insert((self.placed.clone(), balance)) {
+            return false;
+        }
+
+        for i in 0..self.operations.len() {
+            let operation = self.operations[i];
+            if self.placed[i] || operation.invoked > horizon {
+                continue;
+            }
+            let Some(next) = step(balance, operation) else {
+                continue;
+            };
+
+            self.placed[i] = true;
+            if self.run(next) {
+                return true;
+            }
+            self.placed[i] = false;
+        }
+        false
+    }
+}
+
+/// The balance after applying `operation` to `balance`, or `None` if the account
+/// could not have given the result the client saw.
+fn step(balance: i64, operation: &Operation) -> Option<i64> {
+    let (next, result) = match operation.op {
+        Op::Deposit(amount) => (balance + amount, OpResult::Ok),
+        Op::Withdraw(amount) if amount <= balance => (balance - amount, OpResult::Ok),
+        Op::Withdraw(_) => (balance, OpResult::InsufficientFunds),
+        Op::Read => (balance, OpResult::Balance(balance)),
+    };
+    match operation.result {
+        Some(seen) if seen != result => None,
+        _ => Some(next),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn operation(op: Op, span: (u64, u64), result: Option<OpResult>) -> Operation {
+        Operation {
+            client: 0,
+            account: "alice".to_string(),
+            op,
+            invoked: span.0,
+            completed: result.map(|_| span.1),
+            result,
+        }
+    }
+
+    fn opening(balance: i64) -> BTreeMap<String, i64> {
+        [("alice".to_string(), balance)].into()
+    }
+
+    #[test]
+    fn test_check_sequential_history() {
+        let history = [
+            operation(Op::Deposit(10), (1, 2), Some(OpResult::Ok)),
+            operation(Op::Withdraw(60), (3, 4), Some(OpResult::InsufficientFunds)),
+            operation(Op::Withdraw(30), (5, 6), Some(OpResult::Ok)),
+            operation(Op::Read, (7, 8), Some(OpResult::Balance(20))),
+        ];
+        assert_eq!(check(&history, &opening(40)), Ok(()));
+        assert!(check(&history, &opening(0)).is_err());
+    }
+
+    #[test]
+    fn test_check_reorders_concurrent_operations() {
+        // The read overlaps the deposit, so it may see the balance before or after it.
+        let before = [
+            operation(Op::Deposit(10), (1, 4), Some(OpResult::Ok)),
+            operation(Op::Read, (2, 3), Some(OpResult::Balance(0))),
+        ];
+        let after = [
+            operation(Op::Deposit(10), (1, 4), Some(OpResult::Ok)),
+            operation(Op::Read, (2, 3), Some(OpResult::Balance(10))),
+        ];
+        assert_eq!(check(&before, &opening(0)), Ok(()));
+        assert_eq!(check(&after, &opening(0)), Ok(()));
+    }
+
+    #[test]
+    fn test_check_catches_stale_read() {
+        let history = [
+            operation(Op::Deposit(10), (1, 2), Some(OpResult::Ok)),
+            operation(Op::Read, (3, 4), Some(OpResult::Balance(0))),
+        ];
+        let violation = check(&history, &opening(0)).unwrap_err();
+        assert_eq!(violation.account, "alice");
+        assert_eq!(violation.operations, history);
+    }
+
+    #[test]
+    fn test_check_catches_lost_update() {
+        // Two withdrawals both succeeded, but only one could have been funded.
+        let history = [
+            operation(Op::Withdraw(30), (1, 3), Some(OpResult::Ok)),
+            operation(Op::Withdraw(30), (2, 4), Some(OpResult::Ok)),
+            operation(Op::Read, (5, 6), Some(OpResult::Balance(10))),
+        ];
+        assert!(check(&history, &opening(40)).is_err());
+        assert!(check(&history, &opening(70)).is_ok());
+    }
+
+    #[test]
+    fn test_check_indeterminate_operations_may_or_may_not_apply() {
+        let applied = [
+            operation(Op::Deposit(10), (1, 0), None),
+            operation(Op::Read, (2, 3), Some(OpResult::Balance(0))),
+            operation(Op::Read, (4, 5), Some(OpResult::Balance(10))),
+        ];
+        assert_eq!(check(&applied, &opening(0)), Ok(()));
+
+        let never_applied = [
+            operation(Op::Deposit(10), (1, 0), None),
+            operation(Op::Read, (4, 5), Some(OpResult::Balance(0))),
+        ];
+        assert_eq!(check(&never_applied, &opening(0)), Ok(()));
+
+        // But it cannot take effect before it was invoked.
+        let too_early = [
+            operation(Op::Read, (1, 2), Some(OpResult::Balance(10))),
+            operation(Op::Deposit(10), (3, 0), None),
+        ];
+        assert!(check(&too_early, &opening(0)).is_err());
+    }
+}
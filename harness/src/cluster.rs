@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::task::JoinHandle;
+use node::bank::{BankCommand, BankStateMachine, CommandOutcome, TransferOutcome};
+use node::raft::{NotLeader, RaftConfig, RaftNode, Role, SimNetwork};
+use crate::history::{Op, OpResult};
+
+/// How long a client waits for its command to commit before giving up on knowing.
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(1);
+const COMMIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What a client learned from running an operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Done(OpResult),
+    /// The operation certainly did not take effect.
+    Failed,
+    /// The operation may or may not have taken effect.
+    Unknown,
+}
+
+/// Whether a command proposed to the leader committed.
+enum Committed {
+    /// It committed, with this outcome and the balance it left its account at.
+    Applied(CommandOutcome, Option<i64>),
+    /// It certainly did not commit.
+    Rejected,
+    /// It had not committed by the timeout, and may yet.
+    Unknown,
+}
+
+/// A Raft cluster on a `SimNetwork`, with each node applying its committed log to a
+/// bank state machine of its own.
+pub struct SimCluster {
+    network: Arc<SimNetwork>,
+    nodes: Vec<SimNode>,
+    next_tx_id: AtomicU64,
+    _dir: TempDir,
+}
+
+struct SimNode {
+    raft: Arc<RaftNode>,
+    bank: Mutex<Applied>,
+    task: JoinHandle<()>,
+}
+
+/// A node's bank state, and what each command it applied did.
+#[derive(Default)]
+struct Applied {
+    state: BankStateMachine,
+    /// Outcome of the command at each index, with the balance it left its account at.
+    outcomes: BTreeMap<u64, (CommandOutcome, Option<i64>)>,
+}
+
+impl SimCluster {
+    /// Starts nodes `node-1`..`node-N`, with the network's randomness seeded by `seed`.
+    pub fn start(size: usize, seed: u64) -> std::io::Result<Self> {
+        let dir = TempDir::new()?;
+        let network = SimNetwork::new(seed);
+        let ids: Vec<String> = (1..=size).map(|i| format!("node-{}", i)).collect();
+
+        let mut nodes = Vec::new();
+        for id in &ids {
+            let peers = ids
+                .iter()
+                .filter(|peer| *peer != id)
+                .map(|peer| (peer.clone(), String::new()))
+                .collect();
+            let state_path = dir.path().join(format!("{}.state", id));
+            let log_path = dir.path().join(format!("{}.wal", id));
+            let config = RaftConfig::new(id, peers);
+            let raft = RaftNode::new(config, state_path, log_path, network.transport(id))?;
+            network.register(&raft);
+            nodes.push(SimNode {
+                task: raft.start(),
+                raft,
+                bank: Mutex::new(Applied::default()),
+            });
+        }
+
+        Ok(Self {
+            network,
+            nodes,
+            next_tx_id: AtomicU64::new(1),
+            _dir: dir,
+        })
+    }
+
+    pub fn network(&self) -> &Arc<SimNetwork> {
+        &self.network
+    }
+
+    pub fn node_ids(&self) -> Vec<String> {
+        self.nodes.iter().map(|node| node.raft.id().to_string()).collect()
+    }
+
+    /// The node leading the highest term any node has seen, if there is one yet.
+    pub fn leader(&self) -> Option<Arc<RaftNode>> {
+        let term = self.nodes.iter().map(|node| node.raft.current_term()).max()?;
+        self.nodes
+            .iter()
+            .map(|node| &node.raft)
+            .find(|raft| raft.role() == Role::Leader && raft.current_term() == term)
+            .cloned()
+    }
+
+    /// Waits for a leader, failing after 10s without one.
+    pub async fn wait_for_leader(&self) -> std::io::Result<Arc<RaftNode>> {
+        for _ in 0..100 {
+            if let Some(leader) = self.leader() {
+                return Ok(leader);
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "no leader was elected"))
+    }
+
+    /// Creates `account` with `balance`, retrying until the creation is known to commit.
+    pub async fn open_account(&self, account: &str, balance: i64) -> std::io::Result<()> {
+        let command = BankCommand::CreateAccount {
+            account: account.to_string(),
+            initial_balance: balance,
+        };
+        for _ in 0..100 {
+            match self.commit(&command).await? {
+                Committed::Applied(..) => return Ok(()),
+                _ => tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("could not open account {}", account),
+        ))
+    }
+
+    /// Runs `op` on `account` through the current leader. Reads go through the log as
+    /// well, so they see every write that completed before them.
+    pub async fn execute(&self, account: &str, op: Op) -> std::io::Result<Outcome> {
+        let client_tx_id = format!("tx-{}", self.next_tx_id.fetch_add(1, Ordering::Relaxed));
+        let account = account.to_string();
+        let command = match op {
+            Op::Deposit(amount) => BankCommand::Deposit { account, amount, client_tx_id },
+            Op::Withdraw(amount) => BankCommand::Withdraw { account, amount, client_tx_id },
+            // Creating an account that exists changes nothing, but orders the read.
+            Op::Read => BankCommand::CreateAccount { account, initial_balance: 0 },
+        };
+
+        let (outcome, balance) = match self.commit(&command).await? {
+            Committed::Applied(outcome, balance) => (outcome, balance),
+            Committed::Rejected => return Ok(Outcome::Failed),
+            Committed::Unknown => return Ok(Outcome::Unknown),
+        };
+        let result = match (outcome, balance) {
+            (CommandOutcome::Deposit { outcome: TransferOutcome::Ok, .. }, _) => OpResult::Ok,
+            (CommandOutcome::Withdraw(TransferOutcome::Ok), _) => OpResult::Ok,
+            (CommandOutcome::Withdraw(TransferOutcome::InsufficientFunds), _) => {
+                OpResult::InsufficientFunds
+            }
+            (CommandOutcome::AccountExists { .. }, Some(balance)) => OpResult::Balance(balance),
+            (outcome, _) => {
+                return Err(std::io::Error::other(format!(
+                    "unexpected outcome {:?} for {:?}",
+                    outcome, op
+                )));
+            }
+        };
+        Ok(Outcome::Done(result))
+    }
+
+    /// The balance of `account` as `node` has applied it, without asking the leader.
+    /// A node cut off from the leader may return a stale balance.
+    pub fn read_local(&self, node: &str, account: &str) -> std::io::Result<Option<i64>> {
+        let node = self
+            .nodes
+            .iter()
+            .find(|n| n.raft.id() == node)
+            .ok_or_else(|| std::io::Error::other(format!("no node {}", node)))?;
+        node.catch_up()?;
+        Ok(node.bank.lock().unwrap().state.balance(account))
+    }
+
+    /// Proposes `command` to the leader and waits for it to commit and apply there.
+    async fn commit(&self, command: &BankCommand) -> std::io::Result<Committed> {
+        let Some(leader) = self.leader() else {
+            return Ok(Committed::Rejected);
+        };
+        let index = match leader.propose(command.encode()?) {
+            Ok(index) => index,
+            Err(e) if NotLeader::from_io(&e).is_some() => return Ok(Committed::Rejected),
+            Err(e) => return Err(e),
+        };
+        let Some(proposed) = leader.entries(index..=index)?.pop() else {
+            return Ok(Committed::Unknown);
+        };
+        let node = self.nodes.iter().find(|n| n.raft.id() == leader.id()).unwrap();
+
+        let deadline = tokio::time::Instant::now() + COMMIT_TIMEOUT;
+        while leader.commit_index() < index {
+            if tokio::time::Instant::now() >= deadline {
+                return Ok(Committed::Unknown);
+            }
+            tokio::time::sleep(COMMIT_POLL_INTERVAL).await;
+        }
+
+        // Committed entries never change, so a different term means ours was replaced.
+        let committed = leader.entries(index..=index)?.pop();
+        if committed.is_none_or(|entry| entry.term != proposed.term) {
+            return Ok(Committed::Rejected);
+        }
+        node.catch_up()?;
+        let (outcome, balance) = node.bank.lock().unwrap().outcomes[&index].clone();
+        Ok(Committed::Applied(outcome, balance))
+    }
+}
+
+impl SimNode {
+    /// Applies every entry the node knows to be committed.
+    fn catch_up(&self) -> std::io::Result<()> {
+        let mut bank = self.bank.lock().unwrap();
+        let (first, last) = (bank.state.last_applied() + 1, self.raft.commit_index());
+        for entry in self.raft.entries(first..=last)? {
+            let account = match BankCommand::decode(entry.command_slice())? {
+                BankCommand::CreateAccount { account, .. }
+                | BankCommand::Deposit { account, .. }
+                | BankCommand::Withdraw { account, .. } => Some(account),
+                _ => None,
+            };
+            let outcome = bank.state.apply(&entry)?;
+            let balance = account.and_then(|account| bank.state.balance(&account));
+            bank.outcomes.insert(entry.index, (outcome, balance));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SimCluster {
+    fn drop(&mut self) {
+        self.nodes.iter().for_each(|node| node.task.abort());
+    }
+}
+
+impl std::fmt::Debug for SimCluster {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SimCluster").field("network", &self.network).finish_non_exhaustive()
+    }
+}
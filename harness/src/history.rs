@@ -0,0 +1,131 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+
+/// An operation on a single account, as a client issued it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Deposit(i64),
+    Withdraw(i64),
+    Read,
+}
+
+/// What the cluster told the client about an operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpResult {
+    Ok,
+    InsufficientFunds,
+    Balance(i64),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Operation {
+    pub client: usize,
+    pub account: String,
+    pub op: Op,
+    /// Logical time the client invoked it. Invocations and completions share one clock,
+    /// so comparing them gives the real-time order of operations.
+    pub invoked: u64,
+    /// Logical time it completed, or `None` if the client never learned whether it
+    /// took effect.
+    pub completed: Option<u64>,
+    pub result: Option<OpResult>,
+}
+
+/// Handle to an operation that was invoked but has not completed yet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OpId(usize);
+
+/// Records the operations concurrent clients run against the cluster. Clones share one
+/// history.
+#[derive(Clone, Debug, Default)]
+pub struct History {
+    inner: Arc<Mutex<Recorder>>,
+}
+
+#[derive(Debug, Default)]
+struct Recorder {
+    clock: u64,
+    operations: Vec<Operation>,
+    /// Operations known not to have taken effect, which constrain nothing.
+    failed: BTreeSet<usize>,
+}
+
+impl Recorder {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn invoke(&self, client: usize, account: &str, op: Op) -> OpId {
+        let mut recorder = self.inner.lock().unwrap();
+        let invoked = recorder.tick();
+        recorder.operations.push(Operation {
+            client,
+            account: account.to_string(),
+            op,
+            invoked,
+            completed: None,
+            result: None,
+        });
+        OpId(recorder.operations.len() - 1)
+    }
+
+    pub fn complete(&self, id: OpId, result: OpResult) {
+        let mut recorder = self.inner.lock().unwrap();
+        let completed = recorder.tick();
+        let operation = &mut recorder.operations[id.0];
+        operation.completed = Some(completed);
+        operation.result = Some(result);
+    }
+
+    /// Records that the operation definitely did not take effect, e.g. because no
+    /// leader accepted it. Operations neither completed nor failed stay indeterminate.
+    pub fn fail(&self, id: OpId) {
+        self.inner.lock().unwrap().failed.insert(id.0);
+    }
+
+    /// Every operation that completed or may have taken effect, in invocation order.
+    pub fn operations(&self) -> Vec<Operation> {
+        let recorder = self.inner.lock().unwrap();
+        recorder
+            .operations
+            .iter()
+            .enumerate()
+            .filter(|(id, _)| !recorder.failed.contains(id))
+            .map(|(_, operation)| operation.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_orders_invocations_and_completions() {
+        let history = History::new();
+        let deposit = history.invoke(0, "alice", Op::Deposit(5));
+        let read = history.invoke(1, "alice", Op::Read);
+        let lost = history.invoke(2, "alice", Op::Withdraw(1));
+        history.complete(read, OpResult::Balance(5));
+        history.complete(deposit, OpResult::Ok);
+        history.fail(lost);
+        let pending = history.invoke(0, "bob", Op::Read);
+
+        let operations = history.clone().operations();
+        assert_eq!(operations.len(), 3);
+        assert_eq!((operations[0].invoked, operations[0].completed), (1, Some(5)));
+        assert_eq!((operations[1].invoked, operations[1].completed), (2, Some(4)));
+        assert_eq!(operations[1].result, Some(OpResult::Balance(5)));
+        assert_eq!(operations[2].invoked, 6);
+        assert_eq!(operations[2].completed, None);
+        assert_eq!(operations[2].result, None);
+        assert_eq!(pending, OpId(3));
+    }
+}
@@ -0,0 +1,9 @@
+mod checker;
+mod cluster;
+mod history;
+mod workload;
+
+pub use checker::{check, Violation};
+pub use cluster::{Outcome, SimCluster};
+pub use history::{History, Op, OpId, OpResult, Operation};
+pub use workload::Workload;
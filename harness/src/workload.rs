@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{RngExt, SeedableRng};
+use tokio::task::JoinSet;
+use crate::cluster::{Outcome, SimCluster};
+use crate::history::{History, Op};
+
+/// A randomized bank workload: concurrent clients depositing into, withdrawing from and
+/// reading a few accounts, optionally while a nemesis partitions the cluster.
+#[derive(Clone, Debug)]
+pub struct Workload {
+    pub clients: usize,
+    pub ops_per_client: usize,
+    pub accounts: Vec<String>,
+    pub opening_balance: i64,
+    /// Cuts a random minority off from the rest every so often while the clients run.
+    pub partitions: bool,
+    pub seed: u64,
+}
+
+impl Default for Workload {
+    fn default() -> Self {
+        Self {
+            clients: 3,
+            ops_per_client: 30,
+            accounts: vec!["alice".to_string(), "bob".to_string()],
+            opening_balance: 100,
+            partitions: false,
+            seed: 0,
+        }
+    }
+}
+
+impl Workload {
+    /// What each account holds before the clients start, for the checker.
+    pub fn opening_balances(&self) -> BTreeMap<String, i64> {
+        self.accounts.iter().map(|account| (account.clone(), self.opening_balance)).collect()
+    }
+
+    /// Opens the accounts, runs every client to the end and returns what they saw.
+    pub async fn run(&self, cluster: &Arc<SimCluster>) -> std::io::Result<History> {
+        cluster.wait_for_leader().await?;
+        for account in &self.accounts {
+            cluster.open_account(account, self.opening_balance).await?;
+        }
+
+        let history = History::new();
+        let mut clients = JoinSet::new();
+        for client in 0..self.clients {
+            let rng = StdRng::seed_from_u64(self.seed.wrapping_add(client as u64));
+            let (workload, cluster, history) = (self.clone(), cluster.clone(), history.clone());
+            clients.spawn(async move { workload.client(client, rng, &cluster, &history).await });
+        }
+        let nemesis = self.partitions.then(|| {
+            let rng = StdRng::seed_from_u64(self.seed.wrapping_sub(1));
+            tokio::spawn(nemesis(rng, cluster.clone()))
+        });
+
+        while let Some(client) = clients.join_next().await {
+            client.map_err(std::io::Error::other)??;
+        }
+        if let Some(nemesis) = nemesis {
+            nemesis.abort();
+            cluster.network().heal();
+        }
+        Ok(history)
+    }
+
+    async fn client(
+        &self,
+        client: usize,
+        mut rng: StdRng,
+        cluster: &SimCluster,
+        history: &History,
+    ) -> std::io::Result<()> {
+        for _ in 0..self.ops_per_client {
+            let account = &self.accounts[rng.random_range(0..self.accounts.len())];
+            let op = match rng.random_range(0..3) {
+                0 => Op::Deposit(rng.random_range(1..=20)),
+                1 => Op::Withdraw(rng.random_range(1..=40)),
+                _ => Op::Read,
+            };
+
+            let id = history.invoke(client, account, op);
+            match cluster.execute(account, op).await? {
+                Outcome::Done(result) => history.complete(id, result),
+                Outcome::Failed => history.fail(id),
+                Outcome::Unknown => {}
+            }
+            tokio::time::sleep(Duration::from_millis(rng.random_range(0..20))).await;
+        }
+        Ok(())
+    }
+}
+
+/// Repeatedly cuts a random minority off from the rest of the cluster, then heals it.
+async fn nemesis(mut rng: StdRng, cluster: Arc<SimCluster>) {
+    let mut ids = cluster.node_ids();
+    loop {
+        tokio::time::sleep(Duration::from_millis(rng.random_range(200..400))).await;
+        ids.shuffle(&mut rng);
+        let (minority, majority) = ids.split_at((ids.len() - 1) / 2);
+        cluster.network().partition(&as_strs(minority), &as_strs(majority));
+
+        tokio::time::sleep(Duration::from_millis(rng.random_range(300..600))).await;
+        cluster.network().heal();
+    }
+}
+
+fn as_strs(ids: &[String]) -> Vec<&str> {
+    ids.iter().map(String::as_str).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checker::check;
+    use crate::history::OpResult;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_workload_without_faults_is_linearizable() {
+        let cluster = Arc::new(SimCluster::start(3, 1).unwrap());
+        let workload = Workload::default();
+
+        let history = workload.run(&cluster).await.unwrap();
+        let operations = history.operations();
+        assert_eq!(operations.len(), workload.clients * workload.ops_per_client);
+        assert!(operations.iter().all(|operation| operation.result.is_some()));
+        assert_eq!(check(&operations, &workload.opening_balances()), Ok(()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_workload_with_partitions_is_linearizable() {
+        let cluster = Arc::new(SimCluster::start(5, 2).unwrap());
+        cluster.network().set_latency(Duration::from_millis(1), Duration::from_millis(5));
+        let workload = Workload {
+            partitions: true,
+            seed: 2,
+            ..Workload::default()
+        };
+
+        let history = workload.run(&cluster).await.unwrap();
+        let operations = history.operations();
+        assert!(operations.iter().any(|operation| operation.result.is_some()));
+        assert_eq!(check(&operations, &workload.opening_balances()), Ok(()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_checker_catches_injected_stale_read() {
+        let cluster = Arc::new(SimCluster::start(3, 3).unwrap());
+        let workload = Workload {
+            accounts: vec!["alice".to_string()],
+            ops_per_client: 0,
+            ..Workload::default()
+        };
+        let history = workload.run(&cluster).await.unwrap();
+        let leader = cluster.wait_for_leader().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // Cut a follower off, then deposit through the majority.
+        let ids = cluster.node_ids();
+        let follower = ids.iter().find(|id| *id != leader.id()).unwrap();
+        let rest: Vec<&str> = ids.iter().filter(|id| *id != follower).map(String::as_str).collect();
+        cluster.network().partition(&[follower], &rest);
+        let deposit = history.invoke(0, "alice", Op::Deposit(10));
+        let outcome = cluster.execute("alice", Op::Deposit(10)).await.unwrap();
+        assert_eq!(outcome, Outcome::Done(OpResult::Ok));
+        history.complete(deposit, OpResult::Ok);
+
+        // Serving the read from the follower's own state misses the deposit.
+        let read = history.invoke(1, "alice", Op::Read);
+        let balance = cluster.read_local(follower, "alice").unwrap().unwrap();
+        history.complete(read, OpResult::Balance(balance));
+        assert_eq!(balance, 100);
+
+        let violation = check(&history.operations(), &workload.opening_balances()).unwrap_err();
+        assert_eq!(violation.account, "alice");
+    }
+}
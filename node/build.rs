@@ -0,0 +1,17 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=../proto/command.proto");
+    println!("cargo:rerun-if-changed=../proto/snapshot.proto");
+
+    tonic_prost_build::configure()
+        .build_server(false)
+        .build_client(false)
+        .compile_protos(
+            &[
+                "../proto/command.proto",
+                "../proto/snapshot.proto",
+            ],
+            &["../proto"],
+        )?;
+
+    Ok(())
+}
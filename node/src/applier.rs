@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+use crate::raft::RaftNode;
+use crate::replica::Replica;
+
+/// How long `RaftApplier::run` waits to try again after failing to apply.
+pub const APPLY_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Applies the entries a Raft node commits to the node's replica, in order, so every
+/// node's bank follows the same log at the same indexes. Whoever proposed an entry on
+/// this node through `RaftNode::propose_waiting` gets the outcome of applying it. The
+/// node compacts its log up to each snapshot the replica saves, and sends that snapshot
+/// to peers that need what it compacted away.
+#[derive(Clone)]
+pub struct RaftApplier {
+    raft: Arc<RaftNode>,
+    replica: Arc<Replica>,
+}
+
+impl RaftApplier {
+    pub fn new(raft: Arc<RaftNode>, replica: Arc<Replica>) -> Self {
+        raft.set_snapshot_store(replica.clone());
+        Self { raft, replica }
+    }
+
+    /// Applies the entries committed past what the replica has applied, and returns how
+    /// many there were, then compacts the log up to the replica's last snapshot. If
+    /// applying them fails, their proposers get the error, and the entries are handed
+    /// over again on the next call.
+    pub fn apply_committed(&self) -> std::io::Result<usize> {
+        let entries = self.raft.entries_since(self.replica.last_applied())?;
+        let count = entries.len();
+        match self.replica.apply_entries(entries.clone()) {
+            Ok(outcomes) => {
+                let mut outcomes: HashMap<_, _> = outcomes.into_iter().collect();
+                for entry in &entries {
+                    let result = outcomes.remove(&entry.index).ok_or_else(|| {
+                        std::io::Error::other(format!("entry {} was not applied", entry.index))
+                    });
+                    self.raft.applied(entry, result);
+                }
+                self.raft.compact_log(self.replica.snapshot_index())?;
+                Ok(count)
+            }
+            Err(e) => {
+                for entry in &entries {
+                    self.raft.applied(entry, Err(std::io::Error::new(e.kind(), e.to_string())));
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Applies entries as they commit, on a blocking thread since each batch waits on an
+    /// fsync, forever. A failure is logged and retried after `APPLY_RETRY_INTERVAL`.
+    pub async fn run(self) {
+        let mut commits = self.raft.commit_watch();
+        loop {
+            let applier = self.clone();
+            let applied = tokio::task::spawn_blocking(move || applier.apply_committed()).await;
+            if let Err(e) = applied.map_err(std::io::Error::other).and_then(|applied| applied) {
+                warn!(error = %e, "failed to apply committed entries; will retry");
+                tokio::time::sleep(APPLY_RETRY_INTERVAL).await;
+                continue;
+            }
+            // The node outlives this loop, which holds on to it, so its commits never end.
+            let _ = commits.changed().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use crate::bank::tests::{create_account, transfer};
+    use crate::bank::{CommandOutcome, TransferOutcome};
+    use crate::bank::DefaultValidator;
+    use crate::metrics::WalMetrics;
+    use crate::raft::tests::{wait_until, TestCluster};
+    use crate::raft::RaftEvent;
+    use crate::replica::SnapshotPolicy;
+    use crate::wal::{
+        ApplyErrorPolicy, LogEntry, Wal, DEFAULT_FILE_MODE, DEFAULT_READ_BUFFER_BYTES,
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn test_every_node_applies_what_the_leader_commits() {
+        let cluster = TestCluster::start(3);
+        let dir = TempDir::new().unwrap();
+        let replicas: Vec<_> = cluster
+            .nodes
+            .iter()
+            .map(|node| {
+                let path = dir.path().join(format!("{}.bank.wal", node.id()));
+                let replica = Arc::new(Replica::open(path.to_str().unwrap()).unwrap());
+                tokio::spawn(RaftApplier::new(node.clone(), replica.clone()).run());
+                replica
+            })
+            .collect();
+        let leader = cluster.wait_for_leader().await;
+
+        let mut outcomes = Vec::new();
+        for command in [
+            create_account("alice", 100),
+            create_account("bob", 0),
+            transfer("alice", "bob", 30, "tx-1"),
+        ] {
+            let waiter = leader.propose_waiting(command.encode().unwrap()).unwrap();
+            outcomes.push((waiter.index(), waiter.outcome().await.unwrap()));
+        }
+        assert_eq!(outcomes[2], (3, CommandOutcome::Transfer(TransferOutcome::Ok)));
+
+        wait_until(|| replicas.iter().all(|replica| replica.last_applied() == 3)).await;
+        let positions = |entries: Vec<LogEntry>| -> Vec<(u64, u64)> {
+            entries.iter().map(|entry| (entry.index, entry.term)).collect()
+        };
+        for replica in &replicas {
+            assert_eq!(replica.read(|sm| sm.balance("bob")), Some(30));
+            let applied = positions(replica.applied_entries(0).unwrap());
+            assert_eq!(applied, positions(leader.entries(..).unwrap()));
+        }
+    }
+    #[tokio::test]
+    async fn test_a_node_behind_the_compacted_log_catches_up_from_a_snapshot_in_its_own_dir() {
+        let dir = TempDir::new().unwrap();
+        let wal_path = |id: &str| dir.path().join(id).join("wal").join("bank.wal");
+        let snapshot_dir = |id: &str| dir.path().join(id).join("snapshots");
+        for id in ["node-1", "node-2", "node-3"] {
+            std::fs::create_dir_all(wal_path(id).parent().unwrap()).unwrap();
+            std::fs::create_dir_all(snapshot_dir(id)).unwrap();
+        }
+        let open = |id: &str| {
+            let (metrics, validator) = (WalMetrics::default(), Arc::new(DefaultValidator));
+            let (mode, policy) = (DEFAULT_FILE_MODE, ApplyErrorPolicy::Halt);
+            let (path, snapshot) = (wal_path(id), snapshot_dir(id).join("bank.wal.snapshot"));
+            let bytes = DEFAULT_READ_BUFFER_BYTES;
+            Replica::open_with_snapshot_path(
+                path.to_str().unwrap(),
+                metrics,
+                validator,
+                mode,
+                policy,
+                bytes,
+                &snapshot,
+            )
+        };
+        let cluster = TestCluster::start_with(3, |config| {
+            config.snapshot_dir = Some(snapshot_dir(&config.id));
+        });
+        let policy = SnapshotPolicy {
+            every_entries: 5,
+            every_bytes: 0,
+        };
+        let (replicas, appliers): (Vec<_>, Vec<_>) = cluster
+            .nodes
+            .iter()
+            .map(|node| {
+                let replica = Arc::new(open(node.id()).unwrap().with_snapshot_policy(policy));
+                let applier = tokio::spawn(RaftApplier::new(node.clone(), replica.clone()).run());
+                (replica, applier)
+            })
+            .unzip();
+        let leader = cluster.wait_for_leader().await;
+        let behind = cluster.nodes.iter().position(|node| node.id() != leader.id()).unwrap();
+        let follower = cluster.nodes[behind].clone();
+        cluster.network.isolate(follower.id());
+
+        let mut commands = vec![create_account("alice", 1000), create_account("bob", 0)];
+        commands.extend((0..5).map(|i| transfer("alice", "bob", 10, &format!("tx-{}", i))));
+        for command in commands {
+            let waiter = leader.propose_waiting(command.encode().unwrap()).unwrap();
+            waiter.outcome().await.unwrap();
+        }
+        for (_, replica) in replicas.iter().enumerate().filter(|(i, _)| *i != behind) {
+            tokio::time::timeout(Duration::from_secs(5), replica.wait_snapshot(5)).await.unwrap();
+        }
+        // Each node compacts its log up to its snapshot as it applies the next entry.
+        let command = transfer("alice", "bob", 10, "tx-5").encode().unwrap();
+        leader.propose_waiting(command).unwrap().outcome().await.unwrap();
+        let compacted = |node: &Arc<RaftNode>| {
+            node.id() == follower.id()
+                || node.entries(..).unwrap().first().is_none_or(|entry| entry.index > 5)
+        };
+        wait_until(|| cluster.nodes.iter().all(compacted)).await;
+
+        let mut events = follower.events();
+        cluster.network.heal();
+        wait_until(|| replicas[behind].last_applied() == 8).await;
+        let installed = std::iter::from_fn(|| events.try_recv().ok())
+            .any(|event| event == RaftEvent::SnapshotInstalled { index: 5 });
+        assert!(installed);
+        assert_eq!(replicas[behind].snapshot_index(), 5);
+        assert_eq!(replicas[behind].read(|sm| sm.balance("bob")), Some(60));
+        // Put together in its snapshot directory and kept there, away from the WAL.
+        let kept: Vec<_> = std::fs::read_dir(snapshot_dir(follower.id()))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(kept, ["bank.wal.snapshot"]);
+
+        // Restarting finds the snapshot there and replays the WAL after it.
+        appliers.iter().for_each(|applier| applier.abort());
+        drop(appliers);
+        drop(replicas);
+        let replica = open(follower.id()).unwrap();
+        assert_eq!((replica.snapshot_index(), replica.last_applied()), (5, 8));
+        assert_eq!(replica.read(|sm| sm.balance("alice")), Some(940));
+        let wal = Wal::new(wal_path(follower.id()).to_str().unwrap()).unwrap();
+        assert_eq!(wal.first_index(), 6);
+    }
+}
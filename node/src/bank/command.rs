@@ -0,0 +1,535 @@
+use bytes::Bytes;
+use prost::encoding::decode_key;
+use prost::Message;
+
+/// Generated from `proto/command.proto`, the on-disk schema of `LogEntry::command`.
+mod pb {
+    tonic::include_proto!("bank.command.v1");
+}
+
+/// One leg of a `BatchTransfer`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transfer {
+    pub from: String,
+    pub to: String,
+    pub amount: i64,
+}
+
+/// A state-changing bank operation, carried in `LogEntry::command`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BankCommand {
+    /// Opens `account`, or an account under an id generated as it applies if it is empty.
+    CreateAccount {
+        account: String,
+        initial_balance: i64,
+    },
+    Transfer {
+        from: String,
+        to: String,
+        amount: i64,
+        client_tx_id: String,
+    },
+    /// Transfers that apply all together or not at all.
+    BatchTransfer {
+        transfers: Vec<Transfer>,
+        client_tx_id: String,
+    },
+    Withdraw {
+        account: String,
+        amount: i64,
+        client_tx_id: String,
+    },
+    SetOverdraftLimit {
+        account: String,
+        overdraft_limit: i64,
+    },
+    /// Freezes `account`, so nothing is debited from it, nor credited to it as well if
+    /// `block_credits` is set, or unfreezes it. Reading it is unaffected.
+    SetAccountStatus {
+        account: String,
+        frozen: bool,
+        block_credits: bool,
+    },
+    /// First step of a cross-shard transfer, on the source shard: debits `from` and
+    /// holds the funds under `saga_id` until the saga finishes.
+    SagaDebit {
+        saga_id: String,
+        from: String,
+        to: String,
+        amount: i64,
+    },
+    /// Second step of a cross-shard transfer, on the destination shard: credits `to`.
+    SagaCredit {
+        saga_id: String,
+        from: String,
+        to: String,
+        amount: i64,
+    },
+    /// Last step of a cross-shard transfer, on the source shard: releases the hold,
+    /// refunding `from` unless `commit` is set.
+    SagaFinish {
+        saga_id: String,
+        commit: bool,
+    },
+    Deposit {
+        account: String,
+        amount: i64,
+        client_tx_id: String,
+    },
+    /// Withdraws only if `account` is still at `expected_version`, so a client can act
+    /// on a balance it read without another write slipping in between.
+    ConditionalWithdraw {
+        account: String,
+        amount: i64,
+        expected_version: u64,
+    },
+    /// Changes the cluster membership recorded in the log.
+    ConfigChange(MembershipChange),
+    /// A command of a kind only a newer version understands, kept as written.
+    Unknown { kind: u32, command: Bytes },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MembershipChange {
+    AddNode { node_id: String, raft_addr: String },
+    RemoveNode { node_id: String },
+    /// Adds a member that votes and counts toward the commit quorum but stores no log.
+    AddWitness { node_id: String, raft_addr: String },
+}
+
+impl BankCommand {
+    pub fn encode(&self) -> std::io::Result<Bytes> {
+        if let BankCommand::Unknown { command, .. } = self {
+            return Ok(command.clone());
+        }
+        Ok(Bytes::from(pb::Command::from(self).encode_to_vec()))
+    }
+
+    /// Short name of the command's kind, as dumps of the log show it.
+    pub fn kind_name(&self) -> &'static str {
+        match self {
+            BankCommand::CreateAccount { .. } => "create_account",
+            BankCommand::Transfer { .. } => "transfer",
+            BankCommand::BatchTransfer { .. } => "batch_transfer",
+            BankCommand::Withdraw { .. } => "withdraw",
+            BankCommand::SetOverdraftLimit { .. } => "set_overdraft_limit",
+            BankCommand::SetAccountStatus { .. } => "set_account_status",
+            BankCommand::SagaDebit { .. } => "saga_debit",
+            BankCommand::SagaCredit { .. } => "saga_credit",
+            BankCommand::SagaFinish { .. } => "saga_finish",
+            BankCommand::Deposit { .. } => "deposit",
+            BankCommand::ConditionalWithdraw { .. } => "conditional_withdraw",
+            BankCommand::ConfigChange(MembershipChange::AddNode { .. }) => "add_node",
+            BankCommand::ConfigChange(MembershipChange::RemoveNode { .. }) => "remove_node",
+            BankCommand::ConfigChange(MembershipChange::AddWitness { .. }) => "add_witness",
+            BankCommand::Unknown { .. } => "unknown",
+        }
+    }
+
+    /// The command's fields as a JSON object, for human-readable dumps of the log.
+    pub fn to_json(&self) -> serde_json::Value {
+        use serde_json::json;
+
+        match self {
+            BankCommand::CreateAccount { account, initial_balance } => {
+                json!({ "account": account, "initial_balance": initial_balance })
+            }
+            BankCommand::Transfer { from, to, amount, client_tx_id } => {
+                json!({ "from": from, "to": to, "amount": amount, "client_tx_id": client_tx_id })
+            }
+            BankCommand::BatchTransfer { transfers, client_tx_id } => {
+                let transfers: Vec<_> = transfers
+                    .iter()
+                    .map(|Transfer { from, to, amount }| {
+                        json!({ "from": from, "to": to, "amount": amount })
+                    })
+                    .collect();
+                json!({ "transfers": transfers, "client_tx_id": client_tx_id })
+            }
+            BankCommand::Withdraw { account, amount, client_tx_id }
+            | BankCommand::Deposit { account, amount, client_tx_id } => {
+                json!({ "account": account, "amount": amount, "client_tx_id": client_tx_id })
+            }
+            BankCommand::ConditionalWithdraw { account, amount, expected_version } => {
+                json!({
+                    "account": account,
+                    "amount": amount,
+                    "expected_version": expected_version,
+                })
+            }
+            BankCommand::SetOverdraftLimit { account, overdraft_limit } => {
+                json!({ "account": account, "overdraft_limit": overdraft_limit })
+            }
+            BankCommand::SetAccountStatus { account, frozen, block_credits } => {
+                json!({ "account": account, "frozen": frozen, "block_credits": block_credits })
+            }
+            BankCommand::SagaDebit { saga_id, from, to, amount }
+            | BankCommand::SagaCredit { saga_id, from, to, amount } => {
+                json!({ "saga_id": saga_id, "from": from, "to": to, "amount": amount })
+            }
+            BankCommand::SagaFinish { saga_id, commit } => {
+                json!({ "saga_id": saga_id, "commit": commit })
+            }
+            BankCommand::ConfigChange(
+                MembershipChange::AddNode { node_id, raft_addr }
+                | MembershipChange::AddWitness { node_id, raft_addr },
+            ) => json!({ "node_id": node_id, "raft_addr": raft_addr }),
+            BankCommand::ConfigChange(MembershipChange::RemoveNode { node_id }) => {
+                json!({ "node_id": node_id })
+            }
+            BankCommand::Unknown { kind, .. } => json!({ "kind": kind }),
+        }
+    }
+
+    /// Decodes a command, skipping fields this version does not know about. A command
+    /// whose kind is unknown decodes to `Unknown` rather than failing.
+    pub fn decode(bytes: &[u8]) -> std::io::Result<Self> {
+        let command = pb::Command::decode(bytes).map_err(invalid_data)?;
+        if let Some(command) = command.kind.and_then(Self::from_proto) {
+            return Ok(command);
+        }
+
+        let (kind, _) = decode_key(&mut &bytes[..])
+            .map_err(|_| invalid_data("command is empty"))?;
+        Ok(BankCommand::Unknown {
+            kind,
+            command: Bytes::copy_from_slice(bytes),
+        })
+    }
+}
+
+impl From<&BankCommand> for pb::Command {
+    fn from(command: &BankCommand) -> Self {
+        use pb::command::Kind;
+
+        let kind = match command.clone() {
+            BankCommand::CreateAccount { account, initial_balance } => {
+                Kind::CreateAccount(pb::CreateAccount { account, initial_balance })
+            }
+            BankCommand::Transfer { from, to, amount, client_tx_id } => {
+                Kind::Transfer(pb::Transfer { from, to, amount, client_tx_id })
+            }
+            BankCommand::BatchTransfer { transfers, client_tx_id } => {
+                let transfers = transfers
+                    .into_iter()
+                    .map(|Transfer { from, to, amount }| pb::TransferLeg { from, to, amount })
+                    .collect();
+                Kind::BatchTransfer(pb::BatchTransfer { transfers, client_tx_id })
+            }
+            BankCommand::Withdraw { account, amount, client_tx_id } => {
+                Kind::Withdraw(pb::Withdraw { account, amount, client_tx_id })
+            }
+            BankCommand::SetOverdraftLimit { account, overdraft_limit } => {
+                Kind::SetOverdraftLimit(pb::SetOverdraftLimit { account, overdraft_limit })
+            }
+            BankCommand::SetAccountStatus { account, frozen, block_credits } => {
+                Kind::SetAccountStatus(pb::SetAccountStatus { account, frozen, block_credits })
+            }
+            BankCommand::SagaDebit { saga_id, from, to, amount } => {
+                Kind::SagaDebit(pb::SagaDebit { saga_id, from, to, amount })
+            }
+            BankCommand::SagaCredit { saga_id, from, to, amount } => {
+                Kind::SagaCredit(pb::SagaCredit { saga_id, from, to, amount })
+            }
+            BankCommand::SagaFinish { saga_id, commit } => {
+                Kind::SagaFinish(pb::SagaFinish { saga_id, commit })
+            }
+            BankCommand::Deposit { account, amount, client_tx_id } => {
+                Kind::Deposit(pb::Deposit { account, amount, client_tx_id })
+            }
+            BankCommand::ConditionalWithdraw { account, amount, expected_version } => {
+                Kind::ConditionalWithdraw(pb::ConditionalWithdraw {
+                    account,
+                    amount,
+                    expected_version,
+                })
+            }
+            BankCommand::ConfigChange(change) => {
+                use pb::config_change::Change;
+
+                let change = match change {
+                    MembershipChange::AddNode { node_id, raft_addr } => {
+                        Change::AddNode(pb::AddNode { node_id, raft_addr })
+                    }
+                    MembershipChange::RemoveNode { node_id } => {
+                        Change::RemoveNode(pb::RemoveNode { node_id })
+                    }
+                    MembershipChange::AddWitness { node_id, raft_addr } => {
+                        Change::AddWitness(pb::AddWitness { node_id, raft_addr })
+                    }
+                };
+                Kind::ConfigChange(pb::ConfigChange { change: Some(change) })
+            }
+            // Written out verbatim by `encode`.
+            BankCommand::Unknown { .. } => return pb::Command::default(),
+        };
+
+        pb::Command { kind: Some(kind) }
+    }
+}
+
+impl BankCommand {
+    /// Converts a decoded command, or returns `None` if part of it is of an unknown kind.
+    fn from_proto(kind: pb::command::Kind) -> Option<Self> {
+        use pb::command::Kind;
+
+        let command = match kind {
+            Kind::CreateAccount(pb::CreateAccount { account, initial_balance }) => {
+                BankCommand::CreateAccount { account, initial_balance }
+            }
+            Kind::Transfer(pb::Transfer { from, to, amount, client_tx_id }) => {
+                BankCommand::Transfer { from, to, amount, client_tx_id }
+            }
+            Kind::BatchTransfer(pb::BatchTransfer { transfers, client_tx_id }) => {
+                let transfers = transfers
+                    .into_iter()
+                    .map(|pb::TransferLeg { from, to, amount }| Transfer { from, to, amount })
+                    .collect();
+                BankCommand::BatchTransfer { transfers, client_tx_id }
+            }
+            Kind::Withdraw(pb::Withdraw { account, amount, client_tx_id }) => {
+                BankCommand::Withdraw { account, amount, client_tx_id }
+            }
+            Kind::SetOverdraftLimit(pb::SetOverdraftLimit { account, overdraft_limit }) => {
+                BankCommand::SetOverdraftLimit { account, overdraft_limit }
+            }
+            Kind::SetAccountStatus(pb::SetAccountStatus { account, frozen, block_credits }) => {
+                BankCommand::SetAccountStatus { account, frozen, block_credits }
+            }
+            Kind::SagaDebit(pb::SagaDebit { saga_id, from, to, amount }) => {
+                BankCommand::SagaDebit { saga_id, from, to, amount }
+            }
+            Kind::SagaCredit(pb::SagaCredit { saga_id, from, to, amount }) => {
+                BankCommand::SagaCredit { saga_id, from, to, amount }
+            }
+            Kind::SagaFinish(pb::SagaFinish { saga_id, commit }) => {
+                BankCommand::SagaFinish { saga_id, commit }
+            }
+            Kind::Deposit(pb::Deposit { account, amount, client_tx_id }) => {
+                BankCommand::Deposit { account, amount, client_tx_id }
+            }
+            Kind::ConditionalWithdraw(pb::ConditionalWithdraw {
+                account,
+                amount,
+                expected_version,
+            }) => BankCommand::ConditionalWithdraw { account, amount, expected_version },
+            Kind::ConfigChange(pb::ConfigChange { change }) => {
+                use pb::config_change::Change;
+
+                let change = match change {
+                    Some(Change::AddNode(pb::AddNode { node_id, raft_addr })) => {
+                        MembershipChange::AddNode { node_id, raft_addr }
+                    }
+                    Some(Change::RemoveNode(pb::RemoveNode { node_id })) => {
+                        MembershipChange::RemoveNode { node_id }
+                    }
+                    Some(Change::AddWitness(pb::AddWitness { node_id, raft_addr })) => {
+                        MembershipChange::AddWitness { node_id, raft_addr }
+                    }
+                    None => return None,
+                };
+                BankCommand::ConfigChange(change)
+            }
+        };
+
+        Some(command)
+    }
+}
+
+fn invalid_data<E>(e: E) -> std::io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use prost::encoding::{encode_key, encode_varint, WireType};
+
+    fn roundtrip(command: BankCommand) {
+        let encoded = command.encode().unwrap();
+        let decoded = BankCommand::decode(&encoded).unwrap();
+
+        assert_eq!(command, decoded);
+    }
+
+    /// Appends a varint field numbered `tag`, as a newer version might add.
+    fn unknown_field(buf: &mut Vec<u8>, tag: u32) {
+        encode_key(tag, WireType::Varint, buf);
+        encode_varint(42, buf);
+    }
+
+    #[test]
+    fn test_bank_command_create_account_roundtrip() {
+        roundtrip(BankCommand::CreateAccount {
+            account: "alice".to_string(),
+            initial_balance: 1_000,
+        });
+    }
+
+    #[test]
+    fn test_bank_command_transfer_roundtrip() {
+        roundtrip(BankCommand::Transfer {
+            from: "alice".to_string(),
+            to: "bob".to_string(),
+            amount: 250,
+            client_tx_id: "tx-1".to_string(),
+        });
+    }
+
+    #[test]
+    fn test_bank_command_batch_transfer_roundtrip() {
+        roundtrip(BankCommand::BatchTransfer {
+            transfers: vec![
+                Transfer { from: "payroll".to_string(), to: "alice".to_string(), amount: 100 },
+                Transfer { from: "payroll".to_string(), to: "bob".to_string(), amount: 200 },
+            ],
+            client_tx_id: "batch-1".to_string(),
+        });
+    }
+
+    #[test]
+    fn test_bank_command_withdraw_roundtrip() {
+        roundtrip(BankCommand::Withdraw {
+            account: "alice".to_string(),
+            amount: 75,
+            client_tx_id: "w-1".to_string(),
+        });
+    }
+
+    #[test]
+    fn test_bank_command_set_overdraft_limit_roundtrip() {
+        roundtrip(BankCommand::SetOverdraftLimit {
+            account: "alice".to_string(),
+            overdraft_limit: 500,
+        });
+    }
+
+    #[test]
+    fn test_bank_command_set_account_status_roundtrip() {
+        roundtrip(BankCommand::SetAccountStatus {
+            account: "alice".to_string(),
+            frozen: true,
+            block_credits: true,
+        });
+    }
+
+    #[test]
+    fn test_bank_command_saga_roundtrip() {
+        roundtrip(BankCommand::SagaDebit {
+            saga_id: "saga-1".to_string(),
+            from: "alice".to_string(),
+            to: "bob".to_string(),
+            amount: 40,
+        });
+        roundtrip(BankCommand::SagaCredit {
+            saga_id: "saga-1".to_string(),
+            from: "alice".to_string(),
+            to: "bob".to_string(),
+            amount: 40,
+        });
+        roundtrip(BankCommand::SagaFinish {
+            saga_id: "saga-1".to_string(),
+            commit: true,
+        });
+        roundtrip(BankCommand::SagaFinish {
+            saga_id: "saga-1".to_string(),
+            commit: false,
+        });
+    }
+
+    #[test]
+    fn test_bank_command_deposit_roundtrip() {
+        roundtrip(BankCommand::Deposit {
+            account: "alice".to_string(),
+            amount: 300,
+            client_tx_id: "d-1".to_string(),
+        });
+    }
+
+    #[test]
+    fn test_bank_command_conditional_withdraw_roundtrip() {
+        roundtrip(BankCommand::ConditionalWithdraw {
+            account: "alice".to_string(),
+            amount: 300,
+            expected_version: 4,
+        });
+    }
+
+    #[test]
+    fn test_bank_command_config_change_roundtrip() {
+        roundtrip(BankCommand::ConfigChange(MembershipChange::AddNode {
+            node_id: "node-4".to_string(),
+            raft_addr: "10.0.0.4:50061".to_string(),
+        }));
+        roundtrip(BankCommand::ConfigChange(MembershipChange::RemoveNode {
+            node_id: "node-2".to_string(),
+        }));
+        roundtrip(BankCommand::ConfigChange(MembershipChange::AddWitness {
+            node_id: "witness-1".to_string(),
+            raft_addr: "10.0.0.9:50061".to_string(),
+        }));
+    }
+
+    #[test]
+    fn test_bank_command_tolerates_unknown_fields() {
+        let transfer = pb::Transfer {
+            from: "alice".to_string(),
+            to: "bob".to_string(),
+            amount: 250,
+            client_tx_id: "tx-1".to_string(),
+        };
+        let mut nested = transfer.encode_to_vec();
+        unknown_field(&mut nested, 15);
+
+        let mut encoded = Vec::new();
+        prost::encoding::bytes::encode(2, &nested, &mut encoded);
+        unknown_field(&mut encoded, 100);
+
+        let decoded = BankCommand::decode(&encoded).unwrap();
+        assert_eq!(
+            decoded,
+            BankCommand::Transfer {
+                from: "alice".to_string(),
+                to: "bob".to_string(),
+                amount: 250,
+                client_tx_id: "tx-1".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_bank_command_decode_unknown_kind() {
+        // Only a command kind this version does not know about.
+        let mut encoded = Vec::new();
+        prost::encoding::bytes::encode(99, &b"future".to_vec(), &mut encoded);
+
+        let decoded = BankCommand::decode(&encoded).unwrap();
+        assert_eq!(
+            decoded,
+            BankCommand::Unknown {
+                kind: 99,
+                command: Bytes::from(encoded.clone()),
+            }
+        );
+        assert_eq!(decoded.encode().unwrap(), encoded);
+
+        // A config change of a kind added later is kept whole too.
+        let mut change = Vec::new();
+        unknown_field(&mut change, 4);
+        let mut encoded = Vec::new();
+        prost::encoding::bytes::encode(10, &change, &mut encoded);
+        assert!(matches!(BankCommand::decode(&encoded), Ok(BankCommand::Unknown { kind: 10, .. })));
+    }
+
+    #[test]
+    fn test_bank_command_decode_malformed() {
+        for bytes in [&[0xFF][..], &[]] {
+            let result = BankCommand::decode(bytes);
+            assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        }
+    }
+}
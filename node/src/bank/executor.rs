@@ -0,0 +1,316 @@
+use std::collections::{BTreeMap, BTreeSet};
+use crate::bank::command::BankCommand;
+use crate::bank::state_machine::{BankStateMachine, CommandOutcome};
+use crate::wal::LogEntry;
+
+/// A piece of bank state a command reads or writes. Commands whose keys do not overlap
+/// are independent of each other.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum StateKey {
+    Account(String),
+    /// A `client_tx_id` or saga id, whose first outcome is remembered.
+    ClientTx(String),
+    Saga(String),
+}
+
+/// Applies batches of committed entries, running commands on disjoint accounts in
+/// parallel. The result is the same as applying the batch in log order: commands that
+/// share any state run in log order, and those that touch state shared by every
+/// command (membership, unknown kinds, finishing a saga, opening an account under a
+/// generated id) run on their own.
+#[derive(Clone, Copy, Debug)]
+pub struct ApplyExecutor {
+    workers: usize,
+}
+
+impl ApplyExecutor {
+    /// Runs up to `workers` groups of independent commands at once; 1 applies serially.
+    pub fn new(workers: usize) -> Self {
+        Self {
+            workers: workers.max(1),
+        }
+    }
+
+    /// Applies `entries`, which follow `sm.last_applied()`, and returns the outcome of
+    /// each in order. Fails without applying anything if a command does not decode.
+    pub fn apply(
+        &self,
+        sm: &mut BankStateMachine,
+        entries: &[LogEntry],
+    ) -> std::io::Result<Vec<CommandOutcome>> {
+        self.apply_observed(sm, entries, &|_| {})
+    }
+
+    /// Like `apply`, calling `observe` with each entry's index just before applying it.
+    fn apply_observed(
+        &self,
+        sm: &mut BankStateMachine,
+        entries: &[LogEntry],
+        observe: &(dyn Fn(u64) + Sync),
+    ) -> std::io::Result<Vec<CommandOutcome>> {
+        let decoded = entries
+            .iter()
+            .map(|entry| BankCommand::decode(entry.command_slice()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let keys: Vec<_> = decoded.iter().map(state_keys).collect();
+        let mut commands: Vec<Option<BankCommand>> = decoded.into_iter().map(Some).collect();
+        let mut take = |i: usize| (i, entries[i].index, commands[i].take().unwrap());
+
+        let mut outcomes = vec![None; entries.len()];
+        for wave in plan(&keys) {
+            if wave.len() == 1 || self.workers == 1 {
+                for (i, index, command) in wave.into_iter().map(&mut take) {
+                    observe(index);
+                    outcomes[i] = Some(sm.apply_command(index, command));
+                }
+                continue;
+            }
+
+            // Deal the wave out to the workers, each applying its share to its own part of
+            // the state.
+            let mut groups = vec![Vec::new(); self.workers.min(wave.len())];
+            let group_count = groups.len();
+            for (n, i) in wave.into_iter().enumerate() {
+                groups[n % group_count].push(i);
+            }
+            let jobs: Vec<_> = groups
+                .into_iter()
+                .map(|group| {
+                    let group_keys: BTreeSet<StateKey> =
+                        group.iter().flat_map(|&i| keys[i].iter().flatten().cloned()).collect();
+                    let part = sm.split_off(&group_keys);
+                    (part, group.into_iter().map(&mut take).collect::<Vec<_>>())
+                })
+                .collect();
+
+            let done: Vec<_> = std::thread::scope(|scope| {
+                let workers: Vec<_> = jobs
+                    .into_iter()
+                    .map(|(mut part, commands)| {
+                        scope.spawn(move || {
+                            let outcomes: Vec<_> = commands
+                                .into_iter()
+                                .map(|(i, index, command)| {
+                                    observe(index);
+                                    (i, part.apply_command(index, command))
+                                })
+                                .collect();
+                            (part, outcomes)
+                        })
+                    })
+                    .collect();
+                workers
+                    .into_iter()
+                    .map(|worker| worker.join().expect("apply worker panicked"))
+                    .collect()
+            });
+            for (part, group_outcomes) in done {
+                sm.absorb(part);
+                for (i, outcome) in group_outcomes {
+                    outcomes[i] = Some(outcome);
+                }
+            }
+        }
+
+        if let Some(last) = entries.last() {
+            sm.set_last_applied(last.index);
+        }
+        Ok(outcomes.into_iter().map(|outcome| outcome.expect("every command is applied")).collect())
+    }
+}
+
+impl Default for ApplyExecutor {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
+/// The state `command` touches, or `None` if it may touch anything.
+fn state_keys(command: &BankCommand) -> Option<BTreeSet<StateKey>> {
+    let account = |id: &str| StateKey::Account(id.to_string());
+    let client_tx = |id: &str| StateKey::ClientTx(id.to_string());
+    let keys = match command {
+        // A generated account id is only known once the command applies.
+        BankCommand::CreateAccount { account: id, .. } if id.is_empty() => return None,
+        BankCommand::CreateAccount { account: id, .. }
+        | BankCommand::SetOverdraftLimit { account: id, .. }
+        | BankCommand::SetAccountStatus { account: id, .. }
+        | BankCommand::ConditionalWithdraw { account: id, .. } => [account(id)].into(),
+        BankCommand::Transfer { from, to, client_tx_id, .. } => {
+            [account(from), account(to), client_tx(client_tx_id)].into()
+        }
+        BankCommand::BatchTransfer { transfers, client_tx_id } => transfers
+            .iter()
+            .flat_map(|transfer| [account(&transfer.from), account(&transfer.to)])
+            .chain([client_tx(client_tx_id)])
+            .collect(),
+        BankCommand::Withdraw { account: id, client_tx_id, .. }
+        | BankCommand::Deposit { account: id, client_tx_id, .. } => {
+            [account(id), client_tx(client_tx_id)].into()
+        }
+        BankCommand::SagaDebit { saga_id, from, .. } => {
+            [account(from), client_tx(saga_id), StateKey::Saga(saga_id.clone())].into()
+        }
+        BankCommand::SagaCredit { saga_id, to, .. } => [account(to), client_tx(saga_id)].into(),
+        // Which account a saga refunds is only known from the saga itself.
+        BankCommand::SagaFinish { .. }
+        | BankCommand::ConfigChange(_)
+        | BankCommand::Unknown { .. } => return None,
+    };
+    Some(keys)
+}
+
+/// Sorts commands with `keys` into waves to apply one after another. No two commands in
+/// a wave share a key, and each comes in a later wave than every earlier command it
+/// shares a key with. A command without keys gets a wave of its own, after every
+/// command before it and before every command after it.
+fn plan(keys: &[Option<BTreeSet<StateKey>>]) -> Vec<Vec<usize>> {
+    let mut waves: Vec<Vec<usize>> = Vec::new();
+    let mut last_wave: BTreeMap<&StateKey, usize> = BTreeMap::new();
+    // The first wave a command may join: the one after the last command without keys.
+    let mut floor = 0;
+
+    for (i, command_keys) in keys.iter().enumerate() {
+        let wave = match command_keys {
+            None => {
+                floor = waves.len() + 1;
+                waves.len()
+            }
+            Some(command_keys) => command_keys
+                .iter()
+                .filter_map(|key| last_wave.get(key))
+                .map(|wave| wave + 1)
+                .fold(floor, usize::max),
+        };
+        if wave == waves.len() {
+            waves.push(Vec::new());
+        }
+        waves[wave].push(i);
+        for key in command_keys.iter().flatten() {
+            last_wave.insert(key, wave);
+        }
+    }
+
+    waves
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use crate::bank::tests::{
+        batch_transfer, command_entry, create_account, saga_debit, saga_finish, transfer,
+        withdraw,
+    };
+
+    fn entries(commands: Vec<BankCommand>) -> Vec<LogEntry> {
+        (1..).zip(commands).map(|(index, command)| command_entry(index, command)).collect()
+    }
+
+    fn waves(commands: &[BankCommand]) -> Vec<Vec<usize>> {
+        plan(&commands.iter().map(state_keys).collect::<Vec<_>>())
+    }
+
+    /// Applies `entries` with `workers`, returning the most commands seen applying at once.
+    fn max_concurrency(workers: usize, sm: &mut BankStateMachine, entries: &[LogEntry]) -> usize {
+        let (running, max) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        let observe = |_| {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max.fetch_max(now, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(50));
+            running.fetch_sub(1, Ordering::SeqCst);
+        };
+        ApplyExecutor::new(workers).apply_observed(sm, entries, &observe).unwrap();
+        max.load(Ordering::SeqCst)
+    }
+
+    fn accounts(names: &[&str]) -> BankStateMachine {
+        let mut sm = BankStateMachine::new();
+        let commands = names.iter().map(|name| create_account(name, 100)).collect();
+        ApplyExecutor::default().apply(&mut sm, &entries(commands)).unwrap();
+        sm
+    }
+
+    #[test]
+    fn test_plan_separates_conflicting_commands() {
+        let commands = [
+            transfer("alice", "bob", 10, "tx-1"),
+            transfer("carol", "dave", 10, "tx-2"),
+            transfer("bob", "erin", 10, "tx-3"),
+            withdraw("frank", 10, "tx-4"),
+            saga_finish("saga-1", true),
+            withdraw("frank", 10, "tx-5"),
+        ];
+
+        assert_eq!(waves(&commands), vec![vec![0, 1, 3], vec![2], vec![4], vec![5]]);
+    }
+
+    #[test]
+    fn test_plan_orders_by_client_tx_id() {
+        // Different accounts, but a retry of the same transaction must see the first.
+        let commands = [transfer("alice", "bob", 10, "tx-1"), withdraw("carol", 10, "tx-1")];
+        assert_eq!(waves(&commands), vec![vec![0], vec![1]]);
+    }
+
+    #[test]
+    fn test_executor_applies_disjoint_transfers_concurrently() {
+        let mut sm = accounts(&["alice", "bob", "carol", "dave"]);
+        let disjoint = entries(vec![
+            transfer("alice", "bob", 10, "tx-1"),
+            transfer("carol", "dave", 10, "tx-2"),
+        ]);
+
+        assert_eq!(max_concurrency(2, &mut sm, &disjoint), 2);
+        assert_eq!(sm.balance("bob"), Some(110));
+        assert_eq!(sm.balance("dave"), Some(110));
+    }
+
+    #[test]
+    fn test_executor_serializes_conflicting_transfers() {
+        let mut sm = accounts(&["alice", "bob", "carol"]);
+        let conflicting = entries(vec![
+            transfer("alice", "bob", 10, "tx-1"),
+            transfer("bob", "carol", 110, "tx-2"),
+        ]);
+
+        assert_eq!(max_concurrency(2, &mut sm, &conflicting), 1);
+        assert_eq!(sm.balance("bob"), Some(0));
+        assert_eq!(sm.balance("carol"), Some(210));
+    }
+
+    #[test]
+    fn test_executor_matches_serial_apply() {
+        let names = ["alice", "bob", "carol", "dave", "erin", "frank"];
+        let mut commands: Vec<BankCommand> =
+            names.iter().map(|name| create_account(name, 100)).collect();
+        for round in 0..20 {
+            let from = names[round % names.len()];
+            let to = names[(round * 7 + 1) % names.len()];
+            commands.push(transfer(from, to, 15 + round as i64, &format!("tx-{}", round)));
+            commands.push(withdraw(names[(round * 3) % names.len()], 5, &format!("w-{}", round)));
+        }
+        commands.push(transfer("alice", "bob", 1_000, "tx-overdrawn"));
+        commands.push(transfer("alice", "bob", 10, "tx-1"));
+        commands.push(batch_transfer(&[("carol", "dave", 5), ("dave", "erin", 5)], "batch-1"));
+        commands.push(saga_debit("saga-1", "frank", "zed", 20));
+        commands.push(saga_finish("saga-1", false));
+        commands.push(create_account("alice", 5));
+        let entries = entries(commands);
+
+        let mut serial = BankStateMachine::new();
+        let serial_outcomes: Vec<_> =
+            entries.iter().map(|entry| serial.apply(entry).unwrap()).collect();
+        let mut parallel = BankStateMachine::new();
+        let parallel_outcomes = ApplyExecutor::new(4).apply(&mut parallel, &entries).unwrap();
+
+        assert_eq!(parallel_outcomes, serial_outcomes);
+        assert_eq!(parallel.last_applied(), serial.last_applied());
+        for name in names {
+            assert_eq!(parallel.account(name), serial.account(name), "{}", name);
+            assert_eq!(parallel.history(name, 0), serial.history(name, 0), "{}", name);
+        }
+        assert_eq!(parallel.transfer_status("tx-7"), serial.transfer_status("tx-7"));
+        assert_eq!(parallel.saga("saga-1"), serial.saga("saga-1"));
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::{BTreeMap, VecDeque};
+
+/// Number of entries kept per account unless configured otherwise.
+pub const DEFAULT_HISTORY_LIMIT: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OperationKind {
+    Open,
+    TransferIn,
+    TransferOut,
+    Withdraw,
+    Deposit,
+}
+
+/// One applied operation as it appears on an account statement.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub kind: OperationKind,
+    pub counterparty: Option<String>,
+    pub amount: i64,
+    /// Balance of the account right after this operation.
+    pub balance: i64,
+    /// Log index of the command that produced this operation.
+    pub index: u64,
+}
+
+/// Per-account ledgers that keep only the most recent `limit` operations.
+///
+/// The ledgers are derived state: replaying the WAL rebuilds them exactly.
+#[derive(Clone, Debug)]
+pub struct History {
+    ledgers: BTreeMap<String, VecDeque<HistoryEntry>>,
+    limit: usize,
+}
+
+impl History {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            ledgers: BTreeMap::new(),
+            limit,
+        }
+    }
+
+    pub fn record(&mut self, account: &str, entry: HistoryEntry) {
+        let ledger = self.ledgers.entry(account.to_string()).or_default();
+        if ledger.len() == self.limit {
+            ledger.pop_front();
+        }
+        if self.limit > 0 {
+            ledger.push_back(entry);
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Removes the ledger of `account`, to be handed back with `put` or `absorb`.
+    pub(crate) fn take(&mut self, account: &str) -> Option<VecDeque<HistoryEntry>> {
+        self.ledgers.remove(account)
+    }
+
+    pub(crate) fn put(&mut self, account: &str, ledger: VecDeque<HistoryEntry>) {
+        self.ledgers.insert(account.to_string(), ledger);
+    }
+
+    /// Every ledger, by account.
+    pub(crate) fn ledgers(&self) -> impl Iterator<Item = (&str, &VecDeque<HistoryEntry>)> {
+        self.ledgers.iter().map(|(account, ledger)| (account.as_str(), ledger))
+    }
+
+    /// Moves every ledger of `other` in, replacing ours for the same account.
+    pub(crate) fn absorb(&mut self, other: History) {
+        self.ledgers.extend(other.ledgers);
+    }
+
+    /// Returns up to `limit` of the newest entries for `account`, oldest first.
+    /// A `limit` of zero returns everything retained.
+    pub fn recent(&self, account: &str, limit: usize) -> Vec<HistoryEntry> {
+        let Some(ledger) = self.ledgers.get(account) else {
+            return Vec::new();
+        };
+        let limit = if limit == 0 { ledger.len() } else { limit.min(ledger.len()) };
+
+        ledger.iter().skip(ledger.len() - limit).cloned().collect()
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(DEFAULT_HISTORY_LIMIT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn withdrawal(index: u64) -> HistoryEntry {
+        HistoryEntry {
+            kind: OperationKind::Withdraw,
+            counterparty: None,
+            amount: 1,
+            balance: -(index as i64),
+            index,
+        }
+    }
+
+    #[test]
+    fn test_history_recent_returns_newest_oldest_first() {
+        let mut history = History::new(10);
+        for index in 1..=5 {
+            history.record("alice", withdrawal(index));
+        }
+
+        let indexes: Vec<u64> = history.recent("alice", 3).iter().map(|e| e.index).collect();
+        assert_eq!(indexes, vec![3, 4, 5]);
+        assert_eq!(history.recent("alice", 0).len(), 5);
+        assert!(history.recent("bob", 3).is_empty());
+    }
+
+    #[test]
+    fn test_history_cap_evicts_oldest() {
+        let mut history = History::new(2);
+        for index in 1..=4 {
+            history.record("alice", withdrawal(index));
+        }
+
+        let indexes: Vec<u64> = history.recent("alice", 0).iter().map(|e| e.index).collect();
+        assert_eq!(indexes, vec![3, 4]);
+    }
+
+    #[test]
+    fn test_history_zero_limit_keeps_nothing() {
+        let mut history = History::new(0);
+        history.record("alice", withdrawal(1));
+        assert!(history.recent("alice", 0).is_empty());
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::BTreeMap;
+use crate::bank::command::BankCommand;
+use crate::bank::state_machine::{BankStateMachine, SagaPhase};
+
+/// What the state looked like before a command applied, to check the state it left
+/// against. Only debug builds take one: applying a command checks that
+///
+/// - the total of all balances changed by nothing, or by exactly the money the command
+///   pays in or takes out, so transfers conserve it, and
+/// - no balance the command lowered ended up below the account's overdraft limit.
+///
+/// A violation is a bug in the state machine, so it panics rather than carrying on from
+/// a state no replica should have.
+#[derive(Debug)]
+pub(super) struct Baseline {
+    kind: &'static str,
+    balances: BTreeMap<String, i64>,
+    total: i128,
+    /// What applying the command adds to the total, if it does anything; negative if it
+    /// takes money out.
+    pays_in: i64,
+}
+
+impl Baseline {
+    pub(super) fn of(sm: &BankStateMachine, command: &BankCommand) -> Self {
+        let balances: BTreeMap<String, i64> =
+            sm.accounts.iter().map(|(id, account)| (id.clone(), account.balance)).collect();
+        let total = balances.values().map(|&balance| i128::from(balance)).sum();
+        Self { kind: command.kind_name(), balances, total, pays_in: pays_in(sm, command) }
+    }
+
+    /// Panics, describing the violation, if `sm` breaks an invariant after entry `index`.
+    pub(super) fn check(&self, sm: &BankStateMachine, index: u64) {
+        let total: i128 = sm.accounts.values().map(|account| i128::from(account.balance)).sum();
+        let change = total - self.total;
+        assert!(
+            change == 0 || change == i128::from(self.pays_in),
+            "invariant violated by {} at entry {}: the total of all balances changed by {}, \
+             but the command may only change it by 0 or {}",
+            self.kind,
+            index,
+            change,
+            self.pays_in
+        );
+
+        for (id, account) in &sm.accounts {
+            let lowered = self.balances.get(id).is_some_and(|&before| account.balance < before);
+            assert!(
+                !lowered || account.balance >= -account.overdraft_limit,
+                "invariant violated by {} at entry {}: it left {:?} at a balance of {}, below \
+                 its overdraft limit of {}",
+                self.kind,
+                index,
+                id,
+                account.balance,
+                account.overdraft_limit
+            );
+        }
+    }
+}
+
+/// What `command` adds to the total of all balances if it applies in full.
+fn pays_in(sm: &BankStateMachine, command: &BankCommand) -> i64 {
+    match command {
+        BankCommand::CreateAccount { initial_balance, .. } => *initial_balance,
+        BankCommand::Deposit { amount, .. } | BankCommand::SagaCredit { amount, .. } => *amount,
+        BankCommand::Withdraw { amount, .. }
+        | BankCommand::ConditionalWithdraw { amount, .. }
+        | BankCommand::SagaDebit { amount, .. } => amount.saturating_neg(),
+        // Compensating refunds the source; settling leaves the funds with the other shard.
+        BankCommand::SagaFinish { saga_id, commit: false } => sm
+            .saga(saga_id)
+            .filter(|saga| saga.phase == SagaPhase::Reserved)
+            .map_or(0, |saga| saga.amount),
+        BankCommand::Transfer { .. }
+        | BankCommand::BatchTransfer { .. }
+        | BankCommand::SagaFinish { .. }
+        | BankCommand::SetOverdraftLimit { .. }
+        | BankCommand::SetAccountStatus { .. }
+        | BankCommand::ConfigChange(_)
+        | BankCommand::Unknown { .. } => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bank::tests::{command_entry, create_account, transfer};
+    use crate::bank::TransferOutcome;
+
+    fn alice_and_bob() -> BankStateMachine {
+        let mut sm = BankStateMachine::new();
+        sm.apply(&command_entry(1, create_account("alice", 100))).unwrap();
+        sm.apply(&command_entry(2, create_account("bob", 0))).unwrap();
+        sm
+    }
+
+    #[test]
+    fn test_invariants_hold_for_a_transfer() {
+        let mut sm = alice_and_bob();
+        let command = transfer("alice", "bob", 30, "tx-1");
+        let baseline = Baseline::of(&sm, &command);
+        sm.apply(&command_entry(3, command)).unwrap();
+        baseline.check(&sm, 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "invariant violated by transfer at entry 3: the total of all \
+                               balances changed by -30")]
+    fn test_a_transfer_that_loses_money_trips_the_invariant() {
+        let mut sm = alice_and_bob();
+        let command = transfer("alice", "bob", 30, "tx-1");
+        let baseline = Baseline::of(&sm, &command);
+
+        // A buggy transfer: it takes the money out of alice but never pays bob.
+        let account = sm.accounts.get_mut("alice").unwrap();
+        account.balance -= 30;
+        sm.transfers.insert("tx-1".to_string(), TransferOutcome::Ok);
+        baseline.check(&sm, 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "it left \"alice\" at a balance of -20, below its overdraft \
+                               limit of 0")]
+    fn test_a_transfer_past_the_overdraft_limit_trips_the_invariant() {
+        let mut sm = alice_and_bob();
+        let command = transfer("alice", "bob", 120, "tx-1");
+        let baseline = Baseline::of(&sm, &command);
+
+        // A buggy transfer: it moves the money without checking alice can cover it.
+        sm.accounts.get_mut("alice").unwrap().balance -= 120;
+        sm.accounts.get_mut("bob").unwrap().balance += 120;
+        baseline.check(&sm, 3);
+    }
+}
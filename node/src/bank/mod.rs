@@ -0,0 +1,22 @@
+mod command;
+mod executor;
+mod history;
+#[cfg(debug_assertions)]
+mod invariants;
+mod snapshot;
+mod state_machine;
+mod tenant;
+mod validation;
+
+pub use command::{BankCommand, MembershipChange, Transfer};
+pub use executor::ApplyExecutor;
+pub use history::{HistoryEntry, OperationKind, DEFAULT_HISTORY_LIMIT};
+pub use snapshot::snapshot_path;
+pub use state_machine::{
+    Account, BankStateMachine, CommandOutcome, Saga, SagaPhase, TransferOutcome,
+};
+pub use tenant::{tenant_of, TenantValidator, TENANT_SEPARATOR};
+pub use validation::{CommandValidator, DefaultValidator, InvalidCommand, MAX_ACCOUNT_ID_LEN};
+
+#[cfg(test)]
+pub(crate) use state_machine::tests;
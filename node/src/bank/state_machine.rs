@@ -0,0 +1,1210 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use tracing::warn;
+use crate::bank::command::{BankCommand, MembershipChange, Transfer};
+use crate::bank::executor::StateKey;
+use crate::bank::history::{History, HistoryEntry, OperationKind};
+#[cfg(debug_assertions)]
+use crate::bank::invariants::Baseline;
+use crate::bank::tenant::{tenant_of, TENANT_SEPARATOR};
+use crate::bank::validation::CommandValidator;
+use crate::wal::{self, LogEntry};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransferOutcome {
+    Ok,
+    InsufficientFunds,
+    /// A transfer from an account to itself.
+    InvalidAccount,
+    /// An account the command debits or credits does not exist. Only `CreateAccount`
+    /// opens accounts; nothing else creates one implicitly.
+    AccountNotFound,
+    /// An account the command debits is frozen, or one it credits is frozen to credits.
+    AccountFrozen,
+}
+
+/// What applying a command did, with any results it returns to the client. Results are
+/// taken from the state the command applied to, so replaying the log yields them again.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CommandOutcome {
+    /// `account` is the id asked for, or the one generated for a `CreateAccount` without.
+    AccountCreated { account: String },
+    AccountExists { account: String },
+    AccountNotFound,
+    OverdraftLimitSet,
+    AccountStatusSet,
+    Transfer(TransferOutcome),
+    BatchTransfer(TransferOutcome),
+    Withdraw(TransferOutcome),
+    /// `balance` is the account's right after the deposit, unless it does not exist.
+    Deposit { outcome: TransferOutcome, balance: Option<i64> },
+    ConfigChanged,
+    /// The command is of a kind this version does not know, or failed validation, so
+    /// it changed nothing.
+    Skipped,
+    SagaDebit(TransferOutcome),
+    SagaCredit(TransferOutcome),
+    /// The phase a `SagaFinish` left the saga in, or found it already in.
+    SagaFinished(SagaPhase),
+    SagaNotFound,
+    ConditionalWithdraw(TransferOutcome),
+    /// A `ConditionalWithdraw` expected another version; `current` is the account's.
+    VersionConflict { current: u64 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SagaPhase {
+    /// `from` has been debited and the funds are held for the destination shard.
+    Reserved,
+    Settled,
+    /// The destination rejected the credit and `from` was refunded.
+    Compensated,
+}
+
+/// A cross-shard transfer this shard debited the source account for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Saga {
+    pub from: String,
+    pub to: String,
+    pub amount: i64,
+    pub phase: SagaPhase,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Account {
+    pub balance: i64,
+    /// How far below zero the balance may go; zero means no overdraft.
+    pub overdraft_limit: i64,
+    /// Set by `SetAccountStatus`: nothing may be debited from the account.
+    pub frozen: bool,
+    /// Nothing may be credited to the account either. Only ever set along with `frozen`.
+    pub credits_frozen: bool,
+    /// Starts at 1 when the account is created and goes up by one with every command
+    /// that changes it.
+    pub version: u64,
+}
+
+impl Account {
+    fn can_debit(&self, balance: i64, amount: i64) -> bool {
+        balance - amount >= -self.overdraft_limit
+    }
+}
+
+/// Deterministic bank state rebuilt by applying committed log entries in order.
+#[derive(Clone, Debug, Default)]
+pub struct BankStateMachine {
+    pub(super) accounts: BTreeMap<String, Account>,
+    pub(super) transfers: BTreeMap<String, TransferOutcome>,
+    pub(super) sagas: BTreeMap<String, Saga>,
+    /// Cluster members added through `ConfigChange`, by node id, with their Raft address.
+    pub(super) members: BTreeMap<String, String>,
+    /// The members that were added as witnesses.
+    pub(super) witnesses: BTreeSet<String>,
+    pub(super) history: History,
+    pub(super) last_applied: u64,
+    /// Checks each command before it is applied, if set.
+    validator: Option<Arc<dyn CommandValidator>>,
+}
+
+impl BankStateMachine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a state machine that keeps at most `history_limit` operations per account.
+    pub fn with_history_limit(history_limit: usize) -> Self {
+        Self {
+            history: History::new(history_limit),
+            ..Self::default()
+        }
+    }
+
+    /// Skips, rather than applies, commands that `validator` refuses.
+    pub fn with_validator(mut self, validator: Arc<dyn CommandValidator>) -> Self {
+        self.validator = Some(validator);
+        self
+    }
+
+    pub fn last_applied(&self) -> u64 {
+        self.last_applied
+    }
+
+    pub fn account(&self, account: &str) -> Option<&Account> {
+        self.accounts.get(account)
+    }
+
+    pub fn balance(&self, account: &str) -> Option<i64> {
+        self.account(account).map(|account| account.balance)
+    }
+
+    /// The sum of the balances of `tenant`'s accounts, e.g. to check it against a quota.
+    pub fn tenant_total(&self, tenant: &str) -> i64 {
+        let prefix = format!("{}{}", tenant, TENANT_SEPARATOR);
+        self.accounts
+            .range(prefix.clone()..)
+            .take_while(|(id, _)| id.starts_with(&prefix))
+            .map(|(_, account)| account.balance)
+            .sum()
+    }
+
+    /// The sum of the balances of each tenant's accounts, by tenant. Accounts of no tenant
+    /// are left out.
+    pub fn tenant_totals(&self) -> BTreeMap<String, i64> {
+        let mut totals = BTreeMap::new();
+        for (id, account) in &self.accounts {
+            if let Some(tenant) = tenant_of(id) {
+                *totals.entry(tenant.to_string()).or_insert(0) += account.balance;
+            }
+        }
+        totals
+    }
+
+    pub fn transfer_status(&self, client_tx_id: &str) -> Option<TransferOutcome> {
+        self.transfers.get(client_tx_id).copied()
+    }
+
+    pub fn members(&self) -> &BTreeMap<String, String> {
+        &self.members
+    }
+
+    pub fn witnesses(&self) -> &BTreeSet<String> {
+        &self.witnesses
+    }
+
+    pub fn saga(&self, saga_id: &str) -> Option<&Saga> {
+        self.sagas.get(saga_id)
+    }
+
+    /// Sagas still holding funds, which a coordinator has to drive to completion.
+    pub fn reserved_sagas(&self) -> impl Iterator<Item = (&str, &Saga)> {
+        self.sagas
+            .iter()
+            .filter(|(_, saga)| saga.phase == SagaPhase::Reserved)
+            .map(|(saga_id, saga)| (saga_id.as_str(), saga))
+    }
+
+    /// Returns up to `limit` of the most recent operations on `account`, oldest first.
+    pub fn history(&self, account: &str, limit: usize) -> Vec<HistoryEntry> {
+        self.history.recent(account, limit)
+    }
+
+    pub fn apply(&mut self, entry: &LogEntry) -> std::io::Result<CommandOutcome> {
+        let command = BankCommand::decode(entry.command_slice())?;
+        let outcome = self.apply_command(entry.index, command);
+        self.last_applied = entry.index;
+
+        Ok(outcome)
+    }
+
+    pub(super) fn set_last_applied(&mut self, index: u64) {
+        self.last_applied = index;
+    }
+
+    /// Moves the state under `keys` out into a state machine of its own, so commands that
+    /// touch nothing else can be applied to it apart from the rest. `absorb` puts it back.
+    pub(super) fn split_off(&mut self, keys: &BTreeSet<StateKey>) -> Self {
+        let mut part = Self::with_history_limit(self.history.limit());
+        part.validator = self.validator.clone();
+        for key in keys {
+            match key {
+                StateKey::Account(id) => {
+                    if let Some(account) = self.accounts.remove(id) {
+                        part.accounts.insert(id.clone(), account);
+                    }
+                    if let Some(ledger) = self.history.take(id) {
+                        part.history.put(id, ledger);
+                    }
+                }
+                StateKey::ClientTx(id) => {
+                    if let Some(outcome) = self.transfers.remove(id) {
+                        part.transfers.insert(id.clone(), outcome);
+                    }
+                }
+                StateKey::Saga(id) => {
+                    if let Some(saga) = self.sagas.remove(id) {
+                        part.sagas.insert(id.clone(), saga);
+                    }
+                }
+            }
+        }
+        part
+    }
+
+    /// Takes back state moved out by `split_off`, with whatever applying commands to it
+    /// changed or created.
+    pub(super) fn absorb(&mut self, part: Self) {
+        self.accounts.extend(part.accounts);
+        self.transfers.extend(part.transfers);
+        self.sagas.extend(part.sagas);
+        self.history.absorb(part.history);
+    }
+
+    /// Applies `command`, checking in debug builds that it left the state consistent; see
+    /// `Baseline`.
+    pub(super) fn apply_command(&mut self, index: u64, command: BankCommand) -> CommandOutcome {
+        #[cfg(debug_assertions)]
+        let baseline = Baseline::of(self, &command);
+        let outcome = self.execute(index, command);
+        #[cfg(debug_assertions)]
+        baseline.check(self, index);
+        outcome
+    }
+
+    fn execute(&mut self, index: u64, command: BankCommand) -> CommandOutcome {
+        if let Some(validator) = &self.validator
+            && let Err(e) = validator.validate(&command)
+        {
+            // Written by a node that validated differently, or before validation existed.
+            warn!(index, error = %e, "skipping invalid command");
+            return CommandOutcome::Skipped;
+        }
+
+        match command {
+            BankCommand::CreateAccount { account, initial_balance } => {
+                let account = match account.is_empty() {
+                    true => generated_account_id(index),
+                    false => account,
+                };
+                if self.accounts.contains_key(&account) {
+                    return CommandOutcome::AccountExists { account };
+                }
+                self.history.record(&account, HistoryEntry {
+                    kind: OperationKind::Open,
+                    counterparty: None,
+                    amount: initial_balance,
+                    balance: initial_balance,
+                    index,
+                });
+                let account_state = Account {
+                    balance: initial_balance,
+                    version: 1,
+                    ..Account::default()
+                };
+                self.accounts.insert(account.clone(), account_state);
+                CommandOutcome::AccountCreated { account }
+            }
+            BankCommand::Transfer { from, to, amount, client_tx_id } => {
+                let transfer = Transfer { from, to, amount };
+                let outcome =
+                    self.apply_once(client_tx_id, |sm| sm.transfer_all(index, &[transfer]));
+                CommandOutcome::Transfer(outcome)
+            }
+            BankCommand::BatchTransfer { transfers, client_tx_id } => {
+                let outcome =
+                    self.apply_once(client_tx_id, |sm| sm.transfer_all(index, &transfers));
+                CommandOutcome::BatchTransfer(outcome)
+            }
+            BankCommand::Withdraw { account, amount, client_tx_id } => {
+                let outcome =
+                    self.apply_once(client_tx_id, |sm| sm.withdraw(index, &account, amount));
+                CommandOutcome::Withdraw(outcome)
+            }
+            BankCommand::Deposit { account, amount, client_tx_id } => {
+                let outcome =
+                    self.apply_once(client_tx_id, |sm| sm.credit(index, &account, None, amount));
+                let balance = self.balance(&account);
+                CommandOutcome::Deposit { outcome, balance }
+            }
+            BankCommand::ConfigChange(change) => {
+                match change {
+                    MembershipChange::AddNode { node_id, raft_addr } => {
+                        self.witnesses.remove(&node_id);
+                        self.members.insert(node_id, raft_addr);
+                    }
+                    MembershipChange::RemoveNode { node_id } => {
+                        self.witnesses.remove(&node_id);
+                        self.members.remove(&node_id);
+                    }
+                    MembershipChange::AddWitness { node_id, raft_addr } => {
+                        self.witnesses.insert(node_id.clone());
+                        self.members.insert(node_id, raft_addr);
+                    }
+                }
+                CommandOutcome::ConfigChanged
+            }
+            BankCommand::Unknown { kind, .. } => {
+                // Written by a newer node; the rest of the log may still be ours to apply.
+                warn!(index, kind, "skipping command of unknown kind");
+                CommandOutcome::Skipped
+            }
+            BankCommand::SetOverdraftLimit { account, overdraft_limit } => {
+                match self.accounts.get_mut(&account) {
+                    Some(account) => {
+                        account.overdraft_limit = overdraft_limit;
+                        account.version += 1;
+                        CommandOutcome::OverdraftLimitSet
+                    }
+                    None => CommandOutcome::AccountNotFound,
+                }
+            }
+            BankCommand::SetAccountStatus { account, frozen, block_credits } => {
+                match self.accounts.get_mut(&account) {
+                    Some(account) => {
+                        account.frozen = frozen;
+                        account.credits_frozen = frozen && block_credits;
+                        account.version += 1;
+                        CommandOutcome::AccountStatusSet
+                    }
+                    None => CommandOutcome::AccountNotFound,
+                }
+            }
+            BankCommand::SagaDebit { saga_id, from, to, amount } => {
+                let key = saga_id.clone();
+                let outcome = self.apply_once(key, |sm| {
+                    let outcome = sm.debit(index, &from, Some(&to), amount);
+                    if outcome == TransferOutcome::Ok {
+                        let phase = SagaPhase::Reserved;
+                        sm.sagas.insert(saga_id, Saga { from, to, amount, phase });
+                    }
+                    outcome
+                });
+                CommandOutcome::SagaDebit(outcome)
+            }
+            BankCommand::SagaCredit { saga_id, from, to, amount } => {
+                let outcome =
+                    self.apply_once(saga_id, |sm| sm.credit(index, &to, Some(&from), amount));
+                CommandOutcome::SagaCredit(outcome)
+            }
+            BankCommand::SagaFinish { saga_id, commit } => {
+                self.finish_saga(index, &saga_id, commit)
+            }
+            BankCommand::ConditionalWithdraw { account, amount, expected_version } => {
+                match self.accounts.get(&account) {
+                    Some(state) if state.version != expected_version => {
+                        CommandOutcome::VersionConflict { current: state.version }
+                    }
+                    _ => {
+                        let outcome = self.debit(index, &account, None, amount);
+                        CommandOutcome::ConditionalWithdraw(outcome)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Settles a reserved saga, or refunds its source account unless `commit` is set.
+    /// A saga that already finished keeps its phase. The refund is paid even into an
+    /// account frozen since, as the funds were only ever held.
+    fn finish_saga(&mut self, index: u64, saga_id: &str, commit: bool) -> CommandOutcome {
+        let Some(saga) = self.sagas.get_mut(saga_id) else {
+            return CommandOutcome::SagaNotFound;
+        };
+        if saga.phase != SagaPhase::Reserved {
+            return CommandOutcome::SagaFinished(saga.phase);
+        }
+
+        if commit {
+            saga.phase = SagaPhase::Settled;
+        } else {
+            saga.phase = SagaPhase::Compensated;
+            let Saga { from, to, amount, .. } = saga.clone();
+            self.pay_in(index, &from, Some(&to), amount);
+        }
+        CommandOutcome::SagaFinished(self.sagas[saga_id].phase)
+    }
+
+    /// Runs `debit` unless `client_tx_id` was seen before, in which case the outcome
+    /// of its first application is reported again.
+    fn apply_once(
+        &mut self,
+        client_tx_id: String,
+        debit: impl FnOnce(&mut Self) -> TransferOutcome,
+    ) -> TransferOutcome {
+        if let Some(outcome) = self.transfers.get(&client_tx_id) {
+            return *outcome;
+        }
+
+        let outcome = debit(self);
+        self.transfers.insert(client_tx_id, outcome);
+        outcome
+    }
+
+    /// Validates every transfer against the balances left by the ones before it and
+    /// only touches `accounts` once the whole set is known to succeed.
+    fn transfer_all(&mut self, index: u64, transfers: &[Transfer]) -> TransferOutcome {
+        let mut staged: BTreeMap<&str, i64> = BTreeMap::new();
+        let mut records = Vec::with_capacity(transfers.len() * 2);
+
+        for Transfer { from, to, amount } in transfers {
+            if from == to {
+                return TransferOutcome::InvalidAccount;
+            }
+            let (Some(from_account), Some(to_balance)) =
+                (self.account(from), self.staged_balance(&staged, to))
+            else {
+                return TransferOutcome::AccountNotFound;
+            };
+            if from_account.frozen || self.account(to).is_some_and(|to| to.credits_frozen) {
+                return TransferOutcome::AccountFrozen;
+            }
+            let from_balance = staged.get(from.as_str()).copied().unwrap_or(from_account.balance);
+            if !from_account.can_debit(from_balance, *amount) {
+                return TransferOutcome::InsufficientFunds;
+            }
+
+            let (from_after, to_after) = (from_balance - amount, to_balance + amount);
+            staged.insert(from, from_after);
+            staged.insert(to, to_after);
+            records.push((from, OperationKind::TransferOut, to, *amount, from_after));
+            records.push((to, OperationKind::TransferIn, from, *amount, to_after));
+        }
+
+        for (account, balance) in staged {
+            if let Some(account) = self.accounts.get_mut(account) {
+                account.balance = balance;
+                account.version += 1;
+            }
+        }
+        for (account, kind, counterparty, amount, balance) in records {
+            self.history.record(account, HistoryEntry {
+                kind,
+                counterparty: Some(counterparty.to_string()),
+                amount,
+                balance,
+                index,
+            });
+        }
+        TransferOutcome::Ok
+    }
+
+    fn withdraw(&mut self, index: u64, account_id: &str, amount: i64) -> TransferOutcome {
+        self.debit(index, account_id, None, amount)
+    }
+
+    /// Takes `amount` out of `account_id`, as a transfer to `counterparty` if there is one.
+    fn debit(
+        &mut self,
+        index: u64,
+        account_id: &str,
+        counterparty: Option<&str>,
+        amount: i64,
+    ) -> TransferOutcome {
+        let Some(account) = self.accounts.get_mut(account_id) else {
+            return TransferOutcome::AccountNotFound;
+        };
+        if account.frozen {
+            return TransferOutcome::AccountFrozen;
+        }
+        if !account.can_debit(account.balance, amount) {
+            return TransferOutcome::InsufficientFunds;
+        }
+
+        account.balance -= amount;
+        account.version += 1;
+        let kind = match counterparty {
+            Some(_) => OperationKind::TransferOut,
+            None => OperationKind::Withdraw,
+        };
+        self.history.record(account_id, HistoryEntry {
+            kind,
+            counterparty: counterparty.map(str::to_string),
+            amount,
+            balance: account.balance,
+            index,
+        });
+        TransferOutcome::Ok
+    }
+
+    /// Pays `amount` into `account_id`, as a transfer from `counterparty` if there is one,
+    /// unless the account is frozen to credits.
+    fn credit(
+        &mut self,
+        index: u64,
+        account_id: &str,
+        counterparty: Option<&str>,
+        amount: i64,
+    ) -> TransferOutcome {
+        if self.account(account_id).is_some_and(|account| account.credits_frozen) {
+            return TransferOutcome::AccountFrozen;
+        }
+        self.pay_in(index, account_id, counterparty, amount)
+    }
+
+    /// Like `credit`, whether or not the account is frozen.
+    fn pay_in(
+        &mut self,
+        index: u64,
+        account_id: &str,
+        counterparty: Option<&str>,
+        amount: i64,
+    ) -> TransferOutcome {
+        let Some(account) = self.accounts.get_mut(account_id) else {
+            return TransferOutcome::AccountNotFound;
+        };
+
+        account.balance += amount;
+        account.version += 1;
+        let kind = match counterparty {
+            Some(_) => OperationKind::TransferIn,
+            None => OperationKind::Deposit,
+        };
+        self.history.record(account_id, HistoryEntry {
+            kind,
+            counterparty: counterparty.map(str::to_string),
+            amount,
+            balance: account.balance,
+            index,
+        });
+        TransferOutcome::Ok
+    }
+
+    fn staged_balance(&self, staged: &BTreeMap<&str, i64>, account: &str) -> Option<i64> {
+        staged.get(account).copied().or_else(|| self.balance(account))
+    }
+}
+
+impl wal::StateMachine for BankStateMachine {
+    /// Skips entries a restored snapshot already covers, which a log whose compaction
+    /// was interrupted may still hold.
+    fn apply(&mut self, entry: &LogEntry) -> std::io::Result<()> {
+        if entry.index <= self.last_applied {
+            return Ok(());
+        }
+        BankStateMachine::apply(self, entry).map(drop)
+    }
+
+    /// Counts the entry as applied, so `last_applied` still reaches the end of the log.
+    fn skip(&mut self, entry: &LogEntry) {
+        self.last_applied = self.last_applied.max(entry.index);
+    }
+}
+
+/// The id a `CreateAccount` without one opens its account under. Taken from the log
+/// index, so every replica, and every replay, picks the same one.
+fn generated_account_id(index: u64) -> String {
+    format!("acct-{}", index)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use crate::bank::tenant::TenantValidator;
+    use crate::bank::validation::DefaultValidator;
+
+    pub(crate) fn command_entry(index: u64, command: BankCommand) -> LogEntry {
+        LogEntry::new(index, 1, command.encode().unwrap())
+    }
+
+    pub(crate) fn create_account(account: &str, initial_balance: i64) -> BankCommand {
+        BankCommand::CreateAccount {
+            account: account.to_string(),
+            initial_balance,
+        }
+    }
+
+    pub(crate) fn transfer(from: &str, to: &str, amount: i64, client_tx_id: &str) -> BankCommand {
+        BankCommand::Transfer {
+            from: from.to_string(),
+            to: to.to_string(),
+            amount,
+            client_tx_id: client_tx_id.to_string(),
+        }
+    }
+
+    pub(crate) fn batch_transfer(
+        transfers: &[(&str, &str, i64)],
+        client_tx_id: &str,
+    ) -> BankCommand {
+        BankCommand::BatchTransfer {
+            transfers: transfers
+                .iter()
+                .map(|(from, to, amount)| Transfer {
+                    from: from.to_string(),
+                    to: to.to_string(),
+                    amount: *amount,
+                })
+                .collect(),
+            client_tx_id: client_tx_id.to_string(),
+        }
+    }
+
+    pub(crate) fn withdraw(account: &str, amount: i64, client_tx_id: &str) -> BankCommand {
+        BankCommand::Withdraw {
+            account: account.to_string(),
+            amount,
+            client_tx_id: client_tx_id.to_string(),
+        }
+    }
+
+    pub(crate) fn set_overdraft_limit(account: &str, overdraft_limit: i64) -> BankCommand {
+        BankCommand::SetOverdraftLimit {
+            account: account.to_string(),
+            overdraft_limit,
+        }
+    }
+
+    pub(crate) fn set_account_status(
+        account: &str,
+        frozen: bool,
+        block_credits: bool,
+    ) -> BankCommand {
+        BankCommand::SetAccountStatus {
+            account: account.to_string(),
+            frozen,
+            block_credits,
+        }
+    }
+
+    fn conditional_withdraw(account: &str, amount: i64, expected_version: u64) -> BankCommand {
+        BankCommand::ConditionalWithdraw {
+            account: account.to_string(),
+            amount,
+            expected_version,
+        }
+    }
+
+    fn apply_all(commands: Vec<BankCommand>) -> (BankStateMachine, Vec<CommandOutcome>) {
+        apply_all_to(BankStateMachine::new(), commands)
+    }
+
+    fn apply_all_to(
+        mut sm: BankStateMachine,
+        commands: Vec<BankCommand>,
+    ) -> (BankStateMachine, Vec<CommandOutcome>) {
+        let outcomes = commands
+            .into_iter()
+            .enumerate()
+            .map(|(i, command)| sm.apply(&command_entry(i as u64 + 1, command)).unwrap())
+            .collect();
+        (sm, outcomes)
+    }
+
+    #[test]
+    fn test_create_account() {
+        let (sm, outcomes) =
+            apply_all(vec![create_account("alice", 100), create_account("alice", 5)]);
+
+        let alice = "alice".to_string();
+        assert_eq!(outcomes, vec![
+            CommandOutcome::AccountCreated { account: alice.clone() },
+            CommandOutcome::AccountExists { account: alice },
+        ]);
+        assert_eq!(sm.balance("alice"), Some(100));
+        assert_eq!(sm.last_applied(), 2);
+    }
+
+    #[test]
+    fn test_transfer_moves_funds() {
+        let (sm, outcomes) = apply_all(vec![
+            create_account("alice", 100),
+            create_account("bob", 0),
+            transfer("alice", "bob", 40, "tx-1"),
+        ]);
+
+        assert_eq!(outcomes[2], CommandOutcome::Transfer(TransferOutcome::Ok));
+        assert_eq!(sm.balance("alice"), Some(60));
+        assert_eq!(sm.balance("bob"), Some(40));
+        assert_eq!(sm.transfer_status("tx-1"), Some(TransferOutcome::Ok));
+    }
+
+    #[test]
+    fn test_transfer_insufficient_funds() {
+        let (sm, outcomes) = apply_all(vec![
+            create_account("alice", 10),
+            create_account("bob", 0),
+            transfer("alice", "bob", 40, "tx-1"),
+        ]);
+
+        assert_eq!(outcomes[2], CommandOutcome::Transfer(TransferOutcome::InsufficientFunds));
+        assert_eq!(sm.balance("alice"), Some(10));
+        assert_eq!(sm.balance("bob"), Some(0));
+    }
+
+    #[test]
+    fn test_transfer_invalid_account() {
+        let (_, outcomes) = apply_all(vec![
+            create_account("alice", 10),
+            transfer("alice", "alice", 5, "tx-1"),
+        ]);
+
+        assert_eq!(outcomes[1], CommandOutcome::Transfer(TransferOutcome::InvalidAccount));
+    }
+
+    #[test]
+    fn test_transfer_to_missing_account_creates_nothing() {
+        let (sm, outcomes) = apply_all(vec![
+            create_account("alice", 10),
+            transfer("alice", "nobody", 5, "tx-1"),
+        ]);
+
+        assert_eq!(outcomes[1], CommandOutcome::Transfer(TransferOutcome::AccountNotFound));
+        assert_eq!(sm.balance("alice"), Some(10));
+        assert_eq!(sm.account("nobody"), None);
+        assert_eq!(sm.history("alice", 0).len(), 1);
+    }
+
+    #[test]
+    fn test_transfer_from_missing_account_creates_nothing() {
+        let (sm, outcomes) = apply_all(vec![
+            create_account("bob", 0),
+            transfer("nobody", "bob", 5, "tx-1"),
+            batch_transfer(&[("nobody", "bob", 5)], "batch-1"),
+        ]);
+
+        assert_eq!(outcomes[1], CommandOutcome::Transfer(TransferOutcome::AccountNotFound));
+        let not_found = CommandOutcome::BatchTransfer(TransferOutcome::AccountNotFound);
+        assert_eq!(outcomes[2], not_found);
+        assert_eq!(sm.balance("bob"), Some(0));
+        assert_eq!(sm.account("nobody"), None);
+    }
+
+    #[test]
+    fn test_transfer_succeeds_once_account_is_created() {
+        let (sm, outcomes) = apply_all(vec![
+            create_account("alice", 10),
+            transfer("alice", "bob", 5, "tx-1"),
+            create_account("bob", 0),
+            transfer("alice", "bob", 5, "tx-2"),
+        ]);
+
+        assert_eq!(outcomes[1], CommandOutcome::Transfer(TransferOutcome::AccountNotFound));
+        assert_eq!(outcomes[3], CommandOutcome::Transfer(TransferOutcome::Ok));
+        assert_eq!(sm.balance("alice"), Some(5));
+        assert_eq!(sm.balance("bob"), Some(5));
+        // The outcome is recorded under its key, so retrying it does not transfer again.
+        assert_eq!(sm.transfer_status("tx-1"), Some(TransferOutcome::AccountNotFound));
+    }
+
+    #[test]
+    fn test_transfer_is_idempotent() {
+        let (sm, outcomes) = apply_all(vec![
+            create_account("alice", 100),
+            create_account("bob", 0),
+            transfer("alice", "bob", 40, "tx-1"),
+            transfer("alice", "bob", 40, "tx-1"),
+        ]);
+
+        assert_eq!(outcomes[3], CommandOutcome::Transfer(TransferOutcome::Ok));
+        assert_eq!(sm.balance("alice"), Some(60));
+        assert_eq!(sm.balance("bob"), Some(40));
+    }
+
+    #[test]
+    fn test_batch_transfer_applies_all() {
+        let (sm, outcomes) = apply_all(vec![
+            create_account("payroll", 300),
+            create_account("alice", 0),
+            create_account("bob", 0),
+            batch_transfer(&[("payroll", "alice", 100), ("payroll", "bob", 200)], "batch-1"),
+        ]);
+
+        assert_eq!(outcomes[3], CommandOutcome::BatchTransfer(TransferOutcome::Ok));
+        assert_eq!(sm.balance("payroll"), Some(0));
+        assert_eq!(sm.balance("alice"), Some(100));
+        assert_eq!(sm.balance("bob"), Some(200));
+        assert_eq!(sm.transfer_status("batch-1"), Some(TransferOutcome::Ok));
+    }
+
+    #[test]
+    fn test_batch_transfer_uses_intermediate_balances() {
+        let (sm, outcomes) = apply_all(vec![
+            create_account("alice", 50),
+            create_account("bob", 0),
+            create_account("carol", 0),
+            // Bob can only pay Carol with the funds Alice sends him earlier in the batch.
+            batch_transfer(&[("alice", "bob", 50), ("bob", "carol", 50)], "batch-1"),
+        ]);
+
+        assert_eq!(outcomes[3], CommandOutcome::BatchTransfer(TransferOutcome::Ok));
+        assert_eq!(sm.balance("alice"), Some(0));
+        assert_eq!(sm.balance("bob"), Some(0));
+        assert_eq!(sm.balance("carol"), Some(50));
+    }
+
+    #[test]
+    fn test_batch_transfer_overdraw_rejects_whole_batch() {
+        let (sm, outcomes) = apply_all(vec![
+            create_account("payroll", 250),
+            create_account("alice", 0),
+            create_account("bob", 0),
+            batch_transfer(&[("payroll", "alice", 100), ("payroll", "bob", 200)], "batch-1"),
+        ]);
+
+        assert_eq!(outcomes[3], CommandOutcome::BatchTransfer(TransferOutcome::InsufficientFunds));
+        assert_eq!(sm.balance("payroll"), Some(250));
+        assert_eq!(sm.balance("alice"), Some(0));
+        assert_eq!(sm.balance("bob"), Some(0));
+        assert_eq!(sm.transfer_status("batch-1"), Some(TransferOutcome::InsufficientFunds));
+    }
+
+    #[test]
+    fn test_withdraw_default_limit_forbids_overdraft() {
+        let (sm, outcomes) = apply_all(vec![
+            create_account("alice", 50),
+            withdraw("alice", 50, "w-1"),
+            withdraw("alice", 1, "w-2"),
+        ]);
+
+        assert_eq!(outcomes[1], CommandOutcome::Withdraw(TransferOutcome::Ok));
+        assert_eq!(outcomes[2], CommandOutcome::Withdraw(TransferOutcome::InsufficientFunds));
+        assert_eq!(sm.balance("alice"), Some(0));
+    }
+
+    #[test]
+    fn test_withdraw_within_overdraft_limit() {
+        let (sm, outcomes) = apply_all(vec![
+            create_account("alice", 50),
+            set_overdraft_limit("alice", 100),
+            withdraw("alice", 150, "w-1"),
+        ]);
+
+        assert_eq!(outcomes[1], CommandOutcome::OverdraftLimitSet);
+        assert_eq!(outcomes[2], CommandOutcome::Withdraw(TransferOutcome::Ok));
+        assert_eq!(sm.balance("alice"), Some(-100));
+    }
+
+    #[test]
+    fn test_withdraw_breaching_overdraft_limit() {
+        let (sm, outcomes) = apply_all(vec![
+            create_account("alice", 50),
+            set_overdraft_limit("alice", 100),
+            withdraw("alice", 151, "w-1"),
+        ]);
+
+        assert_eq!(outcomes[2], CommandOutcome::Withdraw(TransferOutcome::InsufficientFunds));
+        assert_eq!(sm.balance("alice"), Some(50));
+    }
+
+    #[test]
+    fn test_overdraft_limit_applies_to_transfers() {
+        let (sm, outcomes) = apply_all(vec![
+            create_account("alice", 0),
+            create_account("bob", 0),
+            set_overdraft_limit("alice", 100),
+            transfer("alice", "bob", 60, "tx-1"),
+            batch_transfer(&[("alice", "bob", 30), ("alice", "bob", 30)], "batch-1"),
+        ]);
+
+        assert_eq!(outcomes[3], CommandOutcome::Transfer(TransferOutcome::Ok));
+        assert_eq!(outcomes[4], CommandOutcome::BatchTransfer(TransferOutcome::InsufficientFunds));
+        assert_eq!(sm.balance("alice"), Some(-60));
+        assert_eq!(sm.balance("bob"), Some(60));
+    }
+
+    #[test]
+    fn test_frozen_account_refuses_debits_until_unfrozen() {
+        let (sm, outcomes) = apply_all(vec![
+            create_account("alice", 100),
+            create_account("bob", 20),
+            set_account_status("alice", true, false),
+            transfer("alice", "bob", 30, "tx-1"),
+            withdraw("alice", 10, "w-1"),
+            batch_transfer(&[("bob", "alice", 5), ("alice", "bob", 5)], "batch-1"),
+            // Credits still reach it.
+            transfer("bob", "alice", 10, "tx-2"),
+            set_account_status("alice", false, false),
+            transfer("alice", "bob", 30, "tx-3"),
+            set_account_status("carol", true, false),
+        ]);
+
+        let frozen = TransferOutcome::AccountFrozen;
+        assert_eq!(outcomes[2], CommandOutcome::AccountStatusSet);
+        assert_eq!(outcomes[3], CommandOutcome::Transfer(frozen));
+        assert_eq!(outcomes[4], CommandOutcome::Withdraw(frozen));
+        assert_eq!(outcomes[5], CommandOutcome::BatchTransfer(frozen));
+        assert_eq!(outcomes[6], CommandOutcome::Transfer(TransferOutcome::Ok));
+        assert_eq!(outcomes[8], CommandOutcome::Transfer(TransferOutcome::Ok));
+        assert_eq!(outcomes[9], CommandOutcome::AccountNotFound);
+        assert_eq!(sm.balance("alice"), Some(80));
+        assert_eq!(sm.balance("bob"), Some(40));
+        assert!(!sm.account("alice").unwrap().frozen);
+    }
+
+    #[test]
+    fn test_frozen_account_can_refuse_credits_too() {
+        let (sm, outcomes) = apply_all(vec![
+            create_account("alice", 100),
+            create_account("bob", 50),
+            set_account_status("alice", true, true),
+            transfer("bob", "alice", 10, "tx-1"),
+            BankCommand::Deposit {
+                account: "alice".to_string(),
+                amount: 10,
+                client_tx_id: "d-1".to_string(),
+            },
+        ]);
+
+        let frozen = TransferOutcome::AccountFrozen;
+        assert_eq!(outcomes[3], CommandOutcome::Transfer(frozen));
+        assert_eq!(outcomes[4], CommandOutcome::Deposit { outcome: frozen, balance: Some(100) });
+        // Reading the account is unaffected.
+        assert_eq!(sm.balance("alice"), Some(100));
+        assert_eq!(sm.balance("bob"), Some(50));
+        assert!(sm.account("alice").unwrap().credits_frozen);
+    }
+
+    #[test]
+    fn test_set_overdraft_limit_unknown_account() {
+        let (_, outcomes) = apply_all(vec![set_overdraft_limit("nobody", 100)]);
+        assert_eq!(outcomes[0], CommandOutcome::AccountNotFound);
+    }
+
+    #[test]
+    fn test_history_records_transfers_in_order() {
+        let (sm, _) = apply_all(vec![
+            create_account("alice", 100),
+            create_account("bob", 0),
+            transfer("alice", "bob", 30, "tx-1"),
+            transfer("alice", "bob", 500, "tx-2"),
+            transfer("bob", "alice", 10, "tx-3"),
+            withdraw("alice", 5, "w-1"),
+        ]);
+
+        let summary = |account| -> Vec<(OperationKind, Option<String>, i64, i64, u64)> {
+            sm.history(account, 0)
+                .into_iter()
+                .map(|e| (e.kind, e.counterparty, e.amount, e.balance, e.index))
+                .collect()
+        };
+        let bob = || Some("bob".to_string());
+        let alice = || Some("alice".to_string());
+
+        // The rejected tx-2 at index 4 leaves no trace.
+        assert_eq!(summary("alice"), vec![
+            (OperationKind::Open, None, 100, 100, 1),
+            (OperationKind::TransferOut, bob(), 30, 70, 3),
+            (OperationKind::TransferIn, bob(), 10, 80, 5),
+            (OperationKind::Withdraw, None, 5, 75, 6),
+        ]);
+        assert_eq!(summary("bob"), vec![
+            (OperationKind::Open, None, 0, 0, 2),
+            (OperationKind::TransferIn, alice(), 30, 30, 3),
+            (OperationKind::TransferOut, alice(), 10, 20, 5),
+        ]);
+        assert_eq!(sm.history("alice", 2).iter().map(|e| e.index).collect::<Vec<_>>(), vec![5, 6]);
+    }
+
+    #[test]
+    fn test_history_cap_evicts_oldest_entries() {
+        let mut commands = vec![create_account("alice", 100), create_account("bob", 0)];
+        for i in 0..5 {
+            commands.push(transfer("alice", "bob", 1, &format!("tx-{}", i)));
+        }
+        let (sm, _) = apply_all_to(BankStateMachine::with_history_limit(3), commands);
+
+        let alice: Vec<(u64, i64)> =
+            sm.history("alice", 0).iter().map(|e| (e.index, e.balance)).collect();
+        assert_eq!(alice, vec![(5, 97), (6, 96), (7, 95)]);
+    }
+
+    pub(crate) fn saga_debit(saga_id: &str, from: &str, to: &str, amount: i64) -> BankCommand {
+        BankCommand::SagaDebit {
+            saga_id: saga_id.to_string(),
+            from: from.to_string(),
+            to: to.to_string(),
+            amount,
+        }
+    }
+
+    pub(crate) fn saga_finish(saga_id: &str, commit: bool) -> BankCommand {
+        BankCommand::SagaFinish {
+            saga_id: saga_id.to_string(),
+            commit,
+        }
+    }
+
+    #[test]
+    fn test_saga_debit_holds_funds_until_settled() {
+        let (sm, outcomes) = apply_all(vec![
+            create_account("alice", 100),
+            saga_debit("saga-1", "alice", "bob", 40),
+            saga_debit("saga-1", "alice", "bob", 40),
+            saga_debit("saga-2", "alice", "bob", 500),
+        ]);
+
+        assert_eq!(outcomes[1], CommandOutcome::SagaDebit(TransferOutcome::Ok));
+        assert_eq!(outcomes[2], CommandOutcome::SagaDebit(TransferOutcome::Ok));
+        assert_eq!(outcomes[3], CommandOutcome::SagaDebit(TransferOutcome::InsufficientFunds));
+        assert_eq!(sm.balance("alice"), Some(60));
+        let reserved: Vec<&str> = sm.reserved_sagas().map(|(saga_id, _)| saga_id).collect();
+        assert_eq!(reserved, vec!["saga-1"]);
+
+        let (sm, outcomes) =
+            apply_all_to(sm, vec![saga_finish("saga-1", true), saga_finish("saga-1", false)]);
+        let settled = CommandOutcome::SagaFinished(SagaPhase::Settled);
+        assert_eq!(outcomes, vec![settled.clone(), settled]);
+        assert_eq!(sm.balance("alice"), Some(60));
+        assert_eq!(sm.reserved_sagas().count(), 0);
+    }
+
+    #[test]
+    fn test_saga_compensation_refunds_source() {
+        let (sm, outcomes) = apply_all(vec![
+            create_account("alice", 100),
+            saga_debit("saga-1", "alice", "bob", 40),
+            saga_finish("saga-1", false),
+            saga_finish("saga-2", false),
+        ]);
+
+        assert_eq!(outcomes[2], CommandOutcome::SagaFinished(SagaPhase::Compensated));
+        assert_eq!(outcomes[3], CommandOutcome::SagaNotFound);
+        assert_eq!(sm.balance("alice"), Some(100));
+        assert_eq!(sm.saga("saga-1").unwrap().phase, SagaPhase::Compensated);
+    }
+
+    #[test]
+    fn test_create_account_without_an_id_generates_the_same_one_on_replay() {
+        let commands = vec![create_account("alice", 100), create_account("", 50)];
+        let (sm, outcomes) = apply_all(commands.clone());
+        let (_, replayed) = apply_all(commands);
+
+        let generated = CommandOutcome::AccountCreated { account: "acct-2".to_string() };
+        assert_eq!(outcomes[1], generated);
+        assert_eq!(replayed, outcomes);
+        assert_eq!(sm.balance("acct-2"), Some(50));
+    }
+
+    #[test]
+    fn test_deposit_credits_account_once() {
+        let deposit = |account: &str, tx: &str| BankCommand::Deposit {
+            account: account.to_string(),
+            amount: 50,
+            client_tx_id: tx.to_string(),
+        };
+        let (sm, outcomes) = apply_all(vec![
+            create_account("alice", 100),
+            deposit("alice", "d-1"),
+            deposit("alice", "d-1"),
+            deposit("bob", "d-2"),
+        ]);
+
+        let deposited = |outcome, balance| CommandOutcome::Deposit { outcome, balance };
+        assert_eq!(outcomes[1], deposited(TransferOutcome::Ok, Some(150)));
+        // A repeat returns the balance as it stands, not the one the first left.
+        assert_eq!(outcomes[2], deposited(TransferOutcome::Ok, Some(150)));
+        assert_eq!(outcomes[3], deposited(TransferOutcome::AccountNotFound, None));
+        assert_eq!(sm.balance("alice"), Some(150));
+        assert_eq!(sm.history("alice", 1)[0].kind, OperationKind::Deposit);
+    }
+
+    #[test]
+    fn test_every_change_bumps_the_account_version() {
+        let (sm, _) = apply_all(vec![
+            create_account("alice", 100),
+            create_account("bob", 0),
+            transfer("alice", "bob", 10, "tx-1"),
+            transfer("alice", "bob", 10, "tx-1"),
+            withdraw("alice", 500, "tx-2"),
+            set_overdraft_limit("bob", 20),
+        ]);
+
+        // The replayed transfer and the failed withdrawal changed nothing.
+        assert_eq!(sm.account("alice").unwrap().version, 2);
+        assert_eq!(sm.account("bob").unwrap().version, 3);
+    }
+
+    #[test]
+    fn test_conditional_withdraw_at_expected_version() {
+        let (sm, outcomes) = apply_all(vec![
+            create_account("alice", 100),
+            conditional_withdraw("alice", 30, 1),
+            conditional_withdraw("alice", 30, 2),
+        ]);
+
+        assert_eq!(outcomes[1], CommandOutcome::ConditionalWithdraw(TransferOutcome::Ok));
+        assert_eq!(outcomes[2], CommandOutcome::ConditionalWithdraw(TransferOutcome::Ok));
+        assert_eq!(sm.balance("alice"), Some(40));
+        assert_eq!(sm.account("alice").unwrap().version, 3);
+    }
+
+    #[test]
+    fn test_conditional_withdraw_at_stale_version_conflicts() {
+        let (sm, outcomes) = apply_all(vec![
+            create_account("alice", 100),
+            withdraw("alice", 10, "tx-1"),
+            conditional_withdraw("alice", 30, 1),
+            conditional_withdraw("bob", 30, 1),
+        ]);
+
+        assert_eq!(outcomes[2], CommandOutcome::VersionConflict { current: 2 });
+        let not_found = CommandOutcome::ConditionalWithdraw(TransferOutcome::AccountNotFound);
+        assert_eq!(outcomes[3], not_found);
+        assert_eq!(sm.balance("alice"), Some(90));
+        assert_eq!(sm.account("alice").unwrap().version, 2);
+    }
+
+    #[test]
+    fn test_config_change_updates_members() {
+        let add = |id: &str| {
+            BankCommand::ConfigChange(MembershipChange::AddNode {
+                node_id: id.to_string(),
+                raft_addr: format!("{}:50061", id),
+            })
+        };
+        let remove = BankCommand::ConfigChange(MembershipChange::RemoveNode {
+            node_id: "node-2".to_string(),
+        });
+        let (sm, outcomes) = apply_all(vec![add("node-2"), add("node-3"), remove]);
+
+        assert!(outcomes.iter().all(|outcome| *outcome == CommandOutcome::ConfigChanged));
+        let members: Vec<&str> = sm.members().keys().map(String::as_str).collect();
+        assert_eq!(members, vec!["node-3"]);
+    }
+
+    #[test]
+    fn test_config_change_adds_witness() {
+        let witness = |id: &str| {
+            BankCommand::ConfigChange(MembershipChange::AddWitness {
+                node_id: id.to_string(),
+                raft_addr: format!("{}:50061", id),
+            })
+        };
+        let remove = BankCommand::ConfigChange(MembershipChange::RemoveNode {
+            node_id: "witness-2".to_string(),
+        });
+        let (sm, _) = apply_all(vec![witness("witness-1"), witness("witness-2"), remove]);
+
+        let members: Vec<&str> = sm.members().keys().map(String::as_str).collect();
+        assert_eq!(members, vec!["witness-1"]);
+        assert!(sm.witnesses().contains("witness-1"));
+        assert!(!sm.witnesses().contains("witness-2"));
+    }
+
+    #[test]
+    fn test_unknown_command_kind_is_skipped() {
+        let mut sm = BankStateMachine::new();
+        sm.apply(&command_entry(1, create_account("alice", 100))).unwrap();
+
+        let unknown = LogEntry::new(2, 1, Bytes::from_static(b"\x9a\x06\x03new"));
+        assert_eq!(sm.apply(&unknown).unwrap(), CommandOutcome::Skipped);
+        assert_eq!(sm.last_applied(), 2);
+
+        sm.apply(&command_entry(3, withdraw("alice", 30, "w-1"))).unwrap();
+        assert_eq!(sm.balance("alice"), Some(70));
+        assert_eq!(sm.last_applied(), 3);
+    }
+
+    #[test]
+    fn test_tenant_validator_keeps_transfers_within_a_tenant_and_totals_follow() {
+        let validator = TenantValidator::new(Arc::new(DefaultValidator));
+        let sm = BankStateMachine::new().with_validator(Arc::new(validator));
+        let (sm, outcomes) = apply_all_to(sm, vec![
+            create_account("acme:alice", 100),
+            create_account("acme:bob", 50),
+            create_account("globex:carol", 30),
+            create_account("dave", 10),
+            transfer("acme:alice", "acme:bob", 40, "tx-1"),
+            transfer("acme:bob", "globex:carol", 20, "tx-2"),
+            withdraw("globex:carol", 5, "w-1"),
+        ]);
+
+        assert_eq!(outcomes[4], CommandOutcome::Transfer(TransferOutcome::Ok));
+        assert_eq!(outcomes[5], CommandOutcome::Skipped);
+        assert_eq!(sm.balance("acme:bob"), Some(90));
+        assert_eq!(sm.transfer_status("tx-2"), None);
+        assert_eq!((sm.tenant_total("acme"), sm.tenant_total("globex")), (150, 25));
+        assert_eq!(sm.tenant_total("ac"), 0);
+        let totals: Vec<(String, i64)> = sm.tenant_totals().into_iter().collect();
+        assert_eq!(totals, vec![("acme".to_string(), 150), ("globex".to_string(), 25)]);
+    }
+
+    #[test]
+    fn test_apply_rejects_malformed_command() {
+        let mut sm = BankStateMachine::new();
+        let entry = LogEntry::new(1, 1, Bytes::from_static(b"\xFF"));
+
+        assert!(sm.apply(&entry).is_err());
+        assert_eq!(sm.last_applied(), 0);
+    }
+}
@@ -0,0 +1,99 @@
+use std::sync::Arc;
+use crate::bank::command::BankCommand;
+use crate::bank::validation::{CommandValidator, InvalidCommand};
+
+/// Separates the tenant an account belongs to from the rest of its id, as in
+/// `acme:alice`. An id without one belongs to no tenant.
+pub const TENANT_SEPARATOR: char = ':';
+
+/// The tenant `account` belongs to: the part of its id before the first
+/// `TENANT_SEPARATOR`, if there is one.
+pub fn tenant_of(account: &str) -> Option<&str> {
+    account.split_once(TENANT_SEPARATOR).map(|(tenant, _)| tenant)
+}
+
+/// Refuses transfers between accounts of different tenants, counting accounts of no
+/// tenant as one tenant of their own, unless `with_cross_tenant_transfers` allows them.
+/// Every other check is left to the validator it wraps.
+#[derive(Debug)]
+pub struct TenantValidator {
+    inner: Arc<dyn CommandValidator>,
+    cross_tenant_transfers: bool,
+}
+
+impl TenantValidator {
+    pub fn new(inner: Arc<dyn CommandValidator>) -> Self {
+        Self { inner, cross_tenant_transfers: false }
+    }
+
+    /// Lets transfers cross tenants after all, if `allowed`.
+    pub fn with_cross_tenant_transfers(mut self, allowed: bool) -> Self {
+        self.cross_tenant_transfers = allowed;
+        self
+    }
+
+    fn check_same_tenant(&self, from: &str, to: &str) -> Result<(), InvalidCommand> {
+        let (from_tenant, to_tenant) = (tenant_of(from), tenant_of(to));
+        if self.cross_tenant_transfers || from_tenant == to_tenant {
+            return Ok(());
+        }
+        Err(InvalidCommand(format!(
+            "transfer from {:?} to {:?} crosses tenants, which is not allowed",
+            from, to
+        )))
+    }
+}
+
+impl CommandValidator for TenantValidator {
+    fn validate(&self, command: &BankCommand) -> Result<(), InvalidCommand> {
+        self.inner.validate(command)?;
+        match command {
+            BankCommand::Transfer { from, to, .. }
+            | BankCommand::SagaDebit { from, to, .. }
+            | BankCommand::SagaCredit { from, to, .. } => self.check_same_tenant(from, to),
+            BankCommand::BatchTransfer { transfers, .. } => transfers
+                .iter()
+                .try_for_each(|transfer| self.check_same_tenant(&transfer.from, &transfer.to)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bank::tests::{batch_transfer, create_account, transfer};
+    use crate::bank::validation::DefaultValidator;
+
+    fn validator() -> TenantValidator {
+        TenantValidator::new(Arc::new(DefaultValidator))
+    }
+
+    #[test]
+    fn test_tenant_of_takes_the_prefix_before_the_separator() {
+        assert_eq!(tenant_of("acme:alice"), Some("acme"));
+        assert_eq!(tenant_of("acme:ops:bob"), Some("acme"));
+        assert_eq!(tenant_of(":alice"), Some(""));
+        assert_eq!(tenant_of("alice"), None);
+    }
+
+    #[test]
+    fn test_tenant_validator_rejects_cross_tenant_transfers_by_default() {
+        let validator = validator();
+        assert!(validator.validate(&transfer("acme:alice", "acme:bob", 5, "tx-1")).is_ok());
+        assert!(validator.validate(&transfer("alice", "bob", 5, "tx-2")).is_ok());
+
+        let err = validator.validate(&transfer("acme:alice", "globex:bob", 5, "tx-3"));
+        assert!(err.unwrap_err().0.contains("crosses tenants"));
+        assert!(validator.validate(&transfer("acme:alice", "bob", 5, "tx-4")).is_err());
+        let legs = [("acme:alice", "acme:bob", 5), ("acme:bob", "globex:carol", 1)];
+        assert!(validator.validate(&batch_transfer(&legs, "b-1")).is_err());
+        // What the wrapped validator refuses is still refused.
+        assert!(validator.validate(&transfer("acme:alice", "acme:bob", -5, "tx-5")).is_err());
+        assert!(validator.validate(&create_account("acme:alice", 10)).is_ok());
+
+        let validator = validator.with_cross_tenant_transfers(true);
+        assert!(validator.validate(&transfer("acme:alice", "globex:bob", 5, "tx-3")).is_ok());
+        assert!(validator.validate(&batch_transfer(&legs, "b-1")).is_ok());
+    }
+}
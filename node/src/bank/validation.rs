@@ -0,0 +1,148 @@
+use std::fmt;
+use crate::bank::command::BankCommand;
+
+/// Longest account id `DefaultValidator` accepts, in bytes.
+pub const MAX_ACCOUNT_ID_LEN: usize = 64;
+
+/// Checks a command before it is proposed, so an invalid one never reaches the log, and
+/// again as each committed command is applied, replay included, skipping any that
+/// fail. Must be deterministic, since every replica applies the same log with it.
+pub trait CommandValidator: fmt::Debug + Send + Sync {
+    fn validate(&self, command: &BankCommand) -> Result<(), InvalidCommand>;
+}
+
+/// Why a `CommandValidator` refused a command.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidCommand(pub String);
+
+impl fmt::Display for InvalidCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidCommand {}
+
+/// Requires amounts to be positive, opening balances and overdraft limits not to be
+/// negative, batches not to be empty, and account ids to be 1 to `MAX_ACCOUNT_ID_LEN`
+/// ASCII letters, digits, or any of `-_.:@`, bar the empty one of a `CreateAccount`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultValidator;
+
+impl CommandValidator for DefaultValidator {
+    fn validate(&self, command: &BankCommand) -> Result<(), InvalidCommand> {
+        match command {
+            BankCommand::CreateAccount { account, initial_balance } => {
+                // An empty id asks for one to be generated.
+                if !account.is_empty() {
+                    check_account(account)?;
+                }
+                if *initial_balance < 0 {
+                    return Err(invalid("initial_balance must not be negative"));
+                }
+            }
+            BankCommand::Transfer { from, to, amount, .. }
+            | BankCommand::SagaDebit { from, to, amount, .. }
+            | BankCommand::SagaCredit { from, to, amount, .. } => {
+                check_account(from)?;
+                check_account(to)?;
+                check_amount(*amount)?;
+            }
+            BankCommand::BatchTransfer { transfers, .. } => {
+                if transfers.is_empty() {
+                    return Err(invalid("batch must contain at least one transfer"));
+                }
+                for transfer in transfers {
+                    check_account(&transfer.from)?;
+                    check_account(&transfer.to)?;
+                    check_amount(transfer.amount)?;
+                }
+            }
+            BankCommand::Withdraw { account, amount, .. }
+            | BankCommand::Deposit { account, amount, .. }
+            | BankCommand::ConditionalWithdraw { account, amount, .. } => {
+                check_account(account)?;
+                check_amount(*amount)?;
+            }
+            BankCommand::SetAccountStatus { account, .. } => check_account(account)?,
+            BankCommand::SetOverdraftLimit { account, overdraft_limit } => {
+                check_account(account)?;
+                if *overdraft_limit < 0 {
+                    return Err(invalid("overdraft_limit must not be negative"));
+                }
+            }
+            BankCommand::SagaFinish { .. }
+            | BankCommand::ConfigChange(_)
+            | BankCommand::Unknown { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+fn check_account(account: &str) -> Result<(), InvalidCommand> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || "-_.:@".contains(c);
+    if account.is_empty() || account.len() > MAX_ACCOUNT_ID_LEN || !account.chars().all(allowed)
+    {
+        return Err(invalid(format!(
+            "account id {:?} must be 1 to {} letters, digits or -_.:@",
+            account, MAX_ACCOUNT_ID_LEN
+        )));
+    }
+    Ok(())
+}
+
+fn check_amount(amount: i64) -> Result<(), InvalidCommand> {
+    if amount <= 0 {
+        return Err(invalid("amount must be positive"));
+    }
+    Ok(())
+}
+
+fn invalid(message: impl Into<String>) -> InvalidCommand {
+    InvalidCommand(message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bank::tests::{
+        batch_transfer, create_account, set_overdraft_limit, transfer, withdraw,
+    };
+
+    fn check(command: BankCommand) -> Result<(), String> {
+        DefaultValidator.validate(&command).map_err(|e| e.0)
+    }
+
+    #[test]
+    fn test_default_validator_accepts_well_formed_commands() {
+        assert_eq!(check(create_account("alice", 0)), Ok(()));
+        assert_eq!(check(create_account("acct-1.eu:ops@bank_2", 10)), Ok(()));
+        assert_eq!(check(transfer("alice", "bob", 1, "tx-1")), Ok(()));
+        assert_eq!(check(set_overdraft_limit("alice", 0)), Ok(()));
+    }
+
+    #[test]
+    fn test_default_validator_rejects_bad_amounts() {
+        let err = check(transfer("alice", "bob", -5, "tx-1")).unwrap_err();
+        assert_eq!(err, "amount must be positive");
+        assert!(check(withdraw("alice", 0, "tx-1")).is_err());
+        let legs = [("alice", "bob", 5), ("bob", "carol", -1)];
+        assert!(check(batch_transfer(&legs, "b-1")).is_err());
+        assert!(check(batch_transfer(&[], "b-1")).is_err());
+        assert!(check(create_account("alice", -1)).is_err());
+        assert!(check(set_overdraft_limit("alice", -1)).is_err());
+    }
+
+    #[test]
+    fn test_default_validator_rejects_malformed_account_ids() {
+        assert!(check(withdraw("", 5, "tx-1")).is_err());
+        // Only opening an account may leave the id to be generated.
+        assert!(check(create_account("", 0)).is_ok());
+        assert!(check(create_account("alice smith", 0)).is_err());
+        assert!(check(create_account("bob/../carol", 0)).is_err());
+        assert!(check(transfer("alice", "b\u{f6}b", 5, "tx-1")).is_err());
+        let too_long = "a".repeat(MAX_ACCOUNT_ID_LEN + 1);
+        assert!(check(create_account(&too_long, 0)).unwrap_err().contains("must be 1 to 64"));
+        assert!(check(create_account(&too_long[1..], 0)).is_ok());
+    }
+}
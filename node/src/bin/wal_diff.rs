@@ -0,0 +1,22 @@
+use node::wal::first_divergence;
+
+/// Compares the two WALs at the paths given, entry by entry, and reports the first index
+/// at which they differ, exiting with 1 if they do.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+    let (Some(left), Some(right), None) = (args.next(), args.next(), args.next()) else {
+        return Err("usage: wal_diff <path to WAL> <path to WAL>".into());
+    };
+    // Both are opened read-only, as the nodes that own them may be running.
+    let divergence = first_divergence(&left, &right)
+        .map_err(|e| format!("cannot compare {} with {}: {}", left, right, e))?;
+    match divergence {
+        None => println!("{} and {} hold the same entries", left, right),
+        Some(divergence) => {
+            let index = divergence.index();
+            println!("{} and {} diverge at entry {}: {}", left, right, index, divergence);
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
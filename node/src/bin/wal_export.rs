@@ -0,0 +1,17 @@
+use std::io::{BufWriter, Write};
+use node::wal::Wal;
+
+/// Dumps the WAL at the path given as the only argument to stdout, one JSON object per
+/// entry.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+    let (Some(path), None) = (args.next(), args.next()) else {
+        return Err("usage: wal_export <path to WAL>".into());
+    };
+    // Read-only, as the node that owns the log may be running and appending to it.
+    let wal = Wal::open_read_only(&path).map_err(|e| format!("cannot open {}: {}", path, e))?;
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    wal.export_jsonl(&mut out)?;
+    out.flush()?;
+    Ok(())
+}
@@ -0,0 +1,161 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::watch;
+use crate::bank::{BankCommand, BankStateMachine, CommandOutcome};
+use crate::replica::{Replica, SnapshotRequired};
+
+/// One bank operation as a replica applied it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Change {
+    pub index: u64,
+    pub command: BankCommand,
+    pub outcome: CommandOutcome,
+    /// Balance of each account the operation touched, right after it. Accounts that do
+    /// not exist, such as those on other shards, are left out.
+    pub balances: BTreeMap<String, i64>,
+}
+
+/// Follows the operations a replica applies from a given log index on, in order, for
+/// change data capture. It replays the WAL into a state machine of its own, from the
+/// replica's snapshot or from scratch, so each change carries the balances as they
+/// stood right after it, however long ago it was applied.
+pub struct ChangeFeed {
+    replica: Arc<Replica>,
+    state: BankStateMachine,
+    /// Changes before this index are replayed but not reported.
+    from_index: u64,
+    applied: watch::Receiver<u64>,
+}
+
+impl ChangeFeed {
+    /// Starts following `replica` at `from_index`. Fails with `SnapshotRequired` if the
+    /// operations from there on can no longer be replayed, as the WAL was compacted past
+    /// it; the caller must then start over from a snapshot.
+    pub fn open(replica: Arc<Replica>, from_index: u64) -> std::io::Result<Self> {
+        let applied = replica.applied_watch();
+        let state = replica.replay_base()?;
+        let snapshot_index = state.last_applied();
+        if snapshot_index > 0 && from_index <= snapshot_index {
+            return Err(SnapshotRequired {
+                first_index: snapshot_index + 1,
+                snapshot_index,
+            }
+            .into_io());
+        }
+        Ok(Self {
+            replica,
+            state,
+            from_index,
+            applied,
+        })
+    }
+
+    /// Waits for the replica to apply something new to report, and returns every change
+    /// it applied since the last call. Fails with `SnapshotRequired` if the replica
+    /// compacted entries this feed had yet to replay.
+    pub async fn next(&mut self) -> std::io::Result<Vec<Change>> {
+        loop {
+            let changes = self.catch_up()?;
+            if !changes.is_empty() {
+                return Ok(changes);
+            }
+            let replayed = self.state.last_applied();
+            // The sender lives as long as the replica, which this feed keeps alive.
+            let _ = self.applied.wait_for(|applied| *applied > replayed).await;
+        }
+    }
+
+    /// Replays what the replica applied since the last call, returning the changes at
+    /// or after `from_index`.
+    fn catch_up(&mut self) -> std::io::Result<Vec<Change>> {
+        let entries = self.replica.applied_entries(self.state.last_applied())?;
+        let mut changes = Vec::new();
+        for entry in entries {
+            let command = BankCommand::decode(entry.command_slice())?;
+            let accounts = touched_accounts(&self.state, &command);
+            let outcome = self.state.apply(&entry)?;
+            if entry.index < self.from_index {
+                continue;
+            }
+            // An account opened under a generated id is only known from the outcome.
+            let created = match &outcome {
+                CommandOutcome::AccountCreated { account } => Some(account.clone()),
+                _ => None,
+            };
+            let balances = accounts
+                .into_iter()
+                .chain(created)
+                .filter_map(|account| Some((account.clone(), self.state.balance(&account)?)))
+                .collect();
+            changes.push(Change {
+                index: entry.index,
+                command,
+                outcome,
+                balances,
+            });
+        }
+        Ok(changes)
+    }
+}
+
+/// Accounts whose balance `command` may change, looked up in `state` before it applies.
+fn touched_accounts(state: &BankStateMachine, command: &BankCommand) -> Vec<String> {
+    match command {
+        BankCommand::CreateAccount { account, .. }
+        | BankCommand::SetOverdraftLimit { account, .. }
+        | BankCommand::SetAccountStatus { account, .. }
+        | BankCommand::Withdraw { account, .. }
+        | BankCommand::Deposit { account, .. }
+        | BankCommand::ConditionalWithdraw { account, .. }
+        | BankCommand::SagaDebit { from: account, .. }
+        | BankCommand::SagaCredit { to: account, .. } => vec![account.clone()],
+        BankCommand::Transfer { from, to, .. } => vec![from.clone(), to.clone()],
+        BankCommand::BatchTransfer { transfers, .. } => transfers
+            .iter()
+            .flat_map(|transfer| [transfer.from.clone(), transfer.to.clone()])
+            .collect(),
+        // Aborting a saga refunds the account it debited.
+        BankCommand::SagaFinish { saga_id, .. } => {
+            state.saga(saga_id).map(|saga| saga.from.clone()).into_iter().collect()
+        }
+        BankCommand::ConfigChange(_) | BankCommand::Unknown { .. } => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use crate::bank::tests::{create_account, transfer};
+    use crate::replica::SnapshotPolicy;
+
+    #[tokio::test]
+    async fn test_change_feed_refuses_compacted_start() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("bank.wal");
+        let policy = SnapshotPolicy {
+            every_entries: 4,
+            every_bytes: 0,
+        };
+        let replica = Replica::open(path.to_str().unwrap()).unwrap().with_snapshot_policy(policy);
+        let replica = Arc::new(replica);
+        replica.propose(&create_account("alice", 100)).unwrap();
+        replica.propose(&create_account("bob", 0)).unwrap();
+        for i in 0..2 {
+            replica.propose(&transfer("alice", "bob", 10, &format!("tx-{}", i))).unwrap();
+        }
+        replica.wait_snapshot(4).await;
+
+        let err = ChangeFeed::open(replica.clone(), 2).err().unwrap();
+        let required = SnapshotRequired::from_io(&err).unwrap();
+        assert_eq!((required.first_index, required.snapshot_index), (5, 4));
+
+        // Past the snapshot, replay starts from it.
+        let mut feed = ChangeFeed::open(replica.clone(), 5).unwrap();
+        replica.propose(&transfer("bob", "alice", 5, "tx-back")).unwrap();
+        let changes = feed.next().await.unwrap();
+        assert_eq!(changes.len(), 1);
+        let balances = &changes[0].balances;
+        assert_eq!((balances["alice"], balances["bob"]), (85, 15));
+    }
+}
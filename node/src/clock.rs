@@ -0,0 +1,114 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// Source of time for timers, so tests can drive them by hand.
+#[tonic::async_trait]
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> Instant;
+
+    async fn sleep_until(&self, deadline: Instant);
+
+    async fn sleep(&self, duration: Duration) {
+        self.sleep_until(self.now() + duration).await;
+    }
+}
+
+/// Tokio's clock, which `tokio::time::pause` still controls.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+#[tonic::async_trait]
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        tokio::time::sleep_until(deadline).await;
+    }
+}
+
+/// A clock that only moves when `advance` is called, waking every sleeper it passes.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: watch::Sender<Instant>,
+}
+
+impl ManualClock {
+    pub fn new() -> Arc<Self> {
+        let (now, _) = watch::channel(Instant::now());
+        Arc::new(Self { now })
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.now.send_modify(|now| *now += by);
+    }
+}
+
+#[tonic::async_trait]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.borrow()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        let mut now = self.now.subscribe();
+        // The sender lives as long as `self`, so the channel cannot close while we wait.
+        let _ = now.wait_for(|now| *now >= deadline).await;
+    }
+}
+
+/// Runs `future` until `deadline` on `clock`, returning `None` if the deadline comes first.
+pub async fn timeout_at<F: Future>(
+    clock: &dyn Clock,
+    deadline: Instant,
+    future: F,
+) -> Option<F::Output> {
+    tokio::select! {
+        output = future => Some(output),
+        _ = clock.sleep_until(deadline) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_manual_clock_wakes_sleepers_it_passes() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let sleeper = tokio::spawn({
+            let clock = clock.clone();
+            async move { clock.sleep(Duration::from_millis(100)).await }
+        });
+        tokio::task::yield_now().await;
+
+        clock.advance(Duration::from_millis(99));
+        tokio::task::yield_now().await;
+        assert!(!sleeper.is_finished());
+        assert_eq!(clock.now() - start, Duration::from_millis(99));
+
+        clock.advance(Duration::from_millis(1));
+        sleeper.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_timeout_at_manual_deadline() {
+        let clock = ManualClock::new();
+        let deadline = clock.now() + Duration::from_secs(1);
+
+        assert_eq!(timeout_at(clock.as_ref(), deadline, async { 7 }).await, Some(7));
+
+        let pending = tokio::spawn({
+            let clock = clock.clone();
+            async move { timeout_at(clock.as_ref(), deadline, std::future::pending::<()>()).await }
+        });
+        tokio::task::yield_now().await;
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(pending.await.unwrap(), None);
+    }
+}
@@ -0,0 +1,565 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use crate::membership::DEFAULT_MAX_MESSAGE_BYTES;
+use crate::proposal::DEFAULT_PROPOSAL_CAPACITY;
+use crate::raft::DEFAULT_MAX_SNAPSHOT_INSTALLS;
+use crate::replica::{SnapshotPolicy, DEFAULT_SNAPSHOT_EVERY_BYTES, DEFAULT_SNAPSHOT_EVERY_ENTRIES};
+use crate::transport::{
+    ChannelTuning, Keepalive, DEFAULT_CONNECTION_WINDOW, DEFAULT_KEEPALIVE_INTERVAL,
+    DEFAULT_KEEPALIVE_TIMEOUT, DEFAULT_MAX_CONCURRENT_STREAMS, DEFAULT_STREAM_WINDOW, MAX_WINDOW,
+};
+use crate::wal::{
+    ApplyErrorPolicy, DEFAULT_DIR_MODE, DEFAULT_FILE_MODE, DEFAULT_MAX_ENTRY_BYTES,
+    DEFAULT_READ_BUFFER_BYTES,
+};
+
+/// Environment variables `NodeConfig::from_env` reads.
+pub const NODE_ID_ENV: &str = "NODE_ID";
+pub const BOOTSTRAP_ENV: &str = "NODE_BOOTSTRAP";
+pub const DATA_DIR_ENV: &str = "NODE_DATA_DIR";
+pub const SNAPSHOT_DIR_ENV: &str = "NODE_SNAPSHOT_DIR";
+pub const DATA_DIR_MODE_ENV: &str = "NODE_DATA_DIR_MODE";
+pub const WAL_FILE_MODE_ENV: &str = "NODE_WAL_FILE_MODE";
+pub const BANK_ADDR_ENV: &str = "NODE_BANK_ADDR";
+pub const BANK_ADVERTISE_ADDR_ENV: &str = "NODE_BANK_ADVERTISE_ADDR";
+pub const RAFT_ADDR_ENV: &str = "NODE_RAFT_ADDR";
+pub const RAFT_ADVERTISE_ADDR_ENV: &str = "NODE_RAFT_ADVERTISE_ADDR";
+pub const GOSSIP_ADDR_ENV: &str = "NODE_GOSSIP_ADDR";
+pub const GOSSIP_ADVERTISE_ADDR_ENV: &str = "NODE_GOSSIP_ADVERTISE_ADDR";
+pub const GOSSIP_MAX_MESSAGE_BYTES_ENV: &str = "NODE_GOSSIP_MAX_MESSAGE_BYTES";
+pub const METRICS_ADDR_ENV: &str = "NODE_METRICS_ADDR";
+pub const PROPOSAL_CAPACITY_ENV: &str = "NODE_PROPOSAL_CAPACITY";
+pub const FAIR_PROPOSALS_ENV: &str = "NODE_FAIR_PROPOSALS";
+pub const PEERS_ENV: &str = "NODE_PEERS";
+pub const PEER_BANK_ADDRS_ENV: &str = "NODE_PEER_BANK_ADDRS";
+pub const PEER_GOSSIP_ADDRS_ENV: &str = "NODE_PEER_GOSSIP_ADDRS";
+pub const WITNESSES_ENV: &str = "NODE_WITNESSES";
+pub const READ_REPLICAS_ENV: &str = "NODE_READ_REPLICAS";
+pub const ELECTION_PRIORITIES_ENV: &str = "NODE_ELECTION_PRIORITIES";
+pub const SNAPSHOT_EVERY_ENTRIES_ENV: &str = "NODE_SNAPSHOT_EVERY_ENTRIES";
+pub const SNAPSHOT_EVERY_BYTES_ENV: &str = "NODE_SNAPSHOT_EVERY_BYTES";
+pub const REMOVE_DEAD_AFTER_SECS_ENV: &str = "NODE_REMOVE_DEAD_AFTER_SECS";
+pub const KEEPALIVE_INTERVAL_SECS_ENV: &str = "NODE_KEEPALIVE_INTERVAL_SECS";
+pub const KEEPALIVE_TIMEOUT_SECS_ENV: &str = "NODE_KEEPALIVE_TIMEOUT_SECS";
+pub const MAX_ENTRY_BYTES_ENV: &str = "NODE_MAX_ENTRY_BYTES";
+pub const RAFT_WAL_FLUSH_MS_ENV: &str = "NODE_RAFT_WAL_FLUSH_MS";
+pub const RAFT_TCP_NODELAY_ENV: &str = "NODE_RAFT_TCP_NODELAY";
+pub const RAFT_TRIM_ON_STEP_DOWN_ENV: &str = "NODE_RAFT_TRIM_ON_STEP_DOWN";
+pub const MAX_SNAPSHOT_INSTALLS_ENV: &str = "NODE_MAX_SNAPSHOT_INSTALLS";
+pub const REJECT_EXCESS_SNAPSHOT_INSTALLS_ENV: &str = "NODE_REJECT_EXCESS_SNAPSHOT_INSTALLS";
+pub const TENANT_ISOLATION_ENV: &str = "NODE_TENANT_ISOLATION";
+pub const APPLY_ERROR_POLICY_ENV: &str = "NODE_APPLY_ERROR_POLICY";
+pub const WAL_READ_BUFFER_BYTES_ENV: &str = "NODE_WAL_READ_BUFFER_BYTES";
+pub const WAL_QUARANTINE_ENV: &str = "NODE_WAL_QUARANTINE";
+pub const RAFT_STREAM_WINDOW_BYTES_ENV: &str = "NODE_RAFT_STREAM_WINDOW_BYTES";
+pub const RAFT_CONNECTION_WINDOW_BYTES_ENV: &str = "NODE_RAFT_CONNECTION_WINDOW_BYTES";
+pub const RAFT_MAX_CONCURRENT_STREAMS_ENV: &str = "NODE_RAFT_MAX_CONCURRENT_STREAMS";
+pub const REGION_ENV: &str = "NODE_REGION";
+pub const RACK_ENV: &str = "NODE_RACK";
+
+/// Where one of a node's services binds, and the address peers and clients dial to reach
+/// it, which differs from the bind address behind NAT or a container's port mapping.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServiceAddrs {
+    pub listen_addr: SocketAddr,
+    pub advertise_addr: String,
+}
+
+/// Where a node keeps its data, where it listens, and who its Raft peers are.
+#[derive(Clone, Debug)]
+pub struct NodeConfig {
+    pub id: String,
+    pub data_dir: PathBuf,
+    /// Where snapshots of the bank are kept, which may be other storage than the WAL's.
+    pub snapshot_dir: PathBuf,
+    pub bank: ServiceAddrs,
+    pub raft: ServiceAddrs,
+    /// Served by the Raft server when it listens on the same address.
+    pub gossip: ServiceAddrs,
+    /// Largest gossip message, in bytes; longer member lists are sent in fragments.
+    pub gossip_max_message_bytes: u64,
+    /// Where the Prometheus `/metrics` endpoint listens.
+    pub metrics_addr: SocketAddr,
+    /// Writes that may wait for the WAL before new ones are rejected.
+    pub proposal_capacity: usize,
+    /// Whether writes are committed by turns between the clients sending them, rather
+    /// than in the order they arrive.
+    pub fair_proposals: bool,
+    /// Peer node ids mapped to their Raft addresses, excluding this node.
+    pub peers: BTreeMap<String, String>,
+    /// Whether this node forms a new cluster on its own, which others join as voters
+    /// added by config change. It has no peers, and must have no Raft log yet.
+    pub bootstrap: bool,
+    /// Peer node ids mapped to their bank addresses, handed to clients as leader hints
+    /// until a peer advertises its own through gossip.
+    pub peer_bank_addrs: BTreeMap<String, String>,
+    /// Peer node ids mapped to where they gossip, for those that do not on their Raft
+    /// address.
+    pub peer_gossip_addrs: BTreeMap<String, String>,
+    /// Node ids, possibly including this node, that vote but store no Raft log.
+    pub witnesses: BTreeSet<String>,
+    /// Node ids, possibly including this node, that store the log but never lead.
+    pub read_replicas: BTreeSet<String>,
+    /// Election priorities by node id, possibly including this node; unlisted nodes have 0.
+    pub election_priorities: BTreeMap<String, u32>,
+    /// Where this node runs, announced through gossip so that a leader replicates to its
+    /// nearest voters first; empty if unsaid.
+    pub region: String,
+    pub rack: String,
+    /// Entries applied since the last snapshot after which a new one is taken; 0 never.
+    pub snapshot_every_entries: u64,
+    /// WAL size in bytes after which a new snapshot is taken; 0 never.
+    pub snapshot_every_bytes: u64,
+    /// How long a voter must be dead in gossip before the leader removes it from the
+    /// voters, or `None` to never remove voters on its own.
+    pub remove_dead_after: Option<Duration>,
+    /// How connections to Raft peers are kept alive.
+    pub keepalive: Keepalive,
+    /// How connections to and from Raft peers are tuned.
+    pub raft_tuning: ChannelTuning,
+    /// Largest command, in bytes, a write may carry; larger ones are refused.
+    pub max_entry_bytes: u64,
+    /// How often the Raft log is fsynced in the background, with appends in between
+    /// sharing the fsync, or `None` to fsync every append.
+    pub raft_wal_flush_interval: Option<Duration>,
+    /// Whether the Raft log drops its uncommitted entries when this node stops leading.
+    pub raft_trim_on_step_down: bool,
+    /// Most snapshots from the leader this node receives at once, and whether any more
+    /// are turned away, for the leader to retry, rather than made to wait.
+    pub max_snapshot_installs: u32,
+    pub reject_excess_snapshot_installs: bool,
+    /// Whether transfers between accounts of different tenants are refused.
+    pub tenant_isolation: bool,
+    /// What replaying the WAL does with an entry that fails to apply.
+    pub apply_error_policy: ApplyErrorPolicy,
+    /// Bytes the WAL and Raft log are read at a time as they are scanned and replayed.
+    pub wal_read_buffer_bytes: usize,
+    /// Whether starting up moves aside the part of the WAL or Raft log from a block that
+    /// cannot be read on, rather than failing on it.
+    pub wal_quarantine: bool,
+    /// Permission bits `data_dir` is created with, if missing.
+    pub data_dir_mode: u32,
+    /// Permission bits the WAL is created with, if missing.
+    pub wal_file_mode: u32,
+}
+
+impl NodeConfig {
+    /// Reads the `NODE_*` variables, falling back to a single local node.
+    /// Each service advertises the address it listens on unless its `*_ADVERTISE_ADDR`
+    /// says otherwise, and gossip shares the Raft server unless `NODE_GOSSIP_ADDR` is set.
+    /// `NODE_PEERS`, `NODE_PEER_BANK_ADDRS` and `NODE_PEER_GOSSIP_ADDRS` are
+    /// comma-separated `id=host:port` lists, and `NODE_WITNESSES` and
+    /// `NODE_READ_REPLICAS` comma-separated lists of node ids.
+    /// `NODE_ELECTION_PRIORITIES` is a comma-separated `id=priority` list.
+    /// `NODE_RACK` only counts alongside `NODE_REGION`, and `NODE_SNAPSHOT_DIR` defaults
+    /// to `NODE_DATA_DIR`.
+    /// `NODE_SNAPSHOT_EVERY_ENTRIES` and `NODE_SNAPSHOT_EVERY_BYTES` may be 0 to turn
+    /// that snapshot trigger off. `NODE_REMOVE_DEAD_AFTER_SECS` and
+    /// `NODE_RAFT_WAL_FLUSH_MS` are off unless set above 0.
+    /// `NODE_KEEPALIVE_INTERVAL_SECS`, `NODE_KEEPALIVE_TIMEOUT_SECS`,
+    /// `NODE_MAX_ENTRY_BYTES`, `NODE_GOSSIP_MAX_MESSAGE_BYTES` and
+    /// `NODE_WAL_READ_BUFFER_BYTES` must be above 0.
+    /// `NODE_DATA_DIR_MODE` and `NODE_WAL_FILE_MODE` are octal permission bits, such as
+    /// `750` or `0o640`. `NODE_BOOTSTRAP`, `NODE_FAIR_PROPOSALS`, `NODE_RAFT_TCP_NODELAY`,
+    /// `NODE_RAFT_TRIM_ON_STEP_DOWN`, `NODE_REJECT_EXCESS_SNAPSHOT_INSTALLS`,
+    /// `NODE_TENANT_ISOLATION` and `NODE_WAL_QUARANTINE` are `true` or `false`,
+    /// `NODE_APPLY_ERROR_POLICY` is `halt`, the default, or `skip`, and
+    /// `NODE_RAFT_STREAM_WINDOW_BYTES`, `NODE_RAFT_CONNECTION_WINDOW_BYTES`,
+    /// `NODE_RAFT_MAX_CONCURRENT_STREAMS` and `NODE_MAX_SNAPSHOT_INSTALLS` must be above
+    /// 0, the windows below 2 GiB.
+    pub fn from_env() -> std::io::Result<Self> {
+        let var = |name: &str, default: &str| {
+            std::env::var(name).unwrap_or_else(|_| default.to_string())
+        };
+
+        let id = var(NODE_ID_ENV, "node-1");
+        let peers = |name: &str| -> std::io::Result<BTreeMap<String, String>> {
+            let mut peers = parse_peers(name, &var(name, ""))?;
+            peers.remove(&id);
+            Ok(peers)
+        };
+
+        let advertise = |name: &str| std::env::var(name).ok();
+        let raft = parse_service_addrs(
+            (RAFT_ADDR_ENV, &var(RAFT_ADDR_ENV, "127.0.0.1:50061")),
+            (RAFT_ADVERTISE_ADDR_ENV, advertise(RAFT_ADVERTISE_ADDR_ENV)),
+        )?;
+        let gossip = match std::env::var(GOSSIP_ADDR_ENV) {
+            Ok(listen) => parse_service_addrs(
+                (GOSSIP_ADDR_ENV, &listen),
+                (GOSSIP_ADVERTISE_ADDR_ENV, advertise(GOSSIP_ADVERTISE_ADDR_ENV)),
+            )?,
+            Err(_) => raft.clone(),
+        };
+
+        let data_dir = var(DATA_DIR_ENV, "data");
+        Ok(Self {
+            snapshot_dir: PathBuf::from(var(SNAPSHOT_DIR_ENV, &data_dir)),
+            data_dir: PathBuf::from(data_dir),
+            bank: parse_service_addrs(
+                (BANK_ADDR_ENV, &var(BANK_ADDR_ENV, "127.0.0.1:50051")),
+                (BANK_ADVERTISE_ADDR_ENV, advertise(BANK_ADVERTISE_ADDR_ENV)),
+            )?,
+            raft,
+            gossip,
+            gossip_max_message_bytes: parse_bytes(
+                GOSSIP_MAX_MESSAGE_BYTES_ENV,
+                DEFAULT_MAX_MESSAGE_BYTES as u64,
+            )?,
+            metrics_addr: parse_addr(METRICS_ADDR_ENV, &var(METRICS_ADDR_ENV, "127.0.0.1:9464"))?,
+            proposal_capacity: parse_capacity(&var(
+                PROPOSAL_CAPACITY_ENV,
+                &DEFAULT_PROPOSAL_CAPACITY.to_string(),
+            ))?,
+            fair_proposals: parse_bool(FAIR_PROPOSALS_ENV, false)?,
+            peers: peers(PEERS_ENV)?,
+            bootstrap: parse_bool(BOOTSTRAP_ENV, false)?,
+            peer_bank_addrs: peers(PEER_BANK_ADDRS_ENV)?,
+            peer_gossip_addrs: peers(PEER_GOSSIP_ADDRS_ENV)?,
+            witnesses: parse_ids(&var(WITNESSES_ENV, "")),
+            read_replicas: parse_ids(&var(READ_REPLICAS_ENV, "")),
+            election_priorities: parse_priorities(&var(ELECTION_PRIORITIES_ENV, ""))?,
+            region: var(REGION_ENV, ""),
+            rack: var(RACK_ENV, ""),
+            snapshot_every_entries: parse_u64(
+                SNAPSHOT_EVERY_ENTRIES_ENV,
+                &var(SNAPSHOT_EVERY_ENTRIES_ENV, &DEFAULT_SNAPSHOT_EVERY_ENTRIES.to_string()),
+            )?,
+            snapshot_every_bytes: parse_u64(
+                SNAPSHOT_EVERY_BYTES_ENV,
+                &var(SNAPSHOT_EVERY_BYTES_ENV, &DEFAULT_SNAPSHOT_EVERY_BYTES.to_string()),
+            )?,
+            remove_dead_after: Some(parse_u64(
+                REMOVE_DEAD_AFTER_SECS_ENV,
+                &var(REMOVE_DEAD_AFTER_SECS_ENV, "0"),
+            )?)
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs),
+            keepalive: Keepalive {
+                interval: parse_secs(KEEPALIVE_INTERVAL_SECS_ENV, DEFAULT_KEEPALIVE_INTERVAL)?,
+                timeout: parse_secs(KEEPALIVE_TIMEOUT_SECS_ENV, DEFAULT_KEEPALIVE_TIMEOUT)?,
+            },
+            raft_tuning: ChannelTuning {
+                tcp_nodelay: parse_bool(RAFT_TCP_NODELAY_ENV, true)?,
+                stream_window: parse_limit(
+                    RAFT_STREAM_WINDOW_BYTES_ENV,
+                    DEFAULT_STREAM_WINDOW,
+                    MAX_WINDOW,
+                )?,
+                connection_window: parse_limit(
+                    RAFT_CONNECTION_WINDOW_BYTES_ENV,
+                    DEFAULT_CONNECTION_WINDOW,
+                    MAX_WINDOW,
+                )?,
+                max_concurrent_streams: parse_limit(
+                    RAFT_MAX_CONCURRENT_STREAMS_ENV,
+                    DEFAULT_MAX_CONCURRENT_STREAMS,
+                    u32::MAX,
+                )?,
+            },
+            max_entry_bytes: parse_bytes(MAX_ENTRY_BYTES_ENV, DEFAULT_MAX_ENTRY_BYTES)?,
+            raft_wal_flush_interval: Some(parse_u64(
+                RAFT_WAL_FLUSH_MS_ENV,
+                &var(RAFT_WAL_FLUSH_MS_ENV, "0"),
+            )?)
+            .filter(|&millis| millis > 0)
+            .map(Duration::from_millis),
+            raft_trim_on_step_down: parse_bool(RAFT_TRIM_ON_STEP_DOWN_ENV, false)?,
+            max_snapshot_installs: parse_limit(
+                MAX_SNAPSHOT_INSTALLS_ENV,
+                DEFAULT_MAX_SNAPSHOT_INSTALLS,
+                u32::MAX,
+            )?,
+            reject_excess_snapshot_installs: parse_bool(
+                REJECT_EXCESS_SNAPSHOT_INSTALLS_ENV,
+                false,
+            )?,
+            tenant_isolation: parse_bool(TENANT_ISOLATION_ENV, false)?,
+            apply_error_policy: parse_apply_error_policy(&var(APPLY_ERROR_POLICY_ENV, "halt"))?,
+            wal_read_buffer_bytes: parse_bytes(
+                WAL_READ_BUFFER_BYTES_ENV,
+                DEFAULT_READ_BUFFER_BYTES as u64,
+            )? as usize,
+            wal_quarantine: parse_bool(WAL_QUARANTINE_ENV, false)?,
+            data_dir_mode: parse_mode(DATA_DIR_MODE_ENV, std::env::var(DATA_DIR_MODE_ENV).ok())?
+                .unwrap_or(DEFAULT_DIR_MODE),
+            wal_file_mode: parse_mode(WAL_FILE_MODE_ENV, std::env::var(WAL_FILE_MODE_ENV).ok())?
+                .unwrap_or(DEFAULT_FILE_MODE),
+            id,
+        })
+    }
+
+    /// Where to gossip with each peer when joining the cluster.
+    pub fn gossip_seeds(&self) -> Vec<String> {
+        self.peers
+            .iter()
+            .map(|(id, raft_addr)| self.peer_gossip_addrs.get(id).unwrap_or(raft_addr).clone())
+            .collect()
+    }
+
+    pub fn wal_path(&self) -> PathBuf {
+        self.data_dir.join("bank.wal")
+    }
+
+    /// Named as it is next to the WAL, so a snapshot moves between the two directories
+    /// as it is.
+    pub fn snapshot_path(&self) -> PathBuf {
+        self.snapshot_dir.join("bank.wal.snapshot")
+    }
+
+    pub fn raft_state_path(&self) -> PathBuf {
+        self.data_dir.join("raft.state")
+    }
+
+    pub fn raft_log_path(&self) -> PathBuf {
+        self.data_dir.join("raft.wal")
+    }
+
+    pub fn snapshot_policy(&self) -> SnapshotPolicy {
+        SnapshotPolicy {
+            every_entries: self.snapshot_every_entries,
+            every_bytes: self.snapshot_every_bytes,
+        }
+    }
+}
+
+fn parse_peers(name: &str, peers: &str) -> std::io::Result<BTreeMap<String, String>> {
+    peers
+        .split(',')
+        .map(str::trim)
+        .filter(|peer| !peer.is_empty())
+        .map(|peer| match peer.split_once('=') {
+            Some((id, addr)) if !id.is_empty() && !addr.is_empty() => {
+                Ok((id.to_string(), addr.to_string()))
+            }
+            _ => Err(invalid_input(format!("{} entry {:?} is not id=host:port", name, peer))),
+        })
+        .collect()
+}
+
+fn parse_priorities(priorities: &str) -> std::io::Result<BTreeMap<String, u32>> {
+    priorities
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| match entry.split_once('=') {
+            Some((id, priority)) if !id.is_empty() => match priority.parse() {
+                Ok(priority) => Ok((id.to_string(), priority)),
+                Err(_) => Err(invalid_input(format!(
+                    "{} priority {:?} of {} is not a whole number",
+                    ELECTION_PRIORITIES_ENV, priority, id
+                ))),
+            },
+            _ => Err(invalid_input(format!(
+                "{} entry {:?} is not id=priority",
+                ELECTION_PRIORITIES_ENV, entry
+            ))),
+        })
+        .collect()
+}
+
+fn parse_ids(ids: &str) -> BTreeSet<String> {
+    ids.split(',').map(str::trim).filter(|id| !id.is_empty()).map(str::to_string).collect()
+}
+
+fn parse_addr(name: &str, addr: &str) -> std::io::Result<SocketAddr> {
+    addr.parse()
+        .map_err(|e| invalid_input(format!("{} {:?} is not a socket address: {}", name, addr, e)))
+}
+
+/// Reads a listen address, and the address to advertise instead of it, if any, each
+/// with the name of the variable it came from.
+fn parse_service_addrs(
+    (listen_name, listen): (&str, &str),
+    (advertise_name, advertise): (&str, Option<String>),
+) -> std::io::Result<ServiceAddrs> {
+    let listen_addr = parse_addr(listen_name, listen)?;
+    let advertise = advertise.as_deref().map(str::trim).filter(|addr| !addr.is_empty());
+    let advertise_addr = match advertise {
+        None => listen_addr.to_string(),
+        Some(addr) => {
+            let valid = addr.rsplit_once(':').is_some_and(|(host, port)| {
+                !host.is_empty() && port.parse::<u16>().is_ok()
+            });
+            if !valid {
+                let message = format!("{} {:?} is not host:port", advertise_name, addr);
+                return Err(invalid_input(message));
+            }
+            addr.to_string()
+        }
+    };
+    Ok(ServiceAddrs {
+        listen_addr,
+        advertise_addr,
+    })
+}
+
+fn parse_capacity(capacity: &str) -> std::io::Result<usize> {
+    match capacity.parse() {
+        Ok(capacity) if capacity > 0 => Ok(capacity),
+        _ => Err(invalid_input(format!(
+            "{} {:?} is not a positive integer",
+            PROPOSAL_CAPACITY_ENV, capacity
+        ))),
+    }
+}
+
+fn parse_u64(name: &str, value: &str) -> std::io::Result<u64> {
+    value
+        .parse()
+        .map_err(|_| invalid_input(format!("{} {:?} is not a whole number", name, value)))
+}
+
+/// Reads whole seconds above 0 from the variable `name`, or returns `default` if unset.
+fn parse_secs(name: &str, default: Duration) -> std::io::Result<Duration> {
+    let Ok(value) = std::env::var(name) else {
+        return Ok(default);
+    };
+    match parse_u64(name, &value)? {
+        0 => Err(invalid_input(format!("{} must be above 0", name))),
+        secs => Ok(Duration::from_secs(secs)),
+    }
+}
+
+/// Reads a byte count above 0 from the variable `name`, or returns `default` if unset.
+fn parse_bytes(name: &str, default: u64) -> std::io::Result<u64> {
+    let Ok(value) = std::env::var(name) else {
+        return Ok(default);
+    };
+    match parse_u64(name, &value)? {
+        0 => Err(invalid_input(format!("{} must be above 0", name))),
+        bytes => Ok(bytes),
+    }
+}
+
+/// Reads a number from 1 to `max` from the variable `name`, or returns `default` if unset.
+fn parse_limit(name: &str, default: u32, max: u32) -> std::io::Result<u32> {
+    let Ok(value) = std::env::var(name) else {
+        return Ok(default);
+    };
+    match parse_u64(name, &value)? {
+        limit @ 1.. if limit <= u64::from(max) => Ok(limit as u32),
+        _ => Err(invalid_input(format!("{} must be from 1 to {}", name, max))),
+    }
+}
+
+/// Reads `true` or `false` from the variable `name`, or returns `default` if unset.
+fn parse_bool(name: &str, default: bool) -> std::io::Result<bool> {
+    let Ok(value) = std::env::var(name) else {
+        return Ok(default);
+    };
+    value
+        .parse()
+        .map_err(|_| invalid_input(format!("{} {:?} is not true or false", name, value)))
+}
+
+fn parse_apply_error_policy(value: &str) -> std::io::Result<ApplyErrorPolicy> {
+    match value.trim() {
+        "halt" => Ok(ApplyErrorPolicy::Halt),
+        "skip" => Ok(ApplyErrorPolicy::SkipAndLog),
+        _ => Err(invalid_input(format!(
+            "{} {:?} is not halt or skip",
+            APPLY_ERROR_POLICY_ENV, value
+        ))),
+    }
+}
+
+/// Parses the variable `name`, if set, as octal permission bits, with or without a
+/// leading `0o` or `0`.
+fn parse_mode(name: &str, value: Option<String>) -> std::io::Result<Option<u32>> {
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    let digits = value.trim();
+    let digits = digits.strip_prefix("0o").unwrap_or(digits);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(Some(mode)),
+        _ => Err(invalid_input(format!(
+            "{} {:?} is not octal permission bits, such as 600",
+            name, value
+        ))),
+    }
+}
+
+fn invalid_input(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_peers() {
+        let peers =
+            parse_peers(PEERS_ENV, " node-2=10.0.0.2:50061, node-3=10.0.0.3:50061 ,").unwrap();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers["node-2"], "10.0.0.2:50061");
+        assert_eq!(peers["node-3"], "10.0.0.3:50061");
+
+        assert!(parse_peers(PEERS_ENV, "").unwrap().is_empty());
+        assert!(parse_peers(PEERS_ENV, "node-2").is_err());
+        assert!(parse_peers(PEERS_ENV, "=10.0.0.2:50061").is_err());
+    }
+
+    #[test]
+    fn test_parse_service_addrs() {
+        let listen = (BANK_ADDR_ENV, "0.0.0.0:50051");
+        let advertised = |addr: &str| (BANK_ADVERTISE_ADDR_ENV, Some(addr.to_string()));
+
+        let addrs = parse_service_addrs(listen, advertised("bank.node-1.example:30051")).unwrap();
+        assert_eq!(addrs.listen_addr, "0.0.0.0:50051".parse().unwrap());
+        assert_eq!(addrs.advertise_addr, "bank.node-1.example:30051");
+        let addrs = parse_service_addrs(listen, (BANK_ADVERTISE_ADDR_ENV, None)).unwrap();
+        assert_eq!(addrs.advertise_addr, "0.0.0.0:50051");
+        assert_eq!(parse_service_addrs(listen, advertised(" ")).unwrap(), addrs);
+
+        assert!(parse_service_addrs(listen, advertised("bank.node-1.example")).is_err());
+        assert!(parse_service_addrs(listen, advertised(":30051")).is_err());
+        assert!(parse_service_addrs((BANK_ADDR_ENV, "bank:50051"), (BANK_ADVERTISE_ADDR_ENV, None))
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        let mode = |value: &str| parse_mode(WAL_FILE_MODE_ENV, Some(value.to_string()));
+        assert_eq!(mode("600").unwrap(), Some(0o600));
+        assert_eq!(mode("0640").unwrap(), Some(0o640));
+        assert_eq!(mode("0o750").unwrap(), Some(0o750));
+        assert_eq!(parse_mode(WAL_FILE_MODE_ENV, None).unwrap(), None);
+
+        assert!(mode("").is_err());
+        assert!(mode("rw-------").is_err());
+        assert!(mode("680").is_err());
+        assert!(mode("1777").is_err());
+    }
+
+    #[test]
+    fn test_parse_apply_error_policy() {
+        assert_eq!(parse_apply_error_policy("halt").unwrap(), ApplyErrorPolicy::Halt);
+        assert_eq!(parse_apply_error_policy("skip").unwrap(), ApplyErrorPolicy::SkipAndLog);
+        assert!(parse_apply_error_policy("panic").is_err());
+    }
+
+    #[test]
+    fn test_parse_priorities() {
+        let priorities = parse_priorities("node-1=2, node-3=0").unwrap();
+        assert_eq!(priorities.into_iter().collect::<Vec<_>>(), vec![
+            ("node-1".to_string(), 2),
+            ("node-3".to_string(), 0),
+        ]);
+        assert!(parse_priorities("").unwrap().is_empty());
+        assert!(parse_priorities("node-1=high").is_err());
+        assert!(parse_priorities("node-1=-1").is_err());
+        assert!(parse_priorities("node-1").is_err());
+    }
+
+    #[test]
+    fn test_parse_ids() {
+        let ids: Vec<String> = parse_ids(" node-3, ,witness-1 ").into_iter().collect();
+        assert_eq!(ids, vec!["node-3", "witness-1"]);
+        assert!(parse_ids("").is_empty());
+    }
+}
@@ -0,0 +1,146 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::server::NamedService;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+use bank_api::bank::bank_service_server::BankServiceServer;
+use crate::raft::RaftNode;
+use crate::service::BankServiceImpl;
+
+/// How often `HealthMonitor::run` re-evaluates the node's status.
+pub const DEFAULT_HEALTH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Services whose status is published: the whole server (`""`) and the bank API.
+const SERVICES: [&str; 2] = ["", <BankServiceServer<BankServiceImpl> as NamedService>::NAME];
+
+/// Publishes `grpc.health.v1` status for a node: SERVING once its WAL has been
+/// replayed and it is in contact with a Raft majority, NOT_SERVING otherwise, and for
+/// good once it has halted.
+pub struct HealthMonitor {
+    reporter: HealthReporter,
+    raft: Arc<RaftNode>,
+    replayed: AtomicBool,
+    halted: AtomicBool,
+}
+
+impl HealthMonitor {
+    /// Starts out NOT_SERVING until `mark_replayed` and a quorum are both reached.
+    pub async fn new(reporter: HealthReporter, raft: Arc<RaftNode>) -> Self {
+        let monitor = Self {
+            reporter,
+            raft,
+            replayed: AtomicBool::new(false),
+            halted: AtomicBool::new(false),
+        };
+        monitor.publish(ServingStatus::NotServing).await;
+        monitor
+    }
+
+    pub fn mark_replayed(&self) {
+        self.replayed.store(true, Ordering::Release);
+    }
+
+    /// Marks the node as having stopped applying the log, e.g. on an entry that failed to
+    /// apply, so it is NOT_SERVING whatever else holds.
+    pub fn mark_halted(&self) {
+        self.halted.store(true, Ordering::Release);
+    }
+
+    pub fn is_serving(&self) -> bool {
+        self.replayed.load(Ordering::Acquire)
+            && !self.halted.load(Ordering::Acquire)
+            && self.raft.has_quorum()
+    }
+
+    pub async fn refresh(&self) {
+        let status = if self.is_serving() {
+            ServingStatus::Serving
+        } else {
+            ServingStatus::NotServing
+        };
+        self.publish(status).await;
+    }
+
+    /// Refreshes the published status every `interval`, forever.
+    pub async fn run(self: Arc<Self>, interval: Duration) {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            self.refresh().await;
+        }
+    }
+
+    async fn publish(&self, status: ServingStatus) {
+        for service in SERVICES {
+            self.reporter.set_service_status(service, status).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::Request;
+    use tonic_health::pb::health_check_response::ServingStatus as ProtoStatus;
+    use tonic_health::pb::health_server::Health;
+    use tonic_health::pb::HealthCheckRequest;
+    use tonic_health::server::HealthService;
+    use crate::raft::tests::TestCluster;
+
+    async fn status(health: &HealthService, service: &str) -> ProtoStatus {
+        let request = Request::new(HealthCheckRequest {
+            service: service.to_string(),
+        });
+        let response = health.check(request).await.unwrap().into_inner();
+        ProtoStatus::try_from(response.status).unwrap()
+    }
+
+    async fn assert_status(health: &HealthService, expected: ProtoStatus) {
+        for service in SERVICES {
+            assert_eq!(status(health, service).await, expected, "service {:?}", service);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_health_serving_after_replay() {
+        let cluster = TestCluster::start(1);
+        let reporter = HealthReporter::new();
+        let health = HealthService::from_health_reporter(reporter.clone());
+        let monitor = HealthMonitor::new(reporter, cluster.node("node-1")).await;
+
+        cluster.wait_for_leader().await;
+        monitor.refresh().await;
+        assert_status(&health, ProtoStatus::NotServing).await;
+
+        monitor.mark_replayed();
+        monitor.refresh().await;
+        assert_status(&health, ProtoStatus::Serving).await;
+
+        monitor.mark_halted();
+        monitor.refresh().await;
+        assert_status(&health, ProtoStatus::NotServing).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_health_not_serving_without_majority() {
+        let cluster = TestCluster::start(3);
+        let reporter = HealthReporter::new();
+        let health = HealthService::from_health_reporter(reporter.clone());
+        let monitor = Arc::new(HealthMonitor::new(reporter, cluster.node("node-1")).await);
+        monitor.mark_replayed();
+        tokio::spawn(monitor.clone().run(Duration::from_millis(100)));
+
+        cluster.wait_for_leader().await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_status(&health, ProtoStatus::Serving).await;
+
+        cluster.network.isolate("node-1");
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_status(&health, ProtoStatus::NotServing).await;
+
+        cluster.network.heal();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_status(&health, ProtoStatus::Serving).await;
+    }
+}
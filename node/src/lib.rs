@@ -0,0 +1,18 @@
+pub mod applier;
+pub mod bank;
+pub mod changes;
+pub mod clock;
+pub mod config;
+pub mod health;
+pub mod membership;
+pub mod metrics;
+pub mod proposal;
+pub mod raft;
+pub mod reaper;
+pub mod replica;
+pub mod saga;
+pub mod service;
+pub mod shard;
+pub mod telemetry;
+pub mod transport;
+pub mod wal;
@@ -1,6 +1,203 @@
-mod wal;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+use bank_api::bank::bank_service_server::BankServiceServer;
+use gossip::gossip::gossip_server::GossipServer;
+use raft_core::raft::raft_server::RaftServer;
+use node::applier::RaftApplier;
+use node::bank::{CommandValidator, DefaultValidator, TenantValidator};
+use node::config::NodeConfig;
+use node::health::{HealthMonitor, DEFAULT_HEALTH_INTERVAL};
+use node::membership::{self, Membership};
+use node::metrics::{self, NodeMetrics};
+use node::proposal::proposal_queue;
+use node::raft::{
+    GrpcTransport, RaftConfig, RaftEvent, RaftNode, DEFAULT_CHURN_THRESHOLD,
+    DEFAULT_CHURN_WINDOW,
+};
+use node::reaper::{DeadNodeReaper, DEFAULT_REAP_INTERVAL};
+use node::replica::Replica;
+use node::service::{BankServiceImpl, BearerAuth, GossipServiceImpl, RaftServiceImpl};
+use node::telemetry;
+use node::transport::{self, TlsConfig};
+use node::wal;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    telemetry::init().map_err(|e| e as Box<dyn std::error::Error>)?;
+    let config = NodeConfig::from_env()?;
+    wal::create_dir_all(&config.data_dir, config.data_dir_mode)?;
+    wal::create_dir_all(&config.snapshot_dir, config.data_dir_mode)?;
+    let tls = TlsConfig::from_env()?;
+    let metrics = Arc::new(NodeMetrics::new());
+    if config.wal_quarantine {
+        // Opening the logs this way first moves aside whatever in them cannot be read, and
+        // logs the entries that leaves missing, so opening them below does not fail on it.
+        for path in [config.raft_log_path(), config.wal_path()].iter().filter(|p| p.exists()) {
+            let (mode, bytes) = (config.wal_file_mode, config.wal_read_buffer_bytes);
+            let path = path.to_string_lossy();
+            wal::Wal::new_with_quarantine(&path, wal::IntegrityMode::Full, mode, bytes)?;
+        }
+    }
+
+    // Refuse to start if a live node already has this id, before voting or appending as it.
+    let membership = Membership::new(config.id.clone(), config.raft.advertise_addr.clone())
+        .with_bank_addr(config.bank.advertise_addr.clone())
+        .with_gossip_addr(config.gossip.advertise_addr.clone())
+        .with_topology(config.region.clone(), config.rack.clone())
+        .with_max_message_bytes(config.gossip_max_message_bytes as usize);
+    let membership = Arc::new(membership);
+    membership::join(&membership, &config.gossip_seeds(), tls.as_ref()).await?;
+
+    let peers = GrpcTransport::new(
+        config.peers.clone(),
+        tls.clone(),
+        config.keepalive,
+        config.raft_tuning,
+    );
+    let mut raft_config = RaftConfig::new(config.id.clone(), config.peers.clone());
+    raft_config.witnesses = config.witnesses.clone();
+    raft_config.read_replicas = config.read_replicas.clone();
+    raft_config.election_priorities = config.election_priorities.clone();
+    raft_config.topology.insert(config.id.clone(), membership.local().topology());
+    raft_config.max_entry_bytes = config.max_entry_bytes;
+    raft_config.wal_flush_interval = config.raft_wal_flush_interval;
+    raft_config.trim_uncommitted_on_step_down = config.raft_trim_on_step_down;
+    raft_config.max_snapshot_installs = config.max_snapshot_installs;
+    raft_config.reject_excess_snapshot_installs = config.reject_excess_snapshot_installs;
+    raft_config.wal_read_buffer_bytes = config.wal_read_buffer_bytes;
+    raft_config.snapshot_dir = Some(config.snapshot_dir.clone());
+    let raft = match config.bootstrap {
+        true => RaftNode::bootstrap(
+            raft_config,
+            config.raft_state_path(),
+            config.raft_log_path(),
+            Arc::new(peers),
+        )?,
+        false => RaftNode::new(
+            raft_config,
+            config.raft_state_path(),
+            config.raft_log_path(),
+            Arc::new(peers),
+        )?,
+    };
+    raft.on_election_churn(DEFAULT_CHURN_THRESHOLD, DEFAULT_CHURN_WINDOW, |churn| {
+        warn!(elections = churn.elections, window = ?churn.window, "leader elections are churning");
+    });
+
+    // Health stays NOT_SERVING until replay has finished and the node has found a majority.
+    let (reporter, health_service) = tonic_health::server::health_reporter();
+    let health = Arc::new(HealthMonitor::new(reporter, raft.clone()).await);
+
+    let wal_path = config.wal_path();
+    let validator: Arc<dyn CommandValidator> = match config.tenant_isolation {
+        true => Arc::new(TenantValidator::new(Arc::new(DefaultValidator))),
+        false => Arc::new(DefaultValidator),
+    };
+    let replica = Replica::open_with_snapshot_path(
+        &wal_path.to_string_lossy(),
+        metrics.wal(),
+        validator,
+        config.wal_file_mode,
+        config.apply_error_policy,
+        config.wal_read_buffer_bytes,
+        &config.snapshot_path(),
+    )?
+    .with_snapshot_policy(config.snapshot_policy())
+    .with_max_entry_bytes(config.max_entry_bytes);
+    let replica = Arc::new(replica);
+    health.mark_replayed();
+    if replica.halted().is_some() {
+        health.mark_halted();
+    }
+    let events = raft.events();
+    raft.start();
+    tokio::spawn(health.run(DEFAULT_HEALTH_INTERVAL));
+    tokio::spawn(membership::share_topology(membership.clone(), raft.clone()));
+    if let Some(grace) = config.remove_dead_after {
+        let reaper = DeadNodeReaper::new(raft.clone(), membership.clone(), grace);
+        tokio::spawn(reaper.run(DEFAULT_REAP_INTERVAL));
+    }
+
+    metrics.track_raft(raft.clone());
+    metrics.track_replica(replica.clone());
+    // Every node applies what Raft commits; only the leader's proposals are taken.
+    tokio::spawn(RaftApplier::new(raft.clone(), replica.clone()).run());
+    let (proposals, proposal_worker) = proposal_queue(config.proposal_capacity);
+    proposal_worker.with_fair_queuing(config.fair_proposals).spawn_to_raft(raft.clone());
+    metrics.track_proposals(proposals.clone());
+    let metrics_listener = tokio::net::TcpListener::bind(config.metrics_addr).await?;
+    tokio::spawn(metrics::serve(metrics_listener, metrics));
+
+    let bank = BankServiceImpl::new(replica)
+        .with_raft(raft.clone(), config.peer_bank_addrs)
+        .with_proposal_queue(proposals.clone())
+        .with_membership(membership.clone());
+    let mut bank_server = transport::server(tls.as_ref())?;
+    let bank_router = match BearerAuth::from_env() {
+        Some(auth) => bank_server
+            .add_service(health_service)
+            .add_service(BankServiceServer::with_interceptor(bank, auth)),
+        None => bank_server.add_service(health_service).add_service(BankServiceServer::new(bank)),
+    };
+    let mut raft_router = config
+        .raft_tuning
+        .apply_server(transport::server(tls.as_ref())?)
+        .add_service(RaftServer::new(RaftServiceImpl::new(raft.clone())));
+    let gossip_service = GossipServer::new(GossipServiceImpl::new(membership));
+    let gossip_router = if config.gossip.listen_addr == config.raft.listen_addr {
+        raft_router = raft_router.add_service(gossip_service);
+        None
+    } else {
+        Some(transport::server(tls.as_ref())?.add_service(gossip_service))
+    };
+
+    // On SIGTERM, finish the writes already queued, then hand over leadership and sync
+    // the log, so stopping a node does not cost the cluster an election. A node removed
+    // from the voters while it leads stops the same way once the removal commits.
+    let mut terminate = signal(SignalKind::terminate())?;
+    let removed = removed(events);
+    let serve = async {
+        let gossip = async {
+            match gossip_router {
+                Some(router) => router.serve(config.gossip.listen_addr).await,
+                None => Ok(()),
+            }
+        };
+        tokio::try_join!(
+            bank_router.serve(config.bank.listen_addr),
+            raft_router.serve(config.raft.listen_addr),
+            gossip
+        )
+    };
+    tokio::select! {
+        served = serve => {
+            served?;
+        }
+        _ = terminate.recv() => {
+            info!("received SIGTERM; shutting down");
+            proposals.shutdown().await;
+            raft.shutdown().await?;
+        }
+        index = removed => {
+            info!(index, "removed from the voters; shutting down");
+            proposals.shutdown().await;
+            raft.shutdown().await?;
+        }
+    }
+
     Ok(())
 }
+
+/// Resolves with the index of the entry that removed this node from the voters, once it
+/// commits, and never if it does not.
+async fn removed(mut events: broadcast::Receiver<RaftEvent>) -> u64 {
+    loop {
+        match events.recv().await {
+            Ok(RaftEvent::Removed { index }) => return index,
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+        }
+    }
+}
@@ -0,0 +1,672 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use prost::Message;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info};
+use gossip::gossip::gossip_client::GossipClient;
+use gossip::gossip::{GossipMessage, Peer};
+use crate::raft::{RaftNode, Topology};
+use crate::transport::{self, TlsConfig};
+
+/// How long `join` waits on each seed before skipping it.
+pub const JOIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Largest gossip message, in bytes, a node sends unless configured otherwise.
+pub const DEFAULT_MAX_MESSAGE_BYTES: usize = 64 * 1024;
+
+/// Room kept in each gossip response for the fields besides its members.
+const RESPONSE_OVERHEAD: usize = 16;
+/// Membership events a watcher can fall behind by before it misses some.
+const WATCH_BUFFER: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemberStatus {
+    Alive,
+    Dead,
+}
+
+/// A node known to be in the cluster, and the addresses it advertises, which peers and
+/// clients dial whatever address it binds.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Member {
+    pub id: String,
+    /// Where its Raft service is reached.
+    pub addr: String,
+    /// Where clients reach its bank service, or empty if it did not say.
+    pub bank_addr: String,
+    /// Where peers gossip with it, or empty if that is its Raft address.
+    pub gossip_addr: String,
+    /// Where it runs, or empty if it did not say.
+    pub region: String,
+    pub rack: String,
+    pub status: MemberStatus,
+}
+
+impl Member {
+    /// Where to gossip with this member.
+    pub fn gossip_addr(&self) -> &str {
+        match self.gossip_addr.as_str() {
+            "" => &self.addr,
+            addr => addr,
+        }
+    }
+
+    pub fn topology(&self) -> Topology {
+        Topology::new(self.region.clone(), self.rack.clone())
+    }
+
+    fn to_peer(&self) -> Peer {
+        Peer {
+            node_id: self.id.clone(),
+            addr: self.addr.clone(),
+            term: 0,
+            bank_addr: self.bank_addr.clone(),
+            gossip_addr: self.gossip_addr.clone(),
+            region: self.region.clone(),
+            rack: self.rack.clone(),
+        }
+    }
+}
+
+/// A change to the members, carrying the member as it is after the change.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MembershipEvent {
+    /// A node this node did not know of.
+    Join(Member),
+    /// A member that was forgotten with `Membership::remove`.
+    Leave(Member),
+    /// A known member that died, or came back alive, perhaps at another address.
+    StatusChange(Member),
+}
+
+impl MembershipEvent {
+    pub fn member(&self) -> &Member {
+        match self {
+            Self::Join(member) | Self::Leave(member) | Self::StatusChange(member) => member,
+        }
+    }
+
+    /// Brings `members`, keyed by id, up to date with this event. Applying an event for
+    /// a change `members` already holds leaves them as they are.
+    pub fn apply_to(&self, members: &mut BTreeMap<String, Member>) {
+        match self {
+            Self::Join(member) | Self::StatusChange(member) => {
+                members.insert(member.id.clone(), member.clone());
+            }
+            Self::Leave(member) => {
+                members.remove(&member.id);
+            }
+        }
+    }
+}
+
+/// Returned when a node announces an id that a live member at another address holds.
+/// Two processes sharing a node id would each vote and append as that member, which
+/// breaks Raft's safety, so the second one is refused.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DuplicateNodeId {
+    pub id: String,
+    /// Where the live member with that id is.
+    pub existing_addr: String,
+    /// Where the node refused was announced from.
+    pub joining_addr: String,
+}
+
+impl DuplicateNodeId {
+    /// Returns the `DuplicateNodeId` details if `e` was caused by joining with a taken id.
+    pub fn from_io(e: &std::io::Error) -> Option<&Self> {
+        e.get_ref()?.downcast_ref()
+    }
+
+    fn into_io(self) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::AlreadyExists, self)
+    }
+}
+
+impl fmt::Display for DuplicateNodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "node id {} at {} is already in use by a live member at {}",
+            self.id, self.joining_addr, self.existing_addr
+        )
+    }
+}
+
+impl std::error::Error for DuplicateNodeId {}
+
+/// The nodes this node knows of, itself included, keyed by id.
+#[derive(Debug)]
+pub struct Membership {
+    local: Member,
+    members: Mutex<BTreeMap<String, Member>>,
+    /// Sent every change while `members` is locked, so they go out in the order made.
+    events: broadcast::Sender<MembershipEvent>,
+    max_message_bytes: usize,
+}
+
+impl Membership {
+    /// A membership holding just this node, alive at `addr`.
+    pub fn new(id: impl Into<String>, addr: impl Into<String>) -> Self {
+        let local = Member {
+            id: id.into(),
+            addr: addr.into(),
+            bank_addr: String::new(),
+            gossip_addr: String::new(),
+            region: String::new(),
+            rack: String::new(),
+            status: MemberStatus::Alive,
+        };
+        let members = BTreeMap::from([(local.id.clone(), local.clone())]);
+        Self {
+            local,
+            members: Mutex::new(members),
+            events: broadcast::Sender::new(WATCH_BUFFER),
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+        }
+    }
+
+    /// Splits the members this node gossips into fragments of at most `bytes` each.
+    pub fn with_max_message_bytes(mut self, bytes: usize) -> Self {
+        self.max_message_bytes = bytes;
+        self
+    }
+
+    /// Announces `addr` as where clients reach this node's bank service.
+    pub fn with_bank_addr(self, addr: impl Into<String>) -> Self {
+        let addr = addr.into();
+        self.with_local(|local| local.bank_addr = addr.clone())
+    }
+
+    /// Announces `addr` as where peers gossip with this node, instead of its Raft address.
+    pub fn with_gossip_addr(self, addr: impl Into<String>) -> Self {
+        let addr = addr.into();
+        self.with_local(|local| local.gossip_addr = addr.clone())
+    }
+
+    /// Announces this node as running on `rack` in `region`.
+    pub fn with_topology(self, region: impl Into<String>, rack: impl Into<String>) -> Self {
+        let (region, rack) = (region.into(), rack.into());
+        self.with_local(|local| {
+            local.region = region.clone();
+            local.rack = rack.clone();
+        })
+    }
+
+    fn with_local(mut self, update: impl Fn(&mut Member)) -> Self {
+        update(&mut self.local);
+        if let Some(local) = self.members.get_mut().unwrap().get_mut(&self.local.id) {
+            update(local);
+        }
+        self
+    }
+
+    pub fn local(&self) -> &Member {
+        &self.local
+    }
+
+    pub fn member(&self, id: &str) -> Option<Member> {
+        self.members.lock().unwrap().get(id).cloned()
+    }
+
+    pub fn members(&self) -> Vec<Member> {
+        self.members.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Every member as of now, this node included, ordered by id. To follow the members
+    /// from here on, `watch` first and take the snapshot after: every change made since
+    /// is then either in the snapshot or yet to be received, or both, and applying each
+    /// event received with `MembershipEvent::apply_to` brings the snapshot up to date.
+    pub fn snapshot(&self) -> Vec<Member> {
+        self.members()
+    }
+
+    /// Receives every change to the members made from now on, in order. A receiver that
+    /// falls over `WATCH_BUFFER` events behind is told it lagged and must `watch` and
+    /// take a snapshot again.
+    pub fn watch(&self) -> broadcast::Receiver<MembershipEvent> {
+        self.events.subscribe()
+    }
+
+    /// Records `id` as alive at `addr`. Fails if a live member already holds `id` at a
+    /// different address; a node rejoining from the same address, or taking over the
+    /// id of a dead member, is accepted.
+    pub fn observe(&self, id: &str, addr: &str) -> Result<(), DuplicateNodeId> {
+        self.observe_peer(&Peer {
+            node_id: id.to_string(),
+            addr: addr.to_string(),
+            ..Peer::default()
+        })
+    }
+
+    /// Like `observe`, also recording the other addresses `peer` advertises.
+    pub fn observe_peer(&self, peer: &Peer) -> Result<(), DuplicateNodeId> {
+        let (id, addr) = (peer.node_id.as_str(), peer.addr.as_str());
+        let mut members = self.members.lock().unwrap();
+        let existing = members.get(id);
+        if let Some(existing) = existing
+            && existing.status == MemberStatus::Alive
+            && existing.addr != addr
+        {
+            return Err(DuplicateNodeId {
+                id: id.to_string(),
+                existing_addr: existing.addr.clone(),
+                joining_addr: addr.to_string(),
+            });
+        }
+
+        let mut member = match existing {
+            Some(existing) if existing.status == MemberStatus::Alive => existing.clone(),
+            _ => Member {
+                id: id.to_string(),
+                addr: addr.to_string(),
+                bank_addr: String::new(),
+                gossip_addr: String::new(),
+                region: String::new(),
+                rack: String::new(),
+                status: MemberStatus::Alive,
+            },
+        };
+        if !peer.bank_addr.is_empty() {
+            member.bank_addr = peer.bank_addr.clone();
+        }
+        if !peer.gossip_addr.is_empty() {
+            member.gossip_addr = peer.gossip_addr.clone();
+        }
+        if !peer.region.is_empty() {
+            member.region = peer.region.clone();
+            member.rack = peer.rack.clone();
+        }
+        let event = match existing {
+            None => MembershipEvent::Join(member.clone()),
+            Some(existing) if existing.status == MemberStatus::Dead => {
+                MembershipEvent::StatusChange(member.clone())
+            }
+            Some(_) => {
+                members.insert(id.to_string(), member);
+                return Ok(());
+            }
+        };
+        info!(id, addr, "member joined");
+        members.insert(id.to_string(), member);
+        let _ = self.events.send(event);
+        Ok(())
+    }
+
+    /// Every live member as gossip announces it, this node included.
+    pub fn live_peers(&self) -> Vec<Peer> {
+        let members = self.members.lock().unwrap();
+        members
+            .values()
+            .filter(|member| member.status == MemberStatus::Alive)
+            .map(Member::to_peer)
+            .collect()
+    }
+
+    /// The `fragment`th of the pieces `live_peers` is split into to keep each gossip
+    /// response within the message size limit, and how many pieces there are. Past the
+    /// last piece it is empty.
+    pub fn live_peers_fragment(&self, fragment: u32) -> (Vec<Peer>, u32) {
+        let fragments = split(self.live_peers(), self.max_message_bytes);
+        let count = fragments.len() as u32;
+        let peers = fragments.into_iter().nth(fragment as usize).unwrap_or_default();
+        (peers, count)
+    }
+
+    /// Marks `id` dead, so another address may take over its id.
+    pub fn mark_dead(&self, id: &str) {
+        if id == self.local.id {
+            return;
+        }
+        let mut members = self.members.lock().unwrap();
+        if let Some(member) = members.get_mut(id)
+            && member.status != MemberStatus::Dead
+        {
+            member.status = MemberStatus::Dead;
+            let _ = self.events.send(MembershipEvent::StatusChange(member.clone()));
+        }
+    }
+
+    /// Forgets `id` altogether, e.g. once it has left the cluster for good. Gossip from
+    /// it, or about it, makes it join again.
+    pub fn remove(&self, id: &str) {
+        if id == self.local.id {
+            return;
+        }
+        let mut members = self.members.lock().unwrap();
+        if let Some(member) = members.remove(id) {
+            info!(id, "member left");
+            let _ = self.events.send(MembershipEvent::Leave(member));
+        }
+    }
+}
+
+/// Splits `peers` into runs whose encoding, with a response's other fields, fits in
+/// `max_bytes`. A peer too large on its own gets a fragment to itself. There is always at
+/// least one fragment.
+fn split(peers: Vec<Peer>, max_bytes: usize) -> Vec<Vec<Peer>> {
+    let budget = max_bytes.saturating_sub(RESPONSE_OVERHEAD);
+    let mut fragments = vec![Vec::new()];
+    let mut size = 0;
+    for peer in peers {
+        let len = peer.encoded_len();
+        let cost = 1 + prost::length_delimiter_len(len) + len;
+        let current = fragments.last_mut().unwrap();
+        if size + cost > budget && !current.is_empty() {
+            fragments.push(Vec::new());
+            size = 0;
+        }
+        fragments.last_mut().unwrap().push(peer);
+        size += cost;
+    }
+    fragments
+}
+
+/// Announces this node to each of `seeds` before it takes part in Raft, and learns the
+/// members each seed knows of, asking for them a fragment at a time when the seed splits
+/// them. Fails with `DuplicateNodeId` if any of them knows a live member with this node's
+/// id at another address. Seeds that cannot be reached are skipped, as peers starting at
+/// the same time may not be up yet. Returns how many seeds accepted the node.
+pub async fn join<'a>(
+    membership: &Membership,
+    seeds: impl IntoIterator<Item = &'a String>,
+    tls: Option<&TlsConfig>,
+) -> std::io::Result<usize> {
+    let local = membership.local();
+    let mut accepted = 0;
+    'seeds: for seed in seeds {
+        let endpoint = transport::endpoint(seed, tls)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?
+            .connect_timeout(JOIN_TIMEOUT)
+            .timeout(JOIN_TIMEOUT);
+        let channel = match endpoint.connect().await {
+            Ok(channel) => channel,
+            Err(e) => {
+                debug!(%seed, error = %e, "seed unreachable; skipping it");
+                continue;
+            }
+        };
+        let mut client = GossipClient::new(channel);
+        let mut members = Vec::new();
+        let mut fragment = 0;
+        loop {
+            let message = GossipMessage {
+                peers: vec![local.to_peer()],
+                fragment,
+            };
+            let response = match client.exchange(message).await {
+                Ok(response) => response.into_inner(),
+                Err(status) => {
+                    debug!(%seed, %status, "seed did not answer; skipping it");
+                    continue 'seeds;
+                }
+            };
+
+            if !response.accepted {
+                let existing_addr = response.conflict.map(|peer| peer.addr).unwrap_or_default();
+                return Err(DuplicateNodeId {
+                    id: local.id.clone(),
+                    existing_addr,
+                    joining_addr: local.addr.clone(),
+                }
+                .into_io());
+            }
+            members.extend(response.members);
+            fragment += 1;
+            if fragment >= response.fragments {
+                break;
+            }
+        }
+        for peer in &members {
+            if let Err(duplicate) = membership.observe_peer(peer) {
+                debug!(%seed, %duplicate, "seed knows a member elsewhere; keeping ours");
+            }
+        }
+        accepted += 1;
+    }
+
+    info!(id = %local.id, accepted, "joined the cluster");
+    Ok(accepted)
+}
+
+/// Tells `raft` where each member runs as gossip learns it, so that it replicates to the
+/// nearest voters first, for as long as it runs.
+pub async fn share_topology(membership: Arc<Membership>, raft: Arc<RaftNode>) {
+    let share = |member: &Member| {
+        if !member.region.is_empty() {
+            raft.set_topology(&member.id, member.topology());
+        }
+    };
+    loop {
+        let mut events = membership.watch();
+        membership.snapshot().iter().for_each(share);
+        loop {
+            match events.recv().await {
+                Ok(event) => share(event.member()),
+                // Start over from a new snapshot.
+                Err(RecvError::Lagged(_)) => break,
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gossip::gossip::gossip_server::GossipServer;
+    use gossip::gossip::GossipResponse;
+    use crate::service::GossipServiceImpl;
+
+    /// Serves `membership` over gossip on a free local port, returning its address.
+    async fn serve(membership: Arc<Membership>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        serve_on(membership, listener);
+        addr
+    }
+
+    /// Like `serve`, over a transport that fails any response longer than `limit` bytes.
+    async fn serve_limited(membership: Arc<Membership>, limit: usize) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let service = GossipServer::new(GossipServiceImpl::new(membership))
+            .max_encoding_message_size(limit);
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(service)
+                .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+        );
+        addr
+    }
+
+    fn serve_on(membership: Arc<Membership>, listener: tokio::net::TcpListener) {
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(GossipServer::new(GossipServiceImpl::new(membership)))
+                .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+        );
+    }
+
+    #[test]
+    fn test_membership_refuses_a_live_id_at_another_address() {
+        let membership = Membership::new("node-1", "10.0.0.1:50061");
+        membership.observe("node-2", "10.0.0.2:50061").unwrap();
+        membership.observe("node-2", "10.0.0.2:50061").unwrap();
+
+        let duplicate = membership.observe("node-2", "10.0.0.9:50061").unwrap_err();
+        assert_eq!(duplicate.existing_addr, "10.0.0.2:50061");
+        assert!(membership.observe("node-1", "10.0.0.9:50061").is_err());
+        assert_eq!(membership.member("node-2").unwrap().addr, "10.0.0.2:50061");
+
+        // A dead member's id may move to a new address.
+        membership.mark_dead("node-2");
+        membership.observe("node-2", "10.0.0.9:50061").unwrap();
+        let member = membership.member("node-2").unwrap();
+        assert_eq!((member.addr.as_str(), member.status), ("10.0.0.9:50061", MemberStatus::Alive));
+    }
+
+    #[test]
+    fn test_watch_then_snapshot_sees_a_join_in_between_exactly_once() {
+        let membership = Membership::new("node-1", "10.0.0.1:50061");
+        membership.observe("node-2", "10.0.0.2:50061").unwrap();
+
+        let mut events = membership.watch();
+        membership.observe("node-3", "10.0.0.3:50061").unwrap();
+        let mut view: BTreeMap<String, Member> = membership
+            .snapshot()
+            .into_iter()
+            .map(|member| (member.id.clone(), member))
+            .collect();
+        let ids: Vec<&str> = view.keys().map(String::as_str).collect();
+        assert_eq!(ids, vec!["node-1", "node-2", "node-3"]);
+
+        // The join is both in the snapshot and received; applying it changes nothing.
+        let event = events.try_recv().unwrap();
+        assert!(matches!(&event, MembershipEvent::Join(member) if member.id == "node-3"));
+        event.apply_to(&mut view);
+        assert_eq!(view.len(), 3);
+        assert!(events.try_recv().is_err());
+
+        // Later changes come through in order, and only once each.
+        membership.mark_dead("node-2");
+        membership.mark_dead("node-2");
+        membership.observe("node-2", "10.0.0.9:50061").unwrap();
+        membership.observe("node-2", "10.0.0.9:50061").unwrap();
+        membership.remove("node-3");
+        membership.remove("node-1");
+        let mut received = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            event.apply_to(&mut view);
+            received.push(event);
+        }
+        let kinds: Vec<(&str, MemberStatus)> = received
+            .iter()
+            .map(|event| (event.member().id.as_str(), event.member().status))
+            .collect();
+        assert_eq!(kinds, vec![
+            ("node-2", MemberStatus::Dead),
+            ("node-2", MemberStatus::Alive),
+            ("node-3", MemberStatus::Alive),
+        ]);
+        assert!(matches!(received[2], MembershipEvent::Leave(_)));
+        let snapshot: BTreeMap<String, Member> = membership
+            .snapshot()
+            .into_iter()
+            .map(|member| (member.id.clone(), member))
+            .collect();
+        assert_eq!(view, snapshot);
+        assert_eq!(view["node-2"].addr, "10.0.0.9:50061");
+    }
+
+    #[tokio::test]
+    async fn test_join_rejects_duplicate_id_and_allows_rejoin() {
+        let seed = Arc::new(Membership::new("node-1", "10.0.0.1:50061"));
+        let seeds = vec![serve(seed.clone()).await];
+
+        let node_2 = Membership::new("node-2", "10.0.0.2:50061");
+        assert_eq!(join(&node_2, &seeds, None).await.unwrap(), 1);
+        assert_eq!(seed.member("node-2").unwrap().addr, "10.0.0.2:50061");
+        // The same process restarting rejoins from the same address.
+        assert_eq!(join(&node_2, &seeds, None).await.unwrap(), 1);
+
+        let impostor = Membership::new("node-2", "10.0.0.3:50061");
+        let err = join(&impostor, &seeds, None).await.unwrap_err();
+        let duplicate = DuplicateNodeId::from_io(&err).unwrap();
+        assert_eq!(duplicate.existing_addr, "10.0.0.2:50061");
+        assert_eq!(duplicate.joining_addr, "10.0.0.3:50061");
+        assert!(err.to_string().contains("node id node-2"));
+        assert_eq!(seed.member("node-2").unwrap().addr, "10.0.0.2:50061");
+
+        // Taking the seed's own id is refused too.
+        let err = join(&Membership::new("node-1", "10.0.0.4:50061"), &seeds, None).await;
+        assert!(DuplicateNodeId::from_io(&err.unwrap_err()).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_peers_dial_the_addresses_a_node_advertises() {
+        let seed = Arc::new(Membership::new("node-1", "10.0.0.1:50061"));
+        let seeds = vec![serve(seed.clone()).await];
+
+        // Bound to every interface, which is no address to dial, so it advertises another.
+        let listener = tokio::net::TcpListener::bind("0.0.0.0:0").await.unwrap();
+        let bound = listener.local_addr().unwrap().to_string();
+        let advertised = format!("127.0.0.1:{}", listener.local_addr().unwrap().port());
+        let node_2 = Membership::new("node-2", "raft.node-2.example:50061")
+            .with_bank_addr("bank.node-2.example:50051")
+            .with_gossip_addr(advertised.clone())
+            .with_topology("eu-west", "rack-2");
+        let node_2 = Arc::new(node_2);
+        serve_on(node_2.clone(), listener);
+        assert_eq!(join(&node_2, &seeds, None).await.unwrap(), 1);
+
+        let member = seed.member("node-2").unwrap();
+        assert_eq!(member.addr, "raft.node-2.example:50061");
+        assert_eq!(member.bank_addr, "bank.node-2.example:50051");
+        assert_eq!(member.topology(), Topology::new("eu-west", "rack-2"));
+        assert_eq!(member.gossip_addr(), advertised);
+        assert_ne!(member.gossip_addr(), bound);
+        // The seed's answer told the joining node about the seed.
+        assert_eq!(node_2.member("node-1").unwrap().gossip_addr(), "10.0.0.1:50061");
+
+        // A peer that learned of node-2 from the seed reaches it where it advertised.
+        let node_3 = Membership::new("node-3", "10.0.0.3:50061");
+        let learned = vec![member.gossip_addr().to_string()];
+        assert_eq!(join(&node_3, &learned, None).await.unwrap(), 1);
+        assert!(node_2.member("node-3").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_join_skips_unreachable_seeds() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let node = Membership::new("node-2", "10.0.0.2:50061");
+        assert_eq!(join(&node, &[closed], None).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_join_reassembles_a_member_table_fragmented_to_the_size_limit() {
+        const LIMIT: usize = 1024;
+        let large = |max_message_bytes| {
+            let seed = Membership::new("node-1", "10.0.0.1:50061")
+                .with_max_message_bytes(max_message_bytes);
+            for i in 2..500 {
+                let addr = format!("10.0.{}.{}:50061", i / 256, i % 256);
+                seed.observe(&format!("node-{i}"), &addr).unwrap();
+            }
+            Arc::new(seed)
+        };
+
+        // Sent whole, the table is more than the transport carries, so the seed is skipped.
+        let whole = large(DEFAULT_MAX_MESSAGE_BYTES);
+        let seeds = vec![serve_limited(whole, LIMIT).await];
+        let node = Membership::new("node-500", "10.0.1.244:50061");
+        assert_eq!(join(&node, &seeds, None).await.unwrap(), 0);
+
+        let seed = large(LIMIT);
+        let (_, fragments) = seed.live_peers_fragment(0);
+        assert!(fragments > 10, "{fragments} fragments");
+        for fragment in 0..fragments {
+            let (members, _) = seed.live_peers_fragment(fragment);
+            let response = GossipResponse {
+                accepted: true,
+                members,
+                fragment,
+                fragments,
+                ..GossipResponse::default()
+            };
+            assert!(response.encoded_len() <= LIMIT);
+        }
+        assert!(seed.live_peers_fragment(fragments).0.is_empty());
+
+        let seeds = vec![serve_limited(seed.clone(), LIMIT).await];
+        assert_eq!(join(&node, &seeds, None).await.unwrap(), 1);
+        assert_eq!(node.members().len(), 500);
+        assert_eq!(node.members(), seed.members());
+    }
+}
@@ -0,0 +1,303 @@
+mod server;
+
+use std::sync::{Arc, Mutex, OnceLock};
+use prometheus::{
+    exponential_buckets, Encoder, GaugeVec, Histogram, HistogramOpts, IntCounter, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use crate::proposal::ProposalQueue;
+use crate::raft::{RaftNode, Role};
+use crate::replica::Replica;
+
+pub use server::serve;
+
+/// Counters the WAL updates on every append.
+///
+/// Metrics only show up in a scrape once registered, so a `Wal` opened without
+/// `NodeMetrics` records into a private copy nobody reads.
+#[derive(Clone, Debug)]
+pub struct WalMetrics {
+    pub appends: IntCounter,
+    pub bytes: IntCounter,
+    pub fsync_seconds: Histogram,
+}
+
+impl WalMetrics {
+    fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.appends.clone()))?;
+        registry.register(Box::new(self.bytes.clone()))?;
+        registry.register(Box::new(self.fsync_seconds.clone()))
+    }
+
+    /// Counters labelled with `group`, if set.
+    fn labelled(group: Option<&str>) -> Self {
+        // 100µs up to ~3s.
+        let buckets = exponential_buckets(0.0001, 2.0, 16).expect("valid fsync buckets");
+        let mut fsync_opts =
+            HistogramOpts::new("wal_fsync_seconds", "Time spent in WAL fsync calls")
+                .buckets(buckets);
+        fsync_opts.common_opts = labels(fsync_opts.common_opts, group);
+        let counter = |name: &str, help: &str| {
+            IntCounter::with_opts(labels(Opts::new(name, help), group)).expect("valid metric")
+        };
+
+        Self {
+            appends: counter("wal_appends_total", "Entries appended to the WAL"),
+            bytes: counter("wal_bytes_total", "Bytes appended to the WAL"),
+            fsync_seconds: Histogram::with_opts(fsync_opts).expect("valid metric"),
+        }
+    }
+}
+
+impl Default for WalMetrics {
+    fn default() -> Self {
+        Self::labelled(None)
+    }
+}
+
+/// Labels `opts` with the Raft group its metric belongs to, if set.
+fn labels(opts: Opts, group: Option<&str>) -> Opts {
+    match group {
+        Some(group) => opts.const_label("group", group),
+        None => opts,
+    }
+}
+
+/// Every metric a node exports. Raft and replica gauges are sampled at scrape time
+/// from the tracked instances rather than pushed on each change.
+///
+/// A node hosting several Raft groups has one `NodeMetrics` per group, each labelling
+/// its metrics with the group id and tracking that group's instances, all rendered
+/// together: see `with_group` and `add_group`.
+#[derive(Debug)]
+pub struct NodeMetrics {
+    registry: Registry,
+    /// The other groups registered with `add_group`, sampled along with this one.
+    groups: Mutex<Vec<Arc<NodeMetrics>>>,
+    wal: WalMetrics,
+    current_term: IntGauge,
+    commit_index: IntGauge,
+    last_applied: IntGauge,
+    last_log_index: IntGauge,
+    durable_index: IntGauge,
+    state: IntGaugeVec,
+    elections_started: IntCounter,
+    leader_changes: IntCounter,
+    /// Leader only: replication progress of each peer, labelled by peer id.
+    peer_match_index: IntGaugeVec,
+    peer_next_index: IntGaugeVec,
+    peer_lag: IntGaugeVec,
+    peer_last_append_age: GaugeVec,
+    proposal_queue_depth: IntGauge,
+    raft: OnceLock<Arc<RaftNode>>,
+    replica: OnceLock<Arc<Replica>>,
+    proposals: OnceLock<ProposalQueue>,
+}
+
+impl NodeMetrics {
+    /// Metrics for a node with a single Raft group, which are not labelled by group.
+    pub fn new() -> Self {
+        Self::register(Registry::new(), None)
+    }
+
+    /// Metrics for the Raft group `group`, each labelled `group="<group>"`.
+    pub fn with_group(group: &str) -> Self {
+        Self::register(Registry::new(), Some(group))
+    }
+
+    /// Metrics for another Raft group on this node, labelled `group` and rendered along
+    /// with this one's. Panics unless these metrics were made by `with_group`, as the
+    /// same metric cannot be both labelled and not, or if `group` was added already.
+    pub fn add_group(&self, group: &str) -> Arc<NodeMetrics> {
+        let metrics = Arc::new(Self::register(self.registry.clone(), Some(group)));
+        self.groups.lock().unwrap().push(metrics.clone());
+        metrics
+    }
+
+    fn register(registry: Registry, group: Option<&str>) -> Self {
+        let gauge = |name: &str, help: &str| {
+            IntGauge::with_opts(labels(Opts::new(name, help), group)).expect("valid metric")
+        };
+        let counter = |name: &str, help: &str| {
+            IntCounter::with_opts(labels(Opts::new(name, help), group)).expect("valid metric")
+        };
+        let peer_gauge = |name: &str, help: &str| {
+            IntGaugeVec::new(labels(Opts::new(name, help), group), &["peer"])
+                .expect("valid metric")
+        };
+        let metrics = Self {
+            registry,
+            groups: Mutex::new(Vec::new()),
+            wal: WalMetrics::labelled(group),
+            current_term: gauge("raft_current_term", "Latest term this node has seen"),
+            commit_index: gauge("raft_commit_index", "Highest log index known to be committed"),
+            last_applied: gauge("raft_last_applied", "Highest log index applied to the bank"),
+            last_log_index: gauge("raft_last_log_index", "Highest log index this node stores"),
+            durable_index: gauge(
+                "raft_durable_index",
+                "Highest log index this node has fsynced, which commits never pass",
+            ),
+            state: IntGaugeVec::new(
+                labels(
+                    Opts::new("raft_state", "1 for the role this node currently has, 0 otherwise"),
+                    group,
+                ),
+                &["state"],
+            )
+            .expect("valid metric"),
+            elections_started: counter(
+                "raft_elections_started_total",
+                "Elections this node has started as a candidate",
+            ),
+            leader_changes: counter(
+                "raft_leader_changes_total",
+                "Leaders this node has learned of, one per term that had one",
+            ),
+            peer_match_index: peer_gauge(
+                "raft_peer_match_index",
+                "Highest log index the leader knows each peer stores",
+            ),
+            peer_next_index: peer_gauge(
+                "raft_peer_next_index",
+                "Next log index the leader sends each peer",
+            ),
+            peer_lag: peer_gauge(
+                "raft_peer_lag_entries",
+                "Entries the leader stores that each peer is not known to",
+            ),
+            peer_last_append_age: GaugeVec::new(
+                labels(
+                    Opts::new(
+                        "raft_peer_last_append_age_seconds",
+                        "Time since each peer last acknowledged an AppendEntries",
+                    ),
+                    group,
+                ),
+                &["peer"],
+            )
+            .expect("valid metric"),
+            proposal_queue_depth: gauge(
+                "proposal_queue_depth",
+                "Proposals waiting for the WAL to accept them",
+            ),
+            raft: OnceLock::new(),
+            replica: OnceLock::new(),
+            proposals: OnceLock::new(),
+        };
+
+        metrics.wal.register(&metrics.registry).expect("metric names are unique");
+        for collector in [
+            &metrics.current_term,
+            &metrics.commit_index,
+            &metrics.last_applied,
+            &metrics.last_log_index,
+            &metrics.durable_index,
+            &metrics.proposal_queue_depth,
+        ] {
+            metrics.registry.register(Box::new(collector.clone())).expect("unique metric");
+        }
+        for collector in [&metrics.elections_started, &metrics.leader_changes] {
+            metrics.registry.register(Box::new(collector.clone())).expect("unique metric");
+        }
+        for collector in [
+            &metrics.state,
+            &metrics.peer_match_index,
+            &metrics.peer_next_index,
+            &metrics.peer_lag,
+        ] {
+            metrics.registry.register(Box::new(collector.clone())).expect("unique metric");
+        }
+        metrics
+            .registry
+            .register(Box::new(metrics.peer_last_append_age.clone()))
+            .expect("unique metric");
+        metrics
+    }
+
+    /// The WAL counters, to hand to `Replica::open_with_metrics`.
+    pub fn wal(&self) -> WalMetrics {
+        self.wal.clone()
+    }
+
+    pub fn track_raft(&self, raft: Arc<RaftNode>) {
+        let _ = self.raft.set(raft);
+    }
+
+    pub fn track_replica(&self, replica: Arc<Replica>) {
+        let _ = self.replica.set(replica);
+    }
+
+    pub fn track_proposals(&self, proposals: ProposalQueue) {
+        let _ = self.proposals.set(proposals);
+    }
+
+    /// Samples the tracked instances, those of the groups added to this one included, and
+    /// renders everything in Prometheus text format.
+    pub fn render(&self) -> String {
+        self.sample();
+        for group in self.groups.lock().unwrap().iter() {
+            group.sample();
+        }
+
+        let mut buf = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .expect("text encoding into memory cannot fail");
+        String::from_utf8(buf).expect("text encoding is UTF-8")
+    }
+
+    fn sample(&self) {
+        if let Some(raft) = self.raft.get() {
+            self.current_term.set(raft.current_term() as i64);
+            self.last_log_index.set(raft.last_log_index() as i64);
+            self.durable_index.set(raft.durable_index() as i64);
+            let role = raft.role();
+            for (label, state) in [
+                ("leader", Role::Leader),
+                ("follower", Role::Follower),
+                ("candidate", Role::Candidate),
+            ] {
+                self.state.with_label_values(&[label]).set((role == state) as i64);
+            }
+            // Counters only go up, so they are brought up to the node's count.
+            for (counter, count) in [
+                (&self.elections_started, raft.elections_started()),
+                (&self.leader_changes, raft.leader_changes()),
+            ] {
+                counter.inc_by(count.saturating_sub(counter.get()));
+            }
+            self.render_peers(raft);
+        }
+        if let Some(replica) = self.replica.get() {
+            self.commit_index.set(replica.commit_index() as i64);
+            self.last_applied.set(replica.last_applied() as i64);
+        }
+        if let Some(proposals) = self.proposals.get() {
+            self.proposal_queue_depth.set(proposals.depth() as i64);
+        }
+    }
+
+    /// Replaces the per-peer gauges with the leader's view of each peer, so a node that
+    /// stopped leading reports none.
+    fn render_peers(&self, raft: &RaftNode) {
+        self.peer_match_index.reset();
+        self.peer_next_index.reset();
+        self.peer_lag.reset();
+        self.peer_last_append_age.reset();
+        for peer in raft.peer_status() {
+            let labels = [peer.id.as_str()];
+            self.peer_match_index.with_label_values(&labels).set(peer.match_index as i64);
+            self.peer_next_index.with_label_values(&labels).set(peer.next_index as i64);
+            self.peer_lag.with_label_values(&labels).set(peer.lag as i64);
+            if let Some(age) = peer.since_last_append {
+                self.peer_last_append_age.with_label_values(&labels).set(age.as_secs_f64());
+            }
+        }
+    }
+}
+
+impl Default for NodeMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
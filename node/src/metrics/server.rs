@@ -0,0 +1,192 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::CONTENT_TYPE;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use prometheus::{Encoder, TextEncoder};
+use tokio::net::TcpListener;
+use crate::metrics::NodeMetrics;
+
+/// Serves `GET /metrics` in Prometheus text format on `listener` until accepting fails.
+pub async fn serve(listener: TcpListener, metrics: Arc<NodeMetrics>) -> std::io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |request| {
+                let response = respond(&metrics, request);
+                async move { Ok::<_, Infallible>(response) }
+            });
+            // A scraper hanging up mid-response only affects that scrape.
+            let _ = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await;
+        });
+    }
+}
+
+fn respond(metrics: &NodeMetrics, request: Request<Incoming>) -> Response<Full<Bytes>> {
+    if request.method() != Method::GET || request.uri().path() != "/metrics" {
+        let mut response = Response::new(Full::new(Bytes::from_static(b"not found\n")));
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return response;
+    }
+
+    let mut response = Response::new(Full::new(Bytes::from(metrics.render())));
+    let content_type = TextEncoder::new().format_type().parse().expect("valid content type");
+    response.headers_mut().insert(CONTENT_TYPE, content_type);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use crate::bank::tests::{create_account, transfer};
+    use crate::replica::Replica;
+
+    async fn get(addr: &str, path: &str) -> String {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request =
+            format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, addr);
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    /// The value of the sample line named exactly `sample`, labels included.
+    fn sample(body: &str, sample: &str) -> Option<f64> {
+        body.lines()
+            .filter(|line| !line.starts_with('#'))
+            .find_map(|line| line.strip_prefix(sample)?.strip_prefix(' ')?.parse().ok())
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint_reports_wal_and_raft_metrics() {
+        let dir = TempDir::new().unwrap();
+        let metrics = Arc::new(NodeMetrics::new());
+        let wal_path = dir.path().join("bank.wal");
+        let replica = Arc::new(
+            Replica::open_with_metrics(wal_path.to_str().unwrap(), metrics.wal()).unwrap(),
+        );
+        metrics.track_replica(replica.clone());
+        let (proposals, _worker) = crate::proposal::proposal_queue(4);
+        metrics.track_proposals(proposals.clone());
+        let _queued = tokio::spawn(async move {
+            proposals.propose(create_account("carol", 0)).await
+        });
+        tokio::task::yield_now().await;
+
+        replica.propose(&create_account("alice", 100)).unwrap();
+        replica.propose(&create_account("bob", 0)).unwrap();
+        replica.propose(&transfer("alice", "bob", 30, "tx-1")).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(serve(listener, metrics));
+
+        let response = get(&addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("text/plain; version=0.0.4"));
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+
+        assert_eq!(sample(body, "wal_appends_total"), Some(3.0));
+        assert!(sample(body, "wal_bytes_total").unwrap() > 0.0);
+        assert_eq!(sample(body, "wal_fsync_seconds_count"), Some(3.0));
+        assert_eq!(sample(body, "raft_commit_index"), Some(3.0));
+        assert_eq!(sample(body, "raft_last_applied"), Some(3.0));
+        assert_eq!(sample(body, "proposal_queue_depth"), Some(1.0));
+        assert!(body.contains("# TYPE wal_fsync_seconds histogram"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_metrics_report_raft_role_and_term() {
+        let cluster = crate::raft::tests::TestCluster::start(1);
+        let leader = cluster.wait_for_leader().await;
+        let metrics = NodeMetrics::new();
+        metrics.track_raft(leader.clone());
+
+        let body = metrics.render();
+        assert_eq!(sample(&body, "raft_current_term"), Some(leader.current_term() as f64));
+        assert_eq!(sample(&body, "raft_state{state=\"leader\"}"), Some(1.0));
+        assert_eq!(sample(&body, "raft_state{state=\"follower\"}"), Some(0.0));
+        assert_eq!(sample(&body, "raft_state{state=\"candidate\"}"), Some(0.0));
+        assert_eq!(sample(&body, "raft_elections_started_total"), Some(1.0));
+        assert_eq!(sample(&body, "raft_leader_changes_total"), Some(1.0));
+        // Rendering again does not count anything twice.
+        let body = metrics.render();
+        assert_eq!(sample(&body, "raft_elections_started_total"), Some(1.0));
+        assert!(body.contains("# TYPE raft_leader_changes_total counter"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_metrics_report_replication_lag_per_peer() {
+        let cluster = crate::raft::tests::TestCluster::start(2);
+        let leader = cluster.wait_for_leader().await;
+        let follower = cluster.nodes.iter().find(|node| node.id() != leader.id()).unwrap();
+        cluster.network.isolate(follower.id());
+        leader.propose(bytes::Bytes::from_static(b"command")).unwrap();
+        leader.propose(bytes::Bytes::from_static(b"command")).unwrap();
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+
+        let metrics = NodeMetrics::new();
+        metrics.track_raft(leader.clone());
+        let body = metrics.render();
+        let label = format!("{{peer=\"{}\"}}", follower.id());
+        let last_index = leader.last_log_index() as f64;
+        assert_eq!(sample(&body, &format!("raft_peer_lag_entries{}", label)), Some(2.0));
+        let next_index = sample(&body, &format!("raft_peer_next_index{}", label));
+        assert_eq!(next_index, Some(last_index - 1.0));
+        let age = sample(&body, &format!("raft_peer_last_append_age_seconds{}", label));
+        assert!(age.unwrap() >= 1.0);
+
+        // Only a leader reports its peers.
+        let metrics = NodeMetrics::new();
+        metrics.track_raft(follower.clone());
+        assert!(!metrics.render().contains("raft_peer_lag_entries{"));
+    }
+
+    #[test]
+    fn test_metrics_label_each_raft_group_with_its_own_values() {
+        let dir = TempDir::new().unwrap();
+        let metrics = NodeMetrics::with_group("shard-1");
+        let shard_2 = metrics.add_group("shard-2");
+        let open = |group: &str, metrics: &NodeMetrics| {
+            let path = dir.path().join(format!("{}.wal", group));
+            let replica = Replica::open_with_metrics(path.to_str().unwrap(), metrics.wal());
+            let replica = Arc::new(replica.unwrap());
+            metrics.track_replica(replica.clone());
+            replica
+        };
+        let replica_1 = open("shard-1", &metrics);
+        let replica_2 = open("shard-2", &shard_2);
+
+        replica_1.propose(&create_account("alice", 100)).unwrap();
+        for account in ["bob", "carol", "dave"] {
+            replica_2.propose(&create_account(account, 0)).unwrap();
+        }
+
+        let body = metrics.render();
+        for (group, entries) in [("shard-1", 1.0), ("shard-2", 3.0)] {
+            let label = format!("{{group=\"{}\"}}", group);
+            assert_eq!(sample(&body, &format!("raft_commit_index{}", label)), Some(entries));
+            assert_eq!(sample(&body, &format!("raft_last_applied{}", label)), Some(entries));
+            assert_eq!(sample(&body, &format!("wal_appends_total{}", label)), Some(entries));
+        }
+        assert_eq!(sample(&body, "raft_commit_index"), None);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint_unknown_path() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(serve(listener, Arc::new(NodeMetrics::new())));
+
+        assert!(get(&addr, "/").await.starts_with("HTTP/1.1 404 Not Found"));
+    }
+}
@@ -0,0 +1,587 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use crate::bank::{BankCommand, CommandOutcome};
+use crate::raft::RaftNode;
+use crate::replica::Replica;
+
+/// Proposals that may wait for the WAL before new ones are turned away.
+pub const DEFAULT_PROPOSAL_CAPACITY: usize = 1024;
+/// Most proposals the worker commits together, with one append and fsync.
+pub const DEFAULT_MAX_COALESCED: usize = 256;
+
+type ProposalResult = std::io::Result<(u64, CommandOutcome)>;
+
+struct Proposal {
+    command: BankCommand,
+    /// Who proposed it, which fair queuing takes turns between; empty if unknown.
+    client: String,
+    /// When the proposer gives up; past it, the command is dropped instead of applied.
+    deadline: Option<Instant>,
+    reply: oneshot::Sender<ProposalResult>,
+}
+
+enum Job {
+    Propose(Proposal),
+    /// Answered once every proposal queued before it has been handled.
+    Drain(oneshot::Sender<()>),
+}
+
+/// The sending half of a bounded queue of commands waiting to be committed.
+#[derive(Clone, Debug)]
+pub struct ProposalQueue {
+    sender: mpsc::Sender<Job>,
+    /// Set by `shutdown`, after which proposals are turned away.
+    closed: Arc<AtomicBool>,
+    /// Proposals the worker took off the channel but has yet to commit, which only fair
+    /// queuing holds on to.
+    held: Arc<AtomicUsize>,
+}
+
+/// Drains a `ProposalQueue` into a replica, or into Raft, committing the proposals that queued up
+/// together: while one batch waits on its fsync, the next one gathers behind it.
+pub struct ProposalWorker {
+    receiver: mpsc::Receiver<Job>,
+    /// How long the worker waits, once it picks up a proposal, for others to join it.
+    coalesce_window: Duration,
+    max_coalesced: usize,
+    fair_queuing: bool,
+    held: Arc<AtomicUsize>,
+}
+
+/// Creates a queue that holds at most `capacity` proposals, and the worker that drains it.
+pub fn proposal_queue(capacity: usize) -> (ProposalQueue, ProposalWorker) {
+    let (sender, receiver) = mpsc::channel(capacity);
+    let held = Arc::new(AtomicUsize::new(0));
+    let queue = ProposalQueue {
+        sender,
+        closed: Arc::new(AtomicBool::new(false)),
+        held: held.clone(),
+    };
+    let worker = ProposalWorker {
+        receiver,
+        coalesce_window: Duration::ZERO,
+        max_coalesced: DEFAULT_MAX_COALESCED,
+        fair_queuing: false,
+        held,
+    };
+    (queue, worker)
+}
+
+impl ProposalQueue {
+    /// Queues `command` and waits for it to be committed and applied. Fails with
+    /// `WouldBlock` right away, without queueing, when the queue is full.
+    pub async fn propose(&self, command: BankCommand) -> ProposalResult {
+        self.propose_by(command, None).await
+    }
+
+    /// Like `propose`, but fails with `TimedOut`, leaving the command unapplied, if the
+    /// worker only reaches it after `deadline`. A client retrying a transfer with the
+    /// same `client_tx_id` still has it applied at most once.
+    pub async fn propose_by(
+        &self,
+        command: BankCommand,
+        deadline: Option<Instant>,
+    ) -> ProposalResult {
+        self.propose_from("", command, deadline).await
+    }
+
+    /// Like `propose_by`, but on behalf of `client`, between whom a worker with fair
+    /// queuing takes turns.
+    pub async fn propose_from(
+        &self,
+        client: &str,
+        command: BankCommand,
+        deadline: Option<Instant>,
+    ) -> ProposalResult {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "node is shutting down",
+            ));
+        }
+        // What the worker holds counts against the capacity as much as what is queued.
+        if self.depth() >= self.sender.max_capacity() {
+            return Err(queue_full());
+        }
+        let (reply, outcome) = oneshot::channel();
+        let proposal = Proposal {
+            command,
+            client: client.to_string(),
+            deadline,
+            reply,
+        };
+        self.sender.try_send(Job::Propose(proposal)).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => queue_full(),
+            mpsc::error::TrySendError::Closed(_) => worker_gone(),
+        })?;
+
+        outcome.await.map_err(|_| worker_gone())?
+    }
+
+    /// Proposals queued but not yet picked up by the worker for commit.
+    pub fn depth(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity() + self.held.load(Ordering::Acquire)
+    }
+
+    /// Turns away new proposals with `BrokenPipe`, on every handle to the queue, and
+    /// returns once the worker has handled every proposal already queued.
+    pub async fn shutdown(&self) {
+        self.closed.store(true, Ordering::Release);
+        let (done, drained) = oneshot::channel();
+        // Waits for room behind the queued proposals, rather than failing when full.
+        if self.sender.send(Job::Drain(done)).await.is_ok() {
+            let _ = drained.await;
+        }
+    }
+}
+
+impl ProposalWorker {
+    /// Waits `window` after picking up a proposal, so ones submitted just after it are
+    /// committed with it, and commits at most `max_coalesced` together. By default the
+    /// worker does not wait, taking only the proposals already queued.
+    pub fn with_coalescing(mut self, window: Duration, max_coalesced: usize) -> Self {
+        self.coalesce_window = window;
+        self.max_coalesced = max_coalesced.max(1);
+        self
+    }
+
+    /// Takes turns between the clients proposals come from, if `fair`, rather than
+    /// committing them in the order they were queued, so a client with many proposals
+    /// queued cannot hold up one with a few behind them. Each batch then takes one
+    /// proposal from each client waiting, round and round, until it is full.
+    pub fn with_fair_queuing(mut self, fair: bool) -> Self {
+        self.fair_queuing = fair;
+        self
+    }
+
+    /// Commits queued proposals to `replica` on a blocking thread, since each batch
+    /// waits on an fsync. Stops once every `ProposalQueue` handle is dropped.
+    pub fn spawn(self, replica: Arc<Replica>) -> JoinHandle<()> {
+        self.spawn_committing(move |batch| commit(&replica, batch))
+    }
+
+    /// Like `spawn`, proposing the queued commands to `raft` instead, which must lead for
+    /// them to be taken. Each proposer is answered once its entry commits and the node's
+    /// `RaftApplier` applies it, or with `NotLeader` if it never will.
+    pub fn spawn_to_raft(self, raft: Arc<RaftNode>) -> JoinHandle<()> {
+        let runtime = Handle::current();
+        self.spawn_committing(move |batch| propose_to_raft(&raft, &runtime, batch))
+    }
+
+    fn spawn_committing(
+        mut self,
+        mut commit: impl FnMut(Vec<Proposal>) + Send + 'static,
+    ) -> JoinHandle<()> {
+        tokio::task::spawn_blocking(move || {
+            if self.fair_queuing {
+                return self.run_fair(&mut commit);
+            }
+            while let Some(job) = self.receiver.blocking_recv() {
+                let first = match job {
+                    Job::Propose(proposal) => proposal,
+                    Job::Drain(done) => {
+                        let _ = done.send(());
+                        continue;
+                    }
+                };
+                let (batch, drained) = self.gather(first);
+                commit(batch);
+                if let Some(done) = drained {
+                    let _ = done.send(());
+                }
+            }
+        })
+    }
+
+    /// Collects the proposals to commit along with `first`. Stops at a drain request,
+    /// returning it to answer once the batch is committed.
+    fn gather(&mut self, first: Proposal) -> (Vec<Proposal>, Option<oneshot::Sender<()>>) {
+        if !self.coalesce_window.is_zero() {
+            std::thread::sleep(self.coalesce_window);
+        }
+        let mut batch = vec![first];
+        while batch.len() < self.max_coalesced {
+            match self.receiver.try_recv() {
+                Ok(Job::Propose(proposal)) => batch.push(proposal),
+                Ok(Job::Drain(done)) => return (batch, Some(done)),
+                Err(_) => break,
+            }
+        }
+        (batch, None)
+    }
+
+    /// Commits proposals by turns between clients: takes in everything queued, and
+    /// commits a batch of it, until nothing is left. Once a drain request comes in,
+    /// nothing more is taken in until what came before it is committed and it answered.
+    fn run_fair(mut self, commit: &mut impl FnMut(Vec<Proposal>)) {
+        let mut lanes = Lanes::default();
+        let mut drains: Vec<oneshot::Sender<()>> = Vec::new();
+        loop {
+            if lanes.is_empty() {
+                for done in drains.drain(..) {
+                    let _ = done.send(());
+                }
+                let Some(job) = self.receiver.blocking_recv() else {
+                    return;
+                };
+                self.take_in(job, &mut lanes, &mut drains);
+                if !self.coalesce_window.is_zero() {
+                    std::thread::sleep(self.coalesce_window);
+                }
+            }
+            while drains.is_empty()
+                && let Ok(job) = self.receiver.try_recv()
+            {
+                self.take_in(job, &mut lanes, &mut drains);
+            }
+            let batch = lanes.next_batch(self.max_coalesced);
+            self.held.fetch_sub(batch.len(), Ordering::AcqRel);
+            commit(batch);
+        }
+    }
+
+    fn take_in(&self, job: Job, lanes: &mut Lanes, drains: &mut Vec<oneshot::Sender<()>>) {
+        match job {
+            Job::Propose(proposal) => {
+                self.held.fetch_add(1, Ordering::AcqRel);
+                lanes.push(proposal);
+            }
+            Job::Drain(done) => drains.push(done),
+        }
+    }
+}
+
+/// Proposals a worker with fair queuing holds, in a queue per client.
+#[derive(Default)]
+struct Lanes {
+    queues: HashMap<String, VecDeque<Proposal>>,
+    /// Clients with proposals held, in the order their turns come.
+    turns: VecDeque<String>,
+}
+
+impl Lanes {
+    fn is_empty(&self) -> bool {
+        self.turns.is_empty()
+    }
+
+    fn push(&mut self, proposal: Proposal) {
+        let queue = self.queues.entry(proposal.client.clone()).or_default();
+        if queue.is_empty() {
+            self.turns.push_back(proposal.client.clone());
+        }
+        queue.push_back(proposal);
+    }
+
+    /// Up to `max` proposals, one from each client in turn.
+    fn next_batch(&mut self, max: usize) -> Vec<Proposal> {
+        let mut batch = Vec::new();
+        while batch.len() < max
+            && let Some(client) = self.turns.pop_front()
+        {
+            let queue = self.queues.get_mut(&client).expect("a client with a turn has a queue");
+            batch.extend(queue.pop_front());
+            if queue.is_empty() {
+                self.queues.remove(&client);
+            } else {
+                self.turns.push_back(client);
+            }
+        }
+        batch
+    }
+}
+
+/// Commits `batch` to `replica` with a single append, dropping proposals past their
+/// deadline, and answers each proposer.
+fn commit(replica: &Replica, batch: Vec<Proposal>) {
+    let live = drop_expired(batch);
+    if live.is_empty() {
+        return;
+    }
+
+    let commands: Vec<_> = live.iter().map(|proposal| proposal.command.clone()).collect();
+    let results = replica.propose_batch(&commands);
+    for (proposal, result) in live.into_iter().zip(results) {
+        // A proposer without a deadline may have given up waiting; the command is
+        // committed regardless.
+        let _ = proposal.reply.send(result);
+    }
+}
+
+/// Proposes `batch` to `raft`, dropping proposals past their deadline, and answers each
+/// proposer from a task on `runtime` once its entry is applied.
+fn propose_to_raft(raft: &RaftNode, runtime: &Handle, batch: Vec<Proposal>) {
+    let proposed: Vec<_> = drop_expired(batch)
+        .into_iter()
+        .map(|proposal| {
+            let command = proposal.command.encode();
+            (proposal.reply, command.and_then(|command| raft.propose_waiting(command)))
+        })
+        .collect();
+    runtime.spawn(async move {
+        // Entries apply in the order they were proposed, so waiting on each in turn
+        // answers none of them late.
+        for (reply, waiter) in proposed {
+            let result = match waiter {
+                Ok(waiter) => {
+                    let index = waiter.index();
+                    waiter.outcome().await.map(|outcome| (index, outcome))
+                }
+                Err(e) => Err(e),
+            };
+            let _ = reply.send(result);
+        }
+    });
+}
+
+/// Fails the proposals in `batch` whose deadline has passed, and returns the rest.
+fn drop_expired(batch: Vec<Proposal>) -> Vec<Proposal> {
+    let now = Instant::now();
+    let (expired, live): (Vec<_>, Vec<_>) = batch
+        .into_iter()
+        .partition(|proposal| proposal.deadline.is_some_and(|deadline| now >= deadline));
+    for proposal in expired {
+        let _ = proposal.reply.send(Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "proposal deadline passed before it was applied",
+        )));
+    }
+    live
+}
+
+fn queue_full() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::WouldBlock, "proposal queue is full")
+}
+
+fn worker_gone() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::BrokenPipe, "proposal worker has stopped")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+    use crate::bank::tests::create_account;
+    use crate::metrics::WalMetrics;
+
+    #[tokio::test]
+    async fn test_proposal_queue_rejects_when_full_until_drained() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let replica = Arc::new(Replica::open(temp_file.path().to_str().unwrap()).unwrap());
+        let (queue, worker) = proposal_queue(2);
+
+        // Nothing drains the queue yet, so the first two proposals wait in it.
+        let waiting: Vec<_> = ["alice", "bob"]
+            .into_iter()
+            .map(|account| {
+                let queue = queue.clone();
+                tokio::spawn(async move { queue.propose(create_account(account, 100)).await })
+            })
+            .collect();
+        while queue.depth() < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        let err = queue.propose(create_account("carol", 100)).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+        assert_eq!(queue.depth(), 2);
+
+        worker.spawn(replica.clone());
+        for proposal in waiting {
+            let (_, outcome) = proposal.await.unwrap().unwrap();
+            assert!(matches!(outcome, CommandOutcome::AccountCreated { .. }), "{:?}", outcome);
+        }
+        assert_eq!(queue.depth(), 0);
+
+        let (index, _) = queue.propose(create_account("carol", 100)).await.unwrap();
+        assert_eq!(index, 3);
+        assert_eq!(replica.read(|sm| sm.balance("carol")), Some(100));
+    }
+
+    #[tokio::test]
+    async fn test_proposal_queue_drops_expired_proposals_before_apply() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let replica = Arc::new(Replica::open(temp_file.path().to_str().unwrap()).unwrap());
+        let (queue, worker) = proposal_queue(2);
+
+        // Queued before the worker starts, so it is past its deadline when picked up.
+        let expired = {
+            let (queue, deadline) = (queue.clone(), Some(Instant::now()));
+            let command = create_account("alice", 100);
+            tokio::spawn(async move { queue.propose_by(command, deadline).await })
+        };
+        while queue.depth() < 1 {
+            tokio::task::yield_now().await;
+        }
+        worker.spawn(replica.clone());
+
+        let err = expired.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(replica.read(|sm| sm.balance("alice")), None);
+
+        let (index, _) = queue.propose(create_account("bob", 100)).await.unwrap();
+        assert_eq!(index, 1);
+    }
+
+    #[tokio::test]
+    async fn test_proposal_queue_shutdown_drains_queued_proposals() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let replica = Arc::new(Replica::open(temp_file.path().to_str().unwrap()).unwrap());
+        let (queue, worker) = proposal_queue(4);
+
+        let queued: Vec<_> = ["alice", "bob", "carol"]
+            .into_iter()
+            .map(|account| {
+                let queue = queue.clone();
+                tokio::spawn(async move { queue.propose(create_account(account, 100)).await })
+            })
+            .collect();
+        while queue.depth() < 3 {
+            tokio::task::yield_now().await;
+        }
+
+        let shutdown = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.shutdown().await }
+        });
+        while queue.depth() < 4 {
+            tokio::task::yield_now().await;
+        }
+        let err = queue.propose(create_account("dave", 100)).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+
+        worker.spawn(replica.clone());
+        shutdown.await.unwrap();
+        assert_eq!(replica.read(|sm| sm.balance("carol")), Some(100));
+        for proposal in queued {
+            assert!(proposal.await.unwrap().is_ok());
+        }
+        assert_eq!(replica.read(|sm| sm.balance("dave")), None);
+    }
+
+    #[tokio::test]
+    async fn test_proposal_worker_commits_queued_proposals_with_one_fsync() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let metrics = WalMetrics::default();
+        let path = temp_file.path().to_str().unwrap();
+        let replica = Arc::new(Replica::open_with_metrics(path, metrics.clone()).unwrap());
+        let (queue, worker) = proposal_queue(64);
+
+        let queued = propose_concurrently(&queue, 64);
+        while queue.depth() < 64 {
+            tokio::task::yield_now().await;
+        }
+        worker.spawn(replica.clone());
+
+        let mut indexes = Vec::new();
+        for proposal in queued {
+            let (index, outcome) = proposal.await.unwrap().unwrap();
+            assert!(matches!(outcome, CommandOutcome::AccountCreated { .. }), "{:?}", outcome);
+            indexes.push(index);
+        }
+        indexes.sort();
+        assert_eq!(indexes, (1..=64).collect::<Vec<_>>());
+        assert_eq!(metrics.fsync_seconds.get_sample_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_proposal_worker_coalesces_concurrent_proposals() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let metrics = WalMetrics::default();
+        let path = temp_file.path().to_str().unwrap();
+        let replica = Arc::new(Replica::open_with_metrics(path, metrics.clone()).unwrap());
+        let (queue, worker) = proposal_queue(256);
+        worker.with_coalescing(Duration::from_millis(20), 32).spawn(replica.clone());
+
+        let proposals = propose_concurrently(&queue, 200);
+        for proposal in proposals {
+            assert!(proposal.await.unwrap().is_ok());
+        }
+        assert_eq!(replica.last_applied(), 200);
+        // At most 32 commit together, so it takes at least 7 batches.
+        let fsyncs = metrics.fsync_seconds.get_sample_count();
+        assert!((7..50).contains(&fsyncs), "{} fsyncs for 200 proposals", fsyncs);
+    }
+
+    fn propose_concurrently(
+        queue: &ProposalQueue,
+        count: usize,
+    ) -> Vec<JoinHandle<ProposalResult>> {
+        (0..count)
+            .map(|i| {
+                let queue = queue.clone();
+                let command = create_account(&format!("account-{}", i), 100);
+                tokio::spawn(async move { queue.propose(command).await })
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_fair_queuing_does_not_starve_a_client_behind_a_busy_one() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let replica = Arc::new(Replica::open(temp_file.path().to_str().unwrap()).unwrap());
+        let (queue, worker) = proposal_queue(64);
+
+        let propose = |client: &'static str, account: String| {
+            let queue = queue.clone();
+            let command = create_account(&account, 100);
+            tokio::spawn(async move { queue.propose_from(client, command, None).await })
+        };
+        let busy: Vec<_> = (0..50).map(|i| propose("busy", format!("busy-{}", i))).collect();
+        while queue.depth() < 50 {
+            tokio::task::yield_now().await;
+        }
+        let quiet: Vec<_> = (0..3).map(|i| propose("quiet", format!("quiet-{}", i))).collect();
+        while queue.depth() < 53 {
+            tokio::task::yield_now().await;
+        }
+        worker.with_fair_queuing(true).with_coalescing(Duration::ZERO, 4).spawn(replica.clone());
+
+        // In the order they were queued, the quiet client's would come last, at 51 to 53.
+        let mut quiet_indexes = Vec::new();
+        for proposal in quiet {
+            quiet_indexes.push(proposal.await.unwrap().unwrap().0);
+        }
+        assert_eq!(quiet_indexes, vec![2, 4, 6]);
+        for proposal in busy {
+            assert!(proposal.await.unwrap().is_ok());
+        }
+        assert_eq!(replica.last_applied(), 53);
+        assert_eq!(queue.depth(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_fair_queuing_shutdown_drains_held_proposals() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let replica = Arc::new(Replica::open(temp_file.path().to_str().unwrap()).unwrap());
+        let (queue, worker) = proposal_queue(16);
+
+        let queued = propose_concurrently(&queue, 10);
+        while queue.depth() < 10 {
+            tokio::task::yield_now().await;
+        }
+        let shutdown = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.shutdown().await }
+        });
+        worker.with_fair_queuing(true).with_coalescing(Duration::ZERO, 2).spawn(replica.clone());
+        shutdown.await.unwrap();
+        assert_eq!(replica.last_applied(), 10);
+        for proposal in queued {
+            assert!(proposal.await.unwrap().is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proposal_queue_without_worker_fails() {
+        let (queue, worker) = proposal_queue(1);
+        drop(worker);
+
+        let err = queue.propose(create_account("alice", 100)).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard};
+use tokio::sync::oneshot;
+use crate::bank::CommandOutcome;
+use crate::raft::NotLeader;
+
+pub type ApplyResult = std::io::Result<CommandOutcome>;
+
+/// Proposals waiting to learn how their entry applied, by log index. The apply loop
+/// resolves each with the result of the entry at its index, and a follower fails the
+/// ones whose entries a new leader overwrites, so no proposer waits on an entry that
+/// will never apply.
+#[derive(Debug, Default)]
+pub struct ApplyWaiters {
+    waiting: Mutex<BTreeMap<u64, Waiting>>,
+}
+
+#[derive(Debug)]
+struct Waiting {
+    /// Term the entry was proposed in. An entry at the same index from any other term
+    /// is some other leader's, which replaced it.
+    term: u64,
+    reply: oneshot::Sender<ApplyResult>,
+}
+
+/// Resolves once the entry proposed at `index` has been applied, or is known never to be.
+#[derive(Debug)]
+pub struct ApplyWaiter {
+    index: u64,
+    outcome: oneshot::Receiver<ApplyResult>,
+}
+
+impl ApplyWaiters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Waits on the entry proposed at `index` in `term`, replacing any earlier waiter on
+    /// that index, which can only be for an entry since overwritten.
+    pub fn register(&self, index: u64, term: u64) -> ApplyWaiter {
+        let (reply, outcome) = oneshot::channel();
+        self.lock().insert(index, Waiting { term, reply });
+        ApplyWaiter { index, outcome }
+    }
+
+    /// Hands `result`, from applying the entry at `index` written in `term`, to whoever
+    /// waits on that index. A waiter from another term fails with `NotLeader` instead,
+    /// since its entry was replaced.
+    pub fn resolve(&self, index: u64, term: u64, result: ApplyResult) {
+        let Some(waiting) = self.lock().remove(&index) else {
+            return;
+        };
+        let result = if waiting.term == term {
+            result
+        } else {
+            Err(NotLeader { leader_id: None }.into_io())
+        };
+        // The proposer may have given up waiting.
+        let _ = waiting.reply.send(result);
+    }
+
+    /// Fails every waiter at `from` or later with `NotLeader`, naming `leader_id`, as
+    /// their entries were removed from the log.
+    pub fn truncate(&self, from: u64, leader_id: Option<String>) {
+        let truncated = self.lock().split_off(&from);
+        for waiting in truncated.into_values() {
+            let leader_id = leader_id.clone();
+            let _ = waiting.reply.send(Err(NotLeader { leader_id }.into_io()));
+        }
+    }
+
+    /// Proposals still waiting.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<u64, Waiting>> {
+        self.waiting.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl ApplyWaiter {
+    /// Index the entry was proposed at.
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    /// Waits for the entry's result. Fails with `NotLeader` if the entry was overwritten,
+    /// and with `BrokenPipe` if the node stopped before applying it.
+    pub async fn outcome(self) -> ApplyResult {
+        self.outcome.await.map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::BrokenPipe, "node stopped before applying")
+        })?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_apply_waiters_resolve_only_their_own_entry() {
+        let waiters = ApplyWaiters::new();
+        let first = waiters.register(1, 1);
+        let replaced = waiters.register(2, 1);
+        let dropped = waiters.register(3, 1);
+        assert_eq!(waiters.len(), 3);
+
+        let created = CommandOutcome::AccountCreated { account: "alice".to_string() };
+        waiters.resolve(1, 1, Ok(created.clone()));
+        assert_eq!(first.outcome().await.unwrap(), created);
+
+        // Another leader's entry applied at index 2.
+        waiters.resolve(2, 2, Ok(created));
+        let err = replaced.outcome().await.unwrap_err();
+        assert!(NotLeader::from_io(&err).is_some(), "{}", err);
+
+        drop(waiters);
+        let err = dropped.outcome().await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+    }
+}
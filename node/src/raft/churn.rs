@@ -0,0 +1,107 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Alert on more than this many elections within `DEFAULT_CHURN_WINDOW`, by default.
+pub const DEFAULT_CHURN_THRESHOLD: usize = 5;
+pub const DEFAULT_CHURN_WINDOW: Duration = Duration::from_secs(60);
+
+/// What an election churn hook is told: `elections` started within the last `window`,
+/// more than the threshold allows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ElectionChurn {
+    pub elections: usize,
+    pub window: Duration,
+}
+
+pub(crate) type ChurnHook = Box<dyn Fn(ElectionChurn) + Send + Sync>;
+
+/// Watches when elections start and calls a hook once they come faster than `threshold`
+/// per `window`. It fires as the rate crosses the threshold, not on every election past
+/// it, and again only once the rate has dropped back to the threshold or below.
+pub(crate) struct ChurnDetector {
+    threshold: usize,
+    window: Duration,
+    hook: ChurnHook,
+    /// When each election within the window started, oldest first.
+    started: VecDeque<Instant>,
+    /// Set from firing until the rate is back within the threshold.
+    alerting: bool,
+}
+
+impl ChurnDetector {
+    pub(crate) fn new(threshold: usize, window: Duration, hook: ChurnHook) -> Self {
+        Self {
+            threshold,
+            window,
+            hook,
+            started: VecDeque::new(),
+            alerting: false,
+        }
+    }
+
+    /// Notes an election started at `now`, and returns what to tell the hook if this one
+    /// takes the rate over the threshold.
+    pub(crate) fn election_started(&mut self, now: Instant) -> Option<ElectionChurn> {
+        self.started.push_back(now);
+        while self.started.front().is_some_and(|&started| now - started >= self.window) {
+            self.started.pop_front();
+        }
+
+        if self.started.len() <= self.threshold {
+            self.alerting = false;
+            return None;
+        }
+        if self.alerting {
+            return None;
+        }
+        self.alerting = true;
+        Some(ElectionChurn {
+            elections: self.started.len(),
+            window: self.window,
+        })
+    }
+
+    pub(crate) fn fire(&self, churn: ElectionChurn) {
+        (self.hook)(churn);
+    }
+}
+
+impl std::fmt::Debug for ChurnDetector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChurnDetector")
+            .field("threshold", &self.threshold)
+            .field("window", &self.window)
+            .field("started", &self.started.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_churn_detector_fires_once_per_crossing() {
+        let window = Duration::from_secs(10);
+        let mut churn = ChurnDetector::new(2, window, Box::new(|_| {}));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(churn.election_started(at(0)), None);
+        assert_eq!(churn.election_started(at(1)), None);
+        let fired = ElectionChurn {
+            elections: 3,
+            window,
+        };
+        assert_eq!(churn.election_started(at(2)), Some(fired));
+        assert_eq!(churn.election_started(at(3)), None);
+        // Still over the threshold once the first two fall out of the window.
+        assert_eq!(churn.election_started(at(11)), None);
+
+        // Back within it, so the next crossing fires again.
+        assert_eq!(churn.election_started(at(25)), None);
+        assert_eq!(churn.election_started(at(26)), None);
+        assert_eq!(churn.election_started(at(27)), Some(fired));
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::time::Duration;
+use crate::raft::snapshot::{DEFAULT_MAX_SNAPSHOT_INSTALLS, DEFAULT_SNAPSHOT_CHUNK_BYTES};
+use crate::raft::topology::Topology;
+use crate::wal::{DEFAULT_MAX_ENTRY_BYTES, DEFAULT_READ_BUFFER_BYTES};
+
+pub const DEFAULT_ELECTION_TIMEOUT_MIN: Duration = Duration::from_millis(150);
+pub const DEFAULT_ELECTION_TIMEOUT_MAX: Duration = Duration::from_millis(300);
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+pub const DEFAULT_MAX_ENTRIES_PER_APPEND: u64 = 64;
+pub const DEFAULT_MAX_BYTES_PER_APPEND: u64 = 1024 * 1024;
+pub const DEFAULT_APPEND_TIMEOUT: Duration = Duration::from_millis(150);
+pub const DEFAULT_APPEND_RETRIES: u32 = 3;
+pub const DEFAULT_APPEND_RETRY_BACKOFF: Duration = Duration::from_millis(10);
+pub const DEFAULT_VOTE_TIMEOUT: Duration = Duration::from_millis(150);
+pub const DEFAULT_COMMIT_PERSIST_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug)]
+pub struct RaftConfig {
+    pub id: String,
+    /// The other voting members, keyed by node id, with their Raft service address.
+    pub peers: BTreeMap<String, String>,
+    /// Members, possibly including this node, that vote and count toward the commit
+    /// quorum but store no log. They never stand for election, and leaders send them
+    /// heartbeats but no entries.
+    pub witnesses: BTreeSet<String>,
+    /// Members, possibly including this node, that vote and store the whole log, and can
+    /// serve reads through ReadIndex, but never stand for election.
+    pub read_replicas: BTreeSet<String>,
+    pub election_timeout_min: Duration,
+    pub election_timeout_max: Duration,
+    pub heartbeat_interval: Duration,
+    /// Most entries a leader sends a peer in one AppendEntries, so a follower far behind
+    /// is caught up in bounded batches rather than all at once. A follower this far
+    /// behind the commit index streams its missing entries instead.
+    pub max_entries_per_append: u64,
+    /// Most command bytes a leader sends a peer in one AppendEntries. A batch always
+    /// carries at least one entry, however large.
+    pub max_bytes_per_append: u64,
+    /// Largest command a leader accepts in one proposal, and the log in one entry. Every
+    /// member should agree on it, or a follower may refuse entries its leader took.
+    pub max_entry_bytes: u64,
+    /// How long a leader waits for a peer to answer one AppendEntries.
+    pub append_timeout: Duration,
+    /// How many times in a row a leader retries a peer whose AppendEntries failed or
+    /// timed out before marking it lagging. A lagging peer gets probes, heartbeats with
+    /// no entries, until it answers one, so a slow follower is not sent batch after
+    /// batch it cannot take.
+    pub append_retries: u32,
+    /// Wait before the first retry, doubled for each one after.
+    pub append_retry_backoff: Duration,
+    /// How long a candidate waits for a peer to answer one RequestVote. A candidate asks
+    /// every peer at once and wins as soon as a majority grants its vote, so a slow peer
+    /// only costs it the vote it never got.
+    pub vote_timeout: Duration,
+    /// Election priorities by node id, possibly including this node; members not listed
+    /// have priority 0. Each point a node is below the highest priority among the members
+    /// that can lead adds `election_timeout_max` to its election timeout, so a healthy
+    /// node of higher priority times out, and wins, first. Votes are granted as before,
+    /// so if it is down the others still elect one of themselves, only later.
+    pub election_priorities: BTreeMap<String, u32>,
+    /// Where members, possibly including this node, run, by node id. A leader sends
+    /// AppendEntries to nearer voters first, so a majority of them acknowledges sooner;
+    /// members not listed, until `RaftNode::set_topology` places them, count as far away.
+    pub topology: BTreeMap<String, Topology>,
+    /// Fsyncs the log on a background thread this often, so entries appended in between
+    /// share one fsync, instead of fsyncing each append. An entry counts toward a
+    /// majority, and is applied, only once it is on disk here; `None` fsyncs each append.
+    pub wal_flush_interval: Option<Duration>,
+    /// Bytes the log is read at a time when it is scanned and replayed on startup.
+    pub wal_read_buffer_bytes: usize,
+    /// Whether a leader stepping down removes the entries it appended in its own term
+    /// past its commit index, which the next leader would likely overwrite anyway, so it
+    /// reconciles with that leader sooner. Committed entries are never removed, nor are
+    /// those inherited from earlier terms, which an earlier leader may have committed.
+    /// Off by default, as in strict Raft the next leader may still commit them.
+    pub trim_uncommitted_on_step_down: bool,
+    /// How often the commit index is saved with the hard state, if it moved, rather than
+    /// with an fsync on every advance. Safety does not rest on it: a node restarting from
+    /// an older commit index only waits for its leader to tell it the rest again.
+    pub commit_persist_interval: Duration,
+    /// Most snapshot bytes a leader sends in one InstallSnapshot, to a peer that needs
+    /// entries it has compacted away. Each chunk is on the peer's disk before the next
+    /// is sent, so a transfer cut off resumes from the last one.
+    pub snapshot_chunk_bytes: u64,
+    /// Where snapshots from the leader are put together as their chunks arrive, which
+    /// may be other storage than the log's; `None` puts them next to the log.
+    pub snapshot_dir: Option<PathBuf>,
+    /// Most InstallSnapshots this node handles at once, as each writes to disk. Those
+    /// past it wait their turn, or with `reject_excess_snapshot_installs` are turned
+    /// away, for the leader to send again after a backoff.
+    pub max_snapshot_installs: u32,
+    pub reject_excess_snapshot_installs: bool,
+}
+
+impl RaftConfig {
+    pub fn new(id: impl Into<String>, peers: BTreeMap<String, String>) -> Self {
+        Self {
+            id: id.into(),
+            peers,
+            witnesses: BTreeSet::new(),
+            read_replicas: BTreeSet::new(),
+            election_timeout_min: DEFAULT_ELECTION_TIMEOUT_MIN,
+            election_timeout_max: DEFAULT_ELECTION_TIMEOUT_MAX,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            max_entries_per_append: DEFAULT_MAX_ENTRIES_PER_APPEND,
+            max_bytes_per_append: DEFAULT_MAX_BYTES_PER_APPEND,
+            max_entry_bytes: DEFAULT_MAX_ENTRY_BYTES,
+            append_timeout: DEFAULT_APPEND_TIMEOUT,
+            append_retries: DEFAULT_APPEND_RETRIES,
+            append_retry_backoff: DEFAULT_APPEND_RETRY_BACKOFF,
+            vote_timeout: DEFAULT_VOTE_TIMEOUT,
+            election_priorities: BTreeMap::new(),
+            topology: BTreeMap::new(),
+            wal_flush_interval: None,
+            wal_read_buffer_bytes: DEFAULT_READ_BUFFER_BYTES,
+            trim_uncommitted_on_step_down: false,
+            commit_persist_interval: DEFAULT_COMMIT_PERSIST_INTERVAL,
+            snapshot_chunk_bytes: DEFAULT_SNAPSHOT_CHUNK_BYTES,
+            snapshot_dir: None,
+            max_snapshot_installs: DEFAULT_MAX_SNAPSHOT_INSTALLS,
+            reject_excess_snapshot_installs: false,
+        }
+    }
+
+    /// Number of votes (or acknowledgements) that make a majority, counting this node.
+    pub fn quorum(&self) -> usize {
+        let members = self.peers.len() + 1;
+        members / 2 + 1
+    }
+
+    pub fn is_witness(&self, id: &str) -> bool {
+        self.witnesses.contains(id)
+    }
+
+    /// Whether `id` may stand for election: it is neither a witness nor a read replica.
+    pub fn can_lead(&self, id: &str) -> bool {
+        !self.witnesses.contains(id) && !self.read_replicas.contains(id)
+    }
+
+    pub fn election_priority(&self, id: &str) -> u32 {
+        self.election_priorities.get(id).copied().unwrap_or(0)
+    }
+
+    /// Wait before retrying an AppendEntries that failed `failures` times in a row.
+    pub fn append_retry_delay(&self, failures: u32) -> Duration {
+        let doublings = failures.saturating_sub(1).min(16);
+        self.append_retry_backoff.saturating_mul(1 << doublings)
+    }
+
+    /// How much longer than a node of the highest priority this node waits before
+    /// standing for election.
+    pub fn election_delay(&self) -> Duration {
+        let highest = std::iter::once(&self.id)
+            .chain(self.peers.keys())
+            .filter(|id| self.can_lead(id))
+            .map(|id| self.election_priority(id))
+            .max()
+            .unwrap_or(0);
+        let behind = highest.saturating_sub(self.election_priority(&self.id));
+        self.election_timeout_max.saturating_mul(behind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_with_peers(count: usize) -> RaftConfig {
+        let peers = (0..count).map(|i| (format!("peer-{}", i), String::new())).collect();
+        RaftConfig::new("node", peers)
+    }
+
+    #[test]
+    fn test_raft_config_quorum() {
+        assert_eq!(config_with_peers(0).quorum(), 1);
+        assert_eq!(config_with_peers(1).quorum(), 2);
+        assert_eq!(config_with_peers(2).quorum(), 2);
+        assert_eq!(config_with_peers(3).quorum(), 3);
+        assert_eq!(config_with_peers(4).quorum(), 3);
+    }
+
+    #[test]
+    fn test_raft_config_witness_counts_toward_quorum() {
+        let mut config = config_with_peers(2);
+        config.witnesses.insert("peer-1".to_string());
+
+        assert!(config.is_witness("peer-1"));
+        assert!(!config.is_witness("node"));
+        assert_eq!(config.quorum(), 2);
+    }
+
+    #[test]
+    fn test_raft_config_only_data_members_can_lead() {
+        let mut config = config_with_peers(3);
+        config.witnesses.insert("peer-1".to_string());
+        config.read_replicas.insert("peer-2".to_string());
+
+        assert!(config.can_lead("node"));
+        assert!(config.can_lead("peer-0"));
+        assert!(!config.can_lead("peer-1"));
+        assert!(!config.can_lead("peer-2"));
+        assert_eq!(config.quorum(), 3);
+    }
+
+    #[test]
+    fn test_raft_config_election_delay_follows_priority() {
+        let mut config = config_with_peers(3);
+        assert_eq!(config.election_delay(), Duration::ZERO);
+
+        config.election_priorities.insert("peer-0".to_string(), 2);
+        config.election_priorities.insert("node".to_string(), 1);
+        assert_eq!(config.election_delay(), DEFAULT_ELECTION_TIMEOUT_MAX);
+
+        // Members that cannot lead, and ids that are not members, do not count.
+        config.election_priorities.insert("peer-1".to_string(), 5);
+        config.election_priorities.insert("stranger".to_string(), 9);
+        config.witnesses.insert("peer-1".to_string());
+        assert_eq!(config.election_delay(), DEFAULT_ELECTION_TIMEOUT_MAX);
+
+        config.election_priorities.insert("node".to_string(), 3);
+        assert_eq!(config.election_delay(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_raft_config_append_retry_delay_doubles() {
+        let mut config = config_with_peers(2);
+        config.append_retry_backoff = Duration::from_millis(10);
+
+        assert_eq!(config.append_retry_delay(1), Duration::from_millis(10));
+        assert_eq!(config.append_retry_delay(2), Duration::from_millis(20));
+        assert_eq!(config.append_retry_delay(4), Duration::from_millis(80));
+    }
+}
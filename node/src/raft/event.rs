@@ -0,0 +1,30 @@
+/// Events a node buffers for each subscriber; one that falls further behind misses the
+/// oldest and is told how many with `RecvError::Lagged`.
+pub const EVENT_BUFFER: usize = 256;
+
+/// A change to a node's Raft state, as `RaftNode::events` reports it. Each is sent only
+/// once the state it reports is durable, so a subscriber never sees a term or commit
+/// index the node could forget on a crash.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RaftEvent {
+    /// This node won the election for `term`.
+    BecameLeader { term: u64 },
+    /// This node stopped leading or standing for election, in `term`.
+    BecameFollower { term: u64 },
+    /// This node started an election for `term`.
+    BecameCandidate { term: u64 },
+    /// This node moved on to `term`, ahead of any change of role that comes with it.
+    TermChanged { term: u64 },
+    CommitAdvanced { commit_index: u64 },
+    /// The voters other than this node changed, as an entry removing one was appended to
+    /// or overwritten in the log.
+    ConfigChanged { voters: Vec<String> },
+    /// The entry at `index`, removing this node from the voters, committed while it led.
+    /// It has stepped down and stays a follower that stands for no elections.
+    Removed { index: u64 },
+    /// A chunk of the snapshot covering the log up to `index` reached the disk, which now
+    /// holds the snapshot up to `offset`.
+    SnapshotReceived { index: u64, offset: u64 },
+    /// A snapshot from the leader took the place of the log up to `index`.
+    SnapshotInstalled { index: u64 },
+}
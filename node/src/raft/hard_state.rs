@@ -0,0 +1,256 @@
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+/// Raft state that must survive restarts before the node answers any RPC.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HardState {
+    pub current_term: u64,
+    pub voted_for: Option<String>,
+    /// Witness only: (term, index) of the last entry a leader had this node acknowledge.
+    /// A witness stores no log, so it votes only for candidates at least this up to date.
+    pub witnessed: (u64, u64),
+    /// Highest index known committed when last saved, never past what the log holds on
+    /// disk, so a restarted node can apply up to it without waiting to hear from a leader.
+    pub commit_index: u64,
+}
+
+impl HardState {
+    pub fn encode(&self) -> std::io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        buf.write_u64::<LittleEndian>(self.current_term)?;
+        match &self.voted_for {
+            Some(candidate) => {
+                buf.write_u8(1)?;
+                buf.write_u64::<LittleEndian>(candidate.len() as u64)?;
+                buf.extend_from_slice(candidate.as_bytes());
+            }
+            None => buf.write_u8(0)?,
+        }
+        buf.write_u64::<LittleEndian>(self.witnessed.0)?;
+        buf.write_u64::<LittleEndian>(self.witnessed.1)?;
+        buf.write_u64::<LittleEndian>(self.commit_index)?;
+
+        Ok(buf)
+    }
+
+    pub fn decode<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let current_term = reader.read_u64::<LittleEndian>()?;
+        let voted_for = match reader.read_u8()? {
+            0 => None,
+            _ => {
+                let len = reader.read_u64::<LittleEndian>()? as usize;
+                let mut buf = vec![0u8; len];
+                reader.read_exact(&mut buf)?;
+                let candidate = String::from_utf8(buf).map_err(|e| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+                })?;
+                Some(candidate)
+            }
+        };
+
+        // Absent from state saved before witnesses existed.
+        let witnessed = match read_trailing_u64(reader)? {
+            Some(term) => (term, reader.read_u64::<LittleEndian>()?),
+            None => (0, 0),
+        };
+        // Absent from state saved before the commit index was kept.
+        let commit_index = read_trailing_u64(reader)?.unwrap_or_default();
+
+        Ok(Self { current_term, voted_for, witnessed, commit_index })
+    }
+}
+
+/// Reads a field added after the others, or `None` if the state ends before it. Ending
+/// partway through it is an error.
+fn read_trailing_u64<R: Read>(reader: &mut R) -> std::io::Result<Option<u64>> {
+    let mut buf = [0u8; 8];
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    match read {
+        0 => Ok(None),
+        8 => Ok(Some(u64::from_le_bytes(buf))),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "hard state ends partway through a field",
+        )),
+    }
+}
+
+/// Persists `HardState` to a single file, replacing it atomically on every save.
+#[derive(Debug)]
+pub struct HardStateStore {
+    path: PathBuf,
+    /// Tickets handed out, one per save, in the order the saves were asked for.
+    issued: AtomicU64,
+    /// Ticket of the last save written. Held while writing, so saves never interleave.
+    written: Mutex<u64>,
+}
+
+impl HardStateStore {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            issued: AtomicU64::new(0),
+            written: Mutex::new(0),
+        }
+    }
+
+    /// Loads the saved state, or the initial state if nothing was saved yet.
+    pub fn load(&self) -> std::io::Result<HardState> {
+        match std::fs::File::open(&self.path) {
+            Ok(file) => HardState::decode(&mut std::io::BufReader::new(file)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HardState::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, state: &HardState) -> std::io::Result<()> {
+        self.save_unless_overtaken(state, self.ticket()).map(|_| ())
+    }
+
+    /// Reserves a place in the order of saves, for a state to be saved later with
+    /// `save_unless_overtaken`, e.g. once a lock guarding it was released.
+    pub fn ticket(&self) -> u64 {
+        self.issued.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Saves `state` unless a save asked for after `ticket` was taken has been written
+    /// already, in which case `state` is stale and is dropped. Returns whether it was
+    /// saved.
+    pub fn save_unless_overtaken(&self, state: &HardState, ticket: u64) -> std::io::Result<bool> {
+        let mut written = self.written.lock().unwrap_or_else(|e| e.into_inner());
+        if *written > ticket {
+            return Ok(false);
+        }
+        self.write(state)?;
+        *written = ticket;
+        Ok(true)
+    }
+
+    fn write(&self, state: &HardState) -> std::io::Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        {
+            let mut file = std::fs::File::create(&tmp_path)?;
+            file.write_all(&state.encode()?)?;
+            file.sync_data()?;
+        }
+
+        std::fs::rename(&tmp_path, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_hard_state_load_missing_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = HardStateStore::new(temp_dir.path().join("raft.state"));
+
+        assert_eq!(store.load().unwrap(), HardState::default());
+    }
+
+    #[test]
+    fn test_hard_state_save_and_load() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = HardStateStore::new(temp_dir.path().join("raft.state"));
+
+        let voted = HardState {
+            current_term: 7,
+            voted_for: Some("node-2".to_string()),
+            ..HardState::default()
+        };
+        store.save(&voted).unwrap();
+        assert_eq!(store.load().unwrap(), voted);
+
+        let not_voted = HardState {
+            current_term: 8,
+            voted_for: None,
+            witnessed: (7, 12),
+            commit_index: 10,
+        };
+        store.save(&not_voted).unwrap();
+        let reopened = HardStateStore::new(temp_dir.path().join("raft.state"));
+        assert_eq!(reopened.load().unwrap(), not_voted);
+    }
+
+    #[test]
+    fn test_hard_state_save_overtaken_by_a_later_one_is_dropped() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = HardStateStore::new(temp_dir.path().join("raft.state"));
+
+        let stale = HardState { current_term: 3, commit_index: 5, ..HardState::default() };
+        let ticket = store.ticket();
+        let voted = HardState {
+            current_term: 4,
+            voted_for: Some("node-2".to_string()),
+            ..HardState::default()
+        };
+        store.save(&voted).unwrap();
+        assert!(!store.save_unless_overtaken(&stale, ticket).unwrap());
+        assert_eq!(store.load().unwrap(), voted);
+
+        let committed = HardState { commit_index: 5, ..voted };
+        let ticket = store.ticket();
+        assert!(store.save_unless_overtaken(&committed, ticket).unwrap());
+        assert_eq!(store.load().unwrap(), committed);
+    }
+
+    #[test]
+    fn test_hard_state_decode_truncated() {
+        let encoded = HardState {
+            current_term: 1,
+            voted_for: Some("node-1".to_string()),
+            ..HardState::default()
+        }
+        .encode()
+        .unwrap();
+
+        let mut cursor = std::io::Cursor::new(&encoded[..encoded.len() - 1]);
+        assert!(HardState::decode(&mut cursor).is_err());
+    }
+
+    #[test]
+    fn test_hard_state_decode_without_commit_index() {
+        let mut encoded = HardState {
+            current_term: 4,
+            witnessed: (3, 9),
+            commit_index: 9,
+            ..HardState::default()
+        }
+        .encode()
+        .unwrap();
+        encoded.truncate(encoded.len() - 8);
+
+        let state = HardState::decode(&mut std::io::Cursor::new(encoded)).unwrap();
+        assert_eq!((state.witnessed, state.commit_index), ((3, 9), 0));
+    }
+
+    #[test]
+    fn test_hard_state_decode_without_witnessed() {
+        // What a node saved before witnesses existed: term, then no vote.
+        let mut encoded = 3u64.to_le_bytes().to_vec();
+        encoded.push(0);
+
+        let state = HardState::decode(&mut std::io::Cursor::new(encoded)).unwrap();
+        assert_eq!(
+            state,
+            HardState {
+                current_term: 3,
+                ..HardState::default()
+            }
+        );
+    }
+}
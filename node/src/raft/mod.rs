@@ -0,0 +1,24 @@
+mod apply;
+mod churn;
+mod config;
+mod event;
+mod hard_state;
+mod node;
+mod sim;
+mod snapshot;
+mod topology;
+mod transport;
+
+pub use apply::{ApplyResult, ApplyWaiter, ApplyWaiters};
+pub use churn::{ElectionChurn, DEFAULT_CHURN_THRESHOLD, DEFAULT_CHURN_WINDOW};
+pub use config::RaftConfig;
+pub use event::{RaftEvent, EVENT_BUFFER};
+pub use hard_state::{HardState, HardStateStore};
+pub use node::{NotLeader, PeerStatus, RaftNode, Role};
+pub use sim::{SimNetwork, SimTransport};
+pub use snapshot::{SnapshotStore, DEFAULT_MAX_SNAPSHOT_INSTALLS, DEFAULT_SNAPSHOT_CHUNK_BYTES};
+pub use topology::Topology;
+pub use transport::{EntryStream, GrpcTransport, RaftTransport};
+
+#[cfg(test)]
+pub(crate) use node::tests;
@@ -0,0 +1,3757 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use bytes::Bytes;
+use tokio::sync::{broadcast, mpsc, watch, Notify, Semaphore};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+use tonic::Status;
+use tracing::{debug, info, instrument, warn, Span};
+use raft_core::raft::{
+    self as pb, AppendEntriesRequest, AppendEntriesResponse, InstallSnapshotRequest,
+    InstallSnapshotResponse, NodeId, ReadIndexRequest, ReadIndexResponse, RequestVoteRequest,
+    RequestVoteResponse, StreamEntriesRequest, TimeoutNowRequest, TimeoutNowResponse,
+};
+use crate::bank::{BankCommand, MembershipChange};
+use crate::clock::{self, Clock, SystemClock};
+use crate::raft::churn::ChurnDetector;
+use crate::raft::snapshot::{last_included, ConfigChanges, OutgoingSnapshot, SnapshotAssembly};
+use crate::raft::topology::{self, Topology};
+use crate::raft::{
+    ApplyResult, ApplyWaiter, ApplyWaiters, ElectionChurn, EntryStream, HardState,
+    HardStateStore, RaftConfig, RaftEvent, RaftTransport, SnapshotStore, EVENT_BUFFER,
+};
+use crate::wal::{
+    CompactionPoint, DurableWaiter, EntryTooLarge, IntegrityMode, LogEntry, Wal, DEFAULT_FILE_MODE,
+};
+
+/// Batches a leader reads ahead of a follower consuming its StreamEntries.
+const STREAM_BUFFER: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// Returned by `propose` on a node that is not the leader.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NotLeader {
+    /// The leader this node last heard from, if any.
+    pub leader_id: Option<String>,
+}
+
+impl NotLeader {
+    /// Returns the `NotLeader` details if `e` was caused by proposing to a follower.
+    pub fn from_io(e: &std::io::Error) -> Option<&Self> {
+        e.get_ref()?.downcast_ref()
+    }
+
+    pub(crate) fn into_io(self) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::PermissionDenied, self)
+    }
+}
+
+impl fmt::Display for NotLeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.leader_id {
+            Some(leader) => write!(f, "not the leader; the leader is {}", leader),
+            None => write!(f, "not the leader; no leader is known"),
+        }
+    }
+}
+
+impl std::error::Error for NotLeader {}
+
+/// How far a peer is behind the leader, as `RaftNode::peer_status` reports it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerStatus {
+    pub id: String,
+    /// Highest index the peer is known to store.
+    pub match_index: u64,
+    /// Next index the leader sends the peer.
+    pub next_index: u64,
+    /// Entries the leader stores that the peer is not known to: the leader's last index
+    /// minus `match_index`.
+    pub lag: u64,
+    /// Time since the peer last acknowledged an AppendEntries, or `None` if it has not
+    /// since this node became leader.
+    pub since_last_append: Option<Duration>,
+    /// Whether the peer failed too many AppendEntries in a row, and gets only probes
+    /// until it answers one.
+    pub lagging: bool,
+}
+
+/// One member of a Raft group: runs elections, and while leader, replicates its log to
+/// its peers and commits entries once a majority stores them.
+pub struct RaftNode {
+    config: RaftConfig,
+    store: HardStateStore,
+    transport: Arc<dyn RaftTransport>,
+    clock: Arc<dyn Clock>,
+    state: Mutex<RaftState>,
+    /// Wakes the replicators when the leader appends to its log.
+    appended: Notify,
+    /// Wakes the catch-up task when a follower finds itself far behind the leader.
+    behind: Notify,
+    /// Wakes reads waiting for the commit index to reach their read index.
+    committed: Notify,
+    /// The commit index, for whoever follows committed entries as they come.
+    commits: watch::Sender<u64>,
+    /// Wakes the election loop to campaign at once, when the leader hands over to us.
+    campaign: Notify,
+    /// Proposals made through `propose_waiting` that have not been applied yet.
+    waiters: ApplyWaiters,
+    elections_started: AtomicU64,
+    leader_changes: AtomicU64,
+    /// Set by `on_election_churn`.
+    churn: Mutex<Option<ChurnDetector>>,
+    events: broadcast::Sender<RaftEvent>,
+    /// Where members run, starting from `config.topology`.
+    topology: Mutex<BTreeMap<String, Topology>>,
+    /// Set by `set_snapshot_store`.
+    snapshots: Mutex<Option<Arc<dyn SnapshotStore>>>,
+    /// The snapshot being received from the leader, if any.
+    assembly: Mutex<Option<SnapshotAssembly>>,
+    /// Holds `config.max_snapshot_installs` permits, one taken by each InstallSnapshot
+    /// being handled.
+    snapshot_installs: Arc<Semaphore>,
+    /// Where snapshots from the leader are received: `config.snapshot_dir`, or the log's
+    /// directory.
+    snapshot_dir: PathBuf,
+    /// Keeps the voters changed by entries compacted out of the log.
+    voters_path: PathBuf,
+}
+
+/// Where an AppendEntries stands once its entries are in the log.
+enum Appended {
+    Answered(AppendEntriesResponse),
+    /// The entries up to `last_new` must be flushed before they are acknowledged.
+    Flushing { waiter: DurableWaiter, last_new: u64 },
+}
+
+#[derive(Debug)]
+struct RaftState {
+    role: Role,
+    hard: HardState,
+    leader_id: Option<String>,
+    /// Followers and candidates start an election once this passes.
+    election_deadline: Instant,
+    /// When a follower last accepted AppendEntries from the current leader.
+    last_leader_contact: Option<Instant>,
+    /// When a leader last had AppendEntries acknowledged by each peer.
+    peer_contact: BTreeMap<String, Instant>,
+    log: Wal,
+    /// Highest index known to be stored by a majority.
+    commit_index: u64,
+    /// Leader only: the next index to send each peer.
+    next_index: BTreeMap<String, u64>,
+    /// Leader only: the highest index each peer is known to store.
+    match_index: BTreeMap<String, u64>,
+    /// Leader only: peers catching up through StreamEntries. They get heartbeats but no
+    /// entries from the replicators meanwhile.
+    streaming: BTreeSet<String>,
+    /// Leader only: peers that failed `append_retries` AppendEntries in a row. They get
+    /// heartbeats but no entries until they answer one.
+    lagging: BTreeSet<String>,
+    /// Follower only: a StreamEntries catch-up is due or running.
+    catching_up: bool,
+    /// Peers that `RemoveNode` entries in our log took out of the voters, by the index
+    /// of the entry. A removal applies as soon as it is in the log, committed or not, and
+    /// is undone if the entry is overwritten.
+    removals: BTreeMap<u64, String>,
+    /// Voters that `AddNode` entries in our log added to `config.peers`, with their Raft
+    /// addresses, by the index of the entry. Like removals, they apply once in the log.
+    additions: BTreeMap<u64, (String, String)>,
+    /// Set by `shutdown`, or once the entry removing this node commits while it leads:
+    /// the node takes no more proposals and stands for no elections.
+    shutting_down: bool,
+    /// Term of the last leader this node learned of, itself included.
+    leader_term: u64,
+}
+
+impl RaftNode {
+    /// Loads the persisted term and vote from `state_path` and the log from `log_path`.
+    /// The node is inert until `start`.
+    pub fn new(
+        config: RaftConfig,
+        state_path: impl AsRef<Path>,
+        log_path: impl AsRef<Path>,
+        transport: Arc<dyn RaftTransport>,
+    ) -> std::io::Result<Arc<Self>> {
+        Self::new_with_clock(config, state_path, log_path, transport, Arc::new(SystemClock))
+    }
+
+    /// Like `new`, timing elections and heartbeats with `clock`.
+    pub fn new_with_clock(
+        config: RaftConfig,
+        state_path: impl AsRef<Path>,
+        log_path: impl AsRef<Path>,
+        transport: Arc<dyn RaftTransport>,
+        clock: Arc<dyn Clock>,
+    ) -> std::io::Result<Arc<Self>> {
+        let store = HardStateStore::new(state_path);
+        let mut log = Wal::new_with_read_buffer(
+            &log_path.as_ref().to_string_lossy(),
+            IntegrityMode::Full,
+            DEFAULT_FILE_MODE,
+            config.wal_read_buffer_bytes,
+        )?
+        .with_max_entry_bytes(config.max_entry_bytes);
+        if let Some(interval) = config.wal_flush_interval {
+            log = log.with_background_flush(interval)?;
+        }
+        let voters_path = ConfigChanges::path_for(log_path.as_ref());
+        let ConfigChanges {
+            mut removals,
+            mut additions,
+        } = ConfigChanges::load(&voters_path)?;
+        log.replay_with(|entry, _, _| {
+            if let Some(peer) = removed_voter(entry) {
+                removals.insert(entry.index, peer);
+            }
+            if let Some(peer) = added_voter(entry) {
+                additions.insert(entry.index, peer);
+            }
+        })?;
+        for (peer, addr) in additions.values() {
+            transport.add_peer(peer, addr);
+        }
+        let hard = store.load()?;
+        // Committed entries stay committed, so those the log still holds can be applied
+        // again straight away. Only committed entries are ever compacted.
+        let commit_index = hard.commit_index.min(log.last_index()).max(log.first_index() - 1);
+        let state = RaftState {
+            role: Role::Follower,
+            hard,
+            leader_id: None,
+            election_deadline: clock.now(),
+            last_leader_contact: None,
+            peer_contact: BTreeMap::new(),
+            log,
+            commit_index,
+            next_index: BTreeMap::new(),
+            match_index: BTreeMap::new(),
+            streaming: BTreeSet::new(),
+            lagging: BTreeSet::new(),
+            catching_up: false,
+            removals,
+            additions,
+            shutting_down: false,
+            leader_term: 0,
+        };
+
+        let topology = Mutex::new(config.topology.clone());
+        let snapshot_installs = Arc::new(Semaphore::new(config.max_snapshot_installs as usize));
+        let snapshot_dir = match (&config.snapshot_dir, log_path.as_ref().parent()) {
+            (Some(dir), _) => dir.clone(),
+            (None, Some(dir)) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            (None, _) => PathBuf::from("."),
+        };
+        let node = Arc::new(Self {
+            config,
+            store,
+            transport,
+            clock,
+            state: Mutex::new(state),
+            appended: Notify::new(),
+            behind: Notify::new(),
+            committed: Notify::new(),
+            commits: watch::Sender::new(commit_index),
+            campaign: Notify::new(),
+            waiters: ApplyWaiters::new(),
+            elections_started: AtomicU64::new(0),
+            leader_changes: AtomicU64::new(0),
+            churn: Mutex::new(None),
+            events: broadcast::Sender::new(EVENT_BUFFER),
+            topology,
+            snapshots: Mutex::new(None),
+            assembly: Mutex::new(None),
+            snapshot_installs,
+            snapshot_dir,
+            voters_path,
+        });
+        node.lock().election_deadline = node.next_election_deadline();
+        Ok(node)
+    }
+
+    /// Forms a new cluster of this node alone, which leads it at once, committing with a
+    /// quorum of one, and grows it with `add_voter`. Fails with `InvalidInput` if
+    /// `config.peers` is not empty, and with `AlreadyExists` if there is a log or hard
+    /// state at the paths given, so restarting a node with bootstrap still set cannot
+    /// start a second cluster over the history of the first.
+    pub fn bootstrap(
+        config: RaftConfig,
+        state_path: impl AsRef<Path>,
+        log_path: impl AsRef<Path>,
+        transport: Arc<dyn RaftTransport>,
+    ) -> std::io::Result<Arc<Self>> {
+        if !config.peers.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "a bootstrapped cluster starts with no peers; add them once it is up",
+            ));
+        }
+        for path in [state_path.as_ref(), log_path.as_ref()] {
+            if path.exists() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("refusing to bootstrap over the existing {}", path.display()),
+                ));
+            }
+        }
+
+        let node = Self::new(config, state_path, log_path, transport)?;
+        {
+            let mut state = node.lock();
+            state.hard.current_term = 1;
+            state.hard.voted_for = Some(node.config.id.clone());
+            node.store.save(&state.hard)?;
+            node.emit(RaftEvent::TermChanged { term: 1 });
+            node.become_leader(&mut state, 1)?;
+        }
+        info!(id = %node.config.id, "bootstrapped a new cluster");
+        Ok(node)
+    }
+
+    /// Elections this node has started as a candidate since it was created.
+    pub fn elections_started(&self) -> u64 {
+        self.elections_started.load(Ordering::Relaxed)
+    }
+
+    /// Leaders this node has learned of since it was created, itself included: one for
+    /// every term it saw a leader in.
+    pub fn leader_changes(&self) -> u64 {
+        self.leader_changes.load(Ordering::Relaxed)
+    }
+
+    /// Subscribes to the changes of role, term, commit index and voters this node goes
+    /// through from now on, in the order they happen.
+    pub fn events(&self) -> broadcast::Receiver<RaftEvent> {
+        self.events.subscribe()
+    }
+
+    /// Calls `hook` when more than `threshold` elections start on this node within
+    /// `window`, a sign the cluster keeps losing its leader. It fires as elections cross
+    /// the threshold, and again only after they have dropped back within it. Replaces any
+    /// hook set before.
+    pub fn on_election_churn(
+        &self,
+        threshold: usize,
+        window: Duration,
+        hook: impl Fn(ElectionChurn) + Send + Sync + 'static,
+    ) {
+        let detector = ChurnDetector::new(threshold, window, Box::new(hook));
+        *self.churn.lock().unwrap() = Some(detector);
+    }
+
+    /// Sends peers that need entries compacted out of the log the latest snapshot in
+    /// `store`, and installs snapshots from the leader into it. Replaces any store set
+    /// before. Without one, the log is never compacted, so no peer needs a snapshot.
+    pub fn set_snapshot_store(&self, store: Arc<dyn SnapshotStore>) {
+        *self.snapshots.lock().unwrap() = Some(store);
+    }
+
+    fn snapshot_store(&self) -> std::io::Result<Arc<dyn SnapshotStore>> {
+        let store = self.snapshots.lock().unwrap().clone();
+        store.ok_or_else(|| std::io::Error::other("no snapshot store is set"))
+    }
+
+    /// Spawns the election and heartbeat loop. Aborting the handle stops the node.
+    pub fn start(self: &Arc<Self>) -> JoinHandle<()> {
+        tokio::spawn(self.clone().run())
+    }
+
+    pub fn id(&self) -> &str {
+        &self.config.id
+    }
+
+    pub fn config(&self) -> &RaftConfig {
+        &self.config
+    }
+
+    pub fn role(&self) -> Role {
+        self.lock().role
+    }
+
+    pub fn current_term(&self) -> u64 {
+        self.lock().hard.current_term
+    }
+
+    pub fn leader_id(&self) -> Option<String> {
+        self.lock().leader_id.clone()
+    }
+
+    pub fn last_log_index(&self) -> u64 {
+        self.lock().log.last_index()
+    }
+
+    /// Highest log index this node has on disk. It lags `last_log_index` between
+    /// flushes when `wal_flush_interval` is set, and the commit index never passes it.
+    pub fn durable_index(&self) -> u64 {
+        self.lock().log.durable_index()
+    }
+
+    /// Highest log index known to be stored by a majority. It is saved with the hard
+    /// state now and then, so after a restart it resumes from the last one saved, and
+    /// moves on once the leader says otherwise.
+    pub fn commit_index(&self) -> u64 {
+        self.lock().commit_index
+    }
+
+    /// The peers that vote, which are `config().peers` less any this node's log removed.
+    pub fn voters(&self) -> Vec<String> {
+        let state = self.lock();
+        self.voters_in(&state).cloned().collect()
+    }
+
+    /// Records where member `id` runs, e.g. as gossip announced it, so that as leader this
+    /// node sends to the voters nearest to it first.
+    pub fn set_topology(&self, id: &str, topology: Topology) {
+        self.topology.lock().unwrap().insert(id.to_string(), topology);
+    }
+
+    /// `peers` ordered nearest to this node first, or as they are if it knows of no
+    /// topology.
+    fn nearest_first(&self, mut peers: Vec<String>) -> Vec<String> {
+        let topology = self.topology.lock().unwrap();
+        let local = topology.get(&self.config.id).cloned().unwrap_or_default();
+        topology::nearest_first(&local, &topology, &mut peers);
+        peers
+    }
+
+    /// Votes (or acknowledgements) that make a majority of the current voters, counting
+    /// this node unless its log removed it.
+    pub fn quorum(&self) -> usize {
+        self.quorum_in(&self.lock())
+    }
+
+    /// Replication progress of every peer, by id, while this node leads; empty on any
+    /// other node.
+    pub fn peer_status(&self) -> Vec<PeerStatus> {
+        let state = self.lock();
+        if state.role != Role::Leader {
+            return Vec::new();
+        }
+        let (now, last_index) = (self.clock.now(), state.log.last_index());
+        state
+            .match_index
+            .iter()
+            .map(|(peer, &match_index)| PeerStatus {
+                id: peer.clone(),
+                match_index,
+                next_index: state.next_index.get(peer).copied().unwrap_or(last_index + 1),
+                lag: last_index.saturating_sub(match_index),
+                since_last_append: state.peer_contact.get(peer).map(|contact| now - *contact),
+                lagging: state.lagging.contains(peer),
+            })
+            .collect()
+    }
+
+    /// The log entries whose index falls in `range`, committed or not.
+    pub fn entries(&self, range: impl RangeBounds<u64>) -> std::io::Result<Vec<LogEntry>> {
+        self.lock().log.replay_range(range)
+    }
+
+    /// Follows the commit index, which only grows while the node runs. It resumes from
+    /// the persisted commit index on every start, which may be behind the last one seen,
+    /// so a consumer that must survive restarts keeps the index of the last entry it
+    /// handled and resumes from it with `entries_since`.
+    pub fn commit_watch(&self) -> watch::Receiver<u64> {
+        self.commits.subscribe()
+    }
+
+    /// The committed entries after `index`, in order. Once an entry is committed it is
+    /// never overwritten, so a consumer can take these as final and move its cursor to
+    /// the last of them.
+    pub fn entries_since(&self, index: u64) -> std::io::Result<Vec<LogEntry>> {
+        let state = self.lock();
+        if index >= state.commit_index {
+            return Ok(Vec::new());
+        }
+        state.log.replay_range(index + 1..=state.commit_index)
+    }
+
+    /// Removes the entries up to `up_to`, at most the commit index, from the log, once a
+    /// snapshot in the store covers them; a peer that still needs them is sent that
+    /// snapshot instead. The voters they changed are saved next to the log first. Only
+    /// starting the compaction and swapping in the log it built hold the node.
+    pub fn compact_log(&self, up_to: u64) -> std::io::Result<()> {
+        let (compaction, config) = {
+            let state = self.lock();
+            let up_to = up_to.min(state.commit_index);
+            let Some(compaction) = state.log.compaction(up_to)? else {
+                return Ok(());
+            };
+            (compaction, ConfigChanges::up_to(&state.removals, &state.additions, up_to))
+        };
+        config.save(&self.voters_path)?;
+        let built = compaction.build()?;
+        let mut state = self.lock();
+        state.log.install_compaction(built)?;
+        debug!(first_index = state.log.first_index(), "compacted the log");
+        Ok(())
+    }
+
+    /// Appends `command` to the leader's log and returns its index. It is replicated in
+    /// the background and committed once a majority stores it. Fails with `NotLeader`
+    /// on any other node, and on a leader that is shutting down, and with `EntryTooLarge`
+    /// if `command` is over `max_entry_bytes`.
+    pub fn propose(&self, command: Bytes) -> std::io::Result<u64> {
+        self.append_proposal(command, |index, _| index)
+    }
+
+    /// Like `propose`, returning a waiter that the apply loop resolves, through
+    /// `applied`, with the result of applying the entry. If a new leader overwrites the
+    /// entry instead, the waiter fails with `NotLeader` naming that leader.
+    pub fn propose_waiting(&self, command: Bytes) -> std::io::Result<ApplyWaiter> {
+        self.append_proposal(command, |index, term| self.waiters.register(index, term))
+    }
+
+    /// Reports the result of applying the committed `entry` to whoever proposed it
+    /// through `propose_waiting` on this node.
+    pub fn applied(&self, entry: &LogEntry, result: ApplyResult) {
+        self.waiters.resolve(entry.index, entry.term, result);
+    }
+
+    /// Appends `command` as a leader and returns what `then` makes of its index and
+    /// term, called before the entry can be replicated, let alone overwritten.
+    fn append_proposal<T>(
+        &self,
+        command: Bytes,
+        then: impl FnOnce(u64, u64) -> T,
+    ) -> std::io::Result<T> {
+        EntryTooLarge::check(command.len() as u64, self.config.max_entry_bytes)?;
+        let mut state = self.lock();
+        if state.role != Role::Leader || state.shutting_down {
+            let leader_id = state.leader_id.clone().filter(|leader| *leader != self.config.id);
+            return Err(NotLeader { leader_id }.into_io());
+        }
+
+        let (index, term) = (state.log.last_index() + 1, state.hard.current_term);
+        state.log.append(LogEntry::new(index, term, command))?;
+        let proposed = then(index, term);
+        self.advance_commit(&mut state)?;
+        drop(state);
+
+        self.appended.notify_waiters();
+        Ok(proposed)
+    }
+
+    /// Appends an entry taking `peer` out of the voters, which shrinks the quorum as soon
+    /// as it is appended, and returns its index. As a change of one voter at a time is
+    /// only safe with one in flight, it fails with `ResourceBusy` while the last change
+    /// of voters is uncommitted. It also fails unless the voters left, this node included, have a
+    /// quorum among them that acknowledged this leader recently, so a removal cannot
+    /// leave the cluster unable to commit it. Fails with `NotLeader` like `propose`.
+    ///
+    /// `peer` may be this node. It then goes on leading, without counting itself towards
+    /// a quorum, until the removal commits, and then steps down for good, as `shutdown`
+    /// leaves it, so the voters left elect a leader among themselves.
+    pub fn remove_voter(&self, peer: &str) -> std::io::Result<u64> {
+        let mut state = self.lock();
+        if state.role != Role::Leader || state.shutting_down {
+            let leader_id = state.leader_id.clone().filter(|leader| *leader != self.config.id);
+            return Err(NotLeader { leader_id }.into_io());
+        }
+        let removing_self = peer == self.config.id;
+        let voting = match removing_self {
+            true => self.own_vote(&state) == 1,
+            false => self.is_voter(&state, peer),
+        };
+        if !voting {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is not a voting peer", peer),
+            ));
+        }
+        if let Some(pending) = self.pending_change(&state) {
+            return Err(pending_change(pending));
+        }
+
+        let now = self.clock.now();
+        let remaining: Vec<&String> = self.voters_in(&state).filter(|id| *id != peer).collect();
+        let own_vote = usize::from(!removing_self);
+        let quorum = majority(remaining.len() + own_vote);
+        let acked = remaining.iter().filter(|id| self.acked_recently(&state, id, now));
+        let healthy = own_vote + acked.count();
+        if healthy < quorum {
+            return Err(std::io::Error::other(format!(
+                "removing {} would leave {} of {} voters healthy, short of a quorum of {}",
+                peer,
+                healthy,
+                remaining.len() + own_vote,
+                quorum
+            )));
+        }
+
+        let change = MembershipChange::RemoveNode {
+            node_id: peer.to_string(),
+        };
+        let command = BankCommand::ConfigChange(change).encode()?;
+        let (index, term) = (state.log.last_index() + 1, state.hard.current_term);
+        state.log.append(LogEntry::new(index, term, command))?;
+        state.removals.insert(index, peer.to_string());
+        self.emit_voters(&state);
+        state.next_index.remove(peer);
+        state.match_index.remove(peer);
+        state.peer_contact.remove(peer);
+        state.streaming.remove(peer);
+        self.advance_commit(&mut state)?;
+        drop(state);
+
+        info!(peer, index, "proposed removing a voter");
+        self.appended.notify_waiters();
+        Ok(index)
+    }
+
+    /// Appends an entry making `peer`, reached at `addr`, a voter, which grows the quorum
+    /// as soon as it is appended, and returns its index. The leader starts replicating to
+    /// it within a heartbeat. A node added this way should be started with the voters
+    /// before it as its peers, and once it has been added, so it does not campaign for a
+    /// cluster that does not count it yet. Fails with `InvalidInput` if `peer` votes
+    /// already or was removed before, and otherwise like `remove_voter`, bar the health
+    /// check: adding a voter never leaves fewer of them to make a quorum.
+    pub fn add_voter(&self, peer: &str, addr: &str) -> std::io::Result<u64> {
+        let mut state = self.lock();
+        if state.role != Role::Leader || state.shutting_down {
+            let leader_id = state.leader_id.clone().filter(|leader| *leader != self.config.id);
+            return Err(NotLeader { leader_id }.into_io());
+        }
+        let removed = state.removals.values().any(|id| id == peer);
+        if peer == self.config.id || self.is_voter(&state, peer) || removed {
+            let reason = if removed { "was removed" } else { "already votes" };
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} {}", peer, reason),
+            ));
+        }
+        if let Some(pending) = self.pending_change(&state) {
+            return Err(pending_change(pending));
+        }
+
+        let change = MembershipChange::AddNode {
+            node_id: peer.to_string(),
+            raft_addr: addr.to_string(),
+        };
+        let command = BankCommand::ConfigChange(change).encode()?;
+        let (index, term) = (state.log.last_index() + 1, state.hard.current_term);
+        state.log.append(LogEntry::new(index, term, command))?;
+        self.transport.add_peer(peer, addr);
+        state.additions.insert(index, (peer.to_string(), addr.to_string()));
+        self.emit_voters(&state);
+        state.next_index.insert(peer.to_string(), index + 1);
+        state.match_index.insert(peer.to_string(), 0);
+        self.advance_commit(&mut state)?;
+        drop(state);
+
+        info!(peer, addr, index, "proposed adding a voter");
+        self.appended.notify_waiters();
+        Ok(index)
+    }
+
+    /// Whether this node is in contact with a majority: a leader whose heartbeats a
+    /// quorum acknowledged recently, or a follower that recently heard from a leader.
+    pub fn has_quorum(&self) -> bool {
+        let state = self.lock();
+        let now = self.clock.now();
+        let recent = |contact: &Instant| now - *contact <= self.config.election_timeout_max;
+
+        match state.role {
+            Role::Leader => {
+                let acked =
+                    self.voters_in(&state).filter(|peer| self.acked_recently(&state, peer, now));
+                self.own_vote(&state) + acked.count() >= self.quorum_in(&state)
+            }
+            Role::Follower => state.last_leader_contact.as_ref().is_some_and(recent),
+            Role::Candidate => false,
+        }
+    }
+
+    /// The index a linearizable read must see: once this returns, this node's commit
+    /// index has reached every entry committed before the call. A leader confirms it
+    /// still leads with a round of heartbeats; any other node asks the leader, then waits
+    /// for its own commit index to catch up. Nothing here waits for those entries to be
+    /// applied, so a reader must still wait until its state machine reaches the returned
+    /// index. Fails with `NotLeader` when no leader is known.
+    pub async fn read_index(&self) -> std::io::Result<u64> {
+        let leader = {
+            let state = self.lock();
+            match state.role {
+                Role::Leader => None,
+                _ => Some(state.leader_id.clone().ok_or_else(|| {
+                    NotLeader { leader_id: None }.into_io()
+                })?),
+            }
+        };
+        let read_index = match leader {
+            None => self.handle_read_index(ReadIndexRequest::default()).await?.read_index,
+            Some(leader) => {
+                let request = ReadIndexRequest {
+                    follower_id: Some(self.node_id()),
+                };
+                let deadline = self.clock.now() + self.config.election_timeout_max;
+                let response = self.transport.read_index(&leader, request);
+                clock::timeout_at(self.clock.as_ref(), deadline, response)
+                    .await
+                    .ok_or_else(|| {
+                        std::io::Error::new(std::io::ErrorKind::TimedOut, "leader did not answer")
+                    })?
+                    .map_err(std::io::Error::other)?
+                    .read_index
+            }
+        };
+
+        let deadline = self.clock.now() + self.config.election_timeout_max;
+        loop {
+            // Created before checking the commit index, so a commit in between still wakes us.
+            let committed = self.committed.notified();
+            if self.commit_index() >= read_index {
+                return Ok(read_index);
+            }
+            if clock::timeout_at(self.clock.as_ref(), deadline, committed).await.is_none() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "commit index did not reach the read index",
+                ));
+            }
+        }
+    }
+
+    /// Leader side of ReadIndex: returns the commit index once a quorum has acknowledged
+    /// a heartbeat sent after reading it, proving no newer leader could have committed
+    /// past it. Fails with `NotLeader` on any other node, and until the leader has
+    /// committed an entry from its own term.
+    #[instrument(skip_all, fields(node = %self.config.id))]
+    pub async fn handle_read_index(
+        &self,
+        _request: ReadIndexRequest,
+    ) -> std::io::Result<ReadIndexResponse> {
+        let (term, read_index, requests, quorum) = {
+            let state = self.lock();
+            if state.role != Role::Leader {
+                return Err(NotLeader {
+                    leader_id: state.leader_id.clone(),
+                }
+                .into_io());
+            }
+            let term = state.hard.current_term;
+            if state.log.term_at(state.commit_index)? != Some(term) {
+                return Err(std::io::Error::other("no entry from this term is committed yet"));
+            }
+            let mut requests = Vec::new();
+            for peer in self.nearest_first(self.voters_in(&state).cloned().collect()) {
+                let mut request = self.append_request(&state, &peer, term)?;
+                request.entries.clear();
+                requests.push((peer, request));
+            }
+            (term, state.commit_index, requests, self.quorum_in(&state))
+        };
+
+        let mut heartbeats = JoinSet::new();
+        for (peer, request) in requests {
+            let transport = self.transport.clone();
+            heartbeats.spawn(async move { transport.append_entries(&peer, request).await });
+        }
+
+        let deadline = self.clock.now() + self.config.election_timeout_min;
+        let mut acks = self.own_vote(&self.lock());
+        while acks < quorum {
+            let Some(Some(heartbeat)) =
+                clock::timeout_at(self.clock.as_ref(), deadline, heartbeats.join_next()).await
+            else {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "a quorum did not confirm this node still leads",
+                ));
+            };
+            let Ok(Ok(response)) = heartbeat else {
+                continue;
+            };
+            if response.term > term {
+                let mut state = self.lock();
+                self.become_follower(&mut state, response.term, None)?;
+                return Err(NotLeader { leader_id: None }.into_io());
+            }
+            acks += 1;
+        }
+
+        debug!(read_index, "confirmed leadership for a read");
+        Ok(ReadIndexResponse { term, read_index })
+    }
+
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(
+            node = %self.config.id,
+            term = request.term,
+            peer = request.candidate_id.as_ref().map_or("", |node| node.id.as_str()),
+        ),
+    )]
+    pub fn handle_request_vote(
+        &self,
+        request: RequestVoteRequest,
+    ) -> std::io::Result<RequestVoteResponse> {
+        let mut state = self.lock();
+        if request.term > state.hard.current_term {
+            self.become_follower(&mut state, request.term, None)?;
+        }
+
+        let candidate = request.candidate_id.map(|node| node.id).unwrap_or_default();
+        let can_vote = state.hard.voted_for.as_ref().is_none_or(|voted| *voted == candidate);
+        let (last_index, last_term) = self.log_position(&state)?;
+        let vote_granted = request.term == state.hard.current_term
+            && !candidate.is_empty()
+            && can_vote
+            && (request.last_log_term, request.last_log_index) >= (last_term, last_index);
+
+        if vote_granted {
+            state.hard.voted_for = Some(candidate);
+            self.store.save(&state.hard)?;
+            state.election_deadline = self.next_election_deadline();
+        }
+        debug!(vote_granted, "answered vote request");
+
+        Ok(RequestVoteResponse {
+            term: state.hard.current_term,
+            vote_granted,
+        })
+    }
+
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(
+            node = %self.config.id,
+            term = request.term,
+            peer = request.leader_id.as_ref().map_or("", |node| node.id.as_str()),
+            index = request.prev_log_index,
+        ),
+    )]
+    pub async fn handle_append_entries(
+        &self,
+        request: AppendEntriesRequest,
+    ) -> std::io::Result<AppendEntriesResponse> {
+        let (term, leader_commit) = (request.term, request.leader_commit);
+        let (waiter, last_new) = match self.append_from_leader(request)? {
+            Appended::Answered(response) => return Ok(response),
+            Appended::Flushing { waiter, last_new } => (waiter, last_new),
+        };
+        // The leader counts what is acknowledged toward a majority, so with a background
+        // flush, wait until it is on disk: on a blocking thread, so neither the node nor
+        // the runtime is held up meanwhile.
+        tokio::task::spawn_blocking(move || waiter.wait_durable(last_new))
+            .await
+            .map_err(std::io::Error::other)??;
+        let mut state = self.lock();
+        if state.hard.current_term != term {
+            return Ok(AppendEntriesResponse {
+                term: state.hard.current_term,
+                success: false,
+            });
+        }
+        self.acknowledge_append(&mut state, leader_commit, last_new)
+    }
+
+    /// Appends the entries of `request` the log does not hold yet, and answers at once
+    /// unless they still have to reach the disk.
+    fn append_from_leader(&self, request: AppendEntriesRequest) -> std::io::Result<Appended> {
+        let mut state = self.lock();
+        if request.term < state.hard.current_term {
+            debug!(current_term = state.hard.current_term, "rejected stale append");
+            return Ok(Appended::Answered(AppendEntriesResponse {
+                term: state.hard.current_term,
+                success: false,
+            }));
+        }
+
+        let leader = request.leader_id.map(|node| node.id);
+        self.become_follower(&mut state, request.term, leader)?;
+        state.last_leader_contact = Some(self.clock.now());
+        state.election_deadline = self.next_election_deadline();
+
+        if self.config.is_witness(&self.config.id) {
+            // Leaders send a witness the end of their log instead of entries. Having
+            // acknowledged it, the witness must not vote for a candidate behind it.
+            let position = (request.prev_log_term, request.prev_log_index);
+            if position > state.hard.witnessed {
+                state.hard.witnessed = position;
+                self.store.save(&state.hard)?;
+            }
+            return Ok(Appended::Answered(AppendEntriesResponse {
+                term: state.hard.current_term,
+                success: true,
+            }));
+        }
+
+        if state.log.term_at(request.prev_log_index)? != Some(request.prev_log_term) {
+            debug!(last_index = state.log.last_index(), "rejected append that skips entries");
+            self.check_lag(&mut state, request.leader_commit);
+            return Ok(Appended::Answered(AppendEntriesResponse {
+                term: state.hard.current_term,
+                success: false,
+            }));
+        }
+
+        let changes_before = state.removals.len() + state.additions.len();
+        let mut last_new = request.prev_log_index;
+        let mut new_entries = Vec::new();
+        for entry in request.entries {
+            last_new = entry.index;
+            if entry.index <= state.log.last_index() {
+                if state.log.term_at(entry.index)? == Some(entry.term) {
+                    continue;
+                }
+                debug!(index = entry.index, "removing entries that conflict with the leader");
+                self.truncate_log(&mut state, entry.index)?;
+            }
+            // Keep the time the leader stamped it with, rather than our own.
+            let timestamp = Some(entry.timestamp).filter(|&t| t != 0);
+            let entry = LogEntry::new(entry.index, entry.term, entry.command.into());
+            new_entries.push(LogEntry { timestamp, ..entry });
+        }
+        if !new_entries.is_empty() {
+            let bytes: usize = new_entries.iter().map(|entry| entry.command.len()).sum();
+            debug!(count = new_entries.len(), bytes, "appending entries from the leader");
+        }
+        let removals: Vec<_> = new_entries
+            .iter()
+            .filter_map(|entry| removed_voter(entry).map(|peer| (entry.index, peer)))
+            .collect();
+        let additions: Vec<_> = new_entries
+            .iter()
+            .filter_map(|entry| added_voter(entry).map(|peer| (entry.index, peer)))
+            .collect();
+        // Written as one block, so a batch from the leader costs a single fsync.
+        state.log.append_batch(new_entries)?;
+        let changed_voters = !removals.is_empty()
+            || !additions.is_empty()
+            || state.removals.len() + state.additions.len() != changes_before;
+        for (_, (peer, addr)) in &additions {
+            self.transport.add_peer(peer, addr);
+        }
+        state.removals.extend(removals);
+        state.additions.extend(additions);
+        if changed_voters {
+            self.emit_voters(&state);
+        }
+        if let Some(waiter) = state.log.durable_waiter()
+            && waiter.durable_index() < last_new
+        {
+            return Ok(Appended::Flushing { waiter, last_new });
+        }
+        let response = self.acknowledge_append(&mut state, request.leader_commit, last_new)?;
+        Ok(Appended::Answered(response))
+    }
+
+    /// Commits as far as the leader has, within the entries up to `last_new` now on disk,
+    /// and acknowledges them.
+    fn acknowledge_append(
+        &self,
+        state: &mut RaftState,
+        leader_commit: u64,
+        last_new: u64,
+    ) -> std::io::Result<AppendEntriesResponse> {
+        if leader_commit > state.commit_index {
+            let commit_index = state.commit_index.max(leader_commit.min(last_new));
+            self.commit_to(state, commit_index)?;
+        }
+        self.check_lag(state, leader_commit);
+
+        Ok(AppendEntriesResponse {
+            term: state.hard.current_term,
+            success: true,
+        })
+    }
+
+    /// Receives a chunk of the leader's snapshot, on a blocking thread, as each one is
+    /// made durable, and answers with how far the snapshot has got here. Once the last
+    /// one is in and the whole snapshot matches its checksum, installs it in the snapshot
+    /// store, and starts the log over right after it. Past `max_snapshot_installs` at
+    /// once, waits for one to finish, or fails with `WouldBlock` if
+    /// `reject_excess_snapshot_installs`.
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(
+            node = %self.config.id,
+            term = request.term,
+            index = request.last_included_index,
+            offset = request.offset,
+        ),
+    )]
+    pub async fn handle_install_snapshot(
+        self: &Arc<Self>,
+        request: InstallSnapshotRequest,
+    ) -> std::io::Result<InstallSnapshotResponse> {
+        let installs = self.snapshot_installs.clone();
+        let permit = match self.config.reject_excess_snapshot_installs {
+            true => installs.try_acquire_owned().map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::WouldBlock,
+                    "too many snapshot installs in flight",
+                )
+            })?,
+            false => installs.acquire_owned().await.map_err(std::io::Error::other)?,
+        };
+        let node = self.clone();
+        // Held until the chunk is on disk, even if whoever asked stops waiting for it.
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            node.receive_snapshot(request)
+        })
+        .await
+        .map_err(std::io::Error::other)?
+    }
+
+    fn receive_snapshot(
+        &self,
+        request: InstallSnapshotRequest,
+    ) -> std::io::Result<InstallSnapshotResponse> {
+        let last = last_included(&request)?;
+        let (index, snapshot_term) = (last.index, last.term);
+        let chunk_end = request.offset + request.snapshot_chunk.len() as u64;
+        let term = {
+            let mut state = self.lock();
+            if request.term < state.hard.current_term {
+                debug!(current_term = state.hard.current_term, "rejected stale snapshot");
+                return Ok(InstallSnapshotResponse {
+                    term: state.hard.current_term,
+                    ..Default::default()
+                });
+            }
+            let leader = request.leader_id.clone().map(|node| node.id);
+            self.become_follower(&mut state, request.term, leader)?;
+            state.last_leader_contact = Some(self.clock.now());
+            state.election_deadline = self.next_election_deadline();
+
+            if self.holds(&state, index, snapshot_term)? {
+                // The log already has what the snapshot covers; it only needs committing.
+                let commit_index = state.commit_index.max(index);
+                self.commit_to(&mut state, commit_index)?;
+                return Ok(InstallSnapshotResponse {
+                    term: state.hard.current_term,
+                    next_offset: chunk_end,
+                    installed: true,
+                });
+            }
+            state.hard.current_term
+        };
+
+        let mut assembly = self.assembly.lock().unwrap();
+        if !assembly.as_ref().is_some_and(|assembly| assembly.is_for(index, snapshot_term)) {
+            *assembly = Some(SnapshotAssembly::open(&self.snapshot_dir, index, snapshot_term)?);
+        }
+        let receiving = assembly.as_mut().expect("assembly was just opened");
+        let before = receiving.offset();
+        let chunk = &request.snapshot_chunk;
+        let offset = receiving.write_chunk(request.offset, chunk, request.chunk_crc)?;
+        if offset != before {
+            self.emit(RaftEvent::SnapshotReceived { index, offset });
+        }
+        let response = InstallSnapshotResponse {
+            term,
+            next_offset: offset,
+            installed: false,
+        };
+        if !request.done || offset != chunk_end {
+            return Ok(response);
+        }
+
+        let path = match receiving.finish(request.snapshot_crc) {
+            Ok(path) => path,
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                warn!(error = %e, "received a damaged snapshot");
+                return Ok(InstallSnapshotResponse {
+                    next_offset: 0,
+                    ..response
+                });
+            }
+            Err(e) => return Err(e),
+        };
+        *assembly = None;
+        self.snapshot_store()?.install(last, &path)?;
+
+        let config = ConfigChanges::from_pb(request.config_changes);
+        config.save(&self.voters_path)?;
+        let mut state = self.lock();
+        self.waiters.truncate(0, state.leader_id.clone());
+        state.log.reset_to(last)?;
+        for (peer, addr) in config.additions.values() {
+            self.transport.add_peer(peer, addr);
+        }
+        state.removals = config.removals;
+        state.additions = config.additions;
+        self.emit_voters(&state);
+        self.commit_to(&mut state, index)?;
+        info!(index, "installed a snapshot from the leader");
+        self.emit(RaftEvent::SnapshotInstalled { index });
+        Ok(InstallSnapshotResponse {
+            installed: true,
+            ..response
+        })
+    }
+
+    /// Whether the log holds the entry at `index` from `term`, or was compacted past it.
+    fn holds(&self, state: &RaftState, index: u64, term: u64) -> std::io::Result<bool> {
+        if index <= state.log.compaction_point().index {
+            return Ok(true);
+        }
+        Ok(state.log.term_at(index)? == Some(term))
+    }
+
+    /// Starts an election at once if the leader of the current term hands over to this
+    /// node, rather than waiting out the election timeout. Ignored from any other term,
+    /// and by a node that may not lead or is shutting down.
+    #[instrument(level = "debug", skip_all, fields(node = %self.config.id, term = request.term))]
+    pub fn handle_timeout_now(
+        &self,
+        request: TimeoutNowRequest,
+    ) -> std::io::Result<TimeoutNowResponse> {
+        let state = self.lock();
+        let handed_over = request.term == state.hard.current_term
+            && state.role == Role::Follower
+            && self.config.can_lead(&self.config.id)
+            && !state.shutting_down;
+        if handed_over {
+            info!("leader handed over; campaigning now");
+            self.campaign.notify_one();
+        }
+
+        Ok(TimeoutNowResponse {
+            term: state.hard.current_term,
+        })
+    }
+
+    /// Prepares the node to stop: it takes no more proposals and stands for no more
+    /// elections, and a leader hands leadership to its most up-to-date peer, waiting up
+    /// to an election timeout for it to take over, so the cluster need not sit out an
+    /// election. Handing over is best effort; a failure is logged, not returned. Returns
+    /// once the log is on disk.
+    #[instrument(skip(self), fields(node = %self.config.id))]
+    pub async fn shutdown(&self) -> std::io::Result<()> {
+        let (leading, term) = {
+            let mut state = self.lock();
+            state.shutting_down = true;
+            (state.role == Role::Leader, state.hard.current_term)
+        };
+        if leading {
+            match self.transfer_leadership(term).await {
+                Ok(leader) => info!(%leader, "handed over leadership"),
+                Err(e) => warn!(error = %e, "failed to hand over leadership"),
+            }
+        }
+
+        self.lock().log.sync()?;
+        self.persist_commit()?;
+        info!("shut down");
+        Ok(())
+    }
+
+    /// Waits for a peer that may lead to store our whole log, has it campaign, and waits
+    /// for it to win `term + 1` or later. Returns the new leader.
+    async fn transfer_leadership(&self, term: u64) -> std::io::Result<String> {
+        let deadline = self.clock.now() + self.config.election_timeout_max;
+        let timed_out = |what: &str| std::io::Error::new(std::io::ErrorKind::TimedOut, what);
+        let target = loop {
+            let caught_up = {
+                let state = self.lock();
+                if state.role != Role::Leader || state.hard.current_term != term {
+                    return Err(std::io::Error::other("lost leadership before handing it over"));
+                }
+                let last = state.log.last_index();
+                let candidates = state
+                    .match_index
+                    .iter()
+                    .filter(|(peer, _)| self.config.can_lead(peer) && self.is_voter(&state, peer));
+                let Some((peer, matched)) = candidates.max_by_key(|(_, matched)| **matched) else {
+                    return Err(std::io::Error::other("no peer can take over leadership"));
+                };
+                (*matched == last).then(|| peer.clone())
+            };
+            if let Some(target) = caught_up {
+                break target;
+            }
+            if self.clock.now() >= deadline {
+                return Err(timed_out("no peer caught up with the log in time"));
+            }
+            self.appended.notify_waiters();
+            self.clock.sleep(self.config.heartbeat_interval).await;
+        };
+
+        let request = TimeoutNowRequest {
+            term,
+            leader_id: Some(self.node_id()),
+        };
+        let sent = clock::timeout_at(
+            self.clock.as_ref(),
+            deadline,
+            self.transport.timeout_now(&target, request),
+        )
+        .await;
+        match sent {
+            Some(Ok(_)) => {}
+            Some(Err(status)) => return Err(std::io::Error::other(status.to_string())),
+            None => return Err(timed_out("handing over timed out")),
+        }
+
+        loop {
+            {
+                let state = self.lock();
+                if state.hard.current_term > term
+                    && let Some(leader) = &state.leader_id
+                {
+                    return Ok(leader.clone());
+                }
+            }
+            if self.clock.now() >= deadline {
+                return Err(timed_out("no new leader took over in time"));
+            }
+            self.clock.sleep(self.config.heartbeat_interval).await;
+        }
+    }
+
+    /// Leader side of StreamEntries: streams the entries from `request.from_index` to the
+    /// end of the log as it stands now, in batches of one AppendEntries each. Fails with
+    /// `NotLeader` unless this node leads `request.term`, and ends early if it stops.
+    /// The follower's replicator holds back entries until the stream ends.
+    pub fn stream_entries(
+        self: &Arc<Self>,
+        request: StreamEntriesRequest,
+    ) -> std::io::Result<EntryStream> {
+        let term = request.term;
+        let follower = request.follower_id.map(|node| node.id).unwrap_or_default();
+        let last = {
+            let mut state = self.lock();
+            if state.role != Role::Leader || state.hard.current_term != term {
+                return Err(NotLeader {
+                    leader_id: state.leader_id.clone(),
+                }
+                .into_io());
+            }
+            state.streaming.insert(follower.clone());
+            state.log.last_index()
+        };
+        debug!(%follower, from_index = request.from_index, last, "streaming entries");
+
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        let node = self.clone();
+        tokio::spawn(async move {
+            let mut next = request.from_index.max(1);
+            while next <= last {
+                let batch = {
+                    let state = node.lock();
+                    if state.role != Role::Leader || state.hard.current_term != term {
+                        break;
+                    }
+                    node.append_request_from(&state, next, term)
+                };
+                let batch = batch.map_err(|e| Status::internal(e.to_string()));
+                let sent = batch.as_ref().map_or(0, |batch| batch.entries.len() as u64);
+                if tx.send(batch).await.is_err() || sent == 0 {
+                    break;
+                }
+                next += sent;
+            }
+
+            // Resume replicating where the stream left off. If the follower did not
+            // store it all, its next rejection backs the replicator up as usual.
+            let mut state = node.lock();
+            state.streaming.remove(&follower);
+            if let Some(peer_next) = state.next_index.get_mut(&follower) {
+                *peer_next = (*peer_next).max(next);
+            }
+            drop(state);
+            node.appended.notify_waiters();
+        });
+        Ok(Box::pin(ReceiverStream::new(rx)))
+    }
+
+    async fn run(self: Arc<Self>) {
+        // Dropped with the loop, which stops heartbeating when the node is stopped.
+        let mut replicators = JoinSet::new();
+        let mut catch_up = JoinSet::new();
+        catch_up.spawn(self.clone().catch_up());
+        catch_up.spawn(self.clone().persist_commits());
+        let waiter = self.lock().log.durable_waiter();
+        if let Some(waiter) = waiter {
+            catch_up.spawn(self.clone().commit_flushed(waiter));
+        }
+        if !self.config.can_lead(&self.config.id) {
+            // A witness, having no log, or a read replica never stands for election, so
+            // it only answers RPCs.
+            return std::future::pending().await;
+        }
+
+        // Leader only: the term it leads and the peers it runs a replicator for.
+        let mut replicating = (0, BTreeSet::new());
+        loop {
+            let (role, term, deadline, shutting_down) = {
+                let state = self.lock();
+                let term = state.hard.current_term;
+                (state.role, term, state.election_deadline, state.shutting_down)
+            };
+            if role == Role::Leader {
+                // Voters added while leading, or a lead bootstrapped rather than won, get
+                // a replicator of their own here.
+                if replicating.0 != term {
+                    replicators.abort_all();
+                    replicating = (term, BTreeSet::new());
+                }
+                let voters = self.nearest_first(self.voters_in(&self.lock()).cloned().collect());
+                for peer in voters {
+                    if replicating.1.insert(peer.clone()) {
+                        replicators.spawn(self.clone().replicate_to(peer, term));
+                    }
+                }
+                self.clock.sleep(self.config.heartbeat_interval).await;
+                continue;
+            }
+            if shutting_down {
+                return std::future::pending().await;
+            }
+
+            // Heartbeats from a leader handing over may have pushed the deadline back.
+            let handed_over = tokio::select! {
+                _ = self.clock.sleep_until(deadline) => false,
+                _ = self.campaign.notified() => true,
+            };
+            // A node its log removed from the voters would only disrupt those left.
+            let election_due = {
+                let state = self.lock();
+                state.role != Role::Leader
+                    && !state.shutting_down
+                    && self.own_vote(&state) == 1
+                    && (handed_over || state.election_deadline <= self.clock.now())
+            };
+            if !election_due {
+                continue;
+            }
+
+            let elected = self.run_election().await.expect("failed to persist raft hard state");
+            if let Some(term) = elected {
+                replicators.abort_all();
+                let voters = self.nearest_first(self.voters_in(&self.lock()).cloned().collect());
+                for peer in &voters {
+                    replicators.spawn(self.clone().replicate_to(peer.clone(), term));
+                }
+                replicating = (term, voters.into_iter().collect());
+            }
+        }
+    }
+
+    /// Campaigns for the next term, returning it if this node won.
+    #[instrument(skip(self), fields(node = %self.config.id, term = tracing::field::Empty))]
+    async fn run_election(&self) -> std::io::Result<Option<u64>> {
+        let (request, deadline, voters) = {
+            let mut state = self.lock();
+            state.role = Role::Candidate;
+            state.hard.current_term += 1;
+            state.hard.voted_for = Some(self.config.id.clone());
+            state.leader_id = None;
+            state.election_deadline = self.next_election_deadline();
+            self.store.save(&state.hard)?;
+            self.elections_started.fetch_add(1, Ordering::Relaxed);
+            let term = state.hard.current_term;
+            self.emit(RaftEvent::TermChanged { term });
+            self.emit(RaftEvent::BecameCandidate { term });
+
+            let (last_log_index, last_log_term) = last_log_position(&state.log)?;
+            let request = RequestVoteRequest {
+                term: state.hard.current_term,
+                candidate_id: Some(self.node_id()),
+                last_log_index,
+                last_log_term,
+            };
+            let voters: Vec<String> = self.voters_in(&state).cloned().collect();
+            (request, state.election_deadline, voters)
+        };
+        let term = request.term;
+        Span::current().record("term", term);
+        info!("starting election");
+        self.check_churn();
+
+        // Every peer is asked at once; those yet to answer once a majority has are dropped.
+        let quorum = majority(voters.len() + 1);
+        let vote_deadline = self.clock.now() + self.config.vote_timeout;
+        let mut ballots = JoinSet::new();
+        for peer in voters {
+            let (transport, request) = (self.transport.clone(), request.clone());
+            let clock = self.clock.clone();
+            ballots.spawn(async move {
+                let ballot = transport.request_vote(&peer, request);
+                clock::timeout_at(clock.as_ref(), vote_deadline, ballot).await
+            });
+        }
+
+        let mut votes = 1;
+        let mut won = votes >= quorum;
+        // An election that has not won by the next deadline gives way to a new one.
+        while !won {
+            let Some(Some(ballot)) =
+                clock::timeout_at(self.clock.as_ref(), deadline, ballots.join_next()).await
+            else {
+                break;
+            };
+            let Ok(Some(Ok(response))) = ballot else {
+                continue;
+            };
+
+            let mut state = self.lock();
+            if response.term > state.hard.current_term {
+                info!(newer_term = response.term, "abandoned election for a newer term");
+                self.become_follower(&mut state, response.term, None)?;
+                return Ok(None);
+            }
+            if state.role != Role::Candidate || state.hard.current_term != term {
+                return Ok(None);
+            }
+            if response.vote_granted {
+                votes += 1;
+                won = votes >= quorum;
+            }
+        }
+
+        let mut state = self.lock();
+        if !won || state.role != Role::Candidate || state.hard.current_term != term {
+            debug!(votes, "election did not win");
+            return Ok(None);
+        }
+        info!(votes, "won election");
+        self.become_leader(&mut state, term)?;
+        Ok(Some(term))
+    }
+
+    /// Takes the lead for `term`, which this node has just won or bootstrapped.
+    fn become_leader(&self, state: &mut RaftState, term: u64) -> std::io::Result<()> {
+        state.role = Role::Leader;
+        self.emit(RaftEvent::BecameLeader { term });
+        state.leader_id = Some(self.config.id.clone());
+        self.note_leader(state);
+        state.peer_contact.clear();
+        let next = state.log.last_index() + 1;
+        let voters: Vec<String> = self.voters_in(state).cloned().collect();
+        state.next_index = voters.iter().map(|peer| (peer.clone(), next)).collect();
+        state.match_index = voters.into_iter().map(|peer| (peer, 0)).collect();
+        state.streaming.clear();
+        state.lagging.clear();
+        self.advance_commit(state)
+    }
+
+    /// Sends `peer` the entries it is missing, or heartbeats once it has them all, for as
+    /// long as this node leads `term`. A failed AppendEntries is retried after a backoff,
+    /// up to `append_retries` times, and then the peer is marked lagging until it answers.
+    #[instrument(skip(self, peer), fields(node = %self.config.id, peer = %peer))]
+    async fn replicate_to(self: Arc<Self>, peer: String, term: u64) {
+        // AppendEntries to the peer that failed or timed out in a row.
+        let mut failures = 0;
+        loop {
+            // Created before checking for new entries, so an append in between still wakes us.
+            let appended = self.appended.notified();
+            let request = {
+                let state = self.lock();
+                if state.role != Role::Leader
+                    || state.hard.current_term != term
+                    || !self.is_voter(&state, &peer)
+                {
+                    return;
+                }
+                match self.needs_snapshot(&state, &peer) {
+                    true => None,
+                    false => Some(self.append_request(&state, &peer, term)),
+                }
+            };
+            let Some(request) = request else {
+                if let Err(e) = self.send_snapshot(&peer, term).await {
+                    warn!(error = %e, "failed to send a snapshot to peer");
+                    self.clock.sleep(self.config.heartbeat_interval).await;
+                }
+                continue;
+            };
+            let request = match request {
+                Ok(request) => request,
+                Err(e) => {
+                    warn!(error = %e, "failed to read entries for peer");
+                    self.clock.sleep(self.config.heartbeat_interval).await;
+                    continue;
+                }
+            };
+            let (prev_log_index, sent) = (request.prev_log_index, request.entries.len() as u64);
+
+            let deadline = self.clock.now() + self.config.append_timeout;
+            let response = clock::timeout_at(
+                self.clock.as_ref(),
+                deadline,
+                self.transport.append_entries(&peer, request),
+            )
+            .await;
+            let behind = match response {
+                Some(Ok(response)) => {
+                    failures = 0;
+                    let mut state = self.lock();
+                    if response.term > state.hard.current_term {
+                        info!(newer_term = response.term, "stepping down for a newer term");
+                        self.become_follower(&mut state, response.term, None)
+                            .expect("failed to persist raft hard state");
+                        return;
+                    }
+                    if state.role != Role::Leader
+                        || state.hard.current_term != term
+                        || !self.is_voter(&state, &peer)
+                    {
+                        return;
+                    }
+                    if state.lagging.remove(&peer) {
+                        info!("peer answered a probe; resuming replication");
+                    }
+
+                    let next = if response.success {
+                        state.peer_contact.insert(peer.clone(), self.clock.now());
+                        let matched = prev_log_index + sent;
+                        state.match_index.insert(peer.clone(), matched);
+                        self.advance_commit(&mut state).expect("failed to read raft log");
+                        matched + 1
+                    } else {
+                        // The peer lacks the entry before the ones sent; back up one.
+                        prev_log_index.max(1)
+                    };
+                    state.next_index.insert(peer.clone(), next);
+                    !state.streaming.contains(&peer)
+                        && (next <= state.log.last_index() || !response.success)
+                }
+                _ => {
+                    failures += 1;
+                    debug!(failures, "append failed");
+                    if failures <= self.config.append_retries {
+                        self.clock.sleep(self.config.append_retry_delay(failures)).await;
+                        continue;
+                    }
+                    let mut state = self.lock();
+                    if state.role == Role::Leader && state.lagging.insert(peer.clone()) {
+                        warn!(failures, "peer is lagging; probing it before sending more");
+                    }
+                    false
+                }
+            };
+
+            if !behind {
+                tokio::select! {
+                    _ = self.clock.sleep(self.config.heartbeat_interval) => {}
+                    _ = appended => {}
+                }
+            }
+        }
+    }
+
+    /// Sends `peer` the latest snapshot in the store, as it needs entries compacted out
+    /// of the log, picking up from however much of it the peer already has. Once the peer
+    /// installs it, replication carries on from the entry after it. Fails once a chunk
+    /// fails `append_retries` times in a row, to be tried again later.
+    async fn send_snapshot(&self, peer: &str, term: u64) -> std::io::Result<()> {
+        let store = self.snapshot_store()?;
+        let latest = tokio::task::spawn_blocking(move || store.latest())
+            .await
+            .map_err(std::io::Error::other)??;
+        let (index, data) =
+            latest.ok_or_else(|| std::io::Error::other("no snapshot has been saved"))?;
+        let snapshot = {
+            let state = self.lock();
+            let last = match state.log.compaction_point() {
+                compacted if compacted.index == index => compacted,
+                _ => {
+                    let entry = state.log.read_at(index)?.ok_or_else(|| {
+                        std::io::Error::other(format!("snapshot at {} is past the log", index))
+                    })?;
+                    CompactionPoint {
+                        index,
+                        term: entry.term,
+                        chain_hash: entry.chain_hash,
+                    }
+                }
+            };
+            let config = ConfigChanges::up_to(&state.removals, &state.additions, index);
+            OutgoingSnapshot::new(last, data, config)
+        };
+        info!(index, bytes = snapshot.len(), "sending a snapshot to peer");
+
+        // An empty chunk first, which only asks how much of it the peer already has.
+        let (mut offset, mut chunk_bytes) = (0, 0);
+        let mut failures = 0;
+        loop {
+            let request = snapshot.request(term, self.node_id(), offset, chunk_bytes);
+            let deadline = self.clock.now() + self.config.append_timeout;
+            let response = clock::timeout_at(
+                self.clock.as_ref(),
+                deadline,
+                self.transport.install_snapshot(peer, request),
+            )
+            .await;
+            let Some(Ok(response)) = response else {
+                failures += 1;
+                if failures > self.config.append_retries {
+                    return Err(std::io::Error::other(format!(
+                        "peer stopped taking the snapshot at offset {}",
+                        offset
+                    )));
+                }
+                debug!(failures, offset, "snapshot chunk failed");
+                self.clock.sleep(self.config.append_retry_delay(failures)).await;
+                continue;
+            };
+            failures = 0;
+
+            let mut state = self.lock();
+            if response.term > state.hard.current_term {
+                info!(newer_term = response.term, "stepping down for a newer term");
+                return self.become_follower(&mut state, response.term, None);
+            }
+            if state.role != Role::Leader
+                || state.hard.current_term != term
+                || !self.is_voter(&state, peer)
+            {
+                return Ok(());
+            }
+            state.peer_contact.insert(peer.to_string(), self.clock.now());
+            if response.installed {
+                info!(index, "peer installed the snapshot");
+                let matched = state.match_index.get(peer).copied().unwrap_or(0).max(index);
+                state.match_index.insert(peer.to_string(), matched);
+                state.next_index.insert(peer.to_string(), matched + 1);
+                return self.advance_commit(&mut state);
+            }
+            offset = response.next_offset.min(snapshot.len());
+            chunk_bytes = self.config.snapshot_chunk_bytes.max(1);
+        }
+    }
+
+    /// Whether `peer` needs entries compacted out of the log, which only a snapshot has.
+    fn needs_snapshot(&self, state: &RaftState, peer: &str) -> bool {
+        let compacted = state.log.compaction_point().index;
+        !self.config.is_witness(peer)
+            && state.next_index.get(peer).is_some_and(|&next| next <= compacted)
+    }
+
+    /// The AppendEntries carrying the entries `peer` is missing, up to a batch, or none
+    /// for a peer that is streaming or lagging.
+    fn append_request(
+        &self,
+        state: &RaftState,
+        peer: &str,
+        term: u64,
+    ) -> std::io::Result<AppendEntriesRequest> {
+        let next = match self.config.is_witness(peer) {
+            // A witness stores no entries, only how far our log reaches.
+            true => state.log.last_index() + 1,
+            false => state.next_index.get(peer).copied().unwrap_or(state.log.last_index() + 1),
+        };
+        let mut request = self.append_request_from(state, next, term)?;
+        if state.streaming.contains(peer) || state.lagging.contains(peer) {
+            request.entries.clear();
+        }
+        Ok(request)
+    }
+
+    /// The AppendEntries carrying up to a batch of entries from `next`, as many as
+    /// `max_entries_per_append` and `max_bytes_per_append` allow.
+    fn append_request_from(
+        &self,
+        state: &RaftState,
+        next: u64,
+        term: u64,
+    ) -> std::io::Result<AppendEntriesRequest> {
+        let prev_log_index = next - 1;
+        let prev_log_term = state.log.term_at(prev_log_index)?.unwrap_or_default();
+        let max_entries = self.config.max_entries_per_append.max(1);
+        let mut bytes = 0;
+        let entries = state
+            .log
+            .replay_range(next..next + max_entries)?
+            .into_iter()
+            .enumerate()
+            .take_while(|(i, entry)| {
+                bytes += entry.command.len() as u64;
+                *i == 0 || bytes <= self.config.max_bytes_per_append
+            })
+            .map(|(_, entry)| pb::LogEntry {
+                index: entry.index,
+                term: entry.term,
+                command: entry.command.to_vec(),
+                timestamp: entry.timestamp.unwrap_or_default(),
+            })
+            .collect();
+
+        Ok(AppendEntriesRequest {
+            term,
+            leader_id: Some(self.node_id()),
+            prev_log_index,
+            prev_log_term,
+            entries,
+            leader_commit: state.commit_index,
+        })
+    }
+
+    /// Saves the commit index every `commit_persist_interval`, on a blocking thread, so
+    /// that commits are not held up by an fsync each.
+    async fn persist_commits(self: Arc<Self>) {
+        loop {
+            self.clock.sleep(self.config.commit_persist_interval).await;
+            let node = self.clone();
+            match tokio::task::spawn_blocking(move || node.persist_commit()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => warn!(error = %e, "cannot save the commit index"),
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// Saves the commit index with the hard state, if it moved since it was last saved.
+    /// The fsync happens outside the node's lock, so no RPC waits on it. Should a term
+    /// or vote be saved meanwhile, this save is stale and is dropped; the next retries.
+    fn persist_commit(&self) -> std::io::Result<()> {
+        let (hard, ticket) = {
+            let state = self.lock();
+            if state.commit_index <= state.hard.commit_index {
+                return Ok(());
+            }
+            let hard = HardState { commit_index: state.commit_index, ..state.hard.clone() };
+            (hard, self.store.ticket())
+        };
+        if self.store.save_unless_overtaken(&hard, ticket)? {
+            let mut state = self.lock();
+            state.hard.commit_index = state.hard.commit_index.max(hard.commit_index);
+        }
+        Ok(())
+    }
+
+    /// With `wal_flush_interval` set, commits what each flush makes durable: a leader
+    /// counts only the entries it has on disk toward a majority, so a flush may complete
+    /// one that its peers already store.
+    async fn commit_flushed(self: Arc<Self>, waiter: DurableWaiter) {
+        loop {
+            // Created before reading the log, so an append in between still wakes us.
+            let appended = self.appended.notified();
+            let last_index = self.last_log_index();
+            if waiter.durable_index() >= last_index {
+                appended.await;
+                continue;
+            }
+            let flushed = tokio::task::spawn_blocking({
+                let waiter = waiter.clone();
+                move || waiter.wait_durable(last_index)
+            })
+            .await;
+            match flushed {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    warn!(error = %e, "log flush failed; no longer committing");
+                    return;
+                }
+                Err(_) => return,
+            }
+            let mut state = self.lock();
+            if state.role == Role::Leader
+                && let Err(e) = self.advance_commit(&mut state)
+            {
+                warn!(error = %e, "cannot commit flushed entries");
+            }
+        }
+    }
+
+    /// Follower side of StreamEntries: each time an append shows this node far behind,
+    /// streams the missing entries from the leader and applies them as AppendEntries.
+    async fn catch_up(self: Arc<Self>) {
+        loop {
+            self.behind.notified().await;
+            if let Err(e) = self.stream_from_leader().await {
+                debug!(error = %e, "catch-up stream failed");
+            }
+            self.lock().catching_up = false;
+        }
+    }
+
+    #[instrument(skip(self), fields(node = %self.config.id))]
+    async fn stream_from_leader(&self) -> std::io::Result<()> {
+        let (leader, request) = {
+            let state = self.lock();
+            let Some(leader) = state.leader_id.clone() else {
+                return Ok(());
+            };
+            let request = StreamEntriesRequest {
+                term: state.hard.current_term,
+                follower_id: Some(self.node_id()),
+                from_index: state.log.last_index() + 1,
+            };
+            (leader, request)
+        };
+        info!(%leader, from_index = request.from_index, "catching up from the leader by stream");
+
+        let timed_out = || std::io::Error::new(std::io::ErrorKind::TimedOut, "leader stopped");
+        let deadline = self.clock.now() + self.config.election_timeout_min;
+        let stream = self.transport.stream_entries(&leader, request);
+        let mut stream = clock::timeout_at(self.clock.as_ref(), deadline, stream)
+            .await
+            .ok_or_else(timed_out)?
+            .map_err(std::io::Error::other)?;
+        loop {
+            let deadline = self.clock.now() + self.config.election_timeout_min;
+            let Some(message) = clock::timeout_at(self.clock.as_ref(), deadline, stream.next())
+                .await
+                .ok_or_else(timed_out)?
+            else {
+                break;
+            };
+            let message = message.map_err(std::io::Error::other)?;
+            let response = self.handle_append_entries(message).await?;
+            if !response.success {
+                return Err(std::io::Error::other("streamed entries did not follow the log"));
+            }
+        }
+        debug!(last_index = self.last_log_index(), "caught up from the leader");
+        Ok(())
+    }
+
+    /// (index, term) of the end of this node's log, which candidates must be at least as
+    /// up to date as to get its vote. For a witness, the end of the last leader's log.
+    fn log_position(&self, state: &RaftState) -> std::io::Result<(u64, u64)> {
+        if self.config.is_witness(&self.config.id) {
+            let (term, index) = state.hard.witnessed;
+            return Ok((index, term));
+        }
+        last_log_position(&state.log)
+    }
+
+    /// Removes the entries from `from` on, which conflict with the leader's. Committed
+    /// entries are never overwritten, so this fails with `InvalidData`, removing nothing,
+    /// if `from` is at or below the commit index.
+    fn truncate_log(&self, state: &mut RaftState, from: u64) -> std::io::Result<()> {
+        if from <= state.commit_index {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "refusing to overwrite entry {} at or below the commit index {}",
+                    from, state.commit_index
+                ),
+            ));
+        }
+        state.log.truncate_suffix(from)?;
+        state.removals.split_off(&from);
+        state.additions.split_off(&from);
+        self.waiters.truncate(from, state.leader_id.clone());
+        Ok(())
+    }
+
+    /// Wakes the catch-up task if the leader has committed more than a batch past the end
+    /// of our log, so the missing entries come by StreamEntries instead of waiting for
+    /// them an AppendEntries batch at a time.
+    fn check_lag(&self, state: &mut RaftState, leader_commit: u64) {
+        let gap = self.config.max_entries_per_append;
+        if !state.catching_up && leader_commit > state.log.last_index() + gap {
+            state.catching_up = true;
+            self.behind.notify_one();
+        }
+    }
+
+    /// Commits up to the highest index a majority stores, if it is from the current term.
+    /// Earlier entries are committed along with it, never by counting replicas alone.
+    fn advance_commit(&self, state: &mut RaftState) -> std::io::Result<()> {
+        let mut stored: Vec<u64> = self
+            .voters_in(state)
+            .map(|peer| state.match_index.get(peer).copied().unwrap_or(0))
+            .collect();
+        if self.own_vote(state) == 1 {
+            stored.push(state.log.durable_index());
+        }
+        stored.sort_unstable_by(|a, b| b.cmp(a));
+
+        let majority = stored[self.quorum_in(state) - 1];
+        if majority > state.commit_index
+            && state.log.term_at(majority)? == Some(state.hard.current_term)
+        {
+            debug!(commit_index = majority, "advanced commit index");
+            self.commit_to(state, majority)?;
+            self.leave_if_removed(state)?;
+        }
+        Ok(())
+    }
+
+    /// Leader only: steps down for good once the entry removing this node has committed,
+    /// taking no more proposals and standing for no more elections.
+    fn leave_if_removed(&self, state: &mut RaftState) -> std::io::Result<()> {
+        let removal = state.removals.iter().find(|(_, id)| **id == self.config.id);
+        let Some(index) = removal.map(|(&index, _)| index).filter(|&i| i <= state.commit_index)
+        else {
+            return Ok(());
+        };
+        info!(index, "removal of this node committed; stepping down");
+        state.shutting_down = true;
+        let term = state.hard.current_term;
+        self.become_follower(state, term, None)?;
+        self.emit(RaftEvent::Removed { index });
+        Ok(())
+    }
+
+    /// Advances the commit index to `commit_index`, as far as the log is durable here.
+    /// `persist_commits` saves it with the hard state later.
+    fn commit_to(&self, state: &mut RaftState, commit_index: u64) -> std::io::Result<()> {
+        let commit_index = commit_index.min(state.log.durable_index());
+        if commit_index <= state.commit_index {
+            return Ok(());
+        }
+        state.commit_index = commit_index;
+        self.committed.notify_waiters();
+        self.commits.send_replace(commit_index);
+        self.emit(RaftEvent::CommitAdvanced { commit_index });
+        Ok(())
+    }
+
+    fn become_follower(
+        &self,
+        state: &mut RaftState,
+        term: u64,
+        leader_id: Option<String>,
+    ) -> std::io::Result<()> {
+        let led_term = state.hard.current_term;
+        if term > state.hard.current_term {
+            state.hard.current_term = term;
+            state.hard.voted_for = None;
+            self.store.save(&state.hard)?;
+            self.emit(RaftEvent::TermChanged { term });
+        }
+        if state.role != Role::Follower {
+            let term = state.hard.current_term;
+            self.emit(RaftEvent::BecameFollower { term });
+        }
+
+        let stepping_down = state.role == Role::Leader;
+        if stepping_down {
+            // The deadline lapsed while we led; give the new leader a whole timeout to
+            // reach us before standing again.
+            state.election_deadline = self.next_election_deadline();
+        }
+        state.role = Role::Follower;
+        state.leader_id = leader_id;
+        self.note_leader(state);
+        state.peer_contact.clear();
+        if stepping_down && self.config.trim_uncommitted_on_step_down {
+            // Only the entries this node appended as leader: those it inherited may have
+            // been committed on a majority by an earlier leader, unbeknown to it.
+            let mut from = state.log.last_index() + 1;
+            while from > state.commit_index + 1 && state.log.term_at(from - 1)? == Some(led_term) {
+                from -= 1;
+            }
+            if from <= state.log.last_index() {
+                let to = state.log.last_index();
+                info!(from, to, "trimming uncommitted entries on step-down");
+                self.truncate_log(state, from)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Tells subscribers of `events` about `event`, if there are any.
+    fn emit(&self, event: RaftEvent) {
+        let _ = self.events.send(event);
+    }
+
+    /// Tells subscribers of `events` the voters are now those of `state`.
+    fn emit_voters(&self, state: &RaftState) {
+        let voters = self.voters_in(state).cloned().collect();
+        self.emit(RaftEvent::ConfigChanged { voters });
+    }
+
+    /// Counts a leader change if `state` knows of a leader for a term it had none for yet.
+    fn note_leader(&self, state: &mut RaftState) {
+        if state.leader_id.is_some() && state.hard.current_term > state.leader_term {
+            state.leader_term = state.hard.current_term;
+            self.leader_changes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Tells the churn hook, if there is one, that an election just started, and calls it
+    /// if that takes elections past its threshold.
+    fn check_churn(&self) {
+        let mut churn = self.churn.lock().unwrap();
+        let Some(detector) = churn.as_mut() else {
+            return;
+        };
+        if let Some(alert) = detector.election_started(self.clock.now()) {
+            detector.fire(alert);
+        }
+    }
+
+    fn is_voter(&self, state: &RaftState, peer: &str) -> bool {
+        let member = self.config.peers.contains_key(peer)
+            || state.additions.values().any(|(id, _)| id == peer);
+        member && !state.removals.values().any(|id| id == peer)
+    }
+
+    fn voters_in<'a>(&'a self, state: &'a RaftState) -> impl Iterator<Item = &'a String> {
+        let added = state.additions.values().map(|(id, _)| id);
+        self.config.peers.keys().chain(added).filter(move |peer| self.is_voter(state, peer))
+    }
+
+    /// The index of the last voter added or removed, if it is not committed yet.
+    fn pending_change(&self, state: &RaftState) -> Option<u64> {
+        let removal = state.removals.last_key_value().map(|(&index, _)| index);
+        let addition = state.additions.last_key_value().map(|(&index, _)| index);
+        removal.max(addition).filter(|&index| index > state.commit_index)
+    }
+
+    fn quorum_in(&self, state: &RaftState) -> usize {
+        majority(self.voters_in(state).count() + self.own_vote(state))
+    }
+
+    /// 1 while this node votes, or 0 once an entry in its log removed it.
+    fn own_vote(&self, state: &RaftState) -> usize {
+        usize::from(!state.removals.values().any(|id| *id == self.config.id))
+    }
+
+    /// Leader only: whether `peer` acknowledged an AppendEntries within an election
+    /// timeout of `now`.
+    fn acked_recently(&self, state: &RaftState, peer: &str, now: Instant) -> bool {
+        let recent = |contact: &Instant| now - *contact <= self.config.election_timeout_max;
+        state.peer_contact.get(peer).is_some_and(recent)
+    }
+
+    fn next_election_deadline(&self) -> Instant {
+        let timeout = rand::random_range(
+            self.config.election_timeout_min..=self.config.election_timeout_max,
+        );
+        self.clock.now() + timeout + self.config.election_delay()
+    }
+
+    fn node_id(&self) -> NodeId {
+        NodeId {
+            id: self.config.id.clone(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, RaftState> {
+        self.state.lock().unwrap()
+    }
+}
+
+/// Votes that make a majority of `voters`, this node among them.
+fn majority(voters: usize) -> usize {
+    voters / 2 + 1
+}
+
+/// The voter, and its Raft address, that `entry` adds, if it is an `AddNode`.
+fn added_voter(entry: &LogEntry) -> Option<(String, String)> {
+    match BankCommand::decode(entry.command_slice()) {
+        Ok(BankCommand::ConfigChange(MembershipChange::AddNode { node_id, raft_addr })) => {
+            Some((node_id, raft_addr))
+        }
+        _ => None,
+    }
+}
+
+/// Refuses a change of voters while the one at `index` is not committed.
+fn pending_change(index: u64) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::ResourceBusy,
+        format!("the change of voters at index {} is not committed yet", index),
+    )
+}
+
+/// The peer `entry` takes out of the voters, if it is a `RemoveNode` config change.
+fn removed_voter(entry: &LogEntry) -> Option<String> {
+    match BankCommand::decode(entry.command_slice()) {
+        Ok(BankCommand::ConfigChange(MembershipChange::RemoveNode { node_id })) => Some(node_id),
+        _ => None,
+    }
+}
+
+/// (index, term) of the last entry in `log`.
+fn last_log_position(log: &Wal) -> std::io::Result<(u64, u64)> {
+    let last = log.last_index();
+    Ok((last, log.term_at(last)?.unwrap_or_default()))
+}
+
+impl fmt::Debug for RaftNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.lock();
+        f.debug_struct("RaftNode")
+            .field("id", &self.config.id)
+            .field("role", &state.role)
+            .field("current_term", &state.hard.current_term)
+            .field("leader_id", &state.leader_id)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tempfile::TempDir;
+    use tracing_test::traced_test;
+    use crate::bank::CommandOutcome;
+    use crate::clock::ManualClock;
+    use crate::raft::config::{DEFAULT_ELECTION_TIMEOUT_MAX, DEFAULT_ELECTION_TIMEOUT_MIN};
+    use crate::raft::SimNetwork;
+
+    /// Nodes `node-1`..`node-N` wired over a `SimNetwork`, stopped on drop.
+    pub(crate) struct TestCluster {
+        pub(crate) network: Arc<SimNetwork>,
+        pub(crate) nodes: Vec<Arc<RaftNode>>,
+        tasks: Vec<JoinHandle<()>>,
+        dir: TempDir,
+    }
+
+    impl TestCluster {
+        pub(crate) fn start(size: usize) -> Self {
+            Self::start_with_witnesses(size, &[])
+        }
+
+        /// Like `start`, with the nodes in `witnesses` storing no log.
+        pub(crate) fn start_with_witnesses(size: usize, witnesses: &[&str]) -> Self {
+            Self::start_with(size, |config| {
+                config.witnesses = witnesses.iter().map(|id| id.to_string()).collect();
+            })
+        }
+
+        /// Like `start`, with the nodes in `read_replicas` never standing for election.
+        pub(crate) fn start_with_read_replicas(size: usize, read_replicas: &[&str]) -> Self {
+            Self::start_with(size, |config| {
+                config.read_replicas = read_replicas.iter().map(|id| id.to_string()).collect();
+            })
+        }
+
+        /// Like `start`, adjusting each node's config with `configure`.
+        pub(crate) fn start_with(size: usize, configure: impl Fn(&mut RaftConfig)) -> Self {
+            let dir = TempDir::new().unwrap();
+            let network = SimNetwork::new(size as u64);
+            let ids: Vec<String> = (1..=size).map(|i| format!("node-{}", i)).collect();
+
+            let nodes: Vec<Arc<RaftNode>> = ids
+                .iter()
+                .map(|id| {
+                    let peers = ids
+                        .iter()
+                        .filter(|peer| *peer != id)
+                        .map(|peer| (peer.clone(), String::new()))
+                        .collect();
+                    let mut config = RaftConfig::new(id, peers);
+                    configure(&mut config);
+                    let state_path = dir.path().join(format!("{}.state", id));
+                    let log_path = dir.path().join(format!("{}.wal", id));
+                    let node =
+                        RaftNode::new(config, state_path, log_path, network.transport(id)).unwrap();
+                    network.register(&node);
+                    node
+                })
+                .collect();
+            let tasks = nodes.iter().map(RaftNode::start).collect();
+
+            Self {
+                network,
+                nodes,
+                tasks,
+                dir,
+            }
+        }
+
+        pub(crate) fn node(&self, id: &str) -> Arc<RaftNode> {
+            self.nodes.iter().find(|node| node.id() == id).unwrap().clone()
+        }
+
+        /// Waits until `nodes` all store the same log and have committed all of it.
+        pub(crate) async fn wait_for_convergence(&self, nodes: &[Arc<RaftNode>]) {
+            wait_until(|| {
+                let logs: Vec<_> = nodes.iter().map(|node| log_of(node)).collect();
+                logs.windows(2).all(|pair| pair[0] == pair[1])
+                    && nodes.iter().all(|node| node.commit_index() == node.last_log_index())
+            })
+            .await;
+        }
+
+        /// Waits until some node leads the highest term seen, and returns it.
+        pub(crate) async fn wait_for_leader(&self) -> Arc<RaftNode> {
+            for _ in 0..100 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                let term = self.nodes.iter().map(|node| node.current_term()).max().unwrap();
+                let leader = self
+                    .nodes
+                    .iter()
+                    .find(|node| node.role() == Role::Leader && node.current_term() == term);
+                if let Some(leader) = leader {
+                    return leader.clone();
+                }
+            }
+            panic!("no leader was elected");
+        }
+    }
+
+    impl Drop for TestCluster {
+        fn drop(&mut self) {
+            self.tasks.iter().for_each(JoinHandle::abort);
+        }
+    }
+
+    /// Snapshots kept in memory: the one a test says was saved, and the one installed.
+    #[derive(Default)]
+    struct MemorySnapshots {
+        saved: Mutex<Option<(u64, Bytes)>>,
+        installed: Mutex<Option<(u64, Bytes)>>,
+    }
+
+    impl SnapshotStore for MemorySnapshots {
+        fn latest(&self) -> std::io::Result<Option<(u64, Bytes)>> {
+            Ok(self.saved.lock().unwrap().clone())
+        }
+
+        fn install(&self, last: CompactionPoint, path: &Path) -> std::io::Result<()> {
+            let snapshot = (last.index, Bytes::from(std::fs::read(path)?));
+            *self.saved.lock().unwrap() = Some(snapshot.clone());
+            *self.installed.lock().unwrap() = Some(snapshot);
+            std::fs::remove_file(path)
+        }
+    }
+
+    /// A store whose installs each wait for the test to let them finish.
+    struct GatedSnapshots {
+        entered: mpsc::UnboundedSender<u64>,
+        release: Mutex<std::sync::mpsc::Receiver<()>>,
+    }
+
+    impl SnapshotStore for GatedSnapshots {
+        fn latest(&self) -> std::io::Result<Option<(u64, Bytes)>> {
+            Ok(None)
+        }
+
+        fn install(&self, last: CompactionPoint, path: &Path) -> std::io::Result<()> {
+            self.entered.send(last.index).unwrap();
+            self.release.lock().unwrap().recv().unwrap();
+            std::fs::remove_file(path)
+        }
+    }
+
+    /// A node that handles one InstallSnapshot at a time, turning the others away if
+    /// `reject`, with the indexes of the snapshots it starts installing and what lets
+    /// each install finish.
+    fn gated_node(
+        dir: &TempDir,
+        clock: &Arc<ManualClock>,
+        reject: bool,
+    ) -> (Arc<RaftNode>, mpsc::UnboundedReceiver<u64>, std::sync::mpsc::Sender<()>) {
+        let peers = [("node-2".to_string(), String::new())].into();
+        let mut config = RaftConfig::new("node-1", peers);
+        config.max_snapshot_installs = 1;
+        config.reject_excess_snapshot_installs = reject;
+        let state_path = dir.path().join("node-1.state");
+        let log_path = dir.path().join("node-1.wal");
+        let transport = SimNetwork::new(0).transport("node-1");
+        let node =
+            RaftNode::new_with_clock(config, state_path, log_path, transport, clock.clone())
+                .unwrap();
+        let (entered, installing) = mpsc::unbounded_channel();
+        let (release, released) = std::sync::mpsc::channel();
+        let release_gate = Mutex::new(released);
+        node.set_snapshot_store(Arc::new(GatedSnapshots { entered, release: release_gate }));
+        (node, installing, release)
+    }
+
+    /// The whole snapshot, up to `index` in term 1, in one InstallSnapshot from node-2.
+    fn snapshot_request(index: u64) -> InstallSnapshotRequest {
+        let last = CompactionPoint {
+            index,
+            term: 1,
+            ..CompactionPoint::default()
+        };
+        let data = Bytes::from(format!("state up to {}", index));
+        let snapshot = OutgoingSnapshot::new(last, data, ConfigChanges::default());
+        let leader = NodeId {
+            id: "node-2".to_string(),
+        };
+        snapshot.request(1, leader, 0, snapshot.len())
+    }
+
+    /// Polls `condition` every 100ms, failing the test if it does not hold within 10s.
+    pub(crate) async fn wait_until(mut condition: impl FnMut() -> bool) {
+        for _ in 0..100 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("condition did not hold in time");
+    }
+
+    /// (index, term, command) of every entry in `node`'s log.
+    fn log_of(node: &RaftNode) -> Vec<(u64, u64, Bytes)> {
+        let entries = node.entries(..).unwrap();
+        entries.into_iter().map(|entry| (entry.index, entry.term, entry.command)).collect()
+    }
+
+    /// An append from `node-2` carrying `(index, term)` entries after `prev`.
+    fn append(
+        term: u64,
+        prev: (u64, u64),
+        entries: &[(u64, u64)],
+        commit: u64,
+    ) -> AppendEntriesRequest {
+        AppendEntriesRequest {
+            prev_log_index: prev.0,
+            prev_log_term: prev.1,
+            entries: entries
+                .iter()
+                .map(|&(index, term)| pb::LogEntry {
+                    index,
+                    term,
+                    command: format!("command {}", index).into_bytes(),
+                    ..Default::default()
+                })
+                .collect(),
+            leader_commit: commit,
+            ..heartbeat(term, "node-2")
+        }
+    }
+
+    fn vote_request(term: u64, candidate: &str) -> RequestVoteRequest {
+        RequestVoteRequest {
+            term,
+            candidate_id: Some(NodeId {
+                id: candidate.to_string(),
+            }),
+            last_log_index: 0,
+            last_log_term: 0,
+        }
+    }
+
+    fn heartbeat(term: u64, leader: &str) -> AppendEntriesRequest {
+        AppendEntriesRequest {
+            term,
+            leader_id: Some(NodeId {
+                id: leader.to_string(),
+            }),
+            prev_log_index: 0,
+            prev_log_term: 0,
+            entries: Vec::new(),
+            leader_commit: 0,
+        }
+    }
+
+    fn standalone_node(dir: &TempDir) -> Arc<RaftNode> {
+        let peers = [("node-2".to_string(), String::new())].into();
+        let config = RaftConfig::new("node-1", peers);
+        let state_path = dir.path().join("node-1.state");
+        let log_path = dir.path().join("node-1.wal");
+        RaftNode::new(config, state_path, log_path, SimNetwork::new(0).transport("node-1")).unwrap()
+    }
+
+    fn standalone_witness(dir: &TempDir) -> Arc<RaftNode> {
+        let peers = [("node-2".to_string(), String::new())].into();
+        let mut config = RaftConfig::new("node-1", peers);
+        config.witnesses.insert("node-1".to_string());
+        let state_path = dir.path().join("node-1.state");
+        let log_path = dir.path().join("node-1.wal");
+        RaftNode::new(config, state_path, log_path, SimNetwork::new(0).transport("node-1")).unwrap()
+    }
+
+    /// A node whose election timeout is exactly 150ms, timed by `clock`, with one peer
+    /// that never answers.
+    fn manual_node(dir: &TempDir, clock: &Arc<ManualClock>) -> Arc<RaftNode> {
+        let peers = [("node-2".to_string(), String::new())].into();
+        let mut config = RaftConfig::new("node-1", peers);
+        config.election_timeout_min = Duration::from_millis(150);
+        config.election_timeout_max = Duration::from_millis(150);
+        let state_path = dir.path().join("node-1.state");
+        let log_path = dir.path().join("node-1.wal");
+        let transport = SimNetwork::new(0).transport("node-1");
+        RaftNode::new_with_clock(config, state_path, log_path, transport, clock.clone()).unwrap()
+    }
+
+    impl RaftNode {
+        /// A node named `id` with the voters `peers`, its state and log kept in `dir`,
+        /// that starts at `initial_term` and times out elections after exactly
+        /// `election_timeout` on `clock`. Unlike `TestCluster`, nothing about the node is
+        /// left to chance, so which node leads first follows from the timeouts alone.
+        pub(crate) fn new_for_test(
+            id: &str,
+            peers: &[&str],
+            initial_term: u64,
+            election_timeout: Duration,
+            dir: &Path,
+            transport: Arc<dyn RaftTransport>,
+            clock: Arc<dyn Clock>,
+        ) -> Arc<Self> {
+            let peers = peers.iter().map(|peer| (peer.to_string(), String::new())).collect();
+            let mut config = RaftConfig::new(id, peers);
+            config.election_timeout_min = election_timeout;
+            config.election_timeout_max = election_timeout;
+            let state_path = dir.join(format!("{}.state", id));
+            let store = HardStateStore::new(&state_path);
+            let hard = HardState {
+                current_term: initial_term,
+                ..store.load().unwrap()
+            };
+            store.save(&hard).unwrap();
+            let log_path = dir.join(format!("{}.wal", id));
+            Self::new_with_clock(config, state_path, log_path, transport, clock).unwrap()
+        }
+    }
+
+    /// Lets spawned tasks react to the clock before checking on them.
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_raft_election_starts_at_timeout() {
+        let dir = TempDir::new().unwrap();
+        let clock = ManualClock::new();
+        let node = manual_node(&dir, &clock);
+        let _task = node.start();
+        settle().await;
+
+        clock.advance(Duration::from_millis(149));
+        settle().await;
+        assert_eq!(node.role(), Role::Follower);
+        assert_eq!(node.current_term(), 0);
+
+        clock.advance(Duration::from_millis(1));
+        settle().await;
+        assert_eq!(node.role(), Role::Candidate);
+        assert_eq!(node.current_term(), 1);
+
+        // Without a majority the election times out and the next one begins.
+        clock.advance(Duration::from_millis(149));
+        settle().await;
+        assert_eq!(node.current_term(), 1);
+        clock.advance(Duration::from_millis(1));
+        settle().await;
+        assert_eq!(node.current_term(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_raft_first_leader_follows_from_fixed_timeouts() {
+        let dir = TempDir::new().unwrap();
+        let clock = ManualClock::new();
+        let network = SimNetwork::new_with_clock(0, clock.clone());
+        let ids = ["node-a", "node-b", "node-c"];
+        // node-b times out first, so it stands first and wins term 8.
+        let timeouts = [300, 150, 200].map(Duration::from_millis);
+        let nodes: Vec<_> = ids
+            .iter()
+            .zip(timeouts)
+            .map(|(id, timeout)| {
+                let peers: Vec<_> = ids.iter().copied().filter(|peer| peer != id).collect();
+                let transport = network.transport(id);
+                let clock = clock.clone();
+                let node =
+                    RaftNode::new_for_test(id, &peers, 7, timeout, dir.path(), transport, clock);
+                network.register(&node);
+                node
+            })
+            .collect();
+        assert!(nodes.iter().all(|node| node.current_term() == 7));
+        let _tasks: Vec<_> = nodes.iter().map(RaftNode::start).collect();
+        settle().await;
+
+        clock.advance(Duration::from_millis(149));
+        settle().await;
+        assert!(nodes.iter().all(|node| node.role() == Role::Follower));
+
+        clock.advance(Duration::from_millis(1));
+        settle().await;
+        assert_eq!(nodes[1].role(), Role::Leader);
+        assert_eq!(nodes[1].current_term(), 8);
+        for node in [&nodes[0], &nodes[2]] {
+            assert_eq!(node.role(), Role::Follower);
+            assert_eq!(node.current_term(), 8);
+            assert_eq!(node.leader_id().as_deref(), Some("node-b"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_raft_election_cycle_emits_events_in_order() {
+        let dir = TempDir::new().unwrap();
+        let clock = ManualClock::new();
+        let network = SimNetwork::new_with_clock(0, clock.clone());
+        let ids = ["node-a", "node-b", "node-c"];
+        // node-b stands first and wins term 1; node-c stands next once node-b is cut off.
+        let timeouts = [300, 150, 200].map(Duration::from_millis);
+        let nodes: Vec<_> = ids
+            .iter()
+            .zip(timeouts)
+            .map(|(id, timeout)| {
+                let peers: Vec<_> = ids.iter().copied().filter(|peer| peer != id).collect();
+                let transport = network.transport(id);
+                let clock = clock.clone();
+                let node =
+                    RaftNode::new_for_test(id, &peers, 0, timeout, dir.path(), transport, clock);
+                network.register(&node);
+                node
+            })
+            .collect();
+        let mut events = nodes[1].events();
+        let _tasks: Vec<_> = nodes.iter().map(RaftNode::start).collect();
+        settle().await;
+
+        clock.advance(Duration::from_millis(150));
+        settle().await;
+        assert_eq!(nodes[1].role(), Role::Leader);
+        let index = nodes[1].propose(Bytes::from("first")).unwrap();
+        settle().await;
+        assert_eq!(nodes[1].commit_index(), index);
+
+        network.isolate("node-b");
+        clock.advance(Duration::from_millis(200));
+        settle().await;
+        assert_eq!(nodes[2].role(), Role::Leader);
+        network.heal();
+        clock.advance(Duration::from_millis(50));
+        settle().await;
+        assert_eq!(nodes[1].role(), Role::Follower);
+
+        let mut received = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            received.push(event);
+        }
+        assert_eq!(
+            received,
+            [
+                RaftEvent::TermChanged { term: 1 },
+                RaftEvent::BecameCandidate { term: 1 },
+                RaftEvent::BecameLeader { term: 1 },
+                RaftEvent::CommitAdvanced { commit_index: 1 },
+                RaftEvent::TermChanged { term: 2 },
+                RaftEvent::BecameFollower { term: 2 },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_raft_election_wins_once_a_bare_majority_answers() {
+        let dir = TempDir::new().unwrap();
+        let clock = ManualClock::new();
+        let network = SimNetwork::new_with_clock(0, clock.clone());
+        network.set_latency(Duration::from_millis(10), Duration::from_millis(10));
+        // node-d and node-e answer only long after node-a could have won without them.
+        for slow in ["node-d", "node-e"] {
+            network.set_node_latency(slow, Duration::from_secs(1), Duration::from_secs(1));
+        }
+        let ids = ["node-a", "node-b", "node-c", "node-d", "node-e"];
+        let nodes: Vec<_> = ids
+            .iter()
+            .map(|id| {
+                let peers: Vec<_> = ids.iter().copied().filter(|peer| peer != id).collect();
+                let timeout = Duration::from_millis(if *id == "node-a" { 150 } else { 5000 });
+                let (transport, clock) = (network.transport(id), clock.clone());
+                let node =
+                    RaftNode::new_for_test(id, &peers, 0, timeout, dir.path(), transport, clock);
+                network.register(&node);
+                node
+            })
+            .collect();
+        let _tasks: Vec<_> = nodes.iter().map(RaftNode::start).collect();
+        settle().await;
+
+        clock.advance(Duration::from_millis(150));
+        settle().await;
+        assert_eq!(nodes[0].role(), Role::Candidate);
+        // One round trip to node-b and node-c makes three votes of five.
+        for _ in 0..2 {
+            clock.advance(Duration::from_millis(10));
+            settle().await;
+        }
+        assert_eq!(nodes[0].role(), Role::Leader);
+        assert_eq!(nodes[0].current_term(), 1);
+        for slow in &nodes[3..] {
+            assert_eq!((slow.current_term(), slow.role()), (0, Role::Follower));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_raft_commits_through_the_nearest_voters_first() {
+        let dir = TempDir::new().unwrap();
+        let clock = ManualClock::new();
+        let network = SimNetwork::new_with_clock(0, clock.clone());
+        network.set_latency(Duration::from_millis(5), Duration::from_millis(5));
+        // node-a, node-b and node-c share a region; node-d and node-e are an ocean away.
+        for far in ["node-d", "node-e"] {
+            network.set_node_latency(far, Duration::from_millis(500), Duration::from_millis(500));
+        }
+        let ids = ["node-a", "node-b", "node-c", "node-d", "node-e"];
+        let regions = ["eu-west", "eu-west", "eu-west", "us-east", "us-east"];
+        let nodes: Vec<_> = ids
+            .iter()
+            .map(|id| {
+                let peers: Vec<_> = ids.iter().copied().filter(|peer| peer != id).collect();
+                let timeout = Duration::from_millis(if *id == "node-a" { 150 } else { 5000 });
+                let (transport, clock) = (network.transport(id), clock.clone());
+                let node =
+                    RaftNode::new_for_test(id, &peers, 0, timeout, dir.path(), transport, clock);
+                for (peer, region) in ids.iter().zip(regions) {
+                    node.set_topology(peer, Topology::new(region, "rack-1"));
+                }
+                network.register(&node);
+                node
+            })
+            .collect();
+        let _tasks: Vec<_> = nodes.iter().map(RaftNode::start).collect();
+        settle().await;
+
+        let leader = &nodes[0];
+        assert_eq!(leader.nearest_first(leader.voters()), ids[1..]);
+        clock.advance(Duration::from_millis(150));
+        settle().await;
+        for _ in 0..2 {
+            clock.advance(Duration::from_millis(5));
+            settle().await;
+        }
+        assert_eq!(leader.role(), Role::Leader);
+
+        let index = leader.propose(Bytes::from("near")).unwrap();
+        settle().await;
+        for _ in 0..10 {
+            clock.advance(Duration::from_millis(5));
+            settle().await;
+        }
+        // node-b and node-c made the majority long before node-d or node-e could answer.
+        assert_eq!(leader.commit_index(), index);
+        for far in &nodes[3..] {
+            assert_eq!(far.last_log_index(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_raft_follower_quorum_lapses_after_election_timeout() {
+        let dir = TempDir::new().unwrap();
+        let clock = ManualClock::new();
+        let node = manual_node(&dir, &clock);
+
+        node.handle_append_entries(heartbeat(1, "node-2")).await.unwrap();
+        clock.advance(Duration::from_millis(150));
+        assert!(node.has_quorum());
+        clock.advance(Duration::from_millis(1));
+        assert!(!node.has_quorum());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_node_grants_one_vote_per_term() {
+        let dir = TempDir::new().unwrap();
+        let node = standalone_node(&dir);
+
+        assert!(node.handle_request_vote(vote_request(1, "node-2")).unwrap().vote_granted);
+        assert!(node.handle_request_vote(vote_request(1, "node-2")).unwrap().vote_granted);
+        assert!(!node.handle_request_vote(vote_request(1, "node-3")).unwrap().vote_granted);
+        assert!(!node.handle_request_vote(vote_request(0, "node-3")).unwrap().vote_granted);
+
+        let response = node.handle_request_vote(vote_request(2, "node-3")).unwrap();
+        assert!(response.vote_granted);
+        assert_eq!(response.term, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_node_persists_vote() {
+        let dir = TempDir::new().unwrap();
+        standalone_node(&dir).handle_request_vote(vote_request(3, "node-2")).unwrap();
+
+        let restarted = standalone_node(&dir);
+        assert_eq!(restarted.current_term(), 3);
+        assert!(!restarted.handle_request_vote(vote_request(3, "node-3")).unwrap().vote_granted);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_node_append_entries_follows_leader() {
+        let dir = TempDir::new().unwrap();
+        let node = standalone_node(&dir);
+
+        assert!(node.handle_append_entries(heartbeat(2, "node-2")).await.unwrap().success);
+        assert_eq!(node.role(), Role::Follower);
+        assert_eq!(node.leader_id().as_deref(), Some("node-2"));
+        assert!(node.has_quorum());
+
+        let stale = node.handle_append_entries(heartbeat(1, "node-3")).await.unwrap();
+        assert!(!stale.success);
+        assert_eq!(stale.term, 2);
+        assert_eq!(node.leader_id().as_deref(), Some("node-2"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_append_entries_repairs_follower_log() {
+        let dir = TempDir::new().unwrap();
+        let node = standalone_node(&dir);
+
+        let response = node.handle_append_entries(append(1, (0, 0), &[(1, 1), (2, 1)], 1)).await;
+        assert!(response.unwrap().success);
+        assert_eq!(node.last_log_index(), 2);
+        assert_eq!(node.commit_index(), 1);
+
+        // An append that would leave a gap is refused, so the leader backs up.
+        let response = node.handle_append_entries(append(1, (4, 1), &[(5, 1)], 1)).await;
+        assert!(!response.unwrap().success);
+        let response = node.handle_append_entries(append(2, (2, 2), &[(3, 2)], 1)).await;
+        assert!(!response.unwrap().success);
+
+        // A new leader's entries replace the ones that conflict with them.
+        let response = node.handle_append_entries(append(2, (1, 1), &[(2, 2), (3, 2)], 3)).await;
+        assert!(response.unwrap().success);
+        let terms: Vec<u64> = log_of(&node).iter().map(|(_, term, _)| *term).collect();
+        assert_eq!(terms, vec![1, 2, 2]);
+        assert_eq!(node.commit_index(), 3);
+
+        // Entries it already has are left alone, and a vote needs an up-to-date log.
+        assert!(node.handle_append_entries(append(2, (0, 0), &[(1, 1)], 3)).await.unwrap().success);
+        assert_eq!(node.last_log_index(), 3);
+        assert!(!node.handle_request_vote(vote_request(3, "node-3")).unwrap().vote_granted);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_append_entries_never_overwrites_committed_entries() {
+        let dir = TempDir::new().unwrap();
+        let node = standalone_node(&dir);
+        let request = append(1, (0, 0), &[(1, 1), (2, 1), (3, 1)], 2);
+        let response = node.handle_append_entries(request).await;
+        assert!(response.unwrap().success);
+        assert_eq!(node.commit_index(), 2);
+
+        // A leader whose entry conflicts with a committed one is refused outright.
+        let err = node.handle_append_entries(append(2, (1, 1), &[(2, 2)], 2)).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("commit index 2"), "{}", err);
+        let terms: Vec<u64> = log_of(&node).iter().map(|(_, term, _)| *term).collect();
+        assert_eq!(terms, vec![1, 1, 1]);
+
+        // Past the commit index, conflicting entries are replaced as usual.
+        let response = node.handle_append_entries(append(2, (2, 1), &[(3, 2)], 2)).await;
+        assert!(response.unwrap().success);
+        let terms: Vec<u64> = log_of(&node).iter().map(|(_, term, _)| *term).collect();
+        assert_eq!(terms, vec![1, 1, 2]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_follower_keeps_leader_timestamps() {
+        let dir = TempDir::new().unwrap();
+        let node = standalone_node(&dir);
+
+        let mut request = append(1, (0, 0), &[(1, 1), (2, 1)], 0);
+        request.entries[0].timestamp = 1_700_000_000_000;
+        assert!(node.handle_append_entries(request).await.unwrap().success);
+
+        let entries = node.entries(..).unwrap();
+        assert_eq!(entries[0].timestamp, Some(1_700_000_000_000));
+        // An entry the leader sent without one is stamped on arrival.
+        assert!(entries[1].timestamp.unwrap() >= 1_700_000_000_000);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_vote_requires_up_to_date_log() {
+        let dir = TempDir::new().unwrap();
+        let node = standalone_node(&dir);
+        node.handle_append_entries(append(2, (0, 0), &[(1, 1), (2, 2)], 0)).await.unwrap();
+
+        let candidate = |term, last_log_index, last_log_term| RequestVoteRequest {
+            last_log_index,
+            last_log_term,
+            ..vote_request(term, "node-2")
+        };
+        // A longer log is not enough if its last entry is from an older term.
+        assert!(!node.handle_request_vote(candidate(3, 5, 1)).unwrap().vote_granted);
+        assert!(!node.handle_request_vote(candidate(3, 1, 2)).unwrap().vote_granted);
+        assert!(node.handle_request_vote(candidate(3, 1, 3)).unwrap().vote_granted);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_witness_stores_no_log() {
+        let dir = TempDir::new().unwrap();
+        let witness = standalone_witness(&dir);
+
+        let response = witness.handle_append_entries(append(1, (0, 0), &[(1, 1), (2, 1)], 0)).await;
+        assert!(response.unwrap().success);
+        let end_of_log = AppendEntriesRequest {
+            prev_log_index: 5,
+            prev_log_term: 1,
+            ..heartbeat(1, "node-2")
+        };
+        assert!(witness.handle_append_entries(end_of_log).await.unwrap().success);
+        assert_eq!(witness.last_log_index(), 0);
+        assert!(witness.propose(Bytes::from("command")).is_err());
+
+        // Having acknowledged the leader's log up to 5, it votes only for logs that far.
+        let candidate = |last_log_index| RequestVoteRequest {
+            last_log_index,
+            last_log_term: 1,
+            ..vote_request(2, "node-3")
+        };
+        assert!(!witness.handle_request_vote(candidate(4)).unwrap().vote_granted);
+        assert!(witness.handle_request_vote(candidate(5)).unwrap().vote_granted);
+
+        // The acknowledgement survives a restart.
+        drop(witness);
+        let witness = standalone_witness(&dir);
+        let candidate = RequestVoteRequest {
+            last_log_index: 4,
+            last_log_term: 1,
+            ..vote_request(3, "node-3")
+        };
+        assert!(!witness.handle_request_vote(candidate).unwrap().vote_granted);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_two_nodes_and_witness_survive_a_data_node_failure() {
+        let cluster = TestCluster::start_with_witnesses(3, &["node-3"]);
+        let witness = cluster.nodes[2].clone();
+        let leader = cluster.wait_for_leader().await;
+        assert_ne!(leader.id(), witness.id());
+        let index = leader.propose(Bytes::from("before the failure")).unwrap();
+        wait_until(|| leader.commit_index() == index).await;
+
+        // The leader and the witness still make a majority without the other data node.
+        let other = cluster.nodes[..2].iter().find(|node| node.id() != leader.id()).unwrap();
+        cluster.network.isolate(other.id());
+        let index = leader.propose(Bytes::from("without a follower")).unwrap();
+        wait_until(|| leader.commit_index() == index).await;
+
+        // When the leader fails instead, the other data node takes over with the witness.
+        cluster.network.heal();
+        cluster.wait_for_convergence(&[leader.clone(), other.clone()]).await;
+        cluster.network.isolate(leader.id());
+        wait_until(|| other.role() == Role::Leader).await;
+        let index = other.propose(Bytes::from("without the old leader")).unwrap();
+        wait_until(|| other.commit_index() == index).await;
+
+        assert_eq!(witness.last_log_index(), 0);
+        assert_eq!(witness.role(), Role::Follower);
+        let commands: Vec<Bytes> = log_of(other).into_iter().map(|(_, _, c)| c).collect();
+        assert_eq!(
+            commands,
+            ["before the failure", "without a follower", "without the old leader"]
+                .map(Bytes::from)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_read_replica_replicates_and_serves_reads() {
+        let cluster = TestCluster::start_with_read_replicas(3, &["node-3"]);
+        let leader = cluster.wait_for_leader().await;
+        let replica = cluster.node("node-3");
+        assert_ne!(leader.id(), "node-3");
+
+        for i in 0..10 {
+            leader.propose(Bytes::from(format!("cmd-{}", i))).unwrap();
+        }
+        cluster.wait_for_convergence(&cluster.nodes).await;
+
+        let read_index = replica.read_index().await.unwrap();
+        assert_eq!(read_index, leader.commit_index());
+        assert!(replica.commit_index() >= read_index);
+        assert_eq!(log_of(&replica), log_of(&leader));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_read_replica_is_never_elected() {
+        let cluster = TestCluster::start_with_read_replicas(3, &["node-3"]);
+        let replica = cluster.node("node-3");
+
+        let mut leader = cluster.wait_for_leader().await;
+        for _ in 0..5 {
+            let term = leader.current_term();
+            cluster.network.isolate(leader.id());
+            wait_until(|| {
+                cluster.nodes.iter().any(|node| {
+                    node.id() != leader.id()
+                        && node.role() == Role::Leader
+                        && node.current_term() > term
+                })
+            })
+            .await;
+            cluster.network.heal();
+            leader = cluster.wait_for_leader().await;
+
+            assert_ne!(leader.id(), "node-3");
+            assert_ne!(replica.role(), Role::Leader);
+            assert_ne!(replica.role(), Role::Candidate);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_read_index_fails_without_a_leader() {
+        let dir = TempDir::new().unwrap();
+        let node = standalone_node(&dir);
+
+        let e = node.read_index().await.unwrap_err();
+        assert_eq!(NotLeader::from_io(&e).unwrap().leader_id, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_follower_rejects_proposals() {
+        let dir = TempDir::new().unwrap();
+        let node = standalone_node(&dir);
+        node.handle_append_entries(heartbeat(1, "node-2")).await.unwrap();
+
+        let err = node.propose(Bytes::from("command")).unwrap_err();
+        assert_eq!(
+            NotLeader::from_io(&err),
+            Some(&NotLeader {
+                leader_id: Some("node-2".to_string()),
+            })
+        );
+        assert_eq!(node.last_log_index(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_single_node_elects_itself() {
+        let cluster = TestCluster::start(1);
+        let leader = cluster.wait_for_leader().await;
+
+        assert_eq!(leader.id(), "node-1");
+        assert!(leader.has_quorum());
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[traced_test]
+    async fn test_raft_election_is_traced_with_term() {
+        let cluster = TestCluster::start(1);
+        let leader = cluster.wait_for_leader().await;
+        assert_eq!(leader.current_term(), 1);
+
+        assert!(logs_contain("run_election{node=node-1 term=1}"));
+        assert!(logs_contain("starting election"));
+        assert!(logs_contain("won election votes=1"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_cluster_elects_one_leader() {
+        let cluster = TestCluster::start(3);
+        let leader = cluster.wait_for_leader().await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        // Heartbeats keep the leader in place and the followers pointed at it.
+        assert_eq!(leader.role(), Role::Leader);
+        assert!(leader.has_quorum());
+        for node in &cluster.nodes {
+            assert_eq!(node.current_term(), leader.current_term());
+            assert_eq!(node.leader_id().as_deref(), Some(leader.id()));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_peer_status_reports_lag_of_a_cut_off_follower() {
+        let cluster = TestCluster::start(3);
+        let leader = cluster.wait_for_leader().await;
+        for i in 0..3 {
+            leader.propose(Bytes::from(format!("command {}", i))).unwrap();
+        }
+        cluster.wait_for_convergence(&cluster.nodes).await;
+        let synced = leader.last_log_index();
+
+        let behind = cluster.nodes.iter().find(|node| node.id() != leader.id()).unwrap();
+        cluster.network.isolate(behind.id());
+        for i in 3..8 {
+            leader.propose(Bytes::from(format!("command {}", i))).unwrap();
+        }
+        wait_until(|| leader.commit_index() == synced + 5).await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let status = leader.peer_status();
+        assert_eq!(status.len(), 2);
+        for peer in status {
+            if peer.id == behind.id() {
+                assert_eq!((peer.match_index, peer.lag), (synced, 5));
+                assert_eq!(peer.lag, leader.last_log_index() - behind.last_log_index());
+                assert!(peer.since_last_append.unwrap() >= Duration::from_secs(1));
+            } else {
+                let progress = (peer.match_index, peer.next_index, peer.lag);
+                assert_eq!(progress, (synced + 5, synced + 6, 0));
+                assert!(peer.since_last_append.unwrap() < Duration::from_millis(100));
+            }
+        }
+        assert!(behind.peer_status().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_high_priority_node_wins_a_clean_election() {
+        let cluster = TestCluster::start_with(3, |config| {
+            config.election_priorities.insert("node-2".to_string(), 1);
+        });
+
+        let leader = cluster.wait_for_leader().await;
+        assert_eq!(leader.id(), "node-2");
+        assert_eq!(leader.current_term(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_low_priority_node_wins_while_high_priority_node_is_down() {
+        let cluster = TestCluster::start_with(3, |config| {
+            config.election_priorities.insert("node-2".to_string(), 1);
+        });
+        cluster.network.isolate("node-2");
+
+        wait_until(|| {
+            cluster.nodes.iter().any(|node| node.id() != "node-2" && node.role() == Role::Leader)
+        })
+        .await;
+        assert_ne!(cluster.node("node-2").role(), Role::Leader);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_cluster_replaces_isolated_leader() {
+        let cluster = TestCluster::start(3);
+        let old_leader = cluster.wait_for_leader().await;
+
+        cluster.network.isolate(old_leader.id());
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!old_leader.has_quorum());
+
+        let new_leader = cluster.wait_for_leader().await;
+        assert_ne!(new_leader.id(), old_leader.id());
+        assert!(new_leader.current_term() > old_leader.current_term());
+
+        cluster.network.heal();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(old_leader.role(), Role::Follower);
+        assert_eq!(old_leader.leader_id().as_deref(), Some(new_leader.id()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[traced_test]
+    async fn test_raft_far_behind_follower_catches_up_by_stream() {
+        let cluster = TestCluster::start(3);
+        cluster.network.set_latency(Duration::from_millis(1), Duration::from_millis(5));
+        let leader = cluster.wait_for_leader().await;
+        let follower = cluster.nodes.iter().find(|node| node.id() != leader.id()).unwrap();
+        cluster.network.isolate(follower.id());
+        for i in 1..=1000 {
+            leader.propose(Bytes::from(format!("command {}", i))).unwrap();
+        }
+        wait_until(|| leader.commit_index() == 1000).await;
+        assert_eq!(follower.last_log_index(), 0);
+
+        cluster.network.heal();
+        cluster.wait_for_convergence(&cluster.nodes).await;
+        assert!(logs_contain("catching up from the leader by stream"));
+        assert!(logs_contain("streaming entries follower=node-"));
+        assert!(logs_contain("caught up from the leader last_index=1000"));
+        assert_eq!(log_of(follower), log_of(&leader));
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[traced_test]
+    async fn test_raft_catch_up_is_sent_in_bounded_batches() {
+        let cluster = TestCluster::start_with(3, |config| {
+            config.max_entries_per_append = 10;
+            config.max_bytes_per_append = 200;
+        });
+        let leader = cluster.wait_for_leader().await;
+        let follower = cluster.nodes.iter().find(|node| node.id() != leader.id()).unwrap();
+        cluster.network.isolate(follower.id());
+        // Small commands fill a batch by count, large ones by size.
+        for i in 1..=60 {
+            let size = if i <= 30 { 8 } else { 90 };
+            leader.propose(Bytes::from(format!("{:0>1$}", i, size))).unwrap();
+        }
+        wait_until(|| leader.commit_index() == 60).await;
+
+        cluster.network.heal();
+        cluster.wait_for_convergence(&cluster.nodes).await;
+        assert_eq!(log_of(follower), log_of(&leader));
+
+        let follower_span = format!("node={}", follower.id());
+        logs_assert(|lines: &[&str]| {
+            let field = |line: &str, name: &str| {
+                let value = line.split_whitespace().find_map(|f| f.strip_prefix(name))?;
+                value.parse::<usize>().ok()
+            };
+            let mut batches = 0;
+            for line in lines.iter().filter(|line| line.contains("appending entries")) {
+                let (count, bytes) = (field(line, "count="), field(line, "bytes="));
+                match (count, bytes) {
+                    (Some(1..=10), Some(0..=200)) => {}
+                    _ => return Err(format!("unbounded batch: {}", line)),
+                }
+                batches += line.contains(&follower_span) as usize;
+            }
+            // 30 small entries at 10 a batch, then 30 large ones at 2 a batch.
+            match batches {
+                18.. => Ok(()),
+                _ => Err(format!("caught up in only {} batches", batches)),
+            }
+        });
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_snapshot_transfer_resumes_after_the_connection_drops() {
+        let cluster = TestCluster::start_with(3, |config| config.snapshot_chunk_bytes = 64);
+        let stores: BTreeMap<String, Arc<MemorySnapshots>> = cluster
+            .nodes
+            .iter()
+            .map(|node| {
+                let store = Arc::new(MemorySnapshots::default());
+                node.set_snapshot_store(store.clone());
+                (node.id().to_string(), store)
+            })
+            .collect();
+        let leader = cluster.wait_for_leader().await;
+        let follower = cluster.nodes.iter().find(|node| node.id() != leader.id()).unwrap();
+        cluster.network.isolate(follower.id());
+        for i in 1..=5 {
+            leader.propose(Bytes::from(format!("command {}", i))).unwrap();
+        }
+        let others: Vec<_> =
+            cluster.nodes.iter().filter(|node| node.id() != follower.id()).collect();
+        wait_until(|| others.iter().all(|node| node.commit_index() == 5)).await;
+        // Both hold the snapshot, so either can finish sending it if the other loses the lead.
+        let snapshot: Vec<u8> = (0..4096u32).map(|i| (i * 31 % 251) as u8).collect();
+        for node in &others {
+            *stores[node.id()].saved.lock().unwrap() = Some((5, Bytes::from(snapshot.clone())));
+            node.compact_log(5).unwrap();
+            assert_eq!(node.entries(..).unwrap(), Vec::new());
+        }
+
+        // Cut off halfway through the snapshot.
+        let mut events = follower.events();
+        cluster.network.heal();
+        let mut received = 0;
+        let halfway = async {
+            while received < 2048 {
+                if let Ok(RaftEvent::SnapshotReceived { offset, .. }) = events.recv().await {
+                    received = offset;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), halfway).await.expect("no snapshot sent");
+        cluster.network.isolate(follower.id());
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        // A chunk may have landed as the connection went, its answer lost.
+        while let Ok(event) = events.try_recv() {
+            if let RaftEvent::SnapshotReceived { offset, .. } = event {
+                received = offset;
+            }
+        }
+        assert!(stores[follower.id()].installed.lock().unwrap().is_none());
+
+        cluster.network.heal();
+        let mut resumed_at = None;
+        let installed = async {
+            loop {
+                match events.recv().await {
+                    Ok(RaftEvent::SnapshotReceived { offset, .. }) => {
+                        resumed_at.get_or_insert(offset);
+                    }
+                    Ok(RaftEvent::SnapshotInstalled { index }) => break index,
+                    _ => {}
+                }
+            }
+        };
+        let index = tokio::time::timeout(Duration::from_secs(10), installed).await;
+        assert_eq!(index.expect("snapshot not installed"), 5);
+        // The first chunk written once reconnected follows the last one before the cut.
+        assert_eq!(resumed_at, Some(received + 64));
+        let installed = stores[follower.id()].installed.lock().unwrap().clone().unwrap();
+        assert_eq!(installed, (5, Bytes::from(snapshot.clone())));
+        assert_eq!(crate::wal::crc32(&installed.1), crate::wal::crc32(&snapshot));
+
+        // Replication carries on from the entry after the snapshot.
+        let leader = cluster.wait_for_leader().await;
+        let index = leader.propose(Bytes::from("after")).unwrap();
+        wait_until(|| follower.commit_index() >= index).await;
+        assert_eq!(follower.entries(6..).unwrap(), leader.entries(6..).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_raft_snapshot_installs_past_the_limit_wait_their_turn() {
+        let dir = TempDir::new().unwrap();
+        let clock = ManualClock::new();
+        let (node, mut installing, release) = gated_node(&dir, &clock, false);
+        let first = tokio::spawn({
+            let node = node.clone();
+            async move { node.handle_install_snapshot(snapshot_request(5)).await }
+        });
+        assert_eq!(installing.recv().await, Some(5));
+
+        let second = tokio::spawn({
+            let node = node.clone();
+            async move { node.handle_install_snapshot(snapshot_request(9)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!second.is_finished());
+        assert!(installing.try_recv().is_err());
+
+        release.send(()).unwrap();
+        assert!(first.await.unwrap().unwrap().installed);
+        assert_eq!(installing.recv().await, Some(9));
+        release.send(()).unwrap();
+        assert!(second.await.unwrap().unwrap().installed);
+        assert_eq!(node.commit_index(), 9);
+    }
+
+    #[tokio::test]
+    async fn test_raft_snapshot_installs_past_the_limit_are_turned_away() {
+        let dir = TempDir::new().unwrap();
+        let clock = ManualClock::new();
+        let (node, mut installing, release) = gated_node(&dir, &clock, true);
+        let first = tokio::spawn({
+            let node = node.clone();
+            async move { node.handle_install_snapshot(snapshot_request(5)).await }
+        });
+        assert_eq!(installing.recv().await, Some(5));
+
+        let err = node.handle_install_snapshot(snapshot_request(9)).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+        assert!(installing.try_recv().is_err());
+
+        release.send(()).unwrap();
+        assert!(first.await.unwrap().unwrap().installed);
+        // Sent again once the first is done, it goes through.
+        let retried = tokio::spawn({
+            let node = node.clone();
+            async move { node.handle_install_snapshot(snapshot_request(9)).await }
+        });
+        assert_eq!(installing.recv().await, Some(9));
+        release.send(()).unwrap();
+        assert!(retried.await.unwrap().unwrap().installed);
+        assert_eq!(node.commit_index(), 9);
+    }
+
+    #[tokio::test(start_paused = true)]
+    #[traced_test]
+    async fn test_raft_leader_commits_past_a_slow_follower_and_catches_it_up_later() {
+        let cluster = TestCluster::start_with(3, |config| {
+            config.append_timeout = Duration::from_millis(20);
+            config.append_retries = 2;
+            // Long enough that the slow follower never stands for election.
+            config.election_timeout_min = Duration::from_secs(1);
+            config.election_timeout_max = Duration::from_secs(2);
+        });
+        let leader = cluster.wait_for_leader().await;
+        let term = leader.current_term();
+        let slow = cluster.nodes.iter().find(|node| node.id() != leader.id()).unwrap();
+        let status = |leader: &RaftNode| {
+            leader.peer_status().into_iter().find(|peer| peer.id == slow.id()).unwrap()
+        };
+
+        // Every round trip to the slow follower outlasts the timeout.
+        let latency = Duration::from_millis(40);
+        cluster.network.set_node_latency(slow.id(), latency, latency);
+        let mut index = 0;
+        for i in 1..=20 {
+            index = leader.propose(Bytes::from(format!("command {}", i))).unwrap();
+        }
+        wait_until(|| leader.commit_index() == index).await;
+        wait_until(|| status(&leader).lagging).await;
+        assert!(status(&leader).match_index < index);
+        assert!(logs_contain("peer is lagging; probing it before sending more"));
+
+        cluster.network.clear_node_latency(slow.id());
+        wait_until(|| !status(&leader).lagging && status(&leader).match_index == index).await;
+        cluster.wait_for_convergence(&cluster.nodes).await;
+        assert!(logs_contain("peer answered a probe; resuming replication"));
+        assert_eq!((leader.role(), leader.current_term()), (Role::Leader, term));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_shutdown_hands_over_leadership_with_a_durable_log() {
+        let cluster = TestCluster::start(3);
+        let leader = cluster.wait_for_leader().await;
+        for i in 1..=20 {
+            leader.propose(Bytes::from(format!("command {}", i))).unwrap();
+        }
+        wait_until(|| leader.commit_index() == 20).await;
+
+        let started = Instant::now();
+        leader.shutdown().await.unwrap();
+        // Handed over, rather than left for the followers' election timeout.
+        assert!(started.elapsed() < DEFAULT_ELECTION_TIMEOUT_MIN);
+        let successor = cluster.nodes.iter().find(|node| node.role() == Role::Leader).unwrap();
+        assert_ne!(successor.id(), leader.id());
+        assert!(successor.current_term() > 1);
+        assert_eq!(leader.leader_id().as_deref(), Some(successor.id()));
+        let err = leader.propose(Bytes::from("too late")).unwrap_err();
+        assert_eq!(NotLeader::from_io(&err).unwrap().leader_id.as_deref(), Some(successor.id()));
+
+        let log_path = cluster.dir.path().join(format!("{}.wal", leader.id()));
+        let reopened = Wal::new(log_path.to_str().unwrap()).unwrap();
+        assert_eq!(reopened.last_index(), 20);
+        assert_eq!(reopened.replay().unwrap(), leader.entries(..).unwrap());
+
+        // The node that shut down never campaigns again, and the cluster carries on.
+        tokio::time::sleep(DEFAULT_ELECTION_TIMEOUT_MAX * 4).await;
+        assert_eq!(leader.role(), Role::Follower);
+        successor.propose(Bytes::from("after handover")).unwrap();
+        wait_until(|| successor.commit_index() == successor.last_log_index()).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_partitioned_leader_log_converges_after_heal() {
+        let cluster = TestCluster::start(5);
+        cluster.network.set_latency(Duration::from_millis(1), Duration::from_millis(10));
+        let old_leader = cluster.wait_for_leader().await;
+        old_leader.propose(Bytes::from("before the partition")).unwrap();
+        cluster.wait_for_convergence(&cluster.nodes).await;
+
+        // The old leader keeps one follower; the other three form the majority.
+        let follower = cluster.nodes.iter().find(|node| node.id() != old_leader.id()).unwrap();
+        let minority = [old_leader.id(), follower.id()];
+        let majority: Vec<_> =
+            cluster.nodes.iter().filter(|node| !minority.contains(&node.id())).cloned().collect();
+        let majority_ids: Vec<&str> = majority.iter().map(|node| node.id()).collect();
+        cluster.network.partition(&minority, &majority_ids);
+
+        // Without a majority the old leader's entry is stored but never committed.
+        let lost = old_leader.propose(Bytes::from("lost in the minority")).unwrap();
+        let leads = |node: &&Arc<RaftNode>| node.role() == Role::Leader;
+        wait_until(|| majority.iter().any(|node| leads(&node))).await;
+        let new_leader = majority.iter().find(leads).unwrap().clone();
+        assert!(new_leader.current_term() > old_leader.current_term());
+        new_leader.propose(Bytes::from("written by the majority")).unwrap();
+        cluster.wait_for_convergence(&majority).await;
+        assert!(old_leader.commit_index() < lost);
+
+        cluster.network.heal();
+        new_leader.propose(Bytes::from("after the heal")).unwrap();
+        cluster.wait_for_convergence(&cluster.nodes).await;
+
+        let log = log_of(&new_leader);
+        let commands: Vec<Bytes> = log.iter().map(|(_, _, command)| command.clone()).collect();
+        assert_eq!(
+            commands,
+            ["before the partition", "written by the majority", "after the heal"].map(Bytes::from)
+        );
+        assert_eq!(old_leader.role(), Role::Follower);
+        assert_eq!(log_of(&old_leader), log);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_step_down_trims_only_the_uncommitted_tail_when_asked() {
+        for trim in [false, true] {
+            let cluster = TestCluster::start_with(3, |config| {
+                config.trim_uncommitted_on_step_down = trim;
+            });
+            let leader = cluster.wait_for_leader().await;
+            let committed = leader.propose(Bytes::from("committed")).unwrap();
+            cluster.wait_for_convergence(&cluster.nodes).await;
+
+            let ids = cluster.nodes.iter().map(|node| node.id());
+            let others: Vec<&str> = ids.filter(|id| *id != leader.id()).collect();
+            cluster.network.partition(&[leader.id()], &others);
+            leader.propose(Bytes::from("uncommitted 1")).unwrap();
+            let last = leader.propose(Bytes::from("uncommitted 2")).unwrap();
+            assert_eq!((leader.commit_index(), leader.last_log_index()), (committed, last));
+
+            // A vote request for a later term makes the leader step down.
+            let term = leader.current_term() + 1;
+            leader.handle_request_vote(vote_request(term, others[0])).unwrap();
+            assert_eq!(leader.role(), Role::Follower);
+            let expected = if trim { committed } else { last };
+            assert_eq!(leader.last_log_index(), expected, "trim: {}", trim);
+            assert_eq!(leader.commit_index(), committed);
+            assert_eq!(log_of(&leader)[committed as usize - 1].2, Bytes::from("committed"));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_step_down_keeps_inherited_entries_it_does_not_know_are_committed() {
+        let dir = TempDir::new().unwrap();
+        let peers = [("node-2".to_string(), String::new())].into();
+        let mut config = RaftConfig::new("node-1", peers);
+        config.trim_uncommitted_on_step_down = true;
+        let (state_path, log_path) = (dir.path().join("node-1.state"), dir.path().join("wal"));
+        let transport = SimNetwork::new(0).transport("node-1");
+        let node = RaftNode::new(config, state_path, log_path, transport).unwrap();
+
+        // The leader of term 1 may have committed these on a majority without saying so.
+        let response = node.handle_append_entries(append(1, (0, 0), &[(1, 1), (2, 1)], 0)).await;
+        assert!(response.unwrap().success);
+        assert_eq!(node.commit_index(), 0);
+        {
+            let mut state = node.lock();
+            state.hard.current_term = 2;
+            node.become_leader(&mut state, 2).unwrap();
+        }
+        let own = node.propose(Bytes::from("own")).unwrap();
+        assert_eq!((own, node.commit_index()), (3, 0));
+
+        node.handle_request_vote(vote_request(3, "node-2")).unwrap();
+        assert_eq!(node.role(), Role::Follower);
+        let terms: Vec<u64> = log_of(&node).iter().map(|(_, term, _)| *term).collect();
+        assert_eq!(terms, [1, 1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_commit_watch_streams_committed_entries_in_order() {
+        let cluster = TestCluster::start(3);
+        let leader = cluster.wait_for_leader().await;
+        for i in 0..3 {
+            leader.propose(Bytes::from(format!("before {}", i))).unwrap();
+        }
+        cluster.wait_for_convergence(&cluster.nodes).await;
+
+        // A consumer on a follower joins mid-stream, resuming from its own cursor.
+        let follower = cluster.nodes.iter().find(|node| node.id() != leader.id()).unwrap();
+        let mut commits = follower.commit_watch();
+        let mut cursor = 2;
+        let consumer = tokio::spawn({
+            let follower = follower.clone();
+            async move {
+                let mut seen = Vec::new();
+                loop {
+                    for entry in follower.entries_since(cursor).unwrap() {
+                        seen.push(entry.index);
+                        cursor = entry.index;
+                    }
+                    if cursor == 13 {
+                        return seen;
+                    }
+                    commits.changed().await.unwrap();
+                }
+            }
+        });
+        for i in 0..10 {
+            leader.propose(Bytes::from(format!("after {}", i))).unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let seen = consumer.await.unwrap();
+        assert_eq!(seen, (3..=13).collect::<Vec<u64>>());
+        assert!(follower.entries_since(13).unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_counts_elections_and_reports_churn() {
+        let cluster = TestCluster::start(3);
+        let alerts = Arc::new(Mutex::new(Vec::new()));
+        for node in &cluster.nodes {
+            let (alerts, id) = (alerts.clone(), node.id().to_string());
+            node.on_election_churn(1, Duration::from_secs(60), move |churn| {
+                alerts.lock().unwrap().push((id.clone(), churn));
+            });
+        }
+        let first = cluster.wait_for_leader().await;
+        assert_eq!(first.elections_started(), 1);
+        assert!(cluster.nodes.iter().all(|node| node.leader_changes() == 1));
+
+        // Depose the leader over and over.
+        for _ in 0..3 {
+            let leader = cluster.wait_for_leader().await;
+            cluster.network.isolate(leader.id());
+            let term = leader.current_term();
+            wait_until(|| {
+                cluster.nodes.iter().any(|node| {
+                    node.role() == Role::Leader && node.current_term() > term
+                })
+            })
+            .await;
+            cluster.network.heal();
+        }
+        cluster.wait_for_leader().await;
+        cluster.wait_for_convergence(&cluster.nodes).await;
+
+        let elections: u64 = cluster.nodes.iter().map(|node| node.elections_started()).sum();
+        assert!(elections >= 4, "{} elections", elections);
+        let term = cluster.nodes[0].current_term();
+        for node in &cluster.nodes {
+            assert!(node.leader_changes() >= 2, "{} saw {}", node.id(), node.leader_changes());
+            assert!(node.leader_changes() <= term);
+        }
+        // With more elections than nodes, some node started two within the window.
+        let alerts = alerts.lock().unwrap();
+        assert!(!alerts.is_empty());
+        for (id, churn) in alerts.iter() {
+            assert_eq!(churn.elections, 2, "{}", id);
+            assert!(cluster.node(id).elections_started() >= 2);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_restart_resumes_from_the_persisted_commit_index() {
+        let cluster = TestCluster::start(3);
+        let leader = cluster.wait_for_leader().await;
+        for i in 0..3 {
+            leader.propose(Bytes::from(format!("command {}", i))).unwrap();
+        }
+        cluster.wait_for_convergence(&cluster.nodes).await;
+
+        // Restarted on its own files, out of reach of any leader.
+        let follower = cluster.nodes.iter().find(|node| node.id() != leader.id()).unwrap();
+        let id = follower.id();
+        let state_path = cluster.dir.path().join(format!("{}.state", id));
+        let log_path = cluster.dir.path().join(format!("{}.wal", id));
+        let store = HardStateStore::new(&state_path);
+        wait_until(|| store.load().unwrap().commit_index == 3).await;
+        let transport = SimNetwork::new(0).transport(id);
+        let config = follower.config().clone();
+        let restarted = RaftNode::new(config, state_path, log_path, transport).unwrap();
+
+        assert_eq!(restarted.commit_index(), 3);
+        assert_eq!(*restarted.commit_watch().borrow(), 3);
+        let applied: Vec<u64> =
+            restarted.entries_since(0).unwrap().iter().map(|entry| entry.index).collect();
+        assert_eq!(applied, [1, 2, 3]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_saves_the_commit_index_lazily() {
+        let dir = TempDir::new().unwrap();
+        let network = SimNetwork::new(1);
+        let state_path = dir.path().join("node-1.state");
+        let log_path = dir.path().join("node-1.wal");
+        let config = RaftConfig::new("node-1", BTreeMap::new());
+        let transport = network.transport("node-1");
+        let leader = RaftNode::bootstrap(config, &state_path, log_path, transport).unwrap();
+        network.register(&leader);
+        let index = leader.propose(Bytes::from_static(b"command")).unwrap();
+        assert_eq!(leader.commit_index(), index);
+
+        // Committing does not fsync the hard state; the node saves it in the background.
+        let store = HardStateStore::new(&state_path);
+        assert_eq!(store.load().unwrap().commit_index, 0);
+        let _task = leader.start();
+        wait_until(|| store.load().unwrap().commit_index == index).await;
+
+        // Shutting down saves the latest at once.
+        let index = leader.propose(Bytes::from_static(b"last")).unwrap();
+        assert_eq!(leader.commit_index(), index);
+        leader.shutdown().await.unwrap();
+        assert_eq!(store.load().unwrap().commit_index, index);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_apply_waiter_resolves_with_the_applied_result() {
+        let cluster = TestCluster::start(3);
+        let leader = cluster.wait_for_leader().await;
+        let waiter = leader.propose_waiting(Bytes::from("open alice")).unwrap();
+        let index = waiter.index();
+        wait_until(|| leader.commit_index() >= index).await;
+
+        // What the apply loop does once the entry is committed.
+        let entry = leader.entries(index..=index).unwrap().remove(0);
+        let created = CommandOutcome::AccountCreated { account: "alice".to_string() };
+        leader.applied(&entry, Ok(created.clone()));
+        assert_eq!(waiter.outcome().await.unwrap(), created);
+        assert!(leader.waiters.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_apply_waiter_fails_when_its_entry_is_truncated() {
+        let cluster = TestCluster::start(3);
+        let old_leader = cluster.wait_for_leader().await;
+        let others: Vec<_> =
+            cluster.nodes.iter().filter(|node| node.id() != old_leader.id()).cloned().collect();
+        cluster.network.isolate(old_leader.id());
+
+        // Stored by the old leader alone, so never committed.
+        let waiter = old_leader.propose_waiting(Bytes::from("lost")).unwrap();
+        let leads = |node: &&Arc<RaftNode>| node.role() == Role::Leader;
+        wait_until(|| others.iter().any(|node| leads(&node))).await;
+        let new_leader = others.iter().find(leads).unwrap().clone();
+        new_leader.propose(Bytes::from("written by the new leader")).unwrap();
+        cluster.wait_for_convergence(&others).await;
+
+        cluster.network.heal();
+        cluster.wait_for_convergence(&cluster.nodes).await;
+        let err = waiter.outcome().await.unwrap_err();
+        let not_leader = NotLeader::from_io(&err).unwrap();
+        assert_eq!(not_leader.leader_id.as_deref(), Some(new_leader.id()));
+        assert!(old_leader.waiters.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_removes_voters_one_at_a_time() {
+        let cluster = TestCluster::start(5);
+        let leader = cluster.wait_for_leader().await;
+        let followers: Vec<_> =
+            cluster.nodes.iter().filter(|node| node.id() != leader.id()).cloned().collect();
+        let (first, second) = (followers[0].id(), followers[1].id());
+        cluster.network.isolate(first);
+        cluster.network.isolate(second);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let mut events = followers[2].events();
+
+        let err = leader.remove_voter("node-9").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        let index = leader.remove_voter(first).unwrap();
+        assert_eq!((leader.voters().len(), leader.quorum()), (3, 3));
+        let err = leader.remove_voter(second).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ResourceBusy);
+
+        wait_until(|| leader.commit_index() >= index).await;
+        leader.remove_voter(second).unwrap();
+        assert_eq!((leader.voters().len(), leader.quorum()), (2, 2));
+        let healthy = [leader.clone(), followers[2].clone(), followers[3].clone()];
+        cluster.wait_for_convergence(&healthy).await;
+        for node in &healthy[1..] {
+            assert_eq!(node.quorum(), 2);
+        }
+
+        // Followers report each removal as they append it.
+        let mut voters = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let RaftEvent::ConfigChanged { voters: now } = event {
+                voters.push(now.len());
+            }
+        }
+        assert_eq!(voters, [3, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_raft_leader_removing_itself_steps_down_once_committed() {
+        let cluster = TestCluster::start(3);
+        let leader = cluster.wait_for_leader().await;
+        let followers: Vec<_> =
+            cluster.nodes.iter().filter(|node| node.id() != leader.id()).cloned().collect();
+        cluster.wait_for_convergence(&cluster.nodes).await;
+        let mut events = leader.events();
+        let elections = leader.elections_started();
+
+        let index = leader.remove_voter(leader.id()).unwrap();
+        // It leads on, not counting itself, until both followers store the removal.
+        assert_eq!((leader.voters().len(), leader.quorum()), (2, 2));
+        wait_until(|| leader.role() != Role::Leader).await;
+        assert!(leader.commit_index() >= index);
+        let removed = std::iter::from_fn(|| events.try_recv().ok())
+            .any(|event| event == RaftEvent::Removed { index });
+        assert!(removed);
+        let err = leader.propose(Bytes::from_static(b"after removal")).unwrap_err();
+        assert!(NotLeader::from_io(&err).is_some(), "{}", err);
+
+        // The two left elect a leader between them, and the old one never stands again.
+        let old_term = leader.current_term();
+        wait_until(|| followers.iter().any(|node| node.role() == Role::Leader)).await;
+        let new_leader = followers.iter().find(|node| node.role() == Role::Leader).unwrap();
+        assert!(new_leader.current_term() > old_term);
+        let other = followers.iter().find(|node| node.id() != new_leader.id()).unwrap();
+        assert_eq!(new_leader.voters(), [other.id().to_string()]);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(leader.role(), Role::Follower);
+        assert_eq!(leader.elections_started(), elections);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_raft_bootstrap_leads_alone_then_grows_by_config_change() {
+        let dir = TempDir::new().unwrap();
+        let network = SimNetwork::new(1);
+        let paths = |id: &str| {
+            let state_path = dir.path().join(format!("{}.state", id));
+            (state_path, dir.path().join(format!("{}.wal", id)))
+        };
+
+        let (state_path, log_path) = paths("node-1");
+        let config = RaftConfig::new("node-1", BTreeMap::new());
+        let leader =
+            RaftNode::bootstrap(config, state_path, log_path, network.transport("node-1")).unwrap();
+        network.register(&leader);
+        // It leads before it even runs, and commits on its own.
+        assert_eq!((leader.role(), leader.current_term()), (Role::Leader, 1));
+        assert_eq!(leader.quorum(), 1);
+        let index = leader.propose(Bytes::from_static(b"command")).unwrap();
+        assert_eq!(leader.commit_index(), index);
+        let mut tasks = vec![leader.start()];
+
+        // The operator adds node-2, then starts it with node-1 as its peer.
+        let (state_path, log_path) = paths("node-2");
+        let config = RaftConfig::new("node-2", BTreeMap::from([("node-1".into(), "".into())]));
+        let joining =
+            RaftNode::new(config, state_path, log_path, network.transport("node-2")).unwrap();
+        network.register(&joining);
+        let added = leader.add_voter("node-2", "").unwrap();
+        assert_eq!(leader.voters(), vec!["node-2".to_string()]);
+        assert_eq!(leader.quorum(), 2);
+        let err = leader.add_voter("node-3", "").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ResourceBusy);
+        tasks.push(joining.start());
+
+        wait_until(|| joining.commit_index() >= added && leader.commit_index() >= added).await;
+        assert_eq!(log_of(&joining), log_of(&leader));
+        assert_eq!(leader.role(), Role::Leader);
+        assert_eq!(joining.leader_id().as_deref(), Some("node-1"));
+        let err = leader.add_voter("node-2", "").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        tasks.iter().for_each(JoinHandle::abort);
+    }
+
+    #[tokio::test]
+    async fn test_raft_commits_no_further_than_a_batched_flush_made_durable() {
+        let dir = TempDir::new().unwrap();
+        let network = SimNetwork::new(1);
+        let (state_path, log_path) = (dir.path().join("node-1.state"), dir.path().join("wal"));
+        let mut config = RaftConfig::new("node-1", BTreeMap::new());
+        config.wal_flush_interval = Some(Duration::from_millis(500));
+        let leader =
+            RaftNode::bootstrap(config, state_path, log_path, network.transport("node-1")).unwrap();
+        network.register(&leader);
+
+        // A quorum of one stores it, but until the flush it is not on disk here.
+        let index = leader.propose(Bytes::from_static(b"command")).unwrap();
+        assert_eq!(leader.last_log_index(), index);
+        assert!(leader.durable_index() < index);
+        assert!(leader.commit_index() < index);
+
+        let _task = leader.start();
+        wait_until(|| leader.commit_index() == index).await;
+        assert_eq!(leader.durable_index(), index);
+    }
+
+    #[tokio::test]
+    async fn test_raft_append_waits_for_a_slow_flush_without_blocking_the_runtime() {
+        let dir = TempDir::new().unwrap();
+        let peers = [("node-2".to_string(), String::new())].into();
+        let mut config = RaftConfig::new("node-1", peers);
+        config.wal_flush_interval = Some(Duration::from_millis(500));
+        let (state_path, log_path) = (dir.path().join("node-1.state"), dir.path().join("wal"));
+        let transport = SimNetwork::new(0).transport("node-1");
+        let node = RaftNode::new(config, state_path, log_path, transport).unwrap();
+
+        // The test runs on a single thread, so the ticks only add up if waiting for the
+        // flush leaves it free.
+        let ticks = Arc::new(AtomicU64::new(0));
+        let ticker = tokio::spawn({
+            let ticks = ticks.clone();
+            async move {
+                loop {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    ticks.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
+
+        let response = node.handle_append_entries(append(1, (0, 0), &[(1, 1)], 1)).await;
+        assert!(response.unwrap().success);
+        assert_eq!((node.durable_index(), node.commit_index()), (1, 1));
+        assert!(ticks.load(Ordering::Relaxed) >= 3, "{:?}", ticks);
+        ticker.abort();
+    }
+
+    #[test]
+    fn test_raft_refuses_to_bootstrap_over_an_existing_log() {
+        let dir = TempDir::new().unwrap();
+        let network = SimNetwork::new(1);
+        let state_path = dir.path().join("node-1.state");
+        let log_path = dir.path().join("node-1.wal");
+        let bootstrap = || {
+            let config = RaftConfig::new("node-1", BTreeMap::new());
+            RaftNode::bootstrap(config, &state_path, &log_path, network.transport("node-1"))
+        };
+
+        let node = bootstrap().unwrap();
+        node.propose(Bytes::from_static(b"command")).unwrap();
+        drop(node);
+        let err = bootstrap().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert!(err.to_string().contains("node-1.state"), "{err}");
+        // The existing log and term were left alone.
+        let config = RaftConfig::new("node-1", BTreeMap::new());
+        let node = RaftNode::new(config, &state_path, &log_path, network.transport("node-1"));
+        let node = node.unwrap();
+        assert_eq!((node.current_term(), node.last_log_index()), (1, 1));
+
+        // A node meant to join a cluster cannot bootstrap one.
+        let peers = BTreeMap::from([("node-2".to_string(), String::new())]);
+        let config = RaftConfig::new("node-3", peers);
+        let other = dir.path().join("node-3");
+        let err = RaftNode::bootstrap(config, &other, &other, network.transport("node-3"));
+        assert_eq!(err.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
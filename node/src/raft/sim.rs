@@ -0,0 +1,368 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use rand::rngs::StdRng;
+use rand::{RngExt, SeedableRng};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+use tonic::Status;
+use raft_core::raft::{
+    AppendEntriesRequest, AppendEntriesResponse, InstallSnapshotRequest,
+    InstallSnapshotResponse, ReadIndexRequest, ReadIndexResponse, RequestVoteRequest,
+    RequestVoteResponse, StreamEntriesRequest, TimeoutNowRequest, TimeoutNowResponse,
+};
+use crate::clock::{Clock, SystemClock};
+use crate::raft::{EntryStream, RaftNode, RaftTransport};
+
+/// An in-process network that delivers RPCs by calling the target node directly, so a
+/// whole cluster can run in one test. Messages can be delayed, dropped at random, or cut
+/// off by partitions. The randomness comes from a seed, so a failing run can be repeated.
+pub struct SimNetwork {
+    nodes: Mutex<BTreeMap<String, Weak<RaftNode>>>,
+    clock: Arc<dyn Clock>,
+    faults: Mutex<Faults>,
+}
+
+struct Faults {
+    rng: StdRng,
+    /// One-way delay of each message, picked uniformly from this range.
+    latency: (Duration, Duration),
+    /// Delay of each message to or from a node, by id, in place of `latency`.
+    node_latency: BTreeMap<String, (Duration, Duration)>,
+    /// Chance that each request, and separately each response, is lost.
+    drop_rate: f64,
+    /// Node pairs that cannot reach each other, stored with the lower id first.
+    cut: BTreeSet<(String, String)>,
+    isolated: BTreeSet<String>,
+}
+
+impl SimNetwork {
+    /// A network that delivers every message at once, until faults are configured.
+    pub fn new(seed: u64) -> Arc<Self> {
+        Self::new_with_clock(seed, Arc::new(SystemClock))
+    }
+
+    /// Like `new`, delaying messages with `clock`.
+    pub fn new_with_clock(seed: u64, clock: Arc<dyn Clock>) -> Arc<Self> {
+        Arc::new(Self {
+            nodes: Mutex::new(BTreeMap::new()),
+            clock,
+            faults: Mutex::new(Faults {
+                rng: StdRng::seed_from_u64(seed),
+                latency: (Duration::ZERO, Duration::ZERO),
+                node_latency: BTreeMap::new(),
+                drop_rate: 0.0,
+                cut: BTreeSet::new(),
+                isolated: BTreeSet::new(),
+            }),
+        })
+    }
+
+    /// The transport `from` sends its RPCs through.
+    pub fn transport(self: &Arc<Self>, from: &str) -> Arc<SimTransport> {
+        Arc::new(SimTransport {
+            from: from.to_string(),
+            network: self.clone(),
+        })
+    }
+
+    /// Delivers RPCs addressed to `node`'s id to it. The network does not keep it alive.
+    pub fn register(&self, node: &Arc<RaftNode>) {
+        self.nodes.lock().unwrap().insert(node.id().to_string(), Arc::downgrade(node));
+    }
+
+    pub fn set_latency(&self, min: Duration, max: Duration) {
+        self.faults.lock().unwrap().latency = (min, max.max(min));
+    }
+
+    /// Delays each message to or from `id` by `min` to `max` instead of the latency of the
+    /// rest of the network, e.g. to slow down a single follower.
+    pub fn set_node_latency(&self, id: &str, min: Duration, max: Duration) {
+        let mut faults = self.faults.lock().unwrap();
+        faults.node_latency.insert(id.to_string(), (min, max.max(min)));
+    }
+
+    /// Gives `id` the latency of the rest of the network again.
+    pub fn clear_node_latency(&self, id: &str) {
+        self.faults.lock().unwrap().node_latency.remove(id);
+    }
+
+    pub fn set_drop_rate(&self, rate: f64) {
+        self.faults.lock().unwrap().drop_rate = rate.clamp(0.0, 1.0);
+    }
+
+    /// Cuts every link between a node in `group_a` and a node in `group_b`, both ways.
+    pub fn partition(&self, group_a: &[&str], group_b: &[&str]) {
+        let mut faults = self.faults.lock().unwrap();
+        for a in group_a {
+            for b in group_b {
+                faults.cut.insert(link(a, b));
+            }
+        }
+    }
+
+    /// Cuts `id` off from every other node.
+    pub fn isolate(&self, id: &str) {
+        self.faults.lock().unwrap().isolated.insert(id.to_string());
+    }
+
+    /// Removes every partition and isolation. Latency and drops stay as configured.
+    pub fn heal(&self) {
+        let mut faults = self.faults.lock().unwrap();
+        faults.cut.clear();
+        faults.isolated.clear();
+    }
+
+    /// Carries one message from `from` to `to`: waits out its latency, then fails if
+    /// the link is down or the message is dropped.
+    async fn deliver(&self, from: &str, to: &str) -> Result<(), Status> {
+        let (delay, dropped) = {
+            let mut faults = self.faults.lock().unwrap();
+            let (min, max) = [from, to]
+                .iter()
+                .find_map(|id| faults.node_latency.get(*id).copied())
+                .unwrap_or(faults.latency);
+            let delay = if max > min { faults.rng.random_range(min..=max) } else { min };
+            let drop_rate = faults.drop_rate;
+            (delay, drop_rate > 0.0 && faults.rng.random_bool(drop_rate))
+        };
+        if !delay.is_zero() {
+            self.clock.sleep(delay).await;
+        }
+
+        let faults = self.faults.lock().unwrap();
+        let reachable = !faults.isolated.contains(from)
+            && !faults.isolated.contains(to)
+            && !faults.cut.contains(&link(from, to));
+        if !reachable || dropped {
+            return Err(Status::unavailable(format!("{} cannot reach {}", from, to)));
+        }
+        Ok(())
+    }
+
+    async fn route(&self, from: &str, to: &str) -> Result<Arc<RaftNode>, Status> {
+        self.deliver(from, to).await?;
+        self.nodes
+            .lock()
+            .unwrap()
+            .get(to)
+            .and_then(Weak::upgrade)
+            .ok_or_else(|| Status::unavailable(format!("{} is not running", to)))
+    }
+}
+
+impl std::fmt::Debug for SimNetwork {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SimNetwork")
+            .field("nodes", &self.nodes.lock().unwrap().keys().collect::<Vec<_>>())
+            .finish_non_exhaustive()
+    }
+}
+
+fn link(a: &str, b: &str) -> (String, String) {
+    if a <= b {
+        (a.to_string(), b.to_string())
+    } else {
+        (b.to_string(), a.to_string())
+    }
+}
+
+/// One node's view of a `SimNetwork`.
+#[derive(Debug)]
+pub struct SimTransport {
+    from: String,
+    network: Arc<SimNetwork>,
+}
+
+#[tonic::async_trait]
+impl RaftTransport for SimTransport {
+    async fn request_vote(
+        &self,
+        peer: &str,
+        request: RequestVoteRequest,
+    ) -> Result<RequestVoteResponse, Status> {
+        let node = self.network.route(&self.from, peer).await?;
+        let response = node.handle_request_vote(request);
+        self.network.deliver(peer, &self.from).await?;
+        response.map_err(|e| Status::internal(e.to_string()))
+    }
+
+    async fn append_entries(
+        &self,
+        peer: &str,
+        request: AppendEntriesRequest,
+    ) -> Result<AppendEntriesResponse, Status> {
+        let node = self.network.route(&self.from, peer).await?;
+        let response = node.handle_append_entries(request).await;
+        self.network.deliver(peer, &self.from).await?;
+        response.map_err(|e| Status::internal(e.to_string()))
+    }
+
+    async fn stream_entries(
+        &self,
+        peer: &str,
+        request: StreamEntriesRequest,
+    ) -> Result<EntryStream, Status> {
+        let node = self.network.route(&self.from, peer).await?;
+        let entries = node.stream_entries(request).map_err(|e| Status::internal(e.to_string()));
+        self.network.deliver(peer, &self.from).await?;
+        let mut entries = entries?;
+
+        // Each message crosses the network on its own, so the stream breaks with the link.
+        let (tx, rx) = mpsc::channel(1);
+        let (network, from, peer) = (self.network.clone(), self.from.clone(), peer.to_string());
+        tokio::spawn(async move {
+            while let Some(message) = entries.next().await {
+                let message = match network.deliver(&peer, &from).await {
+                    Ok(()) => message,
+                    Err(status) => Err(status),
+                };
+                let failed = message.is_err();
+                if tx.send(message).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Ok(Box::pin(ReceiverStream::new(rx)))
+    }
+
+    async fn read_index(
+        &self,
+        peer: &str,
+        request: ReadIndexRequest,
+    ) -> Result<ReadIndexResponse, Status> {
+        let node = self.network.route(&self.from, peer).await?;
+        let response = node.handle_read_index(request).await;
+        self.network.deliver(peer, &self.from).await?;
+        response.map_err(|e| Status::internal(e.to_string()))
+    }
+
+    async fn timeout_now(
+        &self,
+        peer: &str,
+        request: TimeoutNowRequest,
+    ) -> Result<TimeoutNowResponse, Status> {
+        let node = self.network.route(&self.from, peer).await?;
+        let response = node.handle_timeout_now(request);
+        self.network.deliver(peer, &self.from).await?;
+        response.map_err(|e| Status::internal(e.to_string()))
+    }
+
+    async fn install_snapshot(
+        &self,
+        peer: &str,
+        request: InstallSnapshotRequest,
+    ) -> Result<InstallSnapshotResponse, Status> {
+        let node = self.network.route(&self.from, peer).await?;
+        let response = node.handle_install_snapshot(request).await;
+        self.network.deliver(peer, &self.from).await?;
+        response.map_err(|e| Status::internal(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use raft_core::raft::NodeId;
+    use crate::raft::RaftConfig;
+
+    fn vote_request(candidate: &str) -> RequestVoteRequest {
+        RequestVoteRequest {
+            term: 1,
+            candidate_id: Some(NodeId {
+                id: candidate.to_string(),
+            }),
+            last_log_index: 0,
+            last_log_term: 0,
+        }
+    }
+
+    /// Registers idle nodes `a`, `b` and `c`, kept alive by the returned handles.
+    fn nodes(network: &Arc<SimNetwork>, dir: &TempDir) -> Vec<Arc<RaftNode>> {
+        ["a", "b", "c"]
+            .into_iter()
+            .map(|id| {
+                let config = RaftConfig::new(id, BTreeMap::new());
+                let state_path = dir.path().join(format!("{}.state", id));
+                let log_path = dir.path().join(format!("{}.wal", id));
+                let node =
+                    RaftNode::new(config, state_path, log_path, network.transport(id)).unwrap();
+                network.register(&node);
+                node
+            })
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sim_network_partition_and_heal() {
+        let dir = TempDir::new().unwrap();
+        let network = SimNetwork::new(0);
+        let _nodes = nodes(&network, &dir);
+        let (a, b, c) = (network.transport("a"), network.transport("b"), network.transport("c"));
+
+        network.partition(&["a"], &["b", "c"]);
+        assert!(a.request_vote("b", vote_request("a")).await.is_err());
+        assert!(c.request_vote("a", vote_request("c")).await.is_err());
+        assert!(b.request_vote("c", vote_request("b")).await.is_ok());
+
+        network.heal();
+        assert!(a.request_vote("b", vote_request("a")).await.is_ok());
+
+        network.isolate("c");
+        assert!(b.request_vote("c", vote_request("b")).await.is_err());
+        assert!(b.request_vote("a", vote_request("b")).await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sim_network_latency_and_drops() {
+        let dir = TempDir::new().unwrap();
+        let network = SimNetwork::new(7);
+        let _nodes = nodes(&network, &dir);
+        let a = network.transport("a");
+
+        // A round trip pays the one-way latency twice.
+        network.set_latency(Duration::from_millis(10), Duration::from_millis(10));
+        let started = tokio::time::Instant::now();
+        a.request_vote("b", vote_request("a")).await.unwrap();
+        assert_eq!(started.elapsed(), Duration::from_millis(20));
+
+        // A slow node delays only the messages to and from it.
+        network.set_node_latency("c", Duration::from_millis(100), Duration::from_millis(100));
+        let started = tokio::time::Instant::now();
+        a.request_vote("c", vote_request("a")).await.unwrap();
+        assert_eq!(started.elapsed(), Duration::from_millis(200));
+        let started = tokio::time::Instant::now();
+        a.request_vote("b", vote_request("a")).await.unwrap();
+        assert_eq!(started.elapsed(), Duration::from_millis(20));
+        network.clear_node_latency("c");
+
+        network.set_drop_rate(1.0);
+        assert!(a.request_vote("b", vote_request("a")).await.is_err());
+
+        // The same seed drops the same messages.
+        let outcomes = |seed| async move {
+            let dir = TempDir::new().unwrap();
+            let network = SimNetwork::new(seed);
+            let _nodes = nodes(&network, &dir);
+            network.set_drop_rate(0.5);
+            let a = network.transport("a");
+            let mut outcomes = Vec::new();
+            for _ in 0..20 {
+                outcomes.push(a.request_vote("b", vote_request("a")).await.is_ok());
+            }
+            outcomes
+        };
+        let first = outcomes(3).await;
+        assert_eq!(first, outcomes(3).await);
+        assert!(first.contains(&true) && first.contains(&false));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sim_network_unregistered_node_is_unreachable() {
+        let network = SimNetwork::new(0);
+        let status = network.transport("a").request_vote("b", vote_request("a")).await;
+        assert_eq!(status.unwrap_err().code(), tonic::Code::Unavailable);
+    }
+}
@@ -0,0 +1,414 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use bytes::{Buf, Bytes};
+use prost::Message;
+use raft_core::raft::{ConfigChange, InstallSnapshotRequest, NodeId};
+use crate::wal::{crc32, ChainHash, CompactionPoint};
+
+/// Bytes of snapshot a leader sends in one InstallSnapshot by default.
+pub const DEFAULT_SNAPSHOT_CHUNK_BYTES: u64 = 1024 * 1024;
+
+/// InstallSnapshots a node handles at once by default.
+pub const DEFAULT_MAX_SNAPSHOT_INSTALLS: u32 = 1;
+
+/// Names the files of a snapshot being received, and nothing else in its directory.
+const ASSEMBLY_PREFIX: &str = "install-";
+
+/// The state committed entries are applied to, as a Raft node sees it: what a leader
+/// sends a peer whose entries it has compacted away, and what such a peer installs.
+pub trait SnapshotStore: Send + Sync + 'static {
+    /// The last snapshot saved, and the index of the last entry it covers, or `None` if
+    /// none was.
+    fn latest(&self) -> std::io::Result<Option<(u64, Bytes)>>;
+
+    /// Replaces the state with the snapshot in the file at `path`, which covers the log
+    /// up to and including the entry `last` stands for. The file is the store's to keep
+    /// or remove.
+    fn install(&self, last: CompactionPoint, path: &Path) -> std::io::Result<()>;
+}
+
+/// The voters entries added and removed, by the index of the entry. Once the log is
+/// compacted, the entries no longer say so, so the changes they made are saved next to
+/// it, and sent along with a snapshot that covers them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ConfigChanges {
+    pub(crate) removals: BTreeMap<u64, String>,
+    pub(crate) additions: BTreeMap<u64, (String, String)>,
+}
+
+impl ConfigChanges {
+    /// Path of the file keeping the changes compacted out of the log at `log_path`.
+    pub(crate) fn path_for(log_path: &Path) -> PathBuf {
+        let mut path = log_path.as_os_str().to_owned();
+        path.push(".voters");
+        PathBuf::from(path)
+    }
+
+    /// The changes made by the entries up to `index`.
+    pub(crate) fn up_to(
+        removals: &BTreeMap<u64, String>,
+        additions: &BTreeMap<u64, (String, String)>,
+        index: u64,
+    ) -> Self {
+        Self {
+            removals: removals.range(..=index).map(|(&i, id)| (i, id.clone())).collect(),
+            additions: additions.range(..=index).map(|(&i, peer)| (i, peer.clone())).collect(),
+        }
+    }
+
+    /// Loads the changes saved at `path`, or none if nothing was compacted yet.
+    pub(crate) fn load(path: &Path) -> std::io::Result<Self> {
+        let contents = match std::fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        let mut reader = contents.as_slice();
+        let mut changes = Vec::new();
+        while reader.has_remaining() {
+            let change = ConfigChange::decode_length_delimited(&mut reader)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            changes.push(change);
+        }
+        Ok(Self::from_pb(changes))
+    }
+
+    /// Atomically replaces the file at `path` with these changes.
+    pub(crate) fn save(&self, path: &Path) -> std::io::Result<()> {
+        let mut contents = Vec::new();
+        for change in self.to_pb() {
+            change.encode_length_delimited(&mut contents).map_err(std::io::Error::other)?;
+        }
+        save_atomically(path, &contents)
+    }
+
+    pub(crate) fn to_pb(&self) -> Vec<ConfigChange> {
+        let removals = self.removals.iter().map(|(&index, id)| ConfigChange {
+            index,
+            node_id: id.clone(),
+            addr: String::new(),
+            removed: true,
+        });
+        let additions = self.additions.iter().map(|(&index, (id, addr))| ConfigChange {
+            index,
+            node_id: id.clone(),
+            addr: addr.clone(),
+            removed: false,
+        });
+        removals.chain(additions).collect()
+    }
+
+    pub(crate) fn from_pb(changes: Vec<ConfigChange>) -> Self {
+        let mut config = Self::default();
+        for change in changes {
+            if change.removed {
+                config.removals.insert(change.index, change.node_id);
+            } else {
+                config.additions.insert(change.index, (change.node_id, change.addr));
+            }
+        }
+        config
+    }
+}
+
+/// A snapshot a leader sends a peer, a chunk at a time.
+#[derive(Debug)]
+pub(crate) struct OutgoingSnapshot {
+    last: CompactionPoint,
+    data: Bytes,
+    crc: u32,
+    config: ConfigChanges,
+}
+
+impl OutgoingSnapshot {
+    /// `data`, covering the log up to the entry `last` stands for, where `config` holds
+    /// the voters changed up to there.
+    pub(crate) fn new(last: CompactionPoint, data: Bytes, config: ConfigChanges) -> Self {
+        let crc = crc32(&data);
+        Self { last, data, crc, config }
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    /// The InstallSnapshot a leader of `term` sends with up to `chunk_bytes` of the
+    /// snapshot from `offset`. The last chunk is `done`; an empty one asks the peer where
+    /// it got to.
+    pub(crate) fn request(
+        &self,
+        term: u64,
+        leader_id: NodeId,
+        offset: u64,
+        chunk_bytes: u64,
+    ) -> InstallSnapshotRequest {
+        let start = offset.min(self.len()) as usize;
+        let end = offset.saturating_add(chunk_bytes).min(self.len()) as usize;
+        let chunk = self.data.slice(start..end);
+        InstallSnapshotRequest {
+            term,
+            leader_id: Some(leader_id),
+            last_included_index: self.last.index,
+            last_included_term: self.last.term,
+            last_included_hash: self.last.chain_hash.to_vec(),
+            chunk_crc: crc32(&chunk),
+            done: end as u64 == self.len(),
+            snapshot_chunk: chunk.to_vec(),
+            offset,
+            snapshot_crc: self.crc,
+            config_changes: self.config.to_pb(),
+        }
+    }
+}
+
+/// The last entry the snapshot an InstallSnapshot carries covers.
+pub(crate) fn last_included(request: &InstallSnapshotRequest) -> std::io::Result<CompactionPoint> {
+    let chain_hash: ChainHash = request.last_included_hash.as_slice().try_into().map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("snapshot chain hash is {} bytes", request.last_included_hash.len()),
+        )
+    })?;
+    Ok(CompactionPoint {
+        index: request.last_included_index,
+        term: request.last_included_term,
+        chain_hash,
+    })
+}
+
+/// A snapshot being received into a file in `dir`, chunk by chunk. How much of it is on
+/// disk is saved next to it after each chunk, so a transfer cut off, even by a restart,
+/// picks up from there instead of from the first byte.
+#[derive(Debug)]
+pub(crate) struct SnapshotAssembly {
+    index: u64,
+    term: u64,
+    dir: PathBuf,
+    file: File,
+    /// Bytes of the snapshot received so far.
+    offset: u64,
+}
+
+impl SnapshotAssembly {
+    /// Picks up the snapshot covering up to `index` at `term` in `dir` from where it was
+    /// left, or starts it, removing what is left of any other snapshot received there.
+    pub(crate) fn open(dir: &Path, index: u64, term: u64) -> std::io::Result<Self> {
+        let name = assembly_name(index, term);
+        let own = format!("{}.", name);
+        for entry in std::fs::read_dir(dir)? {
+            let entry_name = entry?.file_name();
+            let entry_name = entry_name.to_string_lossy();
+            if entry_name.starts_with(ASSEMBLY_PREFIX) && !entry_name.starts_with(&own) {
+                std::fs::remove_file(dir.join(&*entry_name))?;
+            }
+        }
+
+        let offset = match File::open(dir.join(format!("{}.offset", name))) {
+            Ok(mut file) => file.read_u64::<LittleEndian>()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        let path = dir.join(format!("{}.partial", name));
+        let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&path)?;
+        // Bytes past the saved offset were written but never recorded; they come again.
+        file.set_len(offset)?;
+        file.flush()?;
+        Ok(Self {
+            index,
+            term,
+            dir: dir.to_path_buf(),
+            file,
+            offset,
+        })
+    }
+
+    /// Whether this is the snapshot covering up to `index` at `term`.
+    pub(crate) fn is_for(&self, index: u64, term: u64) -> bool {
+        (self.index, self.term) == (index, term)
+    }
+
+    /// Bytes of the snapshot received so far, where the next chunk goes.
+    pub(crate) fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Adds `chunk` if it goes at `offset`, where the snapshot has got to, and its CRC-32
+    /// is `crc`, and returns where the snapshot has got to. A chunk for anywhere else, or
+    /// damaged on the way, is dropped, and the leader sends it again from there.
+    pub(crate) fn write_chunk(
+        &mut self,
+        offset: u64,
+        chunk: &[u8],
+        crc: u32,
+    ) -> std::io::Result<u64> {
+        if offset != self.offset || crc32(chunk) != crc || chunk.is_empty() {
+            return Ok(self.offset);
+        }
+        self.file.write_all(chunk)?;
+        self.file.sync_data()?;
+        self.save_offset(offset + chunk.len() as u64)?;
+        Ok(self.offset)
+    }
+
+    /// Checks the whole snapshot received against `crc`, and returns the file it is in,
+    /// which is the caller's from then on. Fails with `InvalidData` if it does not match,
+    /// starting the snapshot over.
+    pub(crate) fn finish(&mut self, crc: u32) -> std::io::Result<PathBuf> {
+        let name = assembly_name(self.index, self.term);
+        let partial = self.dir.join(format!("{}.partial", name));
+        let received = crc32(&std::fs::read(&partial)?);
+        if received != crc {
+            self.file.set_len(0)?;
+            self.save_offset(0)?;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "snapshot at {} has CRC {:08x}, not {:08x}; receiving it again",
+                    self.index, received, crc
+                ),
+            ));
+        }
+        let path = self.dir.join(format!("{}.snapshot", name));
+        std::fs::rename(&partial, &path)?;
+        std::fs::remove_file(self.dir.join(format!("{}.offset", name)))?;
+        Ok(path)
+    }
+
+    fn save_offset(&mut self, offset: u64) -> std::io::Result<()> {
+        let path = self.dir.join(format!("{}.offset", assembly_name(self.index, self.term)));
+        let mut contents = Vec::new();
+        contents.write_u64::<LittleEndian>(offset)?;
+        save_atomically(&path, &contents)?;
+        self.offset = offset;
+        Ok(())
+    }
+}
+
+/// Names the files of the snapshot covering up to `index` at `term` as it is received.
+fn assembly_name(index: u64, term: u64) -> String {
+    format!("{}{}-{}", ASSEMBLY_PREFIX, index, term)
+}
+
+/// Replaces the file at `path` with `contents`, syncing them before renaming them in.
+fn save_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(contents)?;
+        file.sync_data()?;
+    }
+
+    std::fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn snapshot(len: usize) -> OutgoingSnapshot {
+        let data: Vec<u8> = (0..len).map(|i| (i * 7 % 251) as u8).collect();
+        let last = CompactionPoint {
+            index: 5,
+            term: 2,
+            ..CompactionPoint::default()
+        };
+        OutgoingSnapshot::new(last, Bytes::from(data), ConfigChanges::default())
+    }
+
+    fn send(assembly: &mut SnapshotAssembly, request: &InstallSnapshotRequest) -> u64 {
+        let chunk = &request.snapshot_chunk;
+        assembly.write_chunk(request.offset, chunk, request.chunk_crc).unwrap()
+    }
+
+    #[test]
+    fn test_snapshot_assembly_resumes_from_the_offset_it_saved() {
+        let dir = TempDir::new().unwrap();
+        let outgoing = snapshot(100);
+        let leader = NodeId { id: "node-1".to_string() };
+
+        let mut assembly = SnapshotAssembly::open(dir.path(), 5, 2).unwrap();
+        let first = outgoing.request(3, leader.clone(), 0, 40);
+        assert_eq!(send(&mut assembly, &first), 40);
+        drop(assembly);
+
+        // Reopened, as after a restart, it asks for what it lacks, not the first chunk.
+        let mut assembly = SnapshotAssembly::open(dir.path(), 5, 2).unwrap();
+        assert_eq!(assembly.offset(), 40);
+        assert_eq!(send(&mut assembly, &first), 40);
+        let mut offset = 40;
+        while offset < outgoing.len() {
+            offset = send(&mut assembly, &outgoing.request(3, leader.clone(), offset, 40));
+        }
+        let last = outgoing.request(3, leader, 80, 40);
+        assert!(last.done);
+
+        let path = assembly.finish(last.snapshot_crc).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), outgoing.data.to_vec());
+        assert_eq!(crc32(&std::fs::read(&path).unwrap()), outgoing.crc);
+    }
+
+    #[test]
+    fn test_snapshot_assembly_drops_a_damaged_chunk() {
+        let dir = TempDir::new().unwrap();
+        let outgoing = snapshot(100);
+        let leader = NodeId { id: "node-1".to_string() };
+        let mut assembly = SnapshotAssembly::open(dir.path(), 5, 2).unwrap();
+
+        let mut damaged = outgoing.request(3, leader.clone(), 0, 40);
+        damaged.snapshot_chunk[7] ^= 0x01;
+        assert_eq!(send(&mut assembly, &damaged), 0);
+        assert_eq!(send(&mut assembly, &outgoing.request(3, leader, 0, 40)), 40);
+    }
+
+    #[test]
+    fn test_snapshot_assembly_starts_over_if_the_whole_snapshot_does_not_match() {
+        let dir = TempDir::new().unwrap();
+        let outgoing = snapshot(10);
+        let leader = NodeId { id: "node-1".to_string() };
+        let mut assembly = SnapshotAssembly::open(dir.path(), 5, 2).unwrap();
+        let request = outgoing.request(3, leader, 0, 40);
+        assert_eq!(send(&mut assembly, &request), 10);
+
+        let e = assembly.finish(request.snapshot_crc ^ 1).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(assembly.offset(), 0);
+        assert_eq!(SnapshotAssembly::open(dir.path(), 5, 2).unwrap().offset(), 0);
+    }
+
+    #[test]
+    fn test_snapshot_assembly_removes_the_leftovers_of_another_snapshot() {
+        let dir = TempDir::new().unwrap();
+        let leader = NodeId { id: "node-1".to_string() };
+        let mut assembly = SnapshotAssembly::open(dir.path(), 5, 2).unwrap();
+        send(&mut assembly, &snapshot(100).request(3, leader, 0, 40));
+
+        let assembly = SnapshotAssembly::open(dir.path(), 9, 3).unwrap();
+        assert_eq!(assembly.offset(), 0);
+        let mut names: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["install-9-3.partial"]);
+    }
+
+    #[test]
+    fn test_config_changes_roundtrip_through_their_file() {
+        let dir = TempDir::new().unwrap();
+        let path = ConfigChanges::path_for(&dir.path().join("raft.wal"));
+        assert_eq!(ConfigChanges::load(&path).unwrap(), ConfigChanges::default());
+
+        let mut config = ConfigChanges::default();
+        config.removals.insert(4, "node-2".to_string());
+        config.additions.insert(7, ("node-4".to_string(), "10.0.0.4:50051".to_string()));
+        config.save(&path).unwrap();
+        assert_eq!(ConfigChanges::load(&path).unwrap(), config);
+        assert_eq!(ConfigChanges::from_pb(config.to_pb()), config);
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::BTreeMap;
+
+/// Where a member runs, as it announces it through gossip. Either part may be empty when
+/// the member did not say.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Topology {
+    pub region: String,
+    pub rack: String,
+}
+
+impl Topology {
+    pub fn new(region: impl Into<String>, rack: impl Into<String>) -> Self {
+        Self {
+            region: region.into(),
+            rack: rack.into(),
+        }
+    }
+
+    /// How far a member at `other` is from one here: 0 on the same rack of the same
+    /// region, 1 in the same region, and 2 anywhere else or where either region is unknown.
+    pub fn distance(&self, other: &Topology) -> u8 {
+        if self.region.is_empty() || self.region != other.region {
+            return 2;
+        }
+        match !self.rack.is_empty() && self.rack == other.rack {
+            true => 0,
+            false => 1,
+        }
+    }
+}
+
+/// Orders `peers` nearest to `local` first, by where `topology` says each runs. Peers at
+/// the same distance keep their order, so without any topology the order is unchanged.
+pub(crate) fn nearest_first(
+    local: &Topology,
+    topology: &BTreeMap<String, Topology>,
+    peers: &mut [String],
+) {
+    let unknown = Topology::default();
+    peers.sort_by_key(|peer| local.distance(topology.get(peer).unwrap_or(&unknown)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topology_distance() {
+        let here = Topology::new("eu-west", "rack-1");
+        assert_eq!(here.distance(&Topology::new("eu-west", "rack-1")), 0);
+        assert_eq!(here.distance(&Topology::new("eu-west", "rack-2")), 1);
+        assert_eq!(here.distance(&Topology::new("eu-west", "")), 1);
+        assert_eq!(here.distance(&Topology::new("us-east", "rack-1")), 2);
+        assert_eq!(here.distance(&Topology::default()), 2);
+        assert_eq!(Topology::default().distance(&Topology::default()), 2);
+        assert_eq!(Topology::new("eu-west", "").distance(&Topology::new("eu-west", "")), 1);
+    }
+
+    #[test]
+    fn test_nearest_first_orders_by_distance_and_keeps_ties() {
+        let topology = BTreeMap::from([
+            ("node-2".to_string(), Topology::new("us-east", "rack-1")),
+            ("node-3".to_string(), Topology::new("eu-west", "rack-2")),
+            ("node-5".to_string(), Topology::new("eu-west", "rack-1")),
+        ]);
+        let mut peers: Vec<String> =
+            ["node-2", "node-3", "node-4", "node-5"].map(String::from).into();
+
+        nearest_first(&Topology::new("eu-west", "rack-1"), &topology, &mut peers);
+        assert_eq!(peers, ["node-5", "node-3", "node-2", "node-4"]);
+
+        // Knowing nothing of where this node runs leaves the order as it was.
+        let mut unordered = peers.clone();
+        nearest_first(&Topology::default(), &topology, &mut unordered);
+        assert_eq!(unordered, peers);
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio_stream::Stream;
+use tonic::transport::Channel;
+use tonic::Status;
+use tracing::debug;
+use raft_core::raft::raft_client::RaftClient;
+use raft_core::raft::{
+    AppendEntriesRequest, AppendEntriesResponse, InstallSnapshotRequest,
+    InstallSnapshotResponse, ReadIndexRequest, ReadIndexResponse, RequestVoteRequest,
+    RequestVoteResponse, StreamEntriesRequest, TimeoutNowRequest, TimeoutNowResponse,
+};
+use crate::transport::{self, ChannelTuning, Keepalive, ReconnectingChannel, TlsConfig};
+
+/// The batches of entries a leader streams to a follower catching up, each sent as the
+/// AppendEntries that would carry it.
+pub type EntryStream = Pin<Box<dyn Stream<Item = Result<AppendEntriesRequest, Status>> + Send>>;
+
+/// How a `RaftNode` reaches its peers. Peers are addressed by node id.
+#[tonic::async_trait]
+pub trait RaftTransport: Send + Sync + 'static {
+    async fn request_vote(
+        &self,
+        peer: &str,
+        request: RequestVoteRequest,
+    ) -> Result<RequestVoteResponse, Status>;
+
+    async fn append_entries(
+        &self,
+        peer: &str,
+        request: AppendEntriesRequest,
+    ) -> Result<AppendEntriesResponse, Status>;
+
+    /// Asks the leader `peer` for every entry from `request.from_index` on.
+    async fn stream_entries(
+        &self,
+        peer: &str,
+        request: StreamEntriesRequest,
+    ) -> Result<EntryStream, Status>;
+
+    /// Asks the leader `peer` for the index a linearizable read must wait for.
+    async fn read_index(
+        &self,
+        peer: &str,
+        request: ReadIndexRequest,
+    ) -> Result<ReadIndexResponse, Status>;
+
+    /// Asks `peer` to start an election at once, handing it leadership.
+    async fn timeout_now(
+        &self,
+        peer: &str,
+        request: TimeoutNowRequest,
+    ) -> Result<TimeoutNowResponse, Status>;
+
+    /// Sends `peer` a chunk of a snapshot of the leader's state.
+    async fn install_snapshot(
+        &self,
+        peer: &str,
+        request: InstallSnapshotRequest,
+    ) -> Result<InstallSnapshotResponse, Status>;
+
+    /// Learns that `peer`, which joined the voters, is reached at `addr`.
+    fn add_peer(&self, _peer: &str, _addr: &str) {}
+}
+
+/// Opens a channel to a peer, given its address.
+type Dial = Box<dyn Fn(&str) -> Result<Channel, Status> + Send + Sync>;
+
+/// Talks to peers over gRPC, keeping one lazily connected channel per peer, which is
+/// replaced after a backoff if its connection fails.
+pub struct GrpcTransport {
+    peers: Mutex<BTreeMap<String, String>>,
+    dial: Dial,
+    channels: Mutex<HashMap<String, Arc<ReconnectingChannel>>>,
+}
+
+impl GrpcTransport {
+    /// Dials peers over TCP, or TLS when `tls` is set, keeping connections alive with
+    /// `keepalive` and tuned as `tuning` says.
+    pub fn new(
+        peers: BTreeMap<String, String>,
+        tls: Option<TlsConfig>,
+        keepalive: Keepalive,
+        tuning: ChannelTuning,
+    ) -> Self {
+        Self::with_dialer(peers, move |addr| {
+            let endpoint = transport::peer_endpoint(addr, tls.as_ref(), keepalive, tuning)
+                .map_err(|e| Status::invalid_argument(format!("bad address {}: {}", addr, e)))?;
+            Ok(endpoint.connect_lazy())
+        })
+    }
+
+    /// Like `new`, opening the channel to each peer with `dial`, given its address.
+    pub fn with_dialer(
+        peers: BTreeMap<String, String>,
+        dial: impl Fn(&str) -> Result<Channel, Status> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            peers: Mutex::new(peers),
+            dial: Box::new(dial),
+            channels: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Makes `call` with a client for `peer`, noting whether it got through.
+    async fn call<T, F>(
+        &self,
+        peer: &str,
+        call: impl FnOnce(RaftClient<Channel>) -> F,
+    ) -> Result<T, Status>
+    where
+        F: Future<Output = Result<tonic::Response<T>, Status>>,
+    {
+        let channel = self.channel(peer)?;
+        let client = RaftClient::new(channel.get(|| {
+            let addr = self.peers.lock().unwrap()[peer].clone();
+            debug!(peer, addr, "connecting to peer");
+            (self.dial)(&addr)
+        })?);
+        let result = call(client).await;
+        channel.record(&result);
+        result.map(tonic::Response::into_inner)
+    }
+
+    fn channel(&self, peer: &str) -> Result<Arc<ReconnectingChannel>, Status> {
+        if !self.peers.lock().unwrap().contains_key(peer) {
+            return Err(Status::not_found(format!("unknown peer {}", peer)));
+        }
+        let mut channels = self.channels.lock().unwrap();
+        Ok(channels.entry(peer.to_string()).or_default().clone())
+    }
+}
+
+impl fmt::Debug for GrpcTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let peers = self.peers.lock().unwrap();
+        f.debug_struct("GrpcTransport").field("peers", &*peers).finish_non_exhaustive()
+    }
+}
+
+#[tonic::async_trait]
+impl RaftTransport for GrpcTransport {
+    async fn request_vote(
+        &self,
+        peer: &str,
+        request: RequestVoteRequest,
+    ) -> Result<RequestVoteResponse, Status> {
+        self.call(peer, |mut client| async move { client.request_vote(request).await }).await
+    }
+
+    async fn append_entries(
+        &self,
+        peer: &str,
+        request: AppendEntriesRequest,
+    ) -> Result<AppendEntriesResponse, Status> {
+        self.call(peer, |mut client| async move { client.append_entries(request).await }).await
+    }
+
+    async fn stream_entries(
+        &self,
+        peer: &str,
+        request: StreamEntriesRequest,
+    ) -> Result<EntryStream, Status> {
+        let stream =
+            self.call(peer, |mut client| async move { client.stream_entries(request).await });
+        Ok(Box::pin(stream.await?))
+    }
+
+    async fn read_index(
+        &self,
+        peer: &str,
+        request: ReadIndexRequest,
+    ) -> Result<ReadIndexResponse, Status> {
+        self.call(peer, |mut client| async move { client.read_index(request).await }).await
+    }
+
+    async fn timeout_now(
+        &self,
+        peer: &str,
+        request: TimeoutNowRequest,
+    ) -> Result<TimeoutNowResponse, Status> {
+        self.call(peer, |mut client| async move { client.timeout_now(request).await }).await
+    }
+
+    async fn install_snapshot(
+        &self,
+        peer: &str,
+        request: InstallSnapshotRequest,
+    ) -> Result<InstallSnapshotResponse, Status> {
+        self.call(peer, |mut client| async move { client.install_snapshot(request).await }).await
+    }
+
+    fn add_peer(&self, peer: &str, addr: &str) {
+        let mut peers = self.peers.lock().unwrap();
+        peers.entry(peer.to_string()).or_insert_with(|| addr.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+    use bytes::Bytes;
+    use hyper_util::rt::TokioIo;
+    use tempfile::TempDir;
+    use tokio::io::DuplexStream;
+    use tokio::sync::mpsc;
+    use tokio::task::JoinHandle;
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+    use tokio_stream::StreamExt;
+    use tonic::codegen::Service;
+    use tonic::transport::{Endpoint, Server, Uri};
+    use raft_core::raft::raft_server::RaftServer;
+    use crate::raft::tests::wait_until;
+    use crate::raft::{RaftConfig, RaftNode, Role};
+    use crate::service::RaftServiceImpl;
+
+    /// Serves each node's Raft service over in-memory connections. Each connection runs
+    /// through a proxy task, so a test can cut them all as a forgetful NAT would.
+    #[derive(Default)]
+    struct InMemoryNetwork {
+        listeners: Mutex<BTreeMap<String, mpsc::UnboundedSender<DuplexStream>>>,
+        tasks: Mutex<Vec<JoinHandle<()>>>,
+        proxies: Mutex<Vec<JoinHandle<()>>>,
+        dials: AtomicUsize,
+    }
+
+    impl InMemoryNetwork {
+        fn serve(&self, node: Arc<RaftNode>) {
+            let (listener, accepted) = mpsc::unbounded_channel();
+            let incoming = UnboundedReceiverStream::new(accepted).map(Ok::<_, std::io::Error>);
+            let service = RaftServer::new(RaftServiceImpl::new(node.clone()));
+            let server = tokio::spawn(async move {
+                let _ = Server::builder().add_service(service).serve_with_incoming(incoming).await;
+            });
+            self.tasks.lock().unwrap().push(server);
+            self.listeners.lock().unwrap().insert(node.id().to_string(), listener);
+        }
+
+        fn connect(&self, id: &str) -> std::io::Result<DuplexStream> {
+            let (client, mut client_end) = tokio::io::duplex(64 * 1024);
+            let (mut server_end, server) = tokio::io::duplex(64 * 1024);
+            let listeners = self.listeners.lock().unwrap();
+            let listener = listeners.get(id).ok_or(std::io::ErrorKind::NotFound)?;
+            listener.send(server).map_err(|_| std::io::ErrorKind::ConnectionRefused)?;
+            let proxy = tokio::spawn(async move {
+                let _ = tokio::io::copy_bidirectional(&mut client_end, &mut server_end).await;
+            });
+            self.proxies.lock().unwrap().push(proxy);
+            self.dials.fetch_add(1, Ordering::SeqCst);
+            Ok(client)
+        }
+
+        /// Cuts every open connection, leaving both ends to find out it is gone.
+        fn cut_connections(&self) {
+            self.proxies.lock().unwrap().drain(..).for_each(|proxy| proxy.abort());
+        }
+    }
+
+    impl Drop for InMemoryNetwork {
+        fn drop(&mut self) {
+            self.cut_connections();
+            self.tasks.lock().unwrap().iter().for_each(JoinHandle::abort);
+        }
+    }
+
+    /// Connects a channel to the node `id` over the in-memory network.
+    struct Connector {
+        network: Arc<InMemoryNetwork>,
+        id: String,
+    }
+
+    impl Service<Uri> for Connector {
+        type Response = TokioIo<DuplexStream>;
+        type Error = std::io::Error;
+        type Future = std::future::Ready<std::io::Result<Self::Response>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: Uri) -> Self::Future {
+            std::future::ready(self.network.connect(&self.id).map(TokioIo::new))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_grpc_transport_reconnects_after_connections_drop() {
+        let dir = TempDir::new().unwrap();
+        let network = Arc::new(InMemoryNetwork::default());
+        let ids = ["node-1", "node-2"];
+        let nodes: Vec<Arc<RaftNode>> = ids
+            .iter()
+            .map(|&id| {
+                // Peers are addressed by id on the in-memory network.
+                let peers: BTreeMap<String, String> = ids
+                    .iter()
+                    .filter(|&&peer| peer != id)
+                    .map(|peer| (peer.to_string(), peer.to_string()))
+                    .collect();
+                let transport = GrpcTransport::with_dialer(peers.clone(), {
+                    let network = network.clone();
+                    move |addr| {
+                        let connector = Connector {
+                            network: network.clone(),
+                            id: addr.to_string(),
+                        };
+                        let endpoint = Endpoint::from_shared(format!("http://{}", addr))
+                            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+                        Ok(endpoint.connect_with_connector_lazy(connector))
+                    }
+                });
+                // Long enough for no election to start while a peer is redialed.
+                let mut config = RaftConfig::new(id, peers);
+                config.election_timeout_min = Duration::from_secs(1);
+                config.election_timeout_max = Duration::from_secs(2);
+                let state_path = dir.path().join(format!("{}.state", id));
+                let log_path = dir.path().join(format!("{}.wal", id));
+                let node = RaftNode::new(config, state_path, log_path, Arc::new(transport));
+                let node = node.unwrap();
+                network.serve(node.clone());
+                node
+            })
+            .collect();
+        let tasks: Vec<_> = nodes.iter().map(RaftNode::start).collect();
+
+        wait_until(|| nodes.iter().any(|node| node.role() == Role::Leader)).await;
+        let leader = nodes.iter().find(|node| node.role() == Role::Leader).unwrap().clone();
+        let index = leader.propose(Bytes::from("before")).unwrap();
+        wait_until(|| nodes.iter().all(|node| node.commit_index() >= index)).await;
+        let dials = network.dials.load(Ordering::SeqCst);
+
+        // With two voters, nothing commits until the leader reaches its follower again.
+        network.cut_connections();
+        let index = leader.propose(Bytes::from("after")).unwrap();
+        wait_until(|| nodes.iter().all(|node| node.commit_index() >= index)).await;
+        assert!(network.dials.load(Ordering::SeqCst) > dials);
+        assert_eq!(leader.role(), Role::Leader);
+
+        tasks.iter().for_each(JoinHandle::abort);
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{debug, info};
+use crate::membership::{Membership, MemberStatus};
+use crate::raft::{RaftNode, Role};
+
+/// How often `DeadNodeReaper::run` looks for dead voters.
+pub const DEFAULT_REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Takes voters that gossip has seen dead for longer than a grace period out of the Raft
+/// voter set, so a cluster that lost nodes for good gets back a quorum it can reach.
+/// Only the leader proposes removals, one at a time through `RaftNode::remove_voter`,
+/// which refuses while a removal is in flight or if the voters left would not have a
+/// healthy majority, so removals cannot cascade.
+pub struct DeadNodeReaper {
+    raft: Arc<RaftNode>,
+    membership: Arc<Membership>,
+    grace: Duration,
+    /// When each voter was first seen dead, since it was last seen alive.
+    dead_since: BTreeMap<String, Instant>,
+}
+
+impl DeadNodeReaper {
+    pub fn new(raft: Arc<RaftNode>, membership: Arc<Membership>, grace: Duration) -> Self {
+        Self {
+            raft,
+            membership,
+            grace,
+            dead_since: BTreeMap::new(),
+        }
+    }
+
+    /// Notes which voters are dead and, on the leader, proposes removing the one dead the
+    /// longest once that is past the grace period. Returns the voter it proposed removing.
+    pub fn check(&mut self) -> Option<String> {
+        let now = Instant::now();
+        let voters = self.raft.voters();
+        self.dead_since.retain(|id, _| voters.contains(id));
+        for id in &voters {
+            let dead = self
+                .membership
+                .member(id)
+                .is_some_and(|member| member.status == MemberStatus::Dead);
+            if dead {
+                self.dead_since.entry(id.clone()).or_insert(now);
+            } else {
+                self.dead_since.remove(id);
+            }
+        }
+
+        if self.raft.role() != Role::Leader {
+            return None;
+        }
+        let (id, since) = self.dead_since.iter().min_by_key(|(_, since)| **since)?;
+        if now - *since < self.grace {
+            return None;
+        }
+        match self.raft.remove_voter(id) {
+            Ok(index) => {
+                info!(peer = %id, index, dead_for = ?(now - *since), "removing a dead voter");
+                Some(id.clone())
+            }
+            Err(e) => {
+                debug!(peer = %id, error = %e, "cannot remove a dead voter yet");
+                None
+            }
+        }
+    }
+
+    /// Checks every `interval`, forever.
+    pub async fn run(mut self, interval: Duration) {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            self.check();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use crate::raft::tests::{wait_until, TestCluster};
+
+    /// Gossip as `leader` would see it: every node of `cluster` alive but `dead`.
+    fn membership(cluster: &TestCluster, leader: &RaftNode, dead: &[&str]) -> Arc<Membership> {
+        let membership = Arc::new(Membership::new(leader.id(), leader.id()));
+        for node in &cluster.nodes {
+            membership.observe(node.id(), node.id()).unwrap();
+        }
+        for id in dead {
+            membership.mark_dead(id);
+        }
+        membership
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reaper_removes_a_dead_voter_after_the_grace_period() {
+        let cluster = TestCluster::start(4);
+        let leader = cluster.wait_for_leader().await;
+        let followers: Vec<_> =
+            cluster.nodes.iter().filter(|node| node.id() != leader.id()).cloned().collect();
+        let (dead, down) = (followers[0].id(), followers[1].id());
+        cluster.network.isolate(dead);
+        assert_eq!(leader.quorum(), 3);
+
+        let membership = membership(&cluster, &leader, &[dead]);
+        let mut reaper = DeadNodeReaper::new(leader.clone(), membership, Duration::from_secs(10));
+        assert_eq!(reaper.check(), None);
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert_eq!(reaper.check(), None);
+        tokio::time::sleep(Duration::from_secs(6)).await;
+        assert_eq!(reaper.check().as_deref(), Some(dead));
+
+        // Three voters are left, of which two make a quorum.
+        assert_eq!(leader.quorum(), 2);
+        assert!(!leader.voters().iter().any(|id| id == dead));
+        let survivor = followers[2].clone();
+        wait_until(|| !survivor.voters().iter().any(|id| id == dead)).await;
+
+        // So the leader still commits with another follower down.
+        cluster.network.isolate(down);
+        let index = leader.propose(Bytes::from("after removal")).unwrap();
+        wait_until(|| leader.commit_index() >= index).await;
+        assert_eq!(reaper.check(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reaper_does_not_remove_when_quorum_would_break() {
+        let cluster = TestCluster::start(3);
+        let leader = cluster.wait_for_leader().await;
+        let dead: Vec<&str> =
+            cluster.nodes.iter().map(|node| node.id()).filter(|id| *id != leader.id()).collect();
+        for id in &dead {
+            cluster.network.isolate(id);
+        }
+
+        let membership = membership(&cluster, &leader, &dead);
+        let mut reaper = DeadNodeReaper::new(leader.clone(), membership, Duration::from_secs(1));
+        assert_eq!(reaper.check(), None);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        // Dropping either follower would leave the leader alone with a dead voter.
+        assert_eq!(reaper.check(), None);
+        let err = leader.remove_voter(dead[0]).unwrap_err();
+        assert!(err.to_string().contains("short of a quorum of 2"), "{}", err);
+        assert_eq!(leader.voters().len(), 2);
+        assert_eq!(leader.quorum(), 2);
+    }
+}
@@ -0,0 +1,1021 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use bytes::Bytes;
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+use crate::bank::{
+    snapshot_path, ApplyExecutor, BankCommand, BankStateMachine, CommandOutcome,
+    CommandValidator, DefaultValidator, InvalidCommand,
+};
+use crate::metrics::WalMetrics;
+use crate::raft::SnapshotStore;
+use crate::wal::{
+    ApplyErrorPolicy, ApplyHalted, Compaction, CompactionPoint, EntryTooLarge, IntegrityMode,
+    LogEntry, Wal, DEFAULT_FILE_MODE, DEFAULT_MAX_ENTRY_BYTES, DEFAULT_READ_BUFFER_BYTES,
+};
+
+/// Term stamped on locally committed entries until leader election assigns real terms.
+const LOCAL_TERM: u64 = 0;
+
+/// Entries applied since the last snapshot after which a node takes a new one.
+pub const DEFAULT_SNAPSHOT_EVERY_ENTRIES: u64 = 10_000;
+/// WAL size in bytes after which a node takes a new snapshot.
+pub const DEFAULT_SNAPSHOT_EVERY_BYTES: u64 = 64 * 1024 * 1024;
+/// Most committed entries a replica applies in one batch by default.
+pub const DEFAULT_APPLY_BATCH_ENTRIES: usize = 1024;
+
+/// When a replica snapshots its state machine and compacts the WAL up to the snapshot.
+/// A threshold of 0 never triggers; the default policy never snapshots.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SnapshotPolicy {
+    /// Entries applied since the last snapshot that trigger a new one.
+    pub every_entries: u64,
+    /// WAL size, which after a compaction only holds entries past the snapshot, that
+    /// triggers a new one.
+    pub every_bytes: u64,
+}
+
+impl SnapshotPolicy {
+    fn is_due(&self, entries: u64, bytes: u64) -> bool {
+        (self.every_entries > 0 && entries >= self.every_entries)
+            || (self.every_bytes > 0 && bytes >= self.every_bytes)
+    }
+}
+
+/// Returned when entries asked of a replica were compacted into its snapshot. Whoever
+/// asked must start over from a copy of the state as of `snapshot_index` or later.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotRequired {
+    /// The first index that can be asked for.
+    pub first_index: u64,
+    /// Index of the last entry the saved snapshot covers.
+    pub snapshot_index: u64,
+}
+
+impl SnapshotRequired {
+    /// Returns the `SnapshotRequired` details if `e` was caused by asking for compacted
+    /// entries.
+    pub fn from_io(e: &std::io::Error) -> Option<&Self> {
+        e.get_ref()?.downcast_ref()
+    }
+
+    pub(crate) fn into_io(self) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::NotFound, self)
+    }
+}
+
+impl fmt::Display for SnapshotRequired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "entries before {} were compacted into the snapshot at {}",
+            self.first_index, self.snapshot_index
+        )
+    }
+}
+
+impl std::error::Error for SnapshotRequired {}
+
+/// A node's copy of the bank: the WAL, the state machine built from it, and the
+/// index of the last entry applied, which readers can wait on.
+#[derive(Debug)]
+pub struct Replica {
+    /// Shared with the thread taking a snapshot, which compacts the WAL once it is saved.
+    inner: Arc<Mutex<Inner>>,
+    applied: watch::Sender<u64>,
+    /// Index of the last entry the saved snapshot covers.
+    snapshotted: Arc<watch::Sender<u64>>,
+    snapshot_path: PathBuf,
+    /// Held while a snapshot is written to `snapshot_path`, so one taken here and one
+    /// installed from a leader never overwrite each other.
+    snapshot_file: Arc<Mutex<()>>,
+    snapshot_policy: SnapshotPolicy,
+    executor: ApplyExecutor,
+    /// Most committed entries applied, and checkpointed, at once.
+    apply_batch_entries: usize,
+    validator: Arc<dyn CommandValidator>,
+    /// Largest encoded command `propose` and `append` accept.
+    max_entry_bytes: u64,
+    /// Set if replaying the WAL halted on an entry that failed to apply, after which
+    /// nothing more is applied.
+    halted: Option<ApplyHalted>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    wal: Wal,
+    state: BankStateMachine,
+    /// Committed entries that have not been applied to `state` yet.
+    pending: VecDeque<LogEntry>,
+    /// Set while a snapshot is being saved, so only one is taken at a time.
+    snapshotting: bool,
+}
+
+impl Replica {
+    /// Opens the WAL at `path` and replays it into the snapshot saved next to it, or
+    /// into a fresh state machine if there is none.
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        Self::open_with_metrics(path, WalMetrics::default())
+    }
+
+    /// Like `open`, recording WAL activity into `metrics`.
+    pub fn open_with_metrics(path: &str, metrics: WalMetrics) -> std::io::Result<Self> {
+        Self::open_with_validator(path, metrics, Arc::new(DefaultValidator))
+    }
+
+    /// Like `open_with_metrics`, checking commands with `validator` instead of the
+    /// `DefaultValidator`, both before they are proposed and as the log is applied.
+    pub fn open_with_validator(
+        path: &str,
+        metrics: WalMetrics,
+        validator: Arc<dyn CommandValidator>,
+    ) -> std::io::Result<Self> {
+        Self::open_with_file_mode(path, metrics, validator, DEFAULT_FILE_MODE)
+    }
+
+    /// Like `open_with_validator`, creating the WAL with permission bits `file_mode`
+    /// instead of `DEFAULT_FILE_MODE`.
+    pub fn open_with_file_mode(
+        path: &str,
+        metrics: WalMetrics,
+        validator: Arc<dyn CommandValidator>,
+        file_mode: u32,
+    ) -> std::io::Result<Self> {
+        Self::open_with_apply_policy(path, metrics, validator, file_mode, Default::default())
+    }
+
+    /// Like `open_with_file_mode`, doing what `policy` says with an entry that fails to
+    /// apply as the WAL is replayed. Halting does not fail the open: the replica comes up
+    /// with the state as of the entry before, reports it in `halted`, and applies nothing
+    /// more.
+    pub fn open_with_apply_policy(
+        path: &str,
+        metrics: WalMetrics,
+        validator: Arc<dyn CommandValidator>,
+        file_mode: u32,
+        policy: ApplyErrorPolicy,
+    ) -> std::io::Result<Self> {
+        let bytes = DEFAULT_READ_BUFFER_BYTES;
+        Self::open_with_read_buffer(path, metrics, validator, file_mode, policy, bytes)
+    }
+
+    /// Like `open_with_apply_policy`, reading the WAL `read_buffer_bytes` at a time as it
+    /// is scanned and replayed, instead of `DEFAULT_READ_BUFFER_BYTES`.
+    pub fn open_with_read_buffer(
+        path: &str,
+        metrics: WalMetrics,
+        validator: Arc<dyn CommandValidator>,
+        file_mode: u32,
+        policy: ApplyErrorPolicy,
+        read_buffer_bytes: usize,
+    ) -> std::io::Result<Self> {
+        let snapshot = snapshot_path(Path::new(path));
+        let (bytes, mode) = (read_buffer_bytes, file_mode);
+        Self::open_with_snapshot_path(path, metrics, validator, mode, policy, bytes, &snapshot)
+    }
+
+    /// Like `open_with_read_buffer`, restoring the snapshot from `snapshot_path`, and
+    /// saving snapshots there, instead of next to the WAL, so they can live on other
+    /// storage. The WAL must not have been compacted past the snapshot found there, if any.
+    pub fn open_with_snapshot_path(
+        path: &str,
+        metrics: WalMetrics,
+        validator: Arc<dyn CommandValidator>,
+        file_mode: u32,
+        policy: ApplyErrorPolicy,
+        read_buffer_bytes: usize,
+        snapshot_path: &Path,
+    ) -> std::io::Result<Self> {
+        let integrity = IntegrityMode::Full;
+        let wal = Wal::new_with_read_buffer(path, integrity, file_mode, read_buffer_bytes)?;
+        let wal = wal.with_metrics(metrics);
+        let snapshot_path = snapshot_path.to_path_buf();
+        let state = BankStateMachine::load_snapshot(&snapshot_path)?.unwrap_or_default();
+        let snapshot_index = state.last_applied();
+        if wal.compaction_point().index > snapshot_index {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "log starts at {} but the snapshot only covers up to {}",
+                    wal.first_index(),
+                    snapshot_index
+                ),
+            ));
+        }
+        let mut state = state.with_validator(validator.clone());
+        let halted = match wal.apply_to_with_policy(&mut state, policy) {
+            Ok(_) => None,
+            Err(e) => match ApplyHalted::from_io(&e) {
+                Some(halted) => {
+                    error!(index = halted.index, error = %halted.error, "replay halted");
+                    Some(halted.clone())
+                }
+                None => return Err(e),
+            },
+        };
+
+        let (applied, _) = watch::channel(state.last_applied());
+        let (snapshotted, _) = watch::channel(snapshot_index);
+        let inner = Inner {
+            wal,
+            state,
+            pending: VecDeque::new(),
+            snapshotting: false,
+        };
+
+        Ok(Self {
+            inner: Arc::new(Mutex::new(inner)),
+            applied,
+            snapshotted: Arc::new(snapshotted),
+            snapshot_path,
+            snapshot_file: Arc::new(Mutex::new(())),
+            snapshot_policy: SnapshotPolicy::default(),
+            executor: ApplyExecutor::default(),
+            apply_batch_entries: DEFAULT_APPLY_BATCH_ENTRIES,
+            validator,
+            max_entry_bytes: DEFAULT_MAX_ENTRY_BYTES,
+            halted,
+        })
+    }
+
+    /// Snapshots the state machine and compacts the WAL whenever `policy` says so.
+    pub fn with_snapshot_policy(mut self, policy: SnapshotPolicy) -> Self {
+        self.snapshot_policy = policy;
+        self
+    }
+
+    /// Applies committed entries that touch disjoint accounts on up to `workers`
+    /// threads, instead of one at a time.
+    pub fn with_apply_workers(mut self, workers: usize) -> Self {
+        self.executor = ApplyExecutor::new(workers);
+        self
+    }
+
+    /// Applies committed entries in batches of up to `entries` instead of
+    /// `DEFAULT_APPLY_BATCH_ENTRIES`, checkpointing after each batch rather than each entry.
+    pub fn with_apply_batch(mut self, entries: usize) -> Self {
+        self.apply_batch_entries = entries.max(1);
+        self
+    }
+
+    /// Refuses commands that encode to over `bytes`, with `EntryTooLarge`, instead of
+    /// those over `DEFAULT_MAX_ENTRY_BYTES`.
+    pub fn with_max_entry_bytes(mut self, bytes: u64) -> Self {
+        self.max_entry_bytes = bytes;
+        self.lock().wal.set_max_entry_bytes(bytes);
+        self
+    }
+
+    pub fn last_applied(&self) -> u64 {
+        *self.applied.borrow()
+    }
+
+    /// The entry replaying the WAL halted on, if it did; see `open_with_apply_policy`.
+    pub fn halted(&self) -> Option<&ApplyHalted> {
+        self.halted.as_ref()
+    }
+
+    /// Checks `command` the way it is checked when applied, so it can be refused before
+    /// it is proposed.
+    pub fn validate(&self, command: &BankCommand) -> Result<(), InvalidCommand> {
+        self.validator.validate(command)
+    }
+
+    /// Index of the last durably committed entry, applied or not.
+    pub fn commit_index(&self) -> u64 {
+        self.lock().wal.last_index()
+    }
+
+    /// Appends `command` to the WAL and applies everything committed up to and including it.
+    pub fn propose(&self, command: &BankCommand) -> std::io::Result<(u64, CommandOutcome)> {
+        let command = self.encode(command)?;
+        let mut inner = self.lock();
+        let index = inner.append(command)?;
+
+        let outcome = self
+            .apply_pending(&mut inner)?
+            .into_iter()
+            .find_map(|(applied, outcome)| (applied == index).then_some(outcome))
+            .expect("proposed entry is applied with the pending batch");
+
+        Ok((index, outcome))
+    }
+
+    /// Like `propose` for several commands at once, appending them as one block with a
+    /// single fsync before applying them. Returns a result per command, in order: one
+    /// over `max_entry_bytes` fails on its own, while a failed write fails them all.
+    pub fn propose_batch(
+        &self,
+        commands: &[BankCommand],
+    ) -> Vec<std::io::Result<(u64, CommandOutcome)>> {
+        let encoded: Vec<_> = commands.iter().map(|command| self.encode(command)).collect();
+        let appended = {
+            let mut inner = self.lock();
+            let commands = encoded.iter().filter_map(|command| command.as_ref().ok()).cloned();
+            inner.append_batch(commands.collect()).and_then(|indexes| {
+                let outcomes: HashMap<_, _> = self.apply_pending(&mut inner)?.into_iter().collect();
+                Ok((indexes, outcomes))
+            })
+        };
+
+        let (mut indexes, mut outcomes) = match appended {
+            Ok((indexes, outcomes)) => (indexes.into_iter(), outcomes),
+            Err(e) => {
+                let failed = || std::io::Error::new(e.kind(), e.to_string());
+                return encoded
+                    .into_iter()
+                    .map(|command| command.and_then(|_| Err(failed())))
+                    .collect();
+            }
+        };
+        encoded
+            .into_iter()
+            .map(|command| {
+                command?;
+                let index = indexes.next().expect("every encoded command is appended");
+                let outcome =
+                    outcomes.remove(&index).expect("proposed entry is applied with the batch");
+                Ok((index, outcome))
+            })
+            .collect()
+    }
+
+    /// Durably commits `command` without applying it, returning its log index.
+    pub fn append(&self, command: &BankCommand) -> std::io::Result<u64> {
+        let command = self.encode(command)?;
+        self.lock().append(command)
+    }
+
+    /// Appends `entries`, committed through Raft, at the index and term they were committed
+    /// with, and applies everything committed up to them, returning the outcome of each
+    /// entry applied. Entries the WAL already holds are skipped, so the same ones can be
+    /// handed over again after a failure.
+    pub fn apply_entries(
+        &self,
+        entries: Vec<LogEntry>,
+    ) -> std::io::Result<Vec<(u64, CommandOutcome)>> {
+        self.check_not_halted()?;
+        let mut inner = self.lock();
+        let last_index = inner.wal.last_index();
+        let entries: Vec<_> =
+            entries.into_iter().filter(|entry| entry.index > last_index).collect();
+        inner.wal.append_batch(entries.clone())?;
+        inner.pending.extend(entries);
+        self.apply_pending(&mut inner)
+    }
+
+    /// Replaces the state with the snapshot in the file at `path`, which covers up to the
+    /// entry `last` stands for, sent by a Raft leader that compacted away the entries
+    /// this replica lacks, and starts the WAL over right after it. The snapshot becomes
+    /// this replica's own and the file is removed. A replica that applied that entry
+    /// already keeps its state.
+    pub fn install_snapshot(&self, last: CompactionPoint, path: &Path) -> std::io::Result<()> {
+        let index = last.index;
+        let state = BankStateMachine::load_snapshot(path)?.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "no snapshot to install")
+        })?;
+        if state.last_applied() != index {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("snapshot covers up to {}, not {}", state.last_applied(), index),
+            ));
+        }
+        let _file = self.snapshot_file.lock().unwrap();
+        if self.last_applied() < index {
+            state.save_snapshot(&self.snapshot_path)?;
+            let mut inner = self.lock();
+            inner.wal.reset_to(last)?;
+            inner.state = state.with_validator(self.validator.clone());
+            inner.pending.clear();
+            self.snapshotted.send_replace(index);
+            self.applied.send_replace(index);
+            info!(index, "installed a snapshot");
+        }
+        std::fs::remove_file(path)
+    }
+
+    /// Encodes `command` for the WAL, failing with `EntryTooLarge` if it is over
+    /// `max_entry_bytes`, or with `ApplyHalted` if the replica halted and so takes no
+    /// more writes, before taking the lock.
+    fn encode(&self, command: &BankCommand) -> std::io::Result<Bytes> {
+        self.check_not_halted()?;
+        let command = command.encode()?;
+        EntryTooLarge::check(command.len() as u64, self.max_entry_bytes)?;
+        Ok(command)
+    }
+
+    /// Applies every committed entry that has not been applied yet.
+    pub fn apply_committed(&self) -> std::io::Result<Vec<(u64, CommandOutcome)>> {
+        let mut inner = self.lock();
+        self.apply_pending(&mut inner)
+    }
+
+    /// Index of the last entry the saved snapshot covers, or 0 if there is none.
+    pub fn snapshot_index(&self) -> u64 {
+        *self.snapshotted.borrow()
+    }
+
+    /// The saved snapshot, encoded, and the index of the last entry it covers, or `None` if
+    /// none was saved.
+    pub fn saved_snapshot(&self) -> std::io::Result<Option<(u64, Bytes)>> {
+        let _file = self.snapshot_file.lock().unwrap();
+        let snapshot = match std::fs::read(&self.snapshot_path) {
+            Ok(snapshot) => snapshot,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let index = BankStateMachine::restore(&snapshot)?.last_applied();
+        Ok(Some((index, Bytes::from(snapshot))))
+    }
+
+    /// Resolves once a saved snapshot covers at least `index` and the WAL was compacted
+    /// up to it.
+    pub async fn wait_snapshot(&self, index: u64) {
+        let mut snapshotted = self.snapshotted.subscribe();
+        // The sender lives as long as `self`, so the channel cannot close while we wait.
+        let _ = snapshotted.wait_for(|snapshotted| *snapshotted >= index).await;
+    }
+
+    /// Resolves once this replica has applied at least `index`.
+    pub async fn wait_applied(&self, index: u64) {
+        let mut applied = self.applied.subscribe();
+        // The sender lives as long as `self`, so the channel cannot close while we wait.
+        let _ = applied.wait_for(|applied| *applied >= index).await;
+    }
+
+    /// Follows the index of the last entry applied.
+    pub fn applied_watch(&self) -> watch::Receiver<u64> {
+        self.applied.subscribe()
+    }
+
+    /// The state the WAL replays onto: the saved snapshot once the WAL has been compacted
+    /// up to it, or an empty state otherwise. Applying `applied_entries` to it in order
+    /// rebuilds this replica's state, outcome for outcome.
+    pub fn replay_base(&self) -> std::io::Result<BankStateMachine> {
+        let compacted = self.lock().wal.compaction_point().index;
+        let state = match compacted {
+            0 => BankStateMachine::default(),
+            _ => BankStateMachine::load_snapshot(&self.snapshot_path)?.unwrap_or_default(),
+        };
+        Ok(state.with_validator(self.validator.clone()))
+    }
+
+    /// The entries after `index` that have been applied, in order. Fails with
+    /// `SnapshotRequired` if the WAL no longer holds the entry right after `index`.
+    pub fn applied_entries(&self, index: u64) -> std::io::Result<Vec<LogEntry>> {
+        let inner = self.lock();
+        let compacted = inner.wal.compaction_point().index;
+        if index < compacted {
+            return Err(SnapshotRequired {
+                first_index: compacted + 1,
+                snapshot_index: self.snapshot_index(),
+            }
+            .into_io());
+        }
+        let applied = inner.state.last_applied();
+        if index >= applied {
+            return Ok(Vec::new());
+        }
+        inner.wal.replay_range(index + 1..=applied)
+    }
+
+    /// Runs `f` against the current state machine.
+    pub fn read<R>(&self, f: impl FnOnce(&BankStateMachine) -> R) -> R {
+        f(&self.lock().state)
+    }
+
+    /// Applies the pending entries in batches, checkpointing once per batch: readers see
+    /// `last_applied` move to its end, and a snapshot, if one is due, covers up to it.
+    /// A crash partway through a batch only loses state held in memory. Reopening replays
+    /// the WAL onto the last snapshot, skipping the entries it covers, so however much of
+    /// a batch was checkpointed, none of it is applied twice.
+    fn apply_pending(&self, inner: &mut Inner) -> std::io::Result<Vec<(u64, CommandOutcome)>> {
+        self.check_not_halted()?;
+        let mut outcomes = Vec::with_capacity(inner.pending.len());
+        while !inner.pending.is_empty() {
+            let Inner { state, pending, .. } = &mut *inner;
+            let batch = self.apply_batch_entries.min(pending.len());
+            let applied = self.executor.apply(state, &pending.make_contiguous()[..batch])?;
+            outcomes.extend(pending.drain(..batch).map(|entry| entry.index).zip(applied));
+
+            self.applied.send_replace(state.last_applied());
+            self.maybe_snapshot(inner);
+        }
+        Ok(outcomes)
+    }
+
+    fn check_not_halted(&self) -> std::io::Result<()> {
+        match &self.halted {
+            Some(halted) => Err(halted.clone().into_io(std::io::ErrorKind::Other)),
+            None => Ok(()),
+        }
+    }
+
+    /// Starts a snapshot if the policy says one is due. Only a copy of the state is taken
+    /// here; it is encoded and saved on a thread of its own, which then compacts the WAL
+    /// up to it, so applying and appending carry on meanwhile. Failures are only logged, as the
+    /// entries just applied stay committed either way.
+    fn maybe_snapshot(&self, inner: &mut Inner) {
+        let entries = inner.state.last_applied() - self.snapshot_index();
+        if inner.snapshotting || entries == 0 {
+            return;
+        }
+        let bytes = match inner.wal.size() {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                warn!(error = %e, "cannot size the log for a snapshot");
+                return;
+            }
+        };
+        if !self.snapshot_policy.is_due(entries, bytes) {
+            return;
+        }
+
+        inner.snapshotting = true;
+        let state = inner.state.clone();
+        let shared = self.inner.clone();
+        let snapshotted = self.snapshotted.clone();
+        let path = self.snapshot_path.clone();
+        let file = self.snapshot_file.clone();
+        let spawned = std::thread::Builder::new().name("snapshot".to_string()).spawn(move || {
+            let index = state.last_applied();
+            let _file = file.lock().unwrap();
+            if *snapshotted.borrow() >= index {
+                // A snapshot installed meanwhile is newer, and stays.
+                shared.lock().unwrap().snapshotting = false;
+                return;
+            }
+            // Only starting the compaction and swapping in the log it built hold the
+            // lock; writing that log out does not.
+            let built = state
+                .save_snapshot(&path)
+                .and_then(|()| shared.lock().unwrap().wal.compaction(index))
+                .and_then(|compaction| compaction.map(Compaction::build).transpose());
+            let mut inner = shared.lock().unwrap();
+            inner.snapshotting = false;
+            let compacted = built.and_then(|built| match built {
+                Some(built) => inner.wal.install_compaction(built),
+                None => Ok(()),
+            });
+            match compacted {
+                Ok(()) => {
+                    snapshotted.send_replace(index);
+                    info!(index, "saved a snapshot and compacted the log");
+                }
+                Err(e) => warn!(index, error = %e, "snapshot failed; will retry"),
+            }
+        });
+        if let Err(e) = spawned {
+            inner.snapshotting = false;
+            warn!(error = %e, "cannot start a snapshot");
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap()
+    }
+}
+
+impl SnapshotStore for Replica {
+    fn latest(&self) -> std::io::Result<Option<(u64, Bytes)>> {
+        self.saved_snapshot()
+    }
+
+    fn install(&self, last: CompactionPoint, path: &Path) -> std::io::Result<()> {
+        self.install_snapshot(last, path)
+    }
+}
+
+impl Inner {
+    fn append(&mut self, command: Bytes) -> std::io::Result<u64> {
+        let entry = LogEntry::new(self.wal.last_index() + 1, LOCAL_TERM, command);
+        self.wal.append(entry.clone())?;
+
+        let index = entry.index;
+        self.pending.push_back(entry);
+        Ok(index)
+    }
+
+    /// Appends `commands` at the next indexes as one block, returning their indexes.
+    fn append_batch(&mut self, commands: Vec<Bytes>) -> std::io::Result<Vec<u64>> {
+        let first = self.wal.last_index() + 1;
+        let entries: Vec<_> = (first..)
+            .zip(commands)
+            .map(|(index, command)| LogEntry::new(index, LOCAL_TERM, command))
+            .collect();
+        self.wal.append_batch(entries.clone())?;
+
+        let indexes = entries.iter().map(|entry| entry.index).collect();
+        self.pending.extend(entries);
+        Ok(indexes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tempfile::{NamedTempFile, TempDir};
+    use crate::bank::tests::{create_account, transfer};
+    use crate::bank::TransferOutcome;
+
+    #[test]
+    fn test_replica_propose_applies_immediately() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let replica = Replica::open(temp_file.path().to_str().unwrap()).unwrap();
+
+        let (index, outcome) = replica.propose(&create_account("alice", 100)).unwrap();
+
+        assert_eq!(index, 1);
+        assert_eq!(outcome, CommandOutcome::AccountCreated { account: "alice".to_string() });
+        assert_eq!(replica.last_applied(), 1);
+        assert_eq!(replica.read(|sm| sm.balance("alice")), Some(100));
+    }
+
+    #[test]
+    fn test_replica_propose_batch_fails_only_oversized_commands() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let metrics = WalMetrics::default();
+        let path = temp_file.path().to_str().unwrap();
+        let replica =
+            Replica::open_with_metrics(path, metrics.clone()).unwrap().with_max_entry_bytes(64);
+
+        let results = replica.propose_batch(&[
+            create_account("alice", 100),
+            create_account(&"x".repeat(64), 100),
+            transfer("alice", "alice", 10, "tx-1"),
+            create_account("bob", 50),
+        ]);
+        assert_eq!(results.len(), 4);
+        let created = |account: &str| CommandOutcome::AccountCreated {
+            account: account.to_string(),
+        };
+        assert_eq!(results[0].as_ref().unwrap(), &(1, created("alice")));
+        assert!(EntryTooLarge::from_io(results[1].as_ref().unwrap_err()).is_some());
+        assert_eq!(results[2].as_ref().unwrap().0, 2);
+        assert_eq!(results[3].as_ref().unwrap(), &(3, created("bob")));
+
+        assert_eq!(metrics.fsync_seconds.get_sample_count(), 1);
+        assert_eq!(replica.last_applied(), 3);
+        assert_eq!(replica.read(|sm| sm.balance("bob")), Some(50));
+    }
+
+    #[test]
+    fn test_replica_refuses_commands_over_max_entry_bytes() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let alice = create_account("alice", 100);
+        let limit = alice.encode().unwrap().len() as u64;
+        let replica = Replica::open(temp_file.path().to_str().unwrap())
+            .unwrap()
+            .with_max_entry_bytes(limit);
+
+        replica.propose(&alice).unwrap();
+        let err = replica.propose(&create_account("alice-the-second", 100)).unwrap_err();
+        assert_eq!(EntryTooLarge::from_io(&err).unwrap().limit, limit);
+        let err = replica.append(&create_account("alice-the-second", 100)).unwrap_err();
+        assert!(EntryTooLarge::from_io(&err).is_some(), "{}", err);
+        assert_eq!(replica.commit_index(), 1);
+        assert_eq!(replica.last_applied(), 1);
+    }
+
+    #[test]
+    fn test_replica_replays_wal_on_open() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path().to_str().unwrap();
+
+        {
+            let replica = Replica::open(path).unwrap();
+            replica.propose(&create_account("alice", 100)).unwrap();
+            replica.propose(&create_account("bob", 0)).unwrap();
+            replica.propose(&transfer("alice", "bob", 30, "tx-1")).unwrap();
+        }
+
+        let replica = Replica::open(path).unwrap();
+        assert_eq!(replica.last_applied(), 3);
+        assert_eq!(replica.read(|sm| sm.balance("alice")), Some(70));
+        assert_eq!(replica.read(|sm| sm.balance("bob")), Some(30));
+        assert_eq!(replica.read(|sm| sm.transfer_status("tx-1")), Some(TransferOutcome::Ok));
+    }
+
+    #[test]
+    fn test_replica_replay_skips_unknown_command_kinds() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path().to_str().unwrap();
+
+        {
+            let mut wal = Wal::new(path).unwrap();
+            let commands = [
+                create_account("alice", 100).encode().unwrap(),
+                // Field 99 of `Command`: a kind some future version added.
+                bytes::Bytes::from_static(b"\x9a\x06\x03new"),
+                create_account("bob", 0).encode().unwrap(),
+                transfer("alice", "bob", 30, "tx-1").encode().unwrap(),
+            ];
+            for (i, command) in commands.into_iter().enumerate() {
+                wal.append(LogEntry::new(i as u64 + 1, 1, command)).unwrap();
+            }
+        }
+
+        let replica = Replica::open(path).unwrap();
+        assert_eq!(replica.last_applied(), 4);
+        assert_eq!(replica.read(|sm| sm.balance("alice")), Some(70));
+        assert_eq!(replica.read(|sm| sm.balance("bob")), Some(30));
+    }
+
+    #[test]
+    fn test_replica_replay_skips_invalid_commands() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path().to_str().unwrap();
+
+        {
+            // Written straight to the log, as by a node that did not validate them.
+            let mut wal = Wal::new(path).unwrap();
+            let commands = [
+                create_account("alice", 100),
+                create_account("bob", 0),
+                transfer("alice", "bob", -30, "tx-1"),
+                create_account("bad id", 50),
+                transfer("alice", "bob", 30, "tx-2"),
+            ];
+            for (i, command) in commands.into_iter().enumerate() {
+                wal.append(LogEntry::new(i as u64 + 1, 1, command.encode().unwrap())).unwrap();
+            }
+        }
+
+        let replica = Replica::open(path).unwrap();
+        assert_eq!(replica.last_applied(), 5);
+        assert_eq!(replica.read(|sm| sm.balance("alice")), Some(70));
+        assert_eq!(replica.read(|sm| sm.balance("bob")), Some(30));
+        assert_eq!(replica.read(|sm| sm.transfer_status("tx-1")), None);
+        assert_eq!(replica.read(|sm| sm.account("bad id").cloned()), None);
+        assert!(replica.validate(&transfer("alice", "bob", -30, "tx-1")).is_err());
+    }
+
+    #[test]
+    fn test_replica_replay_halts_or_skips_an_entry_that_fails_to_apply() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path().to_str().unwrap();
+
+        {
+            let mut wal = Wal::new(path).unwrap();
+            let commands = [
+                create_account("alice", 100).encode().unwrap(),
+                // Not a `Command` at all, which no version can decode.
+                bytes::Bytes::from_static(b"\xFF"),
+                create_account("bob", 0).encode().unwrap(),
+            ];
+            for (i, command) in commands.into_iter().enumerate() {
+                wal.append(LogEntry::new(i as u64 + 1, 1, command)).unwrap();
+            }
+        }
+
+        let open = |policy| {
+            let validator = Arc::new(DefaultValidator);
+            Replica::open_with_apply_policy(
+                path,
+                WalMetrics::default(),
+                validator,
+                DEFAULT_FILE_MODE,
+                policy,
+            )
+            .unwrap()
+        };
+
+        let replica = open(ApplyErrorPolicy::Halt);
+        assert_eq!(replica.halted().map(|halted| halted.index), Some(2));
+        assert_eq!(replica.last_applied(), 1);
+        assert_eq!(replica.read(|sm| sm.balance("bob")), None);
+        let err = replica.propose(&create_account("carol", 0)).unwrap_err();
+        assert_eq!(ApplyHalted::from_io(&err).map(|halted| halted.index), Some(2));
+        drop(replica);
+
+        let replica = open(ApplyErrorPolicy::SkipAndLog);
+        assert!(replica.halted().is_none());
+        assert_eq!(replica.last_applied(), 3);
+        assert_eq!(replica.read(|sm| sm.balance("bob")), Some(0));
+        assert_eq!(replica.propose(&create_account("carol", 0)).unwrap().0, 4);
+    }
+
+    #[test]
+    fn test_replica_append_defers_apply() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let replica = Replica::open(temp_file.path().to_str().unwrap()).unwrap();
+
+        let index = replica.append(&create_account("alice", 100)).unwrap();
+        assert_eq!(replica.last_applied(), 0);
+        assert_eq!(replica.read(|sm| sm.balance("alice")), None);
+
+        let applied = replica.apply_committed().unwrap();
+        let created = CommandOutcome::AccountCreated { account: "alice".to_string() };
+        assert_eq!(applied, vec![(index, created)]);
+        assert_eq!(replica.last_applied(), index);
+    }
+
+    #[test]
+    fn test_replica_applies_committed_batch_with_workers() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path().to_str().unwrap();
+        let replica = Replica::open(path).unwrap().with_apply_workers(4);
+        for account in ["alice", "bob", "carol", "dave"] {
+            replica.append(&create_account(account, 100)).unwrap();
+        }
+        replica.append(&transfer("alice", "bob", 30, "tx-1")).unwrap();
+        replica.append(&transfer("carol", "dave", 500, "tx-2")).unwrap();
+
+        let applied = replica.apply_committed().unwrap();
+        let indexes: Vec<u64> = applied.iter().map(|(index, _)| *index).collect();
+        assert_eq!(indexes, (1..=6).collect::<Vec<_>>());
+        assert_eq!(applied[4].1, CommandOutcome::Transfer(TransferOutcome::Ok));
+        assert_eq!(applied[5].1, CommandOutcome::Transfer(TransferOutcome::InsufficientFunds));
+        assert_eq!(replica.last_applied(), 6);
+        assert_eq!(replica.read(|sm| sm.balance("bob")), Some(130));
+    }
+
+    #[test]
+    fn test_replica_reapplies_a_batch_cut_short_by_a_crash() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("bank.wal");
+        let path = path.to_str().unwrap();
+        let replica = Replica::open(path).unwrap().with_apply_batch(2);
+        replica.propose(&create_account("alice", 100)).unwrap();
+        replica.propose(&create_account("bob", 0)).unwrap();
+        for i in 0..5 {
+            replica.append(&transfer("alice", "bob", 10, &format!("tx-{}", i))).unwrap();
+        }
+
+        // The node got two entries into the appended ones, snapshotted them and crashed.
+        let mut state = replica.read(|sm| sm.clone());
+        for entry in replica.lock().wal.replay_range(3..=4).unwrap() {
+            state.apply(&entry).unwrap();
+        }
+        state.save_snapshot(&snapshot_path(Path::new(path))).unwrap();
+        drop(replica);
+
+        for _ in 0..2 {
+            let replica = Replica::open(path).unwrap();
+            assert_eq!(replica.last_applied(), 7);
+            assert_eq!(replica.read(|sm| sm.balance("alice")), Some(50));
+            assert_eq!(replica.read(|sm| sm.balance("bob")), Some(50));
+            assert_eq!(replica.read(|sm| sm.history("bob", 0).len()), 6);
+        }
+
+        // Batches end up in the same state as applying the entries one by one.
+        let replica = Replica::open(path).unwrap().with_apply_batch(2);
+        for i in 5..10 {
+            replica.append(&transfer("bob", "alice", 1, &format!("tx-{}", i))).unwrap();
+        }
+        let applied = replica.apply_committed().unwrap();
+        assert_eq!(applied.len(), 5);
+        assert_eq!(replica.last_applied(), 12);
+        assert_eq!(replica.read(|sm| sm.balance("bob")), Some(45));
+    }
+
+    #[tokio::test]
+    async fn test_replica_wait_applied_blocks_until_applied() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path().to_str().unwrap();
+        let replica = std::sync::Arc::new(Replica::open(path).unwrap());
+        let index = replica.append(&create_account("alice", 100)).unwrap();
+
+        let waiter = tokio::spawn({
+            let replica = replica.clone();
+            async move { replica.wait_applied(index).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiter.is_finished());
+
+        replica.apply_committed().unwrap();
+        tokio::time::timeout(Duration::from_secs(1), waiter).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replica_snapshots_and_compacts_past_the_threshold() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("bank.wal");
+        let path = path.to_str().unwrap();
+        let policy = SnapshotPolicy {
+            every_entries: 10,
+            every_bytes: 0,
+        };
+        let replica = Replica::open(path).unwrap().with_snapshot_policy(policy);
+        replica.propose(&create_account("alice", 1000)).unwrap();
+        replica.propose(&create_account("bob", 0)).unwrap();
+        for i in 0..7 {
+            replica.propose(&transfer("alice", "bob", 10, &format!("tx-{}", i))).unwrap();
+        }
+        assert_eq!(replica.snapshot_index(), 0);
+
+        for i in 7..23 {
+            replica.append(&transfer("alice", "bob", 10, &format!("tx-{}", i))).unwrap();
+        }
+        let uncompacted = std::fs::metadata(path).unwrap().len();
+        replica.apply_committed().unwrap();
+        // The replica keeps serving while the snapshot is saved.
+        let (index, _) = replica.propose(&transfer("bob", "alice", 5, "tx-back")).unwrap();
+        assert_eq!(index, 26);
+        assert_eq!(replica.read(|sm| sm.balance("bob")), Some(225));
+
+        let wait = replica.wait_snapshot(25);
+        tokio::time::timeout(Duration::from_secs(5), wait).await.unwrap();
+        assert_eq!(replica.snapshot_index(), 25);
+        let wal = Wal::new(path).unwrap();
+        assert_eq!((wal.first_index(), wal.last_index()), (26, 26));
+        assert!(std::fs::metadata(path).unwrap().len() < uncompacted);
+        drop(replica);
+
+        // Reopening restores the snapshot and replays only what followed it.
+        let replica = Replica::open(path).unwrap();
+        assert_eq!(replica.last_applied(), 26);
+        assert_eq!(replica.snapshot_index(), 25);
+        assert_eq!(replica.read(|sm| sm.balance("alice")), Some(775));
+        assert_eq!(replica.read(|sm| sm.transfer_status("tx-22")), Some(TransferOutcome::Ok));
+        assert_eq!(replica.read(|sm| sm.history("bob", 0).len()), 25);
+    }
+
+    #[tokio::test]
+    async fn test_replica_installs_a_snapshot_another_replica_saved() {
+        let temp_dir = TempDir::new().unwrap();
+        let (source, target) = (temp_dir.path().join("a.wal"), temp_dir.path().join("b.wal"));
+        let (source, target) = (source.to_str().unwrap(), target.to_str().unwrap());
+        let policy = SnapshotPolicy {
+            every_entries: 5,
+            every_bytes: 0,
+        };
+        let replica = Replica::open(source).unwrap().with_snapshot_policy(policy);
+        replica.propose(&create_account("alice", 1000)).unwrap();
+        replica.propose(&create_account("bob", 0)).unwrap();
+        for i in 0..3 {
+            replica.propose(&transfer("alice", "bob", 10, &format!("tx-{}", i))).unwrap();
+        }
+        tokio::time::timeout(Duration::from_secs(5), replica.wait_snapshot(5)).await.unwrap();
+        let (index, data) = replica.saved_snapshot().unwrap().unwrap();
+        let last = Wal::new(source).unwrap().compaction_point();
+        assert_eq!(last.index, index);
+
+        let installing = temp_dir.path().join("install.snapshot");
+        std::fs::write(&installing, &data).unwrap();
+        let installed = Replica::open(target).unwrap();
+        installed.install_snapshot(last, &installing).unwrap();
+        assert!(!installing.exists());
+        assert_eq!((installed.snapshot_index(), installed.last_applied()), (5, 5));
+        assert_eq!(installed.read(|sm| sm.balance("bob")), Some(30));
+
+        // Entries after the snapshot chain on from it, as on the replica that saved it.
+        let (index, _) = replica.propose(&transfer("alice", "bob", 10, "tx-3")).unwrap();
+        let entry = Wal::new(source).unwrap().read_at(index).unwrap().unwrap();
+        installed.apply_entries(vec![entry]).unwrap();
+        drop(installed);
+        let installed = Replica::open(target).unwrap();
+        assert_eq!(installed.last_applied(), 6);
+        assert_eq!(installed.read(|sm| sm.balance("bob")), Some(40));
+        assert_eq!(Wal::new(target).unwrap().verify_chain().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_replica_restores_a_snapshot_kept_apart_from_the_wal() {
+        let temp_dir = TempDir::new().unwrap();
+        let (wal_dir, snapshot_dir) = (temp_dir.path().join("wal"), temp_dir.path().join("snap"));
+        std::fs::create_dir(&wal_dir).unwrap();
+        std::fs::create_dir(&snapshot_dir).unwrap();
+        let path = wal_dir.join("bank.wal");
+        let path = path.to_str().unwrap();
+        let snapshot = snapshot_dir.join("bank.wal.snapshot");
+        let open = || {
+            let (metrics, validator) = (WalMetrics::default(), Arc::new(DefaultValidator));
+            let (mode, policy) = (DEFAULT_FILE_MODE, ApplyErrorPolicy::Halt);
+            let bytes = DEFAULT_READ_BUFFER_BYTES;
+            Replica::open_with_snapshot_path(
+                path, metrics, validator, mode, policy, bytes, &snapshot,
+            )
+        };
+
+        let policy = SnapshotPolicy {
+            every_entries: 5,
+            every_bytes: 0,
+        };
+        let replica = open().unwrap().with_snapshot_policy(policy);
+        replica.propose(&create_account("alice", 1000)).unwrap();
+        replica.propose(&create_account("bob", 0)).unwrap();
+        for i in 0..3 {
+            replica.propose(&transfer("alice", "bob", 10, &format!("tx-{}", i))).unwrap();
+        }
+        let wait = replica.wait_snapshot(5);
+        tokio::time::timeout(Duration::from_secs(5), wait).await.unwrap();
+        replica.propose(&transfer("alice", "bob", 10, "tx-3")).unwrap();
+        drop(replica);
+        assert!(snapshot.exists());
+        assert!(!snapshot_path(Path::new(path)).exists());
+
+        // Reopening finds the snapshot in its own directory and replays the WAL after it.
+        let replica = open().unwrap();
+        assert_eq!((replica.snapshot_index(), replica.last_applied()), (5, 6));
+        assert_eq!(replica.read(|sm| sm.balance("bob")), Some(40));
+        drop(replica);
+
+        // Looking for it next to the WAL instead, the compacted WAL cannot be replayed.
+        let err = Replica::open(path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,269 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use crate::bank::{BankCommand, CommandOutcome, Saga, SagaPhase, TransferOutcome};
+use crate::replica::Replica;
+use crate::shard::ShardRouter;
+
+/// A shard the coordinator can commit saga steps to.
+pub trait SagaShard: Send + Sync {
+    /// Commits `command` through the shard's log and returns how it applied.
+    fn propose(&self, command: &BankCommand) -> std::io::Result<CommandOutcome>;
+
+    /// Sagas the shard debited for that have not finished yet.
+    fn reserved_sagas(&self) -> Vec<(String, Saga)>;
+}
+
+impl SagaShard for Replica {
+    fn propose(&self, command: &BankCommand) -> std::io::Result<CommandOutcome> {
+        Replica::propose(self, command).map(|(_, outcome)| outcome)
+    }
+
+    fn reserved_sagas(&self) -> Vec<(String, Saga)> {
+        self.read(|sm| {
+            sm.reserved_sagas().map(|(saga_id, saga)| (saga_id.to_string(), saga.clone())).collect()
+        })
+    }
+}
+
+/// Runs transfers between accounts on different shards as a saga: debit the source,
+/// credit the destination, then settle the source or refund it if the credit failed.
+///
+/// Every step is keyed by the saga id, so repeating one is harmless. The coordinator
+/// keeps no state of its own: a saga interrupted after its debit stays reserved in the
+/// source shard's log, and `recover` finishes it.
+pub struct SagaCoordinator {
+    router: ShardRouter,
+    shards: BTreeMap<String, Arc<dyn SagaShard>>,
+}
+
+impl SagaCoordinator {
+    pub fn new(router: ShardRouter) -> Self {
+        Self {
+            router,
+            shards: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_shard(mut self, group: impl Into<String>, shard: Arc<dyn SagaShard>) -> Self {
+        self.shards.insert(group.into(), shard);
+        self
+    }
+
+    /// Moves `amount` from `from` to `to`. An I/O error leaves the saga for `recover`.
+    pub fn transfer(
+        &self,
+        saga_id: &str,
+        from: &str,
+        to: &str,
+        amount: i64,
+    ) -> std::io::Result<TransferOutcome> {
+        let source = self.shard_for(from)?;
+        let debit = BankCommand::SagaDebit {
+            saga_id: saga_id.to_string(),
+            from: from.to_string(),
+            to: to.to_string(),
+            amount,
+        };
+        match source.propose(&debit)? {
+            CommandOutcome::SagaDebit(TransferOutcome::Ok) => {}
+            CommandOutcome::SagaDebit(outcome) => return Ok(outcome),
+            other => return Err(unexpected_outcome(other)),
+        }
+
+        let saga = Saga {
+            from: from.to_string(),
+            to: to.to_string(),
+            amount,
+            phase: SagaPhase::Reserved,
+        };
+        self.complete(source.as_ref(), saga_id, &saga)
+    }
+
+    /// Finishes every saga left reserved on any shard, returning how many it finished.
+    pub fn recover(&self) -> std::io::Result<usize> {
+        let mut finished = 0;
+        for source in self.shards.values() {
+            for (saga_id, saga) in source.reserved_sagas() {
+                self.complete(source.as_ref(), &saga_id, &saga)?;
+                finished += 1;
+            }
+        }
+        Ok(finished)
+    }
+
+    /// Credits the destination of a reserved saga, then settles or compensates the source.
+    fn complete(
+        &self,
+        source: &dyn SagaShard,
+        saga_id: &str,
+        saga: &Saga,
+    ) -> std::io::Result<TransferOutcome> {
+        let credit = BankCommand::SagaCredit {
+            saga_id: saga_id.to_string(),
+            from: saga.from.clone(),
+            to: saga.to.clone(),
+            amount: saga.amount,
+        };
+        let outcome = match self.shard_for(&saga.to)?.propose(&credit)? {
+            CommandOutcome::SagaCredit(outcome) => outcome,
+            other => return Err(unexpected_outcome(other)),
+        };
+
+        let finish = BankCommand::SagaFinish {
+            saga_id: saga_id.to_string(),
+            commit: outcome == TransferOutcome::Ok,
+        };
+        match source.propose(&finish)? {
+            CommandOutcome::SagaFinished(_) => Ok(outcome),
+            other => Err(unexpected_outcome(other)),
+        }
+    }
+
+    fn shard_for(&self, account: &str) -> std::io::Result<&Arc<dyn SagaShard>> {
+        let group = self.router.group_for(account);
+        self.shards.get(group).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no shard {} registered for account {}", group, account),
+            )
+        })
+    }
+}
+
+impl std::fmt::Debug for SagaCoordinator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SagaCoordinator")
+            .field("router", &self.router)
+            .field("shards", &self.shards.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+fn unexpected_outcome(outcome: CommandOutcome) -> std::io::Error {
+    std::io::Error::other(format!("unexpected saga step outcome {:?}", outcome))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use crate::bank::tests::{create_account, saga_debit};
+    use crate::shard::tests::account_in;
+
+    struct Shards {
+        dir: TempDir,
+        router: ShardRouter,
+        east: Arc<Replica>,
+        west: Arc<Replica>,
+    }
+
+    impl Shards {
+        fn open() -> Self {
+            let dir = TempDir::new().unwrap();
+            let router = ShardRouter::new(["east", "west"]).unwrap();
+            let (east, west) = Self::replicas(&dir);
+            Self { dir, router, east, west }
+        }
+
+        fn replicas(dir: &TempDir) -> (Arc<Replica>, Arc<Replica>) {
+            let open = |group: &str| {
+                let path = dir.path().join(format!("{}.wal", group));
+                Arc::new(Replica::open(path.to_str().unwrap()).unwrap())
+            };
+            (open("east"), open("west"))
+        }
+
+        /// Drops the replicas and replays them from their WALs, as after a crash.
+        fn restart(&mut self) {
+            (self.east, self.west) = Self::replicas(&self.dir);
+        }
+
+        fn coordinator(&self) -> SagaCoordinator {
+            SagaCoordinator::new(self.router.clone())
+                .with_shard("east", self.east.clone())
+                .with_shard("west", self.west.clone())
+        }
+
+        /// Opens an account on each shard: one on east with 100 and one on west with 0.
+        fn accounts(&self) -> (String, String) {
+            let (from, to) = (account_in(&self.router, "east"), account_in(&self.router, "west"));
+            self.east.propose(&create_account(&from, 100)).unwrap();
+            self.west.propose(&create_account(&to, 0)).unwrap();
+            (from, to)
+        }
+    }
+
+    #[test]
+    fn test_saga_transfer_happy_path() {
+        let shards = Shards::open();
+        let (from, to) = shards.accounts();
+        let coordinator = shards.coordinator();
+
+        let outcome = coordinator.transfer("saga-1", &from, &to, 40).unwrap();
+        assert_eq!(outcome, TransferOutcome::Ok);
+        assert_eq!(shards.east.read(|sm| sm.balance(&from)), Some(60));
+        assert_eq!(shards.west.read(|sm| sm.balance(&to)), Some(40));
+        let phase = shards.east.read(|sm| sm.saga("saga-1").unwrap().phase);
+        assert_eq!(phase, SagaPhase::Settled);
+
+        // Retrying the saga id does not move the money twice.
+        assert_eq!(coordinator.transfer("saga-1", &from, &to, 40).unwrap(), TransferOutcome::Ok);
+        assert_eq!(shards.east.read(|sm| sm.balance(&from)), Some(60));
+        assert_eq!(shards.west.read(|sm| sm.balance(&to)), Some(40));
+    }
+
+    #[test]
+    fn test_saga_destination_failure_compensates_source() {
+        let shards = Shards::open();
+        let (from, _) = shards.accounts();
+        let missing = (0..)
+            .map(|i| format!("missing-{}", i))
+            .find(|id| shards.router.group_for(id) == "west")
+            .unwrap();
+        let coordinator = shards.coordinator();
+
+        let outcome = coordinator.transfer("saga-1", &from, &missing, 40).unwrap();
+        assert_eq!(outcome, TransferOutcome::AccountNotFound);
+        assert_eq!(shards.east.read(|sm| sm.balance(&from)), Some(100));
+        let phase = shards.east.read(|sm| sm.saga("saga-1").unwrap().phase);
+        assert_eq!(phase, SagaPhase::Compensated);
+    }
+
+    #[test]
+    fn test_saga_source_rejection_touches_nothing_else() {
+        let shards = Shards::open();
+        let (from, to) = shards.accounts();
+
+        let outcome = shards.coordinator().transfer("saga-1", &from, &to, 500).unwrap();
+        assert_eq!(outcome, TransferOutcome::InsufficientFunds);
+        assert_eq!(shards.west.last_applied(), 1);
+        assert!(shards.east.read(|sm| sm.saga("saga-1").is_none()));
+    }
+
+    #[test]
+    fn test_saga_recovered_after_coordinator_crash() {
+        let mut shards = Shards::open();
+        let (from, to) = shards.accounts();
+
+        // The coordinator crashed right after the debit committed.
+        shards.east.propose(&saga_debit("saga-1", &from, &to, 40)).unwrap();
+        // And this one after the credit committed too, but before settling.
+        shards.east.propose(&saga_debit("saga-2", &from, &to, 10)).unwrap();
+        let credit = BankCommand::SagaCredit {
+            saga_id: "saga-2".to_string(),
+            from: from.clone(),
+            to: to.clone(),
+            amount: 10,
+        };
+        shards.west.propose(&credit).unwrap();
+
+        shards.restart();
+        assert_eq!(shards.east.read(|sm| sm.reserved_sagas().count()), 2);
+        assert_eq!(shards.coordinator().recover().unwrap(), 2);
+
+        assert_eq!(shards.east.read(|sm| sm.balance(&from)), Some(50));
+        assert_eq!(shards.west.read(|sm| sm.balance(&to)), Some(50));
+        assert_eq!(shards.east.read(|sm| sm.reserved_sagas().count()), 0);
+        assert_eq!(shards.coordinator().recover().unwrap(), 0);
+    }
+}
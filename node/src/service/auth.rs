@@ -0,0 +1,146 @@
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+/// Environment variable holding the comma-separated bearer tokens `BearerAuth::from_env` accepts.
+pub const AUTH_TOKENS_ENV: &str = "NODE_AUTH_TOKENS";
+
+const AUTHORIZATION: &str = "authorization";
+const BEARER_PREFIX: &str = "Bearer ";
+
+/// Rejects requests whose `authorization` metadata is not `Bearer <token>` for an
+/// allowlisted token.
+#[derive(Clone, Debug)]
+pub struct BearerAuth {
+    tokens: Arc<BTreeSet<String>>,
+}
+
+impl BearerAuth {
+    pub fn new(tokens: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self {
+            tokens: Arc::new(tokens.into_iter().map(Into::into).collect()),
+        }
+    }
+
+    /// Builds an allowlist from `NODE_AUTH_TOKENS`, or `None` if it is unset or empty.
+    pub fn from_env() -> Option<Self> {
+        let tokens = std::env::var(AUTH_TOKENS_ENV).ok()?;
+        let tokens: Vec<&str> =
+            tokens.split(',').map(str::trim).filter(|t| !t.is_empty()).collect();
+        (!tokens.is_empty()).then(|| Self::new(tokens))
+    }
+
+    fn is_allowed(&self, token: &str) -> bool {
+        self.tokens.iter().any(|allowed| constant_time_eq(allowed.as_bytes(), token.as_bytes()))
+    }
+}
+
+impl Interceptor for BearerAuth {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let header = request
+            .metadata()
+            .get(AUTHORIZATION)
+            .ok_or_else(|| Status::unauthenticated("missing bearer token"))?;
+        let token = header
+            .to_str()
+            .ok()
+            .and_then(|value| value.strip_prefix(BEARER_PREFIX))
+            .ok_or_else(|| Status::unauthenticated("malformed authorization header"))?;
+
+        if !self.is_allowed(token) {
+            return Err(Status::unauthenticated("invalid bearer token"));
+        }
+        Ok(request)
+    }
+}
+
+/// Compares without short-circuiting on the first differing byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+    use bank_api::bank::bank_service_client::BankServiceClient;
+    use bank_api::bank::bank_service_server::BankServiceServer;
+    use bank_api::bank::{AccountId, CreateAccountRequest};
+    use crate::replica::Replica;
+    use crate::service::BankServiceImpl;
+    use crate::transport;
+
+    fn request_with(authorization: Option<&str>) -> Request<()> {
+        let mut request = Request::new(());
+        if let Some(value) = authorization {
+            request.metadata_mut().insert(AUTHORIZATION, value.parse().unwrap());
+        }
+        request
+    }
+
+    #[test]
+    fn test_bearer_auth_missing_token() {
+        let status = BearerAuth::new(["secret"]).call(request_with(None)).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        assert_eq!(status.message(), "missing bearer token");
+    }
+
+    #[test]
+    fn test_bearer_auth_invalid_token() {
+        let mut auth = BearerAuth::new(["secret"]);
+
+        let status = auth.call(request_with(Some("Bearer guess"))).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        assert_eq!(status.message(), "invalid bearer token");
+
+        let status = auth.call(request_with(Some("secret"))).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[test]
+    fn test_bearer_auth_valid_token() {
+        let mut auth = BearerAuth::new(["old", "secret"]);
+        assert!(auth.call(request_with(Some("Bearer secret"))).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_bearer_auth_valid_token_reaches_handler() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let replica = Arc::new(Replica::open(temp_file.path().to_str().unwrap()).unwrap());
+        let service = BankServiceServer::with_interceptor(
+            BankServiceImpl::new(replica.clone()),
+            BearerAuth::new(["secret"]),
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(
+            transport::server(None)
+                .unwrap()
+                .add_service(service)
+                .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+        );
+
+        let channel = transport::endpoint(&addr, None).unwrap().connect().await.unwrap();
+        let mut client = BankServiceClient::new(channel);
+        let create = |authorization: Option<&str>| {
+            let mut request = Request::new(CreateAccountRequest {
+                account: Some(AccountId { id: "alice".to_string() }),
+                initial_balance: 100,
+            });
+            if let Some(value) = authorization {
+                request.metadata_mut().insert(AUTHORIZATION, value.parse().unwrap());
+            }
+            request
+        };
+
+        let status = client.create_account(create(None)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        assert_eq!(replica.last_applied(), 0);
+
+        let response = client.create_account(create(Some("Bearer secret"))).await.unwrap();
+        assert!(response.into_inner().success);
+        assert_eq!(replica.read(|sm| sm.balance("alice")), Some(100));
+    }
+}
@@ -0,0 +1,1511 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use tonic::{Request, Response, Status};
+use bank_api::bank::bank_service_server::BankService;
+use bank_api::bank::{
+    AccountBalance, AccountId, BatchTransferRequest, ChangeEvent, ClientTxId,
+    CreateAccountRequest, CreateAccountResponse, DepositRequest, DepositResponse,
+    GetBalanceRequest, GetBalanceResponse,
+    GetHistoryRequest, GetHistoryResponse, GetTransferStatusRequest, GetTransferStatusResponse,
+    HistoryEntry, MultiGetRequest, MultiGetResponse, SetOverdraftLimitRequest,
+    SetOverdraftLimitResponse, SubscribeChangesRequest, TransferRequest, TransferResponse,
+    TransferStatus, WithdrawRequest,
+};
+use bank_api::CLIENT_ID_HEADER;
+use crate::bank::{self, BankCommand, CommandOutcome, OperationKind, Transfer, TransferOutcome};
+use crate::changes::{Change, ChangeFeed};
+use crate::membership::Membership;
+use crate::proposal::{proposal_queue, ProposalQueue, DEFAULT_PROPOSAL_CAPACITY};
+use crate::raft::{NotLeader, RaftNode, Role};
+use crate::replica::{Replica, SnapshotRequired};
+use crate::service::error::BankError;
+use crate::shard::ShardRouter;
+use crate::wal::EntryTooLarge;
+
+/// How long a read waits for the local replica to reach the requested `min_index`.
+const DEFAULT_READ_WAIT: Duration = Duration::from_secs(5);
+/// Metadata key carrying how long the client waits for a call, in gRPC's own encoding.
+const GRPC_TIMEOUT_HEADER: &str = "grpc-timeout";
+/// Changes a subscriber may fall behind by before the feed waits for it.
+const CHANGE_STREAM_BUFFER: usize = 64;
+
+/// The operations a `SubscribeChanges` call streams.
+pub type ChangeStream = Pin<Box<dyn Stream<Item = Result<ChangeEvent, Status>> + Send>>;
+
+/// gRPC front-end for a node's `Replica`.
+#[derive(Debug)]
+pub struct BankServiceImpl {
+    replica: Arc<Replica>,
+    proposals: ProposalQueue,
+    read_wait: Duration,
+    leadership: Option<Leadership>,
+    /// Where peers say their bank service is, which leader hints prefer.
+    membership: Option<Arc<Membership>>,
+    sharding: Option<Sharding>,
+}
+
+/// Lets a node accept writes only while it leads, and point clients at the leader otherwise.
+#[derive(Debug)]
+struct Leadership {
+    raft: Arc<RaftNode>,
+    /// Bank service address of each peer, for the leader hint.
+    bank_addrs: BTreeMap<String, String>,
+}
+
+/// Keeps a node to the accounts of the one shard its replica holds.
+#[derive(Debug)]
+struct Sharding {
+    router: ShardRouter,
+    local_group: String,
+}
+
+impl BankServiceImpl {
+    /// Commits writes through a proposal queue of the default capacity, whose worker
+    /// is spawned onto the current Tokio runtime.
+    pub fn new(replica: Arc<Replica>) -> Self {
+        let (proposals, worker) = proposal_queue(DEFAULT_PROPOSAL_CAPACITY);
+        worker.spawn(replica.clone());
+
+        Self {
+            replica,
+            proposals,
+            read_wait: DEFAULT_READ_WAIT,
+            leadership: None,
+            membership: None,
+            sharding: None,
+        }
+    }
+
+    /// Commits writes through `proposals` instead, e.g. to share it with metrics.
+    pub fn with_proposal_queue(mut self, proposals: ProposalQueue) -> Self {
+        self.proposals = proposals;
+        self
+    }
+
+    pub fn with_read_wait(mut self, read_wait: Duration) -> Self {
+        self.read_wait = read_wait;
+        self
+    }
+
+    /// Commits writes through `raft`, through a proposal queue of the default capacity,
+    /// rejecting them unless it is the leader and hinting at the leader's bank address
+    /// from `bank_addrs` when it is known. The replica must be fed the entries `raft`
+    /// commits, by a `RaftApplier`, for the writes to be answered. A queue passed to
+    /// `with_proposal_queue` afterwards must propose to `raft` as well.
+    pub fn with_raft(mut self, raft: Arc<RaftNode>, bank_addrs: BTreeMap<String, String>) -> Self {
+        let (proposals, worker) = proposal_queue(DEFAULT_PROPOSAL_CAPACITY);
+        worker.spawn_to_raft(raft.clone());
+        self.proposals = proposals;
+        self.leadership = Some(Leadership { raft, bank_addrs });
+        self
+    }
+
+    /// Hints at the bank address the leader advertises through gossip in `membership`,
+    /// over the one `with_raft` was given for it.
+    pub fn with_membership(mut self, membership: Arc<Membership>) -> Self {
+        self.membership = Some(membership);
+        self
+    }
+
+    /// Serves only accounts that `router` places in `local_group`, redirecting the rest.
+    pub fn with_shards(mut self, router: ShardRouter, local_group: impl Into<String>) -> Self {
+        self.sharding = Some(Sharding {
+            router,
+            local_group: local_group.into(),
+        });
+        self
+    }
+
+    /// Checks that this node's shard owns every one of `accounts`.
+    fn route<'a>(&self, accounts: impl IntoIterator<Item = &'a str>) -> Result<(), BankError> {
+        match &self.sharding {
+            Some(sharding) => sharding.check(accounts),
+            None => Ok(()),
+        }
+    }
+
+    /// Blocks until the replica has applied `min_index`, so a client reads its own writes.
+    async fn wait_applied(&self, min_index: u64) -> Result<(), BankError> {
+        tokio::time::timeout(self.read_wait, self.replica.wait_applied(min_index))
+            .await
+            .map_err(|_| BankError::NotApplied {
+                applied: self.replica.last_applied(),
+                required: min_index,
+            })
+    }
+
+    /// Blocks until the replica has applied everything committed before the call, as a
+    /// ReadIndex round through Raft finds it, and `min_index` too, so what is read next
+    /// is linearizable. Returns the index it waited for. Without Raft, it only waits for
+    /// `min_index`.
+    async fn wait_read_index(&self, min_index: u64) -> Result<u64, BankError> {
+        let read_index = match &self.leadership {
+            Some(leadership) => leadership.raft.read_index().await.map_err(|e| {
+                match NotLeader::from_io(&e) {
+                    Some(NotLeader { leader_id: None }) => BankError::NoLeader,
+                    _ => BankError::LeadershipUnconfirmed(e.to_string()),
+                }
+            })?,
+            None => 0,
+        };
+        let index = read_index.max(min_index);
+        self.wait_applied(index).await?;
+        Ok(index)
+    }
+
+    /// Queues `command` for commit, turning it away with `INVALID_ARGUMENT` if the
+    /// replica's validator refuses it or it is over the replica's entry size limit, with
+    /// `RESOURCE_EXHAUSTED` when the queue is full rather than buffering without bound,
+    /// and with `DEADLINE_EXCEEDED` when the client's `deadline` passes before it is
+    /// applied. It is queued on behalf of `client`, as the request named it. With Raft,
+    /// it fails with a leader hint if this node loses leadership before the command
+    /// commits.
+    async fn propose(
+        &self,
+        command: BankCommand,
+        deadline: Option<Instant>,
+        client: &str,
+    ) -> Result<(u64, CommandOutcome), BankError> {
+        self.replica.validate(&command).map_err(|e| BankError::InvalidArgument(e.to_string()))?;
+        if let Some(leadership) = &self.leadership {
+            leadership.check(self.membership.as_deref())?;
+        }
+
+        let proposed = self.proposals.propose_from(client, command, deadline).await;
+        proposed.map_err(|e| match e.kind() {
+            _ if EntryTooLarge::from_io(&e).is_some() => BankError::InvalidArgument(e.to_string()),
+            _ if let (Some(not_leader), Some(leadership)) =
+                (NotLeader::from_io(&e), &self.leadership) =>
+            {
+                leadership.redirect(not_leader.leader_id.clone(), self.membership.as_deref())
+            }
+            std::io::ErrorKind::WouldBlock => BankError::Overloaded(e.to_string()),
+            std::io::ErrorKind::TimedOut => BankError::DeadlineExceeded(e.to_string()),
+            std::io::ErrorKind::BrokenPipe => BankError::ShuttingDown(e.to_string()),
+            _ => BankError::Internal(format!("failed to commit command: {}", e)),
+        })
+    }
+}
+
+impl Leadership {
+    /// Fails with `FAILED_PRECONDITION` and a leader hint when a follower knows the
+    /// leader from its last AppendEntries, or `UNAVAILABLE` when no leader is known.
+    /// The hint is the bank address the leader advertises in `membership`, if it does.
+    fn check(&self, membership: Option<&Membership>) -> Result<(), BankError> {
+        if self.raft.role() == Role::Leader {
+            return Ok(());
+        }
+        Err(self.redirect(self.raft.leader_id(), membership))
+    }
+
+    /// Points the client at `leader_id`, as `check` does, or says no leader is known.
+    fn redirect(&self, leader_id: Option<String>, membership: Option<&Membership>) -> BankError {
+        let Some(leader_id) = leader_id else {
+            return BankError::NoLeader;
+        };
+
+        let advertised = membership
+            .and_then(|membership| membership.member(&leader_id))
+            .map(|member| member.bank_addr)
+            .filter(|addr| !addr.is_empty());
+        let leader_addr = advertised.or_else(|| self.bank_addrs.get(&leader_id).cloned());
+        BankError::NotLeader { leader_id, leader_addr }
+    }
+}
+
+impl Sharding {
+    /// Fails with `FAILED_PRECONDITION` naming the owning shard when `accounts` belong
+    /// to another one, or `UNIMPLEMENTED` when they span several shards.
+    fn check<'a>(&self, accounts: impl IntoIterator<Item = &'a str>) -> Result<(), BankError> {
+        let groups: BTreeSet<&str> =
+            accounts.into_iter().map(|account| self.router.group_for(account)).collect();
+        let group = match groups.len() {
+            0 => return Ok(()),
+            1 => *groups.first().unwrap(),
+            _ => {
+                let groups = groups.into_iter().map(str::to_string).collect();
+                return Err(BankError::CrossShard { groups });
+            }
+        };
+        if group == self.local_group {
+            return Ok(());
+        }
+
+        Err(BankError::WrongShard {
+            group: group.to_string(),
+            local_group: self.local_group.clone(),
+            addr: self.router.addr_of(group).map(str::to_string),
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl BankService for BankServiceImpl {
+    async fn create_account(
+        &self,
+        request: Request<CreateAccountRequest>,
+    ) -> Result<Response<CreateAccountResponse>, Status> {
+        let (deadline, client) = (grpc_deadline(&request), client_id(&request));
+        let request = request.into_inner();
+        // Without an id, the state machine generates one as it applies the command.
+        let account = request.account.map(|account| account.id).unwrap_or_default();
+        if account.is_empty() {
+            if self.sharding.is_some() {
+                return Err(BankError::InvalidArgument(
+                    "account id is required when accounts are sharded".to_string(),
+                )
+                .into());
+            }
+        } else {
+            self.route([account.as_str()])?;
+        }
+
+        let command = BankCommand::CreateAccount {
+            account,
+            initial_balance: request.initial_balance,
+        };
+        let (applied_index, outcome) = self.propose(command, deadline, &client).await?;
+
+        let (success, message, account) = match outcome {
+            CommandOutcome::AccountCreated { account } => (true, String::new(), account),
+            CommandOutcome::AccountExists { account } => {
+                (false, "account already exists".to_string(), account)
+            }
+            other => return Err(unexpected_outcome(other)),
+        };
+
+        Ok(Response::new(CreateAccountResponse {
+            success,
+            message,
+            applied_index,
+            account: Some(AccountId { id: account }),
+        }))
+    }
+
+    async fn get_balance(
+        &self,
+        request: Request<GetBalanceRequest>,
+    ) -> Result<Response<GetBalanceResponse>, Status> {
+        let request = request.into_inner();
+        let account = account_id(request.account, "account")?;
+        self.route([account.as_str()])?;
+        self.wait_read_index(request.min_index).await?;
+
+        let balance = self
+            .replica
+            .read(|sm| sm.balance(&account))
+            .ok_or_else(|| BankError::AccountNotFound(account.clone()))?;
+
+        Ok(Response::new(GetBalanceResponse { balance }))
+    }
+
+    async fn multi_get(
+        &self,
+        request: Request<MultiGetRequest>,
+    ) -> Result<Response<MultiGetResponse>, Status> {
+        let request = request.into_inner();
+        if request.accounts.is_empty() {
+            let message = "at least one account is required".to_string();
+            return Err(BankError::InvalidArgument(message).into());
+        }
+        let accounts = request
+            .accounts
+            .into_iter()
+            .map(|account| account_id(Some(account), "accounts"))
+            .collect::<Result<Vec<_>, BankError>>()?;
+        self.route(accounts.iter().map(String::as_str))?;
+        self.wait_read_index(request.min_index).await?;
+
+        // One read of the state machine, so every balance is as of the same applied index.
+        let (read_index, balances) = self.replica.read(|sm| {
+            let balances = accounts
+                .iter()
+                .map(|account| {
+                    let balance = sm
+                        .balance(account)
+                        .ok_or_else(|| BankError::AccountNotFound(account.clone()))?;
+                    Ok(AccountBalance {
+                        account: Some(AccountId { id: account.clone() }),
+                        balance,
+                    })
+                })
+                .collect::<Result<Vec<_>, BankError>>();
+            (sm.last_applied(), balances)
+        });
+
+        Ok(Response::new(MultiGetResponse {
+            balances: balances?,
+            read_index,
+        }))
+    }
+
+    async fn transfer(
+        &self,
+        request: Request<TransferRequest>,
+    ) -> Result<Response<TransferResponse>, Status> {
+        let (deadline, client) = (grpc_deadline(&request), client_id(&request));
+        let request = request.into_inner();
+        let from = account_id(request.from, "from")?;
+        let to = account_id(request.to, "to")?;
+        let client_tx_id = client_tx_id(request.client_tx_id)?;
+        self.route([from.as_str(), to.as_str()])?;
+
+        let command = BankCommand::Transfer {
+            from,
+            to,
+            amount: request.amount,
+            client_tx_id,
+        };
+        let (applied_index, outcome) = self.propose(command, deadline, &client).await?;
+
+        let CommandOutcome::Transfer(outcome) = outcome else {
+            return Err(unexpected_outcome(outcome));
+        };
+
+        Ok(Response::new(TransferResponse {
+            status: transfer_status(outcome) as i32,
+            message: String::new(),
+            applied_index,
+        }))
+    }
+
+    async fn batch_transfer(
+        &self,
+        request: Request<BatchTransferRequest>,
+    ) -> Result<Response<TransferResponse>, Status> {
+        let (deadline, client) = (grpc_deadline(&request), client_id(&request));
+        let request = request.into_inner();
+        let client_tx_id = client_tx_id(request.client_tx_id)?;
+
+        let transfers = request
+            .transfers
+            .into_iter()
+            .map(|leg| {
+                Ok(Transfer {
+                    from: account_id(leg.from, "from")?,
+                    to: account_id(leg.to, "to")?,
+                    amount: leg.amount,
+                })
+            })
+            .collect::<Result<Vec<_>, Status>>()?;
+        self.route(transfers.iter().flat_map(|leg| [leg.from.as_str(), leg.to.as_str()]))?;
+
+        let command = BankCommand::BatchTransfer { transfers, client_tx_id };
+        let (applied_index, outcome) = self.propose(command, deadline, &client).await?;
+
+        let CommandOutcome::BatchTransfer(outcome) = outcome else {
+            return Err(unexpected_outcome(outcome));
+        };
+
+        Ok(Response::new(TransferResponse {
+            status: transfer_status(outcome) as i32,
+            message: String::new(),
+            applied_index,
+        }))
+    }
+
+    async fn withdraw(
+        &self,
+        request: Request<WithdrawRequest>,
+    ) -> Result<Response<TransferResponse>, Status> {
+        let (deadline, client) = (grpc_deadline(&request), client_id(&request));
+        let request = request.into_inner();
+        let account = account_id(request.account, "account")?;
+        let client_tx_id = client_tx_id(request.client_tx_id)?;
+        self.route([account.as_str()])?;
+
+        let command = BankCommand::Withdraw {
+            account,
+            amount: request.amount,
+            client_tx_id,
+        };
+        let (applied_index, outcome) = self.propose(command, deadline, &client).await?;
+
+        let CommandOutcome::Withdraw(outcome) = outcome else {
+            return Err(unexpected_outcome(outcome));
+        };
+
+        Ok(Response::new(TransferResponse {
+            status: transfer_status(outcome) as i32,
+            message: String::new(),
+            applied_index,
+        }))
+    }
+
+    async fn deposit(
+        &self,
+        request: Request<DepositRequest>,
+    ) -> Result<Response<DepositResponse>, Status> {
+        let (deadline, client) = (grpc_deadline(&request), client_id(&request));
+        let request = request.into_inner();
+        let account = account_id(request.account, "account")?;
+        let client_tx_id = client_tx_id(request.client_tx_id)?;
+        self.route([account.as_str()])?;
+
+        let command = BankCommand::Deposit {
+            account,
+            amount: request.amount,
+            client_tx_id,
+        };
+        let (applied_index, outcome) = self.propose(command, deadline, &client).await?;
+
+        let CommandOutcome::Deposit { outcome, balance } = outcome else {
+            return Err(unexpected_outcome(outcome));
+        };
+
+        Ok(Response::new(DepositResponse {
+            status: transfer_status(outcome) as i32,
+            message: String::new(),
+            applied_index,
+            balance: balance.unwrap_or_default(),
+        }))
+    }
+
+    async fn set_overdraft_limit(
+        &self,
+        request: Request<SetOverdraftLimitRequest>,
+    ) -> Result<Response<SetOverdraftLimitResponse>, Status> {
+        let (deadline, client) = (grpc_deadline(&request), client_id(&request));
+        let request = request.into_inner();
+        let account = account_id(request.account, "account")?;
+        self.route([account.as_str()])?;
+
+        let command = BankCommand::SetOverdraftLimit {
+            account,
+            overdraft_limit: request.overdraft_limit,
+        };
+        let (applied_index, outcome) = self.propose(command, deadline, &client).await?;
+
+        let (success, message) = match outcome {
+            CommandOutcome::OverdraftLimitSet => (true, String::new()),
+            CommandOutcome::AccountNotFound => (false, "account does not exist".to_string()),
+            other => return Err(unexpected_outcome(other)),
+        };
+
+        Ok(Response::new(SetOverdraftLimitResponse {
+            success,
+            message,
+            applied_index,
+        }))
+    }
+
+    async fn get_history(
+        &self,
+        request: Request<GetHistoryRequest>,
+    ) -> Result<Response<GetHistoryResponse>, Status> {
+        let request = request.into_inner();
+        let account = account_id(request.account, "account")?;
+        self.route([account.as_str()])?;
+        self.wait_read_index(request.min_index).await?;
+
+        let entries = self
+            .replica
+            .read(|sm| {
+                sm.account(&account)?;
+                Some(sm.history(&account, request.limit as usize))
+            })
+            .ok_or_else(|| BankError::AccountNotFound(account.clone()))?;
+
+        Ok(Response::new(GetHistoryResponse {
+            entries: entries.into_iter().map(history_entry).collect(),
+        }))
+    }
+
+    async fn get_transfer_status(
+        &self,
+        request: Request<GetTransferStatusRequest>,
+    ) -> Result<Response<GetTransferStatusResponse>, Status> {
+        let request = request.into_inner();
+        let client_tx_id = client_tx_id(request.client_tx_id)?;
+        self.wait_read_index(request.min_index).await?;
+
+        let status = self
+            .replica
+            .read(|sm| sm.transfer_status(&client_tx_id))
+            .map_or(TransferStatus::Unknown, transfer_status);
+
+        Ok(Response::new(GetTransferStatusResponse {
+            status: status as i32,
+            message: String::new(),
+        }))
+    }
+
+    type SubscribeChangesStream = ChangeStream;
+
+    async fn subscribe_changes(
+        &self,
+        request: Request<SubscribeChangesRequest>,
+    ) -> Result<Response<Self::SubscribeChangesStream>, Status> {
+        let from_index = request.into_inner().from_index;
+        let mut feed =
+            ChangeFeed::open(self.replica.clone(), from_index).map_err(change_feed_error)?;
+
+        let (sender, receiver) = mpsc::channel(CHANGE_STREAM_BUFFER);
+        tokio::spawn(async move {
+            loop {
+                let changes = tokio::select! {
+                    changes = feed.next() => changes,
+                    // Nothing left to do once the subscriber hangs up.
+                    () = sender.closed() => return,
+                };
+                let events = match changes {
+                    Ok(changes) => changes.into_iter().map(|change| Ok(change_event(change))),
+                    Err(e) => {
+                        let _ = sender.send(Err(change_feed_error(e))).await;
+                        return;
+                    }
+                };
+                for event in events {
+                    if sender.send(event).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+}
+
+fn change_event(change: Change) -> ChangeEvent {
+    let balances = change
+        .balances
+        .into_iter()
+        .map(|(id, balance)| AccountBalance {
+            account: Some(AccountId { id }),
+            balance,
+        })
+        .collect();
+    ChangeEvent {
+        index: change.index,
+        kind: change.command.kind_name().to_string(),
+        command: change.command.to_json().to_string(),
+        balances,
+    }
+}
+
+/// Maps a change feed failure to `OUT_OF_RANGE`, with the snapshot index attached, when
+/// the changes asked for were compacted away, and to `INTERNAL` otherwise.
+fn change_feed_error(e: std::io::Error) -> Status {
+    match SnapshotRequired::from_io(&e) {
+        Some(required) => BankError::Compacted(required.clone()).into(),
+        None => BankError::Internal(format!("failed to read changes: {}", e)).into(),
+    }
+}
+
+fn account_id(account: Option<AccountId>, field: &str) -> Result<String, BankError> {
+    account
+        .map(|account| account.id)
+        .filter(|id| !id.is_empty())
+        .ok_or_else(|| BankError::InvalidArgument(format!("{} account id is required", field)))
+}
+
+fn client_tx_id(client_tx_id: Option<ClientTxId>) -> Result<String, BankError> {
+    client_tx_id
+        .map(|tx| tx.id)
+        .filter(|id| !id.is_empty())
+        .ok_or_else(|| BankError::InvalidArgument("client_tx_id is required".to_string()))
+}
+
+fn transfer_status(outcome: TransferOutcome) -> TransferStatus {
+    match outcome {
+        TransferOutcome::Ok => TransferStatus::CommittedOk,
+        TransferOutcome::InsufficientFunds => TransferStatus::CommittedInsufficientFunds,
+        TransferOutcome::InvalidAccount => TransferStatus::CommittedInvalidAccount,
+        TransferOutcome::AccountNotFound => TransferStatus::CommittedAccountNotFound,
+        TransferOutcome::AccountFrozen => TransferStatus::CommittedAccountFrozen,
+    }
+}
+
+fn history_entry(entry: bank::HistoryEntry) -> HistoryEntry {
+    let kind = match entry.kind {
+        OperationKind::Open => bank_api::bank::OperationKind::Open,
+        OperationKind::TransferIn => bank_api::bank::OperationKind::TransferIn,
+        OperationKind::TransferOut => bank_api::bank::OperationKind::TransferOut,
+        OperationKind::Withdraw => bank_api::bank::OperationKind::Withdraw,
+        OperationKind::Deposit => bank_api::bank::OperationKind::Deposit,
+    };
+
+    HistoryEntry {
+        kind: kind as i32,
+        counterparty: entry.counterparty.map(|id| AccountId { id }),
+        amount: entry.amount,
+        balance: entry.balance,
+        index: entry.index,
+    }
+}
+
+fn unexpected_outcome(outcome: CommandOutcome) -> Status {
+    BankError::Internal(format!("unexpected command outcome {:?}", outcome)).into()
+}
+
+/// When the client gives up on `request`, going by its `grpc-timeout` header.
+fn grpc_deadline<T>(request: &Request<T>) -> Option<Instant> {
+    let timeout = request.metadata().get(GRPC_TIMEOUT_HEADER)?.to_str().ok()?;
+    parse_grpc_timeout(timeout).map(|timeout| Instant::now() + timeout)
+}
+
+/// Who sent `request`, going by its `x-client-id` header; empty if it does not say.
+fn client_id<T>(request: &Request<T>) -> String {
+    let client = request.metadata().get(CLIENT_ID_HEADER).and_then(|value| value.to_str().ok());
+    client.unwrap_or_default().to_string()
+}
+
+/// Parses a `grpc-timeout` value: up to 8 digits followed by a unit, from `H`ours down
+/// to `n`anoseconds.
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    let (amount, unit) = value.split_at(value.len().checked_sub(1)?);
+    if amount.is_empty() || amount.len() > 8 {
+        return None;
+    }
+    let amount: u64 = amount.parse().ok()?;
+    let timeout = match unit {
+        "H" => Duration::from_secs(amount * 60 * 60),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    };
+    Some(timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::{NamedTempFile, TempDir};
+    use tonic::metadata::MetadataValue;
+    use bank_api::bank::TransferLeg;
+    use bank_api::{LEADER_ADDR_HEADER, LEADER_ID_HEADER, SHARD_ADDR_HEADER, SHARD_GROUP_HEADER};
+    use crate::applier::RaftApplier;
+    use crate::bank::tests::{create_account, transfer};
+    use crate::raft::tests::{wait_until, TestCluster};
+    use crate::shard::tests::account_in;
+
+    fn account(id: &str) -> Option<AccountId> {
+        Some(AccountId { id: id.to_string() })
+    }
+
+    fn test_service(temp_file: &NamedTempFile) -> (Arc<Replica>, BankServiceImpl) {
+        let replica = Arc::new(Replica::open(temp_file.path().to_str().unwrap()).unwrap());
+        let service = BankServiceImpl::new(replica.clone());
+        (replica, service)
+    }
+
+    /// A service committing writes through `node`, over a replica of its own kept in
+    /// `dir` that the entries `node` commits are applied to.
+    fn raft_service(
+        node: &Arc<RaftNode>,
+        dir: &TempDir,
+        bank_addrs: BTreeMap<String, String>,
+    ) -> (Arc<Replica>, BankServiceImpl) {
+        let path = dir.path().join(format!("{}.bank.wal", node.id()));
+        let replica = Arc::new(Replica::open(path.to_str().unwrap()).unwrap());
+        tokio::spawn(RaftApplier::new(node.clone(), replica.clone()).run());
+        let service = BankServiceImpl::new(replica.clone()).with_raft(node.clone(), bank_addrs);
+        (replica, service)
+    }
+
+    /// A `raft_service` on every node of `cluster`, by node id.
+    fn raft_services(
+        cluster: &TestCluster,
+        dir: &TempDir,
+    ) -> BTreeMap<String, (Arc<Replica>, BankServiceImpl)> {
+        let service = |node: &Arc<RaftNode>| raft_service(node, dir, BTreeMap::new());
+        cluster.nodes.iter().map(|node| (node.id().to_string(), service(node))).collect()
+    }
+
+    #[tokio::test]
+    async fn test_mutations_return_applied_index() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let (_, service) = test_service(&temp_file);
+
+        for (i, id) in ["alice", "bob"].iter().enumerate() {
+            let response = service
+                .create_account(Request::new(CreateAccountRequest {
+                    account: account(id),
+                    initial_balance: 100,
+                }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert!(response.success);
+            assert_eq!(response.applied_index, i as u64 + 1);
+        }
+
+        let response = service
+            .transfer(Request::new(TransferRequest {
+                from: account("alice"),
+                to: account("bob"),
+                amount: 10,
+                client_tx_id: Some(ClientTxId { id: "tx-1".to_string() }),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.status, TransferStatus::CommittedOk as i32);
+        assert_eq!(response.applied_index, 3);
+    }
+
+    #[tokio::test]
+    async fn test_deposit_returns_the_balance_after_it_applied() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let (replica, service) = test_service(&temp_file);
+        replica.propose(&create_account("alice", 100)).unwrap();
+
+        let response = service
+            .deposit(Request::new(DepositRequest {
+                account: account("alice"),
+                amount: 25,
+                client_tx_id: Some(ClientTxId { id: "d-1".to_string() }),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.status, TransferStatus::CommittedOk as i32);
+        assert_eq!(response.applied_index, 2);
+        assert_eq!(response.balance, 125);
+    }
+
+    #[tokio::test]
+    async fn test_create_account_without_an_id_returns_the_generated_one() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let (replica, service) = test_service(&temp_file);
+
+        let response = service
+            .create_account(Request::new(CreateAccountRequest {
+                account: None,
+                initial_balance: 100,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(response.success);
+        let id = response.account.unwrap().id;
+        assert_eq!(id, "acct-1");
+        assert_eq!(replica.read(|sm| sm.balance(&id)), Some(100));
+
+        let response = service
+            .create_account(Request::new(CreateAccountRequest {
+                account: account("bob"),
+                initial_balance: 0,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.account, account("bob"));
+    }
+
+    #[tokio::test]
+    async fn test_transfer_to_missing_account_reports_it() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let (replica, service) = test_service(&temp_file);
+        replica.propose(&create_account("alice", 100)).unwrap();
+
+        let response = service
+            .transfer(Request::new(TransferRequest {
+                from: account("alice"),
+                to: account("bob"),
+                amount: 10,
+                client_tx_id: Some(ClientTxId { id: "tx-1".to_string() }),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.status, TransferStatus::CommittedAccountNotFound as i32);
+        assert_eq!(replica.read(|sm| sm.balance("alice")), Some(100));
+        assert_eq!(replica.read(|sm| sm.balance("bob")), None);
+    }
+
+    #[tokio::test]
+    async fn test_batch_transfer_is_all_or_nothing() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let (replica, service) = test_service(&temp_file);
+        replica.propose(&create_account("payroll", 100)).unwrap();
+        replica.propose(&create_account("alice", 0)).unwrap();
+
+        let leg = |amount| TransferLeg {
+            from: account("payroll"),
+            to: account("alice"),
+            amount,
+        };
+        let response = service
+            .batch_transfer(Request::new(BatchTransferRequest {
+                transfers: vec![leg(60), leg(60)],
+                client_tx_id: Some(ClientTxId { id: "batch-1".to_string() }),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert_eq!(response.status, TransferStatus::CommittedInsufficientFunds as i32);
+        assert_eq!(response.applied_index, 3);
+        assert_eq!(replica.read(|sm| sm.balance("payroll")), Some(100));
+    }
+
+    #[tokio::test]
+    async fn test_get_history_returns_most_recent_entries() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let (replica, service) = test_service(&temp_file);
+        replica.propose(&create_account("alice", 100)).unwrap();
+        replica.propose(&create_account("bob", 0)).unwrap();
+        replica.propose(&transfer("alice", "bob", 10, "tx-1")).unwrap();
+        replica.propose(&transfer("alice", "bob", 20, "tx-2")).unwrap();
+
+        let response = service
+            .get_history(Request::new(GetHistoryRequest {
+                account: account("alice"),
+                limit: 2,
+                min_index: 4,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        let entries: Vec<(i32, i64, i64, u64)> = response
+            .entries
+            .iter()
+            .map(|e| (e.kind, e.amount, e.balance, e.index))
+            .collect();
+        let out = bank_api::bank::OperationKind::TransferOut as i32;
+        assert_eq!(entries, vec![(out, 10, 90, 3), (out, 20, 70, 4)]);
+        assert_eq!(response.entries[0].counterparty, account("bob"));
+    }
+
+    #[tokio::test]
+    async fn test_read_with_min_index_blocks_until_applied() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let (replica, service) = test_service(&temp_file);
+        let service = Arc::new(service);
+
+        // Commit without applying, as on a replica lagging behind its log.
+        let min_index = replica.append(&create_account("alice", 100)).unwrap();
+
+        let read = tokio::spawn({
+            let service = service.clone();
+            async move {
+                service
+                    .get_balance(Request::new(GetBalanceRequest {
+                        account: account("alice"),
+                        min_index,
+                    }))
+                    .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!read.is_finished());
+
+        replica.apply_committed().unwrap();
+        let response = read.await.unwrap().unwrap().into_inner();
+        assert_eq!(response.balance, 100);
+    }
+
+    #[tokio::test]
+    async fn test_read_on_another_node_waits_to_catch_up_with_min_index() {
+        let cluster = TestCluster::start(3);
+        let leader = cluster.wait_for_leader().await;
+        let follower = cluster.nodes.iter().find(|node| node.id() != leader.id()).unwrap();
+        let dir = TempDir::new().unwrap();
+        let (_, service) = raft_service(&leader, &dir, BTreeMap::new());
+        // The follower's replica is fed nothing yet, as on a node lagging behind its log.
+        let path = dir.path().join("lagging.bank.wal");
+        let lagging = Arc::new(Replica::open(path.to_str().unwrap()).unwrap());
+        let follower_service = BankServiceImpl::new(lagging.clone())
+            .with_raft(follower.clone(), BTreeMap::new());
+
+        let request = CreateAccountRequest {
+            account: account("alice"),
+            initial_balance: 100,
+        };
+        let response = service.create_account(Request::new(request)).await.unwrap();
+        let min_index = response.into_inner().applied_index;
+        let read = tokio::spawn(async move {
+            let request = GetBalanceRequest {
+                account: account("alice"),
+                min_index,
+            };
+            follower_service.get_balance(Request::new(request)).await
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!read.is_finished());
+        assert_eq!(lagging.last_applied(), 0);
+
+        tokio::spawn(RaftApplier::new(follower.clone(), lagging.clone()).run());
+        let response = read.await.unwrap().unwrap().into_inner();
+        assert_eq!(response.balance, 100);
+        assert!(lagging.last_applied() >= min_index);
+    }
+
+    #[tokio::test]
+    async fn test_multi_get_is_not_torn_by_concurrent_transfers() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let (replica, service) = test_service(&temp_file);
+        let accounts = ["alice", "bob", "carol"];
+        for id in accounts {
+            replica.propose(&create_account(id, 100)).unwrap();
+        }
+
+        let writer = std::thread::spawn({
+            let replica = replica.clone();
+            move || {
+                for i in 0..300 {
+                    let from = accounts[i % 3];
+                    let to = accounts[(i + 1) % 3];
+                    replica.propose(&transfer(from, to, 7, &format!("tx-{}", i))).unwrap();
+                }
+            }
+        });
+
+        let mut last_index = 0;
+        while !writer.is_finished() {
+            let response = service
+                .multi_get(Request::new(MultiGetRequest {
+                    accounts: accounts.iter().map(|id| account(id).unwrap()).collect(),
+                    min_index: 0,
+                }))
+                .await
+                .unwrap()
+                .into_inner();
+            let ids: Vec<_> = response.balances.iter().map(|b| b.account.clone()).collect();
+            assert_eq!(ids, accounts.map(account));
+            let total: i64 = response.balances.iter().map(|b| b.balance).sum();
+            assert_eq!(total, 300, "torn read at index {}", response.read_index);
+            assert!(response.read_index >= last_index);
+            last_index = response.read_index;
+            tokio::task::yield_now().await;
+        }
+        writer.join().unwrap();
+
+        let status = service
+            .multi_get(Request::new(MultiGetRequest {
+                accounts: vec![account("alice").unwrap(), account("dave").unwrap()],
+                min_index: 0,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        let status = service.multi_get(Request::new(MultiGetRequest::default())).await;
+        assert_eq!(status.unwrap_err().code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_multi_get_on_a_follower_sees_writes_committed_before_it() {
+        let cluster = TestCluster::start(3);
+        let dir = TempDir::new().unwrap();
+        let services = raft_services(&cluster, &dir);
+        let leader = cluster.wait_for_leader().await;
+        let follower = cluster.nodes.iter().find(|node| node.id() != leader.id()).unwrap();
+
+        let mut applied_index = 0;
+        for id in ["alice", "bob"] {
+            let request = CreateAccountRequest {
+                account: account(id),
+                initial_balance: 100,
+            };
+            let response = services[leader.id()].1.create_account(Request::new(request)).await;
+            applied_index = response.unwrap().into_inner().applied_index;
+        }
+
+        // Asked for nothing in particular, the follower still reads both accounts.
+        let response = services[follower.id()]
+            .1
+            .multi_get(Request::new(MultiGetRequest {
+                accounts: vec![account("alice").unwrap(), account("bob").unwrap()],
+                min_index: 0,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(response.read_index >= applied_index);
+        let balances: Vec<_> = response.balances.iter().map(|b| b.balance).collect();
+        assert_eq!(balances, [100, 100]);
+    }
+
+    #[tokio::test]
+    async fn test_read_replica_serves_reads_as_of_the_leader() {
+        let cluster = TestCluster::start_with_read_replicas(3, &["node-3"]);
+        let dir = TempDir::new().unwrap();
+        let services = raft_services(&cluster, &dir);
+        let leader = cluster.wait_for_leader().await;
+        let create = |id: &str| {
+            Request::new(CreateAccountRequest {
+                account: account(id),
+                initial_balance: 100,
+            })
+        };
+        services[leader.id()].1.create_account(create("alice")).await.unwrap();
+        services[leader.id()].1.create_account(create("bob")).await.unwrap();
+        let transfer = Request::new(TransferRequest {
+            from: account("alice"),
+            to: account("bob"),
+            amount: 10,
+            client_tx_id: Some(ClientTxId { id: "tx-1".to_string() }),
+        });
+        services[leader.id()].1.transfer(transfer).await.unwrap();
+
+        let replica = &services["node-3"].1;
+        let request = GetBalanceRequest {
+            account: account("bob"),
+            min_index: 0,
+        };
+        let response = replica.get_balance(Request::new(request)).await.unwrap().into_inner();
+        assert_eq!(response.balance, 110);
+        let request = GetTransferStatusRequest {
+            client_tx_id: Some(ClientTxId { id: "tx-1".to_string() }),
+            min_index: 0,
+        };
+        let response = replica.get_transfer_status(Request::new(request)).await.unwrap();
+        assert_eq!(response.into_inner().status, TransferStatus::CommittedOk as i32);
+        let request = GetHistoryRequest {
+            account: account("alice"),
+            limit: 10,
+            min_index: 0,
+        };
+        let response = replica.get_history(Request::new(request)).await.unwrap().into_inner();
+        assert_eq!(response.entries.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_read_with_unreached_min_index_times_out() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let (_, service) = test_service(&temp_file);
+        let service = service.with_read_wait(Duration::from_millis(10));
+
+        let status = service
+            .get_balance(Request::new(GetBalanceRequest {
+                account: account("alice"),
+                min_index: 5,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+    }
+
+    #[tokio::test]
+    async fn test_negative_transfer_is_rejected_before_the_log() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let (replica, service) = test_service(&temp_file);
+        replica.propose(&create_account("alice", 100)).unwrap();
+        replica.propose(&create_account("bob", 0)).unwrap();
+
+        let status = service
+            .transfer(Request::new(TransferRequest {
+                from: account("alice"),
+                to: account("bob"),
+                amount: -10,
+                client_tx_id: Some(ClientTxId { id: "tx-1".to_string() }),
+            }))
+            .await
+            .unwrap_err();
+
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(status.message(), "amount must be positive");
+        assert_eq!(replica.commit_index(), 2);
+        assert_eq!(replica.read(|sm| sm.balance("bob")), Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_account_id_is_rejected_before_the_log() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let (replica, service) = test_service(&temp_file);
+
+        let status = service
+            .create_account(Request::new(CreateAccountRequest {
+                account: account("alice; DROP TABLE accounts"),
+                initial_balance: 100,
+            }))
+            .await
+            .unwrap_err();
+
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(status.message().contains("account id"));
+        assert_eq!(replica.commit_index(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_status_unknown_transaction() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let (_, service) = test_service(&temp_file);
+
+        let response = service
+            .get_transfer_status(Request::new(GetTransferStatusRequest {
+                client_tx_id: Some(ClientTxId { id: "missing".to_string() }),
+                min_index: 0,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.status, TransferStatus::Unknown as i32);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_write_to_follower_redirects_to_leader() {
+        let cluster = TestCluster::start(3);
+        let leader = cluster.wait_for_leader().await;
+        let follower = cluster.nodes.iter().find(|node| node.id() != leader.id()).unwrap();
+        let bank_addrs: BTreeMap<String, String> = cluster
+            .nodes
+            .iter()
+            .map(|node| (node.id().to_string(), format!("{}.bank:50051", node.id())))
+            .collect();
+        let transfer_request = || {
+            Request::new(TransferRequest {
+                from: account("alice"),
+                to: account("bob"),
+                amount: 10,
+                client_tx_id: Some(ClientTxId { id: "tx-1".to_string() }),
+            })
+        };
+
+        let dir = TempDir::new().unwrap();
+        let (follower_replica, service) = raft_service(follower, &dir, bank_addrs.clone());
+        let status = service.transfer(transfer_request()).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        assert_eq!(status.metadata().get(LEADER_ID_HEADER).unwrap(), leader.id());
+        assert_eq!(
+            status.metadata().get(LEADER_ADDR_HEADER).unwrap(),
+            bank_addrs[leader.id()].as_str()
+        );
+        assert_eq!(follower_replica.last_applied(), 0);
+
+        let (_, service) = raft_service(&leader, &dir, bank_addrs);
+        for (id, balance) in [("alice", 100), ("bob", 0)] {
+            let request = CreateAccountRequest {
+                account: account(id),
+                initial_balance: balance,
+            };
+            service.create_account(Request::new(request)).await.unwrap();
+        }
+        let response = service.transfer(transfer_request()).await.unwrap().into_inner();
+        assert_eq!(response.status, TransferStatus::CommittedOk as i32);
+        assert_eq!(response.applied_index, 3);
+    }
+
+    // In real time: a proposal worker waiting on its queue holds a blocking thread, which
+    // keeps a paused clock from advancing to the heartbeats that commit on followers.
+    #[tokio::test]
+    async fn test_writes_through_the_leader_apply_on_every_node() {
+        let cluster = TestCluster::start(3);
+        let dir = TempDir::new().unwrap();
+        let services = raft_services(&cluster, &dir);
+        let leader = cluster.wait_for_leader().await;
+        let service = &services[leader.id()].1;
+
+        for (id, balance) in [("payroll", 100), ("alice", 0), ("bob", 0)] {
+            let request = CreateAccountRequest {
+                account: account(id),
+                initial_balance: balance,
+            };
+            service.create_account(Request::new(request)).await.unwrap();
+        }
+        let leg = |to: &str, amount| TransferLeg {
+            from: account("payroll"),
+            to: account(to),
+            amount,
+        };
+        let response = service
+            .batch_transfer(Request::new(BatchTransferRequest {
+                transfers: vec![leg("alice", 60), leg("bob", 40)],
+                client_tx_id: Some(ClientTxId { id: "batch-1".to_string() }),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.status, TransferStatus::CommittedOk as i32);
+        assert_eq!(response.applied_index, 4);
+        assert_eq!(leader.commit_index(), 4);
+
+        wait_until(|| services.values().all(|(replica, _)| replica.last_applied() == 4)).await;
+        for (replica, _) in services.values() {
+            let balances = replica.read(|sm| (sm.balance("alice"), sm.balance("bob")));
+            assert_eq!(balances, (Some(60), Some(40)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_overdraft_limit_set_on_the_leader_applies_on_every_node() {
+        let cluster = TestCluster::start(3);
+        let dir = TempDir::new().unwrap();
+        let services = raft_services(&cluster, &dir);
+        let leader = cluster.wait_for_leader().await;
+        let service = &services[leader.id()].1;
+
+        let request = CreateAccountRequest {
+            account: account("alice"),
+            initial_balance: 0,
+        };
+        service.create_account(Request::new(request)).await.unwrap();
+        let response = service
+            .set_overdraft_limit(Request::new(SetOverdraftLimitRequest {
+                account: account("alice"),
+                overdraft_limit: 50,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(response.success);
+        assert_eq!(response.applied_index, leader.commit_index());
+
+        let applied = response.applied_index;
+        wait_until(|| services.values().all(|(replica, _)| replica.last_applied() >= applied))
+            .await;
+        for (replica, _) in services.values() {
+            let limit = replica.read(|sm| sm.account("alice").map(|a| a.overdraft_limit));
+            assert_eq!(limit, Some(50));
+        }
+        let response = service
+            .withdraw(Request::new(WithdrawRequest {
+                account: account("alice"),
+                amount: 30,
+                client_tx_id: Some(ClientTxId { id: "w-1".to_string() }),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.status, TransferStatus::CommittedOk as i32);
+    }
+
+    #[tokio::test]
+    async fn test_write_overwritten_by_a_new_leader_redirects_to_it() {
+        let cluster = TestCluster::start(3);
+        let dir = TempDir::new().unwrap();
+        let mut services = raft_services(&cluster, &dir);
+        let old_leader = cluster.wait_for_leader().await;
+        let others: Vec<_> =
+            cluster.nodes.iter().filter(|node| node.id() != old_leader.id()).cloned().collect();
+        let create = |id: &str| {
+            Request::new(CreateAccountRequest {
+                account: account(id),
+                initial_balance: 100,
+            })
+        };
+        cluster.network.isolate(old_leader.id());
+
+        // Stored by the old leader alone, so never committed.
+        let (_, old_service) = services.remove(old_leader.id()).unwrap();
+        let last_index = old_leader.last_log_index();
+        let lost = tokio::spawn(async move { old_service.create_account(create("lost")).await });
+        wait_until(|| old_leader.last_log_index() > last_index).await;
+        let leads = |node: &&Arc<RaftNode>| node.role() == Role::Leader;
+        wait_until(|| others.iter().any(|node| leads(&node))).await;
+        let new_leader = others.iter().find(leads).unwrap().clone();
+        services[new_leader.id()].1.create_account(create("kept")).await.unwrap();
+
+        cluster.network.heal();
+        let status = lost.await.unwrap().unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        assert_eq!(status.metadata().get(LEADER_ID_HEADER).unwrap(), new_leader.id());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_leader_hint_prefers_the_advertised_bank_address() {
+        let cluster = TestCluster::start(3);
+        let leader = cluster.wait_for_leader().await;
+        let follower = cluster.nodes.iter().find(|node| node.id() != leader.id()).unwrap();
+        let configured = BTreeMap::from([(leader.id().to_string(), "10.0.0.1:50051".to_string())]);
+        let membership = Arc::new(Membership::new(follower.id(), follower.id()));
+        let announced = gossip::gossip::Peer {
+            node_id: leader.id().to_string(),
+            addr: leader.id().to_string(),
+            bank_addr: "bank.leader.example:30051".to_string(),
+            ..Default::default()
+        };
+        membership.observe_peer(&announced).unwrap();
+
+        let temp_file = NamedTempFile::new().unwrap();
+        let (_, service) = test_service(&temp_file);
+        let service = service.with_raft(follower.clone(), configured).with_membership(membership);
+        let request = Request::new(TransferRequest {
+            from: account("alice"),
+            to: account("bob"),
+            amount: 10,
+            client_tx_id: Some(ClientTxId { id: "tx-1".to_string() }),
+        });
+        let status = service.transfer(request).await.unwrap_err();
+        assert_eq!(
+            status.metadata().get(LEADER_ADDR_HEADER).unwrap(),
+            "bank.leader.example:30051"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_write_without_known_leader_is_unavailable() {
+        let cluster = TestCluster::start(3);
+        // Cut off before any election can finish, so no leader is ever heard of.
+        for node in &cluster.nodes {
+            cluster.network.isolate(node.id());
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let temp_file = NamedTempFile::new().unwrap();
+        let (_, service) = test_service(&temp_file);
+        let service = service.with_raft(cluster.node("node-1"), BTreeMap::new());
+        let status = service
+            .create_account(Request::new(CreateAccountRequest {
+                account: account("alice"),
+                initial_balance: 100,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+    }
+
+    #[tokio::test]
+    async fn test_write_rejected_when_proposal_queue_is_full() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let (replica, service) = test_service(&temp_file);
+        // No worker drains this queue, so its one slot stays taken.
+        let (proposals, _worker) = proposal_queue(1);
+        let service = Arc::new(service.with_proposal_queue(proposals.clone()));
+        let create = |id: &str| {
+            Request::new(CreateAccountRequest {
+                account: account(id),
+                initial_balance: 100,
+            })
+        };
+
+        let waiting = tokio::spawn({
+            let service = service.clone();
+            async move { service.create_account(create("alice")).await }
+        });
+        while proposals.depth() < 1 {
+            tokio::task::yield_now().await;
+        }
+
+        let status = service.create_account(create("bob")).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert!(!waiting.is_finished());
+        assert_eq!(replica.last_applied(), 0);
+    }
+
+    /// Two shards and an account owned by each.
+    fn two_shards() -> (ShardRouter, String, String) {
+        let addrs = BTreeMap::from([
+            ("east".to_string(), "east.bank:50051".to_string()),
+            ("west".to_string(), "west.bank:50051".to_string()),
+        ]);
+        let router = ShardRouter::new(["east", "west"]).unwrap().with_addrs(addrs);
+        let (east, west) = (account_in(&router, "east"), account_in(&router, "west"));
+        (router, east, west)
+    }
+
+    #[tokio::test]
+    async fn test_request_for_other_shard_redirects() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let (replica, service) = test_service(&temp_file);
+        let (router, east, west) = two_shards();
+        let service = service.with_shards(router, "east");
+        let create = |id: &str| {
+            Request::new(CreateAccountRequest {
+                account: account(id),
+                initial_balance: 100,
+            })
+        };
+
+        let status = service.create_account(create(&west)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        assert_eq!(status.metadata().get(SHARD_GROUP_HEADER).unwrap(), "west");
+        assert_eq!(status.metadata().get(SHARD_ADDR_HEADER).unwrap(), "west.bank:50051");
+        assert_eq!(replica.last_applied(), 0);
+
+        let status = service
+            .get_balance(Request::new(GetBalanceRequest {
+                account: account(&west),
+                min_index: 0,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.metadata().get(SHARD_GROUP_HEADER).unwrap(), "west");
+
+        assert!(service.create_account(create(&east)).await.unwrap().into_inner().success);
+    }
+
+    #[tokio::test]
+    async fn test_cross_shard_transfer_is_rejected() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let (replica, service) = test_service(&temp_file);
+        let (router, east, west) = two_shards();
+        let service = service.with_shards(router, "east");
+
+        let status = service
+            .transfer(Request::new(TransferRequest {
+                from: account(&east),
+                to: account(&west),
+                amount: 10,
+                client_tx_id: Some(ClientTxId { id: "tx-1".to_string() }),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unimplemented);
+        assert!(status.message().contains("cross-shard"));
+        assert_eq!(replica.last_applied(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_write_past_its_deadline_is_not_applied() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let (replica, service) = test_service(&temp_file);
+
+        let mut request = Request::new(CreateAccountRequest {
+            account: account("alice"),
+            initial_balance: 100,
+        });
+        request.metadata_mut().insert(GRPC_TIMEOUT_HEADER, MetadataValue::from_static("0n"));
+        let status = service.create_account(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::DeadlineExceeded);
+        assert_eq!(replica.last_applied(), 0);
+        assert_eq!(replica.read(|sm| sm.balance("alice")), None);
+    }
+
+    #[test]
+    fn test_parse_grpc_timeout() {
+        assert_eq!(parse_grpc_timeout("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_grpc_timeout("3M"), Some(Duration::from_secs(180)));
+        assert_eq!(parse_grpc_timeout("5S"), Some(Duration::from_secs(5)));
+        assert_eq!(parse_grpc_timeout("250m"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_grpc_timeout("7u"), Some(Duration::from_micros(7)));
+        assert_eq!(parse_grpc_timeout("0n"), Some(Duration::ZERO));
+        assert_eq!(parse_grpc_timeout(""), None);
+        assert_eq!(parse_grpc_timeout("S"), None);
+        assert_eq!(parse_grpc_timeout("5s"), None);
+        assert_eq!(parse_grpc_timeout("123456789S"), None);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_changes_replays_history_then_follows() {
+        use tokio_stream::StreamExt;
+
+        let temp_file = NamedTempFile::new().unwrap();
+        let (replica, service) = test_service(&temp_file);
+        replica.propose(&create_account("alice", 100)).unwrap();
+        replica.propose(&create_account("bob", 0)).unwrap();
+        replica.propose(&transfer("alice", "bob", 30, "tx-1")).unwrap();
+
+        let request = Request::new(SubscribeChangesRequest { from_index: 2 });
+        let mut changes = service.subscribe_changes(request).await.unwrap().into_inner();
+        let balances = |event: &ChangeEvent| -> Vec<(String, i64)> {
+            let balance = |b: &AccountBalance| (b.account.clone().unwrap().id, b.balance);
+            event.balances.iter().map(balance).collect()
+        };
+
+        let event = changes.next().await.unwrap().unwrap();
+        assert_eq!((event.index, event.kind.as_str()), (2, "create_account"));
+        assert_eq!(balances(&event), [("bob".to_string(), 0)]);
+        let event = changes.next().await.unwrap().unwrap();
+        assert_eq!((event.index, event.kind.as_str()), (3, "transfer"));
+        assert_eq!(balances(&event), [("alice".to_string(), 70), ("bob".to_string(), 30)]);
+
+        // Then operations as they apply.
+        replica.propose(&transfer("bob", "alice", 5, "tx-2")).unwrap();
+        let event = changes.next().await.unwrap().unwrap();
+        assert_eq!(event.index, 4);
+        assert!(event.command.contains("tx-2"), "{}", event.command);
+        assert_eq!(balances(&event), [("alice".to_string(), 75), ("bob".to_string(), 25)]);
+    }
+}
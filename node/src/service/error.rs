@@ -0,0 +1,348 @@
+use std::fmt;
+use tonic::metadata::MetadataValue;
+use tonic::{Code, Status};
+use bank_api::{
+    reason, ERROR_REASON_HEADER, LEADER_ADDR_HEADER, LEADER_ID_HEADER, SHARD_ADDR_HEADER,
+    SHARD_GROUP_HEADER, SNAPSHOT_INDEX_HEADER,
+};
+use crate::raft::NotLeader;
+use crate::replica::SnapshotRequired;
+
+/// Why the bank service turned a request down. Each becomes a `Status` of the code its
+/// variant names, carrying the variant's `reason` in `ERROR_REASON_HEADER` and, where
+/// noted, further headers, so a client can branch on them rather than on the message.
+/// A command that commits is not an error, even one that moved no money for lack of
+/// funds: its outcome is in the response.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BankError {
+    /// `INVALID_ARGUMENT`.
+    InvalidArgument(String),
+    /// `FAILED_PRECONDITION`, with `LEADER_ID_HEADER`, and `LEADER_ADDR_HEADER` if the
+    /// leader's bank address is known.
+    NotLeader {
+        leader_id: String,
+        leader_addr: Option<String>,
+    },
+    /// `UNAVAILABLE`.
+    NoLeader,
+    /// `UNAVAILABLE`: the leader could not confirm it still leads, so a read cannot be
+    /// served as of now.
+    LeadershipUnconfirmed(String),
+    /// `FAILED_PRECONDITION`, with `SHARD_GROUP_HEADER`, and `SHARD_ADDR_HEADER` if the
+    /// shard's address is known.
+    WrongShard {
+        group: String,
+        local_group: String,
+        addr: Option<String>,
+    },
+    /// `UNIMPLEMENTED`.
+    CrossShard { groups: Vec<String> },
+    /// `OUT_OF_RANGE`, with `SNAPSHOT_INDEX_HEADER`.
+    Compacted(SnapshotRequired),
+    /// `UNAVAILABLE`: the read waited too long for this node to apply `required`.
+    NotApplied { applied: u64, required: u64 },
+    /// `NOT_FOUND`.
+    AccountNotFound(String),
+    /// `RESOURCE_EXHAUSTED`.
+    Overloaded(String),
+    /// `DEADLINE_EXCEEDED`.
+    DeadlineExceeded(String),
+    /// `UNAVAILABLE`.
+    ShuttingDown(String),
+    /// `INTERNAL`.
+    Internal(String),
+}
+
+impl BankError {
+    pub fn code(&self) -> Code {
+        match self {
+            BankError::InvalidArgument(_) => Code::InvalidArgument,
+            BankError::NotLeader { .. } | BankError::WrongShard { .. } => Code::FailedPrecondition,
+            BankError::NoLeader
+            | BankError::LeadershipUnconfirmed(_)
+            | BankError::NotApplied { .. }
+            | BankError::ShuttingDown(_) => Code::Unavailable,
+            BankError::CrossShard { .. } => Code::Unimplemented,
+            BankError::Compacted(_) => Code::OutOfRange,
+            BankError::AccountNotFound(_) => Code::NotFound,
+            BankError::Overloaded(_) => Code::ResourceExhausted,
+            BankError::DeadlineExceeded(_) => Code::DeadlineExceeded,
+            BankError::Internal(_) => Code::Internal,
+        }
+    }
+
+    /// One of the `bank_api::reason` constants.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            BankError::InvalidArgument(_) => reason::INVALID_ARGUMENT,
+            BankError::NotLeader { .. } => reason::NOT_LEADER,
+            BankError::NoLeader => reason::NO_LEADER,
+            BankError::LeadershipUnconfirmed(_) => reason::LEADERSHIP_UNCONFIRMED,
+            BankError::WrongShard { .. } => reason::WRONG_SHARD,
+            BankError::CrossShard { .. } => reason::CROSS_SHARD,
+            BankError::Compacted(_) => reason::COMPACTED,
+            BankError::NotApplied { .. } => reason::NOT_APPLIED,
+            BankError::AccountNotFound(_) => reason::ACCOUNT_NOT_FOUND,
+            BankError::Overloaded(_) => reason::OVERLOADED,
+            BankError::DeadlineExceeded(_) => reason::DEADLINE_EXCEEDED,
+            BankError::ShuttingDown(_) => reason::SHUTTING_DOWN,
+            BankError::Internal(_) => reason::INTERNAL,
+        }
+    }
+}
+
+impl fmt::Display for BankError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BankError::InvalidArgument(message)
+            | BankError::LeadershipUnconfirmed(message)
+            | BankError::Overloaded(message)
+            | BankError::DeadlineExceeded(message)
+            | BankError::ShuttingDown(message)
+            | BankError::Internal(message) => f.write_str(message),
+            BankError::NotLeader { leader_id, .. } => {
+                write!(f, "not the leader; {} is", leader_id)
+            }
+            BankError::NoLeader => f.write_str("no leader is currently known"),
+            BankError::WrongShard { group, local_group, .. } => write!(
+                f,
+                "wrong shard; accounts belong to {}, this node serves {}",
+                group, local_group
+            ),
+            BankError::CrossShard { groups } => write!(
+                f,
+                "cross-shard transfers are not supported; accounts span shards {}",
+                groups.join(", ")
+            ),
+            BankError::Compacted(required) => write!(f, "{}", required),
+            BankError::NotApplied { applied, required } => write!(
+                f,
+                "replica has applied index {} but the read requires {}",
+                applied, required
+            ),
+            BankError::AccountNotFound(account) => {
+                write!(f, "account {} does not exist", account)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BankError {}
+
+impl From<BankError> for Status {
+    fn from(e: BankError) -> Self {
+        let mut status = Status::new(e.code(), e.to_string());
+        let metadata = status.metadata_mut();
+        metadata.insert(ERROR_REASON_HEADER, MetadataValue::from_static(e.reason()));
+        let mut insert = |key: &'static str, value: &str| {
+            if let Ok(value) = MetadataValue::try_from(value) {
+                metadata.insert(key, value);
+            }
+        };
+        match &e {
+            BankError::NotLeader { leader_id, leader_addr } => {
+                insert(LEADER_ID_HEADER, leader_id);
+                if let Some(addr) = leader_addr {
+                    insert(LEADER_ADDR_HEADER, addr);
+                }
+            }
+            BankError::WrongShard { group, addr, .. } => {
+                insert(SHARD_GROUP_HEADER, group);
+                if let Some(addr) = addr {
+                    insert(SHARD_ADDR_HEADER, addr);
+                }
+            }
+            BankError::Compacted(required) => {
+                insert(SNAPSHOT_INDEX_HEADER, &required.snapshot_index.to_string());
+            }
+            _ => {}
+        }
+        status
+    }
+}
+
+/// Why the Raft service failed a peer's request, each becoming a `Status` of the code
+/// its variant names, with its `reason` in `ERROR_REASON_HEADER`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RaftError {
+    /// `FAILED_PRECONDITION`, with `LEADER_ID_HEADER` if the leader is known.
+    NotLeader(NotLeader),
+    /// `UNAVAILABLE`: a leader could not confirm it still leads.
+    LeadershipUnconfirmed(String),
+    /// `UNIMPLEMENTED`.
+    Unsupported(&'static str),
+    /// `RESOURCE_EXHAUSTED`: the node is handling as many snapshots as it takes at once.
+    Overloaded(String),
+    /// `INTERNAL`: the node failed to persist its state.
+    Internal(String),
+}
+
+impl RaftError {
+    /// `NotLeader` if `e` carries one, otherwise what `otherwise` makes of `e`.
+    pub fn from_io(e: std::io::Error, otherwise: impl FnOnce(std::io::Error) -> Self) -> Self {
+        match NotLeader::from_io(&e) {
+            Some(not_leader) => RaftError::NotLeader(not_leader.clone()),
+            None => otherwise(e),
+        }
+    }
+
+    pub fn code(&self) -> Code {
+        match self {
+            RaftError::NotLeader(_) => Code::FailedPrecondition,
+            RaftError::LeadershipUnconfirmed(_) => Code::Unavailable,
+            RaftError::Unsupported(_) => Code::Unimplemented,
+            RaftError::Overloaded(_) => Code::ResourceExhausted,
+            RaftError::Internal(_) => Code::Internal,
+        }
+    }
+
+    /// One of the `bank_api::reason` constants.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            RaftError::NotLeader(_) => reason::NOT_LEADER,
+            RaftError::LeadershipUnconfirmed(_) => reason::LEADERSHIP_UNCONFIRMED,
+            RaftError::Unsupported(_) => reason::UNSUPPORTED,
+            RaftError::Overloaded(_) => reason::OVERLOADED,
+            RaftError::Internal(_) => reason::INTERNAL,
+        }
+    }
+}
+
+impl fmt::Display for RaftError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RaftError::NotLeader(not_leader) => write!(f, "{}", not_leader),
+            RaftError::LeadershipUnconfirmed(message)
+            | RaftError::Overloaded(message)
+            | RaftError::Internal(message) => f.write_str(message),
+            RaftError::Unsupported(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for RaftError {}
+
+impl From<RaftError> for Status {
+    fn from(e: RaftError) -> Self {
+        let mut status = Status::new(e.code(), e.to_string());
+        let metadata = status.metadata_mut();
+        metadata.insert(ERROR_REASON_HEADER, MetadataValue::from_static(e.reason()));
+        if let RaftError::NotLeader(NotLeader { leader_id: Some(leader_id) }) = &e
+            && let Ok(value) = MetadataValue::try_from(leader_id.as_str())
+        {
+            metadata.insert(LEADER_ID_HEADER, value);
+        }
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header<'a>(status: &'a Status, key: &str) -> Option<&'a str> {
+        status.metadata().get(key).map(|value| value.to_str().unwrap())
+    }
+
+    #[test]
+    fn test_bank_errors_map_to_their_status_codes_and_reasons() {
+        let required = SnapshotRequired { first_index: 11, snapshot_index: 10 };
+        let cases = [
+            (BankError::InvalidArgument("bad".into()), Code::InvalidArgument, "bad"),
+            (BankError::NoLeader, Code::Unavailable, "no leader is currently known"),
+            (BankError::LeadershipUnconfirmed("no quorum".into()), Code::Unavailable, "no quorum"),
+            (
+                BankError::CrossShard { groups: vec!["a".into(), "b".into()] },
+                Code::Unimplemented,
+                "cross-shard transfers are not supported; accounts span shards a, b",
+            ),
+            (
+                BankError::NotApplied { applied: 3, required: 5 },
+                Code::Unavailable,
+                "replica has applied index 3 but the read requires 5",
+            ),
+            (
+                BankError::AccountNotFound("alice".into()),
+                Code::NotFound,
+                "account alice does not exist",
+            ),
+            (BankError::Overloaded("full".into()), Code::ResourceExhausted, "full"),
+            (BankError::DeadlineExceeded("late".into()), Code::DeadlineExceeded, "late"),
+            (BankError::ShuttingDown("closed".into()), Code::Unavailable, "closed"),
+            (BankError::Internal("broken".into()), Code::Internal, "broken"),
+            (BankError::Compacted(required.clone()), Code::OutOfRange, &required.to_string()),
+        ];
+        for (error, code, message) in cases {
+            let reason = error.reason();
+            let status = Status::from(error);
+            assert_eq!((status.code(), status.message()), (code, message));
+            assert_eq!(header(&status, ERROR_REASON_HEADER), Some(reason));
+        }
+    }
+
+    #[test]
+    fn test_bank_errors_carry_the_headers_a_client_retries_with() {
+        let status = Status::from(BankError::NotLeader {
+            leader_id: "node-2".into(),
+            leader_addr: Some("10.0.0.2:50051".into()),
+        });
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        assert_eq!(status.message(), "not the leader; node-2 is");
+        assert_eq!(header(&status, ERROR_REASON_HEADER), Some(reason::NOT_LEADER));
+        assert_eq!(header(&status, LEADER_ID_HEADER), Some("node-2"));
+        assert_eq!(header(&status, LEADER_ADDR_HEADER), Some("10.0.0.2:50051"));
+
+        let status = Status::from(BankError::WrongShard {
+            group: "shard-b".into(),
+            local_group: "shard-a".into(),
+            addr: None,
+        });
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        assert_eq!(header(&status, ERROR_REASON_HEADER), Some(reason::WRONG_SHARD));
+        assert_eq!(header(&status, SHARD_GROUP_HEADER), Some("shard-b"));
+        assert_eq!(header(&status, SHARD_ADDR_HEADER), None);
+
+        let required = SnapshotRequired { first_index: 11, snapshot_index: 10 };
+        let status = Status::from(BankError::Compacted(required));
+        assert_eq!(header(&status, SNAPSHOT_INDEX_HEADER), Some("10"));
+    }
+
+    #[test]
+    fn test_raft_errors_map_to_their_status_codes_and_reasons() {
+        let not_leader = NotLeader { leader_id: Some("node-2".into()) };
+        let cases = [
+            (
+                RaftError::NotLeader(not_leader.clone()),
+                Code::FailedPrecondition,
+                not_leader.to_string(),
+            ),
+            (
+                RaftError::LeadershipUnconfirmed("no quorum".into()),
+                Code::Unavailable,
+                "no quorum".to_string(),
+            ),
+            (
+                RaftError::Unsupported("submit is not supported yet"),
+                Code::Unimplemented,
+                "submit is not supported yet".to_string(),
+            ),
+            (RaftError::Overloaded("busy".into()), Code::ResourceExhausted, "busy".to_string()),
+            (RaftError::Internal("disk".into()), Code::Internal, "disk".to_string()),
+        ];
+        for (error, code, message) in cases {
+            let reason = error.reason();
+            let status = Status::from(error);
+            assert_eq!((status.code(), status.message()), (code, message.as_str()));
+            assert_eq!(header(&status, ERROR_REASON_HEADER), Some(reason));
+        }
+
+        let status = Status::from(RaftError::from_io(not_leader.into_io(), |e| {
+            RaftError::Internal(e.to_string())
+        }));
+        assert_eq!(header(&status, LEADER_ID_HEADER), Some("node-2"));
+        let status = Status::from(RaftError::from_io(std::io::Error::other("disk"), |e| {
+            RaftError::Internal(e.to_string())
+        }));
+        assert_eq!(status.code(), Code::Internal);
+    }
+}
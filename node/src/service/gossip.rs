@@ -0,0 +1,52 @@
+use std::sync::Arc;
+use tonic::{Request, Response, Status};
+use gossip::gossip::gossip_server::Gossip;
+use gossip::gossip::{GossipMessage, GossipResponse, Peer};
+use crate::membership::Membership;
+
+/// gRPC front-end through which other nodes announce themselves to this node's
+/// `Membership`.
+pub struct GossipServiceImpl {
+    membership: Arc<Membership>,
+}
+
+impl GossipServiceImpl {
+    pub fn new(membership: Arc<Membership>) -> Self {
+        Self { membership }
+    }
+}
+
+#[tonic::async_trait]
+impl Gossip for GossipServiceImpl {
+    /// Records every announced peer as alive, and answers with the requested fragment of
+    /// the live members this node knows of. A peer that takes the id of a live member at
+    /// another address is refused, and the response names that member.
+    async fn exchange(
+        &self,
+        request: Request<GossipMessage>,
+    ) -> Result<Response<GossipResponse>, Status> {
+        let message = request.into_inner();
+        for peer in message.peers {
+            if let Err(duplicate) = self.membership.observe_peer(&peer) {
+                return Ok(Response::new(GossipResponse {
+                    accepted: false,
+                    conflict: Some(Peer {
+                        node_id: duplicate.id,
+                        addr: duplicate.existing_addr,
+                        ..Peer::default()
+                    }),
+                    ..GossipResponse::default()
+                }));
+            }
+        }
+
+        let (members, fragments) = self.membership.live_peers_fragment(message.fragment);
+        Ok(Response::new(GossipResponse {
+            accepted: true,
+            conflict: None,
+            members,
+            fragment: message.fragment,
+            fragments,
+        }))
+    }
+}
@@ -0,0 +1,11 @@
+mod auth;
+mod bank;
+mod error;
+mod gossip;
+mod raft;
+
+pub use auth::{BearerAuth, AUTH_TOKENS_ENV};
+pub use bank::BankServiceImpl;
+pub use error::{BankError, RaftError};
+pub use gossip::GossipServiceImpl;
+pub use raft::RaftServiceImpl;
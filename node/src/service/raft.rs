@@ -0,0 +1,102 @@
+use std::sync::Arc;
+use tonic::{Request, Response, Status};
+use raft_core::raft::raft_server::Raft;
+use raft_core::raft::{
+    AppendEntriesRequest, AppendEntriesResponse, InstallSnapshotRequest, InstallSnapshotResponse,
+    ReadIndexRequest, ReadIndexResponse, RequestVoteRequest, RequestVoteResponse,
+    StreamEntriesRequest, SubmitRequest, SubmitResponse, TimeoutNowRequest, TimeoutNowResponse,
+};
+use crate::raft::{EntryStream, RaftNode};
+use crate::service::error::RaftError;
+
+/// gRPC front-end peers use to reach a node's `RaftNode`.
+pub struct RaftServiceImpl {
+    node: Arc<RaftNode>,
+}
+
+impl RaftServiceImpl {
+    pub fn new(node: Arc<RaftNode>) -> Self {
+        Self { node }
+    }
+}
+
+#[tonic::async_trait]
+impl Raft for RaftServiceImpl {
+    async fn request_vote(
+        &self,
+        request: Request<RequestVoteRequest>,
+    ) -> Result<Response<RequestVoteResponse>, Status> {
+        self.node
+            .handle_request_vote(request.into_inner())
+            .map(Response::new)
+            .map_err(|e| RaftError::Internal(format!("failed to persist vote: {}", e)).into())
+    }
+
+    async fn append_entries(
+        &self,
+        request: Request<AppendEntriesRequest>,
+    ) -> Result<Response<AppendEntriesResponse>, Status> {
+        self.node
+            .handle_append_entries(request.into_inner())
+            .await
+            .map(Response::new)
+            .map_err(|e| RaftError::Internal(format!("failed to persist term: {}", e)).into())
+    }
+
+    type StreamEntriesStream = EntryStream;
+
+    async fn stream_entries(
+        &self,
+        request: Request<StreamEntriesRequest>,
+    ) -> Result<Response<Self::StreamEntriesStream>, Status> {
+        self.node.stream_entries(request.into_inner()).map(Response::new).map_err(|e| {
+            let error = RaftError::from_io(e, |e| {
+                RaftError::Internal(format!("failed to stream entries: {}", e))
+            });
+            error.into()
+        })
+    }
+
+    async fn read_index(
+        &self,
+        request: Request<ReadIndexRequest>,
+    ) -> Result<Response<ReadIndexResponse>, Status> {
+        self.node.handle_read_index(request.into_inner()).await.map(Response::new).map_err(|e| {
+            let error = RaftError::from_io(e, |e| {
+                RaftError::LeadershipUnconfirmed(format!("failed to confirm leadership: {}", e))
+            });
+            error.into()
+        })
+    }
+
+    async fn timeout_now(
+        &self,
+        request: Request<TimeoutNowRequest>,
+    ) -> Result<Response<TimeoutNowResponse>, Status> {
+        self.node
+            .handle_timeout_now(request.into_inner())
+            .map(Response::new)
+            .map_err(|e| RaftError::Internal(format!("failed to start election: {}", e)).into())
+    }
+
+    async fn install_snapshot(
+        &self,
+        request: Request<InstallSnapshotRequest>,
+    ) -> Result<Response<InstallSnapshotResponse>, Status> {
+        let installed = self.node.handle_install_snapshot(request.into_inner()).await;
+        installed.map(Response::new).map_err(|e| {
+            let error = match e.kind() {
+                std::io::ErrorKind::WouldBlock => RaftError::Overloaded(e.to_string()),
+                _ => RaftError::Internal(format!("failed to install snapshot: {}", e)),
+            };
+            error.into()
+        })
+    }
+
+    async fn submit(
+        &self,
+        _request: Request<SubmitRequest>,
+    ) -> Result<Response<SubmitResponse>, Status> {
+        Err(RaftError::Unsupported("submit is not supported yet").into())
+    }
+}
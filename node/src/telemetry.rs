@@ -0,0 +1,15 @@
+use tracing_subscriber::EnvFilter;
+
+/// Installs a global subscriber that writes each event to stdout as one JSON object,
+/// including the fields of every span it happened in. Filtering follows `RUST_LOG`
+/// and defaults to `info`. Fails if a global subscriber is already installed.
+pub fn init() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+
+    tracing_subscriber::fmt()
+        .json()
+        .with_current_span(true)
+        .with_span_list(true)
+        .with_env_filter(filter)
+        .try_init()
+}
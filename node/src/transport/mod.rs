@@ -0,0 +1,221 @@
+mod reconnect;
+mod tls;
+
+use std::time::Duration;
+use tonic::transport::{Endpoint, Server};
+
+pub use reconnect::{ReconnectingChannel, MAX_RECONNECT_BACKOFF, MIN_RECONNECT_BACKOFF};
+pub use tls::{TlsConfig, TLS_CA_ENV, TLS_CERT_ENV, TLS_KEY_ENV};
+
+/// How often a connection to a peer is pinged while open, busy or idle.
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+/// How long a ping may go unanswered before the connection is given up for dead.
+pub const DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// HTTP/2 flow-control window of each stream on a connection between Raft peers.
+pub const DEFAULT_STREAM_WINDOW: u32 = 2 * 1024 * 1024;
+/// HTTP/2 flow-control window shared by every stream on a connection between Raft peers.
+pub const DEFAULT_CONNECTION_WINDOW: u32 = 8 * 1024 * 1024;
+/// Streams a Raft peer may have open at once on one connection to a node.
+pub const DEFAULT_MAX_CONCURRENT_STREAMS: u32 = 256;
+/// Largest flow-control window HTTP/2 allows.
+pub const MAX_WINDOW: u32 = (1 << 31) - 1;
+
+/// HTTP/2 keepalive for connections to peers. The pings keep idle connections alive
+/// through NATs and firewalls that drop quiet ones, and find out when one was dropped
+/// anyway, so it is replaced before a call has to time out on it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keepalive {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_KEEPALIVE_INTERVAL,
+            timeout: DEFAULT_KEEPALIVE_TIMEOUT,
+        }
+    }
+}
+
+impl Keepalive {
+    /// Sets up `endpoint` to ping its connection as configured, and to turn on TCP
+    /// keepalive at the same interval.
+    pub fn apply(&self, endpoint: Endpoint) -> Endpoint {
+        endpoint
+            .http2_keep_alive_interval(self.interval)
+            .keep_alive_timeout(self.timeout)
+            .keep_alive_while_idle(true)
+            .tcp_keepalive(Some(self.interval))
+    }
+}
+
+/// How connections between Raft peers are tuned for latency. Heartbeats and single-entry
+/// appends are small and frequent, so Nagle's algorithm is off to send each at once. The
+/// flow-control windows are fixed, rather than sized by probing, and large enough that a
+/// follower catching up by stream is not held back waiting for window updates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChannelTuning {
+    pub tcp_nodelay: bool,
+    pub stream_window: u32,
+    pub connection_window: u32,
+    /// Applies to the connections a node accepts.
+    pub max_concurrent_streams: u32,
+}
+
+impl Default for ChannelTuning {
+    fn default() -> Self {
+        Self {
+            tcp_nodelay: true,
+            stream_window: DEFAULT_STREAM_WINDOW,
+            connection_window: DEFAULT_CONNECTION_WINDOW,
+            max_concurrent_streams: DEFAULT_MAX_CONCURRENT_STREAMS,
+        }
+    }
+}
+
+impl ChannelTuning {
+    /// Sets up `endpoint`, which dials a peer, as configured.
+    pub fn apply(&self, endpoint: Endpoint) -> Endpoint {
+        endpoint
+            .tcp_nodelay(self.tcp_nodelay)
+            .initial_stream_window_size(self.stream_window)
+            .initial_connection_window_size(self.connection_window)
+            .http2_adaptive_window(false)
+    }
+
+    /// Sets up `server`, which peers dial, as configured.
+    pub fn apply_server(&self, server: Server) -> Server {
+        server
+            .tcp_nodelay(self.tcp_nodelay)
+            .initial_stream_window_size(self.stream_window)
+            .initial_connection_window_size(self.connection_window)
+            .max_concurrent_streams(self.max_concurrent_streams)
+    }
+}
+
+/// Starts a gRPC server builder, terminating TLS when `tls` is set.
+pub fn server(tls: Option<&TlsConfig>) -> Result<Server, tonic::transport::Error> {
+    let builder = Server::builder();
+    match tls {
+        Some(tls) => builder.tls_config(tls.server_config()),
+        None => Ok(builder),
+    }
+}
+
+/// Builds an endpoint for a `host:port` peer address, dialing over TLS when `tls` is set.
+pub fn endpoint(addr: &str, tls: Option<&TlsConfig>) -> Result<Endpoint, tonic::transport::Error> {
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    let endpoint = Endpoint::from_shared(format!("{}://{}", scheme, addr))?;
+    match tls {
+        Some(tls) => endpoint.tls_config(tls.client_config()),
+        None => Ok(endpoint),
+    }
+}
+
+/// Builds the endpoint a Raft peer at `addr` is dialed with, kept alive and tuned as
+/// configured.
+pub fn peer_endpoint(
+    addr: &str,
+    tls: Option<&TlsConfig>,
+    keepalive: Keepalive,
+    tuning: ChannelTuning,
+) -> Result<Endpoint, tonic::transport::Error> {
+    Ok(tuning.apply(keepalive.apply(endpoint(addr, tls)?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+    const SETTINGS: u8 = 0x4;
+    const WINDOW_UPDATE: u8 = 0x8;
+    const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+    const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+    /// The connection window every HTTP/2 connection starts with, before any update.
+    const INITIAL_CONNECTION_WINDOW: u32 = 65_535;
+
+    fn tuning() -> ChannelTuning {
+        ChannelTuning {
+            tcp_nodelay: false,
+            stream_window: 3 * 1024 * 1024,
+            connection_window: 12 * 1024 * 1024,
+            max_concurrent_streams: 17,
+        }
+    }
+
+    /// Reads HTTP/2 frames off `stream` until it has the sender's settings and the update
+    /// to its connection window, returning both.
+    async fn read_settings(stream: &mut TcpStream) -> (HashMap<u16, u32>, u32) {
+        let (mut settings, mut window_update) = (None, None);
+        while settings.is_none() || window_update.is_none() {
+            let mut header = [0; 9];
+            stream.read_exact(&mut header).await.unwrap();
+            let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+            let (kind, flags) = (header[3], header[4]);
+            let stream_id = u32::from_be_bytes(header[5..9].try_into().unwrap()) & !(1 << 31);
+            let mut payload = vec![0; len];
+            stream.read_exact(&mut payload).await.unwrap();
+
+            const ACK: u8 = 0x1;
+            if kind == SETTINGS && flags & ACK == 0 {
+                let pairs = payload.chunks(6).map(|pair| {
+                    let id = u16::from_be_bytes([pair[0], pair[1]]);
+                    (id, u32::from_be_bytes(pair[2..6].try_into().unwrap()))
+                });
+                settings = Some(pairs.collect());
+            } else if kind == WINDOW_UPDATE && stream_id == 0 {
+                window_update = Some(u32::from_be_bytes(payload[..4].try_into().unwrap()));
+            }
+        }
+        (settings.unwrap(), window_update.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_channel_tuning_is_applied_to_peer_connections() {
+        let tuning = tuning();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let endpoint = peer_endpoint(&addr, None, Keepalive::default(), tuning).unwrap();
+        assert!(!endpoint.get_tcp_nodelay());
+        assert_eq!(endpoint.get_tcp_keepalive(), Some(DEFAULT_KEEPALIVE_INTERVAL));
+
+        // What the channel announces when it dials the peer.
+        tokio::spawn(async move { endpoint.connect().await });
+        let (mut peer, _) = listener.accept().await.unwrap();
+        let mut preface = [0; PREFACE.len()];
+        peer.read_exact(&mut preface).await.unwrap();
+        assert_eq!(preface, PREFACE);
+        let (settings, window_update) = read_settings(&mut peer).await;
+        assert_eq!(settings[&SETTINGS_INITIAL_WINDOW_SIZE], tuning.stream_window);
+        assert_eq!(window_update, tuning.connection_window - INITIAL_CONNECTION_WINDOW);
+    }
+
+    #[tokio::test]
+    async fn test_channel_tuning_is_applied_to_the_raft_server() {
+        let tuning = tuning();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_, health) = tonic_health::server::health_reporter();
+        tokio::spawn(
+            tuning
+                .apply_server(server(None).unwrap())
+                .add_service(health)
+                .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+        );
+
+        // What the server announces to a peer that dials it.
+        let mut peer = TcpStream::connect(addr).await.unwrap();
+        peer.write_all(PREFACE).await.unwrap();
+        peer.write_all(&[0, 0, 0, SETTINGS, 0, 0, 0, 0, 0]).await.unwrap();
+        let (settings, window_update) = read_settings(&mut peer).await;
+        assert_eq!(settings[&SETTINGS_MAX_CONCURRENT_STREAMS], tuning.max_concurrent_streams);
+        assert_eq!(settings[&SETTINGS_INITIAL_WINDOW_SIZE], tuning.stream_window);
+        assert_eq!(window_update, tuning.connection_window - INITIAL_CONNECTION_WINDOW);
+    }
+}
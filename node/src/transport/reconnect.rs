@@ -0,0 +1,130 @@
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::time::Instant;
+use tonic::transport::Channel;
+use tonic::{Code, Status};
+
+/// How long a peer is left alone after a call to it first fails to get through.
+pub const MIN_RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+/// The longest a peer is left alone, however many calls in a row failed.
+pub const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// A channel to one peer that is thrown away once a call on it fails to get through, and
+/// dialed again on the next call after a backoff that doubles with each failure in a
+/// row. A connection that went stale, say behind a NAT that forgot it, costs a few calls
+/// rather than the peer, and a peer that is down is not redialed on every heartbeat.
+#[derive(Debug, Default)]
+pub struct ReconnectingChannel {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    channel: Option<Channel>,
+    /// Calls that failed to get through since the last one that did.
+    failures: u32,
+    /// Set after a failure: the peer is not dialed again before it.
+    retry_at: Option<Instant>,
+}
+
+impl ReconnectingChannel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The channel to the peer, opened with `dial` if the last one was thrown away.
+    /// Fails with `Unavailable`, without dialing, while backing off.
+    pub fn get(&self, dial: impl FnOnce() -> Result<Channel, Status>) -> Result<Channel, Status> {
+        let mut state = self.lock();
+        if let Some(channel) = &state.channel {
+            return Ok(channel.clone());
+        }
+        let now = Instant::now();
+        if let Some(retry_at) = state.retry_at
+            && now < retry_at
+        {
+            let wait = retry_at - now;
+            return Err(Status::unavailable(format!("reconnecting in {:?}", wait)));
+        }
+
+        let channel = dial()?;
+        state.channel = Some(channel.clone());
+        Ok(channel)
+    }
+
+    /// Notes how a call made on the channel went. One that failed to get through throws
+    /// the channel away; any other outcome, errors from the peer included, ends the
+    /// backoff.
+    pub fn record<T>(&self, result: &Result<T, Status>) {
+        let mut state = self.lock();
+        match result {
+            Err(status) if is_disconnect(status) => {
+                state.channel = None;
+                state.failures += 1;
+                state.retry_at = Some(Instant::now() + backoff(state.failures));
+            }
+            _ => {
+                state.failures = 0;
+                state.retry_at = None;
+            }
+        }
+    }
+
+    /// Calls that failed to get through since the last one that did.
+    pub fn failures(&self) -> u32 {
+        self.lock().failures
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Whether `status` says the call never reached the peer or lost its connection, as
+/// opposed to being answered with an error. Tonic reports both connect failures and
+/// expired keepalive pings as `Unavailable`, and a connection reset mid-call as `Unknown`.
+fn is_disconnect(status: &Status) -> bool {
+    matches!(status.code(), Code::Unavailable | Code::Unknown)
+}
+
+fn backoff(failures: u32) -> Duration {
+    let doublings = failures.saturating_sub(1).min(16);
+    (MIN_RECONNECT_BACKOFF * 2u32.pow(doublings)).min(MAX_RECONNECT_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::transport::Endpoint;
+
+    fn dial() -> Result<Channel, Status> {
+        Ok(Endpoint::from_static("http://127.0.0.1:1").connect_lazy())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reconnecting_channel_backs_off_after_failures() {
+        let channel = ReconnectingChannel::new();
+        channel.get(dial).unwrap();
+
+        // An answer from the peer, even an error, keeps the channel.
+        channel.record::<()>(&Err(Status::internal("failed to persist vote")));
+        channel.get(|| panic!("redialed a working channel")).unwrap();
+
+        channel.record::<()>(&Err(Status::unavailable("connection refused")));
+        let err = channel.get(dial).unwrap_err();
+        assert_eq!(err.code(), Code::Unavailable);
+        tokio::time::sleep(MIN_RECONNECT_BACKOFF).await;
+        channel.get(dial).unwrap();
+
+        channel.record::<()>(&Err(Status::unknown("connection reset")));
+        assert_eq!(channel.failures(), 2);
+        tokio::time::sleep(MIN_RECONNECT_BACKOFF).await;
+        assert!(channel.get(dial).is_err());
+        tokio::time::sleep(MIN_RECONNECT_BACKOFF).await;
+        channel.get(dial).unwrap();
+
+        channel.record(&Ok(()));
+        assert_eq!(channel.failures(), 0);
+        assert_eq!(backoff(100), MAX_RECONNECT_BACKOFF);
+    }
+}
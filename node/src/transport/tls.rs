@@ -0,0 +1,211 @@
+use std::path::Path;
+use tonic::transport::{Certificate, ClientTlsConfig, Identity, ServerTlsConfig};
+
+/// Environment variables `TlsConfig::from_env` reads PEM paths from.
+pub const TLS_CERT_ENV: &str = "NODE_TLS_CERT";
+pub const TLS_KEY_ENV: &str = "NODE_TLS_KEY";
+pub const TLS_CA_ENV: &str = "NODE_TLS_CA";
+
+/// TLS material shared by every gRPC server and peer channel a node builds.
+///
+/// The node's certificate is used both as its server identity and, with mutual
+/// TLS, as the client identity it presents to peers. The CA verifies the other
+/// side in both directions.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    identity: Identity,
+    ca: Option<Certificate>,
+    mutual: bool,
+    domain_name: Option<String>,
+}
+
+impl TlsConfig {
+    /// Loads the node certificate, its private key and an optional CA from PEM files.
+    /// Mutual TLS is enabled whenever a CA is given.
+    pub fn from_pem_files(
+        cert: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+        ca: Option<impl AsRef<Path>>,
+    ) -> std::io::Result<Self> {
+        let identity = Identity::from_pem(std::fs::read(cert)?, std::fs::read(key)?);
+        let ca = ca.map(std::fs::read).transpose()?.map(Certificate::from_pem);
+
+        Ok(Self {
+            identity,
+            mutual: ca.is_some(),
+            ca,
+            domain_name: None,
+        })
+    }
+
+    /// Builds a config from the `NODE_TLS_*` variables, or `None` if no certificate is set.
+    pub fn from_env() -> std::io::Result<Option<Self>> {
+        let Ok(cert) = std::env::var(TLS_CERT_ENV) else {
+            return Ok(None);
+        };
+        let key = std::env::var(TLS_KEY_ENV).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is set but {} is not", TLS_CERT_ENV, TLS_KEY_ENV),
+            )
+        })?;
+        let ca = std::env::var(TLS_CA_ENV).ok();
+
+        Self::from_pem_files(cert, key, ca).map(Some)
+    }
+
+    /// Requires (or stops requiring) peers to present a certificate signed by the CA.
+    pub fn with_mutual_tls(mut self, mutual: bool) -> Self {
+        self.mutual = mutual;
+        self
+    }
+
+    /// Overrides the name checked against the server certificate, for dialing by IP.
+    pub fn with_domain_name(mut self, domain_name: impl Into<String>) -> Self {
+        self.domain_name = Some(domain_name.into());
+        self
+    }
+
+    pub fn server_config(&self) -> ServerTlsConfig {
+        let config = ServerTlsConfig::new().identity(self.identity.clone());
+        match (&self.ca, self.mutual) {
+            (Some(ca), true) => config.client_ca_root(ca.clone()),
+            _ => config,
+        }
+    }
+
+    pub fn client_config(&self) -> ClientTlsConfig {
+        let mut config = ClientTlsConfig::new();
+        if let Some(ca) = &self.ca {
+            config = config.ca_certificate(ca.clone());
+        }
+        if self.mutual {
+            config = config.identity(self.identity.clone());
+        }
+        if let Some(domain_name) = &self.domain_name {
+            config = config.domain_name(domain_name.clone());
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, Issuer, KeyPair};
+    use tempfile::TempDir;
+    use bank_api::bank::bank_service_client::BankServiceClient;
+    use bank_api::bank::bank_service_server::BankServiceServer;
+    use bank_api::bank::{AccountId, CreateAccountRequest};
+    use crate::replica::Replica;
+    use crate::service::BankServiceImpl;
+    use crate::transport;
+
+    /// A CA plus certificates it issued, written as PEM files into a temp dir.
+    pub(crate) struct TestPki {
+        pub(crate) dir: TempDir,
+        ca_params: CertificateParams,
+        ca_key: KeyPair,
+    }
+
+    impl TestPki {
+        pub(crate) fn new() -> Self {
+            let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca_key = KeyPair::generate().unwrap();
+            let ca_cert = ca_params.self_signed(&ca_key).unwrap();
+
+            let dir = TempDir::new().unwrap();
+            std::fs::write(dir.path().join("ca.pem"), ca_cert.pem()).unwrap();
+
+            Self { dir, ca_params, ca_key }
+        }
+
+        pub(crate) fn ca_path(&self) -> PathBuf {
+            self.dir.path().join("ca.pem")
+        }
+
+        /// Issues a `localhost` certificate and returns its (cert, key) paths.
+        pub(crate) fn issue(&self, name: &str) -> (PathBuf, PathBuf) {
+            let params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+            let key = KeyPair::generate().unwrap();
+            let issuer = Issuer::from_params(&self.ca_params, &self.ca_key);
+            let cert = params.signed_by(&key, &issuer).unwrap();
+
+            let cert_path = self.dir.path().join(format!("{}.pem", name));
+            let key_path = self.dir.path().join(format!("{}.key", name));
+            std::fs::write(&cert_path, cert.pem()).unwrap();
+            std::fs::write(&key_path, key.serialize_pem()).unwrap();
+            (cert_path, key_path)
+        }
+
+        pub(crate) fn config(&self, name: &str) -> TlsConfig {
+            let (cert, key) = self.issue(name);
+            TlsConfig::from_pem_files(cert, key, Some(self.ca_path()))
+                .unwrap()
+                .with_domain_name("localhost")
+        }
+    }
+
+    async fn serve_bank(tls: &TlsConfig) -> (String, TempDir) {
+        let wal_dir = TempDir::new().unwrap();
+        let wal_path = wal_dir.path().join("bank.wal");
+        let replica = Arc::new(Replica::open(wal_path.to_str().unwrap()).unwrap());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = transport::server(Some(tls))
+            .unwrap()
+            .add_service(BankServiceServer::new(BankServiceImpl::new(replica)));
+        tokio::spawn(server.serve_with_incoming(
+            tokio_stream::wrappers::TcpListenerStream::new(listener),
+        ));
+
+        (addr.to_string(), wal_dir)
+    }
+
+    async fn create_account(addr: &str, tls: &TlsConfig) -> Result<(), Box<dyn std::error::Error>> {
+        let channel = transport::endpoint(addr, Some(tls))?.connect().await?;
+        BankServiceClient::new(channel)
+            .create_account(CreateAccountRequest {
+                account: Some(AccountId { id: "alice".to_string() }),
+                initial_balance: 100,
+            })
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls_accepts_client_with_valid_cert() {
+        let pki = TestPki::new();
+        let (addr, _wal_dir) = serve_bank(&pki.config("server")).await;
+
+        create_account(&addr, &pki.config("client")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls_rejects_client_without_cert() {
+        let pki = TestPki::new();
+        let (addr, _wal_dir) = serve_bank(&pki.config("server")).await;
+
+        // Trusts the server but presents no certificate of its own.
+        let client = pki.config("client").with_mutual_tls(false);
+        assert!(create_account(&addr, &client).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls_rejects_client_from_unknown_ca() {
+        let pki = TestPki::new();
+        let (addr, _wal_dir) = serve_bank(&pki.config("server")).await;
+
+        // A certificate from another CA, presented by a client that still trusts our CA.
+        let rogue = TestPki::new();
+        let (cert, key) = rogue.issue("rogue");
+        let client = TlsConfig::from_pem_files(cert, key, Some(pki.ca_path()))
+            .unwrap()
+            .with_domain_name("localhost");
+        assert!(create_account(&addr, &client).await.is_err());
+    }
+}
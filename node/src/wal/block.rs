@@ -0,0 +1,293 @@
+use std::io::{BufReader, Read};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use bytes::Bytes;
+use crate::wal::entry::LogEntry;
+use crate::wal::format::BLOCK_VERSION;
+use crate::wal::storage::{LogStorage, StorageReader};
+
+/// Bytes a WAL reads from its file at a time when scanning or replaying it, by default;
+/// the same as a plain `BufReader`.
+pub const DEFAULT_READ_BUFFER_BYTES: usize = 8 * 1024;
+
+/// Every block starts with the length of its payload and the payload's CRC-32.
+pub(crate) const BLOCK_HEADER_LEN: u64 = 8;
+
+/// CRC-32 (IEEE) lookup table, one entry per byte value.
+const CRC_TABLE: [u32; 256] = crc_table();
+
+/// Frames `entries` as one block: a header, then the entries encoded back to back, so a
+/// batch is written, checksummed and synced as a unit. Logs older than format version 3
+/// store entries unframed, so for them the entries are just concatenated.
+pub(crate) fn encode_block(entries: &[LogEntry], version: u8) -> std::io::Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    for entry in entries {
+        payload.extend_from_slice(&entry.encode_version(version)?);
+    }
+    if version < BLOCK_VERSION {
+        return Ok(payload);
+    }
+
+    let len = u32::try_from(payload.len()).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("WAL block of {} bytes exceeds the 4 GiB limit", payload.len()),
+        )
+    })?;
+    let mut block = Vec::with_capacity(BLOCK_HEADER_LEN as usize + payload.len());
+    block.write_u32::<LittleEndian>(len)?;
+    block.write_u32::<LittleEndian>(crc32(&payload))?;
+    block.extend_from_slice(&payload);
+    Ok(block)
+}
+
+/// Reads the entries of a WAL back one at a time, unpacking them from their blocks.
+pub(crate) struct EntryReader<S> {
+    storage: S,
+    reader: BufReader<StorageReader<S>>,
+    version: u8,
+    /// Offset just past the last entry returned, or of the first entry before any is.
+    offset: u64,
+    /// Offset of the block holding the last entry returned. Unframed entries are each
+    /// their own block.
+    block_start: u64,
+    /// What is left of the current block's payload. Entries are sliced out of it, so
+    /// their commands share one allocation per block.
+    block: Bytes,
+    /// Whether each block's CRC is checked as it is loaded.
+    verify: bool,
+}
+
+impl<S: LogStorage> EntryReader<S> {
+    /// Reads the entries of a log of format `version` that start at `data_start`.
+    pub(crate) fn new(storage: &S, data_start: u64, version: u8) -> std::io::Result<Self> {
+        Self::with_buffer(storage, data_start, version, DEFAULT_READ_BUFFER_BYTES)
+    }
+
+    /// Like `new`, reading the storage `buffer_bytes` at a time.
+    pub(crate) fn with_buffer(
+        storage: &S,
+        data_start: u64,
+        version: u8,
+        buffer_bytes: usize,
+    ) -> std::io::Result<Self> {
+        Ok(Self {
+            storage: storage.clone(),
+            reader: BufReader::with_capacity(buffer_bytes, StorageReader::new(storage, data_start)),
+            version,
+            offset: data_start,
+            block_start: data_start,
+            block: Bytes::new(),
+            verify: true,
+        })
+    }
+
+    /// Loads blocks without checking their CRC, for a scan that trusts what is on disk.
+    pub(crate) fn without_crc_check(mut self) -> Self {
+        self.verify = false;
+        self
+    }
+
+    pub(crate) fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub(crate) fn block_start(&self) -> u64 {
+        self.block_start
+    }
+
+    /// Whether entries of the block at `block_start` are still to be read.
+    pub(crate) fn mid_block(&self) -> bool {
+        !self.block.is_empty()
+    }
+
+    /// The next entry, or `None` at the end of the log. A block or entry cut short by a
+    /// torn write ends the log too; a whole block that fails its CRC is an error.
+    pub(crate) fn next_entry(&mut self) -> std::io::Result<Option<LogEntry>> {
+        if self.version < BLOCK_VERSION {
+            return match LogEntry::decode_version(&mut self.reader, self.version) {
+                Ok(entry) => {
+                    self.block_start = self.offset;
+                    self.offset += entry.encoded_len_version(self.version);
+                    Ok(Some(entry))
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+                Err(e) => Err(e),
+            };
+        }
+
+        while self.block.is_empty() {
+            if !self.read_block()? {
+                return Ok(None);
+            }
+        }
+        let before = self.block.len();
+        let entry = LogEntry::decode_shared(&mut self.block, self.version).map_err(|e| {
+            invalid_data(format!("WAL block at offset {} is malformed: {}", self.block_start, e))
+        })?;
+        self.offset += (before - self.block.len()) as u64;
+        Ok(Some(entry))
+    }
+
+    /// Loads the block at `offset`, returning false at the end of the log.
+    fn read_block(&mut self) -> std::io::Result<bool> {
+        let mut header = [0; BLOCK_HEADER_LEN as usize];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        let len = (&header[..]).read_u32::<LittleEndian>()?;
+        let payload_start = self.offset + BLOCK_HEADER_LEN;
+        let end = self.storage.len()?;
+        if u64::from(len) > end.saturating_sub(payload_start) {
+            // A torn write leaves a block shorter than its header says, but only as the
+            // last thing in the log: with an intact block after it, the length is damaged.
+            if intact_block_within(&self.storage, payload_start, end)? {
+                return Err(invalid_data(format!(
+                    "WAL block at offset {} claims {} bytes, more than the {} left after it",
+                    self.offset,
+                    len,
+                    end.saturating_sub(payload_start)
+                )));
+            }
+            return Ok(false);
+        }
+        let mut payload = vec![0; len as usize];
+        match self.reader.read_exact(&mut payload) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e),
+        }
+
+        let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        if self.verify && crc32(&payload) != crc {
+            return Err(invalid_data(format!(
+                "WAL block at offset {} fails its CRC check",
+                self.offset
+            )));
+        }
+        self.block_start = self.offset;
+        self.offset += BLOCK_HEADER_LEN;
+        self.block = Bytes::from(payload);
+        Ok(true)
+    }
+}
+
+/// Whether a whole block, one with a payload that passes its CRC check, starts anywhere in
+/// `start..end` of `storage`. Blocks are never empty, so eight zero bytes are not one.
+/// Only read past a block that claims more than is left, so normally just the tail a torn
+/// write left.
+fn intact_block_within<S: LogStorage>(storage: &S, start: u64, end: u64) -> std::io::Result<bool> {
+    let mut bytes = Vec::new();
+    StorageReader::new(storage, start).take(end - start).read_to_end(&mut bytes)?;
+    let header_len = BLOCK_HEADER_LEN as usize;
+    Ok((0..bytes.len().saturating_sub(header_len)).any(|at| {
+        let header = &bytes[at..at + header_len];
+        let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        len > 0
+            && bytes[at + header_len..].get(..len).is_some_and(|payload| crc32(payload) == crc)
+    }))
+}
+
+/// CRC-32 (IEEE), as zlib and Ethernet compute it.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let crc = data.iter().fold(!0u32, |crc, &byte| {
+        CRC_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    });
+    !crc
+}
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::entry::tests::create_test_entry;
+    use crate::wal::format::TIMESTAMP_VERSION;
+    use crate::wal::storage::MemoryStorage;
+
+    fn read_all(storage: &MemoryStorage, version: u8) -> std::io::Result<Vec<LogEntry>> {
+        let mut reader = EntryReader::new(storage, 0, version)?;
+        let mut entries = Vec::new();
+        while let Some(entry) = reader.next_entry()? {
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    #[test]
+    fn test_crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn test_block_roundtrip() {
+        let entries: Vec<LogEntry> =
+            (1..=4).map(|i| create_test_entry(i, 1, format!("entry {}", i).as_bytes())).collect();
+        let storage = MemoryStorage::new();
+        storage.append_bytes(&encode_block(&entries[..3], BLOCK_VERSION).unwrap()).unwrap();
+        storage.append_bytes(&encode_block(&entries[3..], BLOCK_VERSION).unwrap()).unwrap();
+
+        assert_eq!(read_all(&storage, BLOCK_VERSION).unwrap(), entries);
+    }
+
+    #[test]
+    fn test_block_unframed_before_block_version() {
+        let entries = [create_test_entry(1, 1, b"a"), create_test_entry(2, 1, b"b")];
+        let encoded = encode_block(&entries, TIMESTAMP_VERSION).unwrap();
+
+        let concatenated: Vec<u8> = entries
+            .iter()
+            .flat_map(|entry| entry.encode_version(TIMESTAMP_VERSION).unwrap().to_vec())
+            .collect();
+        assert_eq!(encoded, concatenated);
+    }
+
+    #[test]
+    fn test_block_torn_write_ends_the_log() {
+        let first = encode_block(&[create_test_entry(1, 1, b"kept")], BLOCK_VERSION).unwrap();
+        let torn = encode_block(&[create_test_entry(2, 1, b"torn")], BLOCK_VERSION).unwrap();
+        let storage = MemoryStorage::new();
+        storage.append_bytes(&first).unwrap();
+        storage.append_bytes(&torn[..torn.len() - 3]).unwrap();
+
+        let entries = read_all(&storage, BLOCK_VERSION).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].command.as_ref(), b"kept");
+    }
+
+    #[test]
+    fn test_block_with_a_damaged_length_before_the_tail_is_an_error() {
+        let mut first = encode_block(&[create_test_entry(1, 1, b"first")], BLOCK_VERSION).unwrap();
+        let second = encode_block(&[create_test_entry(2, 1, b"second")], BLOCK_VERSION).unwrap();
+        first[..4].copy_from_slice(&0x00FF_FFFFu32.to_le_bytes());
+        let storage = MemoryStorage::new();
+        storage.append_bytes(&first).unwrap();
+        storage.append_bytes(&second).unwrap();
+
+        // Read as a torn tail, it would silently drop the second block.
+        let err = read_all(&storage, BLOCK_VERSION).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("claims 16777215 bytes"), "{}", err);
+    }
+}
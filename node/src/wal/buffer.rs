@@ -0,0 +1,123 @@
+use std::io::Write;
+
+/// Bytes a background-flushed WAL stages before writing them out, by default.
+pub const DEFAULT_WRITE_BUFFER_BYTES: usize = 64 * 1024;
+
+/// Stages small writes in memory and hands them to the file in one `write_all` once
+/// `capacity` would be exceeded, or when flushed ahead of an fsync. A write as large as
+/// the capacity goes straight through, and a capacity of 0 stages nothing.
+#[derive(Debug)]
+pub(crate) struct WriteBuffer {
+    staged: Vec<u8>,
+    capacity: usize,
+}
+
+impl WriteBuffer {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            staged: Vec::new(),
+            capacity,
+        }
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8], sink: &mut impl Write) -> std::io::Result<()> {
+        if self.staged.len() + bytes.len() > self.capacity {
+            self.flush(sink)?;
+        }
+        if bytes.len() >= self.capacity {
+            return sink.write_all(bytes);
+        }
+        self.staged.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Writes out everything staged.
+    pub(crate) fn flush(&mut self, sink: &mut impl Write) -> std::io::Result<()> {
+        if !self.staged.is_empty() {
+            sink.write_all(&self.staged)?;
+            self.staged.clear();
+        }
+        Ok(())
+    }
+
+    /// Stages up to `capacity` bytes from now on, writing out what is staged if it no
+    /// longer fits.
+    pub(crate) fn set_capacity(
+        &mut self,
+        capacity: usize,
+        sink: &mut impl Write,
+    ) -> std::io::Result<()> {
+        if self.staged.len() > capacity {
+            self.flush(sink)?;
+        }
+        self.capacity = capacity;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts the `write` calls made on it, each of which would be a syscall on a file.
+    #[derive(Default)]
+    struct CountingWriter {
+        writes: usize,
+        written: Vec<u8>,
+    }
+
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.writes += 1;
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Writes 100 blocks of 100 bytes through a buffer of `capacity`, flushing at the
+    /// end as before an fsync, and returns the writer.
+    fn write_blocks(capacity: usize) -> CountingWriter {
+        let mut buffer = WriteBuffer::new(capacity);
+        let mut sink = CountingWriter::default();
+        for block in 0..100u8 {
+            buffer.write(&[block; 100], &mut sink).unwrap();
+        }
+        buffer.flush(&mut sink).unwrap();
+        sink
+    }
+
+    #[test]
+    fn test_write_buffer_coalesces_writes() {
+        let unbuffered = write_blocks(0);
+        assert_eq!(unbuffered.writes, 100);
+
+        let buffered = write_blocks(DEFAULT_WRITE_BUFFER_BYTES);
+        assert_eq!(buffered.writes, 1);
+        assert_eq!(buffered.written, unbuffered.written);
+
+        // Two blocks fit at a time.
+        let small = write_blocks(250);
+        assert_eq!(small.writes, 50);
+        assert_eq!(small.written, unbuffered.written);
+    }
+
+    #[test]
+    fn test_write_buffer_passes_large_writes_through() {
+        let mut buffer = WriteBuffer::new(150);
+        let mut sink = CountingWriter::default();
+        buffer.write(&[1; 100], &mut sink).unwrap();
+        buffer.write(&[2; 200], &mut sink).unwrap();
+        assert_eq!(sink.writes, 2);
+        assert_eq!(sink.written.len(), 300);
+
+        buffer.write(&[3; 100], &mut sink).unwrap();
+        buffer.set_capacity(50, &mut sink).unwrap();
+        assert_eq!(sink.writes, 3);
+        buffer.flush(&mut sink).unwrap();
+        assert_eq!(sink.writes, 3);
+    }
+}
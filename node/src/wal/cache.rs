@@ -0,0 +1,108 @@
+use std::collections::VecDeque;
+use std::ops::RangeBounds;
+use crate::wal::entry::LogEntry;
+
+/// The most recently appended entries of a log, kept in memory so that reading them back,
+/// e.g. to replicate them to several followers, does not go to disk. Holds a contiguous
+/// run of indexes ending at the last entry appended.
+#[derive(Debug)]
+pub(crate) struct EntryCache {
+    entries: VecDeque<LogEntry>,
+    capacity: usize,
+}
+
+impl EntryCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Index of the oldest cached entry, or `None` if nothing is cached.
+    pub(crate) fn first_index(&self) -> Option<u64> {
+        self.entries.front().map(|entry| entry.index)
+    }
+
+    /// Caches `entry`, evicting the oldest entry once full. An entry that does not follow
+    /// the last one cached starts the cache over.
+    pub(crate) fn push(&mut self, entry: LogEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.back().is_some_and(|last| last.index + 1 != entry.index) {
+            self.entries.clear();
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// The cached entries in `range`, or `None` unless the cache holds every entry from
+    /// the start of the range to the end of the log.
+    pub(crate) fn range(&self, start: u64, range: &impl RangeBounds<u64>) -> Option<Vec<LogEntry>> {
+        if self.first_index()? > start {
+            return None;
+        }
+        let entries = self.entries.iter().filter(|entry| range.contains(&entry.index));
+        Some(entries.cloned().collect())
+    }
+
+    /// Evicts every entry from `from` onwards.
+    pub(crate) fn truncate_suffix(&mut self, from: u64) {
+        self.entries.retain(|entry| entry.index < from);
+    }
+
+    /// Evicts every entry up to and including `up_to`.
+    pub(crate) fn truncate_prefix(&mut self, up_to: u64) {
+        self.entries.retain(|entry| entry.index > up_to);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::entry::tests::create_test_entry;
+
+    fn indexes(entries: Option<Vec<LogEntry>>) -> Option<Vec<u64>> {
+        entries.map(|entries| entries.iter().map(|entry| entry.index).collect())
+    }
+
+    #[test]
+    fn test_entry_cache_keeps_most_recent() {
+        let mut cache = EntryCache::new(3);
+        for i in 1..=5 {
+            cache.push(create_test_entry(i, 1, b"entry"));
+        }
+
+        assert_eq!(cache.first_index(), Some(3));
+        assert_eq!(indexes(cache.range(3, &(3..))), Some(vec![3, 4, 5]));
+        assert_eq!(indexes(cache.range(4, &(4..=4))), Some(vec![4]));
+        assert_eq!(indexes(cache.range(2, &(2..))), None);
+    }
+
+    #[test]
+    fn test_entry_cache_truncation() {
+        let mut cache = EntryCache::new(10);
+        for i in 1..=5 {
+            cache.push(create_test_entry(i, 1, b"entry"));
+        }
+
+        cache.truncate_suffix(4);
+        assert_eq!(indexes(cache.range(1, &(1..))), Some(vec![1, 2, 3]));
+        cache.truncate_prefix(1);
+        assert_eq!(cache.first_index(), Some(2));
+
+        // A gap starts the cache over.
+        cache.push(create_test_entry(7, 1, b"entry"));
+        assert_eq!(indexes(cache.range(7, &(7..))), Some(vec![7]));
+    }
+
+    #[test]
+    fn test_entry_cache_disabled() {
+        let mut cache = EntryCache::new(0);
+        cache.push(create_test_entry(1, 1, b"entry"));
+        assert_eq!(cache.first_index(), None);
+    }
+}
@@ -0,0 +1,116 @@
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crate::wal::entry::{ChainHash, GENESIS_HASH};
+
+/// Bytes a `CompactionPoint` takes in its file.
+const POINT_LEN: usize = 8 + 8 + GENESIS_HASH.len();
+
+/// The last entry removed by `Wal::truncate_prefix`. The log resumes right after it,
+/// and its chain hash anchors the chain of the entries that remain.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompactionPoint {
+    pub index: u64,
+    pub term: u64,
+    pub chain_hash: ChainHash,
+}
+
+impl CompactionPoint {
+    /// Path of the file recording the compaction point of the WAL at `wal_path`.
+    pub(crate) fn path_for(wal_path: &Path) -> PathBuf {
+        let mut path = wal_path.as_os_str().to_owned();
+        path.push(".compacted");
+        PathBuf::from(path)
+    }
+
+    /// Path of the file recording the term boundaries a term-preserving compaction of the
+    /// WAL at `wal_path` kept.
+    pub(crate) fn terms_path_for(wal_path: &Path) -> PathBuf {
+        let mut path = wal_path.as_os_str().to_owned();
+        path.push(".terms");
+        PathBuf::from(path)
+    }
+
+    /// Loads the compaction point, or the start of the log if nothing was compacted.
+    pub(crate) fn load(path: &Path) -> std::io::Result<Self> {
+        Ok(Self::load_all(path)?.pop().unwrap_or_default())
+    }
+
+    /// Loads every point saved by `save_all`, or none if there is no file at `path`.
+    pub(crate) fn load_all(path: &Path) -> std::io::Result<Vec<Self>> {
+        let mut contents = Vec::new();
+        match std::fs::File::open(path) {
+            Ok(mut file) => file.read_to_end(&mut contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut reader = contents.as_slice();
+        let mut points = Vec::with_capacity(contents.len() / POINT_LEN);
+        while !reader.is_empty() {
+            let index = reader.read_u64::<LittleEndian>()?;
+            let term = reader.read_u64::<LittleEndian>()?;
+            let mut chain_hash = GENESIS_HASH;
+            reader.read_exact(&mut chain_hash)?;
+            points.push(Self {
+                index,
+                term,
+                chain_hash,
+            });
+        }
+        Ok(points)
+    }
+
+    /// Atomically replaces the file at `path` with this compaction point.
+    pub(crate) fn save(&self, path: &Path) -> std::io::Result<()> {
+        Self::save_all(std::slice::from_ref(self), path)
+    }
+
+    /// Atomically replaces the file at `path` with `points`.
+    pub(crate) fn save_all(points: &[Self], path: &Path) -> std::io::Result<()> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        {
+            let mut file = std::fs::File::create(&tmp_path)?;
+            for point in points {
+                file.write_u64::<LittleEndian>(point.index)?;
+                file.write_u64::<LittleEndian>(point.term)?;
+                file.write_all(&point.chain_hash)?;
+            }
+            file.sync_data()?;
+        }
+
+        std::fs::rename(&tmp_path, path)
+    }
+}
+
+/// Carried inside an `io::Error` when a read asks for an entry that compaction removed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Compacted {
+    pub index: u64,
+    pub first_index: u64,
+}
+
+impl Compacted {
+    /// Returns the `Compacted` details if `e` was caused by reading a compacted index.
+    pub fn from_io(e: &std::io::Error) -> Option<&Self> {
+        e.get_ref()?.downcast_ref()
+    }
+
+    pub(crate) fn into_io(self) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::NotFound, self)
+    }
+}
+
+impl std::fmt::Display for Compacted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "entry {} has been compacted; the log starts at {}",
+            self.index, self.first_index
+        )
+    }
+}
+
+impl std::error::Error for Compacted {}
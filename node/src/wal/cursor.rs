@@ -0,0 +1,125 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use crate::wal::entry::LogEntry;
+use crate::wal::wal::Wal;
+
+/// Entries a cursor reads from the file at a time.
+const CURSOR_BATCH: u64 = 256;
+
+/// Iterates the log at a path up to the last index it had when the cursor was made,
+/// however much is appended meanwhile, so that going over the log twice yields the same
+/// entries. `advance` moves that bound up to the end of the log as it is then.
+///
+/// It reads the file in batches, opening it anew for each, so the writer is never held
+/// up. Should the log be compacted past the cursor, it yields the `Compacted` error; should
+/// it be truncated below the bound, iteration ends where the log does.
+#[derive(Debug)]
+pub struct WalCursor {
+    path: PathBuf,
+    next_index: u64,
+    upper_bound: u64,
+    batch: VecDeque<LogEntry>,
+}
+
+impl WalCursor {
+    pub(crate) fn new(path: PathBuf, from_index: u64, upper_bound: u64) -> Self {
+        Self { path, next_index: from_index.max(1), upper_bound, batch: VecDeque::new() }
+    }
+
+    /// The index of the entry the cursor yields next.
+    pub fn next_index(&self) -> u64 {
+        self.batch.front().map_or(self.next_index, |entry| entry.index)
+    }
+
+    /// The last index the cursor yields before it has to be advanced.
+    pub fn upper_bound(&self) -> u64 {
+        self.upper_bound
+    }
+
+    /// Moves the bound up to the last index of the log now, and returns it.
+    pub fn advance(&mut self) -> std::io::Result<u64> {
+        let wal = Wal::open_read_only(&self.path.to_string_lossy())?;
+        self.upper_bound = self.upper_bound.max(wal.last_index());
+        Ok(self.upper_bound)
+    }
+
+    fn read_batch(&mut self) -> std::io::Result<()> {
+        let wal = Wal::open_read_only(&self.path.to_string_lossy())?;
+        let end = self.upper_bound.min(self.next_index.saturating_add(CURSOR_BATCH - 1));
+        let entries = wal.replay_range(self.next_index..=end)?;
+        self.next_index = match entries.last() {
+            Some(last) => last.index + 1,
+            // The log was truncated under the cursor: there is nothing left to yield.
+            None => self.upper_bound + 1,
+        };
+        self.batch.extend(entries);
+        Ok(())
+    }
+}
+
+impl Iterator for WalCursor {
+    type Item = std::io::Result<LogEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.batch.is_empty()
+            && self.next_index <= self.upper_bound
+            && let Err(e) = self.read_batch()
+        {
+            return Some(Err(e));
+        }
+        self.batch.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use crate::wal::entry::tests::create_test_entry;
+
+    #[test]
+    fn test_cursor_ignores_entries_appended_until_advanced() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("bank.wal");
+        let path = path.to_str().unwrap();
+        let mut wal = Wal::new(path).unwrap();
+        for index in 1..=3 {
+            wal.append(create_test_entry(index, 1, b"before")).unwrap();
+        }
+
+        let mut cursor = Wal::open_read_only(path).unwrap().cursor(1).unwrap();
+        assert_eq!(cursor.next().unwrap().unwrap().index, 1);
+        for index in 4..=6 {
+            wal.append(create_test_entry(index, 1, b"after")).unwrap();
+        }
+        let indexes: Vec<u64> = cursor.by_ref().map(|entry| entry.unwrap().index).collect();
+        assert_eq!(indexes, vec![2, 3]);
+        assert_eq!((cursor.next_index(), cursor.upper_bound()), (4, 3));
+
+        assert_eq!(cursor.advance().unwrap(), 6);
+        let entries: Vec<LogEntry> = cursor.map(|entry| entry.unwrap()).collect();
+        let indexes: Vec<u64> = entries.iter().map(|entry| entry.index).collect();
+        assert_eq!(indexes, vec![4, 5, 6]);
+        assert!(entries.iter().all(|entry| entry.command.as_ref() == b"after"));
+    }
+
+    #[test]
+    fn test_cursor_reads_a_log_longer_than_one_batch() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("bank.wal");
+        let path = path.to_str().unwrap();
+        let mut wal = Wal::new(path).unwrap();
+        let last = CURSOR_BATCH * 2 + 10;
+        for index in 1..=last {
+            wal.append(create_test_entry(index, 1, b"command")).unwrap();
+        }
+        wal.truncate_prefix(5).unwrap();
+
+        let indexes: Vec<u64> = wal.cursor(6).unwrap().map(|entry| entry.unwrap().index).collect();
+        assert_eq!(indexes, (6..=last).collect::<Vec<u64>>());
+
+        // Starting below the compaction point fails, as `replay_range` does.
+        let mut cursor = wal.cursor(2).unwrap();
+        assert!(cursor.next().unwrap().is_err());
+    }
+}
@@ -0,0 +1,142 @@
+use std::fmt;
+use crate::wal::cursor::WalCursor;
+use crate::wal::entry::LogEntry;
+use crate::wal::wal::Wal;
+
+/// Where two logs first disagree, as `first_divergence` finds it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Divergence {
+    /// The entries that should both be at `index` carry other indexes, which only a
+    /// damaged log does.
+    Index { index: u64, left: u64, right: u64 },
+    /// Entry `index` is from term `left` in one log and `right` in the other.
+    Term { index: u64, left: u64, right: u64 },
+    /// Entry `index` is from the same term in both logs, with another command.
+    Command { index: u64 },
+    /// The left log ends before entry `index`, which the right one holds.
+    LeftEnds { index: u64 },
+    /// The right log ends before entry `index`, which the left one holds.
+    RightEnds { index: u64 },
+}
+
+impl Divergence {
+    /// The first index at which the logs disagree.
+    pub fn index(&self) -> u64 {
+        match *self {
+            Self::Index { index, .. }
+            | Self::Term { index, .. }
+            | Self::Command { index }
+            | Self::LeftEnds { index }
+            | Self::RightEnds { index } => index,
+        }
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Index { index, left, right } => write!(
+                f,
+                "where entry {} should be, the left log has entry {} and the right {}",
+                index, left, right
+            ),
+            Self::Term { index, left, right } => write!(
+                f,
+                "entry {} is from term {} on the left and {} on the right",
+                index, left, right
+            ),
+            Self::Command { index } => write!(f, "entry {} carries a different command", index),
+            Self::LeftEnds { index } => write!(f, "the left log ends before entry {}", index),
+            Self::RightEnds { index } => write!(f, "the right log ends before entry {}", index),
+        }
+    }
+}
+
+/// Compares the logs at `left` and `right` entry by entry, from the first index both
+/// still hold, and returns where they first differ in index, term or command, or `None`
+/// if they hold the same entries. Both are read a batch at a time, in step, as a
+/// `WalCursor` reads them, so neither is loaded whole; both may be live logs, of which
+/// only the entries there when the comparison starts are compared.
+pub fn first_divergence(left: &str, right: &str) -> std::io::Result<Option<Divergence>> {
+    let (left, right) = (Wal::open_read_only(left)?, Wal::open_read_only(right)?);
+    let from = left.first_index().max(right.first_index());
+    let (mut left, mut right) = (left.cursor(from)?, right.cursor(from)?);
+    let mut index = from;
+    loop {
+        let divergence = match (next(&mut left)?, next(&mut right)?) {
+            (None, None) => return Ok(None),
+            (None, Some(_)) => Divergence::LeftEnds { index },
+            (Some(_), None) => Divergence::RightEnds { index },
+            (Some(l), Some(r)) if l.index != index || r.index != index => {
+                Divergence::Index { index, left: l.index, right: r.index }
+            }
+            (Some(l), Some(r)) if l.term != r.term => {
+                Divergence::Term { index, left: l.term, right: r.term }
+            }
+            (Some(l), Some(r)) if l.command != r.command => Divergence::Command { index },
+            (Some(_), Some(_)) => {
+                index += 1;
+                continue;
+            }
+        };
+        return Ok(Some(divergence));
+    }
+}
+
+fn next(cursor: &mut WalCursor) -> std::io::Result<Option<LogEntry>> {
+    cursor.next().transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use crate::wal::entry::tests::create_test_entry;
+
+    fn write_log(temp_dir: &TempDir, name: &str, entries: &[(u64, &[u8])]) -> String {
+        let path = temp_dir.path().join(name);
+        let path = path.to_str().unwrap().to_string();
+        let mut wal = Wal::new(&path).unwrap();
+        for (i, (term, command)) in entries.iter().enumerate() {
+            wal.append(create_test_entry(i as u64 + 1, *term, command)).unwrap();
+        }
+        path
+    }
+
+    #[test]
+    fn test_identical_logs_do_not_diverge() {
+        let temp_dir = TempDir::new().unwrap();
+        let entries: Vec<(u64, &[u8])> = (0..600).map(|i| (1 + i / 100, &b"command"[..])).collect();
+        let left = write_log(&temp_dir, "left.wal", &entries);
+        let right = write_log(&temp_dir, "right.wal", &entries);
+
+        assert_eq!(first_divergence(&left, &right).unwrap(), None);
+        assert_eq!(first_divergence(&left, &left).unwrap(), None);
+    }
+
+    #[test]
+    fn test_logs_diverge_at_the_first_entry_that_differs() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut entries: Vec<(u64, &[u8])> = (0..400).map(|_| (1, &b"command"[..])).collect();
+        let left = write_log(&temp_dir, "left.wal", &entries);
+
+        // Past the first batch a cursor reads, and differing again after.
+        entries[299] = (1, b"other command");
+        entries[349] = (2, b"command");
+        let right = write_log(&temp_dir, "right.wal", &entries);
+        let divergence = first_divergence(&left, &right).unwrap().unwrap();
+        assert_eq!(divergence, Divergence::Command { index: 300 });
+        assert_eq!(divergence.index(), 300);
+
+        entries[299] = (1, b"command");
+        let right = write_log(&temp_dir, "term.wal", &entries);
+        let divergence = Divergence::Term { index: 350, left: 1, right: 2 };
+        assert_eq!(first_divergence(&left, &right).unwrap(), Some(divergence));
+
+        let shorter = write_log(&temp_dir, "shorter.wal", &entries[..120]);
+        let divergence = Some(Divergence::RightEnds { index: 121 });
+        assert_eq!(first_divergence(&left, &shorter).unwrap(), divergence);
+        let divergence = Some(Divergence::LeftEnds { index: 121 });
+        assert_eq!(first_divergence(&shorter, &left).unwrap(), divergence);
+    }
+}
@@ -0,0 +1,88 @@
+use std::path::Path;
+
+/// Permission bits a WAL file is created with: readable and writable by its owner only,
+/// as it holds every account's history. Unix only; the process umask can still clear
+/// bits from a mode, as it does for any file created.
+pub const DEFAULT_FILE_MODE: u32 = 0o600;
+/// Permission bits a directory created for WAL files is given.
+pub const DEFAULT_DIR_MODE: u32 = 0o700;
+
+/// Options that create a file with permission bits `mode`. It is set as the file is
+/// created, so there is no moment when the file is more open than that. Opening a file
+/// that exists leaves its bits as they are.
+pub(crate) fn open_options(mode: u32) -> std::fs::OpenOptions {
+    let mut options = std::fs::OpenOptions::new();
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+    #[cfg(not(unix))]
+    let _ = mode;
+    options
+}
+
+/// Creates `dir` and any missing parents, each with permission bits `mode`.
+pub fn create_dir_all(dir: &Path, mode: u32) -> std::io::Result<()> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, mode);
+    #[cfg(not(unix))]
+    let _ = mode;
+    builder.create(dir)
+}
+
+/// Fsyncs the directory holding `path`, so that a file just created in it or renamed
+/// into it survives a crash. Syncing the file itself only covers its contents: on ext4
+/// and the like, the directory entry naming it can still be lost until this is done.
+pub(crate) fn sync_parent_dir(path: &Path) -> std::io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    sync_dir(dir)
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    std::fs::File::open(dir)?.sync_all()
+}
+
+/// The standard library cannot open a directory to fsync it elsewhere.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+    use super::*;
+
+    #[test]
+    fn test_sync_parent_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("bank.wal");
+        std::fs::write(&path, b"").unwrap();
+        sync_parent_dir(&path).unwrap();
+        // A bare file name lives in the current directory.
+        sync_parent_dir(Path::new("bank.wal")).unwrap();
+
+        let missing = temp_dir.path().join("gone").join("bank.wal");
+        let err = sync_parent_dir(&missing).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_create_dir_all_sets_mode() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path().join("data").join("wal");
+        create_dir_all(&dir, DEFAULT_DIR_MODE).unwrap();
+
+        for dir in [dir.parent().unwrap(), &dir] {
+            let mode = std::fs::metadata(dir).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, DEFAULT_DIR_MODE, "{:?}", dir);
+        }
+        // Like `fs::create_dir_all`, a directory that exists is fine.
+        create_dir_all(&dir, DEFAULT_DIR_MODE).unwrap();
+    }
+}
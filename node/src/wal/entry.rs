@@ -1,28 +1,162 @@
 use std::io::Read;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use bytes::Bytes;
+use bytes::{Buf, Bytes};
+use sha2::{Digest, Sha256};
+use crate::wal::block::crc32;
+use crate::wal::format::{FLAGS_VERSION, FORMAT_VERSION, TIMESTAMP_VERSION};
 
-#[derive(Clone, Debug)]
+/// SHA-256 over the previous entry's chain hash and this entry's contents.
+pub type ChainHash = [u8; 32];
+
+/// The chain hash that precedes the first entry of a log.
+pub const GENESIS_HASH: ChainHash = [0; 32];
+
+/// From format version 4, every entry has a flags byte saying which optional fields it
+/// carries. The low four bits are critical: they change how the entry reads, so a reader
+/// that does not know one refuses the entry. The high four are not: their fields sit at
+/// the end of the entry's trailer, after the ones the reader knows, and are skipped.
+pub(crate) const CRITICAL_FLAGS: u8 = 0x0F;
+/// Critical: the trailer starts with the entry's timestamp.
+pub(crate) const FLAG_TIMESTAMP: u8 = 0x01;
+/// Not critical: the trailer holds a CRC-32 of the entry up to it.
+pub(crate) const FLAG_CHECKSUM: u8 = 0x10;
+const KNOWN_FLAGS: u8 = FLAG_TIMESTAMP | FLAG_CHECKSUM;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct LogEntry {
     pub index: u64,
     pub term: u64,
     pub command: Bytes,
+    /// Links this entry to every entry before it. The WAL sets it on append.
+    pub chain_hash: ChainHash,
+    /// When the entry was first appended, in unix milliseconds. The WAL stamps entries
+    /// that arrive without one; entries from logs older than format version 2 have none.
+    pub timestamp: Option<u64>,
 }
 
 impl LogEntry {
+    pub fn new(index: u64, term: u64, command: Bytes) -> Self {
+        Self {
+            index,
+            term,
+            command,
+            chain_hash: GENESIS_HASH,
+            timestamp: None,
+        }
+    }
+
+    /// The command as a plain slice, for apply paths that only read it.
+    pub fn command_slice(&self) -> &[u8] {
+        &self.command
+    }
+
+    /// The chain hash this entry has when it follows an entry hashed to `prev`.
+    pub fn chain_from(&self, prev: &ChainHash) -> ChainHash {
+        let mut hasher = Sha256::new();
+        hasher.update(prev);
+        hasher.update(self.index.to_le_bytes());
+        hasher.update(self.term.to_le_bytes());
+        hasher.update((self.command.len() as u64).to_le_bytes());
+        hasher.update(&self.command);
+        if let Some(timestamp) = self.timestamp {
+            hasher.update(timestamp.to_le_bytes());
+        }
+        hasher.finalize().into()
+    }
+
+    /// Size of the entry once encoded: index, term and command length, the command, the
+    /// chain hash and, from format version 2, the timestamp. From version 4 the entry
+    /// also has its flags and a trailer, which holds the timestamp only if there is one.
+    pub fn encoded_len(&self) -> u64 {
+        self.encoded_len_version(FORMAT_VERSION)
+    }
+
+    pub(crate) fn encoded_len_version(&self, version: u8) -> u64 {
+        self.encoded_len_flagged(version, 0)
+    }
+
+    fn encoded_len_flagged(&self, version: u8, extra_flags: u8) -> u64 {
+        let fixed = 3 * 8 + self.command.len() + self.chain_hash.len();
+        let rest = if version >= FLAGS_VERSION {
+            1 + 2 + trailer_len(self.flags(extra_flags))
+        } else if version >= TIMESTAMP_VERSION {
+            8
+        } else {
+            0
+        };
+        (fixed + rest) as u64
+    }
+
+    /// The flags this entry is written with: whichever of `extra_flags` the writer asks
+    /// for, and `FLAG_TIMESTAMP` if it has a timestamp.
+    fn flags(&self, extra_flags: u8) -> u8 {
+        let timestamp = if self.timestamp.is_some() { FLAG_TIMESTAMP } else { 0 };
+        (extra_flags & KNOWN_FLAGS & !FLAG_TIMESTAMP) | timestamp
+    }
+
     pub fn encode(&self) -> std::io::Result<Bytes> {
-        let mut buf = Vec::new();
+        self.encode_version(FORMAT_VERSION)
+    }
+
+    /// Encodes the entry as a log of format `version` stores it. Versions before 2 have
+    /// no room for the timestamp, so it is dropped.
+    pub(crate) fn encode_version(&self, version: u8) -> std::io::Result<Bytes> {
+        self.encode_flagged(version, 0)
+    }
+
+    /// Like `encode_version`, also setting the optional `extra_flags` the entry can carry,
+    /// such as `FLAG_CHECKSUM`. They are dropped for versions before 4.
+    pub(crate) fn encode_flagged(&self, version: u8, extra_flags: u8) -> std::io::Result<Bytes> {
+        let mut buf = Vec::with_capacity(self.encoded_len_flagged(version, extra_flags) as usize);
         buf.write_u64::<LittleEndian>(self.index)?;
         buf.write_u64::<LittleEndian>(self.term)?;
+        let flags = self.flags(extra_flags);
+        if version >= FLAGS_VERSION {
+            buf.write_u8(flags)?;
+        }
 
         let command_len = self.command.len() as u64;
         buf.write_u64::<LittleEndian>(command_len)?;
         buf.extend_from_slice(&self.command);
+        buf.extend_from_slice(&self.chain_hash);
+        if version >= FLAGS_VERSION {
+            buf.write_u16::<LittleEndian>(trailer_len(flags) as u16)?;
+            if let Some(timestamp) = self.timestamp {
+                buf.write_u64::<LittleEndian>(timestamp)?;
+            }
+            if flags & FLAG_CHECKSUM != 0 {
+                let crc = crc32(&buf);
+                buf.write_u32::<LittleEndian>(crc)?;
+            }
+        } else if version >= TIMESTAMP_VERSION {
+            // 0 stands for an entry without a timestamp.
+            buf.write_u64::<LittleEndian>(self.timestamp.unwrap_or_default())?;
+        }
 
         Ok(Bytes::from(buf))
     }
 
     pub fn decode<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        Self::decode_version(reader, FORMAT_VERSION)
+    }
+
+    /// Decodes an entry from a log of format `version`.
+    pub(crate) fn decode_version<R: Read>(reader: &mut R, version: u8) -> std::io::Result<Self> {
+        if version >= FLAGS_VERSION {
+            // Flagged entries have a variable length, so read one whole and slice it.
+            let mut head = [0; 3 * 8 + 1];
+            reader.read_exact(&mut head)?;
+            let command_len = u64::from_le_bytes(head[17..].try_into().unwrap());
+            let rest_len = command_len.checked_add(GENESIS_HASH.len() as u64 + 2).ok_or_else(|| {
+                invalid_data(format!("entry claims a command of {} bytes", command_len))
+            })?;
+            let mut buf = head.to_vec();
+            read_appended(reader, &mut buf, rest_len)?;
+            let trailer_len = u16::from_le_bytes([buf[buf.len() - 2], buf[buf.len() - 1]]);
+            read_appended(reader, &mut buf, u64::from(trailer_len))?;
+            return Self::decode_shared(&mut Bytes::from(buf), version);
+        }
+
         let index = reader.read_u64::<LittleEndian>()?;
         let term = reader.read_u64::<LittleEndian>()?;
         let command_len = reader.read_u64::<LittleEndian>()? as usize;
@@ -30,25 +164,196 @@ impl LogEntry {
         let mut command_buf = vec![0u8; command_len];
         reader.read_exact(&mut command_buf)?;
 
+        let mut chain_hash = GENESIS_HASH;
+        reader.read_exact(&mut chain_hash)?;
+
+        let timestamp = if version >= TIMESTAMP_VERSION {
+            Some(reader.read_u64::<LittleEndian>()?).filter(|&t| t != 0)
+        } else {
+            None
+        };
+
         Ok(LogEntry {
             index,
             term,
             command: Bytes::from(command_buf),
+            chain_hash,
+            timestamp,
         })
     }
+
+    /// Like `decode_version`, decoding from the front of `buf` and advancing past the
+    /// entry. The command is sliced out of `buf`, sharing its allocation, not copied.
+    pub(crate) fn decode_shared(buf: &mut Bytes, version: u8) -> std::io::Result<Self> {
+        if version >= FLAGS_VERSION {
+            return Self::decode_flagged(buf);
+        }
+
+        let timestamp_len = if version >= TIMESTAMP_VERSION { 8 } else { 0 };
+        let command_len = buf.get(16..24).ok_or_else(cut_short)?;
+        let command_len = u64::from_le_bytes(command_len.try_into().unwrap()) as usize;
+        let needed = command_len.checked_add(3 * 8 + GENESIS_HASH.len() + timestamp_len);
+        if needed.is_none_or(|needed| buf.len() < needed) {
+            return Err(cut_short());
+        }
+
+        let index = buf.get_u64_le();
+        let term = buf.get_u64_le();
+        buf.advance(8);
+        let command = buf.split_to(command_len);
+        let mut chain_hash = GENESIS_HASH;
+        buf.copy_to_slice(&mut chain_hash);
+        let timestamp = if version >= TIMESTAMP_VERSION {
+            Some(buf.get_u64_le()).filter(|&t| t != 0)
+        } else {
+            None
+        };
+
+        Ok(LogEntry {
+            index,
+            term,
+            command,
+            chain_hash,
+            timestamp,
+        })
+    }
+
+    /// Decodes a flagged entry, of format version 4 or later, from the front of `buf`.
+    /// Fails on a critical flag this build does not know; unknown flags that are not
+    /// critical are skipped along with their part of the trailer.
+    fn decode_flagged(buf: &mut Bytes) -> std::io::Result<Self> {
+        let command_len = buf.get(17..25).ok_or_else(cut_short)?;
+        let command_len = u64::from_le_bytes(command_len.try_into().unwrap()) as usize;
+        let fixed_len = command_len.checked_add(3 * 8 + 1 + GENESIS_HASH.len() + 2);
+        let trailer_len = fixed_len
+            .and_then(|fixed_len| buf.get(fixed_len - 2..fixed_len))
+            .map(|len| u16::from_le_bytes([len[0], len[1]]) as usize)
+            .ok_or_else(cut_short)?;
+        let len = fixed_len.unwrap() + trailer_len;
+        if buf.len() < len {
+            return Err(cut_short());
+        }
+        let mut entry = buf.split_to(len);
+        let whole = entry.clone();
+
+        let index = entry.get_u64_le();
+        let term = entry.get_u64_le();
+        let flags = entry.get_u8();
+        let unknown_critical = flags & !KNOWN_FLAGS & CRITICAL_FLAGS;
+        if unknown_critical != 0 {
+            return Err(invalid_data(format!(
+                "entry {} has critical flags {:#04x} this build does not know",
+                index, unknown_critical
+            )));
+        }
+        if trailer_len < self::trailer_len(flags) {
+            return Err(invalid_data(format!(
+                "entry {} has a trailer too short for its flags {:#04x}",
+                index, flags
+            )));
+        }
+        entry.advance(8);
+        let command = entry.split_to(command_len);
+        let mut chain_hash = GENESIS_HASH;
+        entry.copy_to_slice(&mut chain_hash);
+        entry.advance(2);
+        let timestamp = (flags & FLAG_TIMESTAMP != 0).then(|| entry.get_u64_le());
+        if flags & FLAG_CHECKSUM != 0 {
+            let covered = whole.len() - entry.len();
+            if entry.get_u32_le() != crc32(&whole[..covered]) {
+                return Err(invalid_data(format!("entry {} fails its checksum", index)));
+            }
+        }
+
+        Ok(LogEntry {
+            index,
+            term,
+            command,
+            chain_hash,
+            timestamp,
+        })
+    }
+}
+
+/// Size of the trailer fields of an entry with `flags`, counting the flags this build knows.
+fn trailer_len(flags: u8) -> usize {
+    let timestamp = if flags & FLAG_TIMESTAMP != 0 { 8 } else { 0 };
+    let checksum = if flags & FLAG_CHECKSUM != 0 { 4 } else { 0 };
+    timestamp + checksum
+}
+
+/// Reads `len` more bytes from `reader` onto the end of `buf`.
+/// Reads the next `len` bytes onto the end of `buf`, failing with `InvalidData` if the
+/// reader runs out first. The length is untrusted, so the buffer only grows as far as
+/// there are bytes to fill it.
+fn read_appended<R: Read>(reader: &mut R, buf: &mut Vec<u8>, len: u64) -> std::io::Result<()> {
+    let read = reader.take(len).read_to_end(buf)? as u64;
+    if read < len {
+        return Err(invalid_data(format!(
+            "entry claims {} more bytes than the {} left to read",
+            len, read
+        )));
+    }
+    Ok(())
+}
+
+fn cut_short() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "entry is cut short")
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+/// Carried inside an `io::Error` when a command is refused for being larger than the
+/// log accepts in one entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EntryTooLarge {
+    /// Size of the command, in bytes.
+    pub bytes: u64,
+    pub limit: u64,
+}
+
+impl EntryTooLarge {
+    /// Fails with `EntryTooLarge` if a command of `bytes` is over `limit`.
+    pub(crate) fn check(bytes: u64, limit: u64) -> std::io::Result<()> {
+        if bytes > limit {
+            return Err(Self { bytes, limit }.into_io());
+        }
+        Ok(())
+    }
+
+    /// Returns the `EntryTooLarge` details if `e` was caused by an oversized command.
+    pub fn from_io(e: &std::io::Error) -> Option<&Self> {
+        e.get_ref()?.downcast_ref()
+    }
+
+    pub(crate) fn into_io(self) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, self)
+    }
 }
 
+impl std::fmt::Display for EntryTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "command of {} bytes is over the limit of {} bytes per log entry",
+            self.bytes, self.limit
+        )
+    }
+}
+
+impl std::error::Error for EntryTooLarge {}
+
 #[cfg(test)]
 pub(super) mod tests {
     use bytes::Bytes;
-    use crate::wal::entry::LogEntry;
+    use crate::wal::block::crc32;
+    use crate::wal::entry::{LogEntry, FLAG_CHECKSUM, FLAG_TIMESTAMP, GENESIS_HASH};
+    use crate::wal::format::{FORMAT_VERSION, TIMESTAMP_VERSION};
 
     pub(crate) fn create_test_entry(index: u64, term: u64, command: &[u8]) -> LogEntry {
-        LogEntry {
-            index,
-            term,
-            command: Bytes::from(command.to_vec()),
-        }
+        LogEntry::new(index, term, Bytes::from(command.to_vec()))
     }
 
     #[test]
@@ -62,6 +367,225 @@ pub(super) mod tests {
         assert_eq!(entry.index, decoded.index);
         assert_eq!(entry.term, decoded.term);
         assert_eq!(entry.command, decoded.command);
+        assert_eq!(entry.encoded_len(), encoded.len() as u64);
+    }
+
+    #[test]
+    fn test_log_entry_encode_decode_timestamp() {
+        let mut entry = create_test_entry(7, 2, b"test command");
+        entry.timestamp = Some(1_700_000_000_000);
+        let encoded = entry.encode().unwrap();
+
+        let mut cursor = std::io::Cursor::new(encoded.as_ref());
+        let decoded = LogEntry::decode(&mut cursor).unwrap();
+
+        assert_eq!(decoded.timestamp, Some(1_700_000_000_000));
+        assert_eq!(entry.encoded_len(), encoded.len() as u64);
+
+        // An entry appended without a timestamp comes back without one.
+        let encoded = create_test_entry(7, 2, b"test command").encode().unwrap();
+        let mut cursor = std::io::Cursor::new(encoded.as_ref());
+        assert_eq!(LogEntry::decode(&mut cursor).unwrap().timestamp, None);
+    }
+
+    #[test]
+    fn test_log_entry_encode_decode_before_timestamps() {
+        let version = TIMESTAMP_VERSION - 1;
+        let mut entry = create_test_entry(7, 2, b"test command");
+        entry.timestamp = Some(1_700_000_000_000);
+        let encoded = entry.encode_version(version).unwrap();
+        assert_eq!(entry.encoded_len_version(version), encoded.len() as u64);
+        assert_eq!(encoded.len() as u64 + 8, entry.encoded_len_version(TIMESTAMP_VERSION));
+
+        let mut cursor = std::io::Cursor::new(encoded.as_ref());
+        let decoded = LogEntry::decode_version(&mut cursor, version).unwrap();
+        assert_eq!(decoded.index, 7);
+        assert_eq!(decoded.command, entry.command);
+        assert_eq!(decoded.timestamp, None);
+    }
+
+    /// Encodes `entry` with `flags` set on top of its own, and `extra` appended to its
+    /// trailer, as a writer that knows more flags would.
+    fn encode_with_unknown_flags(entry: &LogEntry, flags: u8, extra: &[u8]) -> Bytes {
+        let mut encoded = entry.encode().unwrap().to_vec();
+        encoded[16] |= flags;
+        let at = 3 * 8 + 1 + entry.command.len() + GENESIS_HASH.len();
+        let trailer_len = u16::from_le_bytes([encoded[at], encoded[at + 1]]);
+        let trailer_len = trailer_len + extra.len() as u16;
+        encoded[at..at + 2].copy_from_slice(&trailer_len.to_le_bytes());
+        encoded.extend_from_slice(extra);
+        Bytes::from(encoded)
+    }
+
+    fn decode_both_ways(encoded: &Bytes) -> std::io::Result<LogEntry> {
+        let mut cursor = std::io::Cursor::new(encoded.as_ref());
+        let read = LogEntry::decode(&mut cursor);
+        let mut rest = encoded.clone();
+        let shared = LogEntry::decode_shared(&mut rest, FORMAT_VERSION);
+        match (read, shared) {
+            (Ok(read), Ok(shared)) => {
+                assert_eq!(read, shared);
+                assert!(rest.is_empty());
+                Ok(read)
+            }
+            (Err(read), Err(shared)) => {
+                assert_eq!(read.kind(), shared.kind());
+                Err(read)
+            }
+            (read, shared) => panic!("decoders disagree: {:?} vs {:?}", read, shared),
+        }
+    }
+
+    #[test]
+    fn test_log_entry_flag_timestamp() {
+        let entry = create_test_entry(7, 2, b"test command");
+        let encoded = entry.encode().unwrap();
+        assert_eq!(encoded[16], 0);
+        assert_eq!(decode_both_ways(&encoded).unwrap().timestamp, None);
+
+        // Only an entry with a timestamp stores one, so 0 is a timestamp like any other.
+        let stamped = LogEntry {
+            timestamp: Some(0),
+            ..entry.clone()
+        };
+        let encoded = stamped.encode().unwrap();
+        assert_eq!(encoded[16], FLAG_TIMESTAMP);
+        assert_eq!(encoded.len() as u64, entry.encoded_len() + 8);
+        assert_eq!(stamped.encoded_len(), encoded.len() as u64);
+        assert_eq!(decode_both_ways(&encoded).unwrap(), stamped);
+    }
+
+    #[test]
+    fn test_log_entry_flag_checksum() {
+        let mut entry = create_test_entry(7, 2, b"test command");
+        entry.timestamp = Some(1_700_000_000_000);
+        let encoded = entry.encode_flagged(FORMAT_VERSION, FLAG_CHECKSUM).unwrap();
+        assert_eq!(encoded[16], FLAG_TIMESTAMP | FLAG_CHECKSUM);
+        assert_eq!(encoded.len() as u64, entry.encoded_len() + 4);
+        assert_eq!(decode_both_ways(&encoded).unwrap(), entry);
+
+        let mut corrupt = encoded.to_vec();
+        corrupt[30] ^= 1;
+        let err = decode_both_ways(&Bytes::from(corrupt)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("checksum"), "{}", err);
+
+        // Formats without flags have nowhere to put the checksum.
+        let version = FORMAT_VERSION - 1;
+        let unflagged = entry.encode_flagged(version, FLAG_CHECKSUM).unwrap();
+        assert_eq!(unflagged, entry.encode_version(version).unwrap());
+    }
+
+    #[test]
+    fn test_log_entry_refuses_unknown_critical_flags() {
+        let mut entry = create_test_entry(7, 2, b"test command");
+        entry.timestamp = Some(1_700_000_000_000);
+        for flag in [0x02, 0x04, 0x08] {
+            let encoded = encode_with_unknown_flags(&entry, flag, &[]);
+            let err = decode_both_ways(&encoded).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains("critical flags"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_log_entry_tolerates_unknown_non_critical_flags() {
+        let mut entry = create_test_entry(7, 2, b"test command");
+        entry.timestamp = Some(1_700_000_000_000);
+        for flag in [0x20, 0x40, 0x80] {
+            let encoded = encode_with_unknown_flags(&entry, flag, b"from a newer writer");
+            assert_eq!(decode_both_ways(&encoded).unwrap(), entry);
+        }
+
+        // Known fields still read right when unknown ones follow them in the trailer.
+        let mut encoded = entry.encode_flagged(FORMAT_VERSION, FLAG_CHECKSUM).unwrap().to_vec();
+        let at = 3 * 8 + 1 + entry.command.len() + GENESIS_HASH.len();
+        encoded[16] |= 0x20;
+        encoded[at..at + 2].copy_from_slice(&(12u16 + 3).to_le_bytes());
+        let crc = crc32(&encoded[..at + 2 + 8]);
+        encoded[at + 2 + 8..].copy_from_slice(&crc.to_le_bytes());
+        encoded.extend_from_slice(b"new");
+        let encoded = Bytes::from(encoded);
+        assert_eq!(decode_both_ways(&encoded).unwrap(), entry);
+
+        // A trailer too short for the flags the reader knows is not skipped over.
+        let mut short = entry.encode().unwrap().to_vec();
+        short[at..at + 2].copy_from_slice(&4u16.to_le_bytes());
+        short.truncate(short.len() - 4);
+        let err = decode_both_ways(&Bytes::from(short)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_log_entry_decode_shared_slices_the_buffer() {
+        let mut entry = create_test_entry(3, 1, b"shared command");
+        entry.timestamp = Some(1_700_000_000_000);
+        let mut encoded = entry.encode().unwrap().to_vec();
+        encoded.extend_from_slice(&create_test_entry(4, 1, b"next").encode().unwrap());
+        let buf = Bytes::from(encoded);
+
+        let mut rest = buf.clone();
+        let decoded = LogEntry::decode_shared(&mut rest, FORMAT_VERSION).unwrap();
+        assert_eq!(decoded, entry);
+        assert_eq!(decoded.command_slice(), b"shared command");
+        let backing = buf.as_ptr_range();
+        assert!(backing.contains(&decoded.command_slice().as_ptr()));
+
+        assert_eq!(LogEntry::decode_shared(&mut rest, FORMAT_VERSION).unwrap().index, 4);
+        assert!(rest.is_empty());
+        let err = LogEntry::decode_shared(&mut rest, FORMAT_VERSION).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_log_entry_decode_rejects_a_command_length_past_the_end() {
+        let encoded = create_test_entry(1, 1, b"test").encode().unwrap().to_vec();
+        for command_len in [1 << 40, u64::MAX] {
+            let mut corrupt = encoded.clone();
+            corrupt[17..25].copy_from_slice(&command_len.to_le_bytes());
+            let mut cursor = std::io::Cursor::new(corrupt);
+            let err = LogEntry::decode(&mut cursor).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData, "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_log_entry_decode_shared_rejects_truncated_entry() {
+        let encoded = create_test_entry(1, 1, b"test").encode().unwrap();
+        let mut truncated = encoded.slice(..encoded.len() - 1);
+
+        let err = LogEntry::decode_shared(&mut truncated, FORMAT_VERSION).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_log_entry_encode_decode_chain_hash() {
+        let mut entry = create_test_entry(1, 1, b"test command");
+        entry.chain_hash = entry.chain_from(&GENESIS_HASH);
+        let encoded = entry.encode().unwrap();
+
+        let mut cursor = std::io::Cursor::new(encoded.as_ref());
+        let decoded = LogEntry::decode(&mut cursor).unwrap();
+
+        assert_ne!(decoded.chain_hash, GENESIS_HASH);
+        assert_eq!(decoded.chain_hash, entry.chain_hash);
+    }
+
+    #[test]
+    fn test_log_entry_chain_covers_every_field() {
+        let entry = create_test_entry(2, 1, b"test command");
+        let hash = entry.chain_from(&GENESIS_HASH);
+
+        assert_ne!(entry.chain_from(&[1; 32]), hash);
+        assert_ne!(create_test_entry(3, 1, b"test command").chain_from(&GENESIS_HASH), hash);
+        assert_ne!(create_test_entry(2, 2, b"test command").chain_from(&GENESIS_HASH), hash);
+        assert_ne!(create_test_entry(2, 1, b"test commanD").chain_from(&GENESIS_HASH), hash);
+
+        let stamped = LogEntry {
+            timestamp: Some(1),
+            ..entry.clone()
+        };
+        assert_ne!(stamped.chain_from(&GENESIS_HASH), hash);
     }
 
     #[test]
@@ -0,0 +1,244 @@
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use crate::metrics::WalMetrics;
+use crate::wal::buffer::WriteBuffer;
+use crate::wal::storage::{LogStorage, StorageWriter};
+
+/// Fsyncs a WAL on a background thread, so appends return once written and every
+/// entry written since the last flush shares one fsync (group commit). Appends are
+/// staged in a `WriteBuffer` that is written out before each fsync, so they share write
+/// syscalls too.
+#[derive(Debug)]
+pub(crate) struct Flusher<S> {
+    shared: Arc<Shared<S>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug)]
+struct Shared<S> {
+    state: Mutex<State<S>>,
+    changed: Condvar,
+}
+
+#[derive(Debug)]
+struct State<S> {
+    storage: S,
+    /// Appended bytes not yet written to `storage`.
+    buffer: WriteBuffer,
+    /// Last index appended, whether still in `buffer`, written or durable.
+    written: u64,
+    /// Last index known to be on disk.
+    durable: u64,
+    /// How long the last fsync took.
+    last_fsync: Option<Duration>,
+    /// Set once an fsync fails; nothing written after it can be trusted to be durable.
+    error: Option<String>,
+    stopping: bool,
+    stopped: bool,
+}
+
+/// Waits for entries appended through a background-flushed WAL to reach disk.
+#[derive(Clone, Debug)]
+pub struct DurableWaiter {
+    shared: Arc<dyn Durability>,
+}
+
+/// What a `DurableWaiter` needs of a flusher, whatever storage it flushes.
+trait Durability: std::fmt::Debug + Send + Sync {
+    fn durable_index(&self) -> u64;
+    fn wait_durable(&self, index: u64) -> std::io::Result<()>;
+}
+
+impl<S: LogStorage> Flusher<S> {
+    /// Starts flushing `storage` every `interval`, with everything up to `durable` already
+    /// on disk, staging up to `buffer_bytes` of appends in between.
+    pub(crate) fn spawn(
+        storage: &S,
+        durable: u64,
+        interval: Duration,
+        buffer_bytes: usize,
+        metrics: WalMetrics,
+    ) -> std::io::Result<Self> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                storage: storage.clone(),
+                buffer: WriteBuffer::new(buffer_bytes),
+                written: durable,
+                durable,
+                last_fsync: None,
+                error: None,
+                stopping: false,
+                stopped: false,
+            }),
+            changed: Condvar::new(),
+        });
+
+        let thread = std::thread::Builder::new().name("wal-flusher".to_string()).spawn({
+            let shared = shared.clone();
+            move || shared.run(interval, metrics)
+        })?;
+
+        Ok(Self {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// Stages `encoded`, the entries up to `index`, for the next flush. Fails if an
+    /// earlier flush did.
+    pub(crate) fn write(&self, encoded: &[u8], index: u64) -> std::io::Result<()> {
+        let mut state = self.shared.lock();
+        state.check()?;
+        let state = &mut *state;
+        state.buffer.write(encoded, &mut StorageWriter(&state.storage))?;
+        state.written = index;
+        Ok(())
+    }
+
+    /// Writes out whatever is staged, without syncing it, so reading the log sees it.
+    pub(crate) fn write_out(&self) -> std::io::Result<()> {
+        let mut state = self.shared.lock();
+        let state = &mut *state;
+        state.buffer.flush(&mut StorageWriter(&state.storage))
+    }
+
+    pub(crate) fn set_buffer_capacity(&self, bytes: usize) -> std::io::Result<()> {
+        let mut state = self.shared.lock();
+        let state = &mut *state;
+        state.buffer.set_capacity(bytes, &mut StorageWriter(&state.storage))
+    }
+
+    /// Switches to flushing `storage`, whose contents up to `durable` are already on disk.
+    pub(crate) fn replace_storage(&self, storage: &S, durable: u64) {
+        let mut state = self.shared.lock();
+        state.storage = storage.clone();
+        state.written = state.written.max(durable);
+        state.durable = state.durable.max(durable);
+        self.shared.changed.notify_all();
+    }
+
+    /// Records that everything after `last` was removed and the rest is on disk.
+    pub(crate) fn truncated(&self, last: u64) {
+        let mut state = self.shared.lock();
+        state.written = last;
+        state.durable = last;
+        self.shared.changed.notify_all();
+    }
+
+    pub(crate) fn last_fsync_duration(&self) -> Option<Duration> {
+        self.shared.lock().last_fsync
+    }
+
+    pub(crate) fn waiter(&self) -> DurableWaiter {
+        DurableWaiter {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<S> Drop for Flusher<S> {
+    /// Flushes whatever is still pending before the thread exits.
+    fn drop(&mut self) {
+        self.shared.lock().stopping = true;
+        self.shared.changed.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl<S: LogStorage> Shared<S> {
+    fn run(&self, interval: Duration, metrics: WalMetrics) {
+        let mut state = self.lock();
+        loop {
+            state = self
+                .changed
+                .wait_timeout_while(state, interval, |state| !state.stopping)
+                .unwrap()
+                .0;
+
+            let target = state.written;
+            if target > state.durable && state.error.is_none() {
+                let storage = {
+                    let state = &mut *state;
+                    let written = state.buffer.flush(&mut StorageWriter(&state.storage));
+                    written.map(|()| state.storage.clone())
+                };
+                drop(state);
+
+                let fsync_started = Instant::now();
+                let result = storage.and_then(|storage| storage.sync());
+                let fsync = fsync_started.elapsed();
+                metrics.fsync_seconds.observe(fsync.as_secs_f64());
+
+                state = self.lock();
+                state.last_fsync = Some(fsync);
+                match result {
+                    Ok(()) => state.durable = state.durable.max(target),
+                    Err(e) => state.error = Some(e.to_string()),
+                }
+                self.changed.notify_all();
+            }
+
+            if state.stopping {
+                break;
+            }
+        }
+
+        state.stopped = true;
+        self.changed.notify_all();
+    }
+}
+
+impl<S> Shared<S> {
+    fn lock(&self) -> MutexGuard<'_, State<S>> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl<S> State<S> {
+    fn check(&self) -> std::io::Result<()> {
+        match &self.error {
+            Some(error) => Err(std::io::Error::other(format!("WAL flush failed: {}", error))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl DurableWaiter {
+    /// Last index known to be on disk.
+    pub fn durable_index(&self) -> u64 {
+        self.shared.durable_index()
+    }
+
+    /// Blocks until `index` is on disk. Fails if a flush fails first, or if the WAL is
+    /// closed without `index` having been written.
+    pub fn wait_durable(&self, index: u64) -> std::io::Result<()> {
+        self.shared.wait_durable(index)
+    }
+}
+
+impl<S: std::fmt::Debug + Send> Durability for Shared<S> {
+    fn durable_index(&self) -> u64 {
+        self.lock().durable
+    }
+
+    fn wait_durable(&self, index: u64) -> std::io::Result<()> {
+        let state = self
+            .changed
+            .wait_while(self.lock(), |state| {
+                state.durable < index && state.error.is_none() && !state.stopped
+            })
+            .unwrap();
+
+        if state.durable >= index {
+            return Ok(());
+        }
+        state.check()?;
+        Err(std::io::Error::new(
+            std::io::ErrorKind::BrokenPipe,
+            format!("WAL closed before entry {} was durable", index),
+        ))
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use crate::wal::entry::{ChainHash, LogEntry};
+use crate::wal::read_only::ReadOnlyWal;
+use crate::wal::wal::Wal;
+
+/// How often a follower checks the log for changes by default.
+pub const DEFAULT_FOLLOW_INTERVAL: Duration = Duration::from_millis(50);
+/// Entries a follower can have yielded and still find where a truncated log parts from
+/// what it saw; past that it starts again from the head of the log.
+const FOLLOW_HISTORY: usize = 1024;
+/// Entries a follower reads ahead of a consumer that has not caught up.
+const FOLLOW_BUFFER: usize = 64;
+
+/// Carried inside an `io::Error` a follower yields when the log was truncated under it:
+/// entries it yielded from `next_index` on are no longer in the log, or those it had yet
+/// to yield before it were compacted away. The stream carries on from `next_index`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LogReset {
+    pub next_index: u64,
+}
+
+impl LogReset {
+    /// Returns the `LogReset` details if `e` reports a log truncated under a follower.
+    pub fn from_io(e: &std::io::Error) -> Option<&Self> {
+        e.get_ref()?.downcast_ref()
+    }
+
+    fn into_io(self) -> std::io::Error {
+        std::io::Error::other(self)
+    }
+}
+
+impl std::fmt::Display for LogReset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the log was truncated; following resumes at {}", self.next_index)
+    }
+}
+
+impl std::error::Error for LogReset {}
+
+/// Reads a log file as another process appends to it, in a thread of its own.
+struct Follower {
+    path: PathBuf,
+    interval: Duration,
+    next_index: u64,
+    /// Chain hashes of the entries yielded most recently, by index.
+    yielded: BTreeMap<u64, ChainHash>,
+    /// Length and modification time of the file when it was last read.
+    seen: Option<(u64, SystemTime)>,
+}
+
+impl Follower {
+    fn run(mut self, tx: mpsc::Sender<std::io::Result<LogEntry>>) {
+        while !tx.is_closed() {
+            let result = self.changed().and_then(|changed| match changed {
+                true => self.read(&tx),
+                false => Ok(()),
+            });
+            if let Err(e) = result {
+                let _ = tx.blocking_send(Err(e));
+                return;
+            }
+            std::thread::sleep(self.interval);
+        }
+    }
+
+    /// Whether the file was written to since it was last read.
+    fn changed(&mut self) -> std::io::Result<bool> {
+        let metadata = std::fs::metadata(&self.path)?;
+        let seen = Some((metadata.len(), metadata.modified()?));
+        Ok(std::mem::replace(&mut self.seen, seen) != seen)
+    }
+
+    /// Sends every entry from `next_index` to the end of the log, after a `LogReset` if
+    /// the log no longer holds what was sent before.
+    fn read(&mut self, tx: &mpsc::Sender<std::io::Result<LogEntry>>) -> std::io::Result<()> {
+        let wal = Wal::open_read_only(&self.path.to_string_lossy())?;
+        let next_index = self.resume_at(&wal)?;
+        if next_index != self.next_index {
+            self.next_index = next_index;
+            self.yielded.retain(|&index, _| index < next_index);
+            if tx.blocking_send(Err(LogReset { next_index }.into_io())).is_err() {
+                return Ok(());
+            }
+        }
+        if self.next_index > wal.last_index() {
+            return Ok(());
+        }
+
+        for entry in wal.replay_range(self.next_index..)? {
+            self.next_index = entry.index + 1;
+            self.yielded.insert(entry.index, entry.chain_hash);
+            if self.yielded.len() > FOLLOW_HISTORY {
+                self.yielded.pop_first();
+            }
+            if tx.blocking_send(Ok(entry)).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Where following picks up in `wal`: `next_index` while the log still holds the
+    /// entries yielded before it, otherwise just past the last of them it does hold.
+    fn resume_at(&self, wal: &ReadOnlyWal) -> std::io::Result<u64> {
+        let first_index = wal.first_index();
+        if self.next_index < first_index {
+            return Ok(first_index);
+        }
+        for (&index, hash) in self.yielded.iter().rev() {
+            if self.holds(wal, index, hash)? {
+                return Ok(index + 1);
+            }
+        }
+        // Either nothing was sent yet, or the log parts from everything that was.
+        match self.yielded.is_empty() {
+            true => Ok(self.next_index),
+            false => Ok(first_index),
+        }
+    }
+
+    /// Whether `wal` still has the entry at `index` that was yielded with `hash`.
+    fn holds(&self, wal: &ReadOnlyWal, index: u64, hash: &ChainHash) -> std::io::Result<bool> {
+        let compacted = wal.compaction_point();
+        if index > wal.last_index() {
+            return Ok(false);
+        }
+        if index < compacted.index {
+            // Compaction only removes entries that can no longer change.
+            return Ok(true);
+        }
+        if index == compacted.index {
+            return Ok(compacted.chain_hash == *hash);
+        }
+        Ok(wal.read_at(index)?.is_some_and(|entry| entry.chain_hash == *hash))
+    }
+}
+
+/// Follows the log at `path` from `from_index`, checking every `interval`.
+pub(crate) fn follow(
+    path: PathBuf,
+    from_index: u64,
+    interval: Duration,
+) -> impl Stream<Item = std::io::Result<LogEntry>> {
+    let (tx, rx) = mpsc::channel(FOLLOW_BUFFER);
+    let follower = Follower {
+        path,
+        interval,
+        next_index: from_index.max(1),
+        yielded: BTreeMap::new(),
+        seen: None,
+    };
+    std::thread::spawn(move || follower.run(tx));
+    ReceiverStream::new(rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use tokio_stream::StreamExt;
+    use crate::wal::entry::tests::create_test_entry;
+
+    async fn next(
+        stream: &mut (impl Stream<Item = std::io::Result<LogEntry>> + Unpin),
+    ) -> std::io::Result<LogEntry> {
+        tokio::time::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .expect("follower yielded nothing")
+            .expect("follower stream ended")
+    }
+
+    #[tokio::test]
+    async fn test_follow_yields_entries_appended_after_it_started() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("bank.wal");
+        let path = path.to_str().unwrap();
+        let mut wal = Wal::new(path).unwrap();
+        for index in 1..=3 {
+            wal.append(create_test_entry(index, 1, b"before")).unwrap();
+        }
+
+        let mut stream = Box::pin(Wal::open_read_only(path).unwrap().follow(2));
+        for index in 2..=3 {
+            assert_eq!(next(&mut stream).await.unwrap().index, index);
+        }
+
+        for index in 4..=6 {
+            wal.append(create_test_entry(index, 1, b"after")).unwrap();
+        }
+        for index in 4..=6 {
+            let entry = next(&mut stream).await.unwrap();
+            assert_eq!((entry.index, entry.command.as_ref()), (index, &b"after"[..]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_follow_resets_when_the_log_is_truncated_under_it() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("bank.wal");
+        let path = path.to_str().unwrap();
+        let mut wal = Wal::new(path).unwrap();
+        for index in 1..=5 {
+            wal.append(create_test_entry(index, 1, b"term 1")).unwrap();
+        }
+
+        let mut stream = Box::pin(wal.follow(1));
+        for index in 1..=5 {
+            assert_eq!(next(&mut stream).await.unwrap().index, index);
+        }
+
+        // A new leader overwrites the last two entries.
+        wal.truncate_suffix(4).unwrap();
+        for index in 4..=6 {
+            wal.append(create_test_entry(index, 2, b"term 2")).unwrap();
+        }
+        let err = next(&mut stream).await.unwrap_err();
+        assert_eq!(LogReset::from_io(&err), Some(&LogReset { next_index: 4 }));
+        for index in 4..=6 {
+            let entry = next(&mut stream).await.unwrap();
+            assert_eq!((entry.index, entry.term), (index, 2));
+        }
+
+        // Compaction past where a new follower starts skips it ahead.
+        wal.truncate_prefix(3).unwrap();
+        let mut stream = Box::pin(wal.follow(2));
+        let err = next(&mut stream).await.unwrap_err();
+        assert_eq!(LogReset::from_io(&err), Some(&LogReset { next_index: 4 }));
+        assert_eq!(next(&mut stream).await.unwrap().index, 4);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::wal::storage::LogStorage;
+
+/// Opens every WAL file written with a header.
+const MAGIC: [u8; 4] = *b"BWAL";
+/// 0xFEFF as this build writes it: little-endian, like every other multi-byte field.
+const BYTE_ORDER_MARK: [u8; 2] = [0xFF, 0xFE];
+/// The same mark written big-endian.
+const SWAPPED_BYTE_ORDER_MARK: [u8; 2] = [0xFE, 0xFF];
+
+/// Version 2 added entry timestamps, version 3 framed entries in blocks and version 4
+/// gave each entry a flags byte.
+pub(crate) const FORMAT_VERSION: u8 = 4;
+/// The first version whose entries carry a timestamp.
+pub(crate) const TIMESTAMP_VERSION: u8 = 2;
+/// The first version that stores entries in checksummed blocks.
+pub(crate) const BLOCK_VERSION: u8 = 3;
+/// The first version whose entries carry flags for their optional fields.
+pub(crate) const FLAGS_VERSION: u8 = 4;
+/// Logs without a header store entries the way version 1 does.
+pub(crate) const HEADERLESS_VERSION: u8 = 1;
+pub(crate) const HEADER_LEN: u64 = 8;
+
+/// The header at the start of a WAL file: magic, format version, byte-order mark and a
+/// reserved byte. Logs written before the header existed start straight with an entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct FileHeader {
+    pub(crate) version: u8,
+}
+
+impl FileHeader {
+    pub(crate) fn current() -> Self {
+        Self {
+            version: FORMAT_VERSION,
+        }
+    }
+
+    pub(crate) fn encode(&self) -> [u8; HEADER_LEN as usize] {
+        let [m0, m1, m2, m3] = MAGIC;
+        let [b0, b1] = BYTE_ORDER_MARK;
+        [m0, m1, m2, m3, self.version, b0, b1, 0]
+    }
+
+    /// Reads the header of the log in `storage`, or returns `None` for a log without one.
+    /// Fails if the header says the entries were written in another byte order or a newer
+    /// format.
+    pub(crate) fn read(storage: &impl LogStorage) -> std::io::Result<Option<Self>> {
+        let mut header = [0; HEADER_LEN as usize];
+        let mut read = 0;
+        while read < header.len() {
+            match storage.read_range(read as u64, &mut header[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        Self::decode(&header[..read])
+    }
+
+    fn decode(header: &[u8]) -> std::io::Result<Option<Self>> {
+        if header.len() < HEADER_LEN as usize || header[..4] != MAGIC {
+            return Ok(None);
+        }
+
+        let version = header[4];
+        match [header[5], header[6]] {
+            BYTE_ORDER_MARK => {}
+            SWAPPED_BYTE_ORDER_MARK => {
+                return Err(invalid_data(
+                    "WAL was written big-endian, but this reader expects little-endian"
+                        .to_string(),
+                ));
+            }
+            mark => {
+                return Err(invalid_data(format!(
+                    "WAL has an unknown byte-order mark {:02X?}",
+                    mark
+                )));
+            }
+        }
+        if version > FORMAT_VERSION {
+            return Err(invalid_data(format!(
+                "WAL format version {} is newer than the supported version {}",
+                version, FORMAT_VERSION
+            )));
+        }
+
+        Ok(Some(Self { version }))
+    }
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_header_roundtrip() {
+        let header = FileHeader::current();
+        assert_eq!(FileHeader::decode(&header.encode()).unwrap(), Some(header));
+    }
+
+    #[test]
+    fn test_file_header_absent_from_legacy_log() {
+        assert_eq!(FileHeader::decode(&[]).unwrap(), None);
+        assert_eq!(FileHeader::decode(&1u64.to_le_bytes()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_file_header_rejects_big_endian_log() {
+        let mut header = FileHeader::current().encode();
+        header[5..7].copy_from_slice(&SWAPPED_BYTE_ORDER_MARK);
+
+        let err = FileHeader::decode(&header).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("big-endian"), "{}", err);
+
+        header[5..7].copy_from_slice(&[0, 0]);
+        assert!(FileHeader::decode(&header).unwrap_err().to_string().contains("byte-order mark"));
+    }
+
+    #[test]
+    fn test_file_header_rejects_newer_version() {
+        let header = FileHeader {
+            version: FORMAT_VERSION + 1,
+        };
+        assert!(FileHeader::decode(&header.encode()).is_err());
+    }
+}
@@ -0,0 +1,200 @@
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crate::wal::block::crc32;
+use crate::wal::compaction::CompactionPoint;
+use crate::wal::entry::{ChainHash, GENESIS_HASH};
+use crate::wal::storage::{LogStorage, StorageReader};
+
+/// Entries a WAL appends between two saves of its tail index.
+pub(crate) const INDEX_EVERY_ENTRIES: u64 = 4096;
+/// Bytes of the log just before the indexed offset that the index checksums.
+const CHECKED_TAIL_BYTES: u64 = 4096;
+/// Entries between two blocks a `SeekIndex` records, at least.
+pub(crate) const SEEK_EVERY_ENTRIES: u64 = 256;
+
+/// Offsets of blocks spread through the log, by the index of the first entry in each, so
+/// reading an entry starts at the closest block before it rather than the head of the
+/// log. Only every few hundred entries are recorded, which keeps it small in memory and
+/// in the tail index.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct SeekIndex {
+    /// The first index and the offset of each block recorded, in order.
+    points: Vec<(u64, u64)>,
+}
+
+impl SeekIndex {
+    /// Records that the block starting with `index` is at `offset`, if it starts at
+    /// least `SEEK_EVERY_ENTRIES` past the last block recorded.
+    pub(crate) fn record(&mut self, index: u64, offset: u64) {
+        let due = self.points.last().is_none_or(|&(last, _)| index >= last + SEEK_EVERY_ENTRIES);
+        if due {
+            self.points.push((index, offset));
+        }
+    }
+
+    /// Offset of the last block recorded that starts at or before `index`, if any.
+    pub(crate) fn offset_for(&self, index: u64) -> Option<u64> {
+        let after = self.points.partition_point(|&(first, _)| first <= index);
+        after.checked_sub(1).map(|at| self.points[at].1)
+    }
+
+    /// Forgets the blocks holding `from` and later, once they are cut from the log.
+    pub(crate) fn truncate(&mut self, from: u64) {
+        let kept = self.points.partition_point(|&(first, _)| first < from);
+        self.points.truncate(kept);
+    }
+
+    fn write(&self, w: &mut impl Write) -> std::io::Result<()> {
+        w.write_u64::<LittleEndian>(self.points.len() as u64)?;
+        for &(index, offset) in &self.points {
+            w.write_u64::<LittleEndian>(index)?;
+            w.write_u64::<LittleEndian>(offset)?;
+        }
+        Ok(())
+    }
+
+    /// Reads what `write` wrote, or nothing from an index saved before it held blocks.
+    fn read(r: &mut impl Read) -> std::io::Result<Self> {
+        let count = match r.read_u64::<LittleEndian>() {
+            Ok(count) => count,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        let mut points = Vec::new();
+        for _ in 0..count {
+            points.push((r.read_u64::<LittleEndian>()?, r.read_u64::<LittleEndian>()?));
+        }
+        Ok(Self { points })
+    }
+}
+
+/// Where the log stood at some offset, saved next to it so opening can pick up the scan
+/// from there rather than read every entry, along with where its blocks are up to there.
+/// Everything it says is trusted once the checksum of the bytes just before `offset`
+/// still matches the log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TailIndex {
+    /// Offset just past the last entry indexed.
+    pub(crate) offset: u64,
+    pub(crate) last_index: u64,
+    pub(crate) last_hash: ChainHash,
+    pub(crate) last_timestamp: u64,
+    /// Index of the compaction point the log had; compacting rewrites the log.
+    pub(crate) compacted: u64,
+    /// CRC-32 of up to `CHECKED_TAIL_BYTES` of the log just before `offset`.
+    tail_crc: u32,
+    /// Blocks before `offset`.
+    pub(crate) seek: SeekIndex,
+}
+
+impl TailIndex {
+    /// Path of the index kept for the WAL at `wal_path`.
+    pub(crate) fn path_for(wal_path: &Path) -> PathBuf {
+        let mut path = wal_path.as_os_str().to_owned();
+        path.push(".index");
+        PathBuf::from(path)
+    }
+
+    /// Indexes the log in `storage`, whose entries start at `data_start`, up to `offset`.
+    pub(crate) fn new(
+        storage: &impl LogStorage,
+        data_start: u64,
+        offset: u64,
+        last: (u64, ChainHash, u64),
+        compacted: &CompactionPoint,
+        seek: &SeekIndex,
+    ) -> std::io::Result<Self> {
+        let (last_index, last_hash, last_timestamp) = last;
+        Ok(Self {
+            offset,
+            last_index,
+            last_hash,
+            last_timestamp,
+            compacted: compacted.index,
+            tail_crc: tail_crc(storage, data_start, offset)?,
+            seek: seek.clone(),
+        })
+    }
+
+    /// Loads the index, or returns `None` if there is none or it cannot be read whole.
+    pub(crate) fn load(path: &Path) -> std::io::Result<Option<Self>> {
+        let mut file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut read = || -> std::io::Result<Self> {
+            let offset = file.read_u64::<LittleEndian>()?;
+            let last_index = file.read_u64::<LittleEndian>()?;
+            let mut last_hash = GENESIS_HASH;
+            file.read_exact(&mut last_hash)?;
+            Ok(Self {
+                offset,
+                last_index,
+                last_hash,
+                last_timestamp: file.read_u64::<LittleEndian>()?,
+                compacted: file.read_u64::<LittleEndian>()?,
+                tail_crc: file.read_u32::<LittleEndian>()?,
+                seek: SeekIndex::read(&mut file)?,
+            })
+        };
+        match read() {
+            Ok(index) => Ok(Some(index)),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Atomically replaces the file at `path` with this index.
+    pub(crate) fn save(&self, path: &Path) -> std::io::Result<()> {
+        let tmp_path = path.with_extension("index.tmp");
+        {
+            let mut file = std::fs::File::create(&tmp_path)?;
+            file.write_u64::<LittleEndian>(self.offset)?;
+            file.write_u64::<LittleEndian>(self.last_index)?;
+            file.write_all(&self.last_hash)?;
+            file.write_u64::<LittleEndian>(self.last_timestamp)?;
+            file.write_u64::<LittleEndian>(self.compacted)?;
+            file.write_u32::<LittleEndian>(self.tail_crc)?;
+            self.seek.write(&mut file)?;
+            file.sync_data()?;
+        }
+
+        std::fs::rename(&tmp_path, path)
+    }
+
+    /// Removes the index at `path`, if there is one.
+    pub(crate) fn remove(path: &Path) -> std::io::Result<()> {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the index still describes the log in `storage`: the log reaches `offset`,
+    /// has the same compaction point, and holds the same bytes just before `offset`.
+    pub(crate) fn matches(
+        &self,
+        storage: &impl LogStorage,
+        data_start: u64,
+        compacted: &CompactionPoint,
+    ) -> std::io::Result<bool> {
+        if self.compacted != compacted.index
+            || self.offset < data_start
+            || self.offset > storage.len()?
+        {
+            return Ok(false);
+        }
+        Ok(tail_crc(storage, data_start, self.offset)? == self.tail_crc)
+    }
+}
+
+/// CRC-32 of up to `CHECKED_TAIL_BYTES` of the log before `offset`, none before
+/// `data_start`.
+fn tail_crc(storage: &impl LogStorage, data_start: u64, offset: u64) -> std::io::Result<u32> {
+    let start = offset.saturating_sub(CHECKED_TAIL_BYTES).max(data_start);
+    let mut tail = vec![0u8; (offset - start) as usize];
+    StorageReader::new(storage, start).read_exact(&mut tail)?;
+    Ok(crc32(&tail))
+}
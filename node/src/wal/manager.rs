@@ -0,0 +1,181 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use crate::wal::dir::{self, DEFAULT_DIR_MODE};
+use crate::wal::wal::Wal;
+
+const WAL_PREFIX: &str = "group-";
+const WAL_EXTENSION: &str = "wal";
+
+/// Owns a directory holding one WAL per Raft group, stored as `group-<id>.wal`.
+#[derive(Debug)]
+pub struct WalManager {
+    dir: PathBuf,
+    open: Arc<Mutex<BTreeSet<String>>>,
+}
+
+impl WalManager {
+    /// Uses `dir` for group WALs, creating it with `DEFAULT_DIR_MODE` if needed.
+    pub fn new(dir: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let dir = dir.into();
+        dir::create_dir_all(&dir, DEFAULT_DIR_MODE)?;
+        Ok(Self {
+            dir,
+            open: Arc::new(Mutex::new(BTreeSet::new())),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Path of the WAL for `group`. Group ids may only hold ASCII letters, digits, `-`
+    /// and `_`, so distinct groups never share a file.
+    pub fn path_for(&self, group: &str) -> std::io::Result<PathBuf> {
+        validate_group(group)?;
+        Ok(self.dir.join(format!("{}{}.{}", WAL_PREFIX, group, WAL_EXTENSION)))
+    }
+
+    /// Opens (or creates) the WAL for `group`. Fails with `AlreadyExists` while another
+    /// `Wal` for the same group is still open.
+    pub fn open(&self, group: &str) -> std::io::Result<Wal> {
+        let path = self.path_for(group)?;
+        let path = path.to_str().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("WAL path {:?} is not valid UTF-8", path),
+            )
+        })?;
+
+        if !self.open.lock().unwrap().insert(group.to_string()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("WAL for group {:?} is already open", group),
+            ));
+        }
+        let lease = GroupLease {
+            group: group.to_string(),
+            open: self.open.clone(),
+        };
+
+        Ok(Wal::new(path)?.with_lease(lease))
+    }
+
+    /// Ids of the groups with a WAL in the directory, for recovery on startup.
+    pub fn groups(&self) -> std::io::Result<Vec<String>> {
+        let mut groups = Vec::new();
+        for dir_entry in std::fs::read_dir(&self.dir)? {
+            let path = dir_entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(WAL_EXTENSION) {
+                continue;
+            }
+            let group = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.strip_prefix(WAL_PREFIX));
+            if let Some(group) = group.filter(|group| validate_group(group).is_ok()) {
+                groups.push(group.to_string());
+            }
+        }
+
+        groups.sort();
+        Ok(groups)
+    }
+}
+
+/// Marks a group's WAL as open until the `Wal` holding it is dropped.
+#[derive(Debug)]
+pub(crate) struct GroupLease {
+    group: String,
+    open: Arc<Mutex<BTreeSet<String>>>,
+}
+
+impl Drop for GroupLease {
+    fn drop(&mut self) {
+        self.open.lock().unwrap().remove(&self.group);
+    }
+}
+
+fn validate_group(group: &str) -> std::io::Result<()> {
+    let valid = !group.is_empty()
+        && group.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    if !valid {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid WAL group id {:?}", group),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use crate::wal::entry::tests::create_test_entry;
+
+    #[test]
+    fn test_wal_manager_groups_are_independent() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = WalManager::new(temp_dir.path()).unwrap();
+
+        let mut accounts = manager.open("accounts").unwrap();
+        let mut transfers = manager.open("transfers").unwrap();
+        accounts.append(create_test_entry(1, 1, b"create alice")).unwrap();
+        accounts.append(create_test_entry(2, 1, b"create bob")).unwrap();
+        transfers.append(create_test_entry(1, 3, b"alice -> bob")).unwrap();
+
+        assert_eq!(accounts.last_index(), 2);
+        assert_eq!(transfers.last_index(), 1);
+        drop((accounts, transfers));
+
+        let accounts = manager.open("accounts").unwrap().replay().unwrap();
+        let transfers = manager.open("transfers").unwrap().replay().unwrap();
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[1].command.as_ref(), b"create bob");
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].term, 3);
+    }
+
+    #[test]
+    fn test_wal_manager_rejects_opening_a_group_twice() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = WalManager::new(temp_dir.path()).unwrap();
+
+        let wal = manager.open("accounts").unwrap();
+        let err = manager.open("accounts").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+
+        // Dropping the WAL frees the group again.
+        drop(wal);
+        assert!(manager.open("accounts").is_ok());
+    }
+
+    #[test]
+    fn test_wal_manager_rejects_invalid_group_ids() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = WalManager::new(temp_dir.path()).unwrap();
+
+        for group in ["", "../accounts", "a.b", "a/b", "ünïcode"] {
+            let err = manager.open(group).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput, "{:?}", group);
+        }
+    }
+
+    #[test]
+    fn test_wal_manager_enumerates_existing_groups() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = WalManager::new(temp_dir.path().join("wal")).unwrap();
+        assert!(manager.groups().unwrap().is_empty());
+
+        for group in ["shard-2", "shard-1"] {
+            manager.open(group).unwrap().append(create_test_entry(1, 1, b"x")).unwrap();
+        }
+        manager.open("shard-1").unwrap().truncate_prefix(1).unwrap();
+        std::fs::write(manager.dir().join("notes.txt"), b"not a wal").unwrap();
+        std::fs::write(manager.dir().join("other.wal"), b"").unwrap();
+
+        let manager = WalManager::new(temp_dir.path().join("wal")).unwrap();
+        assert_eq!(manager.groups().unwrap(), vec!["shard-1", "shard-2"]);
+    }
+}
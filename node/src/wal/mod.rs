@@ -1,2 +1,37 @@
+#[allow(clippy::module_inception)]
 mod wal;
+mod block;
+mod buffer;
+mod cache;
+mod compaction;
+mod cursor;
+mod diff;
+mod dir;
 mod entry;
+mod flusher;
+mod follow;
+mod format;
+mod index;
+mod manager;
+mod read_only;
+mod state_machine;
+mod storage;
+
+pub(crate) use block::crc32;
+pub use block::DEFAULT_READ_BUFFER_BYTES;
+pub use buffer::DEFAULT_WRITE_BUFFER_BYTES;
+pub use compaction::{Compacted, CompactionPoint};
+pub use cursor::WalCursor;
+pub use diff::{first_divergence, Divergence};
+pub use dir::{create_dir_all, DEFAULT_DIR_MODE, DEFAULT_FILE_MODE};
+pub use entry::{ChainHash, EntryTooLarge, LogEntry, GENESIS_HASH};
+pub use flusher::DurableWaiter;
+pub use follow::{LogReset, DEFAULT_FOLLOW_INTERVAL};
+pub use manager::WalManager;
+pub use read_only::ReadOnlyWal;
+pub use state_machine::{ApplyErrorPolicy, ApplyHalted, StateMachine};
+pub use storage::{FileStorage, LogStorage, MemoryStorage};
+pub use wal::{
+    BuiltCompaction, Compaction, IntegrityMode, Quarantined, Wal, WalStats,
+    DEFAULT_CACHE_ENTRIES, DEFAULT_MAX_ENTRY_BYTES,
+};
@@ -0,0 +1,159 @@
+use std::io::Write;
+use std::ops::RangeBounds;
+use tokio_stream::Stream;
+use crate::wal::compaction::CompactionPoint;
+use crate::wal::cursor::WalCursor;
+use crate::wal::entry::LogEntry;
+use crate::wal::state_machine::{ApplyErrorPolicy, StateMachine};
+use crate::wal::wal::{Wal, WalStats};
+
+/// A log opened with `Wal::open_read_only`. It only has the methods of `Wal` that read,
+/// so a tool holding one cannot append to, truncate or compact the log by mistake:
+///
+/// ```compile_fail
+/// let mut wal = node::wal::Wal::open_read_only("bank.wal").unwrap();
+/// let entry = wal.read_at(1).unwrap().unwrap();
+/// wal.append(entry).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct ReadOnlyWal {
+    wal: Wal,
+}
+
+impl ReadOnlyWal {
+    pub(crate) fn new(wal: Wal) -> Self {
+        Self { wal }
+    }
+
+    pub fn first_index(&self) -> u64 {
+        self.wal.first_index()
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.wal.last_index()
+    }
+
+    pub fn compaction_point(&self) -> CompactionPoint {
+        self.wal.compaction_point()
+    }
+
+    /// See `Wal::term_boundaries`.
+    pub fn term_boundaries(&self) -> &[CompactionPoint] {
+        self.wal.term_boundaries()
+    }
+
+    /// See `Wal::term_at`.
+    pub fn term_at(&self, index: u64) -> std::io::Result<Option<u64>> {
+        self.wal.term_at(index)
+    }
+
+    pub fn stats(&self) -> std::io::Result<WalStats> {
+        self.wal.stats()
+    }
+
+    pub fn replay(&self) -> std::io::Result<Vec<LogEntry>> {
+        self.wal.replay()
+    }
+
+    /// See `Wal::replay_with`.
+    pub fn replay_with(&self, f: impl FnMut(&LogEntry, u64, u64)) -> std::io::Result<()> {
+        self.wal.replay_with(f)
+    }
+
+    /// See `Wal::replay_range`.
+    pub fn replay_range(&self, range: impl RangeBounds<u64>) -> std::io::Result<Vec<LogEntry>> {
+        self.wal.replay_range(range)
+    }
+
+    /// See `Wal::read_at`.
+    pub fn read_at(&self, index: u64) -> std::io::Result<Option<LogEntry>> {
+        self.wal.read_at(index)
+    }
+
+    /// See `Wal::apply_to`.
+    pub fn apply_to(&self, sm: &mut impl StateMachine) -> std::io::Result<u64> {
+        self.wal.apply_to(sm)
+    }
+
+    /// See `Wal::apply_to_with_policy`.
+    pub fn apply_to_with_policy(
+        &self,
+        sm: &mut impl StateMachine,
+        policy: ApplyErrorPolicy,
+    ) -> std::io::Result<u64> {
+        self.wal.apply_to_with_policy(sm, policy)
+    }
+
+    /// See `Wal::export_jsonl`.
+    pub fn export_jsonl(&self, w: &mut impl Write) -> std::io::Result<u64> {
+        self.wal.export_jsonl(w)
+    }
+
+    /// See `Wal::follow`.
+    pub fn follow(
+        &self,
+        from_index: u64,
+    ) -> impl Stream<Item = std::io::Result<LogEntry>> + use<> {
+        self.wal.follow(from_index)
+    }
+
+    /// See `Wal::cursor`.
+    pub fn cursor(&self, from_index: u64) -> std::io::Result<WalCursor> {
+        self.wal.cursor(from_index)
+    }
+
+    /// See `Wal::verify_chain`.
+    pub fn verify_chain(&self) -> std::io::Result<Option<u64>> {
+        self.wal.verify_chain()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use crate::wal::entry::tests::create_test_entry;
+
+    #[test]
+    fn test_read_only_wal_replays_a_log_open_for_writing() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("bank.wal");
+        let path = path.to_str().unwrap();
+
+        let mut wal = Wal::new(path).unwrap();
+        for index in 1..=4 {
+            wal.append(create_test_entry(index, 1, b"command")).unwrap();
+        }
+        wal.truncate_prefix(1).unwrap();
+        let size = std::fs::metadata(path).unwrap().len();
+
+        let read_only = Wal::open_read_only(path).unwrap();
+        let indexes: Vec<u64> = read_only.replay().unwrap().iter().map(|e| e.index).collect();
+        assert_eq!(indexes, vec![2, 3, 4]);
+        assert_eq!(read_only.read_at(3).unwrap().unwrap().command.as_ref(), b"command");
+        assert_eq!(read_only.verify_chain().unwrap(), None);
+        let stats = read_only.stats().unwrap();
+        assert_eq!((stats.first_index, stats.last_index, stats.entries), (2, 4, 3));
+        assert_eq!(stats.bytes, size);
+
+        // The writer carries on; opening again sees its new entries.
+        wal.append(create_test_entry(5, 1, b"command")).unwrap();
+        assert_eq!(Wal::open_read_only(path).unwrap().last_index(), 5);
+    }
+
+    #[test]
+    fn test_read_only_wal_never_creates_or_writes() {
+        let temp_dir = TempDir::new().unwrap();
+        let missing = temp_dir.path().join("missing.wal");
+        let err = Wal::open_read_only(missing.to_str().unwrap()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert!(!missing.exists());
+
+        // An empty file is not given a header, as `Wal::new` would.
+        let empty = temp_dir.path().join("empty.wal");
+        std::fs::write(&empty, b"").unwrap();
+        let read_only = Wal::open_read_only(empty.to_str().unwrap()).unwrap();
+        assert!(read_only.replay().unwrap().is_empty());
+        assert_eq!(std::fs::metadata(&empty).unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,119 @@
+use std::fmt;
+use crate::wal::LogEntry;
+
+/// Something a WAL can be replayed into, entry by entry, with `Wal::apply_to`.
+pub trait StateMachine {
+    fn apply(&mut self, entry: &LogEntry) -> std::io::Result<()>;
+
+    /// Called instead of `apply` for an entry `ApplyErrorPolicy::SkipAndLog` skipped after
+    /// `apply` failed on it, e.g. to count it as applied all the same.
+    fn skip(&mut self, _entry: &LogEntry) {}
+}
+
+/// What replaying a WAL does when applying an entry fails, which only a bug, or an entry
+/// written by a newer version, should ever cause.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ApplyErrorPolicy {
+    /// Stops before the entry, failing with `ApplyHalted`, so nothing past it is applied
+    /// to a state that may already be wrong.
+    #[default]
+    Halt,
+    /// Logs the error, leaves the entry unapplied, and carries on with the next one.
+    SkipAndLog,
+}
+
+/// Carried inside an `io::Error` when replay halted on an entry that failed to apply.
+/// Everything before `index` was applied.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ApplyHalted {
+    pub index: u64,
+    /// Why applying the entry failed.
+    pub error: String,
+}
+
+impl ApplyHalted {
+    /// Returns the `ApplyHalted` details if `e` reports replay halted on a failed entry.
+    pub fn from_io(e: &std::io::Error) -> Option<&Self> {
+        e.get_ref()?.downcast_ref()
+    }
+
+    pub(crate) fn into_io(self, kind: std::io::ErrorKind) -> std::io::Error {
+        std::io::Error::new(kind, self)
+    }
+}
+
+impl fmt::Display for ApplyHalted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "applying entry {} failed, so applying halted: {}", self.index, self.error)
+    }
+}
+
+impl std::error::Error for ApplyHalted {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use crate::wal::entry::tests::create_test_entry;
+    use crate::wal::Wal;
+
+    /// Records the entries it applied and skipped, failing on `fail_at`.
+    #[derive(Debug, Default)]
+    struct FailingStateMachine {
+        fail_at: u64,
+        applied: Vec<u64>,
+        skipped: Vec<u64>,
+    }
+
+    impl StateMachine for FailingStateMachine {
+        fn apply(&mut self, entry: &LogEntry) -> std::io::Result<()> {
+            if entry.index == self.fail_at {
+                return Err(std::io::Error::other("cannot apply this one"));
+            }
+            self.applied.push(entry.index);
+            Ok(())
+        }
+
+        fn skip(&mut self, entry: &LogEntry) {
+            self.skipped.push(entry.index);
+        }
+    }
+
+    fn wal_of(temp_dir: &TempDir, entries: u64) -> Wal {
+        let path = temp_dir.path().join("bank.wal");
+        let mut wal = Wal::new(path.to_str().unwrap()).unwrap();
+        for index in 1..=entries {
+            wal.append(create_test_entry(index, 1, b"command")).unwrap();
+        }
+        wal
+    }
+
+    #[test]
+    fn test_halt_policy_stops_before_the_entry_that_fails() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal = wal_of(&temp_dir, 5);
+        let mut sm = FailingStateMachine { fail_at: 3, ..Default::default() };
+
+        let err = wal.apply_to_with_policy(&mut sm, ApplyErrorPolicy::Halt).unwrap_err();
+        let halted = ApplyHalted::from_io(&err).unwrap();
+        assert_eq!(halted.index, 3);
+        assert!(halted.error.contains("cannot apply this one"));
+        assert_eq!(err.kind(), std::io::ErrorKind::Other);
+        assert_eq!((sm.applied, sm.skipped), (vec![1, 2], vec![]));
+
+        // Halting is what `apply_to` does.
+        let mut sm = FailingStateMachine { fail_at: 3, ..Default::default() };
+        assert_eq!(ApplyHalted::from_io(&wal.apply_to(&mut sm).unwrap_err()).unwrap().index, 3);
+    }
+
+    #[test]
+    fn test_skip_policy_carries_on_past_the_entry_that_fails() {
+        let temp_dir = TempDir::new().unwrap();
+        let wal = wal_of(&temp_dir, 5);
+        let mut sm = FailingStateMachine { fail_at: 3, ..Default::default() };
+
+        let last = wal.apply_to_with_policy(&mut sm, ApplyErrorPolicy::SkipAndLog).unwrap();
+        assert_eq!(last, 5);
+        assert_eq!((sm.applied, sm.skipped), (vec![1, 2, 4, 5], vec![3]));
+    }
+}
@@ -1,78 +1,1371 @@
-use std::io::{Seek, Write};
-use crate::wal::entry::LogEntry;
+use std::io::{BufRead, Read, Write};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use base64::Engine;
+use bytes::Bytes;
+use tokio_stream::Stream;
+use tracing::{error, instrument, warn};
+use crate::bank::BankCommand;
+use crate::metrics::WalMetrics;
+use crate::wal::block::{encode_block, EntryReader, DEFAULT_READ_BUFFER_BYTES};
+use crate::wal::buffer::DEFAULT_WRITE_BUFFER_BYTES;
+use crate::wal::cache::EntryCache;
+use crate::wal::compaction::{Compacted, CompactionPoint};
+use crate::wal::cursor::WalCursor;
+use crate::wal::dir::{sync_parent_dir, DEFAULT_FILE_MODE};
+use crate::wal::entry::{ChainHash, EntryTooLarge, LogEntry};
+use crate::wal::flusher::{DurableWaiter, Flusher};
+use crate::wal::follow::{self, DEFAULT_FOLLOW_INTERVAL};
+use crate::wal::format::{
+    FileHeader, FORMAT_VERSION, HEADERLESS_VERSION, HEADER_LEN, TIMESTAMP_VERSION,
+};
+use crate::wal::index::{SeekIndex, TailIndex, INDEX_EVERY_ENTRIES};
+use crate::wal::manager::GroupLease;
+use crate::wal::read_only::ReadOnlyWal;
+use crate::wal::state_machine::{ApplyErrorPolicy, ApplyHalted, StateMachine};
+use crate::wal::storage::{FileStorage, LogStorage, StorageReader};
+
+/// How many of the most recent entries a WAL keeps in memory by default.
+pub const DEFAULT_CACHE_ENTRIES: usize = 1024;
+/// Largest command, in bytes, a WAL accepts in one entry by default.
+pub const DEFAULT_MAX_ENTRY_BYTES: u64 = 1024 * 1024;
+/// Most entries per block when compaction or an import writes many entries at once.
+const BULK_BLOCK_ENTRIES: usize = 256;
+
+/// The extent and size of a log, as `Wal::stats` reports it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WalStats {
+    pub first_index: u64,
+    pub last_index: u64,
+    /// Entries stored, which compaction leaves out.
+    pub entries: u64,
+    /// Size of the file, header included.
+    pub bytes: u64,
+}
+
+/// What opening a log does with the bytes after the last block it can read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TailRepair {
+    /// Leaves them, as opening a log read-only does.
+    Leave,
+    /// Moves aside what a torn write left, and fails on a whole block that is corrupt.
+    Torn,
+    /// Moves aside everything from the first block it cannot read on, corrupt or torn.
+    Quarantine,
+}
 
+/// The bytes of a log that opening it moved aside, from the first block it could not
+/// read on. Entries past `last_index` that were in them are missing from the log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Quarantined {
+    /// The file the bytes were moved to.
+    pub path: PathBuf,
+    /// Offset in the log they were cut from.
+    pub offset: u64,
+    pub bytes: u64,
+    /// The last entry left in the log.
+    pub last_index: u64,
+}
+
+/// How far `Wal::scan_tail` got through a log.
+struct ScannedTail {
+    last_index: u64,
+    last_hash: ChainHash,
+    last_timestamp: u64,
+    /// Offset the last whole, readable block ends at.
+    end: u64,
+    seek: SeekIndex,
+    /// Why the block at `end` could not be read, if it is there whole but corrupt.
+    corrupt: Option<std::io::Error>,
+}
+
+/// How much of the log opening it checks against the CRC of each block. Reads check
+/// every block they load whatever the mode; this only spares the scan on open, which
+/// otherwise reads the whole log. Short of `Full`, opening also picks the scan up from
+/// the tail index the log last saved, if the log still matches it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IntegrityMode {
+    /// Checks every block.
+    #[default]
+    Full,
+    /// Checks only the last block, the one a crash may have left torn or half-synced,
+    /// and trusts the ones before it.
+    TailOnly,
+    /// Checks nothing.
+    None,
+}
+
+/// A log of entries kept in `S`, by default a file on the local filesystem.
 #[derive(Debug)]
-pub struct Wal {
-    file: std::fs::File,
+pub struct Wal<S: LogStorage = FileStorage> {
+    /// Names the log, and the files kept next to it, whatever the storage.
+    path: PathBuf,
+    storage: S,
+    /// Offset of the first entry: just past the header, or 0 in a log written before
+    /// files had one.
+    data_start: u64,
+    /// Format version of the entries in the file.
+    version: u8,
+    /// Where the log starts; everything up to it was removed by `truncate_prefix`.
+    compacted: CompactionPoint,
+    /// The last entry of each term compaction removed, oldest first, if it keeps them.
+    term_boundaries: Vec<CompactionPoint>,
+    /// Whether `truncate_prefix` adds to `term_boundaries`.
+    preserve_terms: bool,
     last_index: u64,
+    /// Offset just past the last block appended, buffered or not, where the next one goes.
+    end: u64,
+    /// Where some of the blocks are, for reads to start close to the entries they want.
+    seek: SeekIndex,
+    /// Chain hash of the last entry, which the next append extends.
+    last_hash: ChainHash,
+    /// Latest timestamp in the log, which the next stamped entry does not go below.
+    last_timestamp: u64,
+    metrics: WalMetrics,
+    /// How long the last fsync of appended entries took, when there is no flusher.
+    last_fsync: Option<Duration>,
+    /// The most recent entries, so reading them back does not go to disk.
+    cache: EntryCache,
+    /// Fsyncs in the background when set; otherwise every append fsyncs itself.
+    flusher: Option<Flusher<S>>,
+    /// Bytes of appends the flusher stages before writing them out.
+    write_buffer_bytes: usize,
+    /// Bytes read from the log at a time when it is scanned or replayed.
+    read_buffer_bytes: usize,
+    /// Largest command an appended entry may carry.
+    max_entry_bytes: u64,
+    /// Whether `append_command` coalesces a command with an identical one just before it.
+    dedup_consecutive: bool,
+    /// Whether compaction fsyncs the directory after renaming files into it.
+    sync_dir: bool,
+    /// Entries appended since the tail index was last saved.
+    unindexed: u64,
+    /// Held while a `WalManager` group has this log open.
+    lease: Option<GroupLease>,
+    /// What opening the log moved aside, if anything.
+    quarantined: Option<Quarantined>,
 }
 
 impl Wal {
     pub fn new(path: &str) -> std::io::Result<Self> {
-        let file = std::fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .read(true)
-            .open(path)?;
+        Self::new_with_integrity(path, IntegrityMode::Full)
+    }
 
-        let last_index = Self::scan_last_index(&file)?;
+    /// Like `new`, checking only as much of the log as `integrity` says while opening it.
+    /// A log created here is synced into its directory before this returns.
+    pub fn new_with_integrity(path: &str, integrity: IntegrityMode) -> std::io::Result<Self> {
+        Self::new_with_file_mode(path, integrity, DEFAULT_FILE_MODE)
+    }
 
-        Ok(Self { file, last_index })
+    /// Like `new_with_integrity`, creating the log, and the file compaction rewrites it
+    /// into, with permission bits `mode` instead of `DEFAULT_FILE_MODE`. A log that
+    /// exists keeps the bits it has.
+    pub fn new_with_file_mode(
+        path: &str,
+        integrity: IntegrityMode,
+        mode: u32,
+    ) -> std::io::Result<Self> {
+        Self::new_with_read_buffer(path, integrity, mode, DEFAULT_READ_BUFFER_BYTES)
     }
 
-    fn scan_last_index(file: &std::fs::File) -> std::io::Result<u64> {
-        let mut file = file.try_clone()?;
-        file.seek(std::io::SeekFrom::Start(0))?;
+    /// Like `new_with_file_mode`, reading the log `read_buffer_bytes` at a time, instead of
+    /// `DEFAULT_READ_BUFFER_BYTES`, whenever it is scanned or replayed, opening included.
+    /// A larger buffer takes fewer reads to get through a long log.
+    pub fn new_with_read_buffer(
+        path: &str,
+        integrity: IntegrityMode,
+        mode: u32,
+        read_buffer_bytes: usize,
+    ) -> std::io::Result<Self> {
+        Self::open_repairing(path, integrity, mode, read_buffer_bytes, TailRepair::Torn)
+    }
 
-        let mut reader = std::io::BufReader::new(file);
-        let mut last_index = 0;
+    /// Like `new_with_read_buffer`, recovering from a block it cannot read rather than
+    /// failing on it: everything from that block on is moved to a file named after the
+    /// log with `.corrupt` added, for an operator to look into, and the log carries on
+    /// from the last entry before it. `quarantined` says what was moved, and so which
+    /// entries are missing.
+    pub fn new_with_quarantine(
+        path: &str,
+        integrity: IntegrityMode,
+        mode: u32,
+        read_buffer_bytes: usize,
+    ) -> std::io::Result<Self> {
+        Self::open_repairing(path, integrity, mode, read_buffer_bytes, TailRepair::Quarantine)
+    }
+
+    fn open_repairing(
+        path: &str,
+        integrity: IntegrityMode,
+        mode: u32,
+        read_buffer_bytes: usize,
+        repair: TailRepair,
+    ) -> std::io::Result<Self> {
+        let path = PathBuf::from(path);
+        let created = !path.exists();
+        let storage = FileStorage::open_with_mode(&path, mode)?;
+        let (data_start, version) = Self::init_header(&storage)?;
+        if created {
+            sync_parent_dir(&path)?;
+        }
+        let buffer = read_buffer_bytes;
+        Self::from_storage(path, storage, data_start, version, integrity, buffer, repair)
+    }
+
+    /// Opens the log at `path` for reading only, e.g. from a tool inspecting the log of a
+    /// running node. Unlike `new`, it fails if there is no log at `path`, and it never
+    /// writes to the file, not even a header.
+    pub fn open_read_only(path: &str) -> std::io::Result<ReadOnlyWal> {
+        let path = PathBuf::from(path);
+        let storage = FileStorage::open_read_only(&path)?;
+        let (data_start, version) = Self::read_header(&storage)?;
+        let integrity = IntegrityMode::Full;
+        let wal = Self::from_storage(
+            path,
+            storage,
+            data_start,
+            version,
+            integrity,
+            DEFAULT_READ_BUFFER_BYTES,
+            TailRepair::Leave,
+        )?;
+        Ok(ReadOnlyWal::new(wal))
+    }
+
+    /// Streams the entries of the log from `from_index` on as they are written, by this
+    /// process or another, like `tail -f`. Once it has yielded every entry in the log, it
+    /// checks for more every `DEFAULT_FOLLOW_INTERVAL`. Should the log be truncated under
+    /// it, it yields a `LogReset` error naming the index it resumes at; any other error
+    /// ends the stream. Entries are seen once written out, which a write buffer delays.
+    pub fn follow(
+        &self,
+        from_index: u64,
+    ) -> impl Stream<Item = std::io::Result<LogEntry>> + use<> {
+        follow::follow(self.path.clone(), from_index, DEFAULT_FOLLOW_INTERVAL)
+    }
+
+    /// Returns a cursor over the entries from `from_index` up to the last one appended so
+    /// far; see `WalCursor`. Entries still in the write buffer are written out first.
+    pub fn cursor(&self, from_index: u64) -> std::io::Result<WalCursor> {
+        self.write_out()?;
+        Ok(WalCursor::new(self.path.clone(), from_index, self.last_index))
+    }
+
+    /// Writes the entries read from `r`, in the format `export_jsonl` produces, to a new
+    /// log at `path_out` and returns it. Only the index, term, timestamp and base64
+    /// command of each line are read; chain hashes and block CRCs are computed afresh.
+    /// Fails if `path_out` already exists, and on the first malformed line or one whose
+    /// index does not follow the line before, counting from 1; a failed import removes
+    /// what it wrote, so no partial log is left at `path_out`.
+    pub fn import_jsonl(path_out: &str, r: impl Read) -> std::io::Result<Self> {
+        if Path::new(path_out).exists() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("cannot import into {}, which already exists", path_out),
+            ));
+        }
 
+        let mut wal = Self::new(path_out)?;
+        if let Err(e) = wal.import_entries(r) {
+            drop(wal);
+            // The import's error is the one worth reporting, even if cleaning up fails.
+            let _ = std::fs::remove_file(path_out);
+            let _ = TailIndex::remove(&TailIndex::path_for(Path::new(path_out)));
+            return Err(e);
+        }
+        Ok(wal)
+    }
+
+    /// Appends the entries read from `r` to this new, empty log, for `import_jsonl`.
+    fn import_entries(&mut self, r: impl Read) -> std::io::Result<()> {
+        let mut batch = Vec::with_capacity(BULK_BLOCK_ENTRIES);
+        for (number, line) in std::io::BufReader::new(r).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let invalid = |message: String| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("line {}: {}", number + 1, message),
+                )
+            };
+            let entry = import_line(&line).map_err(invalid)?;
+            let expected = self.last_index + batch.len() as u64 + 1;
+            if entry.index != expected {
+                return Err(invalid(format!(
+                    "entry has index {} where {} was expected",
+                    entry.index, expected
+                )));
+            }
+
+            batch.push(entry);
+            if batch.len() == BULK_BLOCK_ENTRIES {
+                self.append_batch(std::mem::take(&mut batch))?;
+            }
+        }
+        self.append_batch(batch)
+    }
+}
+
+impl<S: LogStorage> Wal<S> {
+    /// Opens the log kept in `storage`, giving it a header if it is empty. The compaction
+    /// point and tail index are still kept in files next to `path`.
+    pub fn with_storage(
+        path: &str,
+        storage: S,
+        integrity: IntegrityMode,
+    ) -> std::io::Result<Self> {
+        let (data_start, version) = Self::init_header(&storage)?;
+        let path = PathBuf::from(path);
+        let buffer = DEFAULT_READ_BUFFER_BYTES;
+        Self::from_storage(path, storage, data_start, version, integrity, buffer, TailRepair::Torn)
+    }
+
+    /// Opens the log in `storage`, moving the bytes after the last block it can read
+    /// aside as `repair` says. Appends would otherwise land after them, where no scan
+    /// gets to them.
+    fn from_storage(
+        path: PathBuf,
+        storage: S,
+        data_start: u64,
+        version: u8,
+        integrity: IntegrityMode,
+        read_buffer_bytes: usize,
+        repair: TailRepair,
+    ) -> std::io::Result<Self> {
+        let compacted = CompactionPoint::load(&CompactionPoint::path_for(&path))?;
+        let term_boundaries = CompactionPoint::load_all(&CompactionPoint::terms_path_for(&path))?;
+        let index = match integrity {
+            IntegrityMode::Full => None,
+            _ => TailIndex::load(&TailIndex::path_for(&path))?,
+        };
+        let index = match index {
+            Some(index) if index.matches(&storage, data_start, &compacted)? => Some(index),
+            _ => None,
+        };
+
+        let scanned = Self::scan_tail(
+            &storage,
+            data_start,
+            version,
+            &compacted,
+            index,
+            integrity,
+            read_buffer_bytes,
+        )?;
+        let ScannedTail { last_index, last_hash, last_timestamp, end, seek, corrupt } = scanned;
+        if let Some(e) = corrupt
+            && repair != TailRepair::Quarantine
+        {
+            return Err(e);
+        }
+        let len = storage.len()?;
+        let mut quarantined = None;
+        if repair != TailRepair::Leave && end < len {
+            let moved = Self::move_aside(&path, &storage, end, len)?;
+            warn!(
+                path = %path.display(),
+                offset = end,
+                bytes = len - end,
+                last_index,
+                moved_to = %moved.display(),
+                "moved the unreadable tail of the WAL aside; entries after last_index are gone"
+            );
+            storage.set_len(end)?;
+            storage.sync()?;
+            let bytes = len - end;
+            quarantined = Some(Quarantined { path: moved, offset: end, bytes, last_index });
+        }
+        let end = storage.len()?;
+
+        Ok(Self {
+            path,
+            storage,
+            data_start,
+            version,
+            compacted,
+            term_boundaries,
+            preserve_terms: false,
+            last_index,
+            end,
+            seek,
+            last_hash,
+            last_timestamp,
+            metrics: WalMetrics::default(),
+            cache: EntryCache::new(DEFAULT_CACHE_ENTRIES),
+            last_fsync: None,
+            flusher: None,
+            write_buffer_bytes: DEFAULT_WRITE_BUFFER_BYTES,
+            read_buffer_bytes,
+            max_entry_bytes: DEFAULT_MAX_ENTRY_BYTES,
+            dedup_consecutive: false,
+            sync_dir: true,
+            unindexed: 0,
+            lease: None,
+            quarantined,
+        })
+    }
+
+    /// Copies the bytes of the log in `storage` from `start` to `end` into a file named
+    /// after it with `.corrupt` added, or a number after that if one is there already,
+    /// and syncs it. Returns its path.
+    fn move_aside(path: &Path, storage: &S, start: u64, end: u64) -> std::io::Result<PathBuf> {
+        let mut bytes = Vec::new();
+        StorageReader::new(storage, start).take(end - start).read_to_end(&mut bytes)?;
+        for attempt in 1.. {
+            let mut moved = path.as_os_str().to_owned();
+            moved.push(".corrupt");
+            if attempt > 1 {
+                moved.push(format!(".{}", attempt));
+            }
+            let moved = PathBuf::from(moved);
+            let mut file = match std::fs::File::create_new(&moved) {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            };
+            file.write_all(&bytes)?;
+            file.sync_data()?;
+            sync_parent_dir(&moved)?;
+            return Ok(moved);
+        }
+        unreachable!("some attempt finds a free name")
+    }
+
+    /// Gives an empty log a header, and otherwise checks the one it has. Returns the
+    /// offset of the first entry and the format version of the entries.
+    fn init_header(storage: &S) -> std::io::Result<(u64, u8)> {
+        if storage.is_empty()? {
+            storage.append_bytes(&FileHeader::current().encode())?;
+            storage.sync()?;
+            return Ok((HEADER_LEN, FORMAT_VERSION));
+        }
+        Self::read_header(storage)
+    }
+
+    /// Returns the offset of the first entry and the format version of the entries.
+    fn read_header(storage: &S) -> std::io::Result<(u64, u8)> {
+        Ok(match FileHeader::read(storage)? {
+            Some(header) => (HEADER_LEN, header.version),
+            None => (0, HEADERLESS_VERSION),
+        })
+    }
+
+    /// Records appends, bytes written and fsync latency into `metrics`.
+    pub fn with_metrics(mut self, metrics: WalMetrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Keeps the last `entries` appended entries in memory instead of the default
+    /// `DEFAULT_CACHE_ENTRIES`; 0 turns the cache off.
+    pub fn with_cache_capacity(mut self, entries: usize) -> Self {
+        self.cache = EntryCache::new(entries);
+        self
+    }
+
+    /// Makes `append` return before its entry is durable, fsyncing every `interval` on a
+    /// background thread instead. Use `append_and_wait_durable` where durability matters.
+    /// Call it after `with_metrics`, so the flush thread records into the same metrics.
+    pub fn with_background_flush(mut self, interval: Duration) -> std::io::Result<Self> {
+        let flusher = Flusher::spawn(
+            &self.storage,
+            self.last_index,
+            interval,
+            self.write_buffer_bytes,
+            self.metrics.clone(),
+        )?;
+        self.flusher = Some(flusher);
+        Ok(self)
+    }
+
+    /// With a background flush, stages up to `bytes` of appends in memory and writes them
+    /// out together, before the fsync at the latest, instead of the default
+    /// `DEFAULT_WRITE_BUFFER_BYTES`; 0 writes every append at once. An entry is durable
+    /// at the same point either way. Without a background flush every append is written
+    /// and fsynced at once, and this changes nothing.
+    pub fn with_write_buffer(mut self, bytes: usize) -> std::io::Result<Self> {
+        self.write_buffer_bytes = bytes;
+        if let Some(flusher) = &self.flusher {
+            flusher.set_buffer_capacity(bytes)?;
+        }
+        Ok(self)
+    }
+
+    /// Refuses to append entries whose command is over `bytes`, instead of the default
+    /// `DEFAULT_MAX_ENTRY_BYTES`. Entries already in the log are read back whatever
+    /// their size.
+    pub fn with_max_entry_bytes(mut self, bytes: u64) -> Self {
+        self.set_max_entry_bytes(bytes);
+        self
+    }
+
+    pub(crate) fn set_max_entry_bytes(&mut self, bytes: u64) {
+        self.max_entry_bytes = bytes;
+    }
+
+    /// Whether `truncate_prefix` fsyncs the log's directory after replacing files in it,
+    /// which it does by default. Without it, a crash soon after compaction can keep the
+    /// rewritten log but lose the compaction point saved before it, and opening then
+    /// refuses the log as it no longer starts at 1.
+    pub fn with_dir_sync(mut self, sync: bool) -> Self {
+        self.sync_dir = sync;
+        self
+    }
+
+    /// With `preserve` set, `truncate_prefix` keeps the index, term and chain hash of the
+    /// last entry of each term it removes, so `term_at` can still tell the term at every
+    /// leader change. Off by default. Boundaries already kept are kept either way.
+    pub fn with_term_preservation(mut self, preserve: bool) -> Self {
+        self.preserve_terms = preserve;
+        self
+    }
+
+    /// With `dedup` set, `append_command` does not store a command byte-identical to the
+    /// one in the last entry, of the same term, and returns that entry's index instead.
+    /// Off by default, as a Raft log must hold every proposal, repeats included; only turn
+    /// it on for a log of commands that are safe to apply once however often they are sent.
+    pub fn with_consecutive_dedup(mut self, dedup: bool) -> Self {
+        self.dedup_consecutive = dedup;
+        self
+    }
+
+    pub fn max_entry_bytes(&self) -> u64 {
+        self.max_entry_bytes
+    }
+
+    pub(crate) fn with_lease(mut self, lease: GroupLease) -> Self {
+        self.lease = Some(lease);
+        self
+    }
+
+    /// Finds the index and chain hash of the last entry, the latest timestamp, the offset
+    /// the last whole block ends at and where the blocks are, checking indexes are
+    /// sequential from just after `compacted`, and block CRCs as `integrity` says. Past
+    /// that offset there is only what a torn write left, if anything, unless the block
+    /// there is corrupt, which is reported rather than failed on. Entries at or before
+    /// `compacted` are leftovers of a compaction that did not get to rewrite the log, and
+    /// are skipped. Given a tail `index` that matches the log, only the entries after it
+    /// are scanned, and the blocks before it are taken from it.
+    fn scan_tail(
+        storage: &S,
+        data_start: u64,
+        version: u8,
+        compacted: &CompactionPoint,
+        index: Option<TailIndex>,
+        integrity: IntegrityMode,
+        read_buffer_bytes: usize,
+    ) -> std::io::Result<ScannedTail> {
+        let start = index.as_ref().map_or(data_start, |index| index.offset);
+        let mut reader = EntryReader::with_buffer(storage, start, version, read_buffer_bytes)?;
+        if integrity != IntegrityMode::Full {
+            reader = reader.without_crc_check();
+        }
+        let (mut last_index, mut last_hash, mut last_timestamp, mut seek) = match index {
+            Some(index) => (index.last_index, index.last_hash, index.last_timestamp, index.seek),
+            None => (compacted.index, compacted.chain_hash, 0, SeekIndex::default()),
+        };
+
+        let mut current_block = None;
+        // Where things stood before the block being read, in case it turns out corrupt.
+        let mut before_block = (last_index, last_hash, last_timestamp);
+        let mut corrupt = None;
+        let mut end;
         loop {
-            match LogEntry::decode(&mut reader) {
-                Ok(entry) => {
-                    if entry.index != last_index + 1 {
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Log entries are not sequential",
-                        ));
+            let entry = match reader.next_entry() {
+                Ok(Some(entry)) => entry,
+                Ok(None) => {
+                    end = reader.offset();
+                    break;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                    // An entry that does not decode spoils the rest of its block, which
+                    // goes along with it, along with the entries already read from it.
+                    end = reader.offset();
+                    if reader.mid_block() {
+                        end = reader.block_start();
+                        if current_block == Some(end) {
+                            (last_index, last_hash, last_timestamp) = before_block;
+                        }
                     }
-                    last_index = entry.index;
+                    corrupt = Some(e);
+                    break;
                 }
-                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
                 Err(e) => return Err(e),
+            };
+            if current_block != Some(reader.block_start()) {
+                current_block = Some(reader.block_start());
+                before_block = (last_index, last_hash, last_timestamp);
+                if entry.index > compacted.index {
+                    seek.record(entry.index, reader.block_start());
+                }
+            }
+            if entry.index <= compacted.index {
+                continue;
             }
+            if entry.index != last_index + 1 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Log entries are not sequential",
+                ));
+            }
+            last_index = entry.index;
+            last_hash = entry.chain_hash;
+            last_timestamp = last_timestamp.max(entry.timestamp.unwrap_or_default());
         }
 
-        Ok(last_index)
+        if corrupt.is_none() && integrity == IntegrityMode::TailOnly && reader.offset() > start {
+            // Loading the last block again, checked this time, is all it takes.
+            match EntryReader::new(storage, reader.block_start(), version)?.next_entry() {
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                    end = reader.block_start();
+                    (last_index, last_hash, last_timestamp) = before_block;
+                    corrupt = Some(e);
+                }
+                checked => {
+                    checked?;
+                }
+            }
+        }
+
+        if corrupt.is_some() {
+            seek.truncate(last_index + 1);
+        }
+        Ok(ScannedTail { last_index, last_hash, last_timestamp, end, seek, corrupt })
+    }
+
+    /// The lowest index still stored: 1 for a log that was never compacted, or one
+    /// past the compaction point.
+    pub fn first_index(&self) -> u64 {
+        self.compacted.index + 1
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.last_index
     }
 
+    /// What opening the log moved aside, if it did; see `new_with_quarantine`.
+    pub fn quarantined(&self) -> Option<&Quarantined> {
+        self.quarantined.as_ref()
+    }
+
+    pub fn compaction_point(&self) -> CompactionPoint {
+        self.compacted
+    }
+
+    /// The last entry of each term a term-preserving `truncate_prefix` removed, oldest
+    /// first; see `with_term_preservation`.
+    pub fn term_boundaries(&self) -> &[CompactionPoint] {
+        &self.term_boundaries
+    }
+
+    /// The term of the entry at `index`, or `None` past the end of the log. Below
+    /// `first_index`, only the compaction point, index 0 with term 0 until the log is first
+    /// compacted, and the kept term boundaries have one; other indexes fail with `Compacted`.
+    pub fn term_at(&self, index: u64) -> std::io::Result<Option<u64>> {
+        if index == self.compacted.index {
+            return Ok(Some(self.compacted.term));
+        }
+        if index < self.compacted.index
+            && let Ok(at) = self.term_boundaries.binary_search_by_key(&index, |b| b.index)
+        {
+            return Ok(Some(self.term_boundaries[at].term));
+        }
+        Ok(self.read_at(index)?.map(|entry| entry.term))
+    }
+
+    /// Size of the log file in bytes, header included.
+    pub fn size(&self) -> std::io::Result<u64> {
+        self.write_out()?;
+        self.storage.len()
+    }
+
+    /// Writes out appends the flusher still has staged, so reading the file sees them.
+    fn write_out(&self) -> std::io::Result<()> {
+        match &self.flusher {
+            Some(flusher) => flusher.write_out(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn stats(&self) -> std::io::Result<WalStats> {
+        Ok(WalStats {
+            first_index: self.first_index(),
+            last_index: self.last_index,
+            entries: self.last_index - self.compacted.index,
+            bytes: self.size()?,
+        })
+    }
+
+    /// Last index known to be on disk; the same as `last_index` without a background flush.
+    pub fn durable_index(&self) -> u64 {
+        match &self.flusher {
+            Some(flusher) => flusher.waiter().durable_index(),
+            None => self.last_index,
+        }
+    }
+
+    /// How long the most recent fsync of appended entries took, on the background flush
+    /// thread if there is one, or `None` before the first.
+    pub fn last_fsync_duration(&self) -> Option<Duration> {
+        match &self.flusher {
+            Some(flusher) => flusher.last_fsync_duration(),
+            None => self.last_fsync,
+        }
+    }
+
+    /// Returns once every entry appended so far is on disk.
+    pub fn sync(&self) -> std::io::Result<()> {
+        match &self.flusher {
+            Some(flusher) => flusher.waiter().wait_durable(self.last_index),
+            None => self.storage.sync(),
+        }
+    }
+
+    /// A handle for waiting on durability without holding the WAL, or `None` if every
+    /// append is already durable when it returns.
+    pub fn durable_waiter(&self) -> Option<DurableWaiter> {
+        self.flusher.as_ref().map(Flusher::waiter)
+    }
+
+    #[instrument(level = "debug", skip_all, fields(index = entry.index, term = entry.term))]
     pub fn append(&mut self, entry: LogEntry) -> std::io::Result<()> {
-        let encoded = entry.encode()?;
+        self.write_block(vec![entry])
+    }
+
+    /// Appends `command` in `term` at the next index and returns the index. With
+    /// `with_consecutive_dedup` on, a command identical to the last entry's is not
+    /// appended, and the index of that entry is returned.
+    pub fn append_command(&mut self, term: u64, command: Bytes) -> std::io::Result<u64> {
+        if self.dedup_consecutive && self.last_index >= self.first_index() {
+            let last = self.read_at(self.last_index)?;
+            if let Some(last) = last.filter(|last| last.term == term && last.command == command) {
+                return Ok(last.index);
+            }
+        }
+        let index = self.last_index + 1;
+        self.append(LogEntry::new(index, term, command))?;
+        Ok(index)
+    }
+
+    /// Appends `entries` as one block, with a single write and fsync for all of them.
+    #[instrument(level = "debug", skip_all, fields(count = entries.len()))]
+    pub fn append_batch(&mut self, entries: Vec<LogEntry>) -> std::io::Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        self.write_block(entries)
+    }
 
-        self.file.write_all(&encoded)?;
-        self.file.sync_data()?;
+    /// Writes `entries` as one block, or none of them if any carries a command over
+    /// `max_entry_bytes`, failing with `EntryTooLarge`.
+    fn write_block(&mut self, mut entries: Vec<LogEntry>) -> std::io::Result<()> {
+        for entry in &entries {
+            EntryTooLarge::check(entry.command.len() as u64, self.max_entry_bytes)?;
+        }
+        let (mut last_hash, mut last_timestamp) = (self.last_hash, self.last_timestamp);
+        for entry in &mut entries {
+            entry.timestamp = self.stamp(entry.timestamp, last_timestamp);
+            entry.chain_hash = entry.chain_from(&last_hash);
+            last_hash = entry.chain_hash;
+            last_timestamp = last_timestamp.max(entry.timestamp.unwrap_or_default());
+        }
+        let encoded = encode_block(&entries, self.version)?;
+        let last_index = entries.last().expect("a block holds at least one entry").index;
+        let offset = self.end;
+
+        match &self.flusher {
+            Some(flusher) => flusher.write(&encoded, last_index)?,
+            None => {
+                self.storage.append_bytes(&encoded)?;
+                let fsync_started = Instant::now();
+                self.storage.sync()?;
+                let fsync = fsync_started.elapsed();
+                self.metrics.fsync_seconds.observe(fsync.as_secs_f64());
+                self.last_fsync = Some(fsync);
+            }
+        }
+
+        self.last_index = last_index;
+        self.end += encoded.len() as u64;
+        self.seek.record(entries[0].index, offset);
+        self.last_hash = last_hash;
+        self.last_timestamp = last_timestamp;
+        let entries_len = entries.len() as u64;
+        self.metrics.appends.inc_by(entries_len);
+        self.metrics.bytes.inc_by(encoded.len() as u64);
+        for entry in entries {
+            self.cache.push(entry);
+        }
 
-        self.last_index = entry.index;
+        self.unindexed += entries_len;
+        if self.unindexed >= INDEX_EVERY_ENTRIES {
+            // Without the index, opening only takes longer.
+            if let Err(e) = self.save_index() {
+                warn!(path = %self.path.display(), error = %e, "cannot save the WAL tail index");
+            }
+        }
+        Ok(())
+    }
+
+    /// Saves where the log ends now next to it, so opening it with less than
+    /// `IntegrityMode::Full` scans only what is appended after. Appends save it every
+    /// few thousand entries on their own.
+    pub fn save_index(&mut self) -> std::io::Result<()> {
+        self.write_out()?;
+        let index = TailIndex::new(
+            &self.storage,
+            self.data_start,
+            self.storage.len()?,
+            (self.last_index, self.last_hash, self.last_timestamp),
+            &self.compacted,
+            &self.seek,
+        )?;
+        index.save(&TailIndex::path_for(&self.path))?;
+        self.unindexed = 0;
         Ok(())
     }
 
+    /// Drops the tail index once the log was rewritten under it.
+    fn remove_index(&mut self) -> std::io::Result<()> {
+        self.unindexed = 0;
+        TailIndex::remove(&TailIndex::path_for(&self.path))
+    }
+
+    /// The timestamp to store for an entry appended with `timestamp`: the one it came
+    /// with, e.g. from the leader, or else the current time, never going below
+    /// `last_timestamp`. Logs older than timestamps store none.
+    fn stamp(&self, timestamp: Option<u64>, last_timestamp: u64) -> Option<u64> {
+        if self.version < TIMESTAMP_VERSION {
+            return None;
+        }
+        timestamp.or_else(|| {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            Some((now.as_millis() as u64).max(last_timestamp))
+        })
+    }
+
+    /// Appends `entry` and returns only once it is on disk, even with a background flush.
+    pub fn append_and_wait_durable(&mut self, entry: LogEntry) -> std::io::Result<()> {
+        let index = entry.index;
+        self.append(entry)?;
+        match &self.flusher {
+            Some(flusher) => flusher.waiter().wait_durable(index),
+            None => Ok(()),
+        }
+    }
+
     pub fn replay(&self) -> std::io::Result<Vec<LogEntry>> {
-        let mut file = self.file.try_clone()?;
-        file.seek(std::io::SeekFrom::Start(0))?;
+        let mut entries = Vec::new();
+        self.replay_with(|entry, _, _| entries.push(entry.clone()))?;
+        Ok(entries)
+    }
+
+    /// Reads every stored entry in order, calling `f` with each one, the bytes of the log
+    /// read so far and the log's total size, so callers can report replay progress.
+    pub fn replay_with(&self, mut f: impl FnMut(&LogEntry, u64, u64)) -> std::io::Result<()> {
+        self.for_each_entry(|entry, bytes_read, total_bytes| {
+            f(&entry, bytes_read, total_bytes);
+            Ok(())
+        })
+    }
+
+    /// Applies every stored entry to `sm` as it is read, without holding the whole log in
+    /// memory, and returns the index of the last one, or of the compaction point if
+    /// nothing is stored. Halts on the first entry that fails to apply; see
+    /// `apply_to_with_policy`.
+    pub fn apply_to(&self, sm: &mut impl StateMachine) -> std::io::Result<u64> {
+        self.apply_to_with_policy(sm, ApplyErrorPolicy::Halt)
+    }
+
+    /// Like `apply_to`, doing what `policy` says with an entry `sm` fails to apply: halt
+    /// there, failing with `ApplyHalted` in the kind of the error `sm` returned, or log it
+    /// and carry on. Failing to read the log always fails.
+    pub fn apply_to_with_policy(
+        &self,
+        sm: &mut impl StateMachine,
+        policy: ApplyErrorPolicy,
+    ) -> std::io::Result<u64> {
+        let mut last_index = self.compacted.index;
+        self.for_each_entry(|entry, _, _| {
+            let index = entry.index;
+            if let Err(e) = sm.apply(&entry) {
+                match policy {
+                    ApplyErrorPolicy::Halt => {
+                        let halted = ApplyHalted { index, error: e.to_string() };
+                        return Err(halted.into_io(e.kind()));
+                    }
+                    ApplyErrorPolicy::SkipAndLog => {
+                        error!(index, error = %e, "skipping an entry that failed to apply");
+                        sm.skip(&entry);
+                    }
+                }
+            }
+            last_index = entry.index;
+            Ok(())
+        })?;
+        Ok(last_index)
+    }
+
+    /// Writes every stored entry to `w` as a line of JSON with its index, term and
+    /// timestamp, the kind and fields of the bank command it carries, and the command
+    /// itself in base64. Entries are streamed rather than read into memory all at once.
+    /// Returns how many were written.
+    pub fn export_jsonl(&self, w: &mut impl Write) -> std::io::Result<u64> {
+        let mut exported = 0;
+        self.for_each_entry(|entry, _, _| {
+            serde_json::to_writer(&mut *w, &export_line(&entry))?;
+            w.write_all(b"\n")?;
+            exported += 1;
+            Ok(())
+        })?;
+        Ok(exported)
+    }
+
+    fn entry_reader(&self) -> std::io::Result<EntryReader<S>> {
+        self.entry_reader_at(self.data_start)
+    }
+
+    /// A reader of the log from `offset`, which must be where a block starts.
+    fn entry_reader_at(&self, offset: u64) -> std::io::Result<EntryReader<S>> {
+        let (version, bytes) = (self.version, self.read_buffer_bytes);
+        EntryReader::with_buffer(&self.storage, offset, version, bytes)
+    }
+
+    /// Decodes every stored entry in order and hands it to `f` along with the bytes read
+    /// so far and the log's total size, stopping at the first error.
+    fn for_each_entry(
+        &self,
+        mut f: impl FnMut(LogEntry, u64, u64) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        self.write_out()?;
+        let total_bytes = self.storage.len()?;
+        let mut reader = self.entry_reader()?;
+        while let Some(entry) = reader.next_entry()? {
+            // Leftovers of an interrupted compaction count towards progress only.
+            if entry.index > self.compacted.index {
+                f(entry, reader.offset(), total_bytes)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the stored entries whose index falls in `range`. Fails with a `Compacted`
+    /// error if the range starts below `first_index`.
+    pub fn replay_range(&self, range: impl RangeBounds<u64>) -> std::io::Result<Vec<LogEntry>> {
+        let start = match range.start_bound() {
+            Bound::Included(&start) => start,
+            Bound::Excluded(&start) => start.saturating_add(1),
+            Bound::Unbounded => self.first_index(),
+        };
+        self.check_not_compacted(start)?;
+        if let Some(entries) = self.cache.range(start, &range) {
+            return Ok(entries);
+        }
 
-        let mut reader = std::io::BufReader::new(file);
+        self.write_out()?;
+        // Reads from the closest block before `start` that the seek index knows of.
+        let offset = self.seek.offset_for(start).unwrap_or(self.data_start);
+        let mut reader = self.entry_reader_at(offset)?;
         let mut entries = Vec::new();
 
-        loop {
-            match LogEntry::decode(&mut reader) {
-                Ok(entry) => entries.push(entry),
-                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
-                Err(e) => return Err(e),
+        while let Some(entry) = reader.next_entry()? {
+            if entry.index < start {
+                continue;
+            }
+            if !range.contains(&entry.index) {
+                break;
             }
+            entries.push(entry);
         }
 
         Ok(entries)
     }
+
+    /// Returns the entry at `index`, `None` past the end of the log, or a `Compacted`
+    /// error below `first_index`.
+    pub fn read_at(&self, index: u64) -> std::io::Result<Option<LogEntry>> {
+        Ok(self.replay_range(index..=index)?.pop())
+    }
+
+    /// Removes every entry up to and including `up_to`, e.g. once a snapshot covers them.
+    pub fn truncate_prefix(&mut self, up_to: u64) -> std::io::Result<()> {
+        match self.compaction(up_to)? {
+            Some(compaction) => self.install_compaction(compaction.build()?),
+            None => Ok(()),
+        }
+    }
+
+    /// Removes every entry and starts the log over right after `compacted`, as when an
+    /// installed snapshot takes the place of everything up to it. Unlike
+    /// `truncate_prefix`, its index need not be in the log, and later entries are not kept.
+    pub fn reset_to(&mut self, compacted: CompactionPoint) -> std::io::Result<()> {
+        self.write_out()?;
+        self.remove_index()?;
+        let staged = self.storage.staged()?;
+        staged.append_bytes(&FileHeader::current().encode())?;
+        // The log is emptied before the new start is recorded: if we crash in between, it
+        // reopens empty at the old start, which the leader fills in again.
+        self.storage.replace_with(staged)?;
+        self.sync_dir_of_log()?;
+        if self.preserve_terms {
+            CompactionPoint::save_all(&[], &CompactionPoint::terms_path_for(&self.path))?;
+            self.term_boundaries.clear();
+        }
+        compacted.save(&CompactionPoint::path_for(&self.path))?;
+        self.sync_dir_of_log()?;
+
+        self.data_start = HEADER_LEN;
+        self.version = FORMAT_VERSION;
+        self.compacted = compacted;
+        self.last_index = compacted.index;
+        self.last_hash = compacted.chain_hash;
+        self.end = HEADER_LEN;
+        self.seek = SeekIndex::default();
+        self.cache.truncate_suffix(0);
+        if let Some(flusher) = &self.flusher {
+            flusher.replace_storage(&self.storage, compacted.index);
+            flusher.truncated(compacted.index);
+        }
+        Ok(())
+    }
+
+    /// Starts removing every entry up to and including `up_to`, or returns `None` if
+    /// they are gone already. The compacted log is written by `Compaction::build`, which
+    /// does not need the WAL, so it may go on taking appends meanwhile; they are carried
+    /// over by `install_compaction`.
+    pub fn compaction(&self, up_to: u64) -> std::io::Result<Option<Compaction<S>>> {
+        if up_to < self.first_index() {
+            return Ok(None);
+        }
+        if up_to > self.last_index {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("cannot compact to {} past the last entry {}", up_to, self.last_index),
+            ));
+        }
+        // Building reads the log as it is stored, so everything up to here must be.
+        self.write_out()?;
+        Ok(Some(Compaction {
+            up_to,
+            storage: self.storage.clone(),
+            data_start: self.data_start,
+            version: self.version,
+            read_buffer_bytes: self.read_buffer_bytes,
+            base: self.compacted,
+            last_index: self.last_index,
+        }))
+    }
+
+    /// Puts the log `compaction` built in place of this one, once the entries appended
+    /// since it was started are added to it. Fails, leaving the log as it was, if the
+    /// log was compacted or truncated under it meanwhile.
+    pub fn install_compaction(&mut self, compaction: BuiltCompaction<S>) -> std::io::Result<()> {
+        let mut compaction = compaction;
+        let last_built = compaction.last_built;
+        let unchanged = self.compacted == compaction.base
+            && self.read_at(last_built.index)?.map(|entry| entry.chain_hash)
+                == Some(last_built.chain_hash);
+        if !unchanged {
+            return Err(std::io::Error::other("the log changed while its compaction was built"));
+        }
+        let appended = self.replay_range(last_built.index + 1..)?;
+        for block in appended.chunks(BULK_BLOCK_ENTRIES) {
+            compaction.append_block(block)?;
+        }
+        let BuiltCompaction { up_to, staged, compacted, removed, next_term, end, seek, .. } =
+            compaction;
+
+        if self.preserve_terms {
+            // Saved first: should we crash before the log is rewritten, the boundaries
+            // only name entries it still holds.
+            let next_term = next_term.or_else(|| appended.first().map(|entry| entry.term));
+            let boundaries = self.term_boundaries_after(&removed, next_term);
+            CompactionPoint::save_all(&boundaries, &CompactionPoint::terms_path_for(&self.path))?;
+            self.term_boundaries = boundaries;
+        }
+
+        // Record the new start before rewriting the log: if we crash in between, the
+        // removed entries are still at its head and opening skips them.
+        compacted.save(&CompactionPoint::path_for(&self.path))?;
+        self.remove_index()?;
+        self.sync_dir_of_log()?;
+
+        self.storage.replace_with(staged)?;
+        self.sync_dir_of_log()?;
+
+        self.data_start = HEADER_LEN;
+        self.version = FORMAT_VERSION;
+        self.compacted = compacted;
+        self.end = end;
+        self.seek = seek;
+        self.cache.truncate_prefix(up_to);
+        // The rewritten log was synced whole, pending entries included.
+        if let Some(flusher) = &self.flusher {
+            flusher.replace_storage(&self.storage, self.last_index);
+        }
+        Ok(())
+    }
+
+    /// `term_boundaries` with the last entry of each term in `removed` added, as well as
+    /// the current compaction point if its term ended there. `next_term` is the term of
+    /// the entry left first in the log, if any.
+    fn term_boundaries_after(
+        &self,
+        removed: &[CompactionPoint],
+        next_term: Option<u64>,
+    ) -> Vec<CompactionPoint> {
+        let mut boundaries = self.term_boundaries.clone();
+        let mut points = std::iter::once(self.compacted)
+            .filter(|point| point.index > 0)
+            .chain(removed.iter().copied())
+            .peekable();
+        while let Some(point) = points.next() {
+            let following = points.peek().map(|next| next.term).or(next_term);
+            let recorded = boundaries.last().is_some_and(|last| last.index >= point.index);
+            if following != Some(point.term) && !recorded {
+                boundaries.push(point);
+            }
+        }
+        boundaries
+    }
+
+    /// Makes files just created or renamed next to the log durable, if `sync_dir` is set.
+    fn sync_dir_of_log(&self) -> std::io::Result<()> {
+        if !self.sync_dir {
+            return Ok(());
+        }
+        sync_parent_dir(&self.path)
+    }
+
+    /// Removes every entry from `from` onwards, e.g. when a Raft leader overwrites entries
+    /// that conflict with its own log. Entries that were already compacted cannot be
+    /// removed.
+    pub fn truncate_suffix(&mut self, from: u64) -> std::io::Result<()> {
+        if from > self.last_index {
+            return Ok(());
+        }
+        self.check_not_compacted(from)?;
+
+        self.write_out()?;
+        self.remove_index()?;
+        // Starting from the block of the entry before `from`, which the chain goes on from.
+        let offset = self.seek.offset_for(from - 1).unwrap_or(self.data_start);
+        let mut reader = self.entry_reader_at(offset)?;
+        let mut last_hash = self.compacted.chain_hash;
+        // The entries before `from` in the block that holds it, which are cut along with
+        // the rest of the block and written back.
+        let mut block_start = offset;
+        let mut kept_in_block = Vec::new();
+        loop {
+            let entry = reader.next_entry()?.ok_or_else(|| {
+                let message = "log ends before its last index";
+                std::io::Error::new(std::io::ErrorKind::UnexpectedEof, message)
+            })?;
+            if reader.block_start() != block_start {
+                block_start = reader.block_start();
+                kept_in_block.clear();
+            }
+            if entry.index >= from {
+                break;
+            }
+            if entry.index > self.compacted.index {
+                last_hash = entry.chain_hash;
+            }
+            kept_in_block.push(entry);
+        }
+
+        self.storage.set_len(block_start)?;
+        self.end = block_start;
+        if !kept_in_block.is_empty() {
+            let encoded = encode_block(&kept_in_block, self.version)?;
+            self.storage.append_bytes(&encoded)?;
+            self.end += encoded.len() as u64;
+        }
+        self.storage.sync()?;
+        self.seek.truncate(from);
+        self.last_index = from - 1;
+        self.last_hash = last_hash;
+        self.cache.truncate_suffix(from);
+        // Whatever was pending is gone, and what is left was just synced.
+        if let Some(flusher) = &self.flusher {
+            flusher.truncated(self.last_index);
+        }
+        Ok(())
+    }
+
+    fn check_not_compacted(&self, index: u64) -> std::io::Result<()> {
+        if index < self.first_index() {
+            return Err(Compacted {
+                index,
+                first_index: self.first_index(),
+            }
+            .into_io());
+        }
+        Ok(())
+    }
+
+    /// Walks the log recomputing the hash chain, and returns the index of the first
+    /// entry whose stored hash does not match, or `None` if the chain is intact.
+    /// Unlike a per-entry checksum, this catches entries that were reordered or
+    /// removed as well as modified.
+    pub fn verify_chain(&self) -> std::io::Result<Option<u64>> {
+        let mut prev = self.compacted.chain_hash;
+        for entry in self.replay()? {
+            if entry.chain_from(&prev) != entry.chain_hash {
+                return Ok(Some(entry.index));
+            }
+            prev = entry.chain_hash;
+        }
+
+        Ok(None)
+    }
+}
+
+/// A compaction of a log to some index that `Wal::compaction` started, not written yet.
+#[derive(Debug)]
+pub struct Compaction<S: LogStorage = FileStorage> {
+    up_to: u64,
+    storage: S,
+    data_start: u64,
+    version: u8,
+    read_buffer_bytes: usize,
+    /// Where the log started, which must still hold when the compaction is installed.
+    base: CompactionPoint,
+    /// Last entry stored when the compaction started; building reads no further.
+    last_index: u64,
+}
+
+/// A compacted log that `Compaction::build` wrote beside the log, for
+/// `Wal::install_compaction` to put in its place.
+#[derive(Debug)]
+pub struct BuiltCompaction<S: LogStorage = FileStorage> {
+    up_to: u64,
+    staged: S,
+    base: CompactionPoint,
+    /// Where the compacted log starts.
+    compacted: CompactionPoint,
+    /// The last entry of each run of a term among those removed, oldest first.
+    removed: Vec<CompactionPoint>,
+    /// The last entry the compacted log accounts for, whether removed or kept.
+    last_built: CompactionPoint,
+    /// Term of the first entry kept, if any was.
+    next_term: Option<u64>,
+    /// Length of `staged`.
+    end: u64,
+    /// Where the blocks of `staged` are.
+    seek: SeekIndex,
+}
+
+impl<S: LogStorage> BuiltCompaction<S> {
+    /// Adds `entries`, which carry on from the last one in the log built, as a block.
+    fn append_block(&mut self, entries: &[LogEntry]) -> std::io::Result<()> {
+        let encoded = encode_block(entries, FORMAT_VERSION)?;
+        self.staged.append_bytes(&encoded)?;
+        self.seek.record(entries[0].index, self.end);
+        self.end += encoded.len() as u64;
+        Ok(())
+    }
+}
+
+impl<S: LogStorage> Compaction<S> {
+    /// Writes the entries past `up_to` beside the log, under a current header, reading
+    /// the log through a storage handle of its own rather than the WAL.
+    pub fn build(self) -> std::io::Result<BuiltCompaction<S>> {
+        let staged = self.storage.staged()?;
+        staged.append_bytes(&FileHeader::current().encode())?;
+        let mut built = BuiltCompaction {
+            up_to: self.up_to,
+            staged,
+            base: self.base,
+            compacted: self.base,
+            removed: Vec::new(),
+            last_built: self.base,
+            next_term: None,
+            end: HEADER_LEN,
+            seek: SeekIndex::default(),
+        };
+        let (start, version, bytes) = (self.data_start, self.version, self.read_buffer_bytes);
+        let mut reader = EntryReader::with_buffer(&self.storage, start, version, bytes)?;
+        let mut kept = Vec::with_capacity(BULK_BLOCK_ENTRIES);
+        while built.last_built.index < self.last_index {
+            let entry = reader.next_entry()?.ok_or_else(|| {
+                let message = "log ends before its last index";
+                std::io::Error::new(std::io::ErrorKind::UnexpectedEof, message)
+            })?;
+            // Leftovers of an interrupted compaction are not carried over.
+            if entry.index <= self.base.index {
+                continue;
+            }
+            built.last_built = CompactionPoint {
+                index: entry.index,
+                term: entry.term,
+                chain_hash: entry.chain_hash,
+            };
+            if entry.index <= self.up_to {
+                // Only the last entry of a term can be a boundary.
+                if built.removed.last().is_some_and(|last| last.term == entry.term) {
+                    built.removed.pop();
+                }
+                built.removed.push(built.last_built);
+                built.compacted = built.last_built;
+                continue;
+            }
+            built.next_term.get_or_insert(entry.term);
+            kept.push(entry);
+            if kept.len() == BULK_BLOCK_ENTRIES {
+                built.append_block(&kept)?;
+                kept.clear();
+            }
+        }
+        if !kept.is_empty() {
+            built.append_block(&kept)?;
+        }
+        Ok(built)
+    }
+}
+
+/// One line of `export_jsonl`. A command that does not decode is exported with the
+/// kind "undecodable" and no fields.
+fn export_line(entry: &LogEntry) -> serde_json::Value {
+    let (kind, op) = match BankCommand::decode(entry.command_slice()) {
+        Ok(command) => (command.kind_name(), command.to_json()),
+        Err(_) => ("undecodable", serde_json::Value::Null),
+    };
+    serde_json::json!({
+        "index": entry.index,
+        "term": entry.term,
+        "timestamp": entry.timestamp,
+        "kind": kind,
+        "op": op,
+        "command": base64::engine::general_purpose::STANDARD.encode(entry.command_slice()),
+    })
+}
+
+/// Reads back the entry in a line of `export_jsonl`.
+fn import_line(line: &str) -> Result<LogEntry, String> {
+    let value: serde_json::Value =
+        serde_json::from_str(line).map_err(|e| format!("not valid JSON: {}", e))?;
+    let number = |field: &str| {
+        value[field].as_u64().ok_or_else(|| format!("{} is missing or not a number", field))
+    };
+    let (index, term) = (number("index")?, number("term")?);
+    let timestamp = match value["timestamp"] {
+        serde_json::Value::Null => None,
+        _ => Some(number("timestamp")?),
+    };
+    let command = value["command"].as_str().ok_or("command is missing or not a string")?;
+    let command = base64::engine::general_purpose::STANDARD
+        .decode(command)
+        .map_err(|e| format!("command is not valid base64: {}", e))?;
+
+    Ok(LogEntry {
+        timestamp,
+        ..LogEntry::new(index, term, command.into())
+    })
 }
 
 #[cfg(test)]
@@ -82,134 +1375,804 @@ mod tests {
     use std::fs;
     use std::io::Write;
     use tempfile::{NamedTempFile, TempDir};
+    use crate::bank::tests::{command_entry, create_account, transfer};
+    use crate::bank::BankStateMachine;
     use crate::wal::entry::tests::create_test_entry;
+    use crate::wal::entry::GENESIS_HASH;
+    use crate::wal::storage::MemoryStorage;
+
+    /// A log the tests open, and open again as a restarting node would, on one backend.
+    trait TestLog {
+        type Storage: LogStorage;
+
+        fn create() -> Self;
+
+        /// Names the log; its compaction point and tail index are kept next to it.
+        fn path(&self) -> &str;
+
+        fn open(&self) -> Wal<Self::Storage>;
+    }
+
+    struct FileLog {
+        _dir: TempDir,
+        path: String,
+    }
+
+    impl TestLog for FileLog {
+        type Storage = FileStorage;
+
+        fn create() -> Self {
+            let dir = TempDir::new().unwrap();
+            let path = dir.path().join("test.wal").to_str().unwrap().to_string();
+            Self { _dir: dir, path }
+        }
+
+        fn path(&self) -> &str {
+            &self.path
+        }
+
+        fn open(&self) -> Wal {
+            Wal::new(&self.path).unwrap()
+        }
+    }
+
+    struct MemoryLog {
+        file: FileLog,
+        storage: MemoryStorage,
+    }
+
+    impl TestLog for MemoryLog {
+        type Storage = MemoryStorage;
+
+        fn create() -> Self {
+            Self {
+                file: FileLog::create(),
+                storage: MemoryStorage::new(),
+            }
+        }
+
+        fn path(&self) -> &str {
+            self.file.path()
+        }
+
+        fn open(&self) -> Wal<MemoryStorage> {
+            Wal::with_storage(self.path(), self.storage.clone(), IntegrityMode::Full).unwrap()
+        }
+    }
+
+    /// Runs each test, a function of a `&impl TestLog`, on a file and in memory.
+    macro_rules! on_every_backend {
+        ($($test:ident),* $(,)?) => {$(
+            mod $test {
+                use super::{FileLog, MemoryLog, TestLog};
+
+                #[test]
+                fn on_file() {
+                    super::$test(&FileLog::create());
+                }
+
+                #[test]
+                fn in_memory() {
+                    super::$test(&MemoryLog::create());
+                }
+            }
+        )*};
+    }
+
+    on_every_backend!(
+        test_wal_creation_new_file,
+        test_wal_append_single_entry,
+        test_wal_append_multiple_entries,
+        test_wal_append_entries_different_terms,
+        test_wal_replay_empty,
+        test_wal_replay_single_entry,
+        test_wal_replay_multiple_entries,
+        test_wal_replay_with_reports_progress,
+        test_wal_apply_to_matches_replay,
+        test_wal_append_stamps_nondecreasing_timestamps,
+        test_wal_append_preserves_given_timestamp,
+        test_wal_cached_read_matches_disk,
+        test_wal_truncate_suffix_evicts_cached_entries,
+        test_wal_entries_across_blocks_replay_identically,
+        test_wal_persistence_across_instances,
+        test_wal_append_after_restart,
+        test_wal_large_entries,
+        test_wal_max_entry_bytes,
+        test_wal_consecutive_dedup,
+        test_wal_edge_cases,
+        test_wal_multiple_decode_cycles,
+        test_wal_verify_chain_intact,
+        test_wal_first_index_uncompacted,
+        test_wal_truncate_prefix,
+        test_wal_read_below_first_index_is_compacted,
+        test_wal_truncate_prefix_whole_log,
+        test_wal_reset_to_starts_over_past_an_index_it_never_held,
+        test_wal_compaction_keeps_entries_appended_while_it_is_built,
+        test_wal_compaction_fails_if_the_log_is_truncated_while_it_is_built,
+        test_wal_term_preserving_compaction_keeps_term_boundaries,
+        test_wal_truncate_suffix,
+        test_wal_skips_entries_left_by_interrupted_compaction,
+        test_wal_background_flush_makes_concurrent_appends_durable,
+        test_wal_append_and_wait_durable_waits_for_fsync,
+        test_wal_background_flush_on_close,
+        test_wal_without_background_flush_is_always_durable,
+    );
+
+    fn test_wal_creation_new_file(log: &impl TestLog) {
+        let wal = log.open();
+        assert_eq!(wal.last_index, 0);
+    }
 
     #[test]
-    fn test_wal_creation_new_file() {
+    fn test_wal_creation_existing_empty_file() {
         let temp_file = NamedTempFile::new().unwrap();
         let path = temp_file.path().to_str().unwrap();
 
-        let wal = Wal::new(path).unwrap();
-        assert_eq!(wal.last_index, 0);
+        // Create empty file
+        fs::write(path, b"").unwrap();
+
+        let wal = Wal::new(path).unwrap();
+        assert_eq!(wal.last_index, 0);
+    }
+
+    fn test_wal_append_single_entry(log: &impl TestLog) {
+        let mut wal = log.open();
+        let entry = create_test_entry(1, 1, b"first entry");
+
+        wal.append(entry.clone()).unwrap();
+        assert_eq!(wal.last_index, 1);
+    }
+
+    fn test_wal_append_multiple_entries(log: &impl TestLog) {
+        let mut wal = log.open();
+
+        for i in 1..=5 {
+            let entry = create_test_entry(i, 1, format!("entry {}", i).as_bytes());
+            wal.append(entry).unwrap();
+        }
+
+        assert_eq!(wal.last_index, 5);
+    }
+
+    fn test_wal_append_entries_different_terms(log: &impl TestLog) {
+        let mut wal = log.open();
+
+        // Append entries with increasing terms
+        for i in 1..=3 {
+            let entry = create_test_entry(i, i, format!("entry {} term {}", i, i).as_bytes());
+            wal.append(entry).unwrap();
+        }
+
+        assert_eq!(wal.last_index, 3);
+    }
+
+    fn test_wal_replay_empty(log: &impl TestLog) {
+        let wal = log.open();
+        let entries = wal.replay().unwrap();
+
+        assert!(entries.is_empty());
+    }
+
+    fn test_wal_replay_single_entry(log: &impl TestLog) {
+        let mut wal = log.open();
+        let original_entry = create_test_entry(1, 1, b"test entry");
+
+        wal.append(original_entry.clone()).unwrap();
+
+        let replayed_entries = wal.replay().unwrap();
+        assert_eq!(replayed_entries.len(), 1);
+
+        let replayed_entry = &replayed_entries[0];
+        assert_eq!(replayed_entry.index, original_entry.index);
+        assert_eq!(replayed_entry.term, original_entry.term);
+        assert_eq!(replayed_entry.command, original_entry.command);
+    }
+
+    fn test_wal_replay_multiple_entries(log: &impl TestLog) {
+        let mut wal = log.open();
+        let mut original_entries = Vec::new();
+
+        for i in 1..=5 {
+            let entry = create_test_entry(i, i, format!("entry {}", i).as_bytes());
+            original_entries.push(entry.clone());
+            wal.append(entry).unwrap();
+        }
+
+        let replayed_entries = wal.replay().unwrap();
+        assert_eq!(replayed_entries.len(), 5);
+
+        for (original, replayed) in original_entries.iter().zip(replayed_entries.iter()) {
+            assert_eq!(original.index, replayed.index);
+            assert_eq!(original.term, replayed.term);
+            assert_eq!(original.command, replayed.command);
+        }
+    }
+
+    fn test_wal_replay_with_reports_progress(log: &impl TestLog) {
+        let mut wal = log.open();
+        for i in 1..=5 {
+            wal.append(create_test_entry(i, 1, "x".repeat(i as usize).as_bytes())).unwrap();
+        }
+
+        let mut calls = Vec::new();
+        wal.replay_with(|entry, bytes_read, total_bytes| {
+            calls.push((entry.index, bytes_read, total_bytes));
+        })
+        .unwrap();
+
+        let file_size = wal.size().unwrap();
+        let indexes: Vec<u64> = calls.iter().map(|(index, _, _)| *index).collect();
+        assert_eq!(indexes, vec![1, 2, 3, 4, 5]);
+        assert!(calls.windows(2).all(|pair| pair[0].1 < pair[1].1));
+        assert!(calls.iter().all(|(_, _, total_bytes)| *total_bytes == file_size));
+        assert_eq!(calls.last().unwrap().1, file_size);
+    }
+
+    fn test_wal_apply_to_matches_replay(log: &impl TestLog) {
+        let mut wal = log.open();
+        let commands = [
+            create_account("alice", 100),
+            create_account("bob", 0),
+            transfer("alice", "bob", 30, "tx-1"),
+            transfer("bob", "alice", 50, "tx-2"),
+            transfer("bob", "alice", 10, "tx-3"),
+        ];
+        for (index, command) in (1..).zip(commands) {
+            wal.append(command_entry(index, command)).unwrap();
+        }
+
+        let mut streamed = BankStateMachine::new();
+        assert_eq!(wal.apply_to(&mut streamed).unwrap(), 5);
+
+        let mut collected = BankStateMachine::new();
+        for entry in wal.replay().unwrap() {
+            collected.apply(&entry).unwrap();
+        }
+        for account in ["alice", "bob"] {
+            assert_eq!(streamed.balance(account), collected.balance(account));
+        }
+        assert_eq!(streamed.balance("bob"), Some(20));
+        assert_eq!(streamed.last_applied(), 5);
+
+        // An empty log applies nothing and reports where it starts.
+        wal.truncate_prefix(5).unwrap();
+        assert_eq!(wal.apply_to(&mut BankStateMachine::new()).unwrap(), 5);
+    }
+
+    fn test_wal_append_stamps_nondecreasing_timestamps(log: &impl TestLog) {
+        let mut wal = log.open();
+        for i in 1..=20 {
+            wal.append(create_test_entry(i, 1, b"entry")).unwrap();
+        }
+        drop(wal);
+
+        // Reopening carries on from the latest timestamp in the log.
+        let mut wal = log.open();
+        wal.append(create_test_entry(21, 1, b"entry")).unwrap();
+
+        let timestamps: Vec<u64> =
+            wal.replay().unwrap().iter().map(|entry| entry.timestamp.unwrap()).collect();
+        assert_eq!(timestamps.len(), 21);
+        assert!(timestamps.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", timestamps);
+    }
+
+    fn test_wal_append_preserves_given_timestamp(log: &impl TestLog) {
+        let mut wal = log.open();
+        let mut entry = create_test_entry(1, 1, b"from the leader");
+        entry.timestamp = Some(1_700_000_000_000);
+        wal.append(entry).unwrap();
+
+        assert_eq!(wal.read_at(1).unwrap().unwrap().timestamp, Some(1_700_000_000_000));
+        assert_eq!(wal.verify_chain().unwrap(), None);
+    }
+
+    fn test_wal_cached_read_matches_disk(log: &impl TestLog) {
+        let mut wal = log.open().with_cache_capacity(4);
+        for i in 1..=10 {
+            wal.append(create_test_entry(i, 1, format!("entry {}", i).as_bytes())).unwrap();
+        }
+        assert_eq!(wal.cache.first_index(), Some(7));
+
+        let uncached = log.open().with_cache_capacity(0);
+        for i in 1..=10 {
+            assert_eq!(wal.read_at(i).unwrap(), uncached.read_at(i).unwrap());
+        }
+        assert_eq!(wal.replay_range(8..).unwrap(), uncached.replay_range(8..).unwrap());
+        assert_eq!(wal.replay_range(5..9).unwrap(), uncached.replay_range(5..9).unwrap());
+        assert_eq!(wal.read_at(11).unwrap(), None);
     }
 
-    #[test]
-    fn test_wal_creation_existing_empty_file() {
-        let temp_file = NamedTempFile::new().unwrap();
-        let path = temp_file.path().to_str().unwrap();
+    fn test_wal_truncate_suffix_evicts_cached_entries(log: &impl TestLog) {
+        let mut wal = log.open();
+        for i in 1..=5 {
+            wal.append(create_test_entry(i, 1, b"old")).unwrap();
+        }
 
-        // Create empty file
-        fs::write(path, b"").unwrap();
+        wal.truncate_suffix(3).unwrap();
+        assert_eq!(wal.read_at(3).unwrap(), None);
+        assert_eq!(wal.replay_range(1..).unwrap().len(), 2);
 
-        let wal = Wal::new(path).unwrap();
-        assert_eq!(wal.last_index, 0);
+        wal.append(create_test_entry(3, 2, b"new")).unwrap();
+        let entry = wal.read_at(3).unwrap().unwrap();
+        assert_eq!((entry.term, entry.command), (2, Bytes::from("new")));
+        assert_eq!(wal.read_at(4).unwrap(), None);
+    }
+
+    fn test_wal_entries_across_blocks_replay_identically(log: &impl TestLog) {
+        let mut wal = log.open();
+        let entry = |i: u64| create_test_entry(i, 1, format!("entry {}", i).as_bytes());
+        wal.append_batch((1..=3).map(entry).collect()).unwrap();
+        wal.append(entry(4)).unwrap();
+        wal.append_batch((5..=9).map(entry).collect()).unwrap();
+        wal.append_batch(Vec::new()).unwrap();
+        assert_eq!(wal.last_index(), 9);
+        let written = wal.replay().unwrap();
+        assert_eq!(written.len(), 9);
+        assert_eq!(wal.verify_chain().unwrap(), None);
+
+        let reopened = log.open().with_cache_capacity(0);
+        assert_eq!(reopened.last_index(), 9);
+        assert_eq!(reopened.replay().unwrap(), written);
+        assert_eq!(reopened.replay_range(3..6).unwrap(), written[2..5]);
+        assert_eq!(reopened.read_at(5).unwrap().as_ref(), Some(&written[4]));
+
+        // Truncating inside a block keeps the entries before the cut.
+        let mut reopened = reopened;
+        reopened.truncate_suffix(7).unwrap();
+        reopened.append(entry(7)).unwrap();
+        assert_eq!(reopened.replay().unwrap()[..6], written[..6]);
+        assert_eq!(log.open().last_index(), 7);
+        assert_eq!(reopened.verify_chain().unwrap(), None);
     }
 
     #[test]
-    fn test_wal_append_single_entry() {
+    fn test_wal_detects_corrupt_block_by_crc() {
         let temp_file = NamedTempFile::new().unwrap();
         let path = temp_file.path().to_str().unwrap();
-
         let mut wal = Wal::new(path).unwrap();
-        let entry = create_test_entry(1, 1, b"first entry");
+        wal.append_batch(vec![create_test_entry(1, 1, b"one"), create_test_entry(2, 1, b"two")])
+            .unwrap();
+        drop(wal);
+
+        let mut contents = fs::read(path).unwrap();
+        let last = contents.len() - 1;
+        contents[last] ^= 0xFF;
+        fs::write(path, contents).unwrap();
+
+        let err = Wal::new(path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("CRC"), "{}", err);
+    }
 
-        wal.append(entry.clone()).unwrap();
-        assert_eq!(wal.last_index, 1);
+    /// Flips the case of the first byte of `needle` in the file at `path`, which leaves
+    /// the entry holding it decodable but its block failing the CRC check.
+    fn corrupt_command(path: &str, needle: &[u8]) {
+        let mut contents = fs::read(path).unwrap();
+        let at = contents.windows(needle.len()).position(|w| w == needle).unwrap();
+        contents[at] ^= 0x20;
+        fs::write(path, contents).unwrap();
     }
 
     #[test]
-    fn test_wal_append_multiple_entries() {
+    fn test_wal_integrity_mode_on_open() {
         let temp_file = NamedTempFile::new().unwrap();
         let path = temp_file.path().to_str().unwrap();
-
         let mut wal = Wal::new(path).unwrap();
+        wal.append(create_test_entry(1, 1, b"early")).unwrap();
+        wal.append(create_test_entry(2, 1, b"late")).unwrap();
+        drop(wal);
+        corrupt_command(path, b"early");
+
+        let err = Wal::new(path).unwrap_err();
+        assert!(err.to_string().contains("CRC"), "{}", err);
+
+        // Only the last block is checked, so the corrupt first one goes unnoticed...
+        let wal = Wal::new_with_integrity(path, IntegrityMode::TailOnly).unwrap();
+        assert_eq!(wal.last_index(), 2);
+        // ...until it is read.
+        let err = wal.replay().unwrap_err();
+        assert!(err.to_string().contains("CRC"), "{}", err);
+        drop(wal);
+
+        corrupt_command(path, b"late");
+        let err = Wal::new_with_integrity(path, IntegrityMode::TailOnly).unwrap_err();
+        assert!(err.to_string().contains("CRC"), "{}", err);
+        let wal = Wal::new_with_integrity(path, IntegrityMode::None).unwrap();
+        assert_eq!(wal.last_index(), 2);
+    }
+
+    /// Sets the index of the first entry to one a full scan refuses, so opening fails
+    /// unless it picks the scan up from the tail index.
+    fn trip_full_scan(path: &Path) {
+        let mut contents = fs::read(path).unwrap();
+        contents[HEADER_LEN as usize + 8] ^= 0x20;
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn test_wal_replay_while_appending_sees_a_consistent_prefix() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("bank.wal");
+        let path = path.to_str().unwrap().to_string();
+        let mut wal = Wal::new(&path).unwrap();
+        wal.append(create_test_entry(1, 1, b"entry 1")).unwrap();
+
+        let appender = std::thread::spawn(move || {
+            for index in 2..=500 {
+                let command = format!("entry {}", index);
+                wal.append(create_test_entry(index, index / 100 + 1, command.as_bytes())).unwrap();
+            }
+        });
+
+        let mut seen = 0;
+        while seen < 500 {
+            let entries = Wal::open_read_only(&path).unwrap().replay().unwrap();
+            // Whatever has been appended so far, in order and chained, never less than before.
+            assert!(entries.len() >= seen);
+            let mut prev = GENESIS_HASH;
+            for (entry, index) in entries.iter().zip(1..) {
+                assert_eq!(entry.index, index);
+                assert_eq!(entry.command.as_ref(), format!("entry {}", index).as_bytes());
+                assert_eq!(entry.chain_hash, entry.chain_from(&prev));
+                prev = entry.chain_hash;
+            }
+            seen = entries.len();
+        }
+        appender.join().unwrap();
+    }
 
+    #[test]
+    fn test_wal_opens_from_its_tail_index() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("bank.wal");
+        let mut wal = Wal::new(path.to_str().unwrap()).unwrap();
+        // Large enough that the first entry is outside what the index checksums.
         for i in 1..=5 {
-            let entry = create_test_entry(i, 1, format!("entry {}", i).as_bytes());
-            wal.append(entry).unwrap();
+            wal.append(create_test_entry(i, 1, &[i as u8; 1024])).unwrap();
+        }
+        wal.save_index().unwrap();
+        for i in 6..=8 {
+            wal.append(create_test_entry(i, 2, format!("entry {}", i).as_bytes())).unwrap();
         }
+        let (last_hash, last_timestamp) = (wal.last_hash, wal.last_timestamp);
+        drop(wal);
+        trip_full_scan(&path);
+
+        let integrity = IntegrityMode::TailOnly;
+        let index_path = TailIndex::path_for(&path);
+        let saved_index = fs::read(&index_path).unwrap();
+        fs::remove_file(&index_path).unwrap();
+        let err = Wal::new_with_integrity(path.to_str().unwrap(), integrity).unwrap_err();
+        assert!(err.to_string().contains("not sequential"), "{}", err);
+
+        // Only the entries after the index are scanned, picking the chain up from it.
+        fs::write(&index_path, saved_index).unwrap();
+        let mut wal = Wal::new_with_integrity(path.to_str().unwrap(), integrity).unwrap();
+        assert_eq!(wal.last_index(), 8);
+        assert_eq!((wal.last_hash, wal.last_timestamp), (last_hash, last_timestamp));
+        wal.append(create_test_entry(9, 2, b"entry 9")).unwrap();
+        let appended = wal.read_at(9).unwrap().unwrap();
+        assert_eq!(appended.chain_hash, appended.chain_from(&last_hash));
+    }
 
-        assert_eq!(wal.last_index, 5);
+    #[test]
+    fn test_wal_reads_seek_through_the_blocks_the_tail_index_saved() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("bank.wal");
+        let mut wal = Wal::new(path.to_str().unwrap()).unwrap();
+        for i in 1..=1000 {
+            wal.append(create_test_entry(i, 1, format!("entry {}", i).as_bytes())).unwrap();
+        }
+        wal.save_index().unwrap();
+        drop(wal);
+        trip_full_scan(&path);
+
+        // Neither opening nor reading far from the head goes through the first block.
+        let integrity = IntegrityMode::None;
+        let mut wal = Wal::new_with_integrity(path.to_str().unwrap(), integrity).unwrap();
+        assert_eq!(wal.read_at(900).unwrap().unwrap().command, Bytes::from("entry 900"));
+        let entries = wal.replay_range(500..=502).unwrap();
+        assert_eq!(entries.iter().map(|e| e.index).collect::<Vec<_>>(), vec![500, 501, 502]);
+        assert!(wal.read_at(1).is_err());
+
+        // Nor after cutting the log, or appending to it.
+        wal.truncate_suffix(800).unwrap();
+        for i in 800..=1100 {
+            wal.append(create_test_entry(i, 2, format!("again {}", i).as_bytes())).unwrap();
+        }
+        wal.save_index().unwrap();
+        drop(wal);
+        let wal = Wal::new_with_integrity(path.to_str().unwrap(), integrity).unwrap();
+        assert_eq!(wal.read_at(799).unwrap().unwrap().command, Bytes::from("entry 799"));
+        assert_eq!(wal.read_at(1050).unwrap().unwrap().command, Bytes::from("again 1050"));
+    }
+
+    #[test]
+    fn test_wal_stale_tail_index_falls_back_to_a_full_scan() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("bank.wal");
+        let mut wal = Wal::new(path.to_str().unwrap()).unwrap();
+        for i in 1..=5 {
+            wal.append(create_test_entry(i, 1, format!("entry {}", i).as_bytes())).unwrap();
+        }
+        wal.save_index().unwrap();
+        drop(wal);
+
+        // The log is replaced by a longer one that differs before the indexed offset.
+        let entries = chained_entries(&FileLog::create(), 7);
+        rewrite(path.to_str().unwrap(), &entries);
+
+        let integrity = IntegrityMode::None;
+        let mut wal = Wal::new_with_integrity(path.to_str().unwrap(), integrity).unwrap();
+        assert_eq!(wal.last_index(), 7);
+        assert_eq!(wal.last_hash, entries[6].chain_hash);
+
+        // Rewriting the log under the index removes it.
+        wal.save_index().unwrap();
+        wal.truncate_suffix(6).unwrap();
+        assert!(!TailIndex::path_for(&path).exists());
+        wal.save_index().unwrap();
+        wal.truncate_prefix(2).unwrap();
+        assert!(!TailIndex::path_for(&path).exists());
     }
 
     #[test]
-    fn test_wal_append_entries_different_terms() {
+    fn test_wal_export_jsonl() {
         let temp_file = NamedTempFile::new().unwrap();
         let path = temp_file.path().to_str().unwrap();
-
         let mut wal = Wal::new(path).unwrap();
+        wal.append(command_entry(1, create_account("alice", 100))).unwrap();
+        wal.append(command_entry(2, transfer("alice", "bob", 30, "tx-1"))).unwrap();
+        wal.append(create_test_entry(3, 2, b"\xFF not a command")).unwrap();
+
+        let mut out = Vec::new();
+        assert_eq!(wal.export_jsonl(&mut out).unwrap(), 3);
+        let lines: Vec<serde_json::Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
 
-        // Append entries with increasing terms
-        for i in 1..=3 {
-            let entry = create_test_entry(i, i, format!("entry {} term {}", i, i).as_bytes());
-            wal.append(entry).unwrap();
+        let entries = wal.replay().unwrap();
+        for (line, entry) in lines.iter().zip(&entries) {
+            assert_eq!(line["index"], entry.index);
+            assert_eq!(line["term"], entry.term);
+            assert_eq!(line["timestamp"], entry.timestamp.unwrap());
+            let command = base64::engine::general_purpose::STANDARD
+                .decode(line["command"].as_str().unwrap())
+                .unwrap();
+            assert_eq!(command, entry.command_slice());
         }
-
-        assert_eq!(wal.last_index, 3);
+        assert_eq!(lines[0]["kind"], "create_account");
+        assert_eq!(lines[0]["op"]["account"], "alice");
+        assert_eq!(lines[0]["op"]["initial_balance"], 100);
+        assert_eq!(lines[1]["kind"], "transfer");
+        assert_eq!(lines[1]["op"]["client_tx_id"], "tx-1");
+        assert_eq!(lines[2]["kind"], "undecodable");
+        assert_eq!(lines[2]["term"], 2);
     }
 
     #[test]
-    fn test_wal_replay_empty() {
-        let temp_file = NamedTempFile::new().unwrap();
-        let path = temp_file.path().to_str().unwrap();
+    fn test_wal_import_jsonl_roundtrips_export() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("exported.wal");
+        let mut wal = Wal::new(path.to_str().unwrap()).unwrap();
+        wal.append(command_entry(1, create_account("alice", 100))).unwrap();
+        wal.append_batch(vec![
+            command_entry(2, create_account("bob", 0)),
+            command_entry(3, transfer("alice", "bob", 30, "tx-1")),
+        ])
+        .unwrap();
+        wal.append(create_test_entry(4, 2, b"\xFF not a command")).unwrap();
+        let mut exported = Vec::new();
+        wal.export_jsonl(&mut exported).unwrap();
+
+        let imported_path = temp_dir.path().join("imported.wal");
+        let imported_path = imported_path.to_str().unwrap();
+        let imported = Wal::import_jsonl(imported_path, exported.as_slice()).unwrap();
+        assert_eq!(imported.last_index(), 4);
+        assert_eq!(imported.verify_chain().unwrap(), None);
+        drop(imported);
+        let reopened = Wal::new(imported_path).unwrap();
+        assert_eq!(reopened.replay().unwrap(), wal.replay().unwrap());
+
+        let err = Wal::import_jsonl(imported_path, exported.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+    }
 
-        let wal = Wal::new(path).unwrap();
-        let entries = wal.replay().unwrap();
+    #[test]
+    fn test_wal_import_jsonl_rejects_bad_lines() {
+        let temp_dir = TempDir::new().unwrap();
+        let import = |name: &str, jsonl: &str| {
+            let path = temp_dir.path().join(name);
+            let err = Wal::import_jsonl(path.to_str().unwrap(), jsonl.as_bytes()).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+            assert!(!path.exists(), "{} was left behind", path.display());
+            err.to_string()
+        };
+        let line = |index: u64| {
+            format!(r#"{{"index":{},"term":1,"timestamp":null,"command":"AA=="}}"#, index)
+        };
 
-        assert!(entries.is_empty());
+        let gap = format!("{}\n{}\n", line(1), line(3));
+        assert!(import("gap.wal", &gap).contains("line 2: entry has index 3"));
+        assert!(import("late.wal", &line(2)).contains("where 1 was expected"));
+        let garbage = format!("{}\n\nnot json\n", line(1));
+        assert!(import("garbage.wal", &garbage).contains("line 3: not valid JSON"));
+        let no_term = r#"{"index":1,"command":"AA=="}"#;
+        assert!(import("no-term.wal", no_term).contains("term is missing"));
+        let bad_command = r#"{"index":1,"term":1,"command":"!!"}"#;
+        assert!(import("bad-command.wal", bad_command).contains("not valid base64"));
     }
 
     #[test]
-    fn test_wal_replay_single_entry() {
+    fn test_wal_starts_with_header() {
         let temp_file = NamedTempFile::new().unwrap();
         let path = temp_file.path().to_str().unwrap();
-
         let mut wal = Wal::new(path).unwrap();
-        let original_entry = create_test_entry(1, 1, b"test entry");
-
-        wal.append(original_entry.clone()).unwrap();
-
-        let replayed_entries = wal.replay().unwrap();
-        assert_eq!(replayed_entries.len(), 1);
+        wal.append(create_test_entry(1, 1, b"first entry")).unwrap();
 
-        let replayed_entry = &replayed_entries[0];
-        assert_eq!(replayed_entry.index, original_entry.index);
-        assert_eq!(replayed_entry.term, original_entry.term);
-        assert_eq!(replayed_entry.command, original_entry.command);
+        let contents = fs::read(path).unwrap();
+        assert_eq!(&contents[..HEADER_LEN as usize], &FileHeader::current().encode());
+        drop(wal);
+        assert_eq!(Wal::new(path).unwrap().replay().unwrap().len(), 1);
     }
 
     #[test]
-    fn test_wal_replay_multiple_entries() {
-        let temp_file = NamedTempFile::new().unwrap();
-        let path = temp_file.path().to_str().unwrap();
+    fn test_wal_truncates_a_torn_tail_on_open() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("bank.wal");
+        let path = path.to_str().unwrap();
+        let mut wal = Wal::new(path).unwrap();
+        wal.append(create_test_entry(1, 1, b"first")).unwrap();
+        wal.append(create_test_entry(2, 1, b"second")).unwrap();
+        drop(wal);
+        let whole = fs::metadata(path).unwrap().len();
+        let torn = encode_block(&[create_test_entry(3, 1, b"torn")], FORMAT_VERSION).unwrap();
+        let mut file = fs::OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(&torn[..torn.len() - 3]).unwrap();
+        drop(file);
+
+        // Opening it read-only leaves the file as it is.
+        assert_eq!(Wal::open_read_only(path).unwrap().last_index(), 2);
+        assert!(fs::metadata(path).unwrap().len() > whole);
 
         let mut wal = Wal::new(path).unwrap();
-        let mut original_entries = Vec::new();
+        assert_eq!(wal.last_index(), 2);
+        assert_eq!(fs::metadata(path).unwrap().len(), whole);
+        // What the torn write left is kept aside rather than thrown away.
+        let quarantined = wal.quarantined().unwrap();
+        assert_eq!((quarantined.offset, quarantined.last_index), (whole, 2));
+        assert_eq!(fs::read(&quarantined.path).unwrap(), torn[..torn.len() - 3]);
+        wal.append(create_test_entry(3, 1, b"third")).unwrap();
+        drop(wal);
+        let commands: Vec<Bytes> =
+            Wal::new(path).unwrap().replay().unwrap().into_iter().map(|e| e.command).collect();
+        assert_eq!(commands, [&b"first"[..], b"second", b"third"]);
+    }
 
+    #[test]
+    fn test_wal_quarantines_a_corrupt_block_in_the_middle() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("bank.wal");
+        let path = path.to_str().unwrap();
+        let mut wal = Wal::new(path).unwrap();
+        let mut whole = 0;
         for i in 1..=5 {
-            let entry = create_test_entry(i, i, format!("entry {}", i).as_bytes());
-            original_entries.push(entry.clone());
-            wal.append(entry).unwrap();
+            if i == 3 {
+                whole = wal.size().unwrap();
+            }
+            wal.append(create_test_entry(i, 1, format!("entry {}", i).as_bytes())).unwrap();
         }
+        drop(wal);
+        corrupt_command(path, b"entry 3");
+        let contents = fs::read(path).unwrap();
+        assert!(Wal::new(path).is_err());
+
+        let open = || {
+            let (mode, bytes) = (DEFAULT_FILE_MODE, DEFAULT_READ_BUFFER_BYTES);
+            Wal::new_with_quarantine(path, IntegrityMode::Full, mode, bytes).unwrap()
+        };
+        let mut wal = open();
+        // The log carries on from entry 2, and says entries 3 to 5 are gone.
+        assert_eq!(wal.last_index(), 2);
+        let quarantined = wal.quarantined().unwrap().clone();
+        assert_eq!(quarantined.path, PathBuf::from(format!("{}.corrupt", path)));
+        assert_eq!((quarantined.offset, quarantined.last_index), (whole, 2));
+        assert_eq!(quarantined.bytes, contents.len() as u64 - whole);
+        assert_eq!(fs::read(&quarantined.path).unwrap(), contents[whole as usize..]);
+        wal.append(create_test_entry(3, 2, b"refilled 3")).unwrap();
+        drop(wal);
+
+        // Opened again, there is nothing left to move aside.
+        let wal = open();
+        assert!(wal.quarantined().is_none());
+        assert_eq!(wal.last_index(), 3);
+        assert_eq!(wal.verify_chain().unwrap(), None);
+    }
 
-        let replayed_entries = wal.replay().unwrap();
-        assert_eq!(replayed_entries.len(), 5);
-
-        for (original, replayed) in original_entries.iter().zip(replayed_entries.iter()) {
-            assert_eq!(original.index, replayed.index);
-            assert_eq!(original.term, replayed.term);
-            assert_eq!(original.command, replayed.command);
+    #[test]
+    fn test_wal_quarantines_a_corrupt_tail_block_checked_on_open() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("bank.wal");
+        let path = path.to_str().unwrap();
+        let mut wal = Wal::new(path).unwrap();
+        wal.append(create_test_entry(1, 1, b"early")).unwrap();
+        wal.append(create_test_entry(2, 1, b"late")).unwrap();
+        drop(wal);
+        corrupt_command(path, b"late");
+
+        let modes = [(IntegrityMode::TailOnly, ".corrupt"), (IntegrityMode::Full, ".corrupt.2")];
+        for (integrity, moved_to) in modes {
+            fs::write(format!("{}.keep", path), fs::read(path).unwrap()).unwrap();
+            let (mode, bytes) = (DEFAULT_FILE_MODE, DEFAULT_READ_BUFFER_BYTES);
+            let wal = Wal::new_with_quarantine(path, integrity, mode, bytes).unwrap();
+            assert_eq!(wal.last_index(), 1);
+            let quarantined = wal.quarantined().unwrap();
+            assert_eq!(quarantined.path, PathBuf::from(format!("{}{}", path, moved_to)));
+            drop(wal);
+            // An earlier quarantine is never overwritten.
+            fs::rename(format!("{}.keep", path), path).unwrap();
         }
     }
 
     #[test]
-    fn test_wal_persistence_across_instances() {
+    fn test_wal_rejects_big_endian_log() {
         let temp_file = NamedTempFile::new().unwrap();
         let path = temp_file.path().to_str().unwrap();
+        let mut header = FileHeader::current().encode();
+        header[5..7].copy_from_slice(&[0xFE, 0xFF]);
+        let mut contents = header.to_vec();
+        contents.extend_from_slice(&create_test_entry(1, 1, b"entry").encode().unwrap());
+        fs::write(path, contents).unwrap();
+
+        let err = Wal::new(path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "WAL was written big-endian, but this reader expects little-endian"
+        );
+    }
+
+    #[test]
+    fn test_wal_opens_log_without_header() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("test.wal");
+        let path = path.to_str().unwrap();
+        {
+            let mut file = fs::File::create(path).unwrap();
+            let mut prev = GENESIS_HASH;
+            for i in 1..=3 {
+                let mut entry = create_test_entry(i, 1, format!("entry {}", i).as_bytes());
+                entry.chain_hash = entry.chain_from(&prev);
+                prev = entry.chain_hash;
+                file.write_all(&entry.encode_version(HEADERLESS_VERSION).unwrap()).unwrap();
+            }
+        }
+
+        let mut wal = Wal::new(path).unwrap();
+        assert_eq!(wal.last_index(), 3);
+        assert_eq!(wal.verify_chain().unwrap(), None);
+        // The log has no room for timestamps until it is rewritten.
+        wal.append(create_test_entry(4, 1, b"entry 4")).unwrap();
+        assert!(wal.replay().unwrap().iter().all(|entry| entry.timestamp.is_none()));
+
+        // Compaction rewrites the log with a header.
+        wal.truncate_prefix(1).unwrap();
+        assert_eq!(FileHeader::read(&wal.storage).unwrap(), Some(FileHeader::current()));
+        wal.append(create_test_entry(5, 1, b"entry 5")).unwrap();
+        drop(wal);
+        let wal = Wal::new(path).unwrap();
+        assert_eq!(wal.last_index(), 5);
+        assert_eq!(wal.verify_chain().unwrap(), None);
+        assert!(wal.read_at(5).unwrap().unwrap().timestamp.is_some());
+    }
 
+    fn test_wal_persistence_across_instances(log: &impl TestLog) {
         // Create WAL and append entries
         {
-            let mut wal = Wal::new(path).unwrap();
+            let mut wal = log.open();
             for i in 1..=3 {
                 let entry = create_test_entry(i, 1, format!("persistent entry {}", i).as_bytes());
                 wal.append(entry).unwrap();
@@ -218,7 +2181,7 @@ mod tests {
 
         // Create new WAL instance and verify persistence
         {
-            let wal = Wal::new(path).unwrap();
+            let wal = log.open();
             assert_eq!(wal.last_index, 3);
 
             let entries = wal.replay().unwrap();
@@ -227,12 +2190,28 @@ mod tests {
         }
     }
 
+    /// Scans a whole log of format `TIMESTAMP_VERSION`, which has no header, for its last
+    /// index.
+    fn scan_tail(
+        file: &FileStorage,
+        compacted: &CompactionPoint,
+    ) -> std::io::Result<u64> {
+        let (version, integrity) = (TIMESTAMP_VERSION, IntegrityMode::Full);
+        let bytes = DEFAULT_READ_BUFFER_BYTES;
+        let scanned = Wal::scan_tail(file, 0, version, compacted, None, integrity, bytes)?;
+        match scanned.corrupt {
+            Some(e) => Err(e),
+            None => Ok(scanned.last_index),
+        }
+    }
+
     #[test]
     fn test_wal_scan_last_index_empty() {
         let temp_file = NamedTempFile::new().unwrap();
-        let file = fs::File::open(temp_file.path()).unwrap();
+        let file = FileStorage::open_read_only(temp_file.path()).unwrap();
 
-        let last_index = Wal::scan_last_index(&file).unwrap();
+        let compacted = CompactionPoint::default();
+        let last_index = scan_tail(&file, &compacted).unwrap();
         assert_eq!(last_index, 0);
     }
 
@@ -246,13 +2225,14 @@ mod tests {
             let mut file = fs::File::create(path).unwrap();
             for i in 1..=3 {
                 let entry = create_test_entry(i, 1, b"test");
-                let encoded = entry.encode().unwrap();
+                let encoded = entry.encode_version(TIMESTAMP_VERSION).unwrap();
                 file.write_all(&encoded).unwrap();
             }
         }
 
-        let file = fs::File::open(path).unwrap();
-        let last_index = Wal::scan_last_index(&file).unwrap();
+        let file = FileStorage::open_read_only(Path::new(path)).unwrap();
+        let compacted = CompactionPoint::default();
+        let last_index = scan_tail(&file, &compacted).unwrap();
         assert_eq!(last_index, 3);
     }
 
@@ -268,22 +2248,19 @@ mod tests {
             let entry1 = create_test_entry(1, 1, b"test");
             let entry3 = create_test_entry(3, 1, b"test"); // Skip index 2
 
-            file.write_all(&entry1.encode().unwrap()).unwrap();
-            file.write_all(&entry3.encode().unwrap()).unwrap();
+            file.write_all(&entry1.encode_version(TIMESTAMP_VERSION).unwrap()).unwrap();
+            file.write_all(&entry3.encode_version(TIMESTAMP_VERSION).unwrap()).unwrap();
         }
 
-        let file = fs::File::open(path).unwrap();
-        Wal::scan_last_index(&file).unwrap();
+        let file = FileStorage::open_read_only(Path::new(path)).unwrap();
+        let compacted = CompactionPoint::default();
+        scan_tail(&file, &compacted).unwrap();
     }
 
-    #[test]
-    fn test_wal_append_after_restart() {
-        let temp_file = NamedTempFile::new().unwrap();
-        let path = temp_file.path().to_str().unwrap();
-
+    fn test_wal_append_after_restart(log: &impl TestLog) {
         // First session: append some entries
         {
-            let mut wal = Wal::new(path).unwrap();
+            let mut wal = log.open();
             for i in 1..=3 {
                 let entry = create_test_entry(i, 1, format!("session1 entry {}", i).as_bytes());
                 wal.append(entry).unwrap();
@@ -292,7 +2269,7 @@ mod tests {
 
         // Second session: continue appending
         {
-            let mut wal = Wal::new(path).unwrap();
+            let mut wal = log.open();
             assert_eq!(wal.last_index, 3);
 
             for i in 4..=6 {
@@ -305,22 +2282,18 @@ mod tests {
 
         // Third session: verify all entries
         {
-            let wal = Wal::new(path).unwrap();
+            let wal = log.open();
             let entries = wal.replay().unwrap();
             assert_eq!(entries.len(), 6);
             assert_eq!(wal.last_index, 6);
         }
     }
 
-    #[test]
-    fn test_wal_large_entries() {
-        let temp_file = NamedTempFile::new().unwrap();
-        let path = temp_file.path().to_str().unwrap();
-
-        let mut wal = Wal::new(path).unwrap();
+    fn test_wal_large_entries(log: &impl TestLog) {
+        let mut wal = log.open();
 
         // Create entries with progressively larger commands
-        let sizes = vec![100, 1000, 10000, 100000];
+        let sizes = [100, 1000, 10000, 100000];
         for (i, size) in sizes.iter().enumerate() {
             let large_command = vec![(i + 1) as u8; *size];
             let entry = create_test_entry((i + 1) as u64, 1, &large_command);
@@ -338,19 +2311,56 @@ mod tests {
         }
     }
 
-    #[test]
-    fn test_wal_edge_cases() {
-        let temp_file = NamedTempFile::new().unwrap();
-        let path = temp_file.path().to_str().unwrap();
+    fn test_wal_max_entry_bytes(log: &impl TestLog) {
+        let mut wal = log.open().with_max_entry_bytes(64);
+        assert_eq!(wal.max_entry_bytes(), 64);
+        wal.append(create_test_entry(1, 1, &[1; 64])).unwrap();
+        let size = wal.size().unwrap();
+
+        // One entry over the limit turns away the whole batch, before it is written.
+        let batch = vec![
+            create_test_entry(2, 1, &[2; 10]),
+            create_test_entry(3, 1, &[3; 65]),
+        ];
+        let err = wal.append_batch(batch).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        let too_large = EntryTooLarge::from_io(&err).unwrap();
+        assert_eq!((too_large.bytes, too_large.limit), (65, 64));
+        let err = wal.append(create_test_entry(2, 1, &[2; 65])).unwrap_err();
+        assert!(EntryTooLarge::from_io(&err).is_some(), "{}", err);
+        assert_eq!(wal.last_index(), 1);
+        assert_eq!(wal.size().unwrap(), size);
+
+        wal.append(create_test_entry(2, 1, &[2; 64])).unwrap();
+        drop(wal);
+        let wal = log.open();
+        assert_eq!(wal.replay().unwrap().len(), 2);
+        assert_eq!(wal.verify_chain().unwrap(), None);
+    }
 
-        let mut wal = Wal::new(path).unwrap();
+    fn test_wal_consecutive_dedup(log: &impl TestLog) {
+        // Off by default: a repeated command is stored again.
+        let mut wal = log.open();
+        assert_eq!(wal.append_command(1, Bytes::from("retry")).unwrap(), 1);
+        assert_eq!(wal.append_command(1, Bytes::from("retry")).unwrap(), 2);
+        assert_eq!(wal.replay().unwrap().len(), 2);
+        drop(wal);
+
+        let mut wal = log.open().with_consecutive_dedup(true);
+        assert_eq!(wal.append_command(1, Bytes::from("retry")).unwrap(), 2);
+        assert_eq!(wal.last_index(), 2);
+        // Only the entry just before counts, and only in the same term.
+        assert_eq!(wal.append_command(1, Bytes::from("other")).unwrap(), 3);
+        assert_eq!(wal.append_command(1, Bytes::from("retry")).unwrap(), 4);
+        assert_eq!(wal.append_command(2, Bytes::from("retry")).unwrap(), 5);
+        assert_eq!(wal.replay().unwrap().len(), 5);
+    }
+
+    fn test_wal_edge_cases(log: &impl TestLog) {
+        let mut wal = log.open();
 
         // Test with maximum u64 values
-        let entry = LogEntry {
-            index: u64::MAX,
-            term: u64::MAX,
-            command: Bytes::from(vec![255u8; 100]),
-        };
+        let entry = LogEntry::new(u64::MAX, u64::MAX, Bytes::from(vec![255u8; 100]));
 
         wal.append(entry.clone()).unwrap();
 
@@ -383,6 +2393,30 @@ mod tests {
         assert!(metadata.len() > 0);
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn test_wal_file_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let temp_dir = TempDir::new().unwrap();
+        let mode_of = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        let path = temp_dir.path().join("default.wal");
+        Wal::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(mode_of(&path), DEFAULT_FILE_MODE);
+
+        let path = temp_dir.path().join("configured.wal");
+        let mut wal =
+            Wal::new_with_file_mode(path.to_str().unwrap(), IntegrityMode::Full, 0o640).unwrap();
+        assert_eq!(mode_of(&path), 0o640);
+
+        // Compaction rewrites the log into a new file, which is created the same way.
+        for i in 1..=3 {
+            wal.append(create_test_entry(i, 1, b"test entry")).unwrap();
+        }
+        wal.truncate_prefix(2).unwrap();
+        assert_eq!(mode_of(&path), 0o640);
+    }
+
     #[test]
     fn test_log_entry_encoding_format() {
         let entry = create_test_entry(0x1234567890ABCDEF, 0xFEDCBA0987654321, b"test");
@@ -391,16 +2425,14 @@ mod tests {
         // Verify the encoding format
         assert_eq!(&encoded[0..8], &0x1234567890ABCDEFu64.to_le_bytes());
         assert_eq!(&encoded[8..16], &0xFEDCBA0987654321u64.to_le_bytes());
-        assert_eq!(&encoded[16..24], &4u64.to_le_bytes()); // length of "test"
-        assert_eq!(&encoded[24..28], b"test");
+        assert_eq!(encoded[16], 0); // no flags
+        assert_eq!(&encoded[17..25], &4u64.to_le_bytes()); // length of "test"
+        assert_eq!(&encoded[25..29], b"test");
+        assert_eq!(&encoded[61..], &0u16.to_le_bytes()); // empty trailer
     }
 
-    #[test]
-    fn test_wal_multiple_decode_cycles() {
-        let temp_file = NamedTempFile::new().unwrap();
-        let path = temp_file.path().to_str().unwrap();
-
-        let mut wal = Wal::new(path).unwrap();
+    fn test_wal_multiple_decode_cycles(log: &impl TestLog) {
+        let mut wal = log.open();
 
         // Append entries
         for i in 1..=5 {
@@ -421,5 +2453,484 @@ mod tests {
             }
         }
     }
-}
 
+    /// Appends `count` entries through a `Wal`, then returns them as stored on disk.
+    fn chained_entries(log: &impl TestLog, count: u64) -> Vec<LogEntry> {
+        let mut wal = log.open();
+        for i in 1..=count {
+            wal.append(create_test_entry(i, 1, format!("entry {}", i).as_bytes())).unwrap();
+        }
+        wal.replay().unwrap()
+    }
+
+    /// Replaces the log at `path` with `entries`, stored hashes and all.
+    fn rewrite(path: &str, entries: &[LogEntry]) {
+        let mut file = fs::File::create(path).unwrap();
+        file.write_all(&FileHeader::current().encode()).unwrap();
+        file.write_all(&encode_block(entries, FORMAT_VERSION).unwrap()).unwrap();
+    }
+
+    fn test_wal_verify_chain_intact(log: &impl TestLog) {
+        chained_entries(log, 5);
+
+        let mut wal = log.open();
+        assert_eq!(wal.verify_chain().unwrap(), None);
+
+        // The chain continues across a restart.
+        wal.append(create_test_entry(6, 2, b"entry 6")).unwrap();
+        assert_eq!(wal.verify_chain().unwrap(), None);
+    }
+
+    #[test]
+    fn test_wal_verify_chain_detects_modified_entry() {
+        let log = FileLog::create();
+        let mut entries = chained_entries(&log, 5);
+
+        entries[2].command = Bytes::from("tampered");
+        rewrite(log.path(), &entries);
+
+        assert_eq!(Wal::new(log.path()).unwrap().verify_chain().unwrap(), Some(3));
+    }
+
+    #[test]
+    fn test_wal_verify_chain_detects_removed_entry() {
+        let log = FileLog::create();
+        let mut entries = chained_entries(&log, 5);
+
+        // Drop entry 3 and renumber the rest so the indexes still look sequential.
+        entries.remove(2);
+        for entry in &mut entries[2..] {
+            entry.index -= 1;
+        }
+        rewrite(log.path(), &entries);
+
+        assert_eq!(Wal::new(log.path()).unwrap().verify_chain().unwrap(), Some(3));
+    }
+
+    #[test]
+    fn test_wal_verify_chain_detects_reordered_entries() {
+        let log = FileLog::create();
+        let mut entries = chained_entries(&log, 5);
+
+        // Swap the contents of entries 2 and 4, keeping their positions' indexes.
+        let (second, fourth) = (entries[1].clone(), entries[3].clone());
+        entries[1] = LogEntry { index: 2, ..fourth };
+        entries[3] = LogEntry { index: 4, ..second };
+        rewrite(log.path(), &entries);
+
+        assert_eq!(Wal::new(log.path()).unwrap().verify_chain().unwrap(), Some(2));
+    }
+
+    fn assert_compacted(result: std::io::Result<impl std::fmt::Debug>, index: u64, first: u64) {
+        let err = result.unwrap_err();
+        assert_eq!(
+            Compacted::from_io(&err),
+            Some(&Compacted {
+                index,
+                first_index: first,
+            })
+        );
+    }
+
+    fn test_wal_first_index_uncompacted(log: &impl TestLog) {
+
+        assert_eq!(log.open().first_index(), 1);
+        chained_entries(log, 3);
+        let wal = log.open();
+        assert_eq!(wal.first_index(), 1);
+        assert_eq!(wal.read_at(1).unwrap().unwrap().index, 1);
+        assert!(wal.read_at(4).unwrap().is_none());
+    }
+
+    fn test_wal_truncate_prefix(log: &impl TestLog) {
+        chained_entries(log, 5);
+
+        let mut wal = log.open();
+        wal.truncate_prefix(3).unwrap();
+        assert_eq!(wal.first_index(), 4);
+        assert_eq!(wal.last_index(), 5);
+        assert_eq!(wal.compaction_point().index, 3);
+
+        let indexes: Vec<u64> = wal.replay().unwrap().iter().map(|e| e.index).collect();
+        assert_eq!(indexes, vec![4, 5]);
+        assert_eq!(wal.read_at(4).unwrap().unwrap().command, Bytes::from("entry 4"));
+        assert_eq!(wal.replay_range(4..5).unwrap().len(), 1);
+        assert_eq!(wal.verify_chain().unwrap(), None);
+
+        // Compacting to an already removed index changes nothing.
+        wal.truncate_prefix(2).unwrap();
+        assert_eq!(wal.first_index(), 4);
+
+        // The compaction point survives a restart and the chain carries on from it.
+        drop(wal);
+        let mut wal = log.open();
+        assert_eq!(wal.first_index(), 4);
+        assert_eq!(wal.last_index(), 5);
+        wal.append(create_test_entry(6, 2, b"entry 6")).unwrap();
+        assert_eq!(wal.verify_chain().unwrap(), None);
+    }
+
+    #[test]
+    fn test_wal_replays_the_same_whatever_its_read_buffer() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("test.wal");
+        let path = path.to_str().unwrap();
+        let mut wal = Wal::new(path).unwrap();
+        for i in 1..=300u64 {
+            let command = vec![i as u8; (i as usize * 37) % 2000];
+            wal.append(create_test_entry(i, i / 100 + 1, &command)).unwrap();
+        }
+        wal.truncate_prefix(20).unwrap();
+        let expected = wal.replay().unwrap();
+        drop(wal);
+
+        for bytes in [1, DEFAULT_READ_BUFFER_BYTES, 4 * 1024 * 1024] {
+            let wal =
+                Wal::new_with_read_buffer(path, IntegrityMode::Full, DEFAULT_FILE_MODE, bytes)
+                    .unwrap();
+            assert_eq!((wal.first_index(), wal.last_index()), (21, 300));
+            assert_eq!(wal.replay().unwrap(), expected, "buffer of {} bytes", bytes);
+            assert_eq!(wal.replay_range(150..=160).unwrap(), expected[129..=139]);
+            assert_eq!(wal.verify_chain().unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_wal_syncs_its_directory_on_create_and_compaction() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path().join("group-1");
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("test.wal");
+        let path = path.to_str().unwrap();
+
+        let mut wal = Wal::new(path).unwrap();
+        for i in 1..=4 {
+            wal.append(create_test_entry(i, 1, format!("entry {}", i).as_bytes())).unwrap();
+        }
+        wal.truncate_prefix(2).unwrap();
+        let mut wal = wal.with_dir_sync(false);
+        wal.truncate_prefix(3).unwrap();
+        drop(wal);
+
+        // Only the log and its compaction point are left, no temporary files.
+        let mut files: Vec<_> =
+            fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+        files.sort();
+        assert_eq!(files, ["test.wal", "test.wal.compacted"]);
+        let wal = Wal::new(path).unwrap();
+        assert_eq!((wal.first_index(), wal.last_index()), (4, 4));
+    }
+
+    fn test_wal_read_below_first_index_is_compacted(log: &impl TestLog) {
+        chained_entries(log, 5);
+
+        let mut wal = log.open();
+        wal.truncate_prefix(3).unwrap();
+
+        assert_compacted(wal.read_at(3), 3, 4);
+        assert_compacted(wal.read_at(1), 1, 4);
+        assert_compacted(wal.replay_range(2..=5), 2, 4);
+        assert_compacted(wal.replay_range((Bound::Excluded(2), Bound::Unbounded)), 3, 4);
+
+        // Other errors are not mistaken for compaction.
+        assert!(Compacted::from_io(&std::io::Error::other("boom")).is_none());
+    }
+
+    fn test_wal_truncate_prefix_whole_log(log: &impl TestLog) {
+        chained_entries(log, 3);
+
+        let mut wal = log.open();
+        assert!(wal.truncate_prefix(4).is_err());
+
+        wal.truncate_prefix(3).unwrap();
+        assert_eq!(wal.first_index(), 4);
+        assert_eq!(wal.last_index(), 3);
+        assert!(wal.replay().unwrap().is_empty());
+        assert!(wal.read_at(4).unwrap().is_none());
+
+        wal.append(create_test_entry(4, 2, b"entry 4")).unwrap();
+        drop(wal);
+        let wal = log.open();
+        assert_eq!(wal.first_index(), 4);
+        assert_eq!(wal.last_index(), 4);
+        assert_eq!(wal.verify_chain().unwrap(), None);
+    }
+
+    fn test_wal_reset_to_starts_over_past_an_index_it_never_held(log: &impl TestLog) {
+        chained_entries(log, 3);
+
+        let mut wal = log.open();
+        let compacted = CompactionPoint {
+            index: 10,
+            term: 4,
+            chain_hash: [7; 32],
+        };
+        wal.reset_to(compacted).unwrap();
+        assert_eq!(wal.first_index(), 11);
+        assert_eq!(wal.last_index(), 10);
+        assert_eq!(wal.term_at(10).unwrap(), Some(4));
+        assert_eq!(wal.last_hash, [7; 32]);
+        assert!(wal.replay().unwrap().is_empty());
+        assert!(Compacted::from_io(&wal.read_at(3).unwrap_err()).is_some());
+
+        wal.append(create_test_entry(11, 4, b"entry 11")).unwrap();
+        drop(wal);
+        let wal = log.open();
+        assert_eq!(wal.first_index(), 11);
+        assert_eq!(wal.last_index(), 11);
+        assert_eq!(wal.term_at(10).unwrap(), Some(4));
+        assert_eq!(wal.verify_chain().unwrap(), None);
+    }
+
+    fn test_wal_compaction_keeps_entries_appended_while_it_is_built(log: &impl TestLog) {
+        chained_entries(log, 5);
+
+        let mut wal = log.open();
+        let compaction = wal.compaction(3).unwrap().unwrap();
+        wal.append(create_test_entry(6, 2, b"entry 6")).unwrap();
+        let built = compaction.build().unwrap();
+        wal.append(create_test_entry(7, 2, b"entry 7")).unwrap();
+        // Nothing changes until the built log is installed.
+        assert_eq!(wal.first_index(), 1);
+
+        wal.install_compaction(built).unwrap();
+        assert_eq!((wal.first_index(), wal.last_index()), (4, 7));
+        wal.append(create_test_entry(8, 2, b"entry 8")).unwrap();
+        drop(wal);
+
+        let wal = log.open();
+        let indexes: Vec<u64> = wal.replay().unwrap().iter().map(|e| e.index).collect();
+        assert_eq!(indexes, vec![4, 5, 6, 7, 8]);
+        assert_eq!(wal.verify_chain().unwrap(), None);
+        assert!(wal.compaction(3).unwrap().is_none());
+    }
+
+    fn test_wal_compaction_fails_if_the_log_is_truncated_while_it_is_built(log: &impl TestLog) {
+        chained_entries(log, 5);
+
+        let mut wal = log.open();
+        let built = wal.compaction(2).unwrap().unwrap().build().unwrap();
+        wal.truncate_suffix(4).unwrap();
+        wal.append(create_test_entry(4, 3, b"other 4")).unwrap();
+
+        assert!(wal.install_compaction(built).is_err());
+        assert_eq!((wal.first_index(), wal.last_index()), (1, 4));
+        assert_eq!(wal.read_at(4).unwrap().unwrap().command, Bytes::from("other 4"));
+        assert_eq!(wal.verify_chain().unwrap(), None);
+    }
+
+    fn test_wal_term_preserving_compaction_keeps_term_boundaries(log: &impl TestLog) {
+        let mut wal = log.open().with_term_preservation(true);
+        for (index, term) in (1..).zip([1, 1, 2, 2, 2, 3, 4, 4]) {
+            wal.append(create_test_entry(index, term, b"command")).unwrap();
+        }
+
+        wal.truncate_prefix(6).unwrap();
+        let boundaries: Vec<(u64, u64)> =
+            wal.term_boundaries().iter().map(|b| (b.index, b.term)).collect();
+        assert_eq!(boundaries, vec![(2, 1), (5, 2), (6, 3)]);
+        for (index, term) in [(2, 1), (5, 2), (6, 3), (7, 4), (8, 4)] {
+            assert_eq!(wal.term_at(index).unwrap(), Some(term), "term at {}", index);
+        }
+        assert_compacted(wal.term_at(4), 4, 7);
+        assert_eq!(wal.term_at(9).unwrap(), None);
+
+        // The boundaries survive a restart, and compacting further within a term, or
+        // without preservation, adds none but keeps those there are.
+        drop(wal);
+        let mut wal = log.open().with_term_preservation(true);
+        wal.append(create_test_entry(9, 4, b"command")).unwrap();
+        wal.truncate_prefix(8).unwrap();
+        assert_eq!(wal.term_boundaries().len(), 3);
+        let mut wal = wal.with_term_preservation(false);
+        wal.append(create_test_entry(10, 5, b"command")).unwrap();
+        wal.truncate_prefix(10).unwrap();
+        assert_eq!(wal.term_boundaries().len(), 3);
+        assert_eq!(wal.term_at(5).unwrap(), Some(2));
+        assert_eq!(wal.term_at(10).unwrap(), Some(5));
+        assert_compacted(wal.term_at(9), 9, 11);
+    }
+
+    fn test_wal_truncate_suffix(log: &impl TestLog) {
+        chained_entries(log, 5);
+
+        let mut wal = log.open();
+        wal.truncate_suffix(6).unwrap();
+        assert_eq!(wal.last_index(), 5);
+
+        wal.truncate_suffix(4).unwrap();
+        assert_eq!(wal.last_index(), 3);
+        assert!(wal.read_at(4).unwrap().is_none());
+
+        // The chain carries on from the new last entry, also after a restart.
+        wal.append(create_test_entry(4, 2, b"entry 4 from term 2")).unwrap();
+        drop(wal);
+        let mut wal = log.open();
+        assert_eq!(wal.last_index(), 4);
+        assert_eq!(wal.read_at(4).unwrap().unwrap().term, 2);
+        assert_eq!(wal.verify_chain().unwrap(), None);
+
+        wal.truncate_prefix(2).unwrap();
+        assert_compacted(wal.truncate_suffix(2), 2, 3);
+        wal.truncate_suffix(3).unwrap();
+        assert_eq!(wal.last_index(), 2);
+        wal.append(create_test_entry(3, 3, b"entry 3")).unwrap();
+        assert_eq!(wal.verify_chain().unwrap(), None);
+    }
+
+    fn test_wal_skips_entries_left_by_interrupted_compaction(log: &impl TestLog) {
+        let entries = chained_entries(log, 5);
+
+        // The compaction point was saved but the log was never rewritten.
+        let point = CompactionPoint {
+            index: 2,
+            term: entries[1].term,
+            chain_hash: entries[1].chain_hash,
+        };
+        point.save(&CompactionPoint::path_for(Path::new(log.path()))).unwrap();
+
+        let wal = log.open();
+        assert_eq!(wal.first_index(), 3);
+        assert_eq!(wal.last_index(), 5);
+        assert_eq!(wal.replay().unwrap().first().unwrap().index, 3);
+        assert_eq!(wal.verify_chain().unwrap(), None);
+    }
+
+    fn test_wal_background_flush_makes_concurrent_appends_durable(log: &impl TestLog) {
+        let wal = log.open().with_background_flush(Duration::from_millis(5)).unwrap();
+        let waiter = wal.durable_waiter().unwrap();
+        let wal = std::sync::Arc::new(std::sync::Mutex::new(wal));
+
+        let writers: Vec<_> = (0..4)
+            .map(|writer| {
+                let (wal, waiter) = (wal.clone(), waiter.clone());
+                std::thread::spawn(move || {
+                    for i in 0..25 {
+                        let index = {
+                            let mut wal = wal.lock().unwrap();
+                            let index = wal.last_index() + 1;
+                            let command = format!("writer {} entry {}", writer, i);
+                            wal.append(create_test_entry(index, 1, command.as_bytes())).unwrap();
+                            index
+                        };
+                        waiter.wait_durable(index).unwrap();
+                        assert!(waiter.durable_index() >= index);
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        assert_eq!(waiter.durable_index(), 100);
+        drop(wal);
+        let wal = log.open();
+        assert_eq!(wal.last_index(), 100);
+        assert_eq!(wal.verify_chain().unwrap(), None);
+    }
+
+    fn test_wal_append_and_wait_durable_waits_for_fsync(log: &impl TestLog) {
+        let metrics = WalMetrics::default();
+        let mut wal = log
+            .open()
+            .with_metrics(metrics.clone())
+            .with_background_flush(Duration::from_millis(500))
+            .unwrap();
+
+        // A plain append returns before the flush thread gets to it.
+        wal.append(create_test_entry(1, 1, b"entry 1")).unwrap();
+        assert_eq!(wal.last_index(), 1);
+        assert_eq!(wal.durable_index(), 0);
+        assert_eq!(metrics.fsync_seconds.get_sample_count(), 0);
+
+        wal.append_and_wait_durable(create_test_entry(2, 1, b"entry 2")).unwrap();
+        assert_eq!(wal.durable_index(), 2);
+        assert!(metrics.fsync_seconds.get_sample_count() >= 1);
+    }
+
+    #[test]
+    fn test_wal_records_how_long_each_fsync_took() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("bank.wal");
+        let metrics = WalMetrics::default();
+        let mut wal = Wal::new(path.to_str().unwrap()).unwrap().with_metrics(metrics.clone());
+        assert_eq!(wal.last_fsync_duration(), None);
+
+        for index in 1..=5 {
+            wal.append(create_test_entry(index, 1, b"entry")).unwrap();
+        }
+        assert!(wal.last_fsync_duration().unwrap() > Duration::ZERO);
+        assert_eq!(metrics.fsync_seconds.get_sample_count(), 5);
+        assert!(metrics.fsync_seconds.get_sample_sum() > 0.0);
+
+        // The flush thread's fsyncs are recorded just the same.
+        let mut wal = wal.with_background_flush(Duration::from_millis(5)).unwrap();
+        assert_eq!(wal.last_fsync_duration(), None);
+        wal.append_and_wait_durable(create_test_entry(6, 1, b"entry")).unwrap();
+        assert!(wal.last_fsync_duration().unwrap() > Duration::ZERO);
+        assert!(metrics.fsync_seconds.get_sample_count() >= 6);
+    }
+
+    fn test_wal_background_flush_on_close(log: &impl TestLog) {
+        let mut wal =
+            log.open().with_background_flush(Duration::from_secs(60)).unwrap();
+        let waiter = wal.durable_waiter().unwrap();
+
+        wal.append(create_test_entry(1, 1, b"entry 1")).unwrap();
+        drop(wal);
+
+        // Closing flushes what was written, but nothing more will ever become durable.
+        assert!(waiter.wait_durable(1).is_ok());
+        let err = waiter.wait_durable(2).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        assert_eq!(log.open().last_index(), 1);
+    }
+
+    #[test]
+    fn test_wal_write_buffer_stages_appends_until_flushed() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("test.wal");
+        let path = path.to_str().unwrap();
+        let mut wal =
+            Wal::new(path).unwrap().with_background_flush(Duration::from_secs(60)).unwrap();
+        let header_only = std::fs::metadata(path).unwrap().len();
+
+        for index in 1..=10 {
+            wal.append(create_test_entry(index, 1, b"small entry")).unwrap();
+        }
+        // Nothing has reached the file yet, but the log still reads it all back.
+        assert_eq!(std::fs::metadata(path).unwrap().len(), header_only);
+        assert_eq!(wal.durable_index(), 0);
+        assert_eq!(wal.replay().unwrap().len(), 10);
+        assert!(std::fs::metadata(path).unwrap().len() > header_only);
+
+        // Closing writes out and syncs what is still staged.
+        wal.append(create_test_entry(11, 1, b"small entry")).unwrap();
+        let waiter = wal.durable_waiter().unwrap();
+        drop(wal);
+        assert!(waiter.wait_durable(11).is_ok());
+        assert_eq!(Wal::new(path).unwrap().last_index(), 11);
+
+        // Without a buffer every append is written as it comes.
+        let path = temp_dir.path().join("unbuffered.wal");
+        let path = path.to_str().unwrap();
+        let mut wal = Wal::new(path)
+            .unwrap()
+            .with_background_flush(Duration::from_secs(60))
+            .unwrap()
+            .with_write_buffer(0)
+            .unwrap();
+        wal.append(create_test_entry(1, 1, b"small entry")).unwrap();
+        assert!(std::fs::metadata(path).unwrap().len() > header_only);
+        assert_eq!(wal.durable_index(), 0);
+    }
+
+    fn test_wal_without_background_flush_is_always_durable(log: &impl TestLog) {
+        let mut wal = log.open();
+        assert!(wal.durable_waiter().is_none());
+
+        wal.append_and_wait_durable(create_test_entry(1, 1, b"entry 1")).unwrap();
+        assert_eq!(wal.durable_index(), 1);
+    }
+}